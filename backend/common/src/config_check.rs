@@ -0,0 +1,189 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Static checks of a binary's configuration, which are run before starting the
+//! server so that every problem is reported at once, rather than one at a time
+//! (or minutes after deploying).
+
+use http::Uri;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+
+/// Collects the problems found with a configuration.
+#[derive(Debug, Default)]
+pub struct ConfigCheck {
+    problems: Vec<String>,
+}
+
+impl ConfigCheck {
+    pub fn new() -> ConfigCheck {
+        ConfigCheck::default()
+    }
+
+    /// Note down a problem with the configuration.
+    pub fn problem(&mut self, problem: impl Into<String>) {
+        self.problems.push(problem.into());
+    }
+
+    /// Note down a problem if the result is an error, handing back the value otherwise.
+    pub fn check<T>(&mut self, what: &str, result: anyhow::Result<T>) -> Option<T> {
+        match result {
+            Ok(val) => Some(val),
+            Err(e) => {
+                self.problem(format!("{}: {:#}", what, e));
+                None
+            }
+        }
+    }
+
+    /// Check that we can listen for TCP connections on the address given. The
+    /// socket is closed again straight away.
+    pub fn tcp_listen_addr(&mut self, what: &str, addr: SocketAddr) {
+        if let Err(e) = TcpListener::bind(addr) {
+            self.problem(format!("{}: cannot listen on {}: {}", what, addr, e));
+        }
+    }
+
+    /// Check that we can bind a UDP socket to the address given. The socket is
+    /// closed again straight away.
+    pub fn udp_listen_addr(&mut self, what: &str, addr: SocketAddr) {
+        if let Err(e) = UdpSocket::bind(addr) {
+            self.problem(format!("{}: cannot listen on {}: {}", what, addr, e));
+        }
+    }
+
+    /// Check that no two of the (named) addresses given would try to listen on the
+    /// same port. Binding to port 0 picks a free port, so never overlaps.
+    pub fn distinct_listen_addrs(&mut self, addrs: &[(&str, SocketAddr)]) {
+        for (idx, (name_a, a)) in addrs.iter().enumerate() {
+            for (name_b, b) in &addrs[idx + 1..] {
+                let same_ip =
+                    a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified();
+                if a.port() != 0 && a.port() == b.port() && same_ip {
+                    self.problem(format!("{} ({}) and {} ({}) overlap", name_a, a, name_b, b));
+                }
+            }
+        }
+    }
+
+    /// Check that the host in the URL given can be resolved to at least one address.
+    pub fn resolves(&mut self, what: &str, uri: &Uri) {
+        let host = match uri.host() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return self.problem(format!("{}: {} has no host", what, uri)),
+        };
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") | Some("wss") => 443,
+            _ => 80,
+        });
+        match (host, port).to_socket_addrs() {
+            Ok(addrs) if addrs.len() > 0 => {}
+            Ok(_) => self.problem(format!("{}: {} resolved to no addresses", what, host)),
+            Err(e) => self.problem(format!("{}: cannot resolve {}: {}", what, host, e)),
+        }
+    }
+
+    /// The problems found so far.
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+
+    /// Return an error listing every problem found, if there were any.
+    pub fn finish(self) -> anyhow::Result<()> {
+        if self.problems.is_empty() {
+            return Ok(());
+        }
+        let mut msg = format!("{} configuration problem(s) found:", self.problems.len());
+        for problem in &self.problems {
+            msg.push_str("\n  - ");
+            msg.push_str(problem);
+        }
+        Err(anyhow::anyhow!(msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut check = ConfigCheck::new();
+        assert_eq!(check.check("first", Ok(1)), Some(1));
+        assert_eq!(
+            check.check::<()>("second", Err(anyhow::anyhow!("bad"))),
+            None
+        );
+        check.problem("third is bad too");
+
+        let err = check.finish().unwrap_err().to_string();
+        assert!(err.starts_with("2 configuration problem(s) found:"));
+        assert!(err.contains("\n  - second: bad"));
+        assert!(err.contains("\n  - third is bad too"));
+        assert!(ConfigCheck::new().finish().is_ok());
+    }
+
+    #[test]
+    fn overlapping_listen_addrs_are_problems() {
+        let mut check = ConfigCheck::new();
+        check.distinct_listen_addrs(&[
+            ("--listen", addr("127.0.0.1:8001")),
+            ("--other", addr("127.0.0.2:8001")),
+            ("--random", addr("127.0.0.1:0")),
+            ("--also-random", addr("127.0.0.1:0")),
+        ]);
+        assert!(check.problems().is_empty());
+
+        check.distinct_listen_addrs(&[
+            ("--listen", addr("127.0.0.1:8001")),
+            ("--legacy", addr("0.0.0.0:8001")),
+        ]);
+        assert_eq!(
+            check.problems(),
+            &["--listen (127.0.0.1:8001) and --legacy (0.0.0.0:8001) overlap".to_string()]
+        );
+    }
+
+    #[test]
+    fn addresses_in_use_are_problems() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let in_use = listener.local_addr().unwrap();
+
+        let mut check = ConfigCheck::new();
+        check.tcp_listen_addr("--free", addr("127.0.0.1:0"));
+        assert!(check.problems().is_empty());
+        check.tcp_listen_addr("--in-use", in_use);
+        assert_eq!(check.problems().len(), 1);
+        assert!(check.problems()[0].starts_with("--in-use: cannot listen on"));
+    }
+
+    #[test]
+    fn urls_must_have_a_resolvable_host() {
+        let mut check = ConfigCheck::new();
+        check.resolves(
+            "--core",
+            &"ws://127.0.0.1:8000/shard_submit".parse().unwrap(),
+        );
+        check.resolves("--core", &"ws://[::1]/shard_submit".parse().unwrap());
+        assert!(check.problems().is_empty());
+
+        check.resolves("--core", &"/shard_submit".parse().unwrap());
+        assert_eq!(check.problems(), &["--core: /shard_submit has no host"]);
+    }
+}
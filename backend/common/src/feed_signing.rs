@@ -0,0 +1,87 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Feeds can ask for each batch of messages sent to them to be signed with a key
+//! that is unique to their session, along with a timestamp that increases with every
+//! batch. This lets them notice batches that were tampered with, replayed from an
+//! earlier point in the session, or replayed from a different session entirely.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Sign a batch of feed messages, as sent to the feed, along with the timestamp that
+/// it was signed at. The signature is an HMAC-SHA256 of the timestamp (as big endian
+/// bytes) followed by the batch.
+pub fn sign_batch(key: &[u8], batch: &[u8], ts: u64) -> [u8; 32] {
+    batch_mac(key, batch, ts).finalize().into_bytes().into()
+}
+
+/// Check that a batch of feed messages was signed at the timestamp given with the key
+/// given. The comparison is done in constant time.
+pub fn verify_batch(key: &[u8], batch: &[u8], ts: u64, sig: &[u8; 32]) -> bool {
+    batch_mac(key, batch, ts).verify_slice(sig).is_ok()
+}
+
+fn batch_mac(key: &[u8], batch: &[u8], ts: u64) -> HmacSha256 {
+    // HMAC accepts keys of any length, so this can't fail:
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&ts.to_be_bytes());
+    mac.update(batch);
+    mac
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &[u8] = b"a key that only one feed knows";
+    const BATCH: &[u8] = br#"[0,32,1,[100,1600000000000,null]]"#;
+
+    #[test]
+    fn signed_batches_verify() {
+        let sig = sign_batch(KEY, BATCH, 1000);
+        assert!(verify_batch(KEY, BATCH, 1000, &sig));
+    }
+
+    #[test]
+    fn signatures_depend_on_everything_signed() {
+        let sig = sign_batch(KEY, BATCH, 1000);
+        assert!(!verify_batch(b"another key", BATCH, 1000, &sig));
+        assert!(!verify_batch(
+            KEY,
+            br#"[0,32,1,[101,1600000000000,null]]"#,
+            1000,
+            &sig
+        ));
+        assert!(!verify_batch(KEY, BATCH, 1001, &sig));
+
+        let mut tampered = sig;
+        tampered[0] ^= 1;
+        assert!(!verify_batch(KEY, BATCH, 1000, &tampered));
+    }
+
+    #[test]
+    fn known_signature() {
+        // So that clients in other languages can check that they do the same thing:
+        let sig = sign_batch(b"key", b"batch", 0x0102030405060708);
+        assert_eq!(
+            hex::encode(sig),
+            "9f95c368dd689f82b92bca6f7b8f1a4ce1944b405d74d9623ed83dd2fabd1eab"
+        );
+    }
+}
@@ -14,9 +14,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::{shard_token, ws_deflate};
 use futures::io::{BufReader, BufWriter};
 use hyper::server::conn::AddrStream;
 use hyper::{Body, Request, Response, Server};
+use soketto::extension::Extension;
 use std::future::Future;
 use std::net::SocketAddr;
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
@@ -26,6 +28,23 @@ pub async fn start_server<H, F>(addr: SocketAddr, handler: H) -> Result<(), anyh
 where
     H: Clone + Send + Sync + 'static + FnMut(SocketAddr, Request<Body>) -> F,
     F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
+{
+    start_server_with_shutdown(addr, handler, std::future::pending()).await
+}
+
+/// Like [`start_server`], but stop accepting new connections once `shutdown` resolves.
+/// The server finishes once any requests in progress have been responded to. Connections
+/// that have been upgraded to websockets are no longer the server's concern, and so
+/// are left alone.
+pub async fn start_server_with_shutdown<H, F, S>(
+    addr: SocketAddr,
+    handler: H,
+    shutdown: S,
+) -> Result<(), anyhow::Error>
+where
+    H: Clone + Send + Sync + 'static + FnMut(SocketAddr, Request<Body>) -> F,
+    F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
+    S: Future<Output = ()>,
 {
     let service = hyper::service::make_service_fn(move |addr: &AddrStream| {
         let mut handler = handler.clone();
@@ -35,7 +54,7 @@ where
     let server = Server::bind(&addr).serve(service);
 
     log::info!("listening on http://{}", server.local_addr());
-    server.await?;
+    server.with_graceful_shutdown(shutdown).await?;
 
     Ok(())
 }
@@ -44,8 +63,25 @@ type WsStream = BufReader<BufWriter<Compat<hyper::upgrade::Upgraded>>>;
 pub type WsSender = soketto::connection::Sender<WsStream>;
 pub type WsReceiver = soketto::connection::Receiver<WsStream>;
 
+/// The largest message that we'll accept over a websocket connection.
+pub const MAX_WEBSOCKET_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
 /// A convenience function to upgrade a Hyper request into a Soketto Websocket.
 pub fn upgrade_to_websocket<H, F>(req: Request<Body>, on_upgrade: H) -> hyper::Response<Body>
+where
+    H: 'static + Send + FnOnce(WsSender, WsReceiver) -> F,
+    F: Send + Future<Output = ()>,
+{
+    upgrade_to_websocket_with_compression(req, None, on_upgrade)
+}
+
+/// Like [`upgrade_to_websocket`], but if a compression threshold is given and the client
+/// supports `permessage-deflate`, messages we send of at least that many bytes are compressed.
+pub fn upgrade_to_websocket_with_compression<H, F>(
+    req: Request<Body>,
+    compression_threshold: Option<usize>,
+    on_upgrade: H,
+) -> hyper::Response<Body>
 where
     H: 'static + Send + FnOnce(WsSender, WsReceiver) -> F,
     F: Send + Future<Output = ()>,
@@ -80,12 +116,22 @@ where
     let mut accept_key_buf = [0; 32];
     let accept_key = generate_websocket_accept_key(key.as_bytes(), &mut accept_key_buf);
 
+    let accepted_compression = compression_threshold.and_then(|_| {
+        let offers = req.headers().get_all("Sec-WebSocket-Extensions");
+        ws_deflate::accept_offer(offers.iter().filter_map(|v| v.to_str().ok()))
+    });
+    let compression_threshold = compression_threshold.filter(|_| accepted_compression.is_some());
+
     // Tell the client that we accept the upgrade-to-WS request:
-    let response = Response::builder()
+    let mut response = Response::builder()
         .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
         .header(hyper::header::CONNECTION, "upgrade")
         .header(hyper::header::UPGRADE, "websocket")
-        .header("Sec-WebSocket-Accept", accept_key)
+        .header("Sec-WebSocket-Accept", accept_key);
+    if let Some(accepted) = accepted_compression {
+        response = response.header("Sec-WebSocket-Extensions", accepted);
+    }
+    let response = response
         .body(Body::empty())
         .expect("bug: failed to build response");
 
@@ -105,7 +151,13 @@ where
             soketto::handshake::Server::new(BufReader::new(BufWriter::new(stream.compat())));
 
         // Get hold of a way to send and receive messages:
-        let (sender, receiver) = server.into_builder().finish();
+        let mut builder = server.into_builder();
+        builder.set_max_message_size(MAX_WEBSOCKET_MESSAGE_SIZE);
+        if let Some(threshold) = compression_threshold {
+            let deflate = ws_deflate::Deflate::new(threshold, MAX_WEBSOCKET_MESSAGE_SIZE);
+            builder.add_extensions(Some(Box::new(deflate) as Box<dyn Extension + Send>));
+        }
+        let (sender, receiver) = builder.finish();
 
         // Pass these to our when-upgraded handler:
         on_upgrade(sender, receiver).await;
@@ -114,14 +166,52 @@ where
     response
 }
 
+/// Check that a request is allowed to access admin endpoints. Admin endpoints are disabled
+/// entirely (and we return a 404) if no token is configured. Otherwise, the request must
+/// provide the token in an `Authorization: Bearer <token>` header.
+///
+/// Returns `None` if the request is authorized, or else the response to send back.
+pub fn check_admin_token<B>(req: &Request<B>, admin_token: Option<&str>) -> Option<Response<Body>> {
+    let admin_token = match admin_token {
+        Some(token) => token,
+        None => return Some(basic_response(404, "Not found")),
+    };
+
+    let provided_token = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|v| std::str::from_utf8(v.as_bytes()).ok())
+        .and_then(|v| v.trim().strip_prefix("Bearer "))
+        .map(|v| v.trim());
+
+    match provided_token {
+        Some(token) if shard_token::constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => {
+            None
+        }
+        _ => Some(basic_response(401, "Unauthorized")),
+    }
+}
+
 /// A helper to return a basic HTTP response with a code and text body.
-fn basic_response(code: u16, msg: impl AsRef<str>) -> Response<Body> {
+pub fn basic_response(code: u16, msg: impl AsRef<str>) -> Response<Body> {
     Response::builder()
         .status(code)
         .body(Body::from(msg.as_ref().to_owned()))
         .expect("bug: failed to build response body")
 }
 
+/// A helper to return a JSON response with a code and some serializable body.
+pub fn json_response<T: serde::Serialize>(code: u16, body: &T) -> Response<Body> {
+    match serde_json::to_vec(body) {
+        Ok(bytes) => Response::builder()
+            .status(code)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(bytes))
+            .expect("bug: failed to build response body"),
+        Err(e) => basic_response(500, format!("Failed to serialize response: {}", e)),
+    }
+}
+
 /// Defined in RFC 6455. this is how we convert the Sec-WebSocket-Key in a request into a
 /// Sec-WebSocket-Accept that we return in the response.
 fn generate_websocket_accept_key<'a>(key: &[u8], buf: &'a mut [u8; 32]) -> &'a [u8] {
@@ -139,7 +229,7 @@ fn generate_websocket_accept_key<'a>(key: &[u8], buf: &'a mut [u8; 32]) -> &'a [
 }
 
 /// Check if a request is a websocket upgrade request.
-fn is_upgrade_request<B>(request: &hyper::Request<B>) -> bool {
+pub fn is_upgrade_request<B>(request: &hyper::Request<B>) -> bool {
     header_contains_value(request.headers(), hyper::header::CONNECTION, b"upgrade")
         && header_contains_value(request.headers(), hyper::header::UPGRADE, b"websocket")
 }
@@ -14,8 +14,8 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::ws_client;
 use bincode::Options;
-use common::ws_client;
 use futures::StreamExt;
 
 #[derive(Clone, Debug)]
@@ -25,13 +25,14 @@ pub enum Message<Out> {
     Data(Out),
 }
 
-/// Connect to the telemetry core, retrying the connection if we're disconnected.
+/// Connect to a telemetry core (from a shard, or from another core in the same cluster),
+/// retrying the connection if we're disconnected.
 /// - Sends `Message::Connected` and `Message::Disconnected` when the connection goes up/down.
 /// - Returns a channel that allows you to send messages to the connection.
 /// - Messages are all encoded/decoded to/from bincode, and so need to support being (de)serialized from
 ///   a non self-describing encoding.
 ///
-/// Note: have a look at [`common::internal_messages`] to see the different message types exchanged
+/// Note: have a look at [`crate::internal_messages`] to see the different message types exchanged
 /// between aggregator and core.
 pub async fn create_ws_connection_to_core<In, Out>(
     telemetry_uri: http::Uri,
@@ -20,7 +20,7 @@ use std::net::IpAddr;
 
 use crate::id_type;
 use crate::node_message::Payload;
-use crate::node_types::{BlockHash, NodeDetails};
+use crate::node_types::{BlockHash, NodeDetails, Timestamp};
 use serde::{Deserialize, Serialize};
 
 id_type! {
@@ -40,14 +40,24 @@ pub enum FromShardAggregator {
         node: NodeDetails,
         local_id: ShardNodeId,
         genesis_hash: BlockHash,
+        /// The shard was already connected to this node before it lost its connection
+        /// to us (perhaps because it restarted), so the node is picking up where it left off.
+        resumed: bool,
     },
     /// A message payload with updated details for a node
     UpdateNode {
         local_id: ShardNodeId,
         payload: Payload,
+        /// When the node says that it sent the message, by its own clock.
+        reported_at: Option<Timestamp>,
+        /// When the shard received the message from the node, by the shard's clock.
+        received_at: Timestamp,
     },
     /// Inform the telemetry core that a node has been removed
-    RemoveNode { local_id: ShardNodeId },
+    RemoveNode {
+        local_id: ShardNodeId,
+        reason: NodeCloseReason,
+    },
 }
 
 /// Message sent form the telemetry core to a telemetry shard
@@ -57,6 +67,18 @@ pub enum FromTelemetryCore {
         local_id: ShardNodeId,
         reason: MuteReason,
     },
+    /// The core doesn't know of a node with this ID (perhaps it missed the node
+    /// being added), so the shard should close the node's connection. The node will
+    /// then reconnect and be added again, rather than all of its messages being dropped.
+    Resync { local_id: ShardNodeId },
+    /// Drop updates from every node on the chain with this genesis hash, for this many
+    /// milliseconds (unless told to unmute it sooner).
+    MuteChain {
+        genesis_hash: BlockHash,
+        ttl_ms: u64,
+    },
+    /// Stop dropping updates from nodes on the chain with this genesis hash.
+    UnmuteChain { genesis_hash: BlockHash },
 }
 
 /// Why is the thing being muted?
@@ -64,4 +86,140 @@ pub enum FromTelemetryCore {
 pub enum MuteReason {
     Overquota,
     ChainNotAllowed,
+    /// The core is tracking as many chains as it's allowed to, so the node's chain
+    /// was turned away (or evicted to make room for a first party chain).
+    TelemetryFull,
+}
+
+/// Why a node's connection was closed. Each reason has a stable numeric code (in the
+/// range reserved for private use by websocket close codes), which is used in metrics and
+/// logs. Never change or reuse a code once it's been assigned.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum NodeCloseReason {
+    /// `4000`: The node closed the connection itself.
+    ClientClosed = 4000,
+    /// `4001`: There was an error receiving data from the node, so we gave up on it.
+    ReceiveError = 4001,
+    /// `4002`: The node didn't ask to upgrade its connection to a websocket.
+    BadHandshake = 4002,
+    /// `4003`: The node's address has been temporarily banned, having previously sent us
+    /// too much data.
+    Banned = 4003,
+    /// `4004`: The node's address is in a range that's been blocked by an operator.
+    AddressBlocked = 4004,
+    /// `4005`: The node sent us too much data, so we've closed the connection and banned
+    /// its address for a while.
+    RateLimited = 4005,
+    /// `4006`: The shard reconnected to the telemetry core, so nodes are asked to reconnect
+    /// and tell us about themselves again.
+    CoreReconnected = 4006,
+    /// `4007`: The node moved to a chain that's owned by another telemetry core in the cluster.
+    ChainMoved = 4007,
+    /// `4008`: The connection that the node's messages were arriving on was closed, so we
+    /// won't hear any more about it.
+    ShardDisconnected = 4008,
+    /// `4009`: Something went wrong internally, so the connection couldn't be handled.
+    Internal = 4009,
+    /// `4010`: The node was sending telemetry in HTTP batches, and we stopped hearing from it
+    /// for long enough that its session expired.
+    SessionExpired = 4010,
+    /// `4011`: The telemetry core didn't recognise the node, so it's asked to reconnect and
+    /// tell us about itself again.
+    Resync = 4011,
+    /// `4012`: The node didn't tell us which chain it's on, and we've been configured to
+    /// reject such nodes.
+    NoChain = 4012,
+    /// `4013`: The telemetry core is tracking as many chains as it's allowed to, so the
+    /// node's chain was turned away, or evicted to make room for a first party chain.
+    TelemetryFull = 4013,
+}
+
+impl NodeCloseReason {
+    /// Every reason that a node's connection can be closed.
+    pub const ALL: [NodeCloseReason; 14] = [
+        NodeCloseReason::ClientClosed,
+        NodeCloseReason::ReceiveError,
+        NodeCloseReason::BadHandshake,
+        NodeCloseReason::Banned,
+        NodeCloseReason::AddressBlocked,
+        NodeCloseReason::RateLimited,
+        NodeCloseReason::CoreReconnected,
+        NodeCloseReason::ChainMoved,
+        NodeCloseReason::ShardDisconnected,
+        NodeCloseReason::Internal,
+        NodeCloseReason::SessionExpired,
+        NodeCloseReason::Resync,
+        NodeCloseReason::NoChain,
+        NodeCloseReason::TelemetryFull,
+    ];
+
+    /// The stable numeric code for this reason.
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+
+    /// A short name for this reason, for use in metric labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NodeCloseReason::ClientClosed => "client_closed",
+            NodeCloseReason::ReceiveError => "receive_error",
+            NodeCloseReason::BadHandshake => "bad_handshake",
+            NodeCloseReason::Banned => "banned",
+            NodeCloseReason::AddressBlocked => "address_blocked",
+            NodeCloseReason::RateLimited => "rate_limited",
+            NodeCloseReason::CoreReconnected => "core_reconnected",
+            NodeCloseReason::ChainMoved => "chain_moved",
+            NodeCloseReason::ShardDisconnected => "shard_disconnected",
+            NodeCloseReason::Internal => "internal",
+            NodeCloseReason::SessionExpired => "session_expired",
+            NodeCloseReason::Resync => "resync",
+            NodeCloseReason::NoChain => "no_chain",
+            NodeCloseReason::TelemetryFull => "telemetry_full",
+        }
+    }
+
+    /// Did we decide to close the connection, rather than the node going away by itself?
+    pub fn is_server_initiated(self) -> bool {
+        !matches!(
+            self,
+            NodeCloseReason::ClientClosed | NodeCloseReason::ReceiveError
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn close_reason_codes_are_stable() {
+        let codes: Vec<u16> = NodeCloseReason::ALL.iter().map(|r| r.code()).collect();
+        assert_eq!(
+            codes,
+            vec![
+                4000, 4001, 4002, 4003, 4004, 4005, 4006, 4007, 4008, 4009, 4010, 4011, 4012, 4013
+            ]
+        );
+    }
+
+    #[test]
+    fn close_reason_names_are_unique() {
+        let names: HashSet<&str> = NodeCloseReason::ALL.iter().map(|r| r.as_str()).collect();
+        assert_eq!(names.len(), NodeCloseReason::ALL.len());
+    }
+
+    #[test]
+    fn only_node_initiated_closes_are_not_server_initiated() {
+        let node_initiated: Vec<_> = NodeCloseReason::ALL
+            .iter()
+            .copied()
+            .filter(|r| !r.is_server_initiated())
+            .collect();
+        assert_eq!(
+            node_initiated,
+            vec![NodeCloseReason::ClientClosed, NodeCloseReason::ReceiveError]
+        );
+    }
 }
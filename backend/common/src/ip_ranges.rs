@@ -0,0 +1,321 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Sets of IP address ranges, for blocking nodes, spotting anonymizing proxies and
+//! so on.
+
+use anyhow::{anyhow, Context};
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A range of IP addresses in CIDR notation, eg `192.168.1.0/24` or `2001:db8::/32`.
+/// Any host bits in the address are zeroed, so `192.168.1.7/24` and `192.168.1.0/24`
+/// describe the same range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix_len) = match s.find('/') {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("'{}' is not a valid IP address", addr))?;
+        let max_len = key(addr).1;
+
+        // A bare address is treated as a range containing just that address:
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "'{}' is not a valid prefix length; expecting a number from 0 to {}",
+                        len,
+                        max_len
+                    )
+                })?,
+            None => max_len,
+        };
+
+        let masked = mask(key(addr).0, prefix_len);
+        let addr = match addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from((masked >> 96) as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(masked)),
+        };
+
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// A set of IP ranges. Ranges are stored in a binary prefix tree, so checking whether
+/// an address is in the set only requires walking as many nodes as there are bits in
+/// the address, regardless of how many ranges there are.
+#[derive(Debug, Default)]
+pub struct IpRanges {
+    /// The ranges themselves, so that we can list them back out in order.
+    ranges: BTreeSet<Cidr>,
+    /// Prefix tree for IPv4 ranges.
+    v4: TrieNode,
+    /// Prefix tree for IPv6 ranges.
+    v6: TrieNode,
+}
+
+impl IpRanges {
+    pub fn new() -> IpRanges {
+        IpRanges::default()
+    }
+
+    /// Add a range of addresses. Returns false if the range was already in the set.
+    pub fn insert(&mut self, cidr: Cidr) -> bool {
+        if !self.ranges.insert(cidr) {
+            return false;
+        }
+        let (bits, _) = key(cidr.addr);
+        self.trie_mut(cidr.addr).insert(bits, cidr.prefix_len);
+        true
+    }
+
+    /// Remove a range of addresses. Returns false if the range wasn't in the set. This
+    /// only removes the exact range given; any other ranges overlapping it remain.
+    pub fn remove(&mut self, cidr: &Cidr) -> bool {
+        if !self.ranges.remove(cidr) {
+            return false;
+        }
+        let (bits, _) = key(cidr.addr);
+        self.trie_mut(cidr.addr).remove(bits, cidr.prefix_len);
+        true
+    }
+
+    /// Is the address given contained in any of the ranges?
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 addresses can turn up mapped into IPv6 form; treat them as IPv4:
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        let (bits, len) = key(ip);
+        match ip {
+            IpAddr::V4(_) => self.v4.contains_prefix_of(bits, len),
+            IpAddr::V6(_) => self.v6.contains_prefix_of(bits, len),
+        }
+    }
+
+    /// Iterate over the ranges, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &Cidr> {
+        self.ranges.iter()
+    }
+
+    fn trie_mut(&mut self, addr: IpAddr) -> &mut TrieNode {
+        match addr {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        }
+    }
+}
+
+/// A node in a binary prefix tree. Each level down the tree corresponds to
+/// the next most significant bit of an address.
+#[derive(Debug, Default)]
+struct TrieNode {
+    /// Is there a range ending at this node?
+    terminal: bool,
+    children: [Option<Box<TrieNode>>; 2],
+}
+
+impl TrieNode {
+    fn insert(&mut self, bits: u128, prefix_len: u8) {
+        let mut node = self;
+        for idx in 0..prefix_len {
+            node = node.children[bit(bits, idx)].get_or_insert_with(Default::default);
+        }
+        node.terminal = true;
+    }
+
+    /// Remove the range, tidying up any nodes that no longer lead anywhere.
+    /// Returns true if this node is now empty and can itself be removed.
+    fn remove(&mut self, bits: u128, prefix_len: u8) -> bool {
+        self.remove_from(bits, prefix_len, 0)
+    }
+
+    fn remove_from(&mut self, bits: u128, prefix_len: u8, depth: u8) -> bool {
+        if depth == prefix_len {
+            self.terminal = false;
+        } else {
+            let b = bit(bits, depth);
+            if let Some(child) = &mut self.children[b] {
+                if child.remove_from(bits, prefix_len, depth + 1) {
+                    self.children[b] = None;
+                }
+            }
+        }
+        !self.terminal && self.children.iter().all(|c| c.is_none())
+    }
+
+    /// Does any range in this tree contain the address given?
+    fn contains_prefix_of(&self, bits: u128, len: u8) -> bool {
+        let mut node = self;
+        for idx in 0..len {
+            if node.terminal {
+                return true;
+            }
+            node = match &node.children[bit(bits, idx)] {
+                Some(child) => child,
+                None => return false,
+            };
+        }
+        node.terminal
+    }
+}
+
+/// Convert an address into its bits (left aligned in a u128, so that the most
+/// significant bit of the address is always the most significant bit here), along
+/// with the number of bits in the address.
+fn key(addr: IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(v4) => ((u32::from(v4) as u128) << 96, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+/// Zero all but the first `prefix_len` bits.
+fn mask(bits: u128, prefix_len: u8) -> u128 {
+    match prefix_len {
+        0 => 0,
+        n => bits & (u128::MAX << (128 - n as u32)),
+    }
+}
+
+/// Return the nth most significant bit (0 or 1).
+fn bit(bits: u128, idx: u8) -> usize {
+    ((bits >> (127 - idx as u32)) & 1) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ranges(ranges: &[&str]) -> IpRanges {
+        let mut b = IpRanges::new();
+        for r in ranges {
+            b.insert(r.parse().unwrap());
+        }
+        b
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parsing_normalises_host_bits() {
+        let cidr: Cidr = "192.168.1.7/24".parse().unwrap();
+        assert_eq!(cidr.to_string(), "192.168.1.0/24");
+
+        let cidr: Cidr = "2001:db8:ffff::1/32".parse().unwrap();
+        assert_eq!(cidr.to_string(), "2001:db8::/32");
+
+        let cidr: Cidr = "10.0.0.1".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.1/32");
+    }
+
+    #[test]
+    fn cidr_parsing_rejects_invalid_input() {
+        assert!("192.168.1.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("192.168.1/24".parse::<Cidr>().is_err());
+        assert!("192.168.1.0/".parse::<Cidr>().is_err());
+        assert!("foo".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn ipv4_ranges_are_matched() {
+        let b = ranges(&["192.168.1.0/24", "10.0.0.0/8", "1.2.3.4/32"]);
+
+        assert!(b.contains(ip("192.168.1.0")));
+        assert!(b.contains(ip("192.168.1.255")));
+        assert!(!b.contains(ip("192.168.2.1")));
+        assert!(b.contains(ip("10.255.0.1")));
+        assert!(!b.contains(ip("11.0.0.1")));
+        assert!(b.contains(ip("1.2.3.4")));
+        assert!(!b.contains(ip("1.2.3.5")));
+    }
+
+    #[test]
+    fn ipv6_ranges_are_matched() {
+        let b = ranges(&["2001:db8::/32", "fe80::1/128"]);
+
+        assert!(b.contains(ip("2001:db8::1")));
+        assert!(b.contains(ip("2001:db8:ffff:ffff::1")));
+        assert!(!b.contains(ip("2001:db9::1")));
+        assert!(b.contains(ip("fe80::1")));
+        assert!(!b.contains(ip("fe80::2")));
+    }
+
+    #[test]
+    fn ipv4_and_ipv6_ranges_do_not_overlap() {
+        // ::/8 shares leading bits with 0.0.0.0/8, but they shouldn't match each other:
+        let b = ranges(&["0.0.0.0/8"]);
+        assert!(!b.contains(ip("::1")));
+
+        let b = ranges(&["::/8"]);
+        assert!(!b.contains(ip("0.0.0.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6_addresses_match_ipv4_ranges() {
+        let b = ranges(&["192.168.1.0/24"]);
+        assert!(b.contains(ip("::ffff:192.168.1.20")));
+    }
+
+    #[test]
+    fn zero_length_prefix_contains_everything_of_that_family() {
+        let b = ranges(&["0.0.0.0/0"]);
+        assert!(b.contains(ip("255.255.255.255")));
+        assert!(!b.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn removing_a_range_only_removes_that_range() {
+        let mut b = ranges(&["10.0.0.0/8", "10.1.0.0/16"]);
+
+        assert!(b.remove(&"10.0.0.0/8".parse().unwrap()));
+        assert!(!b.remove(&"10.0.0.0/8".parse().unwrap()));
+
+        assert!(!b.contains(ip("10.2.0.1")));
+        assert!(b.contains(ip("10.1.0.1")));
+
+        assert!(b.remove(&"10.1.0.0/16".parse().unwrap()));
+        assert!(!b.contains(ip("10.1.0.1")));
+        assert_eq!(b.iter().count(), 0);
+    }
+}
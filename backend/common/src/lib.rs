@@ -15,15 +15,22 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 pub mod byte_size;
+pub mod config_check;
+pub mod feed_signing;
 pub mod http_utils;
 pub mod id_type;
+pub mod internal_connection;
 pub mod internal_messages;
+pub mod ip_ranges;
 pub mod node_message;
 pub mod node_types;
 pub mod ready_chunks_all;
 pub mod rolling_total;
+pub mod shard_token;
+pub mod span_logging;
 pub mod time;
 pub mod ws_client;
+pub mod ws_deflate;
 
 mod assign_id;
 mod dense_map;
@@ -37,7 +44,11 @@ mod num_stats;
 pub use assign_id::AssignId;
 pub use dense_map::DenseMap;
 pub use either_sink::EitherSink;
-pub use mean_list::MeanList;
+pub use mean_list::{MeanList, MeanValue, OverflowPolicy};
 pub use most_seen::MostSeen;
 pub use multi_map_unique::MultiMapUnique;
 pub use num_stats::NumStats;
+
+/// The version of the feed protocol, sent to feeds when they first connect. This lives
+/// here rather than in the core so that tests can check against it.
+pub const FEED_VERSION: usize = 42;
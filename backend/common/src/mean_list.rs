@@ -14,23 +14,57 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use num_traits::{Float, Zero};
-use std::ops::AddAssign;
+use num_traits::Zero;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::ops::{AddAssign, Div};
+
+/// The types of value that a [`MeanList`] can hold. This includes integers as well as
+/// floats; the means of integers are rounded down.
+pub trait MeanValue: Copy + PartialOrd + AddAssign + Div<Output = Self> + Zero + From<u8> {}
+
+impl<T> MeanValue for T where T: Copy + PartialOrd + AddAssign + Div<Output = T> + Zero + From<u8> {}
+
+/// What a [`MeanList`] does once it's full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    /// Squash pairs of means together, so that each mean covers twice as many values
+    /// as before, until each covers 32 values; then drop the oldest mean. The list
+    /// covers a longer and longer history, in less and less detail.
+    #[default]
+    CollapseToMean,
+    /// Drop the oldest value. The list always holds the most recent values as they were.
+    SlidingWindow,
+}
 
 pub struct MeanList<T>
 where
-    T: Float + AddAssign + Zero + From<u8>,
+    T: MeanValue,
 {
     period_sum: T,
     period_count: u8,
     mean_index: u8,
     means: [T; 20],
     ticks_per_mean: u8,
+    last_value: Option<T>,
+    overflow_policy: OverflowPolicy,
+}
+
+/// Everything needed to reconstruct a [`MeanList`] exactly. Unlike the form we send to
+/// feeds (just the means), this includes the partially accumulated period and how many
+/// values each mean covers, so that a restored list carries on as the original would.
+#[derive(Serialize, Deserialize)]
+struct MeanListState<T> {
+    period_sum: T,
+    period_count: u8,
+    means: Vec<T>,
+    ticks_per_mean: u8,
+    last_value: Option<T>,
+    overflow_policy: OverflowPolicy,
 }
 
 impl<T> Default for MeanList<T>
 where
-    T: Float + AddAssign + Zero + From<u8>,
+    T: MeanValue,
 {
     fn default() -> MeanList<T> {
         MeanList {
@@ -39,20 +73,62 @@ where
             mean_index: 0,
             means: [T::zero(); 20],
             ticks_per_mean: 1,
+            last_value: None,
+            overflow_policy: OverflowPolicy::default(),
         }
     }
 }
 
 impl<T> MeanList<T>
 where
-    T: Float + AddAssign + Zero + From<u8>,
+    T: MeanValue,
 {
+    /// A new, empty list which handles being full according to the policy given.
+    pub fn new(overflow_policy: OverflowPolicy) -> MeanList<T> {
+        MeanList {
+            overflow_policy,
+            ..MeanList::default()
+        }
+    }
+
     pub fn slice(&self) -> &[T] {
         &self.means[..usize::from(self.mean_index)]
     }
 
+    /// The mean of the means that we're holding, or `None` if we've not calculated any yet.
+    pub fn mean(&self) -> Option<T> {
+        let means = self.slice();
+        if means.is_empty() {
+            return None;
+        }
+        let mut sum = T::zero();
+        for &mean in means {
+            sum += mean;
+        }
+        Some(sum / std::convert::From::from(self.mean_index))
+    }
+
+    /// The given percentile (from 0 to 100) of the means that we're holding, using the
+    /// nearest-rank method, or `None` if we've not calculated any means yet. Since these
+    /// are means, this understates the spread of the values pushed once they're squashed.
+    pub fn percentile(&self, percentile: u8) -> Option<T> {
+        let mut means = self.slice().to_vec();
+        if means.is_empty() {
+            return None;
+        }
+        means.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let rank = (usize::from(percentile.min(100)) * means.len()).div_ceil(100);
+        Some(means[rank.max(1) - 1])
+    }
+
+    /// Push a value, returning `true` if this led to a new mean being calculated.
     pub fn push(&mut self, val: T) -> bool {
-        if self.mean_index == 20 && self.ticks_per_mean < 32 {
+        self.last_value = Some(val);
+
+        if self.mean_index == 20
+            && self.ticks_per_mean < 32
+            && self.overflow_policy == OverflowPolicy::CollapseToMean
+        {
             self.squash_means();
         }
 
@@ -67,10 +143,25 @@ where
         }
     }
 
+    /// Push a value only if it differs from the last value pushed, so that long runs
+    /// of identical values don't skew the means. Returns `true` if the value was pushed,
+    /// and `false` if it was a duplicate and so ignored.
+    pub fn push_if_changed(&mut self, val: T) -> bool {
+        if self.last_value == Some(val) {
+            return false;
+        }
+        self.push(val);
+        true
+    }
+
     fn push_mean(&mut self) {
         let mean = self.period_sum / std::convert::From::from(self.period_count);
 
-        if self.mean_index == 20 && self.ticks_per_mean == 32 {
+        let is_full = match self.overflow_policy {
+            OverflowPolicy::CollapseToMean => self.ticks_per_mean == 32,
+            OverflowPolicy::SlidingWindow => true,
+        };
+        if self.mean_index == 20 && is_full {
             self.means.rotate_left(1);
             self.means[19] = mean;
         } else {
@@ -82,6 +173,66 @@ where
         self.period_count = 0;
     }
 
+    /// Serialize everything needed to restore this list with [`MeanList::deserialize_state`].
+    /// This can be used with `#[serde(serialize_with = "MeanList::serialize_state")]`.
+    pub fn serialize_state<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Serialize,
+        S: Serializer,
+    {
+        MeanListState {
+            period_sum: self.period_sum,
+            period_count: self.period_count,
+            means: self.slice().to_vec(),
+            ticks_per_mean: self.ticks_per_mean,
+            last_value: self.last_value,
+            overflow_policy: self.overflow_policy,
+        }
+        .serialize(serializer)
+    }
+
+    /// Restore a list serialized with [`MeanList::serialize_state`]. This can be used
+    /// with `#[serde(deserialize_with = "MeanList::deserialize_state")]`.
+    pub fn deserialize_state<'de, D>(deserializer: D) -> Result<MeanList<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let state = MeanListState::<T>::deserialize(deserializer)?;
+        let mut means = [T::zero(); 20];
+        if state.means.len() > means.len() {
+            return Err(D::Error::invalid_length(
+                state.means.len(),
+                &"at most 20 means",
+            ));
+        }
+        if !matches!(state.ticks_per_mean, 1 | 2 | 4 | 8 | 16 | 32) {
+            return Err(D::Error::custom(format!(
+                "invalid ticks per mean: {}",
+                state.ticks_per_mean
+            )));
+        }
+        if state.period_count >= state.ticks_per_mean {
+            return Err(D::Error::custom(format!(
+                "period count {} should be less than ticks per mean {}",
+                state.period_count, state.ticks_per_mean
+            )));
+        }
+        means[..state.means.len()].copy_from_slice(&state.means);
+
+        Ok(MeanList {
+            period_sum: state.period_sum,
+            period_count: state.period_count,
+            mean_index: state.means.len() as u8,
+            means,
+            ticks_per_mean: state.ticks_per_mean,
+            last_value: state.last_value,
+            overflow_policy: state.overflow_policy,
+        })
+    }
+
     fn squash_means(&mut self) {
         self.ticks_per_mean *= 2;
         self.mean_index = 10;
@@ -93,3 +244,209 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn push_if_changed_ignores_duplicates() {
+        let mut list = MeanList::<f64>::default();
+
+        assert!(list.push_if_changed(1.0));
+        assert!(!list.push_if_changed(1.0));
+        assert!(!list.push_if_changed(1.0));
+
+        // Only the first value made it into the list:
+        assert_eq!(list.slice(), &[1.0]);
+    }
+
+    #[test]
+    fn push_if_changed_pushes_different_values() {
+        let mut list = MeanList::<f64>::default();
+
+        assert!(list.push_if_changed(1.0));
+        assert!(list.push_if_changed(2.0));
+        assert!(!list.push_if_changed(2.0));
+        assert!(list.push_if_changed(1.0));
+
+        assert_eq!(list.slice(), &[1.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn push_if_changed_compares_against_values_from_push() {
+        let mut list = MeanList::<f64>::default();
+
+        list.push(3.0);
+        assert!(!list.push_if_changed(3.0));
+        assert!(list.push_if_changed(4.0));
+
+        assert_eq!(list.slice(), &[3.0, 4.0]);
+    }
+
+    #[test]
+    fn push_if_changed_reports_pushes_not_new_means() {
+        let mut list = MeanList::<f64>::default();
+        for val in 0..20 {
+            assert!(list.push_if_changed(val as f64));
+        }
+
+        // The list is full, so each mean now covers two values. A different value
+        // is still pushed, even though it doesn't complete a mean:
+        assert!(list.push_if_changed(20.0));
+        assert_eq!(list.slice().last(), Some(&18.5));
+        assert!(list.push_if_changed(21.0));
+        assert_eq!(list.slice().last(), Some(&20.5));
+    }
+
+    #[test]
+    fn percentile_of_means() {
+        let mut list = MeanList::<f32>::default();
+        assert_eq!(list.percentile(95), None);
+
+        for val in &[5.0, 1.0, 4.0, 2.0, 3.0] {
+            list.push(*val);
+        }
+        assert_eq!(list.percentile(0), Some(1.0));
+        assert_eq!(list.percentile(50), Some(3.0));
+        assert_eq!(list.percentile(95), Some(5.0));
+        assert_eq!(list.percentile(100), Some(5.0));
+
+        for val in 6..=20 {
+            list.push(val as f32);
+        }
+        assert_eq!(list.percentile(95), Some(19.0));
+    }
+
+    #[test]
+    fn overflow_policies_compared() {
+        let mut collapsing = MeanList::<f64>::new(OverflowPolicy::CollapseToMean);
+        let mut sliding = MeanList::<f64>::new(OverflowPolicy::SlidingWindow);
+        for val in 1..=22 {
+            collapsing.push(val as f64);
+            sliding.push(val as f64);
+        }
+
+        // Every value is now paired up with another into a single mean:
+        let squashed: Vec<f64> = (0..11).map(|i| i as f64 * 2.0 + 1.5).collect();
+        assert_eq!(collapsing.slice(), &squashed[..]);
+        collapsing.push(23.0);
+        collapsing.push(24.0);
+        assert_eq!(collapsing.slice().len(), 12);
+        assert_eq!(collapsing.slice()[11], 23.5);
+
+        // Only the oldest values were dropped:
+        let window: Vec<f64> = (3..=22).map(|i| i as f64).collect();
+        assert_eq!(sliding.slice(), &window[..]);
+        sliding.push(23.0);
+        assert_eq!(sliding.slice().len(), 20);
+        assert_eq!(sliding.slice()[0], 4.0);
+        assert_eq!(sliding.slice()[19], 23.0);
+    }
+
+    #[test]
+    fn default_overflow_policy_collapses_to_mean() {
+        let mut default = MeanList::<f64>::default();
+        let mut collapsing = MeanList::<f64>::new(OverflowPolicy::CollapseToMean);
+        for val in 1..=100 {
+            default.push(val as f64);
+            collapsing.push(val as f64);
+        }
+        assert_eq!(default.slice(), collapsing.slice());
+    }
+
+    #[test]
+    fn integer_means_are_rounded_down() {
+        let mut list = MeanList::<u64>::default();
+        for val in 1..=20 {
+            list.push(val);
+        }
+        assert_eq!(list.slice(), (1..=20).collect::<Vec<u64>>());
+
+        // Once full, pairs are squashed together into their (rounded down) means:
+        list.push(21);
+        assert_eq!(list.slice(), &[1, 3, 5, 7, 9, 11, 13, 15, 17, 19]);
+        list.push(22);
+        assert_eq!(list.slice(), &[1, 3, 5, 7, 9, 11, 13, 15, 17, 19, 21]);
+        assert_eq!(list.mean(), Some(11));
+        assert_eq!(list.percentile(50), Some(11));
+    }
+
+    fn restore<T>(list: &MeanList<T>) -> MeanList<T>
+    where
+        T: MeanValue + Serialize + for<'de> Deserialize<'de>,
+    {
+        let mut json = Vec::new();
+        list.serialize_state(&mut serde_json::Serializer::new(&mut json))
+            .unwrap();
+        MeanList::deserialize_state(&mut serde_json::Deserializer::from_slice(&json)).unwrap()
+    }
+
+    #[test]
+    fn restored_list_carries_on_like_the_original() {
+        for policy in [
+            OverflowPolicy::CollapseToMean,
+            OverflowPolicy::SlidingWindow,
+        ] {
+            let mut original = MeanList::<f64>::new(policy);
+            // Enough values to have squashed the means a few times, with a
+            // period part way through when we snapshot:
+            for val in 1..=101 {
+                original.push(val as f64);
+            }
+
+            let mut restored = restore(&original);
+            assert_eq!(restored.slice(), original.slice());
+            assert_eq!(restored.mean(), original.mean());
+
+            for val in 102..=300 {
+                assert_eq!(restored.push(val as f64), original.push(val as f64));
+                assert_eq!(restored.slice(), original.slice());
+            }
+            assert!(!restored.push_if_changed(300.0));
+        }
+    }
+
+    #[test]
+    fn empty_list_round_trips() {
+        let original = MeanList::<f32>::default();
+        let mut restored = restore(&original);
+        assert_eq!(restored.slice(), &[] as &[f32]);
+        restored.push(1.5);
+        assert_eq!(restored.slice(), &[1.5]);
+    }
+
+    #[test]
+    fn invalid_state_is_rejected() {
+        let restore = |json: &str| {
+            MeanList::<f64>::deserialize_state(&mut serde_json::Deserializer::from_str(json))
+        };
+        assert!(restore(
+            r#"{"period_sum":0,"period_count":0,"means":[],"ticks_per_mean":3,"last_value":null,"overflow_policy":"SlidingWindow"}"#
+        )
+        .is_err());
+        assert!(restore(
+            r#"{"period_sum":0,"period_count":2,"means":[],"ticks_per_mean":2,"last_value":null,"overflow_policy":"SlidingWindow"}"#
+        )
+        .is_err());
+        assert!(restore(
+            r#"{"period_sum":0,"period_count":0,"means":[1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21],"ticks_per_mean":1,"last_value":null,"overflow_policy":"SlidingWindow"}"#
+        )
+        .is_err());
+        assert!(restore(
+            r#"{"period_sum":1,"period_count":1,"means":[1,2],"ticks_per_mean":2,"last_value":1,"overflow_policy":"CollapseToMean"}"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn mean_of_means() {
+        let mut list = MeanList::<f64>::default();
+        assert_eq!(list.mean(), None);
+
+        list.push(1.0);
+        list.push(2.0);
+        list.push(6.0);
+        assert_eq!(list.mean(), Some(3.0));
+    }
+}
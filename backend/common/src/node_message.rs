@@ -19,7 +19,7 @@
 //! able to serialize these messages to bincode, and various serde attribtues aren't compatible
 //! with this, hence this separate internal representation.
 
-use crate::node_types::{Block, BlockHash, BlockNumber, NodeDetails};
+use crate::node_types::{Block, BlockHash, BlockNumber, NodeDetails, PalletTrieSizes};
 use serde::{Deserialize, Serialize};
 
 pub type NodeMessageId = u64;
@@ -67,7 +67,7 @@ pub enum Payload {
     AfgAuthoritySet(AfgAuthoritySet),
     AfgFinalizedBlocksUpTo,
     AuraPreSealedBlock,
-    PreparedBlockForProposing,
+    PreparedBlockForProposing(PreparedBlock),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -86,6 +86,32 @@ pub struct SystemInterval {
     pub finalized_hash: Option<BlockHash>,
     pub block: Option<Block>,
     pub used_state_cache_size: Option<f32>,
+    pub offchain_worker_queue_depth: Option<u32>,
+    pub swap_used_bytes: Option<u64>,
+    pub swap_total_bytes: Option<u64>,
+    pub wasm_heap_used_bytes: Option<u64>,
+    pub wasm_heap_limit_bytes: Option<u64>,
+    pub cpu_steal_pct: Option<f32>,
+    pub kademlia_queries_per_sec: Option<f32>,
+    pub kademlia_records_stored: Option<u32>,
+    pub block_announce_per_sec: Option<f32>,
+    pub transaction_per_sec: Option<f32>,
+    pub light_request_per_sec: Option<f32>,
+    pub load_avg_1m: Option<f32>,
+    pub load_avg_5m: Option<f32>,
+    pub load_avg_15m: Option<f32>,
+    pub cpu_cores: Option<u32>,
+    pub database_size_bytes: Option<u64>,
+    pub open_fd_count: Option<u32>,
+    pub fd_limit: Option<u32>,
+    pub ntp_offset_ms: Option<i32>,
+    pub avg_peer_reputation: Option<i32>,
+    pub gpu_name: Option<Box<str>>,
+    pub gpu_usage_pct: Option<f32>,
+    pub gpu_memory_used_bytes: Option<u64>,
+    /// How many bytes of the state trie each pallet takes up, largest first, and at most
+    /// [`crate::node_types::MAX_PALLET_TRIE_SIZES`] of them.
+    pub pallet_trie_sizes: Option<PalletTrieSizes>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -114,7 +140,61 @@ pub struct AfgAuthoritySet {
     pub authority_set_id: Box<str>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreparedBlock {
+    /// How long the node took to import the block it's preparing to build on,
+    /// from hearing about it to having imported it.
+    pub import_latency_ms: Option<u32>,
+}
+
+/// Nodes always send these messages, whatever their verbosity.
+pub const VERBOSITY_BASIC: u8 = 0;
+/// Nodes send these consensus messages at this verbosity or above.
+pub const VERBOSITY_CONSENSUS_INFO: u8 = 1;
+/// Nodes send these more detailed consensus messages at this verbosity or above.
+pub const VERBOSITY_CONSENSUS_DEBUG: u8 = 5;
+
 impl Payload {
+    /// The name of this message, as given in the "msg" field of the JSON sent by nodes.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Payload::SystemConnected(_) => "system.connected",
+            Payload::SystemInterval(_) => "system.interval",
+            Payload::BlockImport(_) => "block.import",
+            Payload::NotifyFinalized(_) => "notify.finalized",
+            Payload::TxPoolImport => "txpool.import",
+            Payload::AfgFinalized(_) => "afg.finalized",
+            Payload::AfgReceivedPrecommit(_) => "afg.received_precommit",
+            Payload::AfgReceivedPrevote(_) => "afg.received_prevote",
+            Payload::AfgReceivedCommit(_) => "afg.received_commit",
+            Payload::AfgAuthoritySet(_) => "afg.authority_set",
+            Payload::AfgFinalizedBlocksUpTo => "afg.finalized_blocks_up_to",
+            Payload::AuraPreSealedBlock => "aura.pre_sealed_block",
+            Payload::PreparedBlockForProposing(_) => "prepared_block_for_proposing",
+        }
+    }
+
+    /// The lowest telemetry verbosity at which nodes send this message, following the
+    /// levels that Substrate logs its telemetry at: the basics are always sent, while
+    /// consensus messages are only sent at higher verbosities.
+    pub fn verbosity(&self) -> u8 {
+        match self {
+            Payload::SystemConnected(_)
+            | Payload::SystemInterval(_)
+            | Payload::BlockImport(_)
+            | Payload::NotifyFinalized(_)
+            | Payload::TxPoolImport => VERBOSITY_BASIC,
+            Payload::AfgFinalized(_)
+            | Payload::AfgAuthoritySet(_)
+            | Payload::AfgFinalizedBlocksUpTo
+            | Payload::AuraPreSealedBlock
+            | Payload::PreparedBlockForProposing(_) => VERBOSITY_CONSENSUS_INFO,
+            Payload::AfgReceivedPrecommit(_)
+            | Payload::AfgReceivedPrevote(_)
+            | Payload::AfgReceivedCommit(_) => VERBOSITY_CONSENSUS_DEBUG,
+        }
+    }
+
     pub fn best_block(&self) -> Option<&Block> {
         match self {
             Payload::BlockImport(block) => Some(block),
@@ -168,6 +248,15 @@ mod tests {
                     validator: None,
                     network_id: None,
                     startup_time: None,
+                    chain_type: None,
+                    environment: None,
+                    pruning_mode: None,
+                    offchain_indexing: false,
+                    pending_upgrade_block: None,
+                    verbosity: None,
+                    publicly_reachable: None,
+                    message_schema: None,
+                    extra_info: Default::default(),
                 },
             }),
         });
@@ -185,6 +274,30 @@ mod tests {
                 finalized_hash: None,
                 block: None,
                 used_state_cache_size: None,
+                offchain_worker_queue_depth: None,
+                swap_used_bytes: None,
+                swap_total_bytes: None,
+                wasm_heap_used_bytes: None,
+                wasm_heap_limit_bytes: None,
+                cpu_steal_pct: None,
+                kademlia_queries_per_sec: None,
+                kademlia_records_stored: None,
+                block_announce_per_sec: None,
+                transaction_per_sec: None,
+                light_request_per_sec: None,
+                load_avg_1m: None,
+                load_avg_5m: None,
+                load_avg_15m: None,
+                cpu_cores: None,
+                database_size_bytes: None,
+                open_fd_count: None,
+                fd_limit: None,
+                ntp_offset_ms: None,
+                avg_peer_reputation: None,
+                gpu_name: None,
+                gpu_usage_pct: None,
+                gpu_memory_used_bytes: None,
+                pallet_trie_sizes: None,
             }),
         });
     }
@@ -17,8 +17,11 @@
 //! These types are partly used in [`crate::node_message`], but also stored and used
 //! more generally through the application.
 
+use parity_scale_codec::{Compact, Decode, DecodeAll, Encode, Input, Output};
 use serde::ser::{SerializeTuple, Serializer};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 
 use crate::{time, MeanList};
 
@@ -36,13 +39,287 @@ pub struct NodeDetails {
     pub validator: Option<Box<str>>,
     pub network_id: Option<Box<str>>,
     pub startup_time: Option<Box<str>>,
+    pub chain_type: Option<ChainType>,
+    /// An operator defined label for the environment that the node is running in
+    /// (eg "prod" or "staging"), so that nodes can be told apart and filtered.
+    pub environment: Option<Box<str>>,
+    /// How the node prunes old block state, if it tells us.
+    pub pruning_mode: Option<PruningMode>,
+    /// Whether the node has off-chain indexing enabled, which means that it writes
+    /// data to off-chain storage as blocks are imported and so needs more disk.
+    pub offchain_indexing: bool,
+    /// The block at which a runtime upgrade is scheduled to happen, if the node knows
+    /// of one.
+    pub pending_upgrade_block: Option<BlockNumber>,
+    /// The telemetry verbosity that the node is configured with, if it tells us. Nodes
+    /// only send messages at or below their verbosity; see [`Payload::verbosity`].
+    ///
+    /// [`Payload::verbosity`]: crate::node_message::Payload::verbosity
+    pub verbosity: Option<u8>,
+    /// Whether the node is configured as publicly reachable by anyone, rather than being
+    /// private (eg behind a NAT), if it tells us.
+    pub publicly_reachable: Option<bool>,
+    /// The shape of the messages that the node sends, if the shard that it's connected
+    /// to worked it out.
+    pub message_schema: Option<MessageSchema>,
+    /// Any additional, chain specific fields that the node reports about itself. These
+    /// are passed on to feeds untouched, and are bounded by [`bound_extra_info`].
+    #[serde(with = "extra_info_as_json")]
+    pub extra_info: HashMap<Box<str>, serde_json::Value>,
+}
+
+/// The shape of the telemetry messages that a node sends. Nodes don't tell us which
+/// they use, so it's worked out from the messages themselves. Older nodes send older
+/// shapes, and may be missing fields that newer nodes send.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageSchema {
+    /// Each message is a flat object, with the payload fields alongside `msg` and `ts`.
+    /// Nodes sending these can only report on one node per connection.
+    V1,
+    /// Each message wraps its payload in a `payload` object, alongside an `id` that lets
+    /// one connection report on several nodes.
+    V2,
+}
+
+/// The most entries that we'll keep in [`NodeDetails::extra_info`].
+pub const MAX_EXTRA_INFO_KEYS: usize = 20;
+/// The longest key, in bytes, that we'll keep in [`NodeDetails::extra_info`].
+pub const MAX_EXTRA_INFO_KEY_LEN: usize = 32;
+/// How deeply arrays and objects can be nested in a [`NodeDetails::extra_info`] value.
+pub const MAX_EXTRA_INFO_DEPTH: usize = 2;
+
+/// Drop any entries from some node reported extra info whose key is longer than
+/// [`MAX_EXTRA_INFO_KEY_LEN`] or whose value is nested more than [`MAX_EXTRA_INFO_DEPTH`]
+/// deep, and then keep no more than [`MAX_EXTRA_INFO_KEYS`] of what's left (preferring
+/// keys which sort first, so that the same info is always kept).
+pub fn bound_extra_info(
+    extra_info: HashMap<Box<str>, serde_json::Value>,
+) -> HashMap<Box<str>, serde_json::Value> {
+    let mut entries: Vec<_> = extra_info
+        .into_iter()
+        .filter(|(key, value)| {
+            key.len() <= MAX_EXTRA_INFO_KEY_LEN && json_depth(value) <= MAX_EXTRA_INFO_DEPTH
+        })
+        .collect();
+
+    entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    entries.truncate(MAX_EXTRA_INFO_KEYS);
+    entries.into_iter().collect()
+}
+
+/// Strip control characters (newlines, escape codes and the like) from a node name and
+/// cut it down to at most `max_len` characters. The same name is always sanitized in the
+/// same way, so a node keeps looking like the same node each time it connects. Names
+/// which are already fine are returned untouched.
+pub fn sanitize_node_name(name: Box<str>, max_len: usize) -> Box<str> {
+    let needs_sanitizing =
+        name.chars().any(char::is_control) || name.chars().nth(max_len).is_some();
+    if !needs_sanitizing {
+        return name;
+    }
+    name.chars()
+        .filter(|c| !c.is_control())
+        .take(max_len)
+        .collect()
+}
+
+/// How deeply arrays and objects are nested in some value; scalars have a depth of 0.
+fn json_depth(value: &serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::Array(items) => 1 + items.iter().map(json_depth).max().unwrap_or(0),
+        serde_json::Value::Object(fields) => 1 + fields.values().map(json_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Bincode can't deserialize a [`serde_json::Value`], since it doesn't know up front what
+/// shape the value will be, so the extra info is sent between the shard and core as a
+/// JSON encoded string instead.
+mod extra_info_as_json {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S>(
+        extra_info: &HashMap<Box<str>, serde_json::Value>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // Keys are sorted so that the same info is always encoded in the same way:
+        let sorted: std::collections::BTreeMap<_, _> = extra_info.iter().collect();
+        serde_json::to_string(&sorted)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<HashMap<Box<str>, serde_json::Value>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let json = String::deserialize(deserializer)?;
+        serde_json::from_str(&json).map_err(D::Error::custom)
+    }
+}
+
+/// The environment that nodes which don't report one are considered to be in.
+pub const UNKNOWN_ENVIRONMENT: &str = "unknown";
+
+impl NodeDetails {
+    /// The environment that the node reports being in, or [`UNKNOWN_ENVIRONMENT`].
+    pub fn environment(&self) -> &str {
+        self.environment.as_deref().unwrap_or(UNKNOWN_ENVIRONMENT)
+    }
+}
+
+/// How a node prunes the state of old blocks. This is sent over the wire as the
+/// number of blocks kept, with archive nodes (which keep everything) being `u32::MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruningMode {
+    Archive,
+    Constrained { keep_blocks: u32 },
+}
+
+impl PruningMode {
+    /// The pruning mode for a node that keeps the state of the given number of blocks.
+    pub fn from_keep_blocks(keep_blocks: u32) -> PruningMode {
+        if keep_blocks == u32::MAX {
+            PruningMode::Archive
+        } else {
+            PruningMode::Constrained { keep_blocks }
+        }
+    }
+
+    /// The number of blocks whose state is kept; `u32::MAX` for archive nodes.
+    pub fn keep_blocks(self) -> u32 {
+        match self {
+            PruningMode::Archive => u32::MAX,
+            PruningMode::Constrained { keep_blocks } => keep_blocks,
+        }
+    }
+}
+
+impl Serialize for PruningMode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u32(self.keep_blocks())
+    }
+}
+
+impl<'de> Deserialize<'de> for PruningMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        u32::deserialize(deserializer).map(PruningMode::from_keep_blocks)
+    }
+}
+
+/// The kind of chain that a node is running. Chain names alone are an
+/// ambiguous guide to this, so nodes can tell us explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChainType {
+    RelayChain = 0,
+    Parachain = 1,
+    SoloChain = 2,
+    Testnet = 3,
+    Devnet = 4,
+}
+
+impl ChainType {
+    /// Every chain type, in order of their `u8` representation.
+    pub const ALL: [ChainType; 5] = [
+        ChainType::RelayChain,
+        ChainType::Parachain,
+        ChainType::SoloChain,
+        ChainType::Testnet,
+        ChainType::Devnet,
+    ];
+
+    /// Work out the chain type from either the name of a chain type (for example
+    /// "relay_chain" or "Parachain") or the name of a well known chain (for example
+    /// "Kusama" or "Rococo"). Returns `None` if we don't recognise the string.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<ChainType> {
+        let s: String = s
+            .trim()
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '_' | '-'))
+            .flat_map(char::to_lowercase)
+            .collect();
+
+        let chain_type = match &*s {
+            // Chain type names:
+            "relaychain" | "relay" => ChainType::RelayChain,
+            "parachain" => ChainType::Parachain,
+            "solochain" | "solo" => ChainType::SoloChain,
+            "testnet" => ChainType::Testnet,
+            "devnet" | "development" | "local" | "localtestnet" => ChainType::Devnet,
+            // Well known chains:
+            "polkadot" | "kusama" => ChainType::RelayChain,
+            "westend" | "rococo" | "paseo" => ChainType::Testnet,
+            "statemint" | "statemine" | "polkadotassethub" | "kusamaassethub" | "moonbeam"
+            | "moonriver" | "acala" | "karura" | "astar" | "shiden" => ChainType::Parachain,
+            _ => return None,
+        };
+        Some(chain_type)
+    }
+
+    /// The chain type whose `u8` representation is given, if any.
+    pub fn from_u8(n: u8) -> Option<ChainType> {
+        ChainType::ALL.get(n as usize).copied()
+    }
+
+    /// Chains of this type are expected to be less stable than production chains.
+    pub fn is_unstable(self) -> bool {
+        matches!(self, ChainType::Testnet | ChainType::Devnet)
+    }
+}
+
+impl Serialize for ChainType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for ChainType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let n = u8::deserialize(deserializer)?;
+        ChainType::from_u8(n)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid chain type: {}", n)))
+    }
 }
 
 /// A couple of node statistics.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct NodeStats {
     pub peers: u64,
     pub txcount: u64,
+    /// How much of the runtime's WASM heap is in use, if the node reports it.
+    pub wasm_heap_used_bytes: Option<u64>,
+    /// How large the runtime's WASM heap is allowed to grow, if the node reports it.
+    pub wasm_heap_limit_bytes: Option<u64>,
+    /// How many block announcements the node receives from its peers each second.
+    pub block_announce_per_sec: f32,
+    /// How many transactions the node receives from its peers each second.
+    pub transaction_per_sec: f32,
+    /// How many light client requests the node receives from its peers each second.
+    pub light_request_per_sec: f32,
+    /// How large the node's database is on disk, in bytes, if the node reports it.
+    pub database_size_bytes: Option<u64>,
+    /// The average reputation score that the node's peers have given it, if the node
+    /// reports it. Very negative scores mean that its peers think it's misbehaving.
+    pub avg_peer_reputation: Option<i32>,
 }
 
 // # A note about serialization/deserialization of types in this file:
@@ -59,9 +336,16 @@ impl Serialize for NodeStats {
     where
         S: Serializer,
     {
-        let mut tup = serializer.serialize_tuple(2)?;
+        let mut tup = serializer.serialize_tuple(9)?;
         tup.serialize_element(&self.peers)?;
         tup.serialize_element(&self.txcount)?;
+        tup.serialize_element(&self.wasm_heap_used_bytes)?;
+        tup.serialize_element(&self.wasm_heap_limit_bytes)?;
+        tup.serialize_element(&self.block_announce_per_sec)?;
+        tup.serialize_element(&self.transaction_per_sec)?;
+        tup.serialize_element(&self.light_request_per_sec)?;
+        tup.serialize_element(&self.database_size_bytes)?;
+        tup.serialize_element(&self.avg_peer_reputation)?;
         tup.end()
     }
 }
@@ -71,15 +355,60 @@ impl<'de> Deserialize<'de> for NodeStats {
     where
         D: serde::Deserializer<'de>,
     {
-        let (peers, txcount) = <(u64, u64)>::deserialize(deserializer)?;
-        Ok(NodeStats { peers, txcount })
+        let (
+            peers,
+            txcount,
+            wasm_heap_used_bytes,
+            wasm_heap_limit_bytes,
+            block_announce_per_sec,
+            transaction_per_sec,
+            light_request_per_sec,
+            database_size_bytes,
+            avg_peer_reputation,
+        ) = <(
+            u64,
+            u64,
+            Option<u64>,
+            Option<u64>,
+            f32,
+            f32,
+            f32,
+            Option<u64>,
+            Option<i32>,
+        )>::deserialize(deserializer)?;
+        Ok(NodeStats {
+            peers,
+            txcount,
+            wasm_heap_used_bytes,
+            wasm_heap_limit_bytes,
+            block_announce_per_sec,
+            transaction_per_sec,
+            light_request_per_sec,
+            database_size_bytes,
+            avg_peer_reputation,
+        })
     }
 }
 
+/// The most pallets whose state trie size we'll keep for a node.
+pub const MAX_PALLET_TRIE_SIZES: usize = 50;
+
+/// How many bytes of the state trie each pallet takes up, by pallet name, largest first.
+pub type PalletTrieSizes = Box<[(Box<str>, u64)]>;
+
 /// Node IO details.
 #[derive(Default)]
 pub struct NodeIO {
     pub used_state_cache_size: MeanList<f32>,
+    /// How many tasks are waiting in the offchain worker queue, if the node reports it.
+    pub offchain_worker_queue_depth: Option<u32>,
+    /// How many Kademlia DHT queries the node is handling each second.
+    pub kademlia_queries_per_sec: MeanList<f32>,
+    /// How many records the node's Kademlia DHT is storing, if the node reports it.
+    pub kademlia_records_stored: Option<u32>,
+    /// How many bytes of the state trie each pallet takes up, largest first, if the
+    /// node reports it.
+    pub pallet_trie_sizes: Option<PalletTrieSizes>,
 }
 
 impl Serialize for NodeIO {
@@ -87,9 +416,21 @@ impl Serialize for NodeIO {
     where
         S: Serializer,
     {
-        let mut tup = serializer.serialize_tuple(1)?;
+        let len = if self.pallet_trie_sizes.is_some() {
+            5
+        } else {
+            4
+        };
+        let mut tup = serializer.serialize_tuple(len)?;
         // This is "one-way": we can't deserialize again from this to a MeanList:
         tup.serialize_element(self.used_state_cache_size.slice())?;
+        tup.serialize_element(&self.offchain_worker_queue_depth)?;
+        tup.serialize_element(self.kademlia_queries_per_sec.slice())?;
+        tup.serialize_element(&self.kademlia_records_stored)?;
+        // Few nodes report this, so the rest aren't sent anything about it:
+        if let Some(sizes) = &self.pallet_trie_sizes {
+            tup.serialize_element(sizes)?;
+        }
         tup.end()
     }
 }
@@ -108,6 +449,111 @@ impl Block {
             height: 0,
         }
     }
+
+    /// A short, canonical description of the block for log messages, like
+    /// `height=1234567 hash=0xdeadbeef...cafebabe`. Only the first and last four
+    /// bytes of the hash are shown.
+    pub fn to_log_string(&self) -> String {
+        let hash = self.hash.as_bytes();
+        format!(
+            "height={} hash=0x{}...{}",
+            self.height,
+            hex::encode(&hash[..4]),
+            hex::encode(&hash[hash.len() - 4..])
+        )
+    }
+
+    /// The block as a JSON object with `height` and (full) `hash` fields, for
+    /// structured log consumers.
+    pub fn to_json_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "height": self.height,
+            "hash": format!("{:#x}", self.hash),
+        })
+    }
+
+    /// Pair this block with its age at `now`, given the time at which it was produced.
+    /// A block produced "in the future" (eg due to clock skew) has an age of zero.
+    pub fn with_age(self, timestamp: Timestamp, now: Timestamp) -> BlockAge {
+        BlockAge {
+            block: self,
+            age_ms: now.saturating_sub(timestamp),
+        }
+    }
+
+    /// Could this block be an ancestor of the one given? We only look at block heights
+    /// here, so this is approximate; a `true` means "maybe", but a `false` is certain.
+    pub fn could_be_ancestor_of(&self, descendant: &Block) -> bool {
+        self.height < descendant.height
+    }
+
+    /// An approximate height for the most recent common ancestor of two blocks, assuming
+    /// that the shorter branch branched off from the taller one (so the shorter block is
+    /// itself the common ancestor). Different blocks at the same height have diverged at
+    /// some unknown height, so we can't guess and return `None`.
+    pub fn common_ancestor_height(a: &Block, b: &Block) -> Option<BlockNumber> {
+        if a.height == b.height && a.hash != b.hash {
+            None
+        } else {
+            Some(a.height.min(b.height))
+        }
+    }
+
+    /// Is a node's head block consistent with the genesis block of its chain? A head at
+    /// height 0 is itself a genesis block, so must be the same one. Heads at any other
+    /// height can't be checked against the genesis block, and are assumed to be fine.
+    pub fn verify_genesis_consistency(genesis: &Block, chain_head: &Block) -> bool {
+        chain_head.height != 0 || chain_head.hash == genesis.hash
+    }
+
+    /// Do two blocks have the same genesis hash? We can only tell if both are at height 0,
+    /// in which case their hashes must match; otherwise there's nothing to compare.
+    pub fn genesis_hash_matches(a: &Block, b: &Block) -> bool {
+        a.height != 0 || b.height != 0 || a.hash == b.hash
+    }
+}
+
+/// A block, along with how long ago it was produced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockAge {
+    pub block: Block,
+    pub age_ms: u64,
+}
+
+impl Serialize for BlockAge {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tup = serializer.serialize_tuple(3)?;
+        tup.serialize_element(&self.block.height)?;
+        tup.serialize_element(&self.block.hash)?;
+        tup.serialize_element(&self.age_ms)?;
+        tup.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockAge {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let (height, hash, age_ms) = <(u64, BlockHash, u64)>::deserialize(deserializer)?;
+        Ok(BlockAge {
+            block: Block { hash, height },
+            age_ms,
+        })
+    }
+}
+
+impl fmt::Display for BlockAge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} ({:?}), {}ms old",
+            self.block.height, self.block.hash, self.age_ms
+        )
+    }
 }
 
 /// Node hardware details.
@@ -119,6 +565,93 @@ pub struct NodeHardware {
     pub download: MeanList<f64>,
     /// Stampchange uses means
     pub chart_stamps: MeanList<f64>,
+    /// How much swap the node is using, if it reports it.
+    pub swap_used_bytes: Option<u64>,
+    /// How much swap the node has in total, if it reports it.
+    pub swap_total_bytes: Option<u64>,
+    /// How long the node takes to import blocks, in milliseconds
+    pub import_latency_ms: MeanList<f32>,
+    /// The percentage of CPU time taken away from the node by the hypervisor
+    /// ("steal" time), if it runs in a VM and reports it
+    pub cpu_steal_pct: MeanList<f32>,
+    /// The node's Unix load averages over the last 1, 5 and 15 minutes, if it reports them.
+    pub load_avg_1m: Option<f32>,
+    pub load_avg_5m: Option<f32>,
+    pub load_avg_15m: Option<f32>,
+    /// How many CPU cores the node has, if it reports it.
+    pub cpu_cores: Option<u32>,
+    /// How many file descriptors the node has open, if it reports it.
+    pub open_fd_count: Option<u32>,
+    /// How many file descriptors the node is allowed to open, if it reports it.
+    pub fd_limit: Option<u32>,
+    /// How far the node's clock is from NTP time in milliseconds, if it reports it.
+    /// This is negative if the node's clock is behind.
+    pub ntp_offset_ms: Option<i32>,
+    /// The name of the GPU the node uses to accelerate its work, if it has one and reports it.
+    pub gpu_name: Option<Box<str>>,
+    /// How busy the node's GPU is as a percentage, once the node has reported it.
+    pub gpu_usage_pct: Option<MeanList<f32>>,
+    /// How much of its GPU's memory the node is using in bytes, once the node has reported it.
+    pub gpu_memory_used_bytes: Option<MeanList<u64>>,
+    /// How much the node has uploaded since we first saw it. This isn't sent to feeds.
+    pub upload_total: CumulativeBytes,
+    /// How much the node has downloaded since we first saw it. This isn't sent to feeds.
+    pub download_total: CumulativeBytes,
+}
+
+impl NodeHardware {
+    /// The percentile of import latency that we report and alert on.
+    pub const IMPORT_LATENCY_PERCENTILE: u8 = 95;
+
+    /// The 95th percentile of how long the node takes to import blocks, if it's told us.
+    pub fn import_latency_p95_ms(&self) -> Option<f32> {
+        self.import_latency_ms
+            .percentile(Self::IMPORT_LATENCY_PERCENTILE)
+    }
+
+    /// Has the node told us anything about a GPU?
+    pub fn has_gpu(&self) -> bool {
+        self.gpu_name.is_some()
+            || self.gpu_usage_pct.is_some()
+            || self.gpu_memory_used_bytes.is_some()
+    }
+}
+
+/// A running total of the bytes that a node has transferred one way, worked out from the
+/// rates (in bytes per second) that it reports. Each rate is the node's average since its
+/// last report, and so is counted for the time since the previous sample.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CumulativeBytes {
+    bytes: f64,
+    last_sample_at: Option<Timestamp>,
+}
+
+impl CumulativeBytes {
+    /// Samples further apart than this (in milliseconds) aren't integrated across, since we
+    /// don't know what the node transferred in between; it may have been disconnected, or
+    /// its messages lost.
+    pub const MAX_SAMPLE_GAP_MS: u64 = 60_000;
+
+    /// Take note of a rate that the node reported at the given time.
+    pub fn add_sample(&mut self, bytes_per_sec: f64, at: Timestamp) {
+        // If our clock has gone backwards, there's no telling how long it's been since the
+        // last sample, so we start afresh from this one:
+        let elapsed_ms = self
+            .last_sample_at
+            .and_then(|last| at.checked_sub(last))
+            .filter(|&ms| ms <= Self::MAX_SAMPLE_GAP_MS);
+        if let Some(ms) = elapsed_ms {
+            if bytes_per_sec.is_finite() && bytes_per_sec > 0.0 {
+                self.bytes += bytes_per_sec * ms as f64 / 1000.0;
+            }
+        }
+        self.last_sample_at = Some(at);
+    }
+
+    /// The total number of bytes transferred.
+    pub fn bytes(&self) -> u64 {
+        self.bytes as u64
+    }
 }
 
 impl Serialize for NodeHardware {
@@ -126,11 +659,32 @@ impl Serialize for NodeHardware {
     where
         S: Serializer,
     {
-        let mut tup = serializer.serialize_tuple(3)?;
+        let has_gpu = self.has_gpu();
+        let mut tup = serializer.serialize_tuple(if has_gpu { 16 } else { 15 })?;
         // These are "one-way": we can't deserialize again from them to MeanLists:
         tup.serialize_element(self.upload.slice())?;
         tup.serialize_element(self.download.slice())?;
         tup.serialize_element(self.chart_stamps.slice())?;
+        tup.serialize_element(&self.swap_used_bytes)?;
+        tup.serialize_element(&self.swap_total_bytes)?;
+        tup.serialize_element(&self.import_latency_ms.mean())?;
+        tup.serialize_element(&self.import_latency_p95_ms())?;
+        tup.serialize_element(self.cpu_steal_pct.slice())?;
+        tup.serialize_element(&self.load_avg_1m)?;
+        tup.serialize_element(&self.load_avg_5m)?;
+        tup.serialize_element(&self.load_avg_15m)?;
+        tup.serialize_element(&self.cpu_cores)?;
+        tup.serialize_element(&self.open_fd_count)?;
+        tup.serialize_element(&self.fd_limit)?;
+        tup.serialize_element(&self.ntp_offset_ms)?;
+        // Few nodes have a GPU, so the rest aren't sent anything about one:
+        if has_gpu {
+            tup.serialize_element(&(
+                &self.gpu_name,
+                self.gpu_usage_pct.as_ref().map(MeanList::slice),
+                self.gpu_memory_used_bytes.as_ref().map(MeanList::slice),
+            ))?;
+        }
         tup.end()
     }
 }
@@ -141,6 +695,24 @@ pub struct NodeLocation {
     pub latitude: f32,
     pub longitude: f32,
     pub city: Box<str>,
+    /// The two letter ISO 3166 country code, if known. This isn't sent to feeds.
+    pub country: Option<Box<str>>,
+}
+
+impl NodeLocation {
+    /// A less precise copy of this location, for when we don't want to give away exactly
+    /// where nodes are. The coordinates are rounded to the given number of decimal places
+    /// and the city is left blank; the country is kept.
+    pub fn anonymized(&self, decimal_places: u8) -> NodeLocation {
+        let scale = 10f32.powi(decimal_places as i32);
+        let round = |degrees: f32| (degrees * scale).round() / scale;
+        NodeLocation {
+            latitude: round(self.latitude),
+            longitude: round(self.longitude),
+            city: "".into(),
+            country: self.country.clone(),
+        }
+    }
 }
 
 impl Serialize for NodeLocation {
@@ -166,6 +738,7 @@ impl<'de> Deserialize<'de> for NodeLocation {
             latitude,
             longitude,
             city,
+            country: None,
         })
     }
 }
@@ -174,9 +747,16 @@ impl<'de> Deserialize<'de> for NodeLocation {
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct BlockDetails {
     pub block: Block,
+    /// The block time shown to feeds, which may have been smoothed.
     pub block_time: u64,
+    /// The block time as it was measured. This isn't sent to feeds.
+    pub raw_block_time: u64,
     pub block_timestamp: u64,
     pub propagation_time: Option<u64>,
+    /// On average, how long after the block was first announced by any node
+    /// this node announces it. Unlike propagation time, this isn't affected by
+    /// clock skew, since every time involved is from the server's clock.
+    pub announcement_latency: Option<u64>,
 }
 
 impl Default for BlockDetails {
@@ -185,22 +765,39 @@ impl Default for BlockDetails {
             block: Block::zero(),
             block_timestamp: time::now(),
             block_time: 0,
+            raw_block_time: 0,
             propagation_time: None,
+            announcement_latency: None,
         }
     }
 }
 
+impl BlockDetails {
+    /// Are these details about the same block as the other details? Only the block
+    /// hash is compared, so the timings may differ. Use [`BlockDetails::same_state`]
+    /// to compare every field.
+    pub fn same_block(&self, other: &BlockDetails) -> bool {
+        self.block.hash == other.block.hash
+    }
+
+    /// Are these details identical to the other details, timings and all?
+    pub fn same_state(&self, other: &BlockDetails) -> bool {
+        self == other
+    }
+}
+
 impl Serialize for BlockDetails {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut tup = serializer.serialize_tuple(5)?;
+        let mut tup = serializer.serialize_tuple(6)?;
         tup.serialize_element(&self.block.height)?;
         tup.serialize_element(&self.block.hash)?;
         tup.serialize_element(&self.block_time)?;
         tup.serialize_element(&self.block_timestamp)?;
         tup.serialize_element(&self.propagation_time)?;
+        tup.serialize_element(&self.announcement_latency)?;
         tup.end()
     }
 }
@@ -210,15 +807,662 @@ impl<'de> Deserialize<'de> for BlockDetails {
     where
         D: serde::Deserializer<'de>,
     {
-        let tup = <(u64, BlockHash, u64, u64, Option<u64>)>::deserialize(deserializer)?;
+        let tup =
+            <(u64, BlockHash, u64, u64, Option<u64>, Option<u64>)>::deserialize(deserializer)?;
         Ok(BlockDetails {
             block: Block {
                 height: tup.0,
                 hash: tup.1,
             },
             block_time: tup.2,
+            raw_block_time: tup.2,
             block_timestamp: tup.3,
             propagation_time: tup.4,
+            announcement_latency: tup.5,
+        })
+    }
+}
+
+/// The error we get back if bytes can't be decoded into block details.
+pub use parity_scale_codec::Error as DecodeError;
+
+impl Encode for Block {
+    fn size_hint(&self) -> usize {
+        self.hash.size_hint() + Compact(self.height).size_hint()
+    }
+
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        self.hash.encode_to(dest);
+        Compact(self.height).encode_to(dest);
+    }
+}
+
+impl Decode for Block {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, DecodeError> {
+        Ok(Block {
+            hash: BlockHash::decode(input)?,
+            height: <Compact<BlockNumber>>::decode(input)?.0,
+        })
+    }
+}
+
+// Timings are SCALE compact encoded, since most of them are small. Unlike the
+// JSON sent to feeds, every field is kept, so the details decode exactly as they were.
+impl Encode for BlockDetails {
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        self.block.encode_to(dest);
+        Compact(self.block_time).encode_to(dest);
+        Compact(self.raw_block_time).encode_to(dest);
+        Compact(self.block_timestamp).encode_to(dest);
+        self.propagation_time.map(Compact).encode_to(dest);
+        self.announcement_latency.map(Compact).encode_to(dest);
+    }
+}
+
+impl Decode for BlockDetails {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, DecodeError> {
+        Ok(BlockDetails {
+            block: Block::decode(input)?,
+            block_time: <Compact<u64>>::decode(input)?.0,
+            raw_block_time: <Compact<u64>>::decode(input)?.0,
+            block_timestamp: <Compact<Timestamp>>::decode(input)?.0,
+            propagation_time: <Option<Compact<u64>>>::decode(input)?.map(|c| c.0),
+            announcement_latency: <Option<Compact<u64>>>::decode(input)?.map(|c| c.0),
         })
     }
 }
+
+impl BlockDetails {
+    /// A compact binary (SCALE) encoding of these details, for storing them.
+    pub fn encode_to_bytes(&self) -> Vec<u8> {
+        self.encode()
+    }
+
+    /// Decode details previously encoded with [`BlockDetails::encode_to_bytes`].
+    /// Every byte must be used up, so trailing garbage is an error.
+    pub fn decode_from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        BlockDetails::decode_all(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(height: BlockNumber) -> Block {
+        Block {
+            hash: BlockHash::from_low_u64_be(height),
+            height,
+        }
+    }
+
+    fn known_block() -> Block {
+        let mut hash = [0x11; 32];
+        hash[..4].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        hash[28..].copy_from_slice(&[0xca, 0xfe, 0xba, 0xbe]);
+        Block {
+            hash: BlockHash::from(hash),
+            height: 1234567,
+        }
+    }
+
+    #[test]
+    fn block_log_string() {
+        assert_eq!(
+            known_block().to_log_string(),
+            "height=1234567 hash=0xdeadbeef...cafebabe"
+        );
+        assert_eq!(
+            Block::zero().to_log_string(),
+            "height=0 hash=0x00000000...00000000"
+        );
+    }
+
+    #[test]
+    fn block_json_value() {
+        assert_eq!(
+            known_block().to_json_value(),
+            serde_json::json!({
+                "height": 1234567,
+                "hash": "0xdeadbeef111111111111111111111111111111111111111111111111cafebabe",
+            })
+        );
+    }
+
+    #[test]
+    fn chain_type_from_str() {
+        assert_eq!(
+            ChainType::from_str("relay_chain"),
+            Some(ChainType::RelayChain)
+        );
+        assert_eq!(ChainType::from_str("Parachain"), Some(ChainType::Parachain));
+        assert_eq!(
+            ChainType::from_str("solo-chain"),
+            Some(ChainType::SoloChain)
+        );
+        assert_eq!(ChainType::from_str("TESTNET"), Some(ChainType::Testnet));
+        assert_eq!(ChainType::from_str("Development"), Some(ChainType::Devnet));
+        assert_eq!(
+            ChainType::from_str("Local Testnet"),
+            Some(ChainType::Devnet)
+        );
+        assert_eq!(ChainType::from_str("Kusama"), Some(ChainType::RelayChain));
+        assert_eq!(ChainType::from_str("Moonbeam"), Some(ChainType::Parachain));
+        assert_eq!(ChainType::from_str(" Rococo "), Some(ChainType::Testnet));
+        assert_eq!(ChainType::from_str("Some Random Chain"), None);
+        assert_eq!(ChainType::from_str(""), None);
+    }
+
+    #[test]
+    fn chain_type_serializes_as_u8() {
+        for (n, chain_type) in ChainType::ALL.iter().copied().enumerate() {
+            let json = serde_json::to_string(&chain_type).unwrap();
+            assert_eq!(json, n.to_string());
+            assert_eq!(
+                serde_json::from_str::<ChainType>(&json).unwrap(),
+                chain_type
+            );
+        }
+        assert!(serde_json::from_str::<ChainType>("5").is_err());
+
+        let bytes = bincode::serialize(&Some(ChainType::Testnet)).unwrap();
+        let de: Option<ChainType> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(de, Some(ChainType::Testnet));
+    }
+
+    #[test]
+    fn constrained_pruning_serializes_as_keep_blocks() {
+        let mode = PruningMode::Constrained { keep_blocks: 256 };
+        let json = serde_json::to_string(&mode).unwrap();
+        assert_eq!(json, "256");
+        assert_eq!(serde_json::from_str::<PruningMode>(&json).unwrap(), mode);
+
+        let bytes = bincode::serialize(&Some(mode)).unwrap();
+        let de: Option<PruningMode> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(de, Some(mode));
+    }
+
+    #[test]
+    fn archive_pruning_serializes_as_u32_max() {
+        let json = serde_json::to_string(&PruningMode::Archive).unwrap();
+        assert_eq!(json, u32::MAX.to_string());
+        assert_eq!(
+            serde_json::from_str::<PruningMode>(&json).unwrap(),
+            PruningMode::Archive
+        );
+
+        let bytes = bincode::serialize(&Some(PruningMode::Archive)).unwrap();
+        let de: Option<PruningMode> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(de, Some(PruningMode::Archive));
+    }
+
+    #[test]
+    fn anonymized_location_is_rounded_to_the_grid() {
+        let location = NodeLocation {
+            latitude: 52.5163,
+            longitude: -13.3777,
+            city: "Berlin".into(),
+            country: Some("DE".into()),
+        };
+
+        assert_eq!(
+            location.anonymized(1),
+            NodeLocation {
+                latitude: 52.5,
+                longitude: -13.4,
+                city: "".into(),
+                country: Some("DE".into()),
+            }
+        );
+
+        let whole_degrees = location.anonymized(0);
+        assert_eq!(whole_degrees.latitude, 53.0);
+        assert_eq!(whole_degrees.longitude, -13.0);
+    }
+
+    fn extra_info(json: serde_json::Value) -> HashMap<Box<str>, serde_json::Value> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn extra_info_values_nested_too_deeply_are_dropped() {
+        let bounded = bound_extra_info(extra_info(serde_json::json!({
+            "scalar": 1,
+            "flat": [1, 2, 3],
+            "nested": { "a": [1, 2], "b": "c" },
+            "too_deep": { "a": { "b": [1] } },
+            "empty_too_deep": [[[]]],
+        })));
+
+        let mut keys: Vec<_> = bounded.keys().map(|k| &**k).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["flat", "nested", "scalar"]);
+    }
+
+    #[test]
+    fn extra_info_keys_that_are_too_long_are_dropped() {
+        let longest = "k".repeat(MAX_EXTRA_INFO_KEY_LEN);
+        let too_long = "k".repeat(MAX_EXTRA_INFO_KEY_LEN + 1);
+        let bounded = bound_extra_info(extra_info(serde_json::json!({
+            longest.clone(): true,
+            too_long: true,
+        })));
+
+        assert_eq!(bounded.len(), 1);
+        assert!(bounded.contains_key(&*longest));
+    }
+
+    #[test]
+    fn extra_info_keeps_the_first_keys_when_there_are_too_many() {
+        let info = (0..MAX_EXTRA_INFO_KEYS + 5)
+            .map(|n| {
+                (
+                    format!("key{:02}", n).into_boxed_str(),
+                    serde_json::json!(n),
+                )
+            })
+            .collect();
+        let bounded = bound_extra_info(info);
+
+        assert_eq!(bounded.len(), MAX_EXTRA_INFO_KEYS);
+        assert!(bounded.contains_key("key00"));
+        assert!(!bounded.contains_key(&*format!("key{:02}", MAX_EXTRA_INFO_KEYS)));
+    }
+
+    #[test]
+    fn node_names_lose_control_characters() {
+        assert_eq!(
+            &*sanitize_node_name("Al\u{1b}[31mice\n\r\t\u{7f}".into(), 64),
+            "Al[31mice"
+        );
+        assert_eq!(&*sanitize_node_name("\u{0}\u{1f}".into(), 64), "");
+        // Other unicode is left alone:
+        assert_eq!(&*sanitize_node_name("ノード 🚀".into(), 64), "ノード 🚀");
+    }
+
+    #[test]
+    fn node_names_are_truncated_to_the_max_length() {
+        let long_name = "é".repeat(100);
+        let sanitized = sanitize_node_name(long_name.into(), 64);
+        assert_eq!(sanitized.chars().count(), 64);
+        assert!(sanitized.chars().all(|c| c == 'é'));
+
+        // Control characters don't count towards the length:
+        assert_eq!(&*sanitize_node_name("\nAlice\n".into(), 5), "Alice");
+        assert_eq!(&*sanitize_node_name("Alice".into(), 5), "Alice");
+    }
+
+    #[test]
+    fn node_names_are_sanitized_consistently() {
+        let name = "Bo\u{8}b the \u{7}node";
+        assert_eq!(
+            sanitize_node_name(name.into(), 8),
+            sanitize_node_name(name.into(), 8)
+        );
+        let sanitized = sanitize_node_name(name.into(), 8);
+        assert_eq!(sanitize_node_name(sanitized.clone(), 8), sanitized);
+    }
+
+    #[test]
+    fn extra_info_survives_bincode() {
+        #[derive(Serialize, Deserialize)]
+        struct Wrapper(#[serde(with = "extra_info_as_json")] HashMap<Box<str>, serde_json::Value>);
+
+        let info = extra_info(serde_json::json!({ "a": [1, "two"], "b": { "c": null } }));
+        let bytes = bincode::serialize(&Wrapper(info.clone())).unwrap();
+        let de: Wrapper = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(de.0, info);
+    }
+
+    #[test]
+    fn node_stats_serialize_p2p_message_rates() {
+        let stats = NodeStats {
+            peers: 12,
+            txcount: 3,
+            wasm_heap_used_bytes: Some(100),
+            wasm_heap_limit_bytes: None,
+            block_announce_per_sec: 0.5,
+            transaction_per_sec: 20.25,
+            light_request_per_sec: 150.0,
+            database_size_bytes: None,
+            avg_peer_reputation: None,
+        };
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(json, "[12,3,100,null,0.5,20.25,150.0,null,null]");
+        assert_eq!(serde_json::from_str::<NodeStats>(&json).unwrap(), stats);
+    }
+
+    #[test]
+    fn node_stats_serialize_peer_reputation() {
+        for (reputation, json) in [
+            (Some(250), "250"),
+            (Some(0), "0"),
+            (Some(-1000), "-1000"),
+            (Some(i32::MIN), "-2147483648"),
+            (None, "null"),
+        ]
+        .iter()
+        .copied()
+        {
+            let stats = NodeStats {
+                avg_peer_reputation: reputation,
+                ..NodeStats::default()
+            };
+            let serialized = serde_json::to_string(&stats).unwrap();
+            assert_eq!(
+                serialized,
+                format!("[0,0,null,null,0.0,0.0,0.0,null,{}]", json)
+            );
+            assert_eq!(
+                serde_json::from_str::<NodeStats>(&serialized).unwrap(),
+                stats
+            );
+        }
+    }
+
+    #[test]
+    fn node_hardware_serializes_file_descriptors() {
+        let mut hardware = NodeHardware::default();
+        assert_eq!(
+            serde_json::to_string(&hardware).unwrap(),
+            "[[],[],[],null,null,null,null,[],null,null,null,null,null,null,null]"
+        );
+
+        hardware.open_fd_count = Some(900);
+        hardware.fd_limit = Some(1024);
+        assert_eq!(
+            serde_json::to_string(&hardware).unwrap(),
+            "[[],[],[],null,null,null,null,[],null,null,null,null,900,1024,null]"
+        );
+    }
+
+    #[test]
+    fn node_hardware_serializes_gpu_only_when_reported() {
+        let mut hardware = NodeHardware::default();
+        assert!(!hardware.has_gpu());
+        assert_eq!(
+            serde_json::to_string(&hardware).unwrap(),
+            "[[],[],[],null,null,null,null,[],null,null,null,null,null,null,null]"
+        );
+
+        hardware.gpu_name = Some("NVIDIA A100".into());
+        assert_eq!(
+            serde_json::to_string(&hardware).unwrap(),
+            r#"[[],[],[],null,null,null,null,[],null,null,null,null,null,null,null,["NVIDIA A100",null,null]]"#
+        );
+
+        let mut usage = MeanList::default();
+        usage.push(95.5);
+        let mut memory = MeanList::default();
+        memory.push(1024);
+        hardware.gpu_usage_pct = Some(usage);
+        hardware.gpu_memory_used_bytes = Some(memory);
+        assert_eq!(
+            serde_json::to_string(&hardware).unwrap(),
+            r#"[[],[],[],null,null,null,null,[],null,null,null,null,null,null,null,["NVIDIA A100",[95.5],[1024]]]"#
+        );
+    }
+
+    #[test]
+    fn node_hardware_serializes_negative_ntp_offset() {
+        let hardware = NodeHardware {
+            ntp_offset_ms: Some(-250),
+            ..NodeHardware::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&hardware).unwrap(),
+            "[[],[],[],null,null,null,null,[],null,null,null,null,null,null,-250]"
+        );
+    }
+
+    /// Add a sample of the given rate at each of the given times.
+    fn cumulative_bytes(samples: &[(Timestamp, f64)]) -> u64 {
+        let mut total = CumulativeBytes::default();
+        for &(at, bytes_per_sec) in samples {
+            total.add_sample(bytes_per_sec, at);
+        }
+        total.bytes()
+    }
+
+    #[test]
+    fn cumulative_bytes_integrates_rates_over_time() {
+        // The first sample has nothing before it to count from:
+        assert_eq!(cumulative_bytes(&[(1_000, 500.0)]), 0);
+        assert_eq!(
+            cumulative_bytes(&[(1_000, 500.0), (6_000, 100.0), (11_000, 200.0)]),
+            500 + 1000
+        );
+        // Samples that arrive late count for the time since the last one:
+        assert_eq!(
+            cumulative_bytes(&[(0, 100.0), (5_000, 100.0), (15_000, 300.0), (17_500, 100.0)]),
+            500 + 3000 + 250
+        );
+    }
+
+    #[test]
+    fn cumulative_bytes_skips_gaps_in_samples() {
+        let gap = CumulativeBytes::MAX_SAMPLE_GAP_MS;
+        assert_eq!(
+            cumulative_bytes(&[(0, 100.0), (gap, 100.0), (2 * gap + 1, 100.0)]),
+            gap / 10
+        );
+        // Counting picks up again from the sample after the gap:
+        assert_eq!(
+            cumulative_bytes(&[(0, 100.0), (gap + 1, 100.0), (gap + 5_001, 100.0)]),
+            500
+        );
+    }
+
+    #[test]
+    fn cumulative_bytes_survives_clock_changes_and_bad_rates() {
+        // The clock going backwards counts for nothing:
+        assert_eq!(
+            cumulative_bytes(&[(10_000, 100.0), (5_000, 100.0), (6_000, 100.0)]),
+            100
+        );
+        // Samples at the same time count for nothing too:
+        assert_eq!(cumulative_bytes(&[(1_000, 100.0), (1_000, 100.0)]), 0);
+        // Rates that make no sense are ignored, but still mark the time:
+        assert_eq!(
+            cumulative_bytes(&[
+                (0, 100.0),
+                (1_000, -100.0),
+                (2_000, f64::NAN),
+                (3_000, f64::INFINITY),
+                (4_000, 100.0)
+            ]),
+            100
+        );
+    }
+
+    #[test]
+    fn block_details_same_block_only_compares_hash() {
+        let details = BlockDetails {
+            block: block(10),
+            block_time: 6_000,
+            raw_block_time: 6_000,
+            block_timestamp: 1_000,
+            propagation_time: Some(100),
+            announcement_latency: None,
+        };
+        let retimed = BlockDetails {
+            block_time: 5_000,
+            propagation_time: Some(200),
+            ..details
+        };
+        let other_block = BlockDetails {
+            block: block(11),
+            ..details
+        };
+
+        assert!(details.same_block(&details));
+        assert!(details.same_state(&details));
+
+        // Different timings for the same block:
+        assert!(details.same_block(&retimed));
+        assert!(!details.same_state(&retimed));
+
+        // Same timings for a different block:
+        assert!(!details.same_block(&other_block));
+        assert!(!details.same_state(&other_block));
+    }
+
+    fn known_details() -> BlockDetails {
+        BlockDetails {
+            block: known_block(),
+            block_time: 6_012,
+            raw_block_time: 5_987,
+            block_timestamp: 1_700_000_000_000,
+            propagation_time: Some(250),
+            announcement_latency: None,
+        }
+    }
+
+    #[test]
+    fn block_details_roundtrip_through_bytes() {
+        let details = known_details();
+        let bytes = details.encode_to_bytes();
+        assert_eq!(BlockDetails::decode_from_bytes(&bytes).unwrap(), details);
+
+        let zero = BlockDetails {
+            block: Block::zero(),
+            block_timestamp: 0,
+            ..details
+        };
+        let bytes = zero.encode_to_bytes();
+        assert_eq!(BlockDetails::decode_from_bytes(&bytes).unwrap(), zero);
+    }
+
+    #[test]
+    fn block_details_bytes_must_be_complete() {
+        let mut bytes = known_details().encode_to_bytes();
+        assert!(BlockDetails::decode_from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        bytes.push(0);
+        assert!(BlockDetails::decode_from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn block_details_bytes_are_smaller_than_json() {
+        let details = known_details();
+        let scale = details.encode_to_bytes().len();
+        let json = serde_json::to_vec(&details).unwrap().len();
+
+        // 32 byte hash, then compact height (4), block times (2 + 2),
+        // timestamp (7) and the two options (1 + 2, 1):
+        assert_eq!(scale, 51);
+        assert!(
+            scale * 2 < json,
+            "SCALE: {} bytes, JSON: {} bytes",
+            scale,
+            json
+        );
+    }
+
+    #[test]
+    fn block_age_is_relative_to_now() {
+        let now = 1_000_000;
+        let age = block(10).with_age(now - 6_000, now);
+        assert_eq!(age.block, block(10));
+        assert_eq!(age.age_ms, 6_000);
+    }
+
+    #[test]
+    fn block_produced_now_has_zero_age() {
+        let now = 1_000_000;
+        assert_eq!(block(10).with_age(now, now).age_ms, 0);
+    }
+
+    #[test]
+    fn block_from_the_future_has_zero_age() {
+        let now = 1_000_000;
+        assert_eq!(block(10).with_age(now + 500, now).age_ms, 0);
+    }
+
+    #[test]
+    fn block_age_roundtrips_through_json() {
+        let age = block(10).with_age(1_000, 3_500);
+        let json = serde_json::to_value(age).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([10, format!("{:?}", BlockHash::from_low_u64_be(10)), 2_500])
+        );
+        let decoded: BlockAge = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded, age);
+    }
+
+    #[test]
+    fn block_age_display() {
+        let age = block(10).with_age(1_000, 3_500);
+        assert_eq!(
+            age.to_string(),
+            format!("#10 ({:?}), 2500ms old", BlockHash::from_low_u64_be(10))
+        );
+    }
+
+    #[test]
+    fn could_be_ancestor_of_lower_blocks_only() {
+        assert!(block(9).could_be_ancestor_of(&block(10)));
+        assert!(block(0).could_be_ancestor_of(&block(10)));
+        assert!(!block(10).could_be_ancestor_of(&block(10)));
+        assert!(!block(11).could_be_ancestor_of(&block(10)));
+    }
+
+    #[test]
+    fn could_be_ancestor_of_ignores_hashes() {
+        let other = Block {
+            hash: BlockHash::from_low_u64_be(1234),
+            height: 10,
+        };
+        assert!(block(9).could_be_ancestor_of(&other));
+        assert!(!block(10).could_be_ancestor_of(&other));
+    }
+
+    #[test]
+    fn common_ancestor_of_unequal_heights_is_the_lower_block() {
+        assert_eq!(
+            Block::common_ancestor_height(&block(5), &block(10)),
+            Some(5)
+        );
+        assert_eq!(
+            Block::common_ancestor_height(&block(10), &block(5)),
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn common_ancestor_of_equal_heights() {
+        // The same block is its own common ancestor:
+        assert_eq!(
+            Block::common_ancestor_height(&block(10), &block(10)),
+            Some(10)
+        );
+
+        // Different blocks at the same height diverged somewhere we can't guess at:
+        let other = Block {
+            hash: BlockHash::from_low_u64_be(1234),
+            height: 10,
+        };
+        assert_eq!(Block::common_ancestor_height(&block(10), &other), None);
+        assert_eq!(Block::common_ancestor_height(&other, &block(10)), None);
+    }
+
+    #[test]
+    fn genesis_consistency() {
+        let genesis = Block {
+            hash: BlockHash::from_low_u64_be(1234),
+            height: 0,
+        };
+        assert!(Block::verify_genesis_consistency(&genesis, &genesis));
+        // A head at height 0 with some other hash is on a different chain:
+        assert!(!Block::verify_genesis_consistency(&genesis, &block(0)));
+        // Heads past genesis can't be checked:
+        assert!(Block::verify_genesis_consistency(&genesis, &block(10)));
+
+        assert!(Block::genesis_hash_matches(&genesis, &genesis));
+        assert!(!Block::genesis_hash_matches(&genesis, &block(0)));
+        assert!(!Block::genesis_hash_matches(&block(0), &genesis));
+        assert!(Block::genesis_hash_matches(&genesis, &block(10)));
+        assert!(Block::genesis_hash_matches(&block(10), &block(0)));
+    }
+}
@@ -57,6 +57,15 @@ impl<T: NumOps + Zero + Bounded + Copy + Sum + TryFrom<usize>> NumStats<T> {
         self.sum / cap
     }
 
+    /// How many numbers are currently held (up to the size given on creation).
+    pub fn len(&self) -> usize {
+        std::cmp::min(self.index, self.stack.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn reset(&mut self) {
         self.index = 0;
         self.sum = T::zero();
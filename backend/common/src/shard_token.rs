@@ -0,0 +1,118 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A telemetry core can be told to only accept connections from shards (and cluster peers)
+//! that know a shared token. Our websocket client can't add headers to the upgrade request,
+//! so the token is passed in the query string of the URI that we connect to instead.
+
+/// The query parameter that the token is passed in.
+pub const TOKEN_PARAM: &str = "token";
+
+/// Tokens end up in a query string, so we only allow characters that don't need escaping there.
+pub fn validate(token: &str) -> anyhow::Result<()> {
+    if token.is_empty() {
+        anyhow::bail!("the token must not be empty");
+    }
+    let is_allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~');
+    if !token.chars().all(is_allowed) {
+        anyhow::bail!("the token may only contain ASCII letters, digits, '-', '_', '.' and '~'");
+    }
+    Ok(())
+}
+
+/// Add the token to the query string of a URI that we'll connect to a core on.
+pub fn add_to_uri(uri: http::Uri, token: &str) -> anyhow::Result<http::Uri> {
+    validate(token)?;
+    let mut parts = uri.into_parts();
+    let (path, query) = match &parts.path_and_query {
+        Some(pq) => (pq.path(), pq.query()),
+        None => ("/", None),
+    };
+    let path_and_query = match query {
+        Some(query) if !query.is_empty() => {
+            format!("{}?{}&{}={}", path, query, TOKEN_PARAM, token)
+        }
+        _ => format!("{}?{}={}", path, TOKEN_PARAM, token),
+    };
+    parts.path_and_query = Some(path_and_query.parse()?);
+    Ok(http::Uri::from_parts(parts)?)
+}
+
+/// Is a connection with the given query string allowed in? If we don't expect any token,
+/// then every connection is. Otherwise, the query string must contain the token expected.
+pub fn is_authorized(query: Option<&str>, expected: Option<&str>) -> bool {
+    let expected = match expected {
+        Some(expected) => expected,
+        None => return true,
+    };
+    query
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|pair| pair.strip_prefix(TOKEN_PARAM)?.strip_prefix('='))
+        .any(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+}
+
+/// Compare two byte strings without bailing at the first difference, so that how long the
+/// comparison takes says nothing about how much of a guessed token was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn query_for(uri: &str, token: &str) -> String {
+        let uri = add_to_uri(uri.parse().unwrap(), token).unwrap();
+        uri.query().unwrap().to_owned()
+    }
+
+    #[test]
+    fn shard_with_the_right_token_is_accepted() {
+        let query = query_for("ws://127.0.0.1:8000/shard_submit/", "s3cret");
+        assert!(is_authorized(Some(&query), Some("s3cret")));
+
+        // Any existing query parameters are kept:
+        let query = query_for("ws://127.0.0.1:8000/shard_submit?foo=bar", "s3cret");
+        assert_eq!(query, "foo=bar&token=s3cret");
+        assert!(is_authorized(Some(&query), Some("s3cret")));
+    }
+
+    #[test]
+    fn shard_with_the_wrong_token_or_no_token_is_rejected() {
+        let query = query_for("ws://127.0.0.1:8000/shard_submit/", "wrong");
+        assert!(!is_authorized(Some(&query), Some("s3cret")));
+        assert!(!is_authorized(Some("token=s3cre"), Some("s3cret")));
+        assert!(!is_authorized(Some("tokens=s3cret"), Some("s3cret")));
+        assert!(!is_authorized(Some(""), Some("s3cret")));
+        assert!(!is_authorized(None, Some("s3cret")));
+    }
+
+    #[test]
+    fn every_shard_is_accepted_if_no_token_is_configured() {
+        let query = query_for("ws://127.0.0.1:8000/shard_submit/", "anything");
+        assert!(is_authorized(Some(&query), None));
+        assert!(is_authorized(None, None));
+    }
+
+    #[test]
+    fn tokens_must_be_safe_to_put_in_a_query_string() {
+        assert!(validate("abc-DEF_123.~").is_ok());
+        assert!(validate("").is_err());
+        assert!(validate("a&b").is_err());
+        assert!(validate("a b").is_err());
+    }
+}
@@ -0,0 +1,242 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Hand tracing spans to whatever logger is installed, so that they show up alongside
+//! everything else we log. Each span is logged once it closes, along with its fields and
+//! how long it was open for. (`tracing` can log spans itself via its `log` feature, but
+//! that needs a newer version of `log` than we use.)
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// A tracing [`Subscriber`] which logs spans (and events) at the equivalent log level.
+/// Spans that the logger wouldn't output aren't recorded at all.
+#[derive(Default)]
+pub struct LogSubscriber {
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, OpenSpan>>,
+}
+
+struct OpenSpan {
+    metadata: &'static Metadata<'static>,
+    fields: String,
+    opened_at: Instant,
+    /// How many handles to the span exist. It's closed once the last one is dropped.
+    handles: usize,
+}
+
+impl LogSubscriber {
+    pub fn new() -> Self {
+        LogSubscriber::default()
+    }
+
+    /// Log spans from every thread for the rest of the program. This should be called
+    /// once the logger has been set up.
+    pub fn install() -> anyhow::Result<()> {
+        tracing::subscriber::set_global_default(LogSubscriber::new())?;
+        Ok(())
+    }
+}
+
+impl Subscriber for LogSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let level = to_log_level(metadata.level());
+        level <= log::max_level()
+            && log::logger().enabled(
+                &log::Metadata::builder()
+                    .level(level)
+                    .target(metadata.target())
+                    .build(),
+            )
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = String::new();
+        span.record(&mut FieldWriter(&mut fields));
+        // Span IDs can't be 0:
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.spans.lock().unwrap().insert(
+            id,
+            OpenSpan {
+                metadata: span.metadata(),
+                fields,
+                opened_at: Instant::now(),
+                handles: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut FieldWriter(&mut span.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = String::new();
+        event.record(&mut FieldWriter(&mut fields));
+        log(event.metadata(), format_args!("{}", fields.trim_start()));
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+
+    fn clone_span(&self, id: &Id) -> Id {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            span.handles += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let closed = match spans.get_mut(&id.into_u64()) {
+            Some(span) => {
+                span.handles -= 1;
+                span.handles == 0
+            }
+            None => false,
+        };
+        if !closed {
+            return false;
+        }
+        let span = spans.remove(&id.into_u64()).unwrap();
+        drop(spans);
+
+        log(
+            span.metadata,
+            format_args!("{}", ClosedSpan(&span, span.opened_at.elapsed())),
+        );
+        true
+    }
+}
+
+/// How a closed span is logged, eg `node_message{node_id=1 message_type=block.import} 15µs`.
+struct ClosedSpan<'a>(&'a OpenSpan, Duration);
+
+impl fmt::Display for ClosedSpan<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ClosedSpan(span, open_for) = self;
+        write!(
+            f,
+            "{}{{{}}} {:?}",
+            span.metadata.name(),
+            span.fields.trim_start(),
+            open_for
+        )
+    }
+}
+
+/// Appends each field it's given to a string, as ` name=value`. Event messages are
+/// appended without their name.
+struct FieldWriter<'a>(&'a mut String);
+
+impl Visit for FieldWriter<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.0, " {:?}", value),
+            name => write!(self.0, " {}={:?}", name, value),
+        };
+    }
+}
+
+fn log(metadata: &Metadata<'_>, args: fmt::Arguments<'_>) {
+    log::logger().log(
+        &log::Record::builder()
+            .level(to_log_level(metadata.level()))
+            .target(metadata.target())
+            .module_path(metadata.module_path())
+            .file(metadata.file())
+            .line(metadata.line())
+            .args(args)
+            .build(),
+    );
+}
+
+fn to_log_level(level: &Level) -> log::Level {
+    match *level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct CapturingLogger;
+
+    static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Debug
+        }
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                LINES.lock().unwrap().push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn spans_are_logged_when_they_close() {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        tracing::subscriber::with_default(LogSubscriber::new(), || {
+            let span = tracing::debug_span!(
+                "node_message",
+                node_id = tracing::field::Empty,
+                message_type = "block.import",
+            );
+            let entered = span.clone().entered();
+            span.record("node_id", 1);
+            tracing::trace_span!("too_detailed").in_scope(|| {});
+            drop(entered);
+            assert!(LINES.lock().unwrap().is_empty());
+            drop(span);
+        });
+
+        let lines = LINES.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(
+            lines[0].starts_with("node_message{message_type=block.import node_id=1} "),
+            "{}",
+            lines[0]
+        );
+    }
+}
@@ -228,7 +228,7 @@ pub enum ConnectError {
 pub async fn connect(uri: &http::Uri) -> Result<Connection, ConnectError> {
     let host = uri.host().unwrap_or("127.0.0.1");
     let port = uri.port_u16().unwrap_or(80);
-    let path = uri.path();
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
 
     let socket = TcpStream::connect((host, port)).await?;
     socket.set_nodelay(true).expect("socket set_nodelay failed");
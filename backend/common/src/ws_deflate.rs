@@ -0,0 +1,357 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A minimal `permessage-deflate` WebSocket extension (RFC 7692). No compression
+//! context is kept between messages, and messages smaller than a threshold are sent
+//! uncompressed, since compressing them costs CPU for little or no saving.
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use soketto::base::{Header, OpCode};
+use soketto::extension::{Extension, Param};
+use soketto::{BoxedError, Storage};
+
+/// The name that the extension is negotiated by.
+pub const EXTENSION_NAME: &str = "permessage-deflate";
+
+/// What we send back in the `Sec-WebSocket-Extensions` header when accepting an offer
+/// of `permessage-deflate`.
+const ACCEPT_RESPONSE: &str =
+    "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// What we send back instead if the offer limited our window size (to the size we
+/// use anyway); RFC 7692 §7.1.2.1 has us echo the limit back.
+const ACCEPT_RESPONSE_WITH_WINDOW_BITS: &str = "permessage-deflate; server_no_context_takeover; \
+     client_no_context_takeover; server_max_window_bits=15";
+
+/// Every compressed message ends with this, which is removed before it's sent.
+const TRAILER: [u8; 4] = [0, 0, 0xFF, 0xFF];
+
+/// If a client, given the values of the `Sec-WebSocket-Extensions` headers it sent,
+/// offers `permessage-deflate` with parameters that we can accept, this hands back
+/// what to send in our `Sec-WebSocket-Extensions` header to accept it.
+pub fn accept_offer<'a>(header_values: impl IntoIterator<Item = &'a str>) -> Option<&'static str> {
+    header_values
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .find_map(accept_single_offer)
+}
+
+fn accept_single_offer(offer: &str) -> Option<&'static str> {
+    let mut parts = offer.split(';').map(str::trim);
+    if parts.next() != Some(EXTENSION_NAME) {
+        return None;
+    }
+    let mut limits_window_bits = false;
+    let acceptable = parts.all(|param| {
+        let (name, value) = match param.find('=') {
+            Some(idx) => (
+                param[..idx].trim(),
+                Some(param[idx + 1..].trim_matches('"')),
+            ),
+            None => (param, None),
+        };
+        match name {
+            "server_no_context_takeover" | "client_no_context_takeover" => value.is_none(),
+            // We're free to ignore this; the client will cope with any window size:
+            "client_max_window_bits" => true,
+            // We always compress with the largest window:
+            "server_max_window_bits" => {
+                limits_window_bits = true;
+                value == Some("15")
+            }
+            _ => false,
+        }
+    });
+
+    match (acceptable, limits_window_bits) {
+        (false, _) => None,
+        (true, false) => Some(ACCEPT_RESPONSE),
+        (true, true) => Some(ACCEPT_RESPONSE_WITH_WINDOW_BITS),
+    }
+}
+
+/// The `permessage-deflate` extension, as negotiated by [`accept_offer`].
+#[derive(Debug)]
+pub struct Deflate {
+    /// Messages smaller than this many bytes are sent uncompressed.
+    threshold: usize,
+    /// We refuse to decompress messages into more than this many bytes.
+    max_message_size: usize,
+    /// Are we waiting for the rest of a fragmented compressed message?
+    awaiting_last_fragment: bool,
+}
+
+impl Deflate {
+    pub fn new(threshold: usize, max_message_size: usize) -> Self {
+        Deflate {
+            threshold,
+            max_message_size,
+            awaiting_last_fragment: false,
+        }
+    }
+}
+
+impl Extension for Deflate {
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        EXTENSION_NAME
+    }
+
+    fn params(&self) -> &[Param<'_>] {
+        &[]
+    }
+
+    fn configure(&mut self, _params: &[Param]) -> Result<(), BoxedError> {
+        // Parameters are agreed on when the connection is upgraded:
+        Ok(())
+    }
+
+    fn reserved_bits(&self) -> (bool, bool, bool) {
+        (true, false, false)
+    }
+
+    fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+        if !matches!(header.opcode(), OpCode::Text | OpCode::Binary) {
+            return Ok(());
+        }
+        if data.as_ref().is_empty() || data.as_ref().len() < self.threshold {
+            return Ok(());
+        }
+
+        let compressed = compress(data.as_ref())?;
+        *data = Storage::Owned(compressed);
+        header.set_rsv1(true);
+        header.set_payload_len(data.as_ref().len());
+        Ok(())
+    }
+
+    fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+        match header.opcode() {
+            OpCode::Text | OpCode::Binary if header.is_rsv1() => {
+                if !header.is_fin() {
+                    self.awaiting_last_fragment = true;
+                    return Ok(());
+                }
+            }
+            OpCode::Continue if header.is_fin() && self.awaiting_last_fragment => {
+                self.awaiting_last_fragment = false;
+            }
+            _ => return Ok(()),
+        }
+
+        data.extend_from_slice(&TRAILER);
+        *data = decompress(data, self.max_message_size)?;
+        header.set_rsv1(false);
+        header.set_payload_len(data.len());
+        Ok(())
+    }
+}
+
+/// Compress a whole message, as described in RFC 7692 section 7.2.1.
+fn compress(data: &[u8]) -> Result<Vec<u8>, BoxedError> {
+    let mut compressor = Compress::new(Compression::fast(), false);
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+
+    while (compressor.total_in() as usize) < data.len() {
+        let consumed = compressor.total_in() as usize;
+        if let Status::BufError =
+            compressor.compress_vec(&data[consumed..], &mut out, FlushCompress::None)?
+        {
+            out.reserve(4096);
+        }
+    }
+    // Flush everything out, which ends the message with an empty block:
+    while !out.ends_with(&TRAILER) {
+        out.reserve(64);
+        compressor.compress_vec(&[], &mut out, FlushCompress::Sync)?;
+    }
+
+    out.truncate(out.len() - TRAILER.len());
+    Ok(out)
+}
+
+/// Decompress a whole message (with the trailer put back), refusing to produce
+/// more than `max_size` bytes.
+fn decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>, BoxedError> {
+    let too_large = || -> BoxedError { "decompressed message is too large".into() };
+    let limit = max_size.saturating_add(1);
+    let mut decompressor = Decompress::new(false);
+    let mut out = Vec::with_capacity(data.len().saturating_mul(2).min(limit));
+
+    loop {
+        if out.len() > max_size {
+            return Err(too_large());
+        }
+        if out.len() == out.capacity() {
+            out.reserve(out.len().max(1024).min(limit - out.len()));
+        }
+
+        let consumed = decompressor.total_in() as usize;
+        let produced = out.len();
+        let status =
+            decompressor.decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)?;
+        let made_progress = decompressor.total_in() as usize > consumed || out.len() > produced;
+        let all_consumed = decompressor.total_in() as usize == data.len();
+
+        match status {
+            Status::StreamEnd => break,
+            // Everything is in, and there was room left for anything still buffered:
+            _ if all_consumed && out.len() < out.capacity() => break,
+            _ if !made_progress && out.len() < out.capacity() => {
+                return Err("compressed message is incomplete".into())
+            }
+            _ => {}
+        }
+    }
+
+    if out.len() > max_size {
+        return Err(too_large());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text_header(len: usize) -> Header {
+        let mut header = Header::new(OpCode::Text);
+        header.set_payload_len(len);
+        header
+    }
+
+    #[test]
+    fn messages_below_the_threshold_are_sent_uncompressed() {
+        let mut deflate = Deflate::new(64, 1024);
+        let msg = b"[0,34]".to_vec();
+        let mut header = text_header(msg.len());
+        let mut data = Storage::Shared(&msg);
+
+        deflate.encode(&mut header, &mut data).unwrap();
+
+        assert!(!header.is_rsv1());
+        assert_eq!(data.as_ref(), &msg[..]);
+        assert_eq!(header.payload_len(), msg.len());
+    }
+
+    #[test]
+    fn messages_above_the_threshold_are_sent_compressed() {
+        let mut deflate = Deflate::new(64, 1024 * 1024);
+        let msg = "[3,[1,[\"Alice\",\"Substrate Node\"]]]"
+            .repeat(100)
+            .into_bytes();
+        let mut header = text_header(msg.len());
+        let mut data = Storage::Shared(&msg);
+
+        deflate.encode(&mut header, &mut data).unwrap();
+
+        assert!(header.is_rsv1());
+        assert!(data.as_ref().len() < msg.len());
+        assert_eq!(header.payload_len(), data.as_ref().len());
+
+        // And the other end can decompress it again:
+        let mut received = data.as_ref().to_vec();
+        deflate.decode(&mut header, &mut received).unwrap();
+        assert!(!header.is_rsv1());
+        assert_eq!(received, msg);
+    }
+
+    #[test]
+    fn control_frames_are_never_compressed() {
+        let mut deflate = Deflate::new(0, 1024);
+        let msg = vec![1u8; 100];
+        let mut header = Header::new(OpCode::Ping);
+        header.set_payload_len(msg.len());
+        let mut data = Storage::Shared(&msg);
+
+        deflate.encode(&mut header, &mut data).unwrap();
+        assert!(!header.is_rsv1());
+        assert_eq!(data.as_ref(), &msg[..]);
+    }
+
+    #[test]
+    fn uncompressed_messages_are_received_as_they_are() {
+        let mut deflate = Deflate::new(0, 1024);
+        let mut header = text_header(5);
+        let mut data = b"hello".to_vec();
+        deflate.decode(&mut header, &mut data).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn messages_that_decompress_to_too_much_are_rejected() {
+        let msg = vec![0u8; 10_000];
+        let mut header = text_header(msg.len());
+        let mut data = Storage::Shared(&msg);
+        Deflate::new(0, usize::MAX)
+            .encode(&mut header, &mut data)
+            .unwrap();
+
+        let mut received = data.as_ref().to_vec();
+        let res = Deflate::new(0, 1000).decode(&mut header, &mut received);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn offers_we_can_accept() {
+        assert_eq!(
+            accept_offer(vec!["permessage-deflate"]),
+            Some(ACCEPT_RESPONSE)
+        );
+        // What browsers typically send:
+        assert_eq!(
+            accept_offer(vec!["permessage-deflate; client_max_window_bits"]),
+            Some(ACCEPT_RESPONSE)
+        );
+        assert_eq!(
+            accept_offer(vec![
+                "x-webkit-deflate-frame, permessage-deflate; server_no_context_takeover"
+            ]),
+            Some(ACCEPT_RESPONSE)
+        );
+
+        assert_eq!(accept_offer(Vec::<&str>::new()), None);
+        assert_eq!(accept_offer(vec!["x-webkit-deflate-frame"]), None);
+        assert_eq!(
+            accept_offer(vec!["permessage-deflate; server_max_window_bits=10"]),
+            None
+        );
+        assert_eq!(
+            accept_offer(vec!["permessage-deflate; something_new"]),
+            None
+        );
+    }
+
+    #[test]
+    fn window_bits_we_were_offered_are_echoed_back() {
+        let response = accept_offer(vec!["foo", "permessage-deflate; server_max_window_bits=15"]);
+        assert_eq!(response, Some(ACCEPT_RESPONSE_WITH_WINDOW_BITS));
+        assert!(response.unwrap().ends_with("; server_max_window_bits=15"));
+
+        // The first offer we can accept is the one that counts:
+        assert_eq!(
+            accept_offer(vec![
+                "permessage-deflate; server_max_window_bits=10, \
+                 permessage-deflate; server_max_window_bits=\"15\", \
+                 permessage-deflate"
+            ]),
+            Some(ACCEPT_RESPONSE_WITH_WINDOW_BITS)
+        );
+    }
+}
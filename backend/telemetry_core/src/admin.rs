@@ -0,0 +1,401 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Operator facing endpoints, served under `/admin`. These are only
+//! available if an admin token has been configured.
+
+use crate::aggregator::{
+    node_filter_from_query, AggregatorSet, ChainMemoryUsage, FeedQueueLengths, FeedStats,
+    LatencySummary,
+};
+use crate::api::parse_genesis_hash;
+use crate::list_query::{ListOpts, ListQuery};
+use crate::state::RetentionPolicy;
+use common::http_utils;
+use common::node_types::BlockHash;
+use hyper::{Body, Method, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// All of the admin routes live under this prefix.
+pub const ADMIN_PREFIX: &str = "/admin";
+
+/// How the list of nodes on a chain can be paged through and sorted.
+const NODE_LIST_OPTS: ListOpts = ListOpts {
+    sortable: &[
+        "id",
+        "details.name",
+        "details.implementation",
+        "details.version",
+        "startup_time",
+        "stats.peers",
+        "best_block.height",
+        "finalized_block.height",
+    ],
+    max_limit: 1000,
+};
+
+/// Handle a request to some path beginning with [`ADMIN_PREFIX`].
+pub async fn handle_admin_request(
+    aggregator: AggregatorSet,
+    admin_token: Option<&str>,
+    req: Request<Body>,
+) -> Response<Body> {
+    if let Some(res) = http_utils::check_admin_token(&req, admin_token) {
+        return res;
+    }
+
+    let path = req.uri().path().trim_end_matches('/').to_owned();
+    let path = path.strip_prefix(ADMIN_PREFIX).unwrap_or(&path);
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match (req.method().clone(), segments.as_slice()) {
+        // How much memory each chain is using, biggest first:
+        (Method::GET, ["memory"]) => http_utils::json_response(200, &memory_report(&aggregator)),
+        // How many messages are waiting in each aggregator's internal queues:
+        (Method::GET, ["queues"]) => http_utils::json_response(200, &queue_report(&aggregator)),
+        // How much has been sent to each feed, and how much left out because it was falling
+        // behind, by feed ID:
+        (Method::GET, ["feeds"]) => match aggregator.feed_stats().await {
+            Ok(stats) => http_utils::json_response(200, &feed_report(stats)),
+            Err(e) => {
+                log::error!("Error obtaining feed stats: {}", e);
+                http_utils::basic_response(500, "Error obtaining feed stats")
+            }
+        },
+        // How long node messages about each chain, and from each shard, have taken to get
+        // here and then to be handled by each aggregator:
+        (Method::GET, ["latency"]) => http_utils::json_response(200, &latency_report(&aggregator)),
+        // The current state of the nodes on a chain, given its genesis hash. These can be
+        // filtered in the same way as feeds, eg `?offchain_indexing=true`, or by the shape
+        // of the messages they send, eg `?message_schema=V1` for nodes sending older ones,
+        // and paged through, sorted and trimmed down as described in [`crate::list_query`]:
+        (Method::GET, ["chains", genesis_hash, "nodes"]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            let filter = match node_filter_from_query(req.uri().query()) {
+                Ok(filter) => filter,
+                Err(e) => return http_utils::basic_response(400, e.to_string()),
+            };
+            let query = match ListQuery::from_query(req.uri().query(), &NODE_LIST_OPTS) {
+                Ok(query) => query,
+                Err(e) => return http_utils::basic_response(400, e.to_string()),
+            };
+            let page = aggregator
+                .chain_nodes(genesis_hash, filter)
+                .await
+                .and_then(|nodes| nodes.map(|nodes| query.page(&nodes)).transpose());
+            match page {
+                Ok(Some(page)) => http_utils::json_response(200, &page),
+                Ok(None) => http_utils::basic_response(404, "Chain not found"),
+                Err(e) => {
+                    log::error!("Error obtaining chain nodes: {}", e);
+                    http_utils::basic_response(500, "Error obtaining chain nodes")
+                }
+            }
+        }
+        // How much node count history a chain keeps:
+        (Method::GET, ["chains", genesis_hash, "retention"]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            match aggregator.retention_policy(genesis_hash).await {
+                Ok(Some(policy)) => http_utils::json_response(200, &policy),
+                Ok(None) => http_utils::basic_response(404, "Chain not found"),
+                Err(e) => {
+                    log::error!("Error obtaining retention policy: {}", e);
+                    http_utils::basic_response(500, "Error obtaining retention policy")
+                }
+            }
+        }
+        // Change how much node count history a chain keeps, given a JSON body like
+        // `{"max_samples":100,"max_age_ms":600000}`:
+        (Method::PUT, ["chains", genesis_hash, "retention"]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return http_utils::basic_response(400, e.to_string()),
+            };
+            let policy: RetentionPolicy = match serde_json::from_slice(&body) {
+                Ok(policy) => policy,
+                Err(e) => return http_utils::basic_response(400, e.to_string()),
+            };
+            if let Err(e) = policy.validate() {
+                return http_utils::basic_response(400, e);
+            }
+            match aggregator.set_retention_policy(genesis_hash, policy).await {
+                Ok(true) => http_utils::json_response(200, &policy),
+                Ok(false) => http_utils::basic_response(404, "Chain not found"),
+                Err(e) => {
+                    log::error!("Error setting retention policy: {}", e);
+                    http_utils::basic_response(500, "Error setting retention policy")
+                }
+            }
+        }
+        // Look up the location of a node again, ignoring any location cached for its IP
+        // address, given the genesis hash of its chain and the ID that feeds know it by.
+        // Feeds are told about the new location once it's been found:
+        (Method::POST, ["nodes", genesis_hash, node_id, "relocate"]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            let node_id: usize = match node_id.parse() {
+                Ok(id) => id,
+                Err(_) => return http_utils::basic_response(400, "Invalid node ID"),
+            };
+            match aggregator.relocate_node(genesis_hash, node_id).await {
+                Ok(true) => http_utils::basic_response(202, "Relocating node"),
+                Ok(false) => http_utils::basic_response(404, "Node not found"),
+                Err(e) => {
+                    log::error!("Error relocating node: {}", e);
+                    http_utils::basic_response(500, "Error relocating node")
+                }
+            }
+        }
+        // Have every shard drop updates for a chain that's flooding us, given a JSON body
+        // like `{"ttl_secs":600}`. The mute ends by itself once the time is up:
+        (Method::POST, ["chains", genesis_hash, "mute"]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return http_utils::basic_response(400, e.to_string()),
+            };
+            let ttl = match serde_json::from_slice::<MuteRequest>(&body) {
+                Ok(req) => match req.ttl() {
+                    Ok(ttl) => ttl,
+                    Err(e) => return http_utils::basic_response(400, e),
+                },
+                Err(e) => return http_utils::basic_response(400, e.to_string()),
+            };
+            match aggregator.mute_chain(genesis_hash, ttl).await {
+                Ok(muted_until) => http_utils::json_response(200, &MuteResponse { muted_until }),
+                Err(e) => {
+                    log::error!("Error muting chain: {}", e);
+                    http_utils::basic_response(500, "Error muting chain")
+                }
+            }
+        }
+        // Have every shard stop dropping updates for a chain:
+        (Method::POST, ["chains", genesis_hash, "unmute"]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            match aggregator.unmute_chain(genesis_hash).await {
+                Ok(true) => http_utils::basic_response(200, "Chain unmuted"),
+                Ok(false) => http_utils::basic_response(404, "Chain not muted"),
+                Err(e) => {
+                    log::error!("Error unmuting chain: {}", e);
+                    http_utils::basic_response(500, "Error unmuting chain")
+                }
+            }
+        }
+        _ => http_utils::basic_response(404, "Not found"),
+    }
+}
+
+/// The longest that a chain can be muted for in one go, so that a mute that's forgotten
+/// about doesn't last forever.
+const MAX_MUTE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Deserialize)]
+struct MuteRequest {
+    /// How many seconds the chain should be muted for.
+    ttl_secs: u64,
+}
+
+impl MuteRequest {
+    fn ttl(&self) -> Result<Duration, String> {
+        let ttl = Duration::from_secs(self.ttl_secs);
+        if ttl.as_secs() == 0 {
+            Err("ttl_secs must be greater than 0".to_owned())
+        } else if ttl > MAX_MUTE_TTL {
+            Err(format!(
+                "ttl_secs must be at most {}",
+                MAX_MUTE_TTL.as_secs()
+            ))
+        } else {
+            Ok(ttl)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MuteResponse {
+    /// When the mute will end, in unix milliseconds.
+    muted_until: u64,
+}
+
+#[derive(Serialize)]
+struct MemoryReport {
+    /// When the usage was last gathered from the aggregator.
+    timestamp_unix_ms: u64,
+    chains: Vec<ChainMemoryUsage>,
+}
+
+fn memory_report(aggregator: &AggregatorSet) -> MemoryReport {
+    // Every aggregator knows about every chain, so the first one will do:
+    let metrics = aggregator.latest_metrics().into_iter().next();
+    let (timestamp_unix_ms, mut chains) = match metrics {
+        Some(m) => (m.timestamp_unix_ms, m.chain_memory),
+        None => (0, Vec::new()),
+    };
+    chains.sort_by_key(|c| std::cmp::Reverse(c.usage.total));
+    MemoryReport {
+        timestamp_unix_ms,
+        chains,
+    }
+}
+
+#[derive(Serialize)]
+struct QueueReport {
+    aggregators: Vec<AggregatorQueues>,
+}
+
+#[derive(Serialize)]
+struct AggregatorQueues {
+    /// When the queue depths were last gathered from the aggregator.
+    timestamp_unix_ms: u64,
+    /// Messages from shards and feeds waiting to be handled by the aggregator.
+    ingest: usize,
+    /// Messages waiting to be sent out to each feed.
+    feeds: FeedQueueLengths,
+    /// Messages that were dropped rather than queued, because the queue was too long.
+    dropped: DroppedMessages,
+}
+
+#[derive(Serialize)]
+struct DroppedMessages {
+    ingest: u64,
+    feeds: u64,
+}
+
+fn queue_report(aggregator: &AggregatorSet) -> QueueReport {
+    // Each aggregator has its own queues, so report on all of them:
+    let aggregators = aggregator
+        .latest_metrics()
+        .into_iter()
+        .map(|m| AggregatorQueues {
+            timestamp_unix_ms: m.timestamp_unix_ms,
+            ingest: m.total_messages_to_aggregator,
+            feeds: m.feed_queue_lengths,
+            dropped: DroppedMessages {
+                ingest: m.dropped_messages_to_aggregator,
+                feeds: m.dropped_messages_to_feeds,
+            },
+        })
+        .collect();
+    QueueReport { aggregators }
+}
+
+#[derive(Serialize)]
+struct FeedReport {
+    aggregators: Vec<AggregatorFeeds>,
+}
+
+#[derive(Serialize)]
+struct AggregatorFeeds {
+    /// Feed IDs are only unique within an aggregator.
+    feeds: BTreeMap<u64, FeedStats>,
+}
+
+fn feed_report(stats: Vec<BTreeMap<u64, FeedStats>>) -> FeedReport {
+    let aggregators = stats
+        .into_iter()
+        .map(|feeds| AggregatorFeeds { feeds })
+        .collect();
+    FeedReport { aggregators }
+}
+
+#[derive(Serialize)]
+struct LatencyReport {
+    aggregators: Vec<AggregatorLatency>,
+}
+
+#[derive(Serialize)]
+struct AggregatorLatency {
+    /// When the latencies were last gathered from the aggregator.
+    timestamp_unix_ms: u64,
+    /// Slowest chains (by 99th percentile queue time) first.
+    chains: Vec<ChainLatency>,
+    shards: Vec<ShardLatency>,
+}
+
+#[derive(Serialize)]
+struct ChainLatency {
+    label: Box<str>,
+    genesis_hash: BlockHash,
+    /// How long messages took to get here from shards. This relies on the clocks of
+    /// the shards agreeing with ours.
+    transit: LatencySummary,
+    /// How long messages waited here to be handled.
+    queued: LatencySummary,
+}
+
+#[derive(Serialize)]
+struct ShardLatency {
+    shard_conn_id: u64,
+    transit: LatencySummary,
+    queued: LatencySummary,
+}
+
+fn latency_report(aggregator: &AggregatorSet) -> LatencyReport {
+    // Each aggregator queues messages separately, so report on all of them:
+    let aggregators = aggregator
+        .latest_metrics()
+        .into_iter()
+        .map(|m| {
+            let mut chains: Vec<_> = m
+                .chain_ingest_latency
+                .into_iter()
+                .map(|l| ChainLatency {
+                    label: l.label,
+                    genesis_hash: l.genesis_hash,
+                    transit: l.transit.summary(),
+                    queued: l.queued.summary(),
+                })
+                .collect();
+            chains.sort_by_key(|c| std::cmp::Reverse(c.queued.p99_ms));
+            let mut shards: Vec<_> = m
+                .shard_ingest_latency
+                .into_iter()
+                .map(|l| ShardLatency {
+                    shard_conn_id: l.shard_conn_id,
+                    transit: l.transit.summary(),
+                    queued: l.queued.summary(),
+                })
+                .collect();
+            shards.sort_by_key(|s| s.shard_conn_id);
+            AggregatorLatency {
+                timestamp_unix_ms: m.timestamp_unix_ms,
+                chains,
+                shards,
+            }
+        })
+        .collect();
+    LatencyReport { aggregators }
+}
@@ -14,14 +14,22 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use super::inner_loop;
-use crate::find_location::find_location;
-use crate::state::NodeId;
+use super::inner_loop::{self, FeedStats, NodeFilter};
+use crate::dataset_export::{ChainDataset, NetworkIdHasher};
+use crate::feed_budget::FeedBudgets;
+use crate::feed_priority::FeedPriorities;
+use crate::find_location::{find_location, Anonymizers, CacheStats, LocateRequest};
+use crate::name_redaction::NameRedactor;
+use crate::state::{
+    ChainOpts, LocationCluster, NodeCountHistory, NodeId, NodeInfo, RecentBlock, RetentionPolicy,
+};
 use common::id_type;
+use common::node_types::{BlockHash, Timestamp};
 use futures::{future, Sink, SinkExt};
-use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::Duration;
 
 id_type! {
     /// A unique Id is assigned per websocket connection (or more accurately,
@@ -41,6 +49,46 @@ pub struct AggregatorOpts {
     /// If our incoming message queue exceeds this length, we start
     /// dropping non-essential messages.
     pub max_queue_len: usize,
+    /// If handling a single message takes longer than this, log a warning.
+    pub slow_message_threshold: Option<Duration>,
+    /// Options applied to every chain.
+    pub chain_opts: ChainOpts,
+    /// Labels of third party chains that per-chain metrics are reported for, as
+    /// well as for first party chains.
+    pub metrics_chain_allowlist: Vec<String>,
+    /// How important each feed message is, and so which are dropped first
+    /// when a feed falls behind.
+    pub feed_priorities: FeedPriorities,
+    /// How many feed messages per second each chain can send before only high
+    /// priority messages about it are sent.
+    pub feed_budgets: FeedBudgets,
+    /// If a node reconnects within this long of disconnecting (and looks the same as
+    /// it did), feeds aren't told that it went away and came back. Nodes are recognised
+    /// by their genesis hash and network ID. If `None`, nodes are removed straight away.
+    pub reconnect_debounce: Option<Duration>,
+    /// When a shard disconnects (perhaps because it's restarting), its nodes are kept for
+    /// this long in case they come back via a shard that was connected to them before,
+    /// so that they keep their IDs and history. If `None`, they're removed straight away.
+    pub shard_restart_grace: Option<Duration>,
+    /// Nodes connecting from any of these are shown at a fallback location, rather
+    /// than wherever they appear to be.
+    pub anonymizers: Arc<Anonymizers>,
+    /// If set, node coordinates are rounded to this many decimal places and cities are
+    /// blanked before anything is told about them, so that nodes can't be pinpointed.
+    pub anonymize_locations: Option<u8>,
+    /// If set, warn about chains and shards whose node messages have recently had a 99th
+    /// percentile shard→core transit time or core queue time longer than this.
+    pub ingest_latency_warning: Option<Duration>,
+    /// If set, no more than this many chains are tracked at once. Nodes on new third party
+    /// chains are turned away at the limit, and first party chains evict the third party
+    /// chain with the fewest nodes to make room.
+    pub max_chains: Option<usize>,
+    /// If set, feeds that haven't been sent anything for this long are sent a heartbeat,
+    /// so that they can tell a quiet chain from a dead connection.
+    pub feed_heartbeat_interval: Option<Duration>,
+    /// Parts of node names matching these patterns are redacted before the names are
+    /// handed out to feeds or the public API.
+    pub name_redactor: Arc<NameRedactor>,
 }
 
 struct AggregatorInternal {
@@ -62,19 +110,23 @@ impl Aggregator {
         let (tx_to_aggregator, rx_from_external) = flume::unbounded();
 
         // Kick off a locator task to locate nodes, which hands back a channel to make location requests
-        let tx_to_locator =
-            find_location(tx_to_aggregator.clone().into_sink().with(|(node_id, msg)| {
+        let location_cache_stats = CacheStats::default();
+        let tx_to_locator = find_location(
+            tx_to_aggregator.clone().into_sink().with(|(node_id, msg)| {
                 future::ok::<_, flume::SendError<_>>(inner_loop::ToAggregator::FromFindLocation(
                     node_id, msg,
                 ))
-            }));
+            }),
+            location_cache_stats.clone(),
+            opts.anonymizers.clone(),
+        );
 
         // Handle any incoming messages in our handler loop:
         tokio::spawn(Aggregator::handle_messages(
             rx_from_external,
             tx_to_locator,
-            opts.max_queue_len,
-            opts.denylist,
+            location_cache_stats,
+            opts,
         ));
 
         // Return a handle to our aggregator:
@@ -90,11 +142,11 @@ impl Aggregator {
     /// any more, this task will gracefully end.
     async fn handle_messages(
         rx_from_external: flume::Receiver<inner_loop::ToAggregator>,
-        tx_to_aggregator: flume::Sender<(NodeId, Ipv4Addr)>,
-        max_queue_len: usize,
-        denylist: Vec<String>,
+        tx_to_aggregator: flume::Sender<LocateRequest<NodeId>>,
+        location_cache_stats: CacheStats,
+        opts: AggregatorOpts,
     ) {
-        inner_loop::InnerLoop::new(tx_to_aggregator, denylist, max_queue_len)
+        inner_loop::InnerLoop::new(tx_to_aggregator, location_cache_stats, opts)
             .handle(rx_from_external)
             .await;
     }
@@ -110,6 +162,315 @@ impl Aggregator {
         Ok(metrics)
     }
 
+    /// Gather details about every chain from our aggregator loop
+    pub async fn gather_chains(&self) -> anyhow::Result<inner_loop::ChainList> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherChains(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let chains = rx.recv_async().await?;
+        Ok(chains)
+    }
+
+    /// Gather details about a chain from our aggregator loop
+    pub async fn gather_chain_details(
+        &self,
+        genesis_hash: BlockHash,
+    ) -> anyhow::Result<Option<inner_loop::ChainDetails>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherChainDetails(genesis_hash, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let details = rx.recv_async().await?;
+        Ok(details)
+    }
+
+    /// Gather the node count history of a chain from our aggregator loop
+    pub async fn gather_node_count_history(
+        &self,
+        genesis_hash: BlockHash,
+    ) -> anyhow::Result<Option<NodeCountHistory>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherNodeCountHistory(genesis_hash, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let history = rx.recv_async().await?;
+        Ok(history)
+    }
+
+    /// Gather the current state of a node from our aggregator loop
+    pub async fn gather_node_info(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<Option<NodeInfo>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherNodeInfo(genesis_hash, node_id, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let info = rx.recv_async().await?;
+        Ok(info)
+    }
+
+    /// Gather the current state of the nodes on a chain that match the filter given from
+    /// our aggregator loop
+    pub async fn gather_chain_nodes(
+        &self,
+        genesis_hash: BlockHash,
+        filter: Option<NodeFilter>,
+    ) -> anyhow::Result<Option<Vec<NodeInfo>>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherChainNodes(genesis_hash, filter, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let nodes = rx.recv_async().await?;
+        Ok(nodes)
+    }
+
+    /// Gather the last few new best blocks that a node announced from our aggregator loop
+    pub async fn gather_node_blocks(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<Option<Vec<RecentBlock>>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherNodeBlocks(genesis_hash, node_id, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let blocks = rx.recv_async().await?;
+        Ok(blocks)
+    }
+
+    /// Gather where the nodes on a chain are, clustered into cells of the given size in
+    /// degrees, from our aggregator loop
+    pub async fn gather_location_clusters(
+        &self,
+        genesis_hash: BlockHash,
+        cell_degrees: f32,
+    ) -> anyhow::Result<Option<Vec<LocationCluster>>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherLocationClusters(genesis_hash, cell_degrees, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let clusters = rx.recv_async().await?;
+        Ok(clusters)
+    }
+
+    /// Gather an anonymized snapshot of the nodes on every first party chain from our
+    /// aggregator loop
+    pub async fn gather_dataset(
+        &self,
+        hasher: Arc<NetworkIdHasher>,
+    ) -> anyhow::Result<Vec<ChainDataset>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherDataset(hasher, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let datasets = rx.recv_async().await?;
+        Ok(datasets)
+    }
+
+    /// Ask our aggregator loop to take a sample of the node count of every chain.
+    pub async fn sample_node_counts(&self) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::SampleNodeCounts;
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Ask our aggregator loop to evict node count history from the chains that are due it.
+    pub async fn enforce_retention_policies(&self) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::EnforceRetentionPolicies;
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Replace the denylist that our aggregator loop checks new nodes against, optionally
+    /// closing nodes already connected from chains that are now denied.
+    pub async fn set_denylist(
+        &self,
+        denylist: Vec<String>,
+        close_denied_nodes: bool,
+    ) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::SetDenylist(denylist, close_denied_nodes);
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Replace the patterns that are redacted from node names.
+    pub async fn set_name_redactor(&self, redactor: Arc<NameRedactor>) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::SetNameRedactor(redactor);
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Gather the retention policy of a chain from our aggregator loop.
+    pub async fn gather_retention_policy(
+        &self,
+        genesis_hash: BlockHash,
+    ) -> anyhow::Result<Option<RetentionPolicy>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherRetentionPolicy(genesis_hash, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let policy = rx.recv_async().await?;
+        Ok(policy)
+    }
+
+    /// Gather how much has been sent to each feed from our aggregator loop, by feed ID.
+    pub async fn gather_feed_stats(&self) -> anyhow::Result<BTreeMap<u64, FeedStats>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherFeedStats(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let stats = rx.recv_async().await?;
+        Ok(stats)
+    }
+
+    /// Change the retention policy of a chain, returning `false` if our aggregator loop
+    /// doesn't know about the chain.
+    pub async fn set_retention_policy(
+        &self,
+        genesis_hash: BlockHash,
+        policy: RetentionPolicy,
+    ) -> anyhow::Result<bool> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::SetRetentionPolicy(genesis_hash, policy, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let found = rx.recv_async().await?;
+        Ok(found)
+    }
+
+    /// Look up the location of a node again, returning `false` if our aggregator loop
+    /// doesn't know about the node (or its IPV4 address).
+    pub async fn relocate_node(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<bool> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::RelocateNode(genesis_hash, node_id, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let relocating = rx.recv_async().await?;
+        Ok(relocating)
+    }
+
+    /// Have the shards connected to our aggregator loop drop updates for a chain for the
+    /// given length of time, returning when they'll stop.
+    pub async fn mute_chain(
+        &self,
+        genesis_hash: BlockHash,
+        ttl: Duration,
+    ) -> anyhow::Result<Timestamp> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::MuteChain(genesis_hash, ttl, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let muted_until = rx.recv_async().await?;
+        Ok(muted_until)
+    }
+
+    /// Have the shards connected to our aggregator loop stop dropping updates for a chain,
+    /// returning `false` if it wasn't muted.
+    pub async fn unmute_chain(&self, genesis_hash: BlockHash) -> anyhow::Result<bool> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::UnmuteChain(genesis_hash, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let was_muted = rx.recv_async().await?;
+        Ok(was_muted)
+    }
+
+    /// Ask our aggregator loop to tell feeds about changes in how many nodes are at
+    /// the best block of each chain.
+    pub async fn send_nodes_at_best(&self) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::SendNodesAtBest;
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Ask our aggregator loop to tell feeds about changes to the stats of each chain.
+    pub async fn send_chain_stats(&self) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::SendChainStats;
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Return how many of our aggregator loop's feeds are subscribed to each chain that has
+    /// any, by genesis hash.
+    pub async fn gather_subscriber_counts(&self) -> anyhow::Result<HashMap<BlockHash, u32>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherSubscriberCounts(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let counts = rx.recv_async().await?;
+        Ok(counts)
+    }
+
+    /// Ask our aggregator loop to tell the feeds of each chain how many of them there are,
+    /// if they're due to be told, given how many feeds other aggregators have subscribed
+    /// to each chain.
+    pub async fn send_subscriber_counts(
+        &self,
+        elsewhere: HashMap<BlockHash, u32>,
+    ) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::SendSubscriberCounts(elsewhere);
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Ask our aggregator loop to send a heartbeat to any feed that hasn't been sent
+    /// anything for a while.
+    pub async fn send_feed_heartbeats(&self) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::SendFeedHeartbeats;
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Ask our aggregator loop to remove nodes that disconnected longer ago than
+    /// the reconnect debounce window, and tell feeds about it.
+    pub async fn expire_disconnected_nodes(&self) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::ExpireDisconnectedNodes;
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Ask our aggregator loop to warn about any chains or shards whose node messages
+    /// have been slow to arrive or be handled since it was last asked.
+    pub async fn check_ingest_latency(&self) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::CheckIngestLatency;
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Ask our aggregator loop to tell its feeds that we're shutting down, and then to
+    /// close them. This resolves once every message queued before it has been handled.
+    pub async fn shutdown(&self, restart_in_seconds: Option<u32>) -> anyhow::Result<()> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::Shutdown(restart_in_seconds, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        rx.recv_async().await?;
+        Ok(())
+    }
+
     /// Return a sink that a shard can send messages into to be handled by the aggregator.
     pub fn subscribe_shard(
         &self,
@@ -1,10 +1,43 @@
 use super::aggregator::{Aggregator, AggregatorOpts};
 use super::inner_loop;
+use crate::dataset_export::{ChainDataset, NetworkIdHasher};
+use crate::name_redaction::NameRedactor;
+use crate::state::{
+    LocationCluster, NodeCountHistory, NodeInfo, RecentBlock, RetentionPolicy,
+    NODE_COUNT_SAMPLE_INTERVAL_MS,
+};
+use common::node_types::{BlockHash, Timestamp};
 use common::EitherSink;
 use futures::{Sink, SinkExt};
-use inner_loop::{FromShardWebsocket, Metrics};
+use inner_loop::{ChainDetails, ChainList, FeedStats, FromShardWebsocket, Metrics, NodeFilter};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often feeds are told about changes in how many nodes are at the best block.
+const NODES_AT_BEST_INTERVAL: Duration = Duration::from_secs(1);
+const CHAIN_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often aggregators check whether the feeds of any chain are due to be told how
+/// many of them there are.
+const SUBSCRIBER_COUNTS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often aggregators check for feeds that are due a heartbeat. Each feed is only sent
+/// one once it's gone the heartbeat interval without being sent anything.
+const FEED_HEARTBEATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often nodes that have been disconnected for longer than the reconnect
+/// debounce window are removed.
+const EXPIRE_DISCONNECTED_NODES_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often aggregators check whether any chain is due to have its node count history
+/// evicted. Each chain is only evicted from as often as its retention policy says.
+const ENFORCE_RETENTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often aggregators check for chains and shards whose node messages have been slow to
+/// arrive or be handled. Each check looks at the latencies seen since the last one.
+const CHECK_INGEST_LATENCY_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Clone)]
 pub struct AggregatorSet(Arc<AggregatorSetInner>);
@@ -22,6 +55,10 @@ impl AggregatorSet {
         opts: AggregatorOpts,
     ) -> anyhow::Result<AggregatorSet> {
         assert_ne!(num_aggregators, 0, "You must have 1 or more aggregator");
+        let holds_disconnected_nodes =
+            opts.reconnect_debounce.is_some() || opts.shard_restart_grace.is_some();
+        let ingest_latency_warning = opts.ingest_latency_warning;
+        let sends_feed_heartbeats = opts.feed_heartbeat_interval.is_some();
 
         let aggregators = futures::future::try_join_all(
             (0..num_aggregators).map(|_| Aggregator::spawn(opts.clone())),
@@ -38,6 +75,26 @@ impl AggregatorSet {
 
         // Start asking for metrics:
         this.spawn_metrics_loops();
+        // Start sampling node counts:
+        this.spawn_node_count_sampling_loops();
+        // Start telling feeds how many nodes are at the best block:
+        this.spawn_nodes_at_best_loops();
+        this.spawn_chain_stats_loops();
+        this.spawn_subscriber_counts_loop();
+        // Start evicting node count history according to each chain's retention policy:
+        this.spawn_retention_loops();
+        // Start removing nodes that haven't reconnected in time:
+        if holds_disconnected_nodes {
+            this.spawn_expire_disconnected_nodes_loops();
+        }
+        // Start warning about slow ingestion of node messages:
+        if ingest_latency_warning.is_some() {
+            this.spawn_check_ingest_latency_loops();
+        }
+        // Start sending heartbeats to quiet feeds:
+        if sends_feed_heartbeats {
+            this.spawn_feed_heartbeat_loops();
+        }
 
         Ok(this)
     }
@@ -77,11 +134,405 @@ impl AggregatorSet {
         }
     }
 
+    /// Spawn loops which periodically ask each internal aggregator to sample the
+    /// node count of its chains.
+    fn spawn_node_count_sampling_loops(&self) {
+        let interval = Duration::from_millis(NODE_COUNT_SAMPLE_INTERVAL_MS);
+        for a in self.0.aggregators.clone() {
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = a.sample_node_counts().await {
+                        log::error!("Error sampling node counts (bailing): {}", e);
+                        return;
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            });
+        }
+    }
+
+    /// Spawn loops which periodically ask each internal aggregator to tell its feeds
+    /// how many nodes are at the best block of their chain, if it's changed.
+    fn spawn_nodes_at_best_loops(&self) {
+        for a in self.0.aggregators.clone() {
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = a.send_nodes_at_best().await {
+                        log::error!("Error sending nodes at best (bailing): {}", e);
+                        return;
+                    }
+                    tokio::time::sleep(NODES_AT_BEST_INTERVAL).await;
+                }
+            });
+        }
+    }
+
+    /// Spawn loops which periodically ask each internal aggregator to tell its feeds
+    /// about changes to the stats of each chain.
+    fn spawn_chain_stats_loops(&self) {
+        for a in self.0.aggregators.clone() {
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = a.send_chain_stats().await {
+                        log::error!("Error sending chain stats (bailing): {}", e);
+                        return;
+                    }
+                    tokio::time::sleep(CHAIN_STATS_INTERVAL).await;
+                }
+            });
+        }
+    }
+
+    /// Spawn a loop which periodically asks every internal aggregator how many of its feeds
+    /// are subscribed to each chain, and then has each of them tell the feeds of each chain
+    /// how many there are in total, if they're due to be told.
+    fn spawn_subscriber_counts_loop(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = this.send_subscriber_counts().await {
+                    log::error!("Error sending subscriber counts (bailing): {}", e);
+                    return;
+                }
+                tokio::time::sleep(SUBSCRIBER_COUNTS_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Tell the feeds of each chain how many feeds are subscribed to it across every
+    /// aggregator, if they're due to be told. Each aggregator is told how many feeds the
+    /// others have, and adds its own.
+    async fn send_subscriber_counts(&self) -> anyhow::Result<()> {
+        let counts = self.gather_subscriber_counts().await?;
+        futures::future::try_join_all(self.0.aggregators.iter().enumerate().map(|(idx, a)| {
+            let elsewhere = sum_subscriber_counts(
+                counts
+                    .iter()
+                    .enumerate()
+                    .filter(|&(other_idx, _)| other_idx != idx)
+                    .map(|(_, counts)| counts),
+            );
+            a.send_subscriber_counts(elsewhere)
+        }))
+        .await?;
+        Ok(())
+    }
+
+    /// Return how many feeds each internal aggregator has subscribed to each chain.
+    async fn gather_subscriber_counts(&self) -> anyhow::Result<Vec<HashMap<BlockHash, u32>>> {
+        futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.gather_subscriber_counts()),
+        )
+        .await
+    }
+
+    /// Return how many feeds are subscribed to each chain across every internal aggregator.
+    async fn total_subscriber_counts(&self) -> anyhow::Result<HashMap<BlockHash, u32>> {
+        let counts = self.gather_subscriber_counts().await?;
+        Ok(sum_subscriber_counts(counts.iter()))
+    }
+
+    /// Spawn loops which periodically ask each internal aggregator to send a heartbeat to
+    /// any of its feeds that haven't been sent anything for a while.
+    fn spawn_feed_heartbeat_loops(&self) {
+        for a in self.0.aggregators.clone() {
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(FEED_HEARTBEATS_INTERVAL).await;
+                    if let Err(e) = a.send_feed_heartbeats().await {
+                        log::error!("Error sending feed heartbeats (bailing): {}", e);
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Spawn loops which periodically ask each internal aggregator to evict node count
+    /// history according to the retention policy of each chain.
+    fn spawn_retention_loops(&self) {
+        for a in self.0.aggregators.clone() {
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = a.enforce_retention_policies().await {
+                        log::error!("Error enforcing retention policies (bailing): {}", e);
+                        return;
+                    }
+                    tokio::time::sleep(ENFORCE_RETENTION_INTERVAL).await;
+                }
+            });
+        }
+    }
+
+    /// Spawn loops which periodically ask each internal aggregator to warn about chains
+    /// and shards whose node messages have recently been slow to arrive or be handled.
+    fn spawn_check_ingest_latency_loops(&self) {
+        for a in self.0.aggregators.clone() {
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(CHECK_INGEST_LATENCY_INTERVAL).await;
+                    if let Err(e) = a.check_ingest_latency().await {
+                        log::error!("Error checking ingest latency (bailing): {}", e);
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Spawn loops which periodically ask each internal aggregator to remove the nodes
+    /// which disconnected and haven't reconnected within the debounce window.
+    fn spawn_expire_disconnected_nodes_loops(&self) {
+        for a in self.0.aggregators.clone() {
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = a.expire_disconnected_nodes().await {
+                        log::error!("Error expiring disconnected nodes (bailing): {}", e);
+                        return;
+                    }
+                    tokio::time::sleep(EXPIRE_DISCONNECTED_NODES_INTERVAL).await;
+                }
+            });
+        }
+    }
+
     /// Return the latest metrics we've gathered so far from each internal aggregator.
     pub fn latest_metrics(&self) -> Vec<Metrics> {
         self.0.metrics.lock().unwrap().clone()
     }
 
+    /// Tell every feed that we're shutting down (and when we expect to be back, in seconds,
+    /// if known), and then close them. Each aggregator handles everything that was sent to
+    /// it before this first, so feeds aren't cut off part way through.
+    pub async fn shutdown(&self, restart_in_seconds: Option<u32>) -> anyhow::Result<()> {
+        futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.shutdown(restart_in_seconds)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Return the retention policy of the chain with the given genesis hash, if it exists.
+    pub async fn retention_policy(
+        &self,
+        genesis_hash: BlockHash,
+    ) -> anyhow::Result<Option<RetentionPolicy>> {
+        self.0.aggregators[0]
+            .gather_retention_policy(genesis_hash)
+            .await
+    }
+
+    /// Change the retention policy of the chain with the given genesis hash. Each aggregator
+    /// keeps its own node count history, so every one of them is told. Returns `false` if
+    /// the chain doesn't exist.
+    pub async fn set_retention_policy(
+        &self,
+        genesis_hash: BlockHash,
+        policy: RetentionPolicy,
+    ) -> anyhow::Result<bool> {
+        let found = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.set_retention_policy(genesis_hash, policy)),
+        )
+        .await?;
+        Ok(found.into_iter().any(|found| found))
+    }
+
+    /// Return how much has been sent to each feed, by feed ID. Each aggregator has its own
+    /// feeds (and gives them IDs independently), so there's one set of stats per aggregator.
+    pub async fn feed_stats(&self) -> anyhow::Result<Vec<BTreeMap<u64, FeedStats>>> {
+        futures::future::try_join_all(self.0.aggregators.iter().map(|a| a.gather_feed_stats()))
+            .await
+    }
+
+    /// Replace the denylist of every aggregator, since each one adds nodes independently.
+    /// If `close_denied_nodes`, nodes already connected from a chain that's now denied are
+    /// closed, rather than just new nodes being turned away.
+    pub async fn set_denylist(
+        &self,
+        denylist: Vec<String>,
+        close_denied_nodes: bool,
+    ) -> anyhow::Result<()> {
+        futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.set_denylist(denylist.clone(), close_denied_nodes)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Replace the patterns that every aggregator redacts from node names, since each one
+    /// has its own feeds.
+    pub async fn set_name_redactor(&self, redactor: Arc<NameRedactor>) -> anyhow::Result<()> {
+        futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.set_name_redactor(redactor.clone())),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Look up the location of a node again, ignoring any cached location, returning
+    /// `false` if the node isn't known about. Each aggregator has its own cache of
+    /// locations and tells its own feeds about them, so every one of them is asked.
+    pub async fn relocate_node(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<bool> {
+        let relocating = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.relocate_node(genesis_hash, node_id)),
+        )
+        .await?;
+        Ok(relocating.into_iter().any(|relocating| relocating))
+    }
+
+    /// Have every shard drop updates for a chain for the given length of time, returning
+    /// when they'll stop. Every aggregator hears from every shard, so each of them tells
+    /// the shards (which don't mind being told more than once), and each of them can
+    /// report the mute and tell shards that connect later about it.
+    pub async fn mute_chain(
+        &self,
+        genesis_hash: BlockHash,
+        ttl: Duration,
+    ) -> anyhow::Result<Timestamp> {
+        let muted_until = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.mute_chain(genesis_hash, ttl)),
+        )
+        .await?;
+        Ok(muted_until.into_iter().max().unwrap_or_default())
+    }
+
+    /// Have every shard stop dropping updates for a chain, returning `false` if it
+    /// wasn't muted.
+    pub async fn unmute_chain(&self, genesis_hash: BlockHash) -> anyhow::Result<bool> {
+        let was_muted = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.unmute_chain(genesis_hash)),
+        )
+        .await?;
+        Ok(was_muted.into_iter().any(|was_muted| was_muted))
+    }
+
+    /// Return details about every chain. As with [`AggregatorSet::chain_details`], we can
+    /// ask any aggregator for this, apart from how many feeds each chain has.
+    pub async fn chains(&self) -> anyhow::Result<ChainList> {
+        let (mut list, counts) = futures::future::try_join(
+            self.0.aggregators[0].gather_chains(),
+            self.total_subscriber_counts(),
+        )
+        .await?;
+        for details in &mut list.chains {
+            details.subscriber_count = counts.get(&details.genesis_hash).copied().unwrap_or(0);
+        }
+        Ok(list)
+    }
+
+    /// Return details about the chain with the given genesis hash, if it exists. Every
+    /// aggregator is told about every node, so we can ask any one of them for this. Feeds
+    /// are spread across aggregators though, so every one of them is asked how many feeds
+    /// the chain has.
+    pub async fn chain_details(
+        &self,
+        genesis_hash: BlockHash,
+    ) -> anyhow::Result<Option<ChainDetails>> {
+        let (details, counts) = futures::future::try_join(
+            self.0.aggregators[0].gather_chain_details(genesis_hash),
+            self.total_subscriber_counts(),
+        )
+        .await?;
+        Ok(details.map(|mut details| {
+            details.subscriber_count = counts.get(&genesis_hash).copied().unwrap_or(0);
+            details
+        }))
+    }
+
+    /// Return the node count history of the chain with the given genesis hash, if it exists.
+    /// Each aggregator samples independently, so we just ask the first one.
+    pub async fn node_count_history(
+        &self,
+        genesis_hash: BlockHash,
+    ) -> anyhow::Result<Option<NodeCountHistory>> {
+        self.0.aggregators[0]
+            .gather_node_count_history(genesis_hash)
+            .await
+    }
+
+    /// Return the current state of the node with the given ID on the chain with the given
+    /// genesis hash, if it exists. Every aggregator is told about every node in the same
+    /// order, and so knows it by the same ID; we just ask the first one.
+    pub async fn node_info(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<Option<NodeInfo>> {
+        self.0.aggregators[0]
+            .gather_node_info(genesis_hash, node_id)
+            .await
+    }
+
+    /// Return the current state of every node on the chain with the given genesis hash
+    /// that matches the filter, if the chain exists. As with [`AggregatorSet::node_info`],
+    /// the first aggregator knows as much as any other.
+    pub async fn chain_nodes(
+        &self,
+        genesis_hash: BlockHash,
+        filter: Option<NodeFilter>,
+    ) -> anyhow::Result<Option<Vec<NodeInfo>>> {
+        self.0.aggregators[0]
+            .gather_chain_nodes(genesis_hash, filter)
+            .await
+    }
+
+    /// Return the last few new best blocks that the node with the given ID on the chain
+    /// with the given genesis hash announced, oldest first, if the node exists.
+    pub async fn node_blocks(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<Option<Vec<RecentBlock>>> {
+        self.0.aggregators[0]
+            .gather_node_blocks(genesis_hash, node_id)
+            .await
+    }
+
+    /// Return where the nodes on the chain with the given genesis hash are, clustered into
+    /// square cells of `cell_degrees` on a side, if the chain exists.
+    pub async fn location_clusters(
+        &self,
+        genesis_hash: BlockHash,
+        cell_degrees: f32,
+    ) -> anyhow::Result<Option<Vec<LocationCluster>>> {
+        self.0.aggregators[0]
+            .gather_location_clusters(genesis_hash, cell_degrees)
+            .await
+    }
+
+    /// Return an anonymized snapshot of the nodes on every first party chain.
+    pub async fn dataset(&self, hasher: Arc<NetworkIdHasher>) -> anyhow::Result<Vec<ChainDataset>> {
+        self.0.aggregators[0].gather_dataset(hasher).await
+    }
+
     /// Return a sink that a shard can send messages into to be handled by all aggregators.
     pub fn subscribe_shard(
         &self,
@@ -133,3 +584,156 @@ impl AggregatorSet {
         self.0.aggregators[this_idx].subscribe_feed()
     }
 }
+
+/// Add up how many feeds are subscribed to each chain across some aggregators.
+fn sum_subscriber_counts<'a>(
+    counts: impl IntoIterator<Item = &'a HashMap<BlockHash, u32>>,
+) -> HashMap<BlockHash, u32> {
+    let mut total = HashMap::new();
+    for counts in counts {
+        for (genesis_hash, count) in counts {
+            *total.entry(*genesis_hash).or_insert(0) += count;
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::{FromFeedWebsocket, ToFeedWebsocket};
+    use crate::feed_budget::FeedBudgets;
+    use crate::feed_priority::FeedPriorities;
+    use crate::state::ChainOpts;
+    use common::internal_messages::ShardNodeId;
+    use common::node_types::NodeDetails;
+    use test_utils::feed_message_de::FeedMessage;
+
+    fn opts() -> AggregatorOpts {
+        AggregatorOpts {
+            denylist: Vec::new(),
+            max_queue_len: 1000,
+            slow_message_threshold: None,
+            chain_opts: ChainOpts::default(),
+            metrics_chain_allowlist: Vec::new(),
+            feed_priorities: FeedPriorities::default(),
+            feed_budgets: FeedBudgets::default(),
+            reconnect_debounce: None,
+            shard_restart_grace: None,
+            anonymizers: Default::default(),
+            anonymize_locations: None,
+            ingest_latency_warning: None,
+            max_chains: None,
+            feed_heartbeat_interval: None,
+            name_redactor: Default::default(),
+        }
+    }
+
+    fn node(chain: &str) -> NodeDetails {
+        NodeDetails {
+            chain: chain.into(),
+            name: "Alice".into(),
+            implementation: "Substrate Node".into(),
+            version: "0.1".into(),
+            validator: None,
+            network_id: None,
+            startup_time: None,
+            chain_type: None,
+            environment: None,
+            pruning_mode: None,
+            offchain_indexing: false,
+            pending_upgrade_block: None,
+            verbosity: None,
+            publicly_reachable: None,
+            message_schema: None,
+            extra_info: Default::default(),
+        }
+    }
+
+    /// The last subscriber count that a feed has been sent, if any.
+    fn last_subscriber_count(feed: &flume::Receiver<ToFeedWebsocket>) -> Option<u32> {
+        feed.drain()
+            .flat_map(|ToFeedWebsocket::Bytes(bytes)| FeedMessage::from_bytes(&bytes).unwrap())
+            .filter_map(|msg| match msg {
+                FeedMessage::SubscriberCount { count, .. } => Some(count),
+                _ => None,
+            })
+            .last()
+    }
+
+    #[tokio::test]
+    async fn subscriber_counts_are_summed_across_aggregators() {
+        let aggregator = AggregatorSet::spawn(2, opts()).await.unwrap();
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+
+        let mut shard = aggregator.subscribe_shard();
+        let (channel, _shard_rx) = flume::unbounded();
+        shard
+            .send(FromShardWebsocket::Initialize { channel })
+            .await
+            .unwrap();
+        shard
+            .send(FromShardWebsocket::Add {
+                local_id: ShardNodeId::new(0),
+                ip: "127.0.0.1".parse().unwrap(),
+                node: node("Chain"),
+                genesis_hash,
+                resumed: false,
+            })
+            .await
+            .unwrap();
+
+        // Wait for every aggregator to hear about the chain before feeds subscribe to it:
+        for a in &aggregator.0.aggregators {
+            while a
+                .gather_chain_details(genesis_hash)
+                .await
+                .unwrap()
+                .is_none()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        // Feeds are handed out to aggregators in turn, so two land on each:
+        let mut feeds = Vec::new();
+        for _ in 0..4 {
+            let (_, mut tx) = aggregator.subscribe_feed();
+            let (channel, rx) = flume::unbounded();
+            tx.send(FromFeedWebsocket::Initialize {
+                channel,
+                node_filter: None,
+            })
+            .await
+            .unwrap();
+            tx.send(FromFeedWebsocket::Subscribe {
+                chain: "Chain".into(),
+            })
+            .await
+            .unwrap();
+            feeds.push((tx, rx));
+        }
+        let counts = aggregator.gather_subscriber_counts().await.unwrap();
+        assert_eq!(counts.len(), 2);
+        for counts in &counts {
+            assert_eq!(counts.get(&genesis_hash), Some(&2));
+        }
+
+        // The REST API reports every feed, whichever aggregator it's on:
+        let details = aggregator
+            .chain_details(genesis_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(details.subscriber_count, 4);
+        let list = aggregator.chains().await.unwrap();
+        assert_eq!(list.chains[0].subscriber_count, 4);
+
+        // ...and so are the feeds on every aggregator once they're told about the others:
+        aggregator.send_subscriber_counts().await.unwrap();
+        aggregator.gather_subscriber_counts().await.unwrap();
+        for (_, rx) in &feeds {
+            assert_eq!(last_subscriber_count(rx), Some(4));
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Fuzz targets for the commands and queries that feeds send us. See
+//! [`test_utils::fuzz`] for how to run these for longer than the smoke run that
+//! happens with the other tests.
+
+use super::inner_loop::{node_filter_from_query, FromFeedWebsocket, NodeFilter};
+use std::str::FromStr;
+use test_utils::fuzz;
+
+/// Parsing a command copies (at most) the input, but failing to parse one can
+/// capture a backtrace, which needs some fixed overhead.
+const BUDGET_PER_BYTE: usize = 8;
+const BUDGET_OVERHEAD: usize = 64 * 1024;
+
+/// Turn a command back into the text that a feed would have sent for it.
+fn command_to_string(cmd: &FromFeedWebsocket) -> String {
+    match cmd {
+        FromFeedWebsocket::Ping { value } => format!("ping:{}", value),
+        FromFeedWebsocket::Subscribe { chain } => format!("subscribe:{}", chain),
+        FromFeedWebsocket::SendFinality => "send-finality:".to_string(),
+        FromFeedWebsocket::NoMoreFinality => "no-more-finality:".to_string(),
+        FromFeedWebsocket::SendDistribution => "send-distribution:".to_string(),
+        FromFeedWebsocket::NoMoreDistribution => "no-more-distribution:".to_string(),
+        FromFeedWebsocket::Initialize { .. } | FromFeedWebsocket::Disconnected => {
+            panic!("feeds can't send {:?}", cmd)
+        }
+    }
+}
+
+/// Turn a filter back into the query that a feed would have connected with.
+fn filter_to_query(filter: &NodeFilter) -> String {
+    let mut pairs = Vec::new();
+    if let Some(network_id) = &filter.network_id {
+        pairs.push(format!("node={}", network_id));
+    }
+    if let Some(environment) = &filter.environment {
+        pairs.push(format!("environment={}", environment));
+    }
+    if let Some(offchain_indexing) = filter.offchain_indexing {
+        pairs.push(format!("offchain_indexing={}", offchain_indexing));
+    }
+    pairs.join("&")
+}
+
+#[test]
+fn fuzz_feed_commands() {
+    let seeds = [
+        "ping:1",
+        "ping:",
+        "subscribe:0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3",
+        "subscribe:Polkadot",
+        "send-finality:",
+        "no-more-finality:",
+        "send-distribution:",
+        "no-more-distribution:",
+    ];
+    fuzz::run(&seeds, |bytes| {
+        // Feeds send commands as websocket text frames, which are always UTF-8:
+        let text = match std::str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(_) => return,
+        };
+        let (cmd, allocated) = fuzz::bytes_allocated_during(|| FromFeedWebsocket::from_str(text));
+        fuzz::assert_within_budget(bytes, allocated, BUDGET_PER_BYTE, BUDGET_OVERHEAD);
+
+        if let Ok(cmd) = cmd {
+            let text = command_to_string(&cmd);
+            let reparsed = FromFeedWebsocket::from_str(&text).expect("command should reparse");
+            assert_eq!(command_to_string(&reparsed), text);
+        }
+    });
+}
+
+#[test]
+fn fuzz_feed_node_filters() {
+    let seeds = [
+        "node=12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+        "environment=prod&offchain_indexing=true",
+        "node=a&environment=b&offchain_indexing=false&other=c",
+    ];
+    fuzz::run(&seeds, |bytes| {
+        let query = match std::str::from_utf8(bytes) {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        let (filter, allocated) =
+            fuzz::bytes_allocated_during(|| node_filter_from_query(Some(query)));
+        fuzz::assert_within_budget(bytes, allocated, BUDGET_PER_BYTE, BUDGET_OVERHEAD);
+
+        if let Ok(Some(filter)) = filter {
+            let query = filter_to_query(&filter);
+            let reparsed = node_filter_from_query(Some(&query)).expect("filter should reparse");
+            assert_eq!(reparsed, Some(filter));
+        }
+    });
+}
@@ -0,0 +1,365 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Keep track of how long node messages take to get from a shard to the aggregator
+//! that handles them.
+
+use super::aggregator::ConnId;
+use common::node_types::{BlockHash, Timestamp};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// The upper bounds (in milliseconds) of the buckets that latencies are counted into.
+/// Anything slower than the last bound is counted in one final overflow bucket.
+pub const LATENCY_BUCKETS_MS: [u64; 14] = [
+    1, 2, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000,
+];
+
+/// When a node message was received by a shard, and then by us.
+#[derive(Debug, Clone, Copy)]
+pub struct IngestTimes {
+    /// When the shard received the message from the node, by the shard's clock.
+    pub shard_received_at: Timestamp,
+    /// When we received the message from the shard, by our clock.
+    pub core_received_at: Timestamp,
+    /// When we received the message from the shard. This is only compared against our
+    /// own clock, so unlike the timestamps above it isn't thrown off by clock skew.
+    pub queued_at: Instant,
+}
+
+impl IngestTimes {
+    /// The times for a message which the shard received at the time given, and which
+    /// we've just received.
+    pub fn received_now(shard_received_at: Timestamp) -> IngestTimes {
+        IngestTimes {
+            shard_received_at,
+            core_received_at: common::time::now(),
+            queued_at: Instant::now(),
+        }
+    }
+
+    /// How long the message took to get here from the shard. This relies on the shard's
+    /// clock agreeing with ours; if the shard's clock is ahead, this is 0.
+    pub fn transit_ms(&self) -> u64 {
+        self.core_received_at.saturating_sub(self.shard_received_at)
+    }
+
+    /// How long the message has been waiting here to be handled.
+    pub fn queued_ms(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.queued_at).as_millis() as u64
+    }
+}
+
+/// A count of latencies in each of [`LATENCY_BUCKETS_MS`] (plus an overflow bucket).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    count: u64,
+    sum_ms: u64,
+    max_ms: u64,
+}
+
+impl LatencyHistogram {
+    /// Count a single latency.
+    pub fn record(&mut self, ms: u64) {
+        let idx = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[idx] += 1;
+        self.count += 1;
+        self.sum_ms = self.sum_ms.saturating_add(ms);
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    /// How many latencies have been counted.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The total of every latency counted, in milliseconds.
+    pub fn sum_ms(&self) -> u64 {
+        self.sum_ms
+    }
+
+    /// How many latencies were no more than each of [`LATENCY_BUCKETS_MS`], in order.
+    /// Together with [`Self::count`], this is what a Prometheus histogram wants.
+    pub fn cumulative_counts(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        LATENCY_BUCKETS_MS
+            .iter()
+            .zip(self.buckets.iter())
+            .scan(0, |total, (&bound, &n)| {
+                *total += n;
+                Some((bound, *total))
+            })
+    }
+
+    /// Roughly the given percentile (from 0 to 100) of the latencies counted, in
+    /// milliseconds. This is the upper bound of the bucket that it falls in, or the
+    /// slowest latency seen if that's sooner. `None` if nothing has been counted.
+    pub fn percentile_ms(&self, percentile: u64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (percentile * self.count).div_ceil(100).max(1);
+        let mut total = 0;
+        for (idx, &n) in self.buckets.iter().enumerate() {
+            total += n;
+            if total >= rank {
+                let bound = LATENCY_BUCKETS_MS.get(idx).copied().unwrap_or(u64::MAX);
+                return Some(bound.min(self.max_ms));
+            }
+        }
+        Some(self.max_ms)
+    }
+
+    /// A summary of the latencies counted.
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            mean_ms: (self.count > 0).then(|| self.sum_ms as f64 / self.count as f64),
+            p50_ms: self.percentile_ms(50),
+            p99_ms: self.percentile_ms(99),
+            max_ms: (self.count > 0).then_some(self.max_ms),
+        }
+    }
+}
+
+/// A summary of some latencies, for reporting.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean_ms: Option<f64>,
+    pub p50_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+    pub max_ms: Option<u64>,
+}
+
+/// How long messages took to get here from a shard, and how long they then waited to be
+/// handled, both since we started and since we last checked for slow ingestion.
+#[derive(Debug, Clone, Default)]
+pub struct Latencies {
+    /// Every shard→core transit time since we started.
+    pub transit: LatencyHistogram,
+    /// Every time spent queued in the core since we started.
+    pub queued: LatencyHistogram,
+    recent_transit: LatencyHistogram,
+    recent_queued: LatencyHistogram,
+}
+
+impl Latencies {
+    fn record(&mut self, transit_ms: u64, queued_ms: u64) {
+        self.transit.record(transit_ms);
+        self.queued.record(queued_ms);
+        self.recent_transit.record(transit_ms);
+        self.recent_queued.record(queued_ms);
+    }
+
+    /// The 99th percentile of the recent transit and queue times, if they're over the
+    /// threshold given. This starts a new window of recent latencies either way.
+    fn take_slow_p99(&mut self, threshold_ms: u64) -> Option<(u64, u64)> {
+        let transit = std::mem::take(&mut self.recent_transit).percentile_ms(99)?;
+        let queued = std::mem::take(&mut self.recent_queued).percentile_ms(99)?;
+        (transit > threshold_ms || queued > threshold_ms).then_some((transit, queued))
+    }
+}
+
+/// Somewhere (a chain or a shard) that recent messages have been slow to arrive from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowIngest<K> {
+    pub from: K,
+    pub transit_p99_ms: u64,
+    pub queued_p99_ms: u64,
+}
+
+/// Shard→core transit times and core queue times of node messages, per chain and per shard.
+#[derive(Debug, Default)]
+pub struct IngestLatency {
+    chains: HashMap<BlockHash, Latencies>,
+    shards: HashMap<ConnId, Latencies>,
+}
+
+impl IngestLatency {
+    /// Record the latency of a message about a node on the given chain, which came from
+    /// the given shard and is being handled now.
+    pub fn record(
+        &mut self,
+        genesis_hash: BlockHash,
+        shard_conn_id: ConnId,
+        times: &IngestTimes,
+        now: Instant,
+    ) {
+        let transit_ms = times.transit_ms();
+        let queued_ms = times.queued_ms(now);
+        self.chains
+            .entry(genesis_hash)
+            .or_default()
+            .record(transit_ms, queued_ms);
+        self.shards
+            .entry(shard_conn_id)
+            .or_default()
+            .record(transit_ms, queued_ms);
+    }
+
+    /// The latencies of messages about nodes on each chain.
+    pub fn chains(&self) -> impl Iterator<Item = (&BlockHash, &Latencies)> {
+        self.chains.iter()
+    }
+
+    /// The latencies of messages from each shard.
+    pub fn shards(&self) -> impl Iterator<Item = (&ConnId, &Latencies)> {
+        self.shards.iter()
+    }
+
+    /// Forget about chains that we no longer know of.
+    pub fn retain_chains(&mut self, mut f: impl FnMut(&BlockHash) -> bool) {
+        self.chains.retain(|genesis_hash, _| f(genesis_hash));
+    }
+
+    /// Forget about a shard that has disconnected.
+    pub fn remove_shard(&mut self, shard_conn_id: ConnId) {
+        self.shards.remove(&shard_conn_id);
+    }
+
+    /// The chains and shards whose messages have recently had a 99th percentile transit
+    /// or queue time over the threshold given. Each call starts a new window of recent
+    /// latencies to check next time.
+    pub fn take_slow(
+        &mut self,
+        threshold_ms: u64,
+    ) -> (Vec<SlowIngest<BlockHash>>, Vec<SlowIngest<ConnId>>) {
+        fn take<K: Copy>(
+            latencies: &mut HashMap<K, Latencies>,
+            threshold_ms: u64,
+        ) -> Vec<SlowIngest<K>> {
+            latencies
+                .iter_mut()
+                .filter_map(|(&from, l)| {
+                    let (transit_p99_ms, queued_p99_ms) = l.take_slow_p99(threshold_ms)?;
+                    Some(SlowIngest {
+                        from,
+                        transit_p99_ms,
+                        queued_p99_ms,
+                    })
+                })
+                .collect()
+        }
+        (
+            take(&mut self.chains, threshold_ms),
+            take(&mut self.shards, threshold_ms),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn latencies_are_counted_into_buckets() {
+        let mut hist = LatencyHistogram::default();
+        for ms in [0, 1, 2, 3, 40_000] {
+            hist.record(ms);
+        }
+
+        assert_eq!(hist.count(), 5);
+        assert_eq!(hist.sum_ms(), 40_006);
+        let cumulative: Vec<_> = hist.cumulative_counts().take(4).collect();
+        assert_eq!(cumulative, vec![(1, 2), (2, 3), (5, 4), (10, 4)]);
+        // The overflow bucket isn't given; it's implied by the total count:
+        assert_eq!(hist.cumulative_counts().last(), Some((30_000, 4)));
+    }
+
+    #[test]
+    fn percentiles_are_the_upper_bound_of_their_bucket() {
+        let mut hist = LatencyHistogram::default();
+        assert_eq!(hist.percentile_ms(99), None);
+
+        for _ in 0..98 {
+            hist.record(3);
+        }
+        hist.record(80);
+        hist.record(700);
+
+        assert_eq!(hist.percentile_ms(50), Some(5));
+        assert_eq!(hist.percentile_ms(98), Some(5));
+        assert_eq!(hist.percentile_ms(99), Some(100));
+        // The slowest latency is known exactly, so we never report more than it:
+        assert_eq!(hist.percentile_ms(100), Some(700));
+
+        let summary = hist.summary();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.p99_ms, Some(100));
+        assert_eq!(summary.max_ms, Some(700));
+    }
+
+    #[test]
+    fn transit_time_tolerates_shard_clocks_being_ahead() {
+        let times = IngestTimes {
+            shard_received_at: 10_000,
+            core_received_at: 9_000,
+            queued_at: Instant::now(),
+        };
+        assert_eq!(times.transit_ms(), 0);
+
+        // Queue time is measured separately, so is unaffected:
+        let queued_ms = times.queued_ms(times.queued_at + Duration::from_millis(250));
+        assert_eq!(queued_ms, 250);
+    }
+
+    #[test]
+    fn slow_chains_and_shards_are_reported_once_per_window() {
+        let mut latency = IngestLatency::default();
+        let fast_chain = BlockHash::from_low_u64_be(1);
+        let slow_chain = BlockHash::from_low_u64_be(2);
+        let (shard_a, shard_b) = (ConnId::new(1), ConnId::new(2));
+
+        let start = Instant::now();
+        let received = |transit_ms: u64| IngestTimes {
+            shard_received_at: 1_000,
+            core_received_at: 1_000 + transit_ms,
+            queued_at: start,
+        };
+        let later = start + Duration::from_millis(3);
+
+        latency.record(fast_chain, shard_a, &received(1), later);
+        latency.record(slow_chain, shard_b, &received(2_000), later);
+
+        let (chains, shards) = latency.take_slow(1_000);
+        assert_eq!(
+            chains,
+            vec![SlowIngest {
+                from: slow_chain,
+                transit_p99_ms: 2_000,
+                queued_p99_ms: 3
+            }]
+        );
+        assert_eq!(shards.len(), 1);
+        assert_eq!(shards[0].from, shard_b);
+
+        // Each check starts a new window, but the totals are kept:
+        assert_eq!(latency.take_slow(1_000), (Vec::new(), Vec::new()));
+        let slow = latency.chains().find(|(h, _)| **h == slow_chain).unwrap().1;
+        assert_eq!(slow.transit.count(), 1);
+
+        latency.retain_chains(|h| *h != slow_chain);
+        latency.remove_shard(shard_b);
+        assert_eq!(latency.chains().count(), 1);
+        assert_eq!(latency.shards().count(), 1);
+    }
+}
@@ -14,18 +14,28 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use super::aggregator::ConnId;
+use super::aggregator::{AggregatorOpts, ConnId};
+use super::ingest_latency::{IngestLatency, IngestTimes, LatencyHistogram};
+use crate::dataset_export::{ChainDataset, NetworkIdHasher};
+use crate::feed_budget::{BudgetUsage, FeedBudgets};
 use crate::feed_message::{self, FeedMessageSerializer};
+use crate::feed_priority::{FeedPriorities, Priority};
 use crate::find_location;
-use crate::state::{self, NodeId, State};
+use crate::name_redaction::NameRedactor;
+use crate::state::{
+    self, ChainCapacity, Distribution, LocationCluster, MemoryUsage, NodeCountHistory, NodeId,
+    NodeInfo, RecentBlock, RetentionPolicy, State,
+};
 use bimap::BiMap;
 use common::{
-    internal_messages::{self, MuteReason, ShardNodeId},
+    internal_messages::{self, MuteReason, NodeCloseReason, ShardNodeId},
     node_message,
-    node_types::BlockHash,
+    node_types::{Block, BlockHash, BlockNumber, MessageSchema, NodeDetails, Timestamp},
     time, MultiMapUnique,
 };
-use std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Arc,
@@ -33,9 +43,13 @@ use std::sync::{
 use std::{
     net::{IpAddr, Ipv4Addr},
     str::FromStr,
+    time::{Duration, Instant},
 };
 
 /// Incoming messages come via subscriptions, and end up looking like this.
+// Node messages from shards make up almost all of the traffic here, so there's no
+// point boxing them to make the rarer variants smaller.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug)]
 pub enum ToAggregator {
     FromShardWebsocket(ConnId, FromShardWebsocket),
@@ -44,9 +58,142 @@ pub enum ToAggregator {
     /// Hand back some metrics. The provided sender is expected not to block when
     /// a message is sent into it.
     GatherMetrics(flume::Sender<Metrics>),
+    /// Hand back details about the chain with the given genesis hash, or `None` if
+    /// no such chain exists. The provided sender is expected not to block.
+    GatherChainDetails(BlockHash, flume::Sender<Option<ChainDetails>>),
+    /// Hand back details about every chain. The provided sender is expected not to block.
+    GatherChains(flume::Sender<ChainList>),
+    /// Hand back the node count history of the chain with the given genesis hash, or
+    /// `None` if no such chain exists. The provided sender is expected not to block.
+    GatherNodeCountHistory(BlockHash, flume::Sender<Option<NodeCountHistory>>),
+    /// Hand back the current state of the node with the given ID on the chain with the
+    /// given genesis hash, or `None` if no such node exists. The provided sender is
+    /// expected not to block.
+    GatherNodeInfo(BlockHash, usize, flume::Sender<Option<NodeInfo>>),
+    /// Hand back the current state of every node on the chain with the given genesis
+    /// hash that matches the filter (if one is given), or `None` if no such chain exists.
+    /// The provided sender is expected not to block.
+    GatherChainNodes(
+        BlockHash,
+        Option<NodeFilter>,
+        flume::Sender<Option<Vec<NodeInfo>>>,
+    ),
+    /// Hand back the last few new best blocks that a node announced, given the genesis
+    /// hash of its chain and the ID that feeds know it by.
+    GatherNodeBlocks(BlockHash, usize, flume::Sender<Option<Vec<RecentBlock>>>),
+    /// Hand back where the nodes on a chain are, clustered into cells of the given
+    /// size in degrees, given the genesis hash of the chain.
+    GatherLocationClusters(BlockHash, f32, flume::Sender<Option<Vec<LocationCluster>>>),
+    /// Gather an anonymized snapshot of the nodes on every first party chain.
+    GatherDataset(Arc<NetworkIdHasher>, flume::Sender<Vec<ChainDataset>>),
+    /// Take a sample of the node count of every chain.
+    SampleNodeCounts,
+    /// Tell feeds about any changes in how many nodes are at the best block of their chain.
+    SendNodesAtBest,
+    /// Tell every feed about any changes to the stats of each chain.
+    SendChainStats,
+    /// Hand back how many of this aggregator's feeds are subscribed to each chain that has
+    /// any, by genesis hash. The provided sender is expected not to block.
+    GatherSubscriberCounts(flume::Sender<HashMap<BlockHash, u32>>),
+    /// Tell the feeds of each chain how many of them there are, if they're due to be told,
+    /// given how many feeds other aggregators have subscribed to each chain.
+    SendSubscriberCounts(HashMap<BlockHash, u32>),
+    /// Send a heartbeat to any feed that hasn't been sent anything for a while.
+    SendFeedHeartbeats,
+    /// Remove nodes that disconnected and didn't reconnect within the debounce window.
+    ExpireDisconnectedNodes,
+    /// Warn about any chains or shards whose node messages have recently been slow to
+    /// arrive or to be handled.
+    CheckIngestLatency,
+    /// Evict node count history from each chain that's due it under its retention policy.
+    EnforceRetentionPolicies,
+    /// Hand back the retention policy of the chain with the given genesis hash, or `None`
+    /// if no such chain exists. The provided sender is expected not to block.
+    GatherRetentionPolicy(BlockHash, flume::Sender<Option<RetentionPolicy>>),
+    /// Hand back how much has been sent to each feed, by feed ID. The provided sender is
+    /// expected not to block.
+    GatherFeedStats(flume::Sender<BTreeMap<u64, FeedStats>>),
+    /// Change the retention policy of the chain with the given genesis hash. The provided
+    /// sender is told whether the chain exists, and is expected not to block.
+    SetRetentionPolicy(BlockHash, RetentionPolicy, flume::Sender<bool>),
+    /// Look up the location of the node with the given ID on the chain with the given
+    /// genesis hash again, ignoring any cached location for its IP address. Feeds hear
+    /// about the result as they would any other location. The provided sender is told
+    /// whether a lookup was started, and is expected not to block.
+    RelocateNode(BlockHash, usize, flume::Sender<bool>),
+    /// Have shards drop updates for the chain with the given genesis hash for this long.
+    /// The provided sender is told when the mute will end, and is expected not to block.
+    MuteChain(BlockHash, Duration, flume::Sender<Timestamp>),
+    /// Have shards stop dropping updates for the chain with the given genesis hash. The
+    /// provided sender is told whether the chain was muted, and is expected not to block.
+    UnmuteChain(BlockHash, flume::Sender<bool>),
+    /// Replace the names of the chains that nodes aren't allowed to connect from. If the
+    /// flag is set, nodes already connected from a chain that's now denied are closed.
+    SetDenylist(Vec<String>, bool),
+    /// Replace the patterns that are redacted from node names before they're handed out.
+    SetNameRedactor(Arc<NameRedactor>),
+    /// Tell feeds that the server is shutting down (and when it expects to be back, in
+    /// seconds, if known), and then close them. The provided sender is told once this
+    /// is done, and is expected not to block.
+    Shutdown(Option<u32>, flume::Sender<()>),
+}
+
+impl ToAggregator {
+    /// A short description of the type of message, for logging.
+    fn message_type(&self) -> &'static str {
+        match self {
+            ToAggregator::FromShardWebsocket(_, msg) => match msg {
+                FromShardWebsocket::Initialize { .. } => "shard initialize",
+                FromShardWebsocket::Add { .. } => "shard add node",
+                FromShardWebsocket::Update { payload, .. } => payload.name(),
+                FromShardWebsocket::Remove { .. } => "shard remove node",
+                FromShardWebsocket::Disconnected => "shard disconnected",
+            },
+            ToAggregator::FromFeedWebsocket(_, msg) => match msg {
+                FromFeedWebsocket::Initialize { .. } => "feed initialize",
+                FromFeedWebsocket::Subscribe { .. } => "feed subscribe",
+                FromFeedWebsocket::SendFinality => "feed send finality",
+                FromFeedWebsocket::NoMoreFinality => "feed no more finality",
+                FromFeedWebsocket::SendDistribution => "feed send distribution",
+                FromFeedWebsocket::NoMoreDistribution => "feed no more distribution",
+                FromFeedWebsocket::Ping { .. } => "feed ping",
+                FromFeedWebsocket::Disconnected => "feed disconnected",
+            },
+            ToAggregator::FromFindLocation(..) => "find location",
+            ToAggregator::GatherMetrics(..) => "gather metrics",
+            ToAggregator::GatherChainDetails(..) => "gather chain details",
+            ToAggregator::GatherChains(..) => "gather chains",
+            ToAggregator::GatherNodeCountHistory(..) => "gather node count history",
+            ToAggregator::GatherNodeInfo(..) => "gather node info",
+            ToAggregator::GatherNodeBlocks(..) => "gather node blocks",
+            ToAggregator::GatherLocationClusters(..) => "gather location clusters",
+            ToAggregator::GatherChainNodes(..) => "gather chain nodes",
+            ToAggregator::GatherDataset(..) => "gather dataset",
+            ToAggregator::SampleNodeCounts => "sample node counts",
+            ToAggregator::SendNodesAtBest => "send nodes at best",
+            ToAggregator::SendChainStats => "send chain stats",
+            ToAggregator::GatherSubscriberCounts(..) => "gather subscriber counts",
+            ToAggregator::SendSubscriberCounts(..) => "send subscriber counts",
+            ToAggregator::SendFeedHeartbeats => "send feed heartbeats",
+            ToAggregator::ExpireDisconnectedNodes => "expire disconnected nodes",
+            ToAggregator::CheckIngestLatency => "check ingest latency",
+            ToAggregator::EnforceRetentionPolicies => "enforce retention policies",
+            ToAggregator::GatherRetentionPolicy(..) => "gather retention policy",
+            ToAggregator::GatherFeedStats(..) => "gather feed stats",
+            ToAggregator::SetRetentionPolicy(..) => "set retention policy",
+            ToAggregator::RelocateNode(..) => "relocate node",
+            ToAggregator::MuteChain(..) => "mute chain",
+            ToAggregator::UnmuteChain(..) => "unmute chain",
+            ToAggregator::SetDenylist(..) => "set denylist",
+            ToAggregator::SetNameRedactor(..) => "set name redactor",
+            ToAggregator::Shutdown(..) => "shutdown",
+        }
+    }
 }
 
 /// An incoming shard connection can send these messages to the aggregator.
+// As with `ToAggregator`, node updates are by far the most common variant here.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug)]
 pub enum FromShardWebsocket {
     /// When the socket is opened, it'll send this first
@@ -60,14 +207,24 @@ pub enum FromShardWebsocket {
         ip: std::net::IpAddr,
         node: common::node_types::NodeDetails,
         genesis_hash: common::node_types::BlockHash,
+        /// The shard was connected to this node before it last disconnected from us.
+        resumed: bool,
     },
     /// Update/pass through details about a node.
     Update {
         local_id: ShardNodeId,
         payload: node_message::Payload,
+        /// When the node says that it sent the message, by its own clock.
+        reported_at: Option<Timestamp>,
+        /// When the message was received by the shard and by us, so that we can keep
+        /// track of how long messages take to be handled.
+        ingest: IngestTimes,
+    },
+    /// Tell the aggregator that a node has been removed when it disconnects, and why.
+    Remove {
+        local_id: ShardNodeId,
+        reason: NodeCloseReason,
     },
-    /// Tell the aggregator that a node has been removed when it disconnects.
-    Remove { local_id: ShardNodeId },
     /// The shard is disconnected.
     Disconnected,
 }
@@ -80,6 +237,17 @@ pub enum ToShardWebsocket {
         local_id: ShardNodeId,
         reason: internal_messages::MuteReason,
     },
+    /// We don't recognise the node with this shard-local ID, so ask the shard to have
+    /// it reconnect and tell us about itself again.
+    Resync { local_id: ShardNodeId },
+    /// Drop updates from every node on the chain with this genesis hash for this many
+    /// milliseconds, or until told to unmute it.
+    MuteChain {
+        genesis_hash: BlockHash,
+        ttl_ms: u64,
+    },
+    /// Stop dropping updates from nodes on the chain with this genesis hash.
+    UnmuteChain { genesis_hash: BlockHash },
 }
 
 /// An incoming feed connection can send these messages to the aggregator.
@@ -91,6 +259,9 @@ pub enum FromFeedWebsocket {
     /// progress.
     Initialize {
         channel: flume::Sender<ToFeedWebsocket>,
+        /// If given, the feed only wants to hear about nodes matching this filter
+        /// (along with anything to do with the chain as a whole).
+        node_filter: Option<NodeFilter>,
     },
     /// The feed can subscribe to a chain to receive
     /// messages relating to it.
@@ -99,6 +270,10 @@ pub enum FromFeedWebsocket {
     SendFinality,
     /// The feed doesn't want any more finality info for the chain.
     NoMoreFinality,
+    /// The feed wants to know about the implementations/versions of nodes on the chain.
+    SendDistribution,
+    /// The feed doesn't want any more implementation/version info for the chain.
+    NoMoreDistribution,
     /// An explicit ping message.
     Ping { value: Box<str> },
     /// The feed is disconnected.
@@ -119,16 +294,271 @@ pub struct Metrics {
     /// How many messages are currently queued up in internal channels
     /// waiting to be sent out to feeds.
     pub total_messages_to_feeds: usize,
+    /// How many messages are queued up to be sent to each feed.
+    pub feed_queue_lengths: FeedQueueLengths,
     /// How many messages are queued waiting to be handled by this aggregator.
     pub total_messages_to_aggregator: usize,
     /// How many (non-critical) messages have been dropped by the aggregator because it was overwhelmed.
     pub dropped_messages_to_aggregator: u64,
+    /// How many low priority messages have not been sent to feeds because they had fallen behind.
+    pub dropped_messages_to_feeds: u64,
     /// How many nodes are currently known to this aggregator.
     pub connected_nodes: usize,
     /// How many feeds are currently connected to this aggregator.
     pub connected_feeds: usize,
     /// How many shards are currently connected to this aggregator.
     pub connected_shards: usize,
+    /// How much memory each chain is using, as far as we account for it.
+    pub chain_memory: Vec<ChainMemoryUsage>,
+    /// Block heights and node counts for first party (and allowlisted) chains.
+    pub chain_heights: Vec<ChainHeights>,
+    /// How many nodes have been removed for each reason that they can be removed.
+    pub removed_nodes: Vec<(NodeCloseReason, u64)>,
+    /// How many node count samples each chain's retention policy has evicted.
+    pub retention_evictions: Vec<RetentionEviction>,
+    /// How many node location lookups were answered from the location cache.
+    pub location_cache_hits: u64,
+    /// How many node location lookups had to ask a location service.
+    pub location_cache_misses: u64,
+    /// The fraction of location lookups answered from the cache, if there have been any.
+    pub location_cache_hit_ratio: Option<f64>,
+    /// How long node messages about each chain took to arrive and to be handled.
+    pub chain_ingest_latency: Vec<ChainIngestLatency>,
+    /// How long node messages from each shard took to arrive and to be handled.
+    pub shard_ingest_latency: Vec<ShardIngestLatency>,
+    /// How many chains are being tracked, out of how many are allowed.
+    pub chain_capacity: ChainCapacity,
+}
+
+/// The accounted memory usage of a single chain.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChainMemoryUsage {
+    pub label: Box<str>,
+    pub genesis_hash: BlockHash,
+    pub usage: MemoryUsage,
+}
+
+/// Summary statistics about how many messages are queued up to be sent to each feed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FeedQueueLengths {
+    /// How many feeds there are.
+    pub feeds: usize,
+    /// How many messages are queued for all of them.
+    pub total: usize,
+    pub mean: f64,
+    pub p95: usize,
+    pub max: usize,
+}
+
+impl FeedQueueLengths {
+    fn new(lengths: impl IntoIterator<Item = usize>) -> Self {
+        let mut lengths: Vec<usize> = lengths.into_iter().collect();
+        if lengths.is_empty() {
+            return FeedQueueLengths::default();
+        }
+        lengths.sort_unstable();
+        let feeds = lengths.len();
+        let total: usize = lengths.iter().sum();
+        let rank = (95 * feeds).div_ceil(100);
+        FeedQueueLengths {
+            feeds,
+            total,
+            mean: total as f64 / feeds as f64,
+            p95: lengths[rank.max(1) - 1],
+            max: lengths[feeds - 1],
+        }
+    }
+}
+
+/// How many node count samples have been evicted from a chain by its retention policy.
+#[derive(Clone, Debug, Serialize)]
+pub struct RetentionEviction {
+    pub chain: Box<str>,
+    pub genesis_hash: BlockHash,
+    pub evicted_count: u64,
+}
+
+/// How long node messages about a single chain have taken to get here from shards
+/// (which relies on the shards' clocks agreeing with ours), and how long they then
+/// waited to be handled.
+#[derive(Clone, Debug)]
+pub struct ChainIngestLatency {
+    pub label: Box<str>,
+    pub genesis_hash: BlockHash,
+    pub transit: LatencyHistogram,
+    pub queued: LatencyHistogram,
+}
+
+/// How long node messages from a single shard have taken to get here, and how long they
+/// then waited to be handled.
+#[derive(Clone, Debug)]
+pub struct ShardIngestLatency {
+    pub shard_conn_id: u64,
+    pub transit: LatencyHistogram,
+    pub queued: LatencyHistogram,
+}
+
+/// The block heights and node count of a single chain, for external alerting.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChainHeights {
+    pub label: Box<str>,
+    pub genesis_hash: BlockHash,
+    pub best_height: BlockNumber,
+    pub finalized_height: BlockNumber,
+    /// When the best block last changed, if it has.
+    pub best_block_changed_at: Option<Timestamp>,
+    pub node_count: usize,
+}
+
+/// Details about a single chain, handed back for the REST API.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChainDetails {
+    pub label: Box<str>,
+    pub genesis_hash: BlockHash,
+    pub node_count: usize,
+    pub best_block: Block,
+    pub finalized_block: Block,
+    pub average_block_time: Option<u64>,
+    /// Has the best block gone for much longer than usual without advancing?
+    pub block_production_stalled: bool,
+    pub distribution: Distribution,
+    pub first_party: bool,
+    /// How many feeds are subscribed to the chain.
+    pub subscriber_count: u32,
+    /// If shards have been told to drop updates for the chain, when they'll stop.
+    pub muted_until: Option<Timestamp>,
+    /// How many nodes have told us that they're publicly reachable, and how many that
+    /// they aren't.
+    pub public_nodes: usize,
+    pub private_nodes: usize,
+    /// The fraction of nodes that told us either way which are public, from 0 to 1.
+    pub public_node_ratio: Option<f64>,
+}
+
+/// Details about every chain, along with how many chains we're allowed to track.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChainList {
+    pub chains: Vec<ChainDetails>,
+    pub capacity: ChainCapacity,
+}
+
+impl ChainDetails {
+    fn new(
+        chain: state::StateChain<'_>,
+        subscriber_count: u32,
+        muted_until: Option<Timestamp>,
+        now: Timestamp,
+    ) -> ChainDetails {
+        ChainDetails {
+            label: chain.label().into(),
+            genesis_hash: *chain.genesis_hash(),
+            node_count: chain.node_count(),
+            best_block: *chain.best_block(),
+            finalized_block: *chain.finalized_block(),
+            average_block_time: chain.average_block_time(),
+            block_production_stalled: chain.block_production_stalled(now),
+            distribution: chain.distribution().clone(),
+            first_party: chain.is_first_party(),
+            subscriber_count,
+            muted_until,
+            public_nodes: chain.public_nodes().public(),
+            private_nodes: chain.public_nodes().private(),
+            public_node_ratio: chain.public_nodes().public_ratio(),
+        }
+    }
+}
+
+/// Feeds can ask to only be told about some nodes by connecting with a
+/// `?node=<network_id>`, `?environment=<environment>` and/or
+/// `?offchain_indexing=<true|false>` query.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NodeFilter {
+    /// Only nodes with this network ID.
+    pub network_id: Option<Box<str>>,
+    /// Only nodes in this environment. Nodes that don't report an environment
+    /// are in the [`common::node_types::UNKNOWN_ENVIRONMENT`].
+    pub environment: Option<Box<str>>,
+    /// Only nodes with (or without) off-chain indexing enabled.
+    pub offchain_indexing: Option<bool>,
+    /// Only nodes sending messages of this shape.
+    pub message_schema: Option<MessageSchema>,
+}
+
+impl NodeFilter {
+    /// Does a node with the given details match this filter?
+    pub fn matches_node(&self, details: &NodeDetails) -> bool {
+        let network_id_matches = match &self.network_id {
+            Some(wanted) => details.network_id.as_deref() == Some(&**wanted),
+            None => true,
+        };
+        let environment_matches = match &self.environment {
+            Some(wanted) => details.environment() == &**wanted,
+            None => true,
+        };
+        let offchain_indexing_matches = match self.offchain_indexing {
+            Some(wanted) => details.offchain_indexing == wanted,
+            None => true,
+        };
+        let message_schema_matches = match self.message_schema {
+            Some(wanted) => details.message_schema == Some(wanted),
+            None => true,
+        };
+        network_id_matches
+            && environment_matches
+            && offchain_indexing_matches
+            && message_schema_matches
+    }
+}
+
+/// Returns the node filter that a feed has asked for in its query, if any.
+pub fn node_filter_from_query(query: Option<&str>) -> anyhow::Result<Option<NodeFilter>> {
+    let mut node_filter = NodeFilter::default();
+    for pair in query.unwrap_or("").split('&') {
+        let (key, value) = match pair.find('=') {
+            Some(idx) => (&pair[..idx], &pair[idx + 1..]),
+            None => (pair, ""),
+        };
+        if key == "offchain_indexing" {
+            let wanted = match value {
+                "true" => true,
+                "false" => false,
+                _ => anyhow::bail!("The offchain_indexing filter must be true or false"),
+            };
+            if node_filter.offchain_indexing.is_some() {
+                anyhow::bail!("Only one offchain_indexing filter can be given");
+            }
+            node_filter.offchain_indexing = Some(wanted);
+            continue;
+        }
+        if key == "message_schema" {
+            let wanted = match value {
+                "V1" => MessageSchema::V1,
+                "V2" => MessageSchema::V2,
+                _ => anyhow::bail!("The message_schema filter must be V1 or V2"),
+            };
+            if node_filter.message_schema.is_some() {
+                anyhow::bail!("Only one message_schema filter can be given");
+            }
+            node_filter.message_schema = Some(wanted);
+            continue;
+        }
+        let (field, what) = match key {
+            "node" => (&mut node_filter.network_id, "network ID"),
+            "environment" => (&mut node_filter.environment, "environment"),
+            _ => continue,
+        };
+        if value.trim().is_empty() {
+            anyhow::bail!("The {} filter must be a non-empty {}", key, what);
+        }
+        if field.is_some() {
+            anyhow::bail!("Only one {} filter can be given", key);
+        }
+        *field = Some(value.into());
+    }
+    if node_filter == NodeFilter::default() {
+        return Ok(None);
+    }
+    Ok(Some(node_filter))
 }
 
 // The frontend sends text based commands; parse them into these messages:
@@ -144,6 +574,8 @@ impl FromStr for FromFeedWebsocket {
             "subscribe" => Ok(FromFeedWebsocket::Subscribe { chain: value }),
             "send-finality" => Ok(FromFeedWebsocket::SendFinality),
             "no-more-finality" => Ok(FromFeedWebsocket::NoMoreFinality),
+            "send-distribution" => Ok(FromFeedWebsocket::SendDistribution),
+            "no-more-distribution" => Ok(FromFeedWebsocket::NoMoreDistribution),
             _ => return Err(anyhow::anyhow!("Command {} not recognised", cmd)),
         }
     }
@@ -155,6 +587,83 @@ pub enum ToFeedWebsocket {
     Bytes(bytes::Bytes),
 }
 
+/// How much we've sent to a single feed, to help work out why a feed is slow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct FeedStats {
+    /// How many websocket messages (each a batch of feed messages) have been sent.
+    pub messages_sent: u64,
+    /// How many feed messages were left out of those because the feed was falling
+    /// behind, or the chain they're about was over budget.
+    pub messages_dropped: u64,
+    /// How many bytes have been sent.
+    pub bytes_sent: u64,
+    /// How many messages are waiting to be sent right now.
+    pub queued: usize,
+}
+
+/// How to send messages out to a feed, keeping count of what's been sent.
+struct FeedChannel {
+    tx: flume::Sender<ToFeedWebsocket>,
+    stats: Cell<FeedStats>,
+    /// When the feed was last sent anything (or connected, if it's not been sent anything).
+    last_sent_at: Cell<Timestamp>,
+}
+
+impl FeedChannel {
+    fn new(tx: flume::Sender<ToFeedWebsocket>) -> Self {
+        FeedChannel {
+            tx,
+            stats: Cell::new(FeedStats::default()),
+            last_sent_at: Cell::new(time::now()),
+        }
+    }
+
+    fn send(&self, message: ToFeedWebsocket) -> Result<(), flume::SendError<ToFeedWebsocket>> {
+        self.send_at(message, time::now())
+    }
+
+    fn send_at(
+        &self,
+        message: ToFeedWebsocket,
+        now: Timestamp,
+    ) -> Result<(), flume::SendError<ToFeedWebsocket>> {
+        let ToFeedWebsocket::Bytes(bytes) = &message;
+        let len = bytes.len() as u64;
+        self.tx.send(message)?;
+        let mut stats = self.stats.get();
+        stats.messages_sent += 1;
+        stats.bytes_sent += len;
+        self.stats.set(stats);
+        self.last_sent_at.set(now);
+        Ok(())
+    }
+
+    /// Make a note of feed messages that weren't sent.
+    fn dropped(&self, count: u64) {
+        let mut stats = self.stats.get();
+        stats.messages_dropped += count;
+        self.stats.set(stats);
+    }
+
+    /// How many messages are waiting to be sent.
+    fn len(&self) -> usize {
+        self.tx.len()
+    }
+
+    fn stats(&self) -> FeedStats {
+        FeedStats {
+            queued: self.len(),
+            ..self.stats.get()
+        }
+    }
+}
+
+/// The feeds subscribed to a chain are told how many of them there are whenever that
+/// changes by more than this fraction of what they were last told...
+const SUBSCRIBER_COUNT_CHANGE: f64 = 0.05;
+/// ...or, if it's changed by less, once this many milliseconds have passed since then.
+const SUBSCRIBER_COUNT_INTERVAL_MS: u64 = 60_000;
+
 /// Instances of this are responsible for handling incoming and
 /// outgoing messages in the main aggregator loop.
 pub struct InnerLoop {
@@ -163,9 +672,13 @@ pub struct InnerLoop {
     /// We maintain a mapping between NodeId and ConnId+LocalId, so that we know
     /// which messages are about which nodes.
     node_ids: BiMap<NodeId, (ConnId, ShardNodeId)>,
+    /// Nodes that we've refused to add or told shards to mute. Shards may have sent more
+    /// messages about these before hearing from us, and those are dropped rather than
+    /// treated as being about nodes we've lost track of.
+    muted_shard_nodes: HashSet<(ConnId, ShardNodeId)>,
 
     /// Keep track of how to send messages out to feeds.
-    feed_channels: HashMap<ConnId, flume::Sender<ToFeedWebsocket>>,
+    feed_channels: HashMap<ConnId, FeedChannel>,
     /// Keep track of how to send messages out to shards.
     shard_channels: HashMap<ConnId, flume::Sender<ToShardWebsocket>>,
 
@@ -175,37 +688,156 @@ pub struct InnerLoop {
     /// These feeds want finality info, too.
     feed_conn_id_finality: HashSet<ConnId>,
 
+    /// These feeds want implementation/version info, too.
+    feed_conn_id_distribution: HashSet<ConnId>,
+
+    /// These feeds only want to hear about certain nodes.
+    feed_node_filters: HashMap<ConnId, FeedNodeFilter>,
+
     /// Send messages here to make geographical location requests.
-    tx_to_locator: flume::Sender<(NodeId, Ipv4Addr)>,
+    tx_to_locator: flume::Sender<find_location::LocateRequest<NodeId>>,
+
+    /// The IPV4 address that each node connected from, so that we can look up its
+    /// location again if asked to.
+    node_ips: HashMap<NodeId, Ipv4Addr>,
+
+    /// How often the locator has found locations in its cache.
+    location_cache_stats: find_location::CacheStats,
 
     /// How big can the queue of messages coming in to the aggregator get before messages
     /// are prioritised and dropped to try and get back on track.
     max_queue_len: usize,
+
+    /// If handling a single message takes longer than this, we log a warning.
+    slow_message_threshold: Option<Duration>,
+
+    /// Third party chains that we report per-chain metrics for.
+    metrics_chain_allowlist: HashSet<String>,
+
+    /// How many nodes have been removed by shards, and why.
+    removed_nodes: HashMap<NodeCloseReason, u64>,
+
+    /// How important each feed message is, so that we know which to stop sending
+    /// to feeds that are falling behind.
+    feed_priorities: FeedPriorities,
+
+    /// How many messages we've not sent to feeds because they were falling behind
+    /// (or the chain they're about was over budget).
+    dropped_messages_to_feeds: Cell<u64>,
+
+    /// How many feed messages per second each chain can send.
+    feed_budgets: FeedBudgets,
+
+    /// How much of its budget each chain with a budget has used.
+    feed_budget_usage: HashMap<BlockHash, BudgetUsage>,
+
+    /// The chain stats last sent to feeds, so that we only need to send what's changed.
+    chain_stats: state::ChainStatsDiffer,
+
+    /// The subscriber count last sent to the feeds of each chain that has any, and when.
+    subscriber_counts_sent: HashMap<BlockHash, (u32, Timestamp)>,
+
+    /// How many feeds other aggregators had subscribed to each chain, as of the last time
+    /// we were told. Feeds are spread across aggregators, so these are added to our own
+    /// counts before anyone is told how many feeds a chain has.
+    subscriber_counts_elsewhere: HashMap<BlockHash, u32>,
+
+    /// If nodes reconnect within this many milliseconds of disconnecting, feeds aren't
+    /// told that they went away. `None` if nodes are removed straight away.
+    reconnect_debounce_ms: Option<u64>,
+
+    /// When a shard disconnects, its nodes are held on to for this many milliseconds in
+    /// case they're resumed by a shard that was connected to them before. `None` if they're
+    /// removed straight away.
+    shard_restart_grace_ms: Option<u64>,
+
+    /// If set, located nodes have their coordinates rounded to this many decimal places,
+    /// and their city blanked, before they're stored or sent anywhere.
+    anonymize_locations: Option<u8>,
+
+    /// Nodes which have disconnected, but which we've not removed yet in case they
+    /// reconnect, by their genesis hash and network ID.
+    disconnected_nodes: HashMap<(BlockHash, Box<str>), DisconnectedNode>,
+
+    /// How long node messages have taken to get here from shards, and to be handled.
+    ingest_latency: IngestLatency,
+
+    /// Warn about chains and shards whose node messages have recently had a 99th
+    /// percentile transit or queue time of more than this many milliseconds.
+    ingest_latency_warning_ms: Option<u64>,
+
+    /// Feeds that haven't been sent anything for this many milliseconds are sent a
+    /// heartbeat. `None` if heartbeats aren't sent.
+    feed_heartbeat_interval_ms: Option<u64>,
+
+    /// Chains whose updates shards have been told to drop, and until when. Mutes that
+    /// have run out are ignored, and tidied up the next time a chain is muted.
+    chain_mutes: HashMap<BlockHash, Timestamp>,
+
+    /// Parts of node names matching these patterns are redacted before the names are
+    /// handed out to feeds or the public API.
+    name_redactor: Arc<NameRedactor>,
+
+    /// Whether we've been told to shut down, in which case no new feeds are accepted.
+    shutting_down: bool,
+}
+
+/// A node that has disconnected, but is being held on to in case it comes straight back.
+struct DisconnectedNode {
+    node_id: NodeId,
+    disconnected_at: Timestamp,
+    /// The node went away because its whole shard did, rather than on its own.
+    shard_gone: bool,
 }
 
 impl InnerLoop {
     /// Create a new inner loop handler with the various state it needs.
     pub fn new(
-        tx_to_locator: flume::Sender<(NodeId, Ipv4Addr)>,
-        denylist: Vec<String>,
-        max_queue_len: usize,
+        tx_to_locator: flume::Sender<find_location::LocateRequest<NodeId>>,
+        location_cache_stats: find_location::CacheStats,
+        opts: AggregatorOpts,
     ) -> Self {
         InnerLoop {
-            node_state: State::new(denylist),
+            node_state: State::new(opts.denylist, opts.chain_opts).with_max_chains(opts.max_chains),
             node_ids: BiMap::new(),
+            muted_shard_nodes: HashSet::new(),
             feed_channels: HashMap::new(),
             shard_channels: HashMap::new(),
             chain_to_feed_conn_ids: MultiMapUnique::new(),
             feed_conn_id_finality: HashSet::new(),
+            feed_conn_id_distribution: HashSet::new(),
+            feed_node_filters: HashMap::new(),
             tx_to_locator,
-            max_queue_len,
+            node_ips: HashMap::new(),
+            location_cache_stats,
+            max_queue_len: opts.max_queue_len,
+            slow_message_threshold: opts.slow_message_threshold,
+            metrics_chain_allowlist: opts.metrics_chain_allowlist.into_iter().collect(),
+            removed_nodes: HashMap::new(),
+            feed_priorities: opts.feed_priorities,
+            dropped_messages_to_feeds: Cell::new(0),
+            feed_budgets: opts.feed_budgets,
+            feed_budget_usage: HashMap::new(),
+            chain_stats: state::ChainStatsDiffer::new(),
+            subscriber_counts_sent: HashMap::new(),
+            subscriber_counts_elsewhere: HashMap::new(),
+            reconnect_debounce_ms: opts.reconnect_debounce.map(|d| d.as_millis() as u64),
+            shard_restart_grace_ms: opts.shard_restart_grace.map(|d| d.as_millis() as u64),
+            anonymize_locations: opts.anonymize_locations,
+            disconnected_nodes: HashMap::new(),
+            ingest_latency: IngestLatency::default(),
+            ingest_latency_warning_ms: opts.ingest_latency_warning.map(|d| d.as_millis() as u64),
+            feed_heartbeat_interval_ms: opts.feed_heartbeat_interval.map(|d| d.as_millis() as u64),
+            chain_mutes: HashMap::new(),
+            name_redactor: opts.name_redactor,
+            shutting_down: false,
         }
     }
 
     /// Start handling and responding to incoming messages.
     pub async fn handle(mut self, rx_from_external: flume::Receiver<ToAggregator>) {
         let max_queue_len = self.max_queue_len;
-        let (metered_tx, metered_rx) = flume::unbounded();
+        let (metered_tx, metered_rx) = flume::unbounded::<ToAggregator>();
 
         // Keep count of the number of messages we drop for the sake of metric reporting
         let dropped_messages = Arc::new(AtomicU64::new(0));
@@ -216,6 +848,12 @@ impl InnerLoop {
         let dropped_messages2 = Arc::clone(&dropped_messages);
         tokio::spawn(async move {
             while let Ok(msg) = metered_rx.recv_async().await {
+                // Note some details before handling the message, so that we can
+                // log them if handling the message turns out to be slow.
+                let start = Instant::now();
+                let message_type = msg.message_type();
+                let node_id = self.node_id_for_message(&msg);
+
                 match msg {
                     ToAggregator::FromFeedWebsocket(feed_conn_id, msg) => {
                         self.handle_from_feed(feed_conn_id, msg)
@@ -231,7 +869,79 @@ impl InnerLoop {
                         metered_rx.len(),
                         dropped_messages2.load(Ordering::Relaxed),
                     ),
+                    ToAggregator::GatherChainDetails(genesis_hash, tx) => {
+                        self.handle_gather_chain_details(genesis_hash, tx)
+                    }
+                    ToAggregator::GatherChains(tx) => self.handle_gather_chains(tx),
+                    ToAggregator::GatherNodeCountHistory(genesis_hash, tx) => {
+                        self.handle_gather_node_count_history(genesis_hash, tx)
+                    }
+                    ToAggregator::GatherNodeInfo(genesis_hash, node_id, tx) => {
+                        self.handle_gather_node_info(genesis_hash, node_id, tx)
+                    }
+                    ToAggregator::GatherChainNodes(genesis_hash, filter, tx) => {
+                        self.handle_gather_chain_nodes(genesis_hash, filter, tx)
+                    }
+                    ToAggregator::GatherNodeBlocks(genesis_hash, node_id, tx) => {
+                        self.handle_gather_node_blocks(genesis_hash, node_id, tx)
+                    }
+                    ToAggregator::GatherLocationClusters(genesis_hash, cell_degrees, tx) => {
+                        self.handle_gather_location_clusters(genesis_hash, cell_degrees, tx)
+                    }
+                    ToAggregator::GatherDataset(hasher, tx) => {
+                        self.handle_gather_dataset(&hasher, tx)
+                    }
+                    ToAggregator::SampleNodeCounts => self.handle_sample_node_counts(),
+                    ToAggregator::SendNodesAtBest => self.handle_send_nodes_at_best(),
+                    ToAggregator::SendChainStats => self.handle_send_chain_stats(),
+                    ToAggregator::GatherSubscriberCounts(tx) => {
+                        self.handle_gather_subscriber_counts(tx)
+                    }
+                    ToAggregator::SendSubscriberCounts(elsewhere) => {
+                        self.subscriber_counts_elsewhere = elsewhere;
+                        self.send_subscriber_counts(time::now())
+                    }
+                    ToAggregator::SendFeedHeartbeats => self.send_feed_heartbeats(time::now()),
+                    ToAggregator::ExpireDisconnectedNodes => {
+                        self.expire_disconnected_nodes(time::now())
+                    }
+                    ToAggregator::CheckIngestLatency => self.handle_check_ingest_latency(),
+                    ToAggregator::EnforceRetentionPolicies => {
+                        self.enforce_retention_policies(time::now())
+                    }
+                    ToAggregator::GatherRetentionPolicy(genesis_hash, tx) => {
+                        self.handle_gather_retention_policy(genesis_hash, tx)
+                    }
+                    ToAggregator::GatherFeedStats(tx) => self.handle_gather_feed_stats(tx),
+                    ToAggregator::SetRetentionPolicy(genesis_hash, policy, tx) => {
+                        self.handle_set_retention_policy(genesis_hash, policy, tx)
+                    }
+                    ToAggregator::RelocateNode(genesis_hash, node_id, tx) => {
+                        self.handle_relocate_node(genesis_hash, node_id, tx)
+                    }
+                    ToAggregator::MuteChain(genesis_hash, ttl, tx) => {
+                        let _ = tx.send(self.mute_chain(genesis_hash, ttl, time::now()));
+                    }
+                    ToAggregator::UnmuteChain(genesis_hash, tx) => {
+                        let _ = tx.send(self.unmute_chain(genesis_hash, time::now()));
+                    }
+                    ToAggregator::SetDenylist(denylist, close_denied_nodes) => {
+                        self.handle_set_denylist(denylist, close_denied_nodes)
+                    }
+                    ToAggregator::SetNameRedactor(redactor) => {
+                        self.handle_set_name_redactor(redactor)
+                    }
+                    ToAggregator::Shutdown(restart_in_seconds, tx) => {
+                        self.handle_shutdown(restart_in_seconds, tx)
+                    }
                 }
+
+                warn_if_slow(
+                    self.slow_message_threshold,
+                    start.elapsed(),
+                    node_id,
+                    message_type,
+                );
             }
         });
 
@@ -258,6 +968,24 @@ impl InnerLoop {
         }
     }
 
+    /// Which node (if any) is a message about? If a node is being added, we won't
+    /// know its ID until after the message has been handled.
+    fn node_id_for_message(&self, msg: &ToAggregator) -> Option<NodeId> {
+        let shard_node_id = match msg {
+            ToAggregator::FromFindLocation(node_id, _) => return Some(*node_id),
+            ToAggregator::FromShardWebsocket(
+                conn_id,
+                FromShardWebsocket::Update { local_id, .. },
+            )
+            | ToAggregator::FromShardWebsocket(
+                conn_id,
+                FromShardWebsocket::Remove { local_id, .. },
+            ) => (*conn_id, *local_id),
+            _ => return None,
+        };
+        self.node_ids.get_by_right(&shard_node_id).copied()
+    }
+
     /// Gather and return some metrics.
     fn handle_gather_metrics(
         &mut self,
@@ -273,6 +1001,63 @@ impl InnerLoop {
         let connected_shards = self.shard_channels.len();
         let connected_feeds = self.feed_channels.len();
         let total_messages_to_feeds: usize = self.feed_channels.values().map(|c| c.len()).sum();
+        let feed_queue_lengths =
+            FeedQueueLengths::new(self.feed_channels.values().map(|c| c.len()));
+        let chain_memory = self
+            .node_state
+            .iter_chains()
+            .map(|chain| ChainMemoryUsage {
+                label: chain.label().into(),
+                genesis_hash: *chain.genesis_hash(),
+                usage: chain.memory_usage(),
+            })
+            .collect();
+        let chain_heights = self.chain_heights();
+        let retention_evictions = self
+            .node_state
+            .iter_chains()
+            .map(|chain| RetentionEviction {
+                chain: chain.label().into(),
+                genesis_hash: *chain.genesis_hash(),
+                evicted_count: chain.retention_evictions(),
+            })
+            .collect();
+        let removed_nodes = NodeCloseReason::ALL
+            .iter()
+            .map(|&reason| {
+                (
+                    reason,
+                    self.removed_nodes.get(&reason).copied().unwrap_or(0),
+                )
+            })
+            .collect();
+
+        let node_state = &self.node_state;
+        self.ingest_latency.retain_chains(|genesis_hash| {
+            node_state.get_chain_by_genesis_hash(genesis_hash).is_some()
+        });
+        let chain_ingest_latency = self
+            .ingest_latency
+            .chains()
+            .filter_map(|(genesis_hash, latencies)| {
+                let chain = self.node_state.get_chain_by_genesis_hash(genesis_hash)?;
+                Some(ChainIngestLatency {
+                    label: chain.label().into(),
+                    genesis_hash: *genesis_hash,
+                    transit: latencies.transit.clone(),
+                    queued: latencies.queued.clone(),
+                })
+            })
+            .collect();
+        let shard_ingest_latency = self
+            .ingest_latency
+            .shards()
+            .map(|(&shard_conn_id, latencies)| ShardIngestLatency {
+                shard_conn_id: shard_conn_id.into(),
+                transit: latencies.transit.clone(),
+                queued: latencies.queued.clone(),
+            })
+            .collect();
 
         // Ignore error sending; assume the receiver stopped caring and dropped the channel:
         let _ = rx.send(Metrics {
@@ -281,434 +1066,3503 @@ impl InnerLoop {
             subscribed_feeds,
             subscribed_finality_feeds,
             total_messages_to_feeds,
+            feed_queue_lengths,
             total_messages_to_aggregator,
             dropped_messages_to_aggregator,
+            dropped_messages_to_feeds: self.dropped_messages_to_feeds.get(),
             connected_nodes,
             connected_feeds,
             connected_shards,
+            chain_memory,
+            chain_heights,
+            removed_nodes,
+            retention_evictions,
+            location_cache_hits: self.location_cache_stats.hits(),
+            location_cache_misses: self.location_cache_stats.misses(),
+            location_cache_hit_ratio: self.location_cache_stats.hit_ratio(),
+            chain_ingest_latency,
+            shard_ingest_latency,
+            chain_capacity: self.node_state.chain_capacity(),
         });
     }
 
-    /// Handle messages that come from the node geographical locator.
-    fn handle_from_find_location(&mut self, node_id: NodeId, location: find_location::Location) {
-        self.node_state
-            .update_node_location(node_id, location.clone());
+    /// Warn about any chains or shards whose node messages have recently (since we last
+    /// checked) had a 99th percentile transit or queue time over the warning threshold.
+    fn handle_check_ingest_latency(&mut self) {
+        let threshold_ms = match self.ingest_latency_warning_ms {
+            Some(ms) => ms,
+            None => return,
+        };
 
-        if let Some(loc) = location {
-            let mut feed_message_serializer = FeedMessageSerializer::new();
-            feed_message_serializer.push(feed_message::LocatedNode(
-                node_id.get_chain_node_id().into(),
-                loc.latitude,
-                loc.longitude,
-                &loc.city,
-            ));
+        let node_state = &self.node_state;
+        self.ingest_latency.retain_chains(|genesis_hash| {
+            node_state.get_chain_by_genesis_hash(genesis_hash).is_some()
+        });
+        let (slow_chains, slow_shards) = self.ingest_latency.take_slow(threshold_ms);
 
-            let chain_genesis_hash = self
+        for slow in slow_chains {
+            let label = self
                 .node_state
-                .get_chain_by_node_id(node_id)
-                .map(|chain| *chain.genesis_hash());
-
-            if let Some(chain_genesis_hash) = chain_genesis_hash {
-                self.finalize_and_broadcast_to_chain_feeds(
-                    &chain_genesis_hash,
-                    feed_message_serializer,
-                );
-            }
+                .get_chain_by_genesis_hash(&slow.from)
+                .map(|chain| chain.label())
+                .unwrap_or_default();
+            log::warn!(
+                "Node messages about chain {:?} ({:?}) are slow: p99 of {}ms in transit from shards and {}ms queued here (threshold {}ms)",
+                label,
+                slow.from,
+                slow.transit_p99_ms,
+                slow.queued_p99_ms,
+                threshold_ms
+            );
+        }
+        for slow in slow_shards {
+            log::warn!(
+                "Node messages from shard connection {} are slow: p99 of {}ms in transit and {}ms queued here (threshold {}ms)",
+                u64::from(slow.from),
+                slow.transit_p99_ms,
+                slow.queued_p99_ms,
+                threshold_ms
+            );
         }
     }
 
-    /// Handle messages coming from shards.
-    fn handle_from_shard(&mut self, shard_conn_id: ConnId, msg: FromShardWebsocket) {
-        match msg {
-            FromShardWebsocket::Initialize { channel } => {
-                self.shard_channels.insert(shard_conn_id, channel);
-            }
-            FromShardWebsocket::Add {
-                local_id,
-                ip,
-                node,
-                genesis_hash,
-            } => {
-                match self.node_state.add_node(genesis_hash, node) {
-                    state::AddNodeResult::ChainOnDenyList => {
-                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
-                            let _ = shard_conn.send(ToShardWebsocket::Mute {
-                                local_id,
-                                reason: MuteReason::ChainNotAllowed,
-                            });
-                        }
-                    }
-                    state::AddNodeResult::ChainOverQuota => {
-                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
-                            let _ = shard_conn.send(ToShardWebsocket::Mute {
-                                local_id,
-                                reason: MuteReason::Overquota,
-                            });
-                        }
-                    }
-                    state::AddNodeResult::NodeAddedToChain(details) => {
-                        let node_id = details.id;
+    /// Block heights for first party chains and any others that we've been asked to
+    /// report. Chains that have been removed are no longer reported.
+    fn chain_heights(&self) -> Vec<ChainHeights> {
+        self.node_state
+            .iter_chains()
+            .filter(|chain| {
+                chain.is_first_party() || self.metrics_chain_allowlist.contains(chain.label())
+            })
+            .map(|chain| ChainHeights {
+                label: chain.label().into(),
+                genesis_hash: *chain.genesis_hash(),
+                best_height: chain.best_block().height,
+                finalized_height: chain.finalized_block().height,
+                best_block_changed_at: chain.best_block_changed_at(),
+                node_count: chain.node_count(),
+            })
+            .collect()
+    }
 
-                        // Record ID <-> (shardId,localId) for future messages:
-                        self.node_ids.insert(node_id, (shard_conn_id, local_id));
+    /// Gather and return details about a chain.
+    fn handle_gather_chain_details(
+        &mut self,
+        genesis_hash: BlockHash,
+        tx: flume::Sender<Option<ChainDetails>>,
+    ) {
+        let details = self
+            .node_state
+            .get_chain_by_genesis_hash(&genesis_hash)
+            .map(|chain| {
+                let now = time::now();
+                let subscriber_count = self.subscriber_count_for_chain(chain.label());
+                let muted_until = self.chain_muted_until(chain.genesis_hash(), now);
+                ChainDetails::new(chain, subscriber_count, muted_until, now)
+            });
 
-                        // Don't hold onto details too long because we want &mut self later:
-                        let old_chain_label = details.old_chain_label.to_owned();
-                        let new_chain_label = details.new_chain_label.to_owned();
-                        let chain_node_count = details.chain_node_count;
-                        let has_chain_label_changed = details.has_chain_label_changed;
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(details);
+    }
 
-                        // Tell chain subscribers about the node we've just added:
-                        let mut feed_messages_for_chain = FeedMessageSerializer::new();
-                        feed_messages_for_chain.push(feed_message::AddedNode(
-                            node_id.get_chain_node_id().into(),
-                            &details.node,
-                        ));
-                        self.finalize_and_broadcast_to_chain_feeds(
-                            &genesis_hash,
-                            feed_messages_for_chain,
-                        );
-                        // Tell everybody about the new node count and potential rename:
-                        let mut feed_messages_for_all = FeedMessageSerializer::new();
-                        if has_chain_label_changed {
-                            feed_messages_for_all
-                                .push(feed_message::RemovedChain(&old_chain_label));
-                        }
-                        feed_messages_for_all
-                            .push(feed_message::AddedChain(&new_chain_label, chain_node_count));
-                        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
+    /// Gather and return details about every chain.
+    fn handle_gather_chains(&mut self, tx: flume::Sender<ChainList>) {
+        let now = time::now();
+        let chains = ChainList {
+            chains: self
+                .node_state
+                .iter_chains()
+                .map(|chain| {
+                    let subscriber_count = self.subscriber_count_for_chain(chain.label());
+                    let muted_until = self.chain_muted_until(chain.genesis_hash(), now);
+                    ChainDetails::new(chain, subscriber_count, muted_until, now)
+                })
+                .collect(),
+            capacity: self.node_state.chain_capacity(),
+        };
 
-                        // Ask for the grographical location of the node.
-                        // Currently we only geographically locate IPV4 addresses so ignore IPV6.
-                        if let IpAddr::V4(ip_v4) = ip {
-                            let _ = self.tx_to_locator.send((node_id, ip_v4));
-                        }
-                    }
-                }
-            }
-            FromShardWebsocket::Remove { local_id } => {
-                let node_id = match self.node_ids.remove_by_right(&(shard_conn_id, local_id)) {
-                    Some((node_id, _)) => node_id,
-                    None => {
-                        log::error!(
-                            "Cannot find ID for node with shard/connectionId of {:?}/{:?}",
-                            shard_conn_id,
-                            local_id
-                        );
-                        return;
-                    }
-                };
-                self.remove_nodes_and_broadcast_result(Some(node_id));
-            }
-            FromShardWebsocket::Update { local_id, payload } => {
-                let node_id = match self.node_ids.get_by_right(&(shard_conn_id, local_id)) {
-                    Some(id) => *id,
-                    None => {
-                        log::error!(
-                            "Cannot find ID for node with shard/connectionId of {:?}/{:?}",
-                            shard_conn_id,
-                            local_id
-                        );
-                        return;
-                    }
-                };
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(chains);
+    }
 
-                let mut feed_message_serializer = FeedMessageSerializer::new();
-                let broadcast_finality =
-                    self.node_state
-                        .update_node(node_id, payload, &mut feed_message_serializer);
+    /// Hand back the node count history of a single chain.
+    fn handle_gather_node_count_history(
+        &mut self,
+        genesis_hash: BlockHash,
+        tx: flume::Sender<Option<NodeCountHistory>>,
+    ) {
+        let history = self
+            .node_state
+            .get_chain_by_genesis_hash(&genesis_hash)
+            .map(|chain| chain.node_count_history().clone());
 
-                if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
-                    let genesis_hash = *chain.genesis_hash();
-                    if broadcast_finality {
-                        self.finalize_and_broadcast_to_chain_finality_feeds(
-                            &genesis_hash,
-                            feed_message_serializer,
-                        );
-                    } else {
-                        self.finalize_and_broadcast_to_chain_feeds(
-                            &genesis_hash,
-                            feed_message_serializer,
-                        );
-                    }
-                }
-            }
-            FromShardWebsocket::Disconnected => {
-                // Find all nodes associated with this shard connection ID:
-                let node_ids_to_remove: Vec<NodeId> = self
-                    .node_ids
-                    .iter()
-                    .filter(|(_, &(this_shard_conn_id, _))| shard_conn_id == this_shard_conn_id)
-                    .map(|(&node_id, _)| node_id)
-                    .collect();
-
-                // ... and remove them:
-                self.remove_nodes_and_broadcast_result(node_ids_to_remove);
-            }
-        }
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(history);
     }
 
-    /// Handle messages coming from feeds.
-    fn handle_from_feed(&mut self, feed_conn_id: ConnId, msg: FromFeedWebsocket) {
-        match msg {
-            FromFeedWebsocket::Initialize { channel } => {
-                self.feed_channels.insert(feed_conn_id, channel.clone());
-
-                // Tell the new feed subscription some basic things to get it going:
-                let mut feed_serializer = FeedMessageSerializer::new();
-                feed_serializer.push(feed_message::Version(31));
-                for chain in self.node_state.iter_chains() {
-                    feed_serializer
-                        .push(feed_message::AddedChain(chain.label(), chain.node_count()));
-                }
+    /// Hand back the current state of a single node.
+    fn handle_gather_node_info(
+        &mut self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+        tx: flume::Sender<Option<NodeInfo>>,
+    ) {
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(self.node_info(genesis_hash, node_id));
+    }
 
-                // Send this to the channel that subscribed:
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = channel.send(ToFeedWebsocket::Bytes(bytes));
-                }
-            }
-            FromFeedWebsocket::Ping { value } => {
-                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
-                    Some(chan) => chan,
-                    None => return,
-                };
+    /// The current state of the node with the given (feed facing) ID on a chain, with
+    /// its name redacted, since this is handed out publicly.
+    fn node_info(&self, genesis_hash: BlockHash, node_id: usize) -> Option<NodeInfo> {
+        let chain = self.node_state.get_chain_by_genesis_hash(&genesis_hash)?;
+        let node = chain.get_node(node_id)?;
+        let mut info = NodeInfo::new(node_id, node);
+        info.details.name = self.name_redactor.redact(&info.details.name).into();
+        Some(info)
+    }
 
-                // Pong!
-                let mut feed_serializer = FeedMessageSerializer::new();
-                feed_serializer.push(feed_message::Pong(&value));
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
-                }
-            }
-            FromFeedWebsocket::Subscribe { chain } => {
-                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
-                    Some(chan) => chan,
-                    None => return,
-                };
+    /// Hand back the current state of the nodes on a chain.
+    fn handle_gather_chain_nodes(
+        &mut self,
+        genesis_hash: BlockHash,
+        filter: Option<NodeFilter>,
+        tx: flume::Sender<Option<Vec<NodeInfo>>>,
+    ) {
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(self.chain_nodes(genesis_hash, filter.as_ref()));
+    }
 
-                // Unsubscribe from previous chain if subscribed to one:
-                let old_genesis_hash = self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+    /// The current state of every node on a chain that matches the filter, in the order
+    /// of their (feed facing) IDs.
+    fn chain_nodes(
+        &self,
+        genesis_hash: BlockHash,
+        filter: Option<&NodeFilter>,
+    ) -> Option<Vec<NodeInfo>> {
+        let chain = self.node_state.get_chain_by_genesis_hash(&genesis_hash)?;
+        let nodes = chain
+            .nodes_slice()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, n)| n.as_ref().map(|n| (idx, n)))
+            .filter(|(_, n)| filter.is_none_or(|f| f.matches_node(n.details())))
+            .map(|(idx, n)| NodeInfo::new(idx, n))
+            .collect();
+        Some(nodes)
+    }
 
-                // Untoggle request for finality feeds:
-                self.feed_conn_id_finality.remove(&feed_conn_id);
+    /// Hand back the last few new best blocks that a node announced.
+    fn handle_gather_node_blocks(
+        &mut self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+        tx: flume::Sender<Option<Vec<RecentBlock>>>,
+    ) {
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(self.node_blocks(genesis_hash, node_id));
+    }
 
-                // Get old chain if there was one:
-                let node_state = &self.node_state;
-                let old_chain =
-                    old_genesis_hash.and_then(|hash| node_state.get_chain_by_genesis_hash(&hash));
+    /// The last few new best blocks that the node with the given (feed facing) ID
+    /// on a chain announced, oldest first.
+    fn node_blocks(&self, genesis_hash: BlockHash, node_id: usize) -> Option<Vec<RecentBlock>> {
+        let chain = self.node_state.get_chain_by_genesis_hash(&genesis_hash)?;
+        let node = chain.get_node(node_id)?;
+        Some(node.recent_blocks().copied().collect())
+    }
 
-                // Get new chain, ignoring the rest if it doesn't exist.
-                let new_chain = match self.node_state.get_chain_by_label(&chain) {
-                    Some(chain) => chain,
-                    None => return,
-                };
+    /// Hand back where the nodes on a chain are, clustered into a grid of cells.
+    fn handle_gather_location_clusters(
+        &mut self,
+        genesis_hash: BlockHash,
+        cell_degrees: f32,
+        tx: flume::Sender<Option<Vec<LocationCluster>>>,
+    ) {
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(self.location_clusters(genesis_hash, cell_degrees));
+    }
 
-                // Send messages to the feed about this subscription:
-                let mut feed_serializer = FeedMessageSerializer::new();
-                if let Some(old_chain) = old_chain {
-                    feed_serializer.push(feed_message::UnsubscribedFrom(old_chain.label()));
-                }
-                feed_serializer.push(feed_message::SubscribedTo(new_chain.label()));
-                feed_serializer.push(feed_message::TimeSync(time::now()));
-                feed_serializer.push(feed_message::BestBlock(
-                    new_chain.best_block().height,
-                    new_chain.timestamp(),
-                    new_chain.average_block_time(),
-                ));
-                feed_serializer.push(feed_message::BestFinalized(
-                    new_chain.finalized_block().height,
-                    new_chain.finalized_block().hash,
-                ));
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
-                }
+    /// Where the nodes on a chain whose locations we know are, grouped into square
+    /// cells of `cell_degrees` on a side, largest cluster first.
+    fn location_clusters(
+        &self,
+        genesis_hash: BlockHash,
+        cell_degrees: f32,
+    ) -> Option<Vec<LocationCluster>> {
+        let chain = self.node_state.get_chain_by_genesis_hash(&genesis_hash)?;
+        let locations = chain
+            .nodes_slice()
+            .iter()
+            .filter_map(|node| node.as_ref()?.location());
+        Some(state::cluster_locations(locations, cell_degrees))
+    }
 
-                // If many (eg 10k) nodes are connected, serializing all of their info takes time.
-                // So, parallelise this with Rayon, but we still send out messages for each node in order
-                // (which is helpful for the UI as it tries to maintain a sorted list of nodes). The chunk
-                // size is the max number of node info we fit into 1 message; smaller messages allow the UI
-                // to react a little faster and not have to wait for a larger update to come in. A chunk size
-                // of 64 means each message is ~32k.
-                use rayon::prelude::*;
-                let all_feed_messages: Vec<_> = new_chain
-                    .nodes_slice()
-                    .par_iter()
-                    .enumerate()
-                    .chunks(64)
-                    .filter_map(|nodes| {
-                        let mut feed_serializer = FeedMessageSerializer::new();
-                        for (node_id, node) in nodes
-                            .iter()
-                            .filter_map(|&(idx, n)| n.as_ref().map(|n| (idx, n)))
-                        {
-                            feed_serializer.push(feed_message::AddedNode(node_id, node));
-                            feed_serializer.push(feed_message::FinalizedBlock(
-                                node_id,
-                                node.finalized().height,
-                                node.finalized().hash,
-                            ));
-                            if node.stale() {
-                                feed_serializer.push(feed_message::StaleNode(node_id));
-                            }
-                        }
-                        feed_serializer.into_finalized()
-                    })
-                    .collect();
-                for bytes in all_feed_messages {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
-                }
+    /// Gather an anonymized snapshot of the nodes on every first party chain.
+    fn handle_gather_dataset(
+        &mut self,
+        hasher: &NetworkIdHasher,
+        tx: flume::Sender<Vec<ChainDataset>>,
+    ) {
+        let now = time::now();
+        let datasets = self
+            .node_state
+            .iter_chains()
+            .filter(|chain| chain.is_first_party())
+            .map(|chain| ChainDataset::new(&chain, hasher, now))
+            .collect();
 
-                // Actually make a note of the new chain subsciption:
-                let new_genesis_hash = *new_chain.genesis_hash();
-                self.chain_to_feed_conn_ids
-                    .insert(new_genesis_hash, feed_conn_id);
-            }
-            FromFeedWebsocket::SendFinality => {
-                self.feed_conn_id_finality.insert(feed_conn_id);
-            }
-            FromFeedWebsocket::NoMoreFinality => {
-                self.feed_conn_id_finality.remove(&feed_conn_id);
-            }
-            FromFeedWebsocket::Disconnected => {
-                // The feed has disconnected; clean up references to it:
-                self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
-                self.feed_channels.remove(&feed_conn_id);
-                self.feed_conn_id_finality.remove(&feed_conn_id);
-            }
-        }
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(datasets);
     }
 
-    /// Remove all of the node IDs provided and broadcast messages to feeds as needed.
-    fn remove_nodes_and_broadcast_result(&mut self, node_ids: impl IntoIterator<Item = NodeId>) {
-        // Group by chain to simplify the handling of feed messages:
-        let mut node_ids_per_chain: HashMap<BlockHash, Vec<NodeId>> = HashMap::new();
-        for node_id in node_ids.into_iter() {
-            if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
-                node_ids_per_chain
-                    .entry(*chain.genesis_hash())
-                    .or_default()
-                    .push(node_id);
-            }
+    /// Sample the node count of every chain, and tell feeds subscribed to each chain
+    /// about the new sample.
+    fn handle_sample_node_counts(&mut self) {
+        for (genesis_hash, sample) in self.node_state.sample_node_counts(time::now()) {
+            let mut feed_serializer = FeedMessageSerializer::new();
+            feed_serializer.push(feed_message::NodeCountSample(sample));
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_serializer);
         }
+    }
 
-        // Remove the nodes for each chain
-        let mut feed_messages_for_all = FeedMessageSerializer::new();
-        for (chain_label, node_ids) in node_ids_per_chain {
-            let mut feed_messages_for_chain = FeedMessageSerializer::new();
-            for node_id in node_ids {
-                self.remove_node(
-                    node_id,
-                    &mut feed_messages_for_chain,
-                    &mut feed_messages_for_all,
-                );
-            }
-            self.finalize_and_broadcast_to_chain_feeds(&chain_label, feed_messages_for_chain);
+    /// Evict node count history from the chains that are due it.
+    fn enforce_retention_policies(&mut self, now: Timestamp) {
+        for (genesis_hash, evicted) in self.node_state.enforce_retention_policies(now) {
+            log::debug!(
+                "Evicted {} node count samples from chain {:?}",
+                evicted,
+                genesis_hash
+            );
         }
-        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
     }
 
-    /// Remove a single node by its ID, pushing any messages we'd want to send
-    /// out to feeds onto the provided feed serializers. Doesn't actually send
-    /// anything to the feeds; just updates state as needed.
-    fn remove_node(
+    /// Hand back the retention policy of a chain.
+    fn handle_gather_retention_policy(
         &mut self,
-        node_id: NodeId,
-        feed_for_chain: &mut FeedMessageSerializer,
-        feed_for_all: &mut FeedMessageSerializer,
+        genesis_hash: BlockHash,
+        tx: flume::Sender<Option<RetentionPolicy>>,
     ) {
-        // Remove our top level association (this may already have been done).
-        self.node_ids.remove_by_left(&node_id);
+        let policy = self.node_state.retention_policy(&genesis_hash);
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(policy);
+    }
 
-        let removed_details = match self.node_state.remove_node(node_id) {
-            Some(remove_details) => remove_details,
-            None => {
-                log::error!("Could not find node {:?}", node_id);
-                return;
-            }
-        };
+    /// Hand back how much has been sent to each feed.
+    fn handle_gather_feed_stats(&mut self, tx: flume::Sender<BTreeMap<u64, FeedStats>>) {
+        let stats = self
+            .feed_channels
+            .iter()
+            .map(|(&feed_conn_id, chan)| (feed_conn_id.into(), chan.stats()))
+            .collect();
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(stats);
+    }
 
-        // The chain has been removed (no nodes left in it, or it was renamed):
-        if removed_details.chain_node_count == 0 || removed_details.has_chain_label_changed {
-            feed_for_all.push(feed_message::RemovedChain(&removed_details.old_chain_label));
-        }
+    /// Change the retention policy of a chain.
+    fn handle_set_retention_policy(
+        &mut self,
+        genesis_hash: BlockHash,
+        policy: RetentionPolicy,
+        tx: flume::Sender<bool>,
+    ) {
+        let found = self.node_state.set_retention_policy(&genesis_hash, policy);
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(found);
+    }
 
-        // If the chain still exists, tell everybody about the new label or updated node count:
-        if removed_details.chain_node_count != 0 {
-            feed_for_all.push(feed_message::AddedChain(
-                &removed_details.new_chain_label,
-                removed_details.chain_node_count,
-            ));
+    /// Replace the denylist, closing any nodes already connected from newly denied chains
+    /// if asked to.
+    fn handle_set_denylist(&mut self, denylist: Vec<String>, close_denied_nodes: bool) {
+        self.node_state.set_denylist(denylist);
+        if close_denied_nodes {
+            let denied = self.node_state.denied_nodes();
+            if !denied.is_empty() {
+                log::info!("Closing {} nodes on newly denied chains", denied.len());
+                self.evict_nodes(denied, MuteReason::ChainNotAllowed);
+            }
         }
+    }
 
-        // Assuming the chain hasn't gone away, tell chain subscribers about the node removal
-        if removed_details.chain_node_count != 0 {
-            feed_for_chain.push(feed_message::RemovedNode(
-                node_id.get_chain_node_id().into(),
-            ));
-        }
+    /// Replace the patterns that are redacted from node names. Feeds only see names
+    /// redacted with the new patterns for nodes they hear about from now on.
+    fn handle_set_name_redactor(&mut self, redactor: Arc<NameRedactor>) {
+        self.name_redactor = redactor;
     }
 
-    /// Finalize a [`FeedMessageSerializer`] and broadcast the result to feeds for the chain.
-    fn finalize_and_broadcast_to_chain_feeds(
+    /// Look up the location of a node again, ignoring anything we've cached for its IP
+    /// address. We only locate IPV4 addresses, so nodes without one can't be relocated.
+    fn handle_relocate_node(
         &mut self,
-        genesis_hash: &BlockHash,
-        serializer: FeedMessageSerializer,
+        genesis_hash: BlockHash,
+        node_id: usize,
+        tx: flume::Sender<bool>,
     ) {
-        if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_chain_feeds(genesis_hash, ToFeedWebsocket::Bytes(bytes));
-        }
+        let request = self
+            .node_state
+            .get_node_id(&genesis_hash, node_id)
+            .and_then(|node_id| {
+                let ip = *self.node_ips.get(&node_id)?;
+                Some(find_location::LocateRequest::refresh(node_id, ip))
+            });
+        let relocating = match request {
+            Some(request) => self.tx_to_locator.send(request).is_ok(),
+            None => false,
+        };
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(relocating);
     }
 
-    /// Send a message to all chain feeds.
-    fn broadcast_to_chain_feeds(&mut self, genesis_hash: &BlockHash, message: ToFeedWebsocket) {
-        if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
-            for &feed_id in feeds {
-                if let Some(chan) = self.feed_channels.get_mut(&feed_id) {
-                    let _ = chan.send(message.clone());
-                }
-            }
+    /// Tell every shard to drop updates for a chain for the given length of time, handing
+    /// back when they'll stop. Shards end the mute by themselves once it runs out.
+    pub fn mute_chain(
+        &mut self,
+        genesis_hash: BlockHash,
+        ttl: Duration,
+        now: Timestamp,
+    ) -> Timestamp {
+        let muted_until = now + ttl.as_millis() as u64;
+        self.chain_mutes.retain(|_, until| *until > now);
+        self.chain_mutes.insert(genesis_hash, muted_until);
+        log::info!("Muting chain {:?} for {:?}", genesis_hash, ttl);
+
+        for shard_conn in self.shard_channels.values() {
+            let _ = shard_conn.send(ToShardWebsocket::MuteChain {
+                genesis_hash,
+                ttl_ms: muted_until - now,
+            });
         }
+        muted_until
     }
 
-    /// Finalize a [`FeedMessageSerializer`] and broadcast the result to all feeds
-    fn finalize_and_broadcast_to_all_feeds(&mut self, serializer: FeedMessageSerializer) {
-        if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_all_feeds(ToFeedWebsocket::Bytes(bytes));
+    /// Tell every shard to stop dropping updates for a chain, handing back whether it
+    /// was muted.
+    pub fn unmute_chain(&mut self, genesis_hash: BlockHash, now: Timestamp) -> bool {
+        let was_muted = self.chain_muted_until(&genesis_hash, now).is_some();
+        self.chain_mutes.remove(&genesis_hash);
+        log::info!("Unmuting chain {:?}", genesis_hash);
+
+        // Shards might still think that the chain is muted even if we don't (their clocks
+        // may run a little slow), so they're always told:
+        for shard_conn in self.shard_channels.values() {
+            let _ = shard_conn.send(ToShardWebsocket::UnmuteChain { genesis_hash });
         }
+        was_muted
     }
 
-    /// Send a message to everybody.
-    fn broadcast_to_all_feeds(&mut self, message: ToFeedWebsocket) {
-        for chan in self.feed_channels.values_mut() {
-            let _ = chan.send(message.clone());
-        }
+    /// When shards will stop dropping updates for a chain, if they've been told to drop them.
+    fn chain_muted_until(&self, genesis_hash: &BlockHash, now: Timestamp) -> Option<Timestamp> {
+        self.chain_mutes
+            .get(genesis_hash)
+            .copied()
+            .filter(|&until| until > now)
     }
 
-    /// Finalize a [`FeedMessageSerializer`] and broadcast the result to chain finality feeds
-    fn finalize_and_broadcast_to_chain_finality_feeds(
-        &mut self,
-        genesis_hash: &BlockHash,
-        serializer: FeedMessageSerializer,
-    ) {
-        if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_chain_finality_feeds(genesis_hash, ToFeedWebsocket::Bytes(bytes));
+    /// Tell chain feeds how many nodes are at the best block, if that's changed. This
+    /// is done periodically rather than on every block announcement to limit how
+    /// often feeds are sent it.
+    fn handle_send_nodes_at_best(&mut self) {
+        for (genesis_hash, nodes_at_best) in self.node_state.take_nodes_at_best_changes() {
+            let mut feed_serializer = FeedMessageSerializer::new();
+            feed_serializer.push(feed_message::NodesAtBest(nodes_at_best));
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_serializer);
         }
     }
 
-    /// Send a message to all chain finality feeds.
-    fn broadcast_to_chain_finality_feeds(
-        &mut self,
-        genesis_hash: &BlockHash,
-        message: ToFeedWebsocket,
-    ) {
-        if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
-            // Get all feeds for the chain, but only broadcast to those feeds that
-            // are also subscribed to receive finality updates.
-            for &feed_id in feeds.union(&self.feed_conn_id_finality) {
-                if let Some(chan) = self.feed_channels.get_mut(&feed_id) {
-                    let _ = chan.send(message.clone());
+    /// Tell every feed that we're shutting down, and then stop sending feeds anything.
+    /// Once a feed connection has sent on everything already queued up for it, it will
+    /// see that we've gone away and close.
+    fn handle_shutdown(&mut self, restart_in_seconds: Option<u32>, tx: flume::Sender<()>) {
+        let mut feed_serializer = FeedMessageSerializer::new();
+        feed_serializer.push(feed_message::ServerShutdown(restart_in_seconds));
+        self.finalize_and_broadcast_to_all_feeds(feed_serializer);
+
+        self.shutting_down = true;
+        self.feed_channels.clear();
+
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(());
+    }
+
+    /// Tell every feed about changes to the stats of each chain since they were last sent.
+    fn handle_send_chain_stats(&mut self) {
+        let now = time::now();
+        let mut feed_serializer = FeedMessageSerializer::new();
+        for (label, change) in self.node_state.take_public_node_shortage_changes() {
+            match change {
+                state::ShortageChange::Started { count } => {
+                    log::warn!("[{}] Only {} publicly reachable nodes remain", label, count);
+                    feed_serializer.push(feed_message::PublicNodeShortage(&label, count));
+                }
+                state::ShortageChange::Ended { count } => {
+                    log::info!("[{}] {} publicly reachable nodes again", label, count);
+                    feed_serializer.push(feed_message::PublicNodeShortageResolved(&label, count));
                 }
             }
         }
+        for chain in self.node_state.iter_chains() {
+            let stats = state::ChainStats::new(&chain, now);
+            let was_stalled = self
+                .chain_stats
+                .last_sent(chain.genesis_hash())
+                .map(|last| last.block_production_stalled);
+            match (was_stalled, stats.block_production_stalled) {
+                (Some(false) | None, true) => log::warn!(
+                    "[{}] Block production has stalled at block {}",
+                    chain.label(),
+                    stats.best_block
+                ),
+                (Some(true), false) => log::info!(
+                    "[{}] Block production has recovered at block {}",
+                    chain.label(),
+                    stats.best_block
+                ),
+                _ => {}
+            }
+            if let Some(update) = self.chain_stats.update(*chain.genesis_hash(), stats) {
+                feed_serializer.push(feed_message::ChainStats(
+                    chain.label(),
+                    update.full,
+                    &update.values,
+                    update.continent_distribution.as_deref(),
+                    update.top_pallet_trie_sizes.as_deref(),
+                ));
+            }
+        }
+        self.finalize_and_broadcast_to_all_feeds(feed_serializer);
+    }
+
+    /// How many feeds are subscribed to the chain with the given label, including those
+    /// that other aggregators last told us about.
+    pub fn subscriber_count_for_chain(&self, chain: &str) -> u32 {
+        self.node_state
+            .get_chain_by_label(chain)
+            .map_or(0, |chain| self.subscriber_count(chain.genesis_hash()))
+    }
+
+    fn subscriber_count(&self, genesis_hash: &BlockHash) -> u32 {
+        let elsewhere = self
+            .subscriber_counts_elsewhere
+            .get(genesis_hash)
+            .copied()
+            .unwrap_or(0);
+        self.local_subscriber_count(genesis_hash) + elsewhere
+    }
+
+    /// How many of our own feeds are subscribed to the chain with the given genesis hash.
+    fn local_subscriber_count(&self, genesis_hash: &BlockHash) -> u32 {
+        self.chain_to_feed_conn_ids
+            .get_values(genesis_hash)
+            .map_or(0, |feeds| feeds.len() as u32)
+    }
+
+    /// Hand back how many of our own feeds are subscribed to each chain that has any.
+    fn handle_gather_subscriber_counts(&mut self, tx: flume::Sender<HashMap<BlockHash, u32>>) {
+        let counts = self
+            .node_state
+            .iter_chains()
+            .map(|chain| *chain.genesis_hash())
+            .filter_map(|genesis_hash| {
+                let count = self.local_subscriber_count(&genesis_hash);
+                (count > 0).then_some((genesis_hash, count))
+            })
+            .collect();
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(counts);
+    }
+
+    /// Tell the feeds of each chain how many of them there are, if they're due to be told.
+    fn send_subscriber_counts(&mut self, now: Timestamp) {
+        let genesis_hashes: Vec<BlockHash> = self.subscriber_counts_sent.keys().copied().collect();
+        for genesis_hash in genesis_hashes {
+            self.send_subscriber_count(&genesis_hash, now);
+        }
+    }
+
+    /// Tell the feeds subscribed to a chain how many of them there are, if that's changed
+    /// by more than [`SUBSCRIBER_COUNT_CHANGE`] since they were last told, or has changed at
+    /// all and they were last told [`SUBSCRIBER_COUNT_INTERVAL_MS`] or more ago. Returns
+    /// whether they were told.
+    fn send_subscriber_count(&mut self, genesis_hash: &BlockHash, now: Timestamp) -> bool {
+        // Only our own feeds are sent anything, so there's no one to tell if we have none:
+        let has_feeds = self.local_subscriber_count(genesis_hash) > 0;
+        let count = self.subscriber_count(genesis_hash);
+        let chain = match self.node_state.get_chain_by_genesis_hash(genesis_hash) {
+            Some(chain) if has_feeds => chain,
+            _ => {
+                self.subscriber_counts_sent.remove(genesis_hash);
+                return false;
+            }
+        };
+        if let Some(&(last_count, last_sent_at)) = self.subscriber_counts_sent.get(genesis_hash) {
+            let change = (count as f64 - last_count as f64).abs();
+            let due = now.saturating_sub(last_sent_at) >= SUBSCRIBER_COUNT_INTERVAL_MS;
+            if change <= last_count as f64 * SUBSCRIBER_COUNT_CHANGE && !(due && change > 0.0) {
+                return false;
+            }
+        }
+
+        let mut feed_serializer = FeedMessageSerializer::new();
+        feed_serializer.push(feed_message::SubscriberCount(chain.label(), count));
+        self.subscriber_counts_sent
+            .insert(*genesis_hash, (count, now));
+        self.finalize_and_broadcast_to_chain_feeds(genesis_hash, feed_serializer);
+        true
+    }
+
+    /// Send a heartbeat to every feed that hasn't been sent anything for the heartbeat
+    /// interval, so that it knows that it's still connected.
+    fn send_feed_heartbeats(&mut self, now: Timestamp) {
+        let interval_ms = match self.feed_heartbeat_interval_ms {
+            Some(interval_ms) => interval_ms,
+            None => return,
+        };
+        let mut feed_serializer = FeedMessageSerializer::new();
+        feed_serializer.push(feed_message::Heartbeat(now));
+        let bytes = match feed_serializer.into_finalized() {
+            Some(bytes) => bytes,
+            None => return,
+        };
+        for chan in self.feed_channels.values() {
+            if now.saturating_sub(chan.last_sent_at.get()) >= interval_ms {
+                let _ = chan.send_at(ToFeedWebsocket::Bytes(bytes.clone()), now);
+            }
+        }
+    }
+
+    /// Handle messages that come from the node geographical locator.
+    fn handle_from_find_location(&mut self, node_id: NodeId, location: find_location::Location) {
+        let location = match self.anonymize_locations {
+            Some(decimal_places) => location.map(|loc| Arc::new(loc.anonymized(decimal_places))),
+            None => location,
+        };
+
+        self.node_state
+            .update_node_location(node_id, location.clone());
+
+        if let Some(loc) = location {
+            let mut feed_message_serializer = FeedMessageSerializer::new();
+            feed_message_serializer.push(feed_message::LocatedNode(
+                node_id.get_chain_node_id().into(),
+                loc.latitude,
+                loc.longitude,
+                &loc.city,
+            ));
+
+            let chain_genesis_hash = self
+                .node_state
+                .get_chain_by_node_id(node_id)
+                .map(|chain| *chain.genesis_hash());
+
+            if let Some(chain_genesis_hash) = chain_genesis_hash {
+                self.finalize_and_broadcast_to_chain_feeds(
+                    &chain_genesis_hash,
+                    feed_message_serializer,
+                );
+            }
+        }
+    }
+
+    /// Handle messages coming from shards.
+    fn handle_from_shard(&mut self, shard_conn_id: ConnId, msg: FromShardWebsocket) {
+        match msg {
+            FromShardWebsocket::Initialize { channel } => {
+                // Shards that connect part way through a mute are told about it too:
+                let now = time::now();
+                for (&genesis_hash, &until) in &self.chain_mutes {
+                    if until > now {
+                        let _ = channel.send(ToShardWebsocket::MuteChain {
+                            genesis_hash,
+                            ttl_ms: until - now,
+                        });
+                    }
+                }
+                self.shard_channels.insert(shard_conn_id, channel);
+            }
+            FromShardWebsocket::Add {
+                local_id,
+                ip,
+                node,
+                genesis_hash,
+                resumed,
+            } => {
+                // We don't know the node's ID until it's been added:
+                let span = tracing::debug_span!(
+                    "node_message",
+                    node_id = tracing::field::Empty,
+                    chain = ?genesis_hash,
+                    message_type = "system.connected",
+                );
+                let _entered = span.enter();
+
+                // If a node sends its system details again (perhaps they changed), we'll
+                // be told about it again with the same ID. Remove the existing node first
+                // so that it's replaced rather than left dangling.
+                if let Some(&old_node_id) = self.node_ids.get_by_right(&(shard_conn_id, local_id)) {
+                    self.remove_nodes_and_broadcast_result(Some(old_node_id));
+                }
+                self.muted_shard_nodes.remove(&(shard_conn_id, local_id));
+
+                // A node that's only just disconnected picks up where it left off, and
+                // feeds are none the wiser:
+                if let Some(node_id) =
+                    self.reconnect_disconnected_node(genesis_hash, &node, resumed, time::now())
+                {
+                    span.record("node_id", usize::from(node_id.get_chain_node_id()));
+                    self.node_ids.insert(node_id, (shard_conn_id, local_id));
+                    return;
+                }
+
+                // If this is a new first party chain and we're tracking as many chains as
+                // we can, a third party chain has to go to make room for it:
+                let evicted = self.node_state.make_room_for_chain(&genesis_hash);
+                if !evicted.is_empty() {
+                    self.evict_nodes(evicted, MuteReason::TelemetryFull);
+                }
+
+                match self.node_state.add_node(genesis_hash, node) {
+                    state::AddNodeResult::ChainOnDenyList => {
+                        self.mute_shard_node(shard_conn_id, local_id, MuteReason::ChainNotAllowed);
+                    }
+                    state::AddNodeResult::ChainOverQuota => {
+                        self.mute_shard_node(shard_conn_id, local_id, MuteReason::Overquota);
+                    }
+                    state::AddNodeResult::TooManyChains => {
+                        self.mute_shard_node(shard_conn_id, local_id, MuteReason::TelemetryFull);
+                    }
+                    state::AddNodeResult::NodeAddedToChain(details) => {
+                        let node_id = details.id;
+                        span.record("node_id", usize::from(node_id.get_chain_node_id()));
+
+                        // Record ID <-> (shardId,localId) for future messages:
+                        self.node_ids.insert(node_id, (shard_conn_id, local_id));
+
+                        // Don't hold onto details too long because we want &mut self later:
+                        let old_chain_label = details.old_chain_label.to_owned();
+                        let new_chain_label = details.new_chain_label.to_owned();
+                        let chain_node_count = details.chain_node_count;
+                        let has_chain_label_changed = details.has_chain_label_changed;
+                        let chain_type = details.chain_type;
+                        let first_party = details.first_party;
+                        let node_details = details.node.details().clone();
+
+                        // Tell chain subscribers about the node we've just added:
+                        let mut feed_messages_for_chain = FeedMessageSerializer::new();
+                        feed_messages_for_chain.push(feed_message::AddedNode(
+                            node_id.get_chain_node_id().into(),
+                            &details.node,
+                            &self.name_redactor,
+                        ));
+                        for alert in details.node.alerts().active() {
+                            feed_messages_for_chain.push(feed_message::NodeAlert(
+                                node_id.get_chain_node_id().into(),
+                                alert,
+                            ));
+                        }
+                        state::push_alert_changes(
+                            &details.alert_changes,
+                            &mut feed_messages_for_chain,
+                        );
+                        if let Some(at_block) = details.upgrade_scheduled {
+                            feed_messages_for_chain.push(feed_message::UpgradeScheduled(
+                                &new_chain_label,
+                                at_block,
+                                details.best_block,
+                            ));
+                        }
+                        self.add_node_to_feed_filters(
+                            &genesis_hash,
+                            node_id.get_chain_node_id().into(),
+                            &node_details,
+                        );
+                        self.finalize_and_broadcast_to_chain_feeds(
+                            &genesis_hash,
+                            feed_messages_for_chain,
+                        );
+                        self.broadcast_distribution_to_chain_feeds(&genesis_hash);
+                        // Tell everybody about the new node count and potential rename:
+                        let mut feed_messages_for_all = FeedMessageSerializer::new();
+                        if has_chain_label_changed {
+                            feed_messages_for_all
+                                .push(feed_message::RemovedChain(&old_chain_label));
+                            self.chain_stats.forget(&genesis_hash);
+                        }
+                        feed_messages_for_all.push(feed_message::AddedChain(
+                            &new_chain_label,
+                            chain_node_count,
+                            chain_type,
+                            first_party,
+                        ));
+                        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
+
+                        // Ask for the grographical location of the node.
+                        // Currently we only geographically locate IPV4 addresses so ignore IPV6.
+                        if let IpAddr::V4(ip_v4) = ip {
+                            self.node_ips.insert(node_id, ip_v4);
+                            let _ = self
+                                .tx_to_locator
+                                .send(find_location::LocateRequest::new(node_id, ip_v4));
+                        }
+                    }
+                }
+            }
+            FromShardWebsocket::Remove { local_id, reason } => {
+                let node_id = match self.node_ids.remove_by_right(&(shard_conn_id, local_id)) {
+                    Some((node_id, _)) => node_id,
+                    None if self.muted_shard_nodes.remove(&(shard_conn_id, local_id)) => return,
+                    None => {
+                        log::error!(
+                            "Cannot find ID for node with shard/connectionId of {:?}/{:?}",
+                            shard_conn_id,
+                            local_id
+                        );
+                        return;
+                    }
+                };
+                log::debug!(
+                    "Removing node {:?} ({}, code {})",
+                    node_id,
+                    reason.as_str(),
+                    reason.code()
+                );
+                *self.removed_nodes.entry(reason).or_default() += 1;
+                if !self.hold_disconnected_node(node_id, false, time::now()) {
+                    self.remove_nodes_and_broadcast_result(Some(node_id));
+                }
+            }
+            FromShardWebsocket::Update {
+                local_id,
+                payload,
+                reported_at,
+                ingest,
+            } => {
+                let node_id = match self.node_ids.get_by_right(&(shard_conn_id, local_id)) {
+                    Some(id) => *id,
+                    None if self.muted_shard_nodes.contains(&(shard_conn_id, local_id)) => return,
+                    None => {
+                        log::warn!(
+                            "Cannot find ID for node with shard/connectionId of {:?}/{:?}; asking the shard to resync it",
+                            shard_conn_id,
+                            local_id
+                        );
+                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                            let _ = shard_conn.send(ToShardWebsocket::Resync { local_id });
+                        }
+                        return;
+                    }
+                };
+
+                let genesis_hash = self
+                    .node_state
+                    .get_chain_by_node_id(node_id)
+                    .map(|chain| *chain.genesis_hash());
+                let span = tracing::debug_span!(
+                    "node_message",
+                    node_id = usize::from(node_id.get_chain_node_id()),
+                    chain = tracing::field::Empty,
+                    message_type = payload.name(),
+                );
+                let _entered = span.enter();
+
+                if let Some(genesis_hash) = genesis_hash {
+                    span.record("chain", tracing::field::debug(genesis_hash));
+                    self.ingest_latency.record(
+                        genesis_hash,
+                        shard_conn_id,
+                        &ingest,
+                        Instant::now(),
+                    );
+                }
+
+                let mut feed_message_serializer = FeedMessageSerializer::new();
+                let broadcast_finality = self.node_state.update_node(
+                    node_id,
+                    payload,
+                    reported_at,
+                    &mut feed_message_serializer,
+                );
+
+                if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
+                    let genesis_hash = *chain.genesis_hash();
+                    if broadcast_finality {
+                        self.finalize_and_broadcast_to_chain_finality_feeds(
+                            &genesis_hash,
+                            feed_message_serializer,
+                        );
+                    } else {
+                        self.finalize_and_broadcast_to_chain_feeds(
+                            &genesis_hash,
+                            feed_message_serializer,
+                        );
+                    }
+                }
+            }
+            FromShardWebsocket::Disconnected => {
+                // Find all nodes associated with this shard connection ID:
+                let shard_node_ids: Vec<NodeId> = self
+                    .node_ids
+                    .iter()
+                    .filter(|(_, &(this_shard_conn_id, _))| shard_conn_id == this_shard_conn_id)
+                    .map(|(&node_id, _)| node_id)
+                    .collect();
+
+                // ... and remove them, unless we're holding on to them in case the shard
+                // is only restarting:
+                let now = time::now();
+                let mut node_ids_to_remove = Vec::new();
+                for node_id in shard_node_ids {
+                    self.node_ids.remove_by_left(&node_id);
+                    if !self.hold_disconnected_node(node_id, true, now) {
+                        node_ids_to_remove.push(node_id);
+                    }
+                }
+                self.remove_nodes_and_broadcast_result(node_ids_to_remove);
+                self.muted_shard_nodes
+                    .retain(|&(this_shard_conn_id, _)| this_shard_conn_id != shard_conn_id);
+                self.ingest_latency.remove_shard(shard_conn_id);
+            }
+        }
+    }
+
+    /// Handle messages coming from feeds.
+    fn handle_from_feed(&mut self, feed_conn_id: ConnId, msg: FromFeedWebsocket) {
+        match msg {
+            FromFeedWebsocket::Initialize {
+                channel,
+                node_filter,
+            } => {
+                // Dropping the channel closes the feed; we won't be sending it anything:
+                if self.shutting_down {
+                    return;
+                }
+                let channel = FeedChannel::new(channel);
+                if let Some(filter) = node_filter {
+                    self.feed_node_filters.insert(
+                        feed_conn_id,
+                        FeedNodeFilter {
+                            filter,
+                            node_ids: HashSet::new(),
+                        },
+                    );
+                }
+
+                // Tell the new feed subscription some basic things to get it going:
+                let mut feed_serializer = FeedMessageSerializer::new();
+                feed_serializer.push(feed_message::Version(feed_message::FEED_VERSION));
+                for chain in self.node_state.iter_chains() {
+                    feed_serializer.push(feed_message::AddedChain(
+                        chain.label(),
+                        chain.node_count(),
+                        chain.chain_type(),
+                        chain.is_first_party(),
+                    ));
+                    // The stats that we last sent everybody else, so that the diffs
+                    // which follow apply to them:
+                    if let Some(stats) = self.chain_stats.last_sent(chain.genesis_hash()) {
+                        feed_serializer.push(feed_message::ChainStats(
+                            chain.label(),
+                            true,
+                            &stats.values(),
+                            Some(&stats.continent_distribution),
+                            Some(&stats.top_pallet_trie_sizes),
+                        ));
+                    }
+                }
+
+                // Send this to the channel that subscribed:
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    let _ = channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+                self.feed_channels.insert(feed_conn_id, channel);
+            }
+            FromFeedWebsocket::Ping { value } => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                // Pong!
+                let mut feed_serializer = FeedMessageSerializer::new();
+                feed_serializer.push(feed_message::Pong(&value));
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+            }
+            FromFeedWebsocket::Subscribe { chain } => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                // Unsubscribe from previous chain if subscribed to one:
+                let old_genesis_hash = self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+
+                // Untoggle request for finality and distribution feeds:
+                self.feed_conn_id_finality.remove(&feed_conn_id);
+                self.feed_conn_id_distribution.remove(&feed_conn_id);
+
+                // Get old chain if there was one:
+                let node_state = &self.node_state;
+                let old_chain =
+                    old_genesis_hash.and_then(|hash| node_state.get_chain_by_genesis_hash(&hash));
+
+                // Get new chain, ignoring the rest if it doesn't exist.
+                let new_chain = match self.node_state.get_chain_by_label(&chain) {
+                    Some(chain) => chain,
+                    None => return,
+                };
+
+                // If the feed only wants to hear about certain nodes, find them on the new chain:
+                if let Some(filter) = self.feed_node_filters.get_mut(&feed_conn_id) {
+                    filter.node_ids = new_chain
+                        .nodes_slice()
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(idx, n)| n.as_ref().map(|n| (idx, n)))
+                        .filter(|(_, n)| filter.filter.matches_node(n.details()))
+                        .map(|(idx, _)| idx)
+                        .collect();
+                }
+                let node_filter = self.feed_node_filters.get(&feed_conn_id);
+
+                // Send messages to the feed about this subscription:
+                let mut feed_serializer = FeedMessageSerializer::new();
+                if let Some(old_chain) = old_chain {
+                    feed_serializer.push(feed_message::UnsubscribedFrom(old_chain.label()));
+                }
+                feed_serializer.push(feed_message::SubscribedTo(new_chain.label()));
+                let now = time::now();
+                feed_serializer.push(feed_message::TimeSync(now));
+                feed_serializer.push(feed_message::BestBlock(
+                    new_chain.best_block().height,
+                    new_chain.timestamp(),
+                    new_chain.average_block_time(),
+                ));
+                if let Some(rate) = new_chain.block_production_rate() {
+                    feed_serializer.push(feed_message::BlockProductionRate(rate));
+                }
+                if let Some(at_block) = new_chain.pending_upgrade() {
+                    feed_serializer.push(feed_message::UpgradeScheduled(
+                        new_chain.label(),
+                        at_block,
+                        new_chain.best_block().height,
+                    ));
+                }
+                if let Some(age) = new_chain.best_block_age(now) {
+                    feed_serializer.push(feed_message::BestBlockAge(age));
+                }
+                feed_serializer.push(feed_message::BestFinalized(
+                    new_chain.finalized_block().height,
+                    new_chain.finalized_block().hash,
+                ));
+                feed_serializer.push(feed_message::NodeCountHistory(
+                    new_chain.node_count_history(),
+                ));
+                feed_serializer.push(feed_message::NodesAtBest(new_chain.nodes_at_best()));
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+
+                // If many (eg 10k) nodes are connected, serializing all of their info takes time.
+                // So, parallelise this with Rayon, but we still send out messages for each node in the
+                // order that they joined the chain (which is helpful for the UI as it tries to maintain a
+                // sorted list of nodes, and means that the same nodes are always sent in the same order,
+                // whichever other nodes have come and gone). The chunk
+                // size is the max number of node info we fit into 1 message; smaller messages allow the UI
+                // to react a little faster and not have to wait for a larger update to come in. A chunk size
+                // of 64 means each message is ~32k.
+                use rayon::prelude::*;
+                let name_redactor = &*self.name_redactor;
+                let all_feed_messages: Vec<_> = new_chain
+                    .nodes_in_join_order()
+                    .par_iter()
+                    .chunks(64)
+                    .filter_map(|nodes| {
+                        let mut feed_serializer = FeedMessageSerializer::new();
+                        for &(node_id, node) in
+                            nodes.into_iter().filter(|(idx, _)| match node_filter {
+                                Some(filter) => filter.node_ids.contains(idx),
+                                None => true,
+                            })
+                        {
+                            feed_serializer.push(feed_message::AddedNode(
+                                node_id,
+                                node,
+                                name_redactor,
+                            ));
+                            feed_serializer.push(feed_message::FinalizedBlock(
+                                node_id,
+                                node.finalized().height,
+                                node.finalized().hash,
+                                node.finalized_at(),
+                            ));
+                            if node.stale() {
+                                feed_serializer.push(feed_message::StaleNode(node_id));
+                            }
+                            for alert in node.alerts().active() {
+                                feed_serializer.push(feed_message::NodeAlert(node_id, alert));
+                            }
+                        }
+                        feed_serializer.into_finalized()
+                    })
+                    .collect();
+                for bytes in all_feed_messages {
+                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+
+                // Actually make a note of the new chain subsciption:
+                let new_genesis_hash = *new_chain.genesis_hash();
+                self.chain_to_feed_conn_ids
+                    .insert(new_genesis_hash, feed_conn_id);
+
+                // Let feeds know how many are subscribed to the chains this one moved between.
+                // Even if the new chain's other feeds needn't hear about it yet, this one does:
+                if let Some(old_genesis_hash) = old_genesis_hash {
+                    self.send_subscriber_count(&old_genesis_hash, now);
+                }
+                if !self.send_subscriber_count(&new_genesis_hash, now) {
+                    let count = self.subscriber_count(&new_genesis_hash);
+                    let chain = self.node_state.get_chain_by_genesis_hash(&new_genesis_hash);
+                    if let (Some(chain), Some(feed_channel)) =
+                        (chain, self.feed_channels.get_mut(&feed_conn_id))
+                    {
+                        let mut feed_serializer = FeedMessageSerializer::new();
+                        feed_serializer.push(feed_message::SubscriberCount(chain.label(), count));
+                        if let Some(bytes) = feed_serializer.into_finalized() {
+                            let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                        }
+                    }
+                }
+            }
+            FromFeedWebsocket::SendFinality => {
+                self.feed_conn_id_finality.insert(feed_conn_id);
+            }
+            FromFeedWebsocket::NoMoreFinality => {
+                self.feed_conn_id_finality.remove(&feed_conn_id);
+            }
+            FromFeedWebsocket::SendDistribution => {
+                self.feed_conn_id_distribution.insert(feed_conn_id);
+
+                // Send the current distribution for the subscribed chain straight away,
+                // and then updates whenever it changes:
+                let feed_channel = match self.feed_channels.get(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+                let chain = self
+                    .chain_to_feed_conn_ids
+                    .get_key(&feed_conn_id)
+                    .and_then(|hash| self.node_state.get_chain_by_genesis_hash(hash));
+                if let Some(chain) = chain {
+                    let mut feed_serializer = FeedMessageSerializer::new();
+                    feed_serializer.push(feed_message::ChainDistribution(chain.distribution()));
+                    if let Some(bytes) = feed_serializer.into_finalized() {
+                        let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    }
+                }
+            }
+            FromFeedWebsocket::NoMoreDistribution => {
+                self.feed_conn_id_distribution.remove(&feed_conn_id);
+            }
+            FromFeedWebsocket::Disconnected => {
+                // The feed has disconnected; clean up references to it:
+                if let Some(genesis_hash) = self.chain_to_feed_conn_ids.remove_value(&feed_conn_id)
+                {
+                    self.send_subscriber_count(&genesis_hash, time::now());
+                }
+                self.feed_channels.remove(&feed_conn_id);
+                self.feed_conn_id_finality.remove(&feed_conn_id);
+                self.feed_conn_id_distribution.remove(&feed_conn_id);
+                self.feed_node_filters.remove(&feed_conn_id);
+            }
+        }
+    }
+
+    /// How long to hold on to a node that's disconnected, either on its own or because its
+    /// shard has (`shard_gone`). `None` if it shouldn't be held on to at all.
+    fn hold_window(&self, shard_gone: bool) -> Option<u64> {
+        if shard_gone {
+            self.shard_restart_grace_ms
+        } else {
+            self.reconnect_debounce_ms
+        }
+    }
+
+    /// If reconnects are being debounced (or, for nodes whose shard has gone, if shards are
+    /// given a grace period to restart), hold on to a node that's just disconnected rather
+    /// than removing it, so that feeds needn't hear about it if it comes straight back.
+    /// Nodes without a network ID can't be recognised when they return, so aren't held on
+    /// to. Returns `false` if the node should be removed as normal.
+    fn hold_disconnected_node(
+        &mut self,
+        node_id: NodeId,
+        shard_gone: bool,
+        now: Timestamp,
+    ) -> bool {
+        if self.hold_window(shard_gone).is_none() {
+            return false;
+        }
+        let chain = match self.node_state.get_chain_by_node_id(node_id) {
+            Some(chain) => chain,
+            None => return false,
+        };
+        let network_id = chain
+            .get_node(node_id.get_chain_node_id().into())
+            .and_then(|node| node.details().network_id.clone());
+        let network_id = match network_id {
+            Some(network_id) => network_id,
+            None => return false,
+        };
+
+        let key = (*chain.genesis_hash(), network_id);
+        let disconnected = DisconnectedNode {
+            node_id,
+            disconnected_at: now,
+            shard_gone,
+        };
+        // Two nodes with the same identity have disconnected; only the latest can reconnect:
+        if let Some(previous) = self.disconnected_nodes.insert(key, disconnected) {
+            self.remove_nodes_and_broadcast_result(Some(previous.node_id));
+        }
+        true
+    }
+
+    /// If the node being added disconnected within the debounce window, and looks the same
+    /// to feeds as it did then, return the ID that it had. Nodes whose shard went away are
+    /// only picked back up if the shard adding them was connected to them before (ie it has
+    /// `resumed` them). If it disconnected too long ago, looks different or can't be resumed,
+    /// the old node is removed (and feeds told) so it can be added afresh.
+    fn reconnect_disconnected_node(
+        &mut self,
+        genesis_hash: BlockHash,
+        details: &NodeDetails,
+        resumed: bool,
+        now: Timestamp,
+    ) -> Option<NodeId> {
+        let key = (genesis_hash, details.network_id.clone()?);
+        let disconnected = self.disconnected_nodes.remove(&key)?;
+
+        let node_id = disconnected.node_id;
+        let window = self.hold_window(disconnected.shard_gone).unwrap_or(0);
+        let in_time = now.saturating_sub(disconnected.disconnected_at) <= window;
+        let resumable = resumed || !disconnected.shard_gone;
+        let unchanged = self
+            .node_state
+            .get_chain_by_node_id(node_id)
+            .and_then(|chain| chain.get_node(node_id.get_chain_node_id().into()))
+            .map(|node| same_node_details(node.details(), details))
+            .unwrap_or(false);
+        if in_time && unchanged && resumable {
+            return Some(node_id);
+        }
+
+        self.remove_nodes_and_broadcast_result(Some(node_id));
+        None
+    }
+
+    /// Remove the nodes that disconnected longer ago than the reconnect debounce
+    /// window (or shard restart grace period), and tell feeds about it.
+    fn expire_disconnected_nodes(&mut self, now: Timestamp) {
+        let debounce_window = self.hold_window(false).unwrap_or(0);
+        let shard_gone_window = self.hold_window(true).unwrap_or(0);
+        let mut expired = Vec::new();
+        self.disconnected_nodes.retain(|_, disconnected| {
+            let window = if disconnected.shard_gone {
+                shard_gone_window
+            } else {
+                debounce_window
+            };
+            let keep = now.saturating_sub(disconnected.disconnected_at) <= window;
+            if !keep {
+                expired.push(disconnected.node_id);
+            }
+            keep
+        });
+        if !expired.is_empty() {
+            self.remove_nodes_and_broadcast_result(expired);
+        }
+    }
+
+    /// Remove nodes whose chain has been evicted to make room for another (or denied), and
+    /// ask the shards they're connected to to close their connections.
+    fn evict_nodes(&mut self, node_ids: Vec<NodeId>, reason: MuteReason) {
+        for node_id in &node_ids {
+            let (shard_conn_id, local_id) = match self.node_ids.get_by_left(node_id) {
+                Some(&ids) => ids,
+                // Disconnected nodes that we're holding on to aren't on any shard:
+                None => continue,
+            };
+            self.mute_shard_node(shard_conn_id, local_id, reason.clone());
+        }
+        self.disconnected_nodes
+            .retain(|_, disconnected| !node_ids.contains(&disconnected.node_id));
+        self.remove_nodes_and_broadcast_result(node_ids);
+    }
+
+    /// Ask a shard to mute one of its nodes, and remember that we did so.
+    fn mute_shard_node(
+        &mut self,
+        shard_conn_id: ConnId,
+        local_id: ShardNodeId,
+        reason: MuteReason,
+    ) {
+        self.muted_shard_nodes.insert((shard_conn_id, local_id));
+        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+            let _ = shard_conn.send(ToShardWebsocket::Mute { local_id, reason });
+        }
+    }
+
+    /// Remove all of the node IDs provided and broadcast messages to feeds as needed.
+    fn remove_nodes_and_broadcast_result(&mut self, node_ids: impl IntoIterator<Item = NodeId>) {
+        // Group by chain to simplify the handling of feed messages:
+        let mut node_ids_per_chain: HashMap<BlockHash, Vec<NodeId>> = HashMap::new();
+        for node_id in node_ids.into_iter() {
+            if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
+                node_ids_per_chain
+                    .entry(*chain.genesis_hash())
+                    .or_default()
+                    .push(node_id);
+            }
+        }
+
+        // Remove the nodes for each chain
+        let mut feed_messages_for_all = FeedMessageSerializer::new();
+        for (chain_label, node_ids) in node_ids_per_chain {
+            let mut feed_messages_for_chain = FeedMessageSerializer::new();
+            for &node_id in &node_ids {
+                self.remove_node(
+                    node_id,
+                    &mut feed_messages_for_chain,
+                    &mut feed_messages_for_all,
+                );
+            }
+            self.finalize_and_broadcast_to_chain_feeds(&chain_label, feed_messages_for_chain);
+            self.broadcast_distribution_to_chain_feeds(&chain_label);
+            // Only now that feeds have been told about the removals can we forget about
+            // the nodes, since their IDs may be handed out again to new nodes:
+            self.remove_nodes_from_feed_filters(
+                &chain_label,
+                node_ids.iter().map(|id| id.get_chain_node_id().into()),
+            );
+        }
+        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
+    }
+
+    /// Remove a single node by its ID, pushing any messages we'd want to send
+    /// out to feeds onto the provided feed serializers. Doesn't actually send
+    /// anything to the feeds; just updates state as needed.
+    fn remove_node(
+        &mut self,
+        node_id: NodeId,
+        feed_for_chain: &mut FeedMessageSerializer,
+        feed_for_all: &mut FeedMessageSerializer,
+    ) {
+        // Remove our top level association (this may already have been done).
+        self.node_ids.remove_by_left(&node_id);
+        self.node_ips.remove(&node_id);
+
+        let genesis_hash = self
+            .node_state
+            .get_chain_by_node_id(node_id)
+            .map(|chain| *chain.genesis_hash());
+        let removed_details = match self.node_state.remove_node(node_id) {
+            Some(remove_details) => remove_details,
+            None => {
+                log::error!("Could not find node {:?}", node_id);
+                return;
+            }
+        };
+
+        // Nothing more will be sent about a chain with no nodes left in it:
+        if let (0, Some(genesis_hash)) = (removed_details.chain_node_count, genesis_hash) {
+            self.feed_budget_usage.remove(&genesis_hash);
+        }
+
+        // The chain has been removed (no nodes left in it, or it was renamed):
+        if removed_details.chain_node_count == 0 || removed_details.has_chain_label_changed {
+            feed_for_all.push(feed_message::RemovedChain(&removed_details.old_chain_label));
+            if let Some(genesis_hash) = genesis_hash {
+                self.chain_stats.forget(&genesis_hash);
+            }
+        }
+
+        // If the chain still exists, tell everybody about the new label or updated node count:
+        if removed_details.chain_node_count != 0 {
+            feed_for_all.push(feed_message::AddedChain(
+                &removed_details.new_chain_label,
+                removed_details.chain_node_count,
+                removed_details.chain_type,
+                removed_details.first_party,
+            ));
+        }
+
+        // Assuming the chain hasn't gone away, tell chain subscribers about the node removal
+        // and anything that changed about the remaining nodes as a result:
+        if removed_details.chain_node_count != 0 {
+            feed_for_chain.push(feed_message::RemovedNode(
+                node_id.get_chain_node_id().into(),
+            ));
+            state::push_alert_changes(&removed_details.alert_changes, feed_for_chain);
+        }
+    }
+
+    /// Finalize a [`FeedMessageSerializer`] and broadcast the result to feeds for the chain.
+    fn finalize_and_broadcast_to_chain_feeds(
+        &mut self,
+        genesis_hash: &BlockHash,
+        serializer: FeedMessageSerializer,
+    ) {
+        let lowest_priority = self.chain_lowest_priority_sent(genesis_hash, &serializer);
+        if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
+            self.finalize_and_send_to_feeds(feeds.iter().copied(), serializer, lowest_priority);
+        }
+    }
+
+    /// Given the messages about to be sent about a chain, what's the lowest priority of
+    /// message that its feeds should be sent, given the chain's budget (if it has one)?
+    fn chain_lowest_priority_sent(
+        &mut self,
+        genesis_hash: &BlockHash,
+        serializer: &FeedMessageSerializer,
+    ) -> Priority {
+        let budget = match self.feed_budgets.budget(genesis_hash) {
+            Some(budget) => budget,
+            None => return Priority::Low,
+        };
+        self.feed_budget_usage
+            .entry(*genesis_hash)
+            .or_default()
+            .lowest_priority_sent(budget, serializer.message_count(), time::now())
+    }
+
+    /// Finalize a [`FeedMessageSerializer`] and send the result to each of the feeds given.
+    /// Feeds that only want to hear about certain nodes are sent just the messages for those,
+    /// and feeds that are falling behind aren't sent the lower priority messages. No feed is
+    /// sent messages of a lower priority than `chain_lowest_priority`.
+    fn finalize_and_send_to_feeds(
+        &self,
+        feeds: impl IntoIterator<Item = ConnId>,
+        serializer: FeedMessageSerializer,
+        chain_lowest_priority: Priority,
+    ) {
+        let span = tracing::debug_span!(
+            "feed_dispatch",
+            messages = serializer.message_count(),
+            feeds = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+        let mut feed_count = 0;
+
+        // Feeds that want every message we can give them, grouped by the
+        // lowest priority of message that we're still sending to them.
+        let mut unfiltered_feeds = [Vec::new(), Vec::new(), Vec::new()];
+        for feed_id in feeds {
+            let chan = match self.feed_channels.get(&feed_id) {
+                Some(chan) => chan,
+                None => continue,
+            };
+            feed_count += 1;
+            let lowest_priority = self
+                .feed_priorities
+                .lowest_priority_sent(chan.len())
+                .max(chain_lowest_priority);
+            match self.feed_node_filters.get(&feed_id) {
+                Some(filter) => {
+                    let (bytes, dropped) = self.finalized_for_feed(
+                        &serializer,
+                        lowest_priority,
+                        Some(&filter.node_ids),
+                    );
+                    chan.dropped(dropped);
+                    if let Some(bytes) = bytes {
+                        let _ = chan.send(ToFeedWebsocket::Bytes(bytes));
+                    }
+                }
+                None => unfiltered_feeds[lowest_priority as usize].push(chan),
+            }
+        }
+        span.record("feeds", feed_count);
+
+        let [low, normal, high] = unfiltered_feeds;
+        for (lowest_priority, chans) in [(Priority::Normal, normal), (Priority::High, high)] {
+            if chans.is_empty() {
+                continue;
+            }
+            let (bytes, dropped) = self.finalized_for_feed(&serializer, lowest_priority, None);
+            for chan in &chans {
+                chan.dropped(dropped);
+            }
+            if let Some(bytes) = bytes {
+                let message = ToFeedWebsocket::Bytes(bytes);
+                for chan in chans {
+                    let _ = chan.send(message.clone());
+                }
+            }
+        }
+
+        if low.is_empty() {
+            return;
+        }
+        if let Some(bytes) = serializer.into_finalized() {
+            let message = ToFeedWebsocket::Bytes(bytes);
+            for chan in low {
+                let _ = chan.send(message.clone());
+            }
+        }
+    }
+
+    /// Return the bytes for the messages in a [`FeedMessageSerializer`] that are at least
+    /// the priority given and, if node IDs are given, are about one of those nodes (or about
+    /// the chain as a whole). Messages left out due to their priority are counted as dropped,
+    /// and how many were is handed back too.
+    fn finalized_for_feed(
+        &self,
+        serializer: &FeedMessageSerializer,
+        lowest_priority: Priority,
+        node_ids: Option<&HashSet<usize>>,
+    ) -> (Option<bytes::Bytes>, u64) {
+        let mut dropped = 0;
+        let bytes = serializer.finalized_where(|action, node_id| {
+            if let (Some(node_ids), Some(node_id)) = (node_ids, node_id) {
+                if !node_ids.contains(&node_id) {
+                    return false;
+                }
+            }
+            if self.feed_priorities.priority(action) < lowest_priority {
+                dropped += 1;
+                return false;
+            }
+            true
+        });
+        self.dropped_messages_to_feeds
+            .set(self.dropped_messages_to_feeds.get() + dropped);
+        (bytes, dropped)
+    }
+
+    /// Make a note of a node that's been added to a chain, for any feeds subscribed to
+    /// the chain that only want to hear about nodes like it.
+    fn add_node_to_feed_filters(
+        &mut self,
+        genesis_hash: &BlockHash,
+        node_id: usize,
+        node_details: &NodeDetails,
+    ) {
+        if self.feed_node_filters.is_empty() {
+            return;
+        }
+        if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
+            for feed_id in feeds {
+                if let Some(filter) = self.feed_node_filters.get_mut(feed_id) {
+                    if filter.filter.matches_node(node_details) {
+                        filter.node_ids.insert(node_id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forget about nodes that have been removed from a chain, so that feeds which
+    /// filter by node aren't told about whichever new nodes are given the same IDs.
+    fn remove_nodes_from_feed_filters(
+        &mut self,
+        genesis_hash: &BlockHash,
+        node_ids: impl Iterator<Item = usize>,
+    ) {
+        if self.feed_node_filters.is_empty() {
+            return;
+        }
+        if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
+            for node_id in node_ids {
+                for feed_id in feeds {
+                    if let Some(filter) = self.feed_node_filters.get_mut(feed_id) {
+                        filter.node_ids.remove(&node_id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finalize a [`FeedMessageSerializer`] and broadcast the result to all feeds
+    fn finalize_and_broadcast_to_all_feeds(&mut self, serializer: FeedMessageSerializer) {
+        if let Some(bytes) = serializer.into_finalized() {
+            self.broadcast_to_all_feeds(ToFeedWebsocket::Bytes(bytes));
+        }
+    }
+
+    /// Send a message to everybody.
+    fn broadcast_to_all_feeds(&mut self, message: ToFeedWebsocket) {
+        for chan in self.feed_channels.values_mut() {
+            let _ = chan.send(message.clone());
+        }
+    }
+
+    /// Send the current implementation/version distribution of a chain to any feeds
+    /// subscribed to the chain that have asked for it.
+    fn broadcast_distribution_to_chain_feeds(&mut self, genesis_hash: &BlockHash) {
+        let feeds = match self.chain_to_feed_conn_ids.get_values(genesis_hash) {
+            Some(feeds) => feeds,
+            None => return,
+        };
+        let mut feeds = feeds
+            .intersection(&self.feed_conn_id_distribution)
+            .peekable();
+        if feeds.peek().is_none() {
+            return;
+        }
+        let chain = match self.node_state.get_chain_by_genesis_hash(genesis_hash) {
+            Some(chain) => chain,
+            None => return,
+        };
+
+        let mut feed_serializer = FeedMessageSerializer::new();
+        feed_serializer.push(feed_message::ChainDistribution(chain.distribution()));
+        self.finalize_and_send_to_feeds(feeds.copied(), feed_serializer, Priority::Low);
+    }
+
+    /// Finalize a [`FeedMessageSerializer`] and broadcast the result to chain finality feeds
+    fn finalize_and_broadcast_to_chain_finality_feeds(
+        &mut self,
+        genesis_hash: &BlockHash,
+        serializer: FeedMessageSerializer,
+    ) {
+        let lowest_priority = self.chain_lowest_priority_sent(genesis_hash, &serializer);
+        if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
+            // Get all feeds for the chain, but only broadcast to those feeds that
+            // are also subscribed to receive finality updates.
+            let feeds = feeds.union(&self.feed_conn_id_finality).copied();
+            self.finalize_and_send_to_feeds(feeds, serializer, lowest_priority);
+        }
+    }
+}
+
+/// A feed that only wants to hear about the node(s) matching some filter.
+struct FeedNodeFilter {
+    filter: NodeFilter,
+    /// The feed IDs of the matching nodes on the chain that the feed is subscribed to.
+    node_ids: HashSet<usize>,
+}
+
+/// Would feeds see any difference between nodes with these details? The startup time
+/// isn't compared, since it changes every time a node restarts. Every other field is
+/// named here, so that new ones can't be left out of the comparison by accident.
+fn same_node_details(a: &NodeDetails, b: &NodeDetails) -> bool {
+    let NodeDetails {
+        chain,
+        name,
+        implementation,
+        version,
+        validator,
+        network_id,
+        startup_time: _,
+        chain_type,
+        environment,
+        pruning_mode,
+        offchain_indexing,
+        pending_upgrade_block,
+        verbosity,
+        publicly_reachable,
+        message_schema,
+        extra_info,
+    } = a;
+    *chain == b.chain
+        && *name == b.name
+        && *implementation == b.implementation
+        && *version == b.version
+        && *validator == b.validator
+        && *network_id == b.network_id
+        && *chain_type == b.chain_type
+        && *environment == b.environment
+        && *pruning_mode == b.pruning_mode
+        && *offchain_indexing == b.offchain_indexing
+        && *pending_upgrade_block == b.pending_upgrade_block
+        && *verbosity == b.verbosity
+        && *publicly_reachable == b.publicly_reachable
+        && *message_schema == b.message_schema
+        && *extra_info == b.extra_info
+}
+
+/// Log a warning if handling a message took longer than the threshold given.
+/// Returns true if a warning was logged.
+fn warn_if_slow(
+    threshold: Option<Duration>,
+    elapsed: Duration,
+    node_id: Option<NodeId>,
+    message_type: &str,
+) -> bool {
+    match threshold {
+        Some(threshold) if elapsed > threshold => {
+            log::warn!(
+                "Slow message: handling '{}' for node {:?} took {}ms (threshold is {}ms)",
+                message_type,
+                node_id,
+                elapsed.as_millis(),
+                threshold.as_millis()
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use once_cell::sync::Lazy;
+    use std::sync::Mutex;
+    use test_utils::recorded_spans::RecordingSubscriber;
+
+    /// A logger which stores warnings so that we can check what was logged.
+    struct CapturingLogger;
+
+    static WARNINGS: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                WARNINGS.lock().unwrap().push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    fn capture_warnings() {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        // This errors if a logger is already set, which is fine:
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Warn);
+    }
+
+    fn inner_loop(metrics_chain_allowlist: Vec<String>) -> InnerLoop {
+        let (tx_to_locator, _) = flume::unbounded();
+        InnerLoop::new(
+            tx_to_locator,
+            find_location::CacheStats::default(),
+            AggregatorOpts {
+                denylist: Vec::new(),
+                max_queue_len: 1000,
+                slow_message_threshold: None,
+                chain_opts: state::ChainOpts::default(),
+                metrics_chain_allowlist,
+                feed_priorities: FeedPriorities::default(),
+                feed_budgets: FeedBudgets::default(),
+                reconnect_debounce: None,
+                shard_restart_grace: None,
+                anonymizers: Default::default(),
+                anonymize_locations: None,
+                ingest_latency_warning: None,
+                max_chains: None,
+                feed_heartbeat_interval: None,
+                name_redactor: Default::default(),
+            },
+        )
+    }
+
+    fn node(chain: &str) -> common::node_types::NodeDetails {
+        common::node_types::NodeDetails {
+            chain: chain.into(),
+            name: "Alice".into(),
+            implementation: "Substrate Node".into(),
+            version: "0.1".into(),
+            validator: None,
+            network_id: None,
+            startup_time: None,
+            chain_type: None,
+            environment: None,
+            pruning_mode: None,
+            offchain_indexing: false,
+            pending_upgrade_block: None,
+            verbosity: None,
+            publicly_reachable: None,
+            message_schema: None,
+            extra_info: Default::default(),
+        }
+    }
+
+    fn chain_height_labels(inner: &InnerLoop) -> Vec<String> {
+        let mut labels: Vec<_> = inner
+            .chain_heights()
+            .into_iter()
+            .map(|c| c.label.to_string())
+            .collect();
+        labels.sort();
+        labels
+    }
+
+    /// The genesis hash of one of the default first party chains.
+    fn first_party_genesis_hash(idx: usize) -> BlockHash {
+        state::DEFAULT_FIRST_PARTY_CHAINS[idx].parse().unwrap()
+    }
+
+    #[test]
+    fn chain_heights_only_reported_for_first_party_and_allowlisted_chains() {
+        let mut inner = inner_loop(vec!["Allowed Testnet".to_string()]);
+        inner
+            .node_state
+            .add_node(first_party_genesis_hash(0), node("Polkadot"));
+        // Chains are first party because of their genesis hash, not their label:
+        inner
+            .node_state
+            .add_node(BlockHash::from_low_u64_be(1), node("Kusama"));
+        for (n, chain) in ["Allowed Testnet", "Other Testnet"].iter().enumerate() {
+            inner
+                .node_state
+                .add_node(BlockHash::from_low_u64_be(n as u64 + 2), node(chain));
+        }
+
+        assert_eq!(
+            chain_height_labels(&inner),
+            vec!["Allowed Testnet".to_string(), "Polkadot".to_string()]
+        );
+
+        let polkadot = inner
+            .chain_heights()
+            .into_iter()
+            .find(|c| &*c.label == "Polkadot")
+            .unwrap();
+        assert_eq!(polkadot.node_count, 1);
+        assert_eq!(polkadot.best_height, 0);
+        assert_eq!(polkadot.best_block_changed_at, None);
+    }
+
+    #[test]
+    fn chain_heights_no_longer_reported_once_chain_removed() {
+        let mut inner = inner_loop(Vec::new());
+        let node_id = inner
+            .node_state
+            .add_node(first_party_genesis_hash(1), node("Kusama"))
+            .unwrap_id();
+        assert_eq!(chain_height_labels(&inner), vec!["Kusama".to_string()]);
+
+        // Removing the last node removes the chain, and so its metrics:
+        inner.node_state.remove_node(node_id);
+        assert!(inner.chain_heights().is_empty());
+    }
+
+    #[test]
+    fn node_info_describes_a_single_node() {
+        let mut inner = inner_loop(Vec::new());
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let node_id = inner
+            .node_state
+            .add_node(
+                genesis_hash,
+                common::node_types::NodeDetails {
+                    validator: Some("5Foo".into()),
+                    ..node("Local Testnet")
+                },
+            )
+            .unwrap_id();
+        let block = Block {
+            hash: BlockHash::from_low_u64_be(10),
+            height: 10,
+        };
+        let mut feed = FeedMessageSerializer::new();
+        inner.node_state.update_node(
+            node_id,
+            node_message::Payload::BlockImport(block),
+            None,
+            &mut feed,
+        );
+        inner.node_state.update_node_location(
+            node_id,
+            Some(Arc::new(common::node_types::NodeLocation {
+                latitude: 52.5,
+                longitude: 13.4,
+                city: "Berlin".into(),
+                country: Some("DE".into()),
+            })),
+        );
+
+        let id = node_id.get_chain_node_id().into();
+        let info = inner
+            .node_info(genesis_hash, id)
+            .expect("node should exist");
+        let json = serde_json::to_value(&info).unwrap();
+
+        assert_eq!(json["id"], id);
+        assert_eq!(json["details"]["name"], "Alice");
+        assert_eq!(json["details"]["chain"], "Local Testnet");
+        assert_eq!(json["details"]["validator"], "5Foo");
+        assert_eq!(json["stats"]["peers"], 0);
+        assert_eq!(json["hardware"]["upload"], serde_json::json!([]));
+        assert_eq!(json["location"]["city"], "Berlin");
+        assert_eq!(json["best_block"]["height"], 10);
+        assert_eq!(
+            json["best_block"]["hash"],
+            serde_json::to_value(block.hash).unwrap()
+        );
+        assert_eq!(json["finalized_block"]["height"], 0);
+        assert_eq!(json["stale"], false);
+        assert_eq!(json["verbosity"], 0);
+        assert_eq!(json["verbosity_inferred"], true);
+    }
+
+    #[test]
+    fn node_info_not_found_for_unknown_node_or_chain() {
+        let mut inner = inner_loop(Vec::new());
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let node_id = inner
+            .node_state
+            .add_node(genesis_hash, node("Local Testnet"))
+            .unwrap_id();
+        let id: usize = node_id.get_chain_node_id().into();
+
+        assert!(inner.node_info(genesis_hash, id + 1).is_none());
+        assert!(inner.node_info(BlockHash::from_low_u64_be(2), id).is_none());
+
+        // Nodes are forgotten once they're removed:
+        inner.node_state.remove_node(node_id);
+        assert!(inner.node_info(genesis_hash, id).is_none());
+    }
+
+    #[test]
+    fn chain_nodes_lists_nodes_matching_the_filter() {
+        let mut inner = inner_loop(Vec::new());
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        for (name, offchain_indexing) in [("Alice", true), ("Bob", false), ("Charlie", true)] {
+            inner.node_state.add_node(
+                genesis_hash,
+                common::node_types::NodeDetails {
+                    name: name.into(),
+                    offchain_indexing,
+                    ..node("Local Testnet")
+                },
+            );
+        }
+        let names = |filter: Option<NodeFilter>| -> Vec<String> {
+            inner
+                .chain_nodes(genesis_hash, filter.as_ref())
+                .expect("chain should exist")
+                .into_iter()
+                .map(|info| info.details.name.to_string())
+                .collect()
+        };
+        let offchain_indexing = |wanted| NodeFilter {
+            offchain_indexing: Some(wanted),
+            ..NodeFilter::default()
+        };
+
+        assert_eq!(names(None), vec!["Alice", "Bob", "Charlie"]);
+        assert_eq!(
+            names(Some(offchain_indexing(true))),
+            vec!["Alice", "Charlie"]
+        );
+        assert_eq!(names(Some(offchain_indexing(false))), vec!["Bob"]);
+        assert!(inner
+            .chain_nodes(BlockHash::from_low_u64_be(2), None)
+            .is_none());
+    }
+
+    #[test]
+    fn node_blocks_lists_new_best_blocks_announced() {
+        let mut inner = inner_loop(Vec::new());
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let alice = inner
+            .node_state
+            .add_node(genesis_hash, node("Local Testnet"))
+            .unwrap_id();
+        let bob = inner
+            .node_state
+            .add_node(genesis_hash, node("Local Testnet"))
+            .unwrap_id();
+
+        let announce = |inner: &mut InnerLoop, node_id, height, reported_at| {
+            let block = Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            };
+            inner.node_state.update_node(
+                node_id,
+                node_message::Payload::BlockImport(block),
+                reported_at,
+                &mut FeedMessageSerializer::new(),
+            );
+        };
+        announce(&mut inner, alice, 10, Some(1_000));
+        announce(&mut inner, bob, 10, None);
+        // Not a new best block, so not recorded:
+        announce(&mut inner, alice, 9, Some(2_000));
+        announce(&mut inner, alice, 11, Some(3_000));
+
+        let id: usize = alice.get_chain_node_id().into();
+        let blocks = inner
+            .node_blocks(genesis_hash, id)
+            .expect("node should exist");
+        let json = serde_json::to_value(&blocks).unwrap();
+
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(json[0]["height"], 10);
+        assert_eq!(json[0]["reported_at"], 1_000);
+        assert_eq!(json[0]["propagation_time"], 0);
+        assert_eq!(json[1]["height"], 11);
+        assert_eq!(json[1]["reported_at"], 3_000);
+        assert!(blocks[1].received_at >= blocks[0].received_at);
+
+        // Bob was second to announce block 10, and didn't say when it was sent:
+        let bob_id: usize = bob.get_chain_node_id().into();
+        let json = serde_json::to_value(inner.node_blocks(genesis_hash, bob_id)).unwrap();
+        assert_eq!(json[0]["height"], 10);
+        assert_eq!(json[0]["reported_at"], serde_json::Value::Null);
+        assert!(json[0]["propagation_time"].is_u64());
+
+        // The blocks are forgotten along with the node:
+        inner.node_state.remove_node(alice);
+        assert!(inner.node_blocks(genesis_hash, id).is_none());
+    }
+
+    /// The actions of the feed messages in a batch sent to a feed.
+    fn received_actions(rx: &flume::Receiver<ToFeedWebsocket>) -> Vec<u64> {
+        let ToFeedWebsocket::Bytes(bytes) = rx.try_recv().expect("feed should be sent messages");
+        let values: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+        values
+            .chunks(2)
+            .map(|msg| msg[0].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn quiet_feeds_are_sent_heartbeats() {
+        use test_utils::feed_message_de::FeedMessage;
+        let heartbeats = |feed: &flume::Receiver<ToFeedWebsocket>| {
+            feed.drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| FeedMessage::from_bytes(&bytes).unwrap())
+                .filter_map(|msg| match msg {
+                    FeedMessage::Heartbeat { timestamp } => Some(timestamp),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut inner = inner_loop(Vec::new());
+        inner.feed_heartbeat_interval_ms = Some(10_000);
+        add_shard_node(&mut inner, 0, genesis_hash, node("Chain"));
+        let feed = subscribed_feed(&mut inner, ConnId::new(1), "Chain");
+        let start = time::now();
+
+        inner.send_feed_heartbeats(start + 5_000);
+        assert!(heartbeats(&feed).is_empty());
+        inner.send_feed_heartbeats(start + 10_000);
+        assert_eq!(heartbeats(&feed), vec![start + 10_000]);
+
+        // Heartbeats are only sent as often as the interval, too:
+        inner.send_feed_heartbeats(start + 15_000);
+        assert!(heartbeats(&feed).is_empty());
+        inner.send_feed_heartbeats(start + 20_000);
+        assert_eq!(heartbeats(&feed), vec![start + 20_000]);
+
+        // A feed that's been quiet for a while doesn't need a heartbeat if it's just been
+        // sent something else:
+        let now = time::now();
+        inner.feed_channels[&ConnId::new(1)]
+            .last_sent_at
+            .set(now - 10_000);
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Update {
+                local_id: ShardNodeId::new(0),
+                payload: node_message::Payload::BlockImport(Block {
+                    hash: BlockHash::from_low_u64_be(2),
+                    height: 2,
+                }),
+                reported_at: None,
+                ingest: IngestTimes::received_now(time::now()),
+            },
+        );
+        assert!(!feed.is_empty());
+        inner.send_feed_heartbeats(now);
+        assert!(heartbeats(&feed).is_empty());
+
+        // No heartbeats are sent at all unless asked for:
+        inner.feed_heartbeat_interval_ms = None;
+        inner.send_feed_heartbeats(now + 60_000);
+        assert!(heartbeats(&feed).is_empty());
+    }
+
+    #[test]
+    fn feeds_are_told_how_many_feeds_are_subscribed_to_their_chain() {
+        use test_utils::feed_message_de::FeedMessage;
+        let subscriber_counts = |feed: &flume::Receiver<ToFeedWebsocket>| {
+            feed.drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| FeedMessage::from_bytes(&bytes).unwrap())
+                .filter_map(|msg| match msg {
+                    FeedMessage::SubscriberCount { chain, count } => {
+                        assert_eq!(chain, "Chain");
+                        Some(count)
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut inner = inner_loop(Vec::new());
+        add_shard_node(&mut inner, 0, genesis_hash, node("Chain"));
+
+        // Each feed is told how many feeds there are when it subscribes:
+        let (tx, first_feed) = flume::unbounded();
+        inner.handle_from_feed(
+            ConnId::new(1),
+            FromFeedWebsocket::Initialize {
+                channel: tx,
+                node_filter: None,
+            },
+        );
+        inner.handle_from_feed(
+            ConnId::new(1),
+            FromFeedWebsocket::Subscribe {
+                chain: "Chain".into(),
+            },
+        );
+        assert_eq!(subscriber_counts(&first_feed), vec![1]);
+
+        // Every new feed is news until there are 20, after which one more is less than 5%:
+        let _feeds: Vec<_> = (2..=21)
+            .map(|id| subscribed_feed(&mut inner, ConnId::new(id), "Chain"))
+            .collect();
+        assert_eq!(subscriber_counts(&first_feed), (2..=20).collect::<Vec<_>>());
+        assert_eq!(inner.subscriber_count_for_chain("Chain"), 21);
+        assert_eq!(inner.subscriber_count_for_chain("Other Chain"), 0);
+
+        // ...until enough time has passed:
+        inner.send_subscriber_counts(time::now() + SUBSCRIBER_COUNT_INTERVAL_MS);
+        assert_eq!(subscriber_counts(&first_feed), vec![21]);
+        inner.send_subscriber_counts(time::now() + 2 * SUBSCRIBER_COUNT_INTERVAL_MS);
+        assert!(subscriber_counts(&first_feed).is_empty());
+
+        // Feeds going away are counted in the same way:
+        for id in (2..=21).rev() {
+            inner.handle_from_feed(ConnId::new(id), FromFeedWebsocket::Disconnected);
+        }
+        assert_eq!(
+            subscriber_counts(&first_feed),
+            (1..=19).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(inner.subscriber_count_for_chain("Chain"), 1);
+
+        // Once the last feed has gone, there's nothing to tell anyone:
+        inner.handle_from_feed(ConnId::new(1), FromFeedWebsocket::Disconnected);
+        assert!(inner.subscriber_counts_sent.is_empty());
+    }
+
+    #[test]
+    fn feeds_are_told_when_upgrades_are_scheduled_and_executed() {
+        use test_utils::feed_message_de::FeedMessage;
+        let upgrade_messages = |feed: &flume::Receiver<ToFeedWebsocket>| {
+            feed.drain()
+                .flat_map(|ToFeedWebsocket::Bytes(bytes)| FeedMessage::from_bytes(&bytes).unwrap())
+                .filter(|msg| {
+                    matches!(
+                        msg,
+                        FeedMessage::UpgradeScheduled { .. } | FeedMessage::UpgradeExecuted { .. }
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let import_block = |inner: &mut InnerLoop, height| {
+            inner.handle_from_shard(
+                ConnId::new(100),
+                FromShardWebsocket::Update {
+                    local_id: ShardNodeId::new(0),
+                    payload: node_message::Payload::BlockImport(Block {
+                        hash: BlockHash::from_low_u64_be(height),
+                        height,
+                    }),
+                    reported_at: None,
+                    ingest: IngestTimes::received_now(time::now()),
+                },
+            );
+        };
+
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut inner = inner_loop(Vec::new());
+        add_shard_node(&mut inner, 0, genesis_hash, node("Chain"));
+        import_block(&mut inner, 1);
+        let feed = subscribed_feed(&mut inner, ConnId::new(1), "Chain");
+
+        let upgrading = NodeDetails {
+            pending_upgrade_block: Some(3),
+            verbosity: None,
+            publicly_reachable: None,
+            ..node("Chain")
+        };
+        add_shard_node(&mut inner, 1, genesis_hash, upgrading.clone());
+        // Only the first node to tell us about an upgrade is news:
+        add_shard_node(&mut inner, 2, genesis_hash, upgrading.clone());
+        assert_eq!(
+            upgrade_messages(&feed),
+            vec![FeedMessage::UpgradeScheduled {
+                chain: "Chain".to_string(),
+                at_block: 3,
+                current_block: 1,
+            }]
+        );
+
+        // Feeds subscribing later are told about it too:
+        let (tx, late_feed) = flume::unbounded();
+        inner.handle_from_feed(
+            ConnId::new(2),
+            FromFeedWebsocket::Initialize {
+                channel: tx,
+                node_filter: None,
+            },
+        );
+        inner.handle_from_feed(
+            ConnId::new(2),
+            FromFeedWebsocket::Subscribe {
+                chain: "Chain".into(),
+            },
+        );
+        assert_eq!(
+            upgrade_messages(&late_feed),
+            vec![FeedMessage::UpgradeScheduled {
+                chain: "Chain".to_string(),
+                at_block: 3,
+                current_block: 1,
+            }]
+        );
+
+        import_block(&mut inner, 2);
+        assert!(upgrade_messages(&feed).is_empty());
+        import_block(&mut inner, 3);
+        assert_eq!(
+            upgrade_messages(&feed),
+            vec![FeedMessage::UpgradeExecuted {
+                chain: "Chain".to_string(),
+                at_block: 3,
+            }]
+        );
+
+        // Once executed, the upgrade is forgotten, and the block it was at is old news:
+        import_block(&mut inner, 4);
+        add_shard_node(&mut inner, 3, genesis_hash, upgrading);
+        assert!(upgrade_messages(&feed).is_empty());
+    }
+
+    #[test]
+    fn feeds_are_told_about_shutdown_and_then_closed() {
+        use feed_message::FeedMessage;
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut inner = inner_loop(Vec::new());
+        add_shard_node(&mut inner, 0, genesis_hash, node("Chain"));
+        let feed = subscribed_feed(&mut inner, ConnId::new(1), "Chain");
+
+        let (tx, rx) = flume::unbounded();
+        inner.handle_shutdown(Some(30), tx);
+        rx.try_recv().expect("shutdown should be acknowledged");
+
+        let ToFeedWebsocket::Bytes(bytes) = feed.try_recv().unwrap();
+        let values: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            values,
+            vec![
+                serde_json::json!(feed_message::ServerShutdown::ACTION),
+                serde_json::json!(30)
+            ]
+        );
+        // Nothing else is sent, and the feed's connection will close:
+        assert!(matches!(
+            feed.try_recv(),
+            Err(flume::TryRecvError::Disconnected)
+        ));
+
+        // New feeds aren't accepted either:
+        let (tx, new_feed) = flume::unbounded();
+        inner.handle_from_feed(
+            ConnId::new(2),
+            FromFeedWebsocket::Initialize {
+                channel: tx,
+                node_filter: None,
+            },
+        );
+        assert!(matches!(
+            new_feed.try_recv(),
+            Err(flume::TryRecvError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn chains_over_their_feed_budget_only_send_high_priority_messages() {
+        use feed_message::FeedMessage;
+        let busy = BlockHash::from_low_u64_be(1);
+        let quiet = BlockHash::from_low_u64_be(2);
+        let mut inner = inner_loop(Vec::new());
+        inner.feed_budgets = FeedBudgets::new(None, &[format!("{:?}=10", busy).parse().unwrap()]);
+        add_shard_node(&mut inner, 0, busy, node("Busy"));
+        add_shard_node(&mut inner, 1, quiet, node("Quiet"));
+        let busy_feed = subscribed_feed(&mut inner, ConnId::new(1), "Busy");
+        let quiet_feed = subscribed_feed(&mut inner, ConnId::new(2), "Quiet");
+
+        let stats = common::node_types::NodeStats::default();
+        let low = feed_message::NodeStatsUpdate::ACTION as u64;
+        let high = feed_message::BestFinalized::ACTION as u64;
+        for _ in 0..50 {
+            for genesis_hash in &[busy, quiet] {
+                let mut serializer = FeedMessageSerializer::new();
+                serializer.push(feed_message::NodeStatsUpdate(0, &stats, &[]));
+                serializer.push(feed_message::BestFinalized(1, BlockHash::zero()));
+                inner.finalize_and_broadcast_to_chain_feeds(genesis_hash, serializer);
+            }
+        }
+
+        let counts = |feed: &flume::Receiver<ToFeedWebsocket>| {
+            let actions: Vec<u64> =
+                std::iter::from_fn(|| (!feed.is_empty()).then(|| received_actions(feed)))
+                    .flatten()
+                    .collect();
+            let count = |action| actions.iter().filter(|&&a| a == action).count();
+            (count(low), count(high))
+        };
+
+        // Only a budget's worth of stats updates for the busy chain got through (or
+        // perhaps two, if a new second started part way through), but nothing about
+        // blocks was lost, and the quiet chain wasn't affected at all:
+        let (busy_low, busy_high) = counts(&busy_feed);
+        assert!(busy_low <= 10, "{} low priority messages sent", busy_low);
+        assert_eq!(busy_high, 50);
+        assert_eq!(counts(&quiet_feed), (50, 50));
+        assert!(inner.dropped_messages_to_feeds.get() >= 40);
+    }
+
+    #[test]
+    fn low_priority_feed_messages_dropped_first_as_feed_queue_fills() {
+        let mut inner = inner_loop(Vec::new());
+        inner.feed_priorities = FeedPriorities::new(4, &[]);
+
+        let feed_id = ConnId::new(1);
+        let (tx, rx) = flume::unbounded();
+        inner
+            .feed_channels
+            .insert(feed_id, FeedChannel::new(tx.clone()));
+
+        let stats = common::node_types::NodeStats::default();
+        let send_batch = |inner: &InnerLoop| {
+            let mut serializer = FeedMessageSerializer::new();
+            serializer.push(feed_message::NodeStatsUpdate(0, &stats, &[]));
+            serializer.push(feed_message::StaleNode(0));
+            serializer.push(feed_message::BestFinalized(1, BlockHash::zero()));
+            inner.finalize_and_send_to_feeds(std::iter::once(feed_id), serializer, Priority::Low);
+        };
+        use feed_message::FeedMessage;
+        let low = feed_message::NodeStatsUpdate::ACTION as u64;
+        let normal = feed_message::StaleNode::ACTION as u64;
+        let high = feed_message::BestFinalized::ACTION as u64;
+
+        // Nothing queued up, so everything is sent:
+        send_batch(&inner);
+        assert_eq!(received_actions(&rx), vec![low, normal, high]);
+        assert_eq!(inner.dropped_messages_to_feeds.get(), 0);
+
+        // Queue half full; low priority messages are dropped:
+        for _ in 0..2 {
+            tx.send(ToFeedWebsocket::Bytes(Default::default())).unwrap();
+        }
+        send_batch(&inner);
+        for _ in 0..2 {
+            rx.try_recv().unwrap();
+        }
+        assert_eq!(received_actions(&rx), vec![normal, high]);
+        assert_eq!(inner.dropped_messages_to_feeds.get(), 1);
+
+        // Queue full; only high priority messages are sent:
+        for _ in 0..4 {
+            tx.send(ToFeedWebsocket::Bytes(Default::default())).unwrap();
+        }
+        send_batch(&inner);
+        for _ in 0..4 {
+            rx.try_recv().unwrap();
+        }
+        assert_eq!(received_actions(&rx), vec![high]);
+        assert_eq!(inner.dropped_messages_to_feeds.get(), 3);
+    }
+
+    #[test]
+    fn feed_stats_count_what_each_feed_is_sent_and_dropped() {
+        let mut inner = inner_loop(Vec::new());
+        inner.feed_priorities = FeedPriorities::new(4, &[]);
+        let gather = |inner: &mut InnerLoop| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_gather_feed_stats(tx);
+            rx.try_recv().unwrap()
+        };
+        let received_bytes = |rx: &flume::Receiver<ToFeedWebsocket>| {
+            rx.drain()
+                .map(|ToFeedWebsocket::Bytes(bytes)| bytes.len() as u64)
+                .sum::<u64>()
+        };
+
+        // One feed reads everything that it's sent, and the other reads nothing:
+        let (slow_id, fast_id) = (ConnId::new(1), ConnId::new(2));
+        let (tx, _slow_rx) = flume::unbounded();
+        inner.handle_from_feed(
+            slow_id,
+            FromFeedWebsocket::Initialize {
+                channel: tx,
+                node_filter: None,
+            },
+        );
+        let (tx, fast_rx) = flume::unbounded();
+        inner.handle_from_feed(
+            fast_id,
+            FromFeedWebsocket::Initialize {
+                channel: tx,
+                node_filter: None,
+            },
+        );
+        let mut fast_bytes = received_bytes(&fast_rx);
+
+        let stats = gather(&mut inner);
+        assert_eq!(
+            stats[&2],
+            FeedStats {
+                messages_sent: 1,
+                messages_dropped: 0,
+                bytes_sent: fast_bytes,
+                queued: 0,
+            }
+        );
+        assert_eq!(stats[&1].queued, 1);
+
+        let node_stats = common::node_types::NodeStats::default();
+        for _ in 0..5 {
+            let mut serializer = FeedMessageSerializer::new();
+            serializer.push(feed_message::NodeStatsUpdate(0, &node_stats, &[]));
+            serializer.push(feed_message::BestFinalized(1, BlockHash::zero()));
+            inner.finalize_and_send_to_feeds([slow_id, fast_id], serializer, Priority::Low);
+            fast_bytes += received_bytes(&fast_rx);
+        }
+
+        // The slow feed stopped being sent low priority messages once its queue was half
+        // full, which it was from the second batch on:
+        let stats = gather(&mut inner);
+        assert_eq!(stats[&1].messages_sent, 6);
+        assert_eq!(stats[&1].messages_dropped, 4);
+        assert_eq!(stats[&1].queued, 6);
+        assert_eq!(
+            stats[&2],
+            FeedStats {
+                messages_sent: 6,
+                messages_dropped: 0,
+                bytes_sent: fast_bytes,
+                queued: 0,
+            }
+        );
+
+        // Feeds are forgotten about once they disconnect:
+        inner.handle_from_feed(slow_id, FromFeedWebsocket::Disconnected);
+        assert_eq!(gather(&mut inner).keys().collect::<Vec<_>>(), vec![&2]);
+    }
+
+    /// Subscribe a new feed to the chain given, returning the batches of
+    /// node details that it's sent.
+    fn subscribe_feed(inner: &mut InnerLoop, feed_id: ConnId, chain: &str) -> Vec<bytes::Bytes> {
+        let (tx, rx) = flume::unbounded();
+        inner.handle_from_feed(
+            feed_id,
+            FromFeedWebsocket::Initialize {
+                channel: tx,
+                node_filter: None,
+            },
+        );
+        inner.handle_from_feed(
+            feed_id,
+            FromFeedWebsocket::Subscribe {
+                chain: chain.into(),
+            },
+        );
+        let mut messages: Vec<_> = rx
+            .drain()
+            .map(|ToFeedWebsocket::Bytes(bytes)| bytes)
+            // Skip the version/chain list and the chain details that come first:
+            .skip(2)
+            .collect();
+        // ...and how many feeds are subscribed, which comes last:
+        messages.pop();
+        messages
+    }
+
+    /// Subscribe a new feed to the chain given, returning the channel that it's sent
+    /// messages on once it's been told about the current state of the chain.
+    fn subscribed_feed(
+        inner: &mut InnerLoop,
+        feed_id: ConnId,
+        chain: &str,
+    ) -> flume::Receiver<ToFeedWebsocket> {
+        let (tx, rx) = flume::unbounded();
+        inner.handle_from_feed(
+            feed_id,
+            FromFeedWebsocket::Initialize {
+                channel: tx,
+                node_filter: None,
+            },
+        );
+        inner.handle_from_feed(
+            feed_id,
+            FromFeedWebsocket::Subscribe {
+                chain: chain.into(),
+            },
+        );
+        rx.drain();
+        rx
+    }
+
+    fn add_shard_node(
+        inner: &mut InnerLoop,
+        local_id: usize,
+        genesis_hash: BlockHash,
+        node: common::node_types::NodeDetails,
+    ) {
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Add {
+                local_id: ShardNodeId::new(local_id),
+                ip: "127.0.0.1".parse().unwrap(),
+                node,
+                genesis_hash,
+                resumed: false,
+            },
+        );
+    }
+
+    fn remove_shard_node(inner: &mut InnerLoop, local_id: usize) {
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::new(local_id),
+                reason: NodeCloseReason::ClientClosed,
+            },
+        );
+    }
+
+    fn node_with_network_id(network_id: &str) -> common::node_types::NodeDetails {
+        common::node_types::NodeDetails {
+            network_id: Some(network_id.into()),
+            ..node("Local Testnet")
+        }
+    }
+
+    /// Set up a chain with two nodes, one of which keeps crashing, and a feed subscribed
+    /// to it. Reconnects within a minute of disconnecting are debounced, if asked.
+    fn crash_looping_chain(
+        debounce: bool,
+    ) -> (InnerLoop, BlockHash, flume::Receiver<ToFeedWebsocket>) {
+        let mut inner = inner_loop(Vec::new());
+        inner.reconnect_debounce_ms = debounce.then_some(60 * 1000);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_shard_node(&mut inner, 0, genesis_hash, node_with_network_id("stable"));
+        add_shard_node(&mut inner, 1, genesis_hash, node_with_network_id("crashy"));
+        let feed = subscribed_feed(&mut inner, ConnId::new(1), "Local Testnet");
+        (inner, genesis_hash, feed)
+    }
+
+    #[test]
+    fn rapid_reconnects_are_debounced() {
+        use feed_message::FeedMessage;
+        let (mut inner, genesis_hash, feed) = crash_looping_chain(true);
+        let crashy_id = inner
+            .node_ids
+            .get_by_right(&(ConnId::new(100), ShardNodeId::new(1)))
+            .copied();
+
+        for local_id in 2..12 {
+            remove_shard_node(&mut inner, local_id - 1);
+            add_shard_node(
+                &mut inner,
+                local_id,
+                genesis_hash,
+                node_with_network_id("crashy"),
+            );
+        }
+
+        // Feeds heard nothing, and the node kept its ID:
+        assert!(feed.is_empty());
+        let chain = inner
+            .node_state
+            .get_chain_by_genesis_hash(&genesis_hash)
+            .unwrap();
+        assert_eq!(chain.node_count(), 2);
+        assert_eq!(
+            inner
+                .node_ids
+                .get_by_right(&(ConnId::new(100), ShardNodeId::new(11)))
+                .copied(),
+            crashy_id
+        );
+
+        // Nodes which have reconnected aren't removed when the window passes:
+        inner.expire_disconnected_nodes(time::now() + 61 * 1000);
+        assert!(feed.is_empty());
+
+        // Once the node stays away for longer than the window, it's removed once:
+        remove_shard_node(&mut inner, 11);
+        assert!(feed.is_empty());
+        inner.expire_disconnected_nodes(time::now() + 61 * 1000);
+        assert_eq!(
+            received_actions(&feed),
+            vec![feed_message::RemovedNode::ACTION as u64]
+        );
+        assert_eq!(
+            received_actions(&feed),
+            vec![feed_message::AddedChain::ACTION as u64]
+        );
+        assert!(feed.is_empty());
+    }
+
+    #[test]
+    fn reconnects_with_changed_details_are_not_debounced() {
+        use feed_message::FeedMessage;
+        let changes = [
+            // A new version:
+            common::node_types::NodeDetails {
+                version: "0.2".into(),
+                ..node_with_network_id("crashy")
+            },
+            // Different chain specific fields, which feeds are sent untouched:
+            common::node_types::NodeDetails {
+                extra_info: [("para_id".into(), serde_json::json!(1000))].into(),
+                ..node_with_network_id("crashy")
+            },
+            // Being publicly reachable, which changes how many public nodes the chain has:
+            common::node_types::NodeDetails {
+                publicly_reachable: Some(true),
+                ..node_with_network_id("crashy")
+            },
+            // A different message schema, which feeds can filter nodes by:
+            common::node_types::NodeDetails {
+                message_schema: Some(common::node_types::MessageSchema::V2),
+                ..node_with_network_id("crashy")
+            },
+            // A different verbosity, which changes what the node is expected to send:
+            common::node_types::NodeDetails {
+                verbosity: Some(2),
+                ..node_with_network_id("crashy")
+            },
+        ];
+
+        for changed in changes {
+            let (mut inner, genesis_hash, feed) = crash_looping_chain(true);
+
+            remove_shard_node(&mut inner, 1);
+            assert!(feed.is_empty());
+            add_shard_node(&mut inner, 2, genesis_hash, changed);
+
+            // Feeds are told about the old node going away and the new one arriving:
+            assert_eq!(
+                received_actions(&feed),
+                vec![feed_message::RemovedNode::ACTION as u64]
+            );
+            assert_eq!(
+                received_actions(&feed),
+                vec![feed_message::AddedChain::ACTION as u64]
+            );
+            assert_eq!(
+                received_actions(&feed),
+                vec![feed_message::AddedNode::ACTION as u64]
+            );
+        }
+    }
+
+    #[test]
+    fn reconnects_that_bring_a_new_upgrade_tell_feeds_about_it() {
+        use test_utils::feed_message_de::FeedMessage;
+        let (mut inner, genesis_hash, feed) = crash_looping_chain(true);
+
+        // The node restarts because it's heard about an upgrade:
+        remove_shard_node(&mut inner, 1);
+        let upgrading = common::node_types::NodeDetails {
+            pending_upgrade_block: Some(10),
+            ..node_with_network_id("crashy")
+        };
+        add_shard_node(&mut inner, 2, genesis_hash, upgrading);
+
+        let upgrades: Vec<_> = feed
+            .drain()
+            .flat_map(|ToFeedWebsocket::Bytes(bytes)| FeedMessage::from_bytes(&bytes).unwrap())
+            .filter(|msg| matches!(msg, FeedMessage::UpgradeScheduled { .. }))
+            .collect();
+        assert_eq!(
+            upgrades,
+            vec![FeedMessage::UpgradeScheduled {
+                chain: "Local Testnet".to_string(),
+                at_block: 10,
+                current_block: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn reconnects_that_change_reachability_are_counted() {
+        let (mut inner, genesis_hash, _feed) = crash_looping_chain(true);
+        let public_nodes = |inner: &InnerLoop| {
+            let chain = inner
+                .node_state
+                .get_chain_by_genesis_hash(&genesis_hash)
+                .unwrap();
+            (
+                chain.public_nodes().public(),
+                chain.public_nodes().private(),
+            )
+        };
+
+        remove_shard_node(&mut inner, 1);
+        let reachable = |publicly_reachable| common::node_types::NodeDetails {
+            publicly_reachable: Some(publicly_reachable),
+            ..node_with_network_id("crashy")
+        };
+        add_shard_node(&mut inner, 2, genesis_hash, reachable(true));
+        assert_eq!(public_nodes(&inner), (1, 0));
+
+        remove_shard_node(&mut inner, 2);
+        add_shard_node(&mut inner, 3, genesis_hash, reachable(false));
+        assert_eq!(public_nodes(&inner), (0, 1));
+    }
+
+    #[test]
+    fn reconnects_not_debounced_unless_asked() {
+        use feed_message::FeedMessage;
+        let (mut inner, genesis_hash, feed) = crash_looping_chain(false);
+
+        remove_shard_node(&mut inner, 1);
+        assert_eq!(
+            received_actions(&feed),
+            vec![feed_message::RemovedNode::ACTION as u64]
+        );
+        received_actions(&feed);
+
+        add_shard_node(&mut inner, 2, genesis_hash, node_with_network_id("crashy"));
+        assert_eq!(
+            received_actions(&feed),
+            vec![feed_message::AddedNode::ACTION as u64]
+        );
+    }
+
+    /// Set up a chain with a node on shard 100 which has reported its upload bandwidth a
+    /// few times, another node which isn't on any shard (so that the chain sticks around),
+    /// and a feed subscribed to it. Nodes are held on to for a minute after their shard goes
+    /// away, if asked.
+    fn node_with_history(grace: bool) -> (InnerLoop, BlockHash, flume::Receiver<ToFeedWebsocket>) {
+        let mut inner = inner_loop(Vec::new());
+        inner.shard_restart_grace_ms = grace.then_some(60 * 1000);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        inner
+            .node_state
+            .add_node(genesis_hash, node("Local Testnet"));
+        add_shard_node(&mut inner, 0, genesis_hash, node_with_network_id("node"));
+        for upload in 1..=5 {
+            let interval = serde_json::from_value(serde_json::json!({
+                "bandwidth_upload": upload as f64 * 1000.0
+            }))
+            .unwrap();
+            inner.handle_from_shard(
+                ConnId::new(100),
+                FromShardWebsocket::Update {
+                    local_id: ShardNodeId::new(0),
+                    payload: node_message::Payload::SystemInterval(interval),
+                    reported_at: None,
+                    ingest: IngestTimes::received_now(time::now()),
+                },
+            );
+        }
+        let feed = subscribed_feed(&mut inner, ConnId::new(1), "Local Testnet");
+        (inner, genesis_hash, feed)
+    }
+
+    /// The restarted shard (connection 101) tells us about the node again.
+    fn add_node_to_restarted_shard(inner: &mut InnerLoop, genesis_hash: BlockHash, resumed: bool) {
+        inner.handle_from_shard(
+            ConnId::new(101),
+            FromShardWebsocket::Add {
+                local_id: ShardNodeId::new(0),
+                ip: "127.0.0.1".parse().unwrap(),
+                node: node_with_network_id("node"),
+                genesis_hash,
+                resumed,
+            },
+        );
+    }
+
+    fn upload_history(inner: &InnerLoop, genesis_hash: BlockHash, id: usize) -> serde_json::Value {
+        let info = inner
+            .node_info(genesis_hash, id)
+            .expect("node should exist");
+        serde_json::to_value(&info).unwrap()["hardware"]["upload"].clone()
+    }
+
+    #[test]
+    fn resumed_nodes_keep_their_history_when_their_shard_restarts() {
+        let (mut inner, genesis_hash, feed) = node_with_history(true);
+        let node_id = inner
+            .node_ids
+            .get_by_right(&(ConnId::new(100), ShardNodeId::new(0)))
+            .copied()
+            .unwrap();
+        let id = node_id.get_chain_node_id().into();
+        let history = upload_history(&inner, genesis_hash, id);
+        assert_eq!(history.as_array().unwrap().len(), 5);
+
+        inner.handle_from_shard(ConnId::new(100), FromShardWebsocket::Disconnected);
+        add_node_to_restarted_shard(&mut inner, genesis_hash, true);
+
+        // Feeds heard nothing, and the node carries on with the same ID and history:
+        assert!(feed.is_empty());
+        assert_eq!(
+            inner
+                .node_ids
+                .get_by_right(&(ConnId::new(101), ShardNodeId::new(0)))
+                .copied(),
+            Some(node_id)
+        );
+        assert_eq!(upload_history(&inner, genesis_hash, id), history);
+
+        // It isn't removed when the grace period has passed:
+        inner.expire_disconnected_nodes(time::now() + 61 * 1000);
+        assert!(feed.is_empty());
+    }
+
+    #[test]
+    fn nodes_from_a_restarted_shard_start_afresh_unless_resumed() {
+        use feed_message::FeedMessage;
+        let (mut inner, genesis_hash, feed) = node_with_history(true);
+
+        inner.handle_from_shard(ConnId::new(100), FromShardWebsocket::Disconnected);
+        assert!(feed.is_empty());
+        add_node_to_restarted_shard(&mut inner, genesis_hash, false);
+
+        assert_eq!(
+            received_actions(&feed),
+            vec![feed_message::RemovedNode::ACTION as u64]
+        );
+        assert_eq!(
+            received_actions(&feed),
+            vec![feed_message::AddedChain::ACTION as u64]
+        );
+        assert_eq!(
+            received_actions(&feed),
+            vec![feed_message::AddedNode::ACTION as u64]
+        );
+        let node_id = inner
+            .node_ids
+            .get_by_right(&(ConnId::new(101), ShardNodeId::new(0)))
+            .copied()
+            .unwrap();
+        let history = upload_history(&inner, genesis_hash, node_id.get_chain_node_id().into());
+        assert_eq!(history, serde_json::json!([]));
+    }
+
+    #[test]
+    fn nodes_removed_with_their_shard_unless_given_grace() {
+        use feed_message::FeedMessage;
+        let (mut inner, genesis_hash, feed) = node_with_history(false);
+
+        inner.handle_from_shard(ConnId::new(100), FromShardWebsocket::Disconnected);
+        assert_eq!(
+            received_actions(&feed),
+            vec![feed_message::RemovedNode::ACTION as u64]
+        );
+        received_actions(&feed);
+
+        add_node_to_restarted_shard(&mut inner, genesis_hash, true);
+        assert_eq!(
+            received_actions(&feed),
+            vec![feed_message::AddedNode::ACTION as u64]
+        );
+    }
+
+    #[test]
+    fn updates_for_unknown_nodes_ask_the_shard_to_resync_them() {
+        let mut inner = inner_loop(Vec::new());
+        let (tx, rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Initialize { channel: tx },
+        );
+        add_shard_node(&mut inner, 0, BlockHash::from_low_u64_be(1), node("Kusama"));
+
+        let update = |local_id| FromShardWebsocket::Update {
+            local_id: ShardNodeId::new(local_id),
+            payload: node_message::Payload::BlockImport(Block {
+                hash: BlockHash::from_low_u64_be(10),
+                height: 10,
+            }),
+            reported_at: None,
+            ingest: IngestTimes::received_now(time::now()),
+        };
+
+        // Known nodes are updated as usual:
+        inner.handle_from_shard(ConnId::new(100), update(0));
+        assert!(rx.is_empty());
+
+        // Unknown ones are handed back to the shard to resync:
+        inner.handle_from_shard(ConnId::new(100), update(1));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToShardWebsocket::Resync { local_id }) if local_id == ShardNodeId::new(1)
+        ));
+    }
+
+    #[test]
+    fn updates_for_muted_nodes_are_not_resynced() {
+        let mut inner = inner_loop(Vec::new());
+        inner.node_state = State::new(None, state::ChainOpts::default()).with_max_chains(Some(1));
+        let (tx, rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Initialize { channel: tx },
+        );
+        add_shard_node(&mut inner, 0, BlockHash::from_low_u64_be(1), node("Big"));
+
+        // No room for this node's chain, so it's muted:
+        add_shard_node(&mut inner, 1, BlockHash::from_low_u64_be(2), node("Spam"));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToShardWebsocket::Mute { local_id, .. }) if local_id == ShardNodeId::new(1)
+        ));
+
+        // Updates the shard sent before hearing about that are dropped:
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Update {
+                local_id: ShardNodeId::new(1),
+                payload: node_message::Payload::BlockImport(Block {
+                    hash: BlockHash::from_low_u64_be(10),
+                    height: 10,
+                }),
+                reported_at: None,
+                ingest: IngestTimes::received_now(time::now()),
+            },
+        );
+        assert!(rx.is_empty());
+
+        // Until the shard lets go of the node:
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::new(1),
+                reason: NodeCloseReason::ClientClosed,
+            },
+        );
+        assert!(inner.muted_shard_nodes.is_empty());
+    }
+
+    #[test]
+    fn chains_over_the_limit_are_turned_away_or_evicted() {
+        let mut inner = inner_loop(Vec::new());
+        inner.node_state = State::new(None, state::ChainOpts::default()).with_max_chains(Some(2));
+        let (tx, rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Initialize { channel: tx },
+        );
+        add_shard_node(&mut inner, 0, BlockHash::from_low_u64_be(1), node("Big"));
+        add_shard_node(&mut inner, 1, BlockHash::from_low_u64_be(1), node("Big"));
+        add_shard_node(&mut inner, 2, BlockHash::from_low_u64_be(2), node("Small"));
+        assert!(rx.is_empty());
+
+        // No room for another third party chain:
+        add_shard_node(&mut inner, 3, BlockHash::from_low_u64_be(3), node("Spam"));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToShardWebsocket::Mute { local_id, reason: MuteReason::TelemetryFull })
+                if local_id == ShardNodeId::new(3)
+        ));
+
+        // A first party chain evicts the smallest third party chain, whose nodes are closed:
+        let feed = subscribed_feed(&mut inner, ConnId::new(1), "Big");
+        add_shard_node(&mut inner, 4, first_party_genesis_hash(0), node("Polkadot"));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToShardWebsocket::Mute { local_id, reason: MuteReason::TelemetryFull })
+                if local_id == ShardNodeId::new(2)
+        ));
+        assert!(rx.is_empty());
+        assert!(inner
+            .node_state
+            .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(2))
+            .is_none());
+        assert!(inner
+            .node_state
+            .get_chain_by_genesis_hash(&first_party_genesis_hash(0))
+            .is_some());
+        assert!(!feed.is_empty());
+
+        assert_eq!(
+            inner.node_state.chain_capacity(),
+            ChainCapacity {
+                chains: 2,
+                max_chains: Some(2),
+                evicted_chains: 1,
+                rejected_nodes: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn denylist_changes_apply_to_new_and_optionally_existing_nodes() {
+        let mut inner = inner_loop(Vec::new());
+        let (tx, rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Initialize { channel: tx },
+        );
+        add_shard_node(&mut inner, 0, BlockHash::from_low_u64_be(1), node("Spam"));
+        add_shard_node(&mut inner, 1, BlockHash::from_low_u64_be(2), node("Eggs"));
+
+        // Only new nodes are turned away unless we ask for existing ones to be closed:
+        inner.handle_set_denylist(vec!["Spam".to_string()], false);
+        assert!(rx.is_empty());
+        add_shard_node(&mut inner, 2, BlockHash::from_low_u64_be(1), node("Spam"));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToShardWebsocket::Mute { local_id, reason: MuteReason::ChainNotAllowed })
+                if local_id == ShardNodeId::new(2)
+        ));
+        assert!(inner
+            .node_state
+            .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(1))
+            .is_some());
+
+        inner.handle_set_denylist(vec!["Spam".to_string(), "Eggs".to_string()], true);
+        let mut muted: Vec<_> = rx
+            .drain()
+            .map(|msg| match msg {
+                ToShardWebsocket::Mute {
+                    local_id,
+                    reason: MuteReason::ChainNotAllowed,
+                } => usize::from(local_id),
+                other => panic!("unexpected message to shard: {:?}", other),
+            })
+            .collect();
+        muted.sort_unstable();
+        assert_eq!(muted, vec![0, 1]);
+        assert_eq!(inner.node_state.iter_chains().count(), 0);
+
+        // Removing a chain from the denylist lets its nodes back in:
+        inner.handle_set_denylist(Vec::new(), true);
+        add_shard_node(&mut inner, 3, BlockHash::from_low_u64_be(1), node("Spam"));
+        assert!(rx.is_empty());
+        assert_eq!(inner.node_state.iter_chains().count(), 1);
+    }
+
+    #[test]
+    fn chain_mutes_are_sent_to_shards_and_expire() {
+        let mut inner = inner_loop(Vec::new());
+        let (tx, rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Initialize { channel: tx },
+        );
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_shard_node(&mut inner, 0, genesis_hash, node("Spam"));
+
+        let now = time::now();
+        let muted_until = inner.mute_chain(genesis_hash, Duration::from_secs(60), now);
+        assert_eq!(muted_until, now + 60_000);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ToShardWebsocket::MuteChain { genesis_hash: hash, ttl_ms: 60_000 })
+                if hash == genesis_hash
+        ));
+
+        // The chain list shows that the chain is muted:
+        let (chains_tx, chains_rx) = flume::unbounded();
+        inner.handle_gather_chains(chains_tx);
+        let chains = chains_rx.try_recv().unwrap().chains;
+        assert_eq!(chains[0].muted_until, Some(muted_until));
+
+        // Shards that connect later are told about the mute too:
+        let (tx2, rx2) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(101),
+            FromShardWebsocket::Initialize { channel: tx2 },
+        );
+        assert!(matches!(
+            rx2.try_recv(),
+            Ok(ToShardWebsocket::MuteChain { genesis_hash: hash, .. }) if hash == genesis_hash
+        ));
+
+        // Once the mute runs out, the chain no longer counts as muted:
+        assert_eq!(inner.chain_muted_until(&genesis_hash, muted_until), None);
+        assert!(!inner.unmute_chain(genesis_hash, muted_until));
+
+        // But it can be unmuted before then, and every shard is told:
+        inner.mute_chain(genesis_hash, Duration::from_secs(60), now);
+        rx.drain();
+        rx2.drain();
+        assert!(inner.unmute_chain(genesis_hash, now));
+        for rx in [&rx, &rx2] {
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(ToShardWebsocket::UnmuteChain { genesis_hash: hash }) if hash == genesis_hash
+            ));
+        }
+        assert_eq!(inner.chain_muted_until(&genesis_hash, now), None);
+    }
+
+    #[test]
+    fn nodes_sent_to_feeds_in_the_order_they_joined() {
+        let mut inner = inner_loop(Vec::new());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let first = inner
+            .node_state
+            .add_node(genesis, node("Kusama"))
+            .unwrap_id();
+        for _ in 0..2 {
+            inner.node_state.add_node(genesis, node("Kusama"));
+        }
+        // The next node to join takes the place of the one that's left:
+        inner.node_state.remove_node(first);
+        inner.node_state.add_node(genesis, node("Kusama"));
+
+        let chain = inner
+            .node_state
+            .get_chain_by_genesis_hash(&genesis)
+            .unwrap();
+        let order: Vec<usize> = chain
+            .nodes_in_join_order()
+            .into_iter()
+            .map(|(idx, _)| idx)
+            .collect();
+        assert_eq!(order, vec![1, 2, 0]);
+
+        // Two snapshots of the same state are identical:
+        let a = subscribe_feed(&mut inner, ConnId::new(1), "Kusama");
+        let b = subscribe_feed(&mut inner, ConnId::new(2), "Kusama");
+        assert!(!a.is_empty());
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn redacted_names_never_reach_feeds_or_the_public_api() {
+        let secret = "alice@example.com";
+        let mut inner = inner_loop(Vec::new());
+        inner.name_redactor = Arc::new(NameRedactor::new(&[r"\S+@\S+"]).unwrap());
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let named = NodeDetails {
+            name: format!("{}'s node", secret).into(),
+            ..node("Chain")
+        };
+        let contains_secret = |bytes: &[u8]| String::from_utf8_lossy(bytes).contains(secret);
+
+        // Feeds hear about the node as it's added:
+        let feed = subscribed_feed(&mut inner, ConnId::new(1), "Chain");
+        add_shard_node(&mut inner, 0, genesis_hash, named);
+        let messages: Vec<_> = feed
+            .drain()
+            .map(|ToFeedWebsocket::Bytes(bytes)| bytes)
+            .collect();
+        assert!(!messages.is_empty());
+        assert!(!messages.iter().any(|bytes| contains_secret(bytes)));
+
+        // ...and when they first subscribe:
+        let snapshot = subscribe_feed(&mut inner, ConnId::new(2), "Chain");
+        assert!(!snapshot.is_empty());
+        assert!(!snapshot.iter().any(|bytes| contains_secret(bytes)));
+
+        let id = inner
+            .node_state
+            .get_chain_by_genesis_hash(&genesis_hash)
+            .unwrap()
+            .nodes_in_join_order()[0]
+            .0;
+        let info = inner.node_info(genesis_hash, id).unwrap();
+        assert_eq!(&*info.details.name, "[redacted] node");
+        assert!(!contains_secret(&serde_json::to_vec(&info).unwrap()));
+
+        // The original name is kept internally, and shown to admins:
+        let nodes = inner.chain_nodes(genesis_hash, None).unwrap();
+        assert_eq!(&*nodes[0].details.name, "alice@example.com's node");
+    }
+
+    #[test]
+    fn slow_messages_are_logged() {
+        capture_warnings();
+
+        let threshold = Some(Duration::from_millis(10));
+        let node_id = state::NodeId::new_for_test(1, 2);
+
+        // Artificially take longer than the threshold to "handle" a message:
+        let start = Instant::now();
+        std::thread::sleep(Duration::from_millis(20));
+        let logged = warn_if_slow(threshold, start.elapsed(), Some(node_id), "system.interval");
+
+        assert!(logged);
+        let warnings = WARNINGS.lock().unwrap();
+        let warning = warnings
+            .iter()
+            .find(|w| w.contains("'system.interval'"))
+            .expect("slow message warning should be logged");
+        assert!(warning.contains(&format!("{:?}", Some(node_id))));
+        assert!(warning.contains("threshold is 10ms"));
+    }
+
+    #[test]
+    fn genesis_blocks_from_another_chain_are_logged() {
+        capture_warnings();
+
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut inner = inner_loop(Vec::new());
+        let confused = NodeDetails {
+            name: "Confused Genesis".into(),
+            ..node("Chain")
+        };
+        add_shard_node(&mut inner, 0, genesis_hash, confused);
+        let announce = |inner: &mut InnerLoop, hash| {
+            inner.handle_from_shard(
+                ConnId::new(100),
+                FromShardWebsocket::Update {
+                    local_id: ShardNodeId::new(0),
+                    payload: node_message::Payload::BlockImport(Block { hash, height: 0 }),
+                    reported_at: None,
+                    ingest: IngestTimes::received_now(time::now()),
+                },
+            );
+        };
+        let mismatches = || {
+            WARNINGS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|w| w.contains("Confused Genesis") && w.contains("genesis block"))
+                .count()
+        };
+
+        announce(&mut inner, genesis_hash);
+        assert_eq!(mismatches(), 0);
+        announce(&mut inner, BlockHash::from_low_u64_be(2));
+        assert_eq!(mismatches(), 1);
+    }
+
+    #[test]
+    fn fast_messages_are_not_logged() {
+        let threshold = Some(Duration::from_millis(100));
+        assert!(!warn_if_slow(
+            threshold,
+            Duration::from_millis(1),
+            None,
+            "feed ping"
+        ));
+
+        // No threshold means nothing is ever logged:
+        assert!(!warn_if_slow(
+            None,
+            Duration::from_secs(100),
+            None,
+            "feed ping"
+        ));
+    }
+
+    #[test]
+    fn node_filter_parsed_from_query() {
+        assert_eq!(node_filter_from_query(None).unwrap(), None);
+        assert_eq!(node_filter_from_query(Some("foo=bar")).unwrap(), None);
+        assert_eq!(
+            node_filter_from_query(Some("foo=bar&node=12D3KooW")).unwrap(),
+            Some(NodeFilter {
+                network_id: Some("12D3KooW".into()),
+                environment: None,
+                offchain_indexing: None,
+                message_schema: None,
+            })
+        );
+
+        // The network ID must be given, and only once:
+        assert!(node_filter_from_query(Some("node")).is_err());
+        assert!(node_filter_from_query(Some("node=")).is_err());
+        assert!(node_filter_from_query(Some("node=a&node=b")).is_err());
+    }
+
+    #[test]
+    fn environment_filter_parsed_from_query() {
+        assert_eq!(
+            node_filter_from_query(Some("environment=prod")).unwrap(),
+            Some(NodeFilter {
+                network_id: None,
+                environment: Some("prod".into()),
+                offchain_indexing: None,
+                message_schema: None,
+            })
+        );
+        assert_eq!(
+            node_filter_from_query(Some("environment=staging&node=12D3KooW")).unwrap(),
+            Some(NodeFilter {
+                network_id: Some("12D3KooW".into()),
+                environment: Some("staging".into()),
+                offchain_indexing: None,
+                message_schema: None,
+            })
+        );
+
+        assert!(node_filter_from_query(Some("environment")).is_err());
+        assert!(node_filter_from_query(Some("environment=")).is_err());
+        assert!(node_filter_from_query(Some("environment=a&environment=b")).is_err());
+    }
+
+    #[test]
+    fn environment_filter_matches_nodes_in_that_environment() {
+        let filter = |environment: &str| NodeFilter {
+            network_id: None,
+            environment: Some(environment.into()),
+            offchain_indexing: None,
+            message_schema: None,
+        };
+        let mut prod_node = node("Polkadot");
+        prod_node.environment = Some("prod".into());
+        let unknown_node = node("Polkadot");
+
+        assert!(filter("prod").matches_node(&prod_node));
+        assert!(!filter("staging").matches_node(&prod_node));
+        // Nodes that don't say which environment they're in are "unknown":
+        assert!(!filter("prod").matches_node(&unknown_node));
+        assert!(filter(common::node_types::UNKNOWN_ENVIRONMENT).matches_node(&unknown_node));
+
+        // Both network ID and environment must match if both are given:
+        prod_node.network_id = Some("12D3KooW".into());
+        let both = NodeFilter {
+            network_id: Some("12D3KooW".into()),
+            environment: Some("prod".into()),
+            offchain_indexing: None,
+            message_schema: None,
+        };
+        assert!(both.matches_node(&prod_node));
+        prod_node.environment = Some("staging".into());
+        assert!(!both.matches_node(&prod_node));
+    }
+
+    #[test]
+    fn offchain_indexing_filter_parsed_from_query_and_matched() {
+        let filter = node_filter_from_query(Some("offchain_indexing=true"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(filter.offchain_indexing, Some(true));
+
+        let mut indexing_node = node("Polkadot");
+        indexing_node.offchain_indexing = true;
+        let other_node = node("Polkadot");
+        assert!(filter.matches_node(&indexing_node));
+        assert!(!filter.matches_node(&other_node));
+
+        let filter = node_filter_from_query(Some("offchain_indexing=false"))
+            .unwrap()
+            .unwrap();
+        assert!(!filter.matches_node(&indexing_node));
+        assert!(filter.matches_node(&other_node));
+
+        assert!(node_filter_from_query(Some("offchain_indexing")).is_err());
+        assert!(node_filter_from_query(Some("offchain_indexing=yes")).is_err());
+        assert!(
+            node_filter_from_query(Some("offchain_indexing=true&offchain_indexing=false")).is_err()
+        );
+    }
+
+    #[test]
+    fn message_schema_filter_parsed_from_query_and_matched() {
+        let filter = node_filter_from_query(Some("message_schema=V1"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(filter.message_schema, Some(MessageSchema::V1));
+
+        let old_node = NodeDetails {
+            message_schema: Some(MessageSchema::V1),
+            ..node("Polkadot")
+        };
+        let new_node = NodeDetails {
+            message_schema: Some(MessageSchema::V2),
+            ..node("Polkadot")
+        };
+        // Nodes added without going through a shard have no schema, so match neither:
+        let unknown_node = node("Polkadot");
+        assert!(filter.matches_node(&old_node));
+        assert!(!filter.matches_node(&new_node));
+        assert!(!filter.matches_node(&unknown_node));
+
+        assert!(node_filter_from_query(Some("message_schema=v3")).is_err());
+        assert!(node_filter_from_query(Some("message_schema=V1&message_schema=V2")).is_err());
+    }
+
+    #[test]
+    fn relocating_a_node_asks_for_a_fresh_location() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut inner = inner_loop(Vec::new());
+        let (tx_to_locator, rx_from_inner) = flume::unbounded();
+        inner.tx_to_locator = tx_to_locator;
+        add_shard_node(&mut inner, 0, genesis_hash, node("Chain"));
+
+        // Nodes are located when they're added, using any cached location:
+        let node_id = NodeId::new_for_test(0, 0);
+        let localhost = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            rx_from_inner.drain().collect::<Vec<_>>(),
+            vec![find_location::LocateRequest::new(node_id, localhost)]
+        );
+
+        let relocate = |inner: &mut InnerLoop, genesis_hash, node_id| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_relocate_node(genesis_hash, node_id, tx);
+            rx.try_recv().unwrap()
+        };
+        assert!(relocate(&mut inner, genesis_hash, 0));
+        assert_eq!(
+            rx_from_inner.drain().collect::<Vec<_>>(),
+            vec![find_location::LocateRequest::refresh(node_id, localhost)]
+        );
+
+        // Unknown nodes and chains can't be relocated:
+        assert!(!relocate(&mut inner, genesis_hash, 1));
+        assert!(!relocate(&mut inner, BlockHash::from_low_u64_be(2), 0));
+
+        // Nor can nodes that have gone away:
+        remove_shard_node(&mut inner, 0);
+        assert!(!relocate(&mut inner, genesis_hash, 0));
+        assert!(inner.node_ips.is_empty());
+        assert!(rx_from_inner.is_empty());
+    }
+
+    #[test]
+    fn feed_queue_lengths_are_summarised() {
+        assert_eq!(
+            FeedQueueLengths::new(Vec::new()),
+            FeedQueueLengths::default()
+        );
+
+        let lengths = FeedQueueLengths::new((1..=20).rev());
+        assert_eq!(
+            lengths,
+            FeedQueueLengths {
+                feeds: 20,
+                total: 210,
+                mean: 10.5,
+                p95: 19,
+                max: 20,
+            }
+        );
+    }
+
+    fn located_as(anonymize_locations: Option<u8>) -> common::node_types::NodeLocation {
+        let mut inner = inner_loop(Vec::new());
+        inner.anonymize_locations = anonymize_locations;
+        let node_id = inner
+            .node_state
+            .add_node(BlockHash::from_low_u64_be(1), node("Local Testnet"))
+            .unwrap_id();
+
+        inner.handle_from_find_location(
+            node_id,
+            Some(Arc::new(common::node_types::NodeLocation {
+                latitude: 52.5163,
+                longitude: 13.3777,
+                city: "Berlin".into(),
+                country: Some("DE".into()),
+            })),
+        );
+
+        inner
+            .node_state
+            .get_chain_by_node_id(node_id)
+            .and_then(|chain| chain.get_node(node_id.get_chain_node_id().into()))
+            .and_then(|node| node.location())
+            .cloned()
+            .expect("node should be located")
+    }
+
+    #[test]
+    fn locations_are_anonymized_if_asked() {
+        let location = located_as(Some(1));
+        assert_eq!(location.latitude, 52.5);
+        assert_eq!(location.longitude, 13.4);
+        assert_eq!(&*location.city, "");
+        assert_eq!(location.country.as_deref(), Some("DE"));
+    }
+
+    #[test]
+    fn locations_are_untouched_if_not_anonymized() {
+        let location = located_as(None);
+        assert_eq!(location.latitude, 52.5163);
+        assert_eq!(location.longitude, 13.3777);
+        assert_eq!(&*location.city, "Berlin");
+        assert_eq!(location.country.as_deref(), Some("DE"));
+    }
+
+    #[test]
+    fn ingest_latency_is_reported_per_chain_and_shard() {
+        let mut inner = inner_loop(Vec::new());
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let (tx, _rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Initialize { channel: tx },
+        );
+        add_shard_node(&mut inner, 0, genesis_hash, node("Kusama"));
+
+        // The shard received this 1.5 seconds before we did:
+        let mut ingest = IngestTimes::received_now(0);
+        ingest.shard_received_at = ingest.core_received_at - 1_500;
+        inner.handle_from_shard(
+            ConnId::new(100),
+            FromShardWebsocket::Update {
+                local_id: ShardNodeId::new(0),
+                payload: node_message::Payload::BlockImport(Block::zero()),
+                reported_at: None,
+                ingest,
+            },
+        );
+
+        let (tx, rx) = flume::unbounded();
+        inner.handle_gather_metrics(tx, 0, 0);
+        let metrics = rx.try_recv().unwrap();
+        assert_eq!(metrics.chain_ingest_latency.len(), 1);
+        let chain = &metrics.chain_ingest_latency[0];
+        assert_eq!(&*chain.label, "Kusama");
+        assert_eq!(chain.transit.sum_ms(), 1_500);
+        assert_eq!(chain.queued.count(), 1);
+        assert_eq!(metrics.shard_ingest_latency.len(), 1);
+        assert_eq!(metrics.shard_ingest_latency[0].shard_conn_id, 100);
+
+        // Shards and chains that go away are forgotten about:
+        inner.handle_from_shard(ConnId::new(100), FromShardWebsocket::Disconnected);
+        let (tx, rx) = flume::unbounded();
+        inner.handle_gather_metrics(tx, 0, 0);
+        let metrics = rx.try_recv().unwrap();
+        assert!(metrics.chain_ingest_latency.is_empty());
+        assert!(metrics.shard_ingest_latency.is_empty());
+    }
+
+    #[test]
+    fn node_messages_and_feed_dispatches_are_traced() {
+        let spans = RecordingSubscriber::new();
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        tracing::subscriber::with_default(spans.clone(), || {
+            let mut inner = inner_loop(Vec::new());
+            add_shard_node(&mut inner, 0, genesis_hash, node("Chain"));
+            let _feed = subscribed_feed(&mut inner, ConnId::new(1), "Chain");
+            inner.handle_from_shard(
+                ConnId::new(100),
+                FromShardWebsocket::Update {
+                    local_id: ShardNodeId::new(0),
+                    payload: node_message::Payload::BlockImport(Block {
+                        hash: BlockHash::from_low_u64_be(2),
+                        height: 1,
+                    }),
+                    reported_at: None,
+                    ingest: IngestTimes::received_now(time::now()),
+                },
+            );
+        });
+
+        let fields = |message_type: &str| {
+            BTreeMap::from([
+                ("node_id", "0".to_string()),
+                ("chain", format!("{:?}", genesis_hash)),
+                ("message_type", message_type.to_string()),
+            ])
+        };
+        assert_eq!(
+            spans.fields_of("node_message"),
+            vec![fields("system.connected"), fields("block.import")]
+        );
+
+        // The block import was sent on to the feed subscribed to the chain:
+        let feed_dispatches = spans.fields_of("feed_dispatch");
+        assert_eq!(feed_dispatches.last().unwrap()["feeds"], "1");
     }
 }
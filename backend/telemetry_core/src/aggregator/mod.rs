@@ -16,10 +16,18 @@
 
 mod aggregator;
 mod aggregator_set;
+#[cfg(test)]
+mod fuzz;
+mod ingest_latency;
 mod inner_loop;
 
 // Expose the various message types that can be worked with externally:
 pub use aggregator::AggregatorOpts;
-pub use inner_loop::{FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket, ToShardWebsocket};
+pub use ingest_latency::{IngestTimes, LatencyHistogram, LatencySummary};
+pub use inner_loop::{
+    node_filter_from_query, ChainHeights, ChainIngestLatency, ChainMemoryUsage, FeedQueueLengths,
+    FeedStats, FromFeedWebsocket, FromShardWebsocket, NodeFilter, ToFeedWebsocket,
+    ToShardWebsocket,
+};
 
 pub use aggregator_set::*;
@@ -0,0 +1,241 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A small read-only REST API, served under `/api/v1`, which hands back
+//! details from the aggregator state as JSON.
+
+use crate::aggregator::AggregatorSet;
+use crate::feed_schema;
+use crate::list_query::{ListOpts, ListQuery, Page};
+use crate::state::{ChainCapacity, MAX_CELL_DEGREES, MIN_CELL_DEGREES};
+use common::http_utils;
+use common::node_types::BlockHash;
+use hyper::{Body, Method, Request, Response};
+use serde::Serialize;
+
+/// All of the API routes live under this prefix.
+pub const API_PREFIX: &str = "/api/v1";
+
+/// How big the cells that node locations are clustered into are, unless asked otherwise.
+const DEFAULT_CELL_DEGREES: f32 = 1.0;
+
+/// How the list of chains can be paged through and sorted.
+const CHAIN_LIST_OPTS: ListOpts = ListOpts {
+    sortable: &[
+        "label",
+        "node_count",
+        "best_block.height",
+        "finalized_block.height",
+        "average_block_time",
+    ],
+    max_limit: 500,
+};
+
+/// A page of the list of chains, along with how many chains we're tracking out of
+/// how many we're allowed to.
+#[derive(Serialize)]
+struct ChainsPage {
+    #[serde(flatten)]
+    page: Page,
+    capacity: ChainCapacity,
+}
+
+/// Handle a request to some path beginning with [`API_PREFIX`].
+pub async fn handle_api_request(aggregator: AggregatorSet, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path().trim_end_matches('/');
+    let path = path.strip_prefix(API_PREFIX).unwrap_or(path);
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match (req.method(), segments.as_slice()) {
+        // A description of the messages that are sent to feeds:
+        (&Method::GET, ["feed-schema"]) => {
+            http_utils::json_response(200, &feed_schema::feed_schema())
+        }
+        // Details about every chain. These can be paged through, sorted and trimmed
+        // down as described in [`crate::list_query`]. Alongside them is how many chains
+        // there are, out of how many we'll track, and what we've done to stay under that:
+        (&Method::GET, ["chains"]) => {
+            let query = match ListQuery::from_query(req.uri().query(), &CHAIN_LIST_OPTS) {
+                Ok(query) => query,
+                Err(e) => return http_utils::basic_response(400, e.to_string()),
+            };
+            match aggregator.chains().await.and_then(|list| {
+                Ok(ChainsPage {
+                    page: query.page(&list.chains)?,
+                    capacity: list.capacity,
+                })
+            }) {
+                Ok(page) => http_utils::json_response(200, &page),
+                Err(e) => {
+                    log::error!("Error obtaining chains: {}", e);
+                    http_utils::basic_response(500, "Error obtaining chains")
+                }
+            }
+        }
+        // Details about a single chain, given its genesis hash:
+        (&Method::GET, ["chains", genesis_hash]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            match aggregator.chain_details(genesis_hash).await {
+                Ok(Some(details)) => http_utils::json_response(200, &details),
+                Ok(None) => http_utils::basic_response(404, "Chain not found"),
+                Err(e) => {
+                    log::error!("Error obtaining chain details: {}", e);
+                    http_utils::basic_response(500, "Error obtaining chain details")
+                }
+            }
+        }
+        // Recent samples of the node count of a chain, given its genesis hash:
+        (&Method::GET, ["chains", genesis_hash, "nodecount-history"]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            match aggregator.node_count_history(genesis_hash).await {
+                Ok(Some(history)) => http_utils::json_response(200, &history),
+                Ok(None) => http_utils::basic_response(404, "Chain not found"),
+                Err(e) => {
+                    log::error!("Error obtaining node count history: {}", e);
+                    http_utils::basic_response(500, "Error obtaining node count history")
+                }
+            }
+        }
+        // The current state of a single node, given the genesis hash of its chain and
+        // the ID that feeds know it by:
+        (&Method::GET, ["chains", genesis_hash, "nodes", node_id]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            let node_id: usize = match node_id.parse() {
+                Ok(id) => id,
+                Err(_) => return http_utils::basic_response(400, "Invalid node ID"),
+            };
+            match aggregator.node_info(genesis_hash, node_id).await {
+                Ok(Some(info)) => http_utils::json_response(200, &info),
+                Ok(None) => http_utils::basic_response(404, "Node not found"),
+                Err(e) => {
+                    log::error!("Error obtaining node info: {}", e);
+                    http_utils::basic_response(500, "Error obtaining node info")
+                }
+            }
+        }
+        // The last few new best blocks that a node announced, oldest first, given the
+        // genesis hash of its chain and the ID that feeds know it by:
+        (&Method::GET, ["chains", genesis_hash, "nodes", node_id, "blocks"]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            let node_id: usize = match node_id.parse() {
+                Ok(id) => id,
+                Err(_) => return http_utils::basic_response(400, "Invalid node ID"),
+            };
+            match aggregator.node_blocks(genesis_hash, node_id).await {
+                Ok(Some(blocks)) => http_utils::json_response(200, &blocks),
+                Ok(None) => http_utils::basic_response(404, "Node not found"),
+                Err(e) => {
+                    log::error!("Error obtaining node blocks: {}", e);
+                    http_utils::basic_response(500, "Error obtaining node blocks")
+                }
+            }
+        }
+        // The locations of a chain's nodes, grouped into a grid of cells `?cell=<degrees>`
+        // on a side (1 degree unless given), so that they're quicker to draw on a map:
+        (&Method::GET, ["chains", genesis_hash, "locations"]) => {
+            let genesis_hash = match parse_genesis_hash(genesis_hash) {
+                Some(hash) => hash,
+                None => return http_utils::basic_response(400, "Invalid genesis hash"),
+            };
+            let cell_degrees = match parse_cell_degrees(req.uri().query()) {
+                Ok(cell_degrees) => cell_degrees,
+                Err(e) => return http_utils::basic_response(400, e.to_string()),
+            };
+            match aggregator
+                .location_clusters(genesis_hash, cell_degrees)
+                .await
+            {
+                Ok(Some(clusters)) => http_utils::json_response(200, &clusters),
+                Ok(None) => http_utils::basic_response(404, "Chain not found"),
+                Err(e) => {
+                    log::error!("Error obtaining node locations: {}", e);
+                    http_utils::basic_response(500, "Error obtaining node locations")
+                }
+            }
+        }
+        _ => http_utils::basic_response(404, "Not found"),
+    }
+}
+
+/// Parse a genesis hash from a hex string, which may or may not be prefixed with "0x".
+pub fn parse_genesis_hash(s: &str) -> Option<BlockHash> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    s.parse().ok()
+}
+
+/// Parse the size of the cells to cluster node locations into from a query string.
+fn parse_cell_degrees(query: Option<&str>) -> anyhow::Result<f32> {
+    let value = query
+        .unwrap_or("")
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("cell="));
+    let cell_degrees = match value {
+        Some(value) => value
+            .parse::<f32>()
+            .map_err(|_| anyhow::anyhow!("The cell size should be a number of degrees"))?,
+        None => return Ok(DEFAULT_CELL_DEGREES),
+    };
+    if !(MIN_CELL_DEGREES..=MAX_CELL_DEGREES).contains(&cell_degrees) {
+        anyhow::bail!(
+            "The cell size should be between {} and {} degrees",
+            MIN_CELL_DEGREES,
+            MAX_CELL_DEGREES
+        );
+    }
+    Ok(cell_degrees)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn genesis_hash_parsed_with_or_without_prefix() {
+        let hash = BlockHash::from_low_u64_be(0x1234);
+        let hex = format!("{:x}", hash);
+
+        assert_eq!(parse_genesis_hash(&hex), Some(hash));
+        assert_eq!(parse_genesis_hash(&format!("0x{}", hex)), Some(hash));
+        assert_eq!(parse_genesis_hash("0x1234"), None);
+        assert_eq!(parse_genesis_hash("foo"), None);
+    }
+
+    #[test]
+    fn cell_degrees_parsed_from_query() {
+        assert_eq!(parse_cell_degrees(None).unwrap(), DEFAULT_CELL_DEGREES);
+        assert_eq!(
+            parse_cell_degrees(Some("foo=1")).unwrap(),
+            DEFAULT_CELL_DEGREES
+        );
+        assert_eq!(parse_cell_degrees(Some("foo=1&cell=0.5")).unwrap(), 0.5);
+        assert!(parse_cell_degrees(Some("cell=abc")).is_err());
+        assert!(parse_cell_degrees(Some("cell=0")).is_err());
+        assert!(parse_cell_degrees(Some("cell=91")).is_err());
+        assert!(parse_cell_degrees(Some("cell=NaN")).is_err());
+    }
+}
@@ -0,0 +1,680 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Support for running several telemetry cores side by side. Chains are shared out
+//! between the cores in a cluster using consistent hashing of their genesis hash, and
+//! any node that is submitted to a core which doesn't own its chain is forwarded on
+//! to the core that does.
+
+use common::internal_connection::{create_ws_connection_to_core, Message};
+use common::internal_messages::{
+    FromShardAggregator, FromTelemetryCore, NodeCloseReason, ShardNodeId,
+};
+use common::node_types::BlockHash;
+use common::shard_token;
+use common::AssignId;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Cluster peers submit forwarded nodes to this path. Nodes submitted here are
+/// never forwarded again, so cores that disagree about who owns a chain can't
+/// bounce nodes back and forth between them.
+pub const CLUSTER_SUBMIT_PATH: &str = "/cluster_submit";
+
+/// How many points on the hash ring each cluster member is given. More points
+/// leads to chains being shared out more evenly.
+const POINTS_PER_MEMBER: usize = 64;
+
+/// Knows which telemetry cores make up a cluster.
+pub trait ClusterMembership {
+    /// The address that the other cores in the cluster know us by.
+    fn local(&self) -> SocketAddr;
+    /// The addresses of the other cores in the cluster.
+    fn peers(&self) -> Vec<SocketAddr>;
+}
+
+/// A fixed set of cluster members, handed to us via configuration.
+#[derive(Debug, Clone)]
+pub struct StaticMembership {
+    local: SocketAddr,
+    peers: Vec<SocketAddr>,
+}
+
+impl StaticMembership {
+    pub fn new(local: SocketAddr, mut peers: Vec<SocketAddr>) -> Self {
+        // We aren't our own peer, and we only need to know about each peer once:
+        peers.retain(|&peer| peer != local);
+        peers.sort();
+        peers.dedup();
+        StaticMembership { local, peers }
+    }
+}
+
+impl ClusterMembership for StaticMembership {
+    fn local(&self) -> SocketAddr {
+        self.local
+    }
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.peers.clone()
+    }
+}
+
+/// Assigns chains to cluster members such that adding or removing a member
+/// only moves the chains that it gains or loses, rather than reshuffling
+/// everything.
+#[derive(Debug, Clone)]
+pub struct ConsistentHashRing {
+    ring: BTreeMap<u64, SocketAddr>,
+}
+
+impl ConsistentHashRing {
+    /// Build a ring containing the local core and all of its peers.
+    pub fn new(membership: &dyn ClusterMembership) -> Self {
+        let mut ring = BTreeMap::new();
+        let members = std::iter::once(membership.local()).chain(membership.peers());
+        for member in members {
+            for point in 0..POINTS_PER_MEMBER {
+                let key = format!("{}#{}", member, point);
+                ring.insert(stable_hash(key.as_bytes()), member);
+            }
+        }
+        ConsistentHashRing { ring }
+    }
+
+    /// Which cluster member owns the chain given?
+    pub fn owner(&self, chain: &str) -> SocketAddr {
+        let hash = stable_hash(chain.as_bytes());
+        let (_, &owner) = self
+            .ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .expect("the ring always contains the local member");
+        owner
+    }
+}
+
+/// Every core in the cluster must agree on where things land on the ring, so we can't
+/// use the std hasher, which makes no promises about its output being stable. This is
+/// FNV-1a, followed by a final mixing step to spread similar inputs out.
+fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Messages handed to the forwarding task.
+// Almost every message is forwarded node data, so there's no point boxing it.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+enum ToForwarder {
+    /// Forward a message about a node to the peer given.
+    Forward {
+        peer: SocketAddr,
+        source_id: u64,
+        msg: FromShardAggregator,
+    },
+    /// We've (re)connected to the peer given.
+    PeerConnected(SocketAddr),
+}
+
+/// Decides which chains this core owns, and forwards nodes for any chains that it
+/// doesn't own to the peer that does.
+pub struct Cluster {
+    local: SocketAddr,
+    ring: ConsistentHashRing,
+    tx_to_forwarder: flume::Sender<ToForwarder>,
+    next_source_id: AtomicU64,
+}
+
+impl Cluster {
+    /// Connect to each of our cluster peers and start forwarding nodes to them as needed.
+    /// Every core in the cluster is expected to share the same shard token, if one is given.
+    pub async fn spawn(
+        membership: &dyn ClusterMembership,
+        shard_token: Option<&str>,
+    ) -> Arc<Cluster> {
+        let (cluster, rx_to_forwarder) = Cluster::new(membership);
+        let tx_to_forwarder = cluster.tx_to_forwarder.clone();
+
+        let mut peers = HashMap::new();
+        for addr in membership.peers() {
+            let mut uri = format!("ws://{}{}", addr, CLUSTER_SUBMIT_PATH)
+                .parse()
+                .expect("a socket address makes a valid URI");
+            if let Some(token) = shard_token {
+                uri = shard_token::add_to_uri(uri, token)
+                    .expect("the shard token is validated on startup");
+            }
+            let (tx_to_peer, rx_from_peer) =
+                create_ws_connection_to_core::<FromShardAggregator, FromTelemetryCore>(uri).await;
+            tokio::spawn(handle_peer_messages(
+                addr,
+                rx_from_peer,
+                tx_to_forwarder.clone(),
+            ));
+            peers.insert(addr, Peer::new(tx_to_peer));
+        }
+
+        tokio::spawn(forward_messages(rx_to_forwarder, peers));
+        Arc::new(cluster)
+    }
+
+    fn new(membership: &dyn ClusterMembership) -> (Cluster, flume::Receiver<ToForwarder>) {
+        let (tx_to_forwarder, rx_to_forwarder) = flume::unbounded();
+        let cluster = Cluster {
+            local: membership.local(),
+            ring: ConsistentHashRing::new(membership),
+            tx_to_forwarder,
+            next_source_id: AtomicU64::new(1),
+        };
+        (cluster, rx_to_forwarder)
+    }
+
+    /// Which peer owns the chain with this genesis hash? Returns `None` if we own it.
+    pub fn peer_owner(&self, genesis_hash: &BlockHash) -> Option<SocketAddr> {
+        let owner = self.ring.owner(&hex::encode(genesis_hash.as_bytes()));
+        if owner == self.local {
+            None
+        } else {
+            Some(owner)
+        }
+    }
+
+    /// Create a forwarder for the messages coming from a single shard connection.
+    pub fn node_forwarder(self: &Arc<Self>) -> NodeForwarder {
+        NodeForwarder {
+            source_id: self.next_source_id.fetch_add(1, Ordering::Relaxed),
+            cluster: Arc::clone(self),
+            forwarded: HashMap::new(),
+        }
+    }
+}
+
+/// Forwards nodes from a single shard connection on to whichever peer owns their
+/// chain. When this is dropped (ie the shard disconnects), any nodes that we
+/// forwarded are removed from the peers again.
+pub struct NodeForwarder {
+    source_id: u64,
+    cluster: Arc<Cluster>,
+    forwarded: HashMap<ShardNodeId, SocketAddr>,
+}
+
+impl NodeForwarder {
+    /// Forward the message if it's about a node on a chain owned by one of our peers.
+    /// If the message should be handled locally instead, it's handed back.
+    pub fn handle(&mut self, msg: FromShardAggregator) -> Option<FromShardAggregator> {
+        match msg {
+            FromShardAggregator::AddNode {
+                local_id,
+                genesis_hash,
+                ..
+            } => {
+                let owner = self.cluster.peer_owner(&genesis_hash);
+
+                // If we'd forwarded this node elsewhere before, remove it from there:
+                if let Some(old_peer) = self.forwarded.remove(&local_id) {
+                    if Some(old_peer) != owner {
+                        self.forward(
+                            old_peer,
+                            FromShardAggregator::RemoveNode {
+                                local_id,
+                                reason: NodeCloseReason::ChainMoved,
+                            },
+                        );
+                    }
+                }
+
+                match owner {
+                    Some(peer) => {
+                        self.forwarded.insert(local_id, peer);
+                        self.forward(peer, msg);
+                        None
+                    }
+                    None => Some(msg),
+                }
+            }
+            FromShardAggregator::UpdateNode { local_id, .. } => {
+                match self.forwarded.get(&local_id) {
+                    Some(&peer) => {
+                        self.forward(peer, msg);
+                        None
+                    }
+                    None => Some(msg),
+                }
+            }
+            FromShardAggregator::RemoveNode { local_id, .. } => {
+                match self.forwarded.remove(&local_id) {
+                    Some(peer) => {
+                        self.forward(peer, msg);
+                        None
+                    }
+                    None => Some(msg),
+                }
+            }
+        }
+    }
+
+    fn forward(&self, peer: SocketAddr, msg: FromShardAggregator) {
+        let _ = self.cluster.tx_to_forwarder.send(ToForwarder::Forward {
+            peer,
+            source_id: self.source_id,
+            msg,
+        });
+    }
+}
+
+impl Drop for NodeForwarder {
+    fn drop(&mut self) {
+        for (local_id, peer) in std::mem::take(&mut self.forwarded) {
+            self.forward(
+                peer,
+                FromShardAggregator::RemoveNode {
+                    local_id,
+                    reason: NodeCloseReason::ShardDisconnected,
+                },
+            );
+        }
+    }
+}
+
+/// Our connection to a single cluster peer.
+struct Peer {
+    tx: flume::Sender<FromShardAggregator>,
+    /// Nodes from every shard connection are forwarded to a peer over one connection,
+    /// so we assign them new IDs that are unique on this connection.
+    ids: AssignId<ShardNodeId, (u64, ShardNodeId)>,
+    /// The message used to add each node that we've forwarded, so that we can add them
+    /// all again if we have to reconnect to the peer.
+    added: HashMap<ShardNodeId, FromShardAggregator>,
+}
+
+impl Peer {
+    fn new(tx: flume::Sender<FromShardAggregator>) -> Self {
+        Peer {
+            tx,
+            ids: AssignId::new(),
+            added: HashMap::new(),
+        }
+    }
+
+    /// Swap the shard-local ID in the message for our peer-local ID.
+    fn remap(&mut self, source_id: u64, msg: FromShardAggregator) -> Option<FromShardAggregator> {
+        match msg {
+            FromShardAggregator::AddNode {
+                ip,
+                node,
+                local_id,
+                genesis_hash,
+                resumed,
+            } => {
+                let details = (source_id, local_id);
+                let local_id = match self.ids.get_id(&details) {
+                    Some(id) => id,
+                    None => self.ids.assign_id(details),
+                };
+                let msg = FromShardAggregator::AddNode {
+                    ip,
+                    node,
+                    local_id,
+                    genesis_hash,
+                    resumed,
+                };
+                self.added.insert(local_id, msg.clone());
+                Some(msg)
+            }
+            FromShardAggregator::UpdateNode {
+                local_id,
+                payload,
+                reported_at,
+                received_at,
+            } => {
+                let local_id = self.ids.get_id(&(source_id, local_id))?;
+                Some(FromShardAggregator::UpdateNode {
+                    local_id,
+                    payload,
+                    reported_at,
+                    received_at,
+                })
+            }
+            FromShardAggregator::RemoveNode { local_id, reason } => {
+                let local_id = self.ids.remove_by_details(&(source_id, local_id))?;
+                self.added.remove(&local_id);
+                Some(FromShardAggregator::RemoveNode { local_id, reason })
+            }
+        }
+    }
+}
+
+/// Hand messages to the relevant peer connections.
+async fn forward_messages(
+    rx_to_forwarder: flume::Receiver<ToForwarder>,
+    mut peers: HashMap<SocketAddr, Peer>,
+) {
+    while let Ok(msg) = rx_to_forwarder.recv_async().await {
+        match msg {
+            ToForwarder::Forward {
+                peer,
+                source_id,
+                msg,
+            } => {
+                let peer = match peers.get_mut(&peer) {
+                    Some(peer) => peer,
+                    None => continue,
+                };
+                if let Some(msg) = peer.remap(source_id, msg) {
+                    let _ = peer.tx.send_async(msg).await;
+                }
+            }
+            ToForwarder::PeerConnected(addr) => {
+                // Anything we sent while disconnected was thrown away, so add every
+                // node that we're forwarding to this peer again:
+                let peer = match peers.get(&addr) {
+                    Some(peer) => peer,
+                    None => continue,
+                };
+                for msg in peer.added.values() {
+                    let _ = peer.tx.send_async(msg.clone()).await;
+                }
+            }
+        }
+    }
+}
+
+/// Watch for a peer connection going up and down.
+async fn handle_peer_messages(
+    addr: SocketAddr,
+    rx_from_peer: flume::Receiver<Message<FromTelemetryCore>>,
+    tx_to_forwarder: flume::Sender<ToForwarder>,
+) {
+    while let Ok(msg) = rx_from_peer.recv_async().await {
+        match msg {
+            Message::Connected => {
+                log::info!("Connected to cluster peer {}", addr);
+                if tx_to_forwarder
+                    .send(ToForwarder::PeerConnected(addr))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Message::Disconnected => {
+                log::warn!("Disconnected from cluster peer {}", addr);
+            }
+            // Muting is left to the shard that the node is connected to; the peer will
+            // simply ignore nodes on chains that it won't accept.
+            Message::Data(FromTelemetryCore::Mute { local_id, reason }) => {
+                log::debug!(
+                    "Cluster peer {} asked us to mute node {:?} ({:?})",
+                    addr,
+                    local_id,
+                    reason
+                );
+            }
+            // As with muting, nodes that a peer doesn't recognise are left alone; the shard
+            // that they're connected to will resync them with us if need be.
+            Message::Data(FromTelemetryCore::Resync { local_id }) => {
+                log::debug!(
+                    "Cluster peer {} asked us to resync node {:?}",
+                    addr,
+                    local_id
+                );
+            }
+            // A peer muting a chain tells its own shards to drop the chain's updates, and
+            // we're only forwarding a few nodes to it, so we needn't do anything ourselves.
+            Message::Data(FromTelemetryCore::MuteChain {
+                genesis_hash,
+                ttl_ms,
+            }) => {
+                log::debug!(
+                    "Cluster peer {} muted chain {:?} for {}ms",
+                    addr,
+                    genesis_hash,
+                    ttl_ms
+                );
+            }
+            Message::Data(FromTelemetryCore::UnmuteChain { genesis_hash }) => {
+                log::debug!("Cluster peer {} unmuted chain {:?}", addr, genesis_hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::NodeDetails;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn ring(local: u16, peers: &[u16]) -> ConsistentHashRing {
+        let peers = peers.iter().map(|&p| addr(p)).collect();
+        ConsistentHashRing::new(&StaticMembership::new(addr(local), peers))
+    }
+
+    fn chains(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("chain {}", i)).collect()
+    }
+
+    fn add_node(local_id: usize, genesis_hash: BlockHash) -> FromShardAggregator {
+        FromShardAggregator::AddNode {
+            ip: "127.0.0.1".parse().unwrap(),
+            node: NodeDetails {
+                chain: "Chain".into(),
+                name: "Node".into(),
+                implementation: "Impl".into(),
+                version: "1.0".into(),
+                validator: None,
+                network_id: None,
+                startup_time: None,
+                chain_type: None,
+                environment: None,
+                pruning_mode: None,
+                offchain_indexing: false,
+                pending_upgrade_block: None,
+                verbosity: None,
+                publicly_reachable: None,
+                message_schema: None,
+                extra_info: Default::default(),
+            },
+            local_id: ShardNodeId::from(local_id),
+            genesis_hash,
+            resumed: false,
+        }
+    }
+
+    /// Find a genesis hash owned by the given member of the cluster.
+    fn genesis_hash_owned_by(cluster: &Cluster, owner: Option<SocketAddr>) -> BlockHash {
+        (0..)
+            .map(BlockHash::from_low_u64_be)
+            .find(|hash| cluster.peer_owner(hash) == owner)
+            .unwrap()
+    }
+
+    fn forwarded(rx: &flume::Receiver<ToForwarder>) -> Vec<(SocketAddr, FromShardAggregator)> {
+        rx.try_iter()
+            .filter_map(|msg| match msg {
+                ToForwarder::Forward { peer, msg, .. } => Some((peer, msg)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn static_membership_excludes_self_and_duplicates() {
+        let membership = StaticMembership::new(addr(1), vec![addr(3), addr(1), addr(2), addr(3)]);
+        assert_eq!(membership.local(), addr(1));
+        assert_eq!(membership.peers(), vec![addr(2), addr(3)]);
+    }
+
+    #[test]
+    fn single_member_owns_everything() {
+        let ring = ring(1, &[]);
+        for chain in chains(100) {
+            assert_eq!(ring.owner(&chain), addr(1));
+        }
+    }
+
+    #[test]
+    fn every_member_agrees_on_owners() {
+        // Each core sees itself as local and the others as peers:
+        let a = ring(1, &[2, 3]);
+        let b = ring(2, &[3, 1]);
+        let c = ring(3, &[1, 2]);
+        for chain in chains(1000) {
+            assert_eq!(a.owner(&chain), b.owner(&chain));
+            assert_eq!(a.owner(&chain), c.owner(&chain));
+        }
+    }
+
+    #[test]
+    fn chains_are_shared_out_fairly_evenly() {
+        let ring = ring(1, &[2, 3]);
+        let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+        for chain in chains(3000) {
+            *counts.entry(ring.owner(&chain)).or_default() += 1;
+        }
+        assert_eq!(counts.len(), 3);
+        for (member, count) in counts {
+            assert!(count > 600, "{} only owns {} of 3000 chains", member, count);
+        }
+    }
+
+    #[test]
+    fn adding_a_member_only_moves_chains_to_it() {
+        let before = ring(1, &[2, 3]);
+        let after = ring(1, &[2, 3, 4]);
+        let mut moved = 0;
+        for chain in chains(1000) {
+            let (old, new) = (before.owner(&chain), after.owner(&chain));
+            if old != new {
+                assert_eq!(new, addr(4));
+                moved += 1;
+            }
+        }
+        assert!(moved > 0);
+    }
+
+    #[test]
+    fn nodes_on_local_chains_are_not_forwarded() {
+        let (cluster, rx) = Cluster::new(&StaticMembership::new(addr(1), vec![addr(2)]));
+        let cluster = Arc::new(cluster);
+        let mut forwarder = cluster.node_forwarder();
+        let hash = genesis_hash_owned_by(&cluster, None);
+
+        assert!(forwarder.handle(add_node(1, hash)).is_some());
+        let remove = FromShardAggregator::RemoveNode {
+            local_id: ShardNodeId::from(1),
+            reason: NodeCloseReason::ClientClosed,
+        };
+        assert!(forwarder.handle(remove).is_some());
+        drop(forwarder);
+
+        assert!(forwarded(&rx).is_empty());
+    }
+
+    #[test]
+    fn nodes_on_peer_chains_are_forwarded_until_removed() {
+        let (cluster, rx) = Cluster::new(&StaticMembership::new(addr(1), vec![addr(2)]));
+        let cluster = Arc::new(cluster);
+        let mut forwarder = cluster.node_forwarder();
+        let hash = genesis_hash_owned_by(&cluster, Some(addr(2)));
+
+        assert!(forwarder.handle(add_node(1, hash)).is_none());
+        let remove = FromShardAggregator::RemoveNode {
+            local_id: ShardNodeId::from(1),
+            reason: NodeCloseReason::ClientClosed,
+        };
+        assert!(forwarder.handle(remove.clone()).is_none());
+
+        // Once removed, we no longer know about the node:
+        assert!(forwarder.handle(remove).is_some());
+
+        let msgs = forwarded(&rx);
+        assert_eq!(msgs.len(), 2);
+        assert!(matches!(msgs[0], (a, FromShardAggregator::AddNode { .. }) if a == addr(2)));
+        assert!(matches!(msgs[1], (a, FromShardAggregator::RemoveNode { .. }) if a == addr(2)));
+    }
+
+    #[test]
+    fn forwarded_nodes_are_removed_when_forwarder_dropped() {
+        let (cluster, rx) = Cluster::new(&StaticMembership::new(addr(1), vec![addr(2)]));
+        let cluster = Arc::new(cluster);
+        let mut forwarder = cluster.node_forwarder();
+        let hash = genesis_hash_owned_by(&cluster, Some(addr(2)));
+
+        forwarder.handle(add_node(1, hash));
+        forwarder.handle(add_node(2, hash));
+        drop(forwarder);
+
+        let removed: Vec<_> = forwarded(&rx)
+            .into_iter()
+            .filter(|(_, msg)| {
+                matches!(
+                    msg,
+                    FromShardAggregator::RemoveNode {
+                        reason: NodeCloseReason::ShardDisconnected,
+                        ..
+                    }
+                )
+            })
+            .collect();
+        assert_eq!(removed.len(), 2);
+    }
+
+    #[test]
+    fn peer_ids_are_unique_across_shard_connections() {
+        let (tx, _rx) = flume::unbounded();
+        let mut peer = Peer::new(tx);
+        let hash = BlockHash::zero();
+
+        let id_of = |msg: Option<FromShardAggregator>| match msg {
+            Some(FromShardAggregator::AddNode { local_id, .. }) => local_id,
+            other => panic!("expected AddNode, got {:?}", other),
+        };
+
+        // The same shard-local ID from two different sources gets two different IDs:
+        let a = id_of(peer.remap(1, add_node(1, hash)));
+        let b = id_of(peer.remap(2, add_node(1, hash)));
+        assert_ne!(a, b);
+
+        // Removing a node we know nothing about is ignored:
+        let remove = FromShardAggregator::RemoveNode {
+            local_id: ShardNodeId::from(5),
+            reason: NodeCloseReason::ClientClosed,
+        };
+        assert!(peer.remap(1, remove).is_none());
+
+        // Removing a node we do know about forgets it:
+        let remove = FromShardAggregator::RemoveNode {
+            local_id: ShardNodeId::from(1),
+            reason: NodeCloseReason::ClientClosed,
+        };
+        assert!(peer.remap(1, remove).is_some());
+        assert_eq!(peer.added.len(), 1);
+    }
+}
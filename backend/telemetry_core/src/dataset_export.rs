@@ -0,0 +1,427 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Periodic, anonymized dumps of the nodes on each first party chain, for researchers
+//! interested in network topology and client diversity.
+//!
+//! Node records are exported via [`DatasetNode`], which only has fields for the things
+//! that we're happy to publish. Anything identifying (names, IP addresses, cities and
+//! coordinates) has no place to go, rather than being filtered out on the way.
+
+use crate::aggregator::AggregatorSet;
+use crate::state::{Node, StateChain, SYNCING_DISTANCE};
+use common::node_types::{BlockHash, BlockNumber, Timestamp};
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often a snapshot of each chain is exported.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Exported files are named `<PREFIX><unix timestamp in seconds><SUFFIX>`.
+const FILE_PREFIX: &str = "nodes-";
+const FILE_SUFFIX: &str = ".json.gz";
+
+pub struct DatasetExportOpts {
+    /// Snapshots are written to a subdirectory of this for each chain.
+    pub dir: PathBuf,
+    /// How many snapshots to keep for each chain; older ones are deleted.
+    pub keep_files: usize,
+    /// Network IDs are hashed along with this, so that they can't be recovered by
+    /// hashing known network IDs. If not given, a random salt is used, so hashes
+    /// can only be compared between snapshots exported by the same process.
+    pub salt: Option<String>,
+}
+
+/// Turns network IDs into stable, but anonymous, identifiers.
+pub struct NetworkIdHasher {
+    salt: [u8; 32],
+}
+
+// Keep the salt out of any logs:
+impl std::fmt::Debug for NetworkIdHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NetworkIdHasher").finish_non_exhaustive()
+    }
+}
+
+impl NetworkIdHasher {
+    pub fn new(salt: Option<&str>) -> NetworkIdHasher {
+        let salt = match salt {
+            Some(salt) => Sha256::digest(salt.as_bytes()).into(),
+            None => rand::random(),
+        };
+        NetworkIdHasher { salt }
+    }
+
+    pub fn hash(&self, network_id: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        hasher.update(network_id.as_bytes());
+        hex::encode(&hasher.finalize()[..16])
+    }
+}
+
+/// A snapshot of the nodes on one chain.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainDataset {
+    pub genesis_hash: BlockHash,
+    pub chain: Box<str>,
+    /// When the snapshot was taken, in milliseconds since the unix epoch.
+    pub exported_at: Timestamp,
+    pub nodes: Vec<DatasetNode>,
+}
+
+/// Everything that we export about a single node.
+#[derive(Debug, Clone, Serialize)]
+pub struct DatasetNode {
+    /// See [`NetworkIdHasher`]. `None` if the node didn't tell us its network ID.
+    pub network_id_hash: Option<String>,
+    pub implementation: Box<str>,
+    pub version: Box<str>,
+    /// The two letter country code of the node's location, if we know it.
+    pub country: Option<Box<str>>,
+    pub peers: u64,
+    pub best_height: BlockNumber,
+    pub finalized_height: BlockNumber,
+    pub sync_state: SyncState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncState {
+    /// The node is at or close to the best block of the chain.
+    Synced,
+    /// The node is some way behind the best block of the chain.
+    Syncing,
+    /// The node hasn't told us about a new block in a while.
+    Stale,
+}
+
+impl ChainDataset {
+    pub fn new(chain: &StateChain, hasher: &NetworkIdHasher, now: Timestamp) -> ChainDataset {
+        let best_height = chain.best_block().height;
+        ChainDataset {
+            genesis_hash: *chain.genesis_hash(),
+            chain: chain.label().into(),
+            exported_at: now,
+            nodes: chain
+                .nodes_slice()
+                .iter()
+                .flatten()
+                .map(|node| DatasetNode::new(node, best_height, hasher))
+                .collect(),
+        }
+    }
+}
+
+impl DatasetNode {
+    fn new(node: &Node, chain_best_height: BlockNumber, hasher: &NetworkIdHasher) -> DatasetNode {
+        let details = node.details();
+        let best_height = node.best().height;
+        let sync_state = if node.stale() {
+            SyncState::Stale
+        } else if best_height + SYNCING_DISTANCE < chain_best_height {
+            SyncState::Syncing
+        } else {
+            SyncState::Synced
+        };
+
+        DatasetNode {
+            network_id_hash: details.network_id.as_deref().map(|id| hasher.hash(id)),
+            implementation: details.implementation.clone(),
+            version: details.version.clone(),
+            country: node.location().and_then(|loc| loc.country.clone()),
+            peers: node.stats().peers,
+            best_height,
+            finalized_height: node.finalized().height,
+            sync_state,
+        }
+    }
+}
+
+/// Spawn a task which periodically exports a snapshot of each first party chain. Taking
+/// the snapshot is the only work done on the aggregator loop; serializing, compressing
+/// and writing it to disk all happen elsewhere.
+pub fn spawn_dataset_export_loop(aggregator: AggregatorSet, opts: DatasetExportOpts) {
+    let hasher = Arc::new(NetworkIdHasher::new(opts.salt.as_deref()));
+    let opts = Arc::new(opts);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+        // The first tick completes immediately, but few nodes will have connected yet:
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let datasets = match aggregator.dataset(hasher.clone()).await {
+                Ok(datasets) => datasets,
+                Err(e) => {
+                    log::error!("Error gathering dataset to export (bailing): {}", e);
+                    return;
+                }
+            };
+
+            let opts = opts.clone();
+            let written = tokio::task::spawn_blocking(move || {
+                for dataset in &datasets {
+                    if let Err(e) = write_dataset(&opts.dir, dataset, opts.keep_files) {
+                        log::error!("Error exporting dataset for {}: {:#}", dataset.chain, e);
+                    }
+                }
+            })
+            .await;
+            if let Err(e) = written {
+                log::error!("Error exporting datasets: {}", e);
+            }
+        }
+    });
+}
+
+/// Write a gzip compressed JSON snapshot of a chain to its own subdirectory of `dir`,
+/// deleting all but the latest `keep_files` snapshots of the chain. Returns the path
+/// of the file written.
+pub fn write_dataset(
+    dir: &Path,
+    dataset: &ChainDataset,
+    keep_files: usize,
+) -> anyhow::Result<PathBuf> {
+    let chain_dir = dir.join(format!("{:?}", dataset.genesis_hash));
+    fs::create_dir_all(&chain_dir)?;
+
+    let path = chain_dir.join(format!(
+        "{}{}{}",
+        FILE_PREFIX,
+        dataset.exported_at / 1000,
+        FILE_SUFFIX
+    ));
+    let mut encoder = GzEncoder::new(fs::File::create(&path)?, flate2::Compression::default());
+    serde_json::to_writer(&mut encoder, dataset)?;
+    encoder.finish()?.flush()?;
+
+    prune_datasets(&chain_dir, keep_files)?;
+    Ok(path)
+}
+
+/// Delete all but the newest `keep_files` snapshots in a chain's directory. Files that
+/// don't look like snapshots are left alone.
+fn prune_datasets(chain_dir: &Path, keep_files: usize) -> anyhow::Result<()> {
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(chain_dir)? {
+        let path = entry?.path();
+        let exported_at = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(FILE_PREFIX))
+            .and_then(|name| name.strip_suffix(FILE_SUFFIX))
+            .and_then(|secs| secs.parse::<u64>().ok());
+        if let Some(exported_at) = exported_at {
+            snapshots.push((exported_at, path));
+        }
+    }
+
+    snapshots.sort_unstable_by_key(|(exported_at, _)| std::cmp::Reverse(*exported_at));
+    for (_, path) in snapshots.into_iter().skip(keep_files) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{ChainOpts, State};
+    use common::node_message::Payload;
+    use common::node_types::{Block, NodeDetails, NodeLocation};
+    use flate2::read::GzDecoder;
+    use std::collections::BTreeSet;
+
+    fn node(name: &str, network_id: Option<&str>) -> NodeDetails {
+        NodeDetails {
+            chain: "Chain One".into(),
+            name: name.into(),
+            implementation: "Parity Polkadot".into(),
+            version: "0.9.10".into(),
+            validator: Some("5Validator".into()),
+            network_id: network_id.map(Into::into),
+            startup_time: Some("1625565542717".into()),
+            chain_type: None,
+            environment: None,
+            pruning_mode: None,
+            offchain_indexing: false,
+            pending_upgrade_block: None,
+            verbosity: None,
+            publicly_reachable: None,
+            message_schema: None,
+            extra_info: Default::default(),
+        }
+    }
+
+    fn chain_dataset() -> ChainDataset {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let alice = state
+            .add_node(genesis, node("Alice's Node", Some("12D3KooWAlice")))
+            .unwrap_id();
+        let bob = state
+            .add_node(genesis, node("Bob's Node", None))
+            .unwrap_id();
+        state.update_node_location(
+            alice,
+            Some(Arc::new(NodeLocation {
+                latitude: 52.5,
+                longitude: 13.4,
+                city: "Berlin".into(),
+                country: Some("DE".into()),
+            })),
+        );
+        for (node_id, height) in [(alice, 100), (bob, 20)] {
+            let block = Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            };
+            let mut feed = crate::feed_message::FeedMessageSerializer::new();
+            state.update_node(node_id, Payload::BlockImport(block), None, &mut feed);
+        }
+
+        let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+        ChainDataset::new(
+            &chain,
+            &NetworkIdHasher::new(Some("salt")),
+            1_600_000_000_000,
+        )
+    }
+
+    /// A fresh directory to export to.
+    fn export_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "telemetry-dataset-export-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn only_allowed_fields_are_exported() {
+        let dataset = chain_dataset();
+        let json = serde_json::to_value(&dataset).unwrap();
+
+        let keys = |value: &serde_json::Value| -> BTreeSet<String> {
+            value.as_object().unwrap().keys().cloned().collect()
+        };
+        let expected: BTreeSet<String> = [
+            "network_id_hash",
+            "implementation",
+            "version",
+            "country",
+            "peers",
+            "best_height",
+            "finalized_height",
+            "sync_state",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        for node in json["nodes"].as_array().unwrap() {
+            assert_eq!(keys(node), expected);
+        }
+
+        // Nothing identifying finds its way into the output in any form:
+        let output = json.to_string();
+        for disallowed in &[
+            "Alice",
+            "Bob",
+            "Berlin",
+            "52.5",
+            "13.4",
+            "12D3KooWAlice",
+            "5Validator",
+            "127.0.0.1",
+            "1625565542717",
+        ] {
+            assert!(
+                !output.contains(disallowed),
+                "{} found in {}",
+                disallowed,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn nodes_are_described_anonymously() {
+        let dataset = chain_dataset();
+        let alice = &dataset.nodes[0];
+        let bob = &dataset.nodes[1];
+
+        assert_eq!(&*dataset.chain, "Chain One");
+        assert_eq!(alice.country.as_deref(), Some("DE"));
+        assert_eq!(alice.best_height, 100);
+        assert_eq!(alice.sync_state, SyncState::Synced);
+        assert_eq!(
+            alice.network_id_hash,
+            Some(NetworkIdHasher::new(Some("salt")).hash("12D3KooWAlice"))
+        );
+        assert_eq!(bob.country, None);
+        assert_eq!(bob.network_id_hash, None);
+        assert_eq!(bob.sync_state, SyncState::Syncing);
+    }
+
+    #[test]
+    fn network_id_hashes_depend_on_the_salt() {
+        let a = NetworkIdHasher::new(Some("a"));
+        let b = NetworkIdHasher::new(Some("b"));
+        assert_eq!(a.hash("id"), NetworkIdHasher::new(Some("a")).hash("id"));
+        assert_ne!(a.hash("id"), b.hash("id"));
+        assert_ne!(a.hash("id"), a.hash("other id"));
+        assert_ne!(
+            NetworkIdHasher::new(None).hash("id"),
+            NetworkIdHasher::new(None).hash("id")
+        );
+    }
+
+    #[test]
+    fn datasets_are_written_compressed_and_old_ones_pruned() {
+        let dir = export_dir("prune");
+        let mut dataset = chain_dataset();
+
+        let mut written = Vec::new();
+        for hour in 0..5 {
+            dataset.exported_at = 1_600_000_000_000 + hour * 60 * 60 * 1000;
+            written.push(write_dataset(&dir, &dataset, 3).unwrap());
+        }
+
+        // Only the latest 3 snapshots are kept:
+        for (idx, path) in written.iter().enumerate() {
+            assert_eq!(path.exists(), idx >= 2, "{:?}", path);
+        }
+
+        let file = fs::File::open(written.last().unwrap()).unwrap();
+        let json: serde_json::Value = serde_json::from_reader(GzDecoder::new(file)).unwrap();
+        assert_eq!(json["chain"], "Chain One");
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
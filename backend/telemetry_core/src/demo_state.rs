@@ -0,0 +1,672 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A small, made up network that's kept "alive" for local frontend development, so that
+//! feeds have realistic looking data to show without any real nodes connecting.
+//!
+//! The network is loaded from a [`DemoState`] fixture file, and is then driven by a
+//! [`DemoNetwork`], which pretends to be a shard: nodes are added, import blocks, send
+//! interval updates and now and then disconnect and come back, all by way of the same
+//! messages that real shards send to the aggregators. Everything that happens is decided
+//! by a PRNG seeded with `--demo-seed`, so a given fixture and seed always play out the
+//! same way.
+
+use crate::aggregator::{AggregatorSet, FromShardWebsocket, IngestTimes, ToShardWebsocket};
+use common::internal_messages::{NodeCloseReason, ShardNodeId};
+use common::node_message::{Payload, SystemInterval};
+use common::node_types::{Block, BlockHash, BlockNumber, NodeDetails};
+use futures::SinkExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::time::Duration;
+
+/// How often the demo network moves on.
+const TICK: Duration = Duration::from_millis(500);
+
+/// Nodes send an interval update every this many ticks.
+const INTERVAL_EVERY_TICKS: u64 = 10;
+
+/// Blocks are finalized this many blocks behind the best block.
+const FINALITY_LAG: BlockNumber = 2;
+
+/// The chance that a node misses a new block, and so falls a little behind until the
+/// next one.
+const LAG_CHANCE: f64 = 0.1;
+
+/// The chance, each tick, that a node on a chain disconnects.
+const DISCONNECT_CHANCE: f64 = 0.005;
+
+/// The chance, each tick, that a disconnected node on a chain reconnects.
+const RECONNECT_CHANCE: f64 = 0.05;
+
+/// The network to pretend to have connected.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DemoState {
+    pub chains: Vec<DemoChain>,
+}
+
+/// A chain in a [`DemoState`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DemoChain {
+    pub label: Box<str>,
+    pub genesis_hash: BlockHash,
+    /// The height of the best block when the demo starts.
+    #[serde(default)]
+    pub best_block: BlockNumber,
+    /// How often a new block is produced.
+    #[serde(default = "default_block_time_ms")]
+    pub block_time_ms: u64,
+    pub nodes: Vec<DemoNode>,
+}
+
+/// A node in a [`DemoChain`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DemoNode {
+    pub name: Box<str>,
+    pub implementation: Box<str>,
+    pub version: Box<str>,
+    #[serde(default)]
+    pub validator: Option<Box<str>>,
+    #[serde(default)]
+    pub network_id: Option<Box<str>>,
+    /// The address that the node appears to connect from, which is used to locate it.
+    /// Localhost is already known to be in Berlin, so needs no lookup.
+    #[serde(default = "default_ip")]
+    pub ip: IpAddr,
+    /// Roughly how many peers the node reports having.
+    #[serde(default = "default_peers")]
+    pub peers: u64,
+}
+
+fn default_block_time_ms() -> u64 {
+    6000
+}
+
+fn default_ip() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn default_peers() -> u64 {
+    25
+}
+
+impl DemoState {
+    /// Load a demo state from a JSON fixture file.
+    pub fn load(path: &Path) -> anyhow::Result<DemoState> {
+        let file = std::fs::File::open(path)?;
+        let state: DemoState = serde_json::from_reader(std::io::BufReader::new(file))?;
+        for chain in &state.chains {
+            if chain.block_time_ms == 0 {
+                anyhow::bail!("{}: block_time_ms must be more than 0", chain.label);
+            }
+        }
+        Ok(state)
+    }
+}
+
+/// A node in the demo network.
+struct NetworkNode {
+    node: DemoNode,
+    /// The ID the node is known by while it's connected, or `None` if it's disconnected.
+    local_id: Option<ShardNodeId>,
+    best: Block,
+    /// The node has been muted, so there's no point in sending anything more about it.
+    muted: bool,
+}
+
+/// A chain in the demo network.
+struct NetworkChain {
+    label: Box<str>,
+    genesis_hash: BlockHash,
+    block_time_ms: u64,
+    /// Time since the last block was produced.
+    since_block_ms: u64,
+    /// The most recent blocks, newest last, going back to the finalized block.
+    blocks: VecDeque<Block>,
+    nodes: Vec<NetworkNode>,
+}
+
+impl NetworkChain {
+    fn best(&self) -> Block {
+        *self.blocks.back().expect("there is always a best block")
+    }
+    fn finalized(&self) -> Block {
+        *self.blocks.front().expect("there is always a best block")
+    }
+}
+
+/// Decides what happens on the demo network, and hands back the messages that a shard
+/// would send to the aggregators about it.
+pub struct DemoNetwork {
+    rng: StdRng,
+    chains: Vec<NetworkChain>,
+    ticks_since_interval: u64,
+    next_local_id: usize,
+}
+
+impl DemoNetwork {
+    pub fn new(state: DemoState, seed: u64) -> DemoNetwork {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let chains = state
+            .chains
+            .into_iter()
+            .map(|chain| {
+                let best = Block {
+                    hash: random_hash(&mut rng),
+                    height: chain.best_block,
+                };
+                NetworkChain {
+                    label: chain.label,
+                    genesis_hash: chain.genesis_hash,
+                    block_time_ms: chain.block_time_ms,
+                    since_block_ms: 0,
+                    blocks: std::iter::once(best).collect(),
+                    nodes: chain
+                        .nodes
+                        .into_iter()
+                        .map(|node| NetworkNode {
+                            node,
+                            local_id: None,
+                            best,
+                            muted: false,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        DemoNetwork {
+            rng,
+            chains,
+            ticks_since_interval: 0,
+            next_local_id: 0,
+        }
+    }
+
+    /// Connect every node in the network.
+    pub fn start(&mut self) -> Vec<FromShardWebsocket> {
+        let mut msgs = Vec::new();
+        for chain_idx in 0..self.chains.len() {
+            for node_idx in 0..self.chains[chain_idx].nodes.len() {
+                self.connect(chain_idx, node_idx, &mut msgs);
+            }
+        }
+        msgs
+    }
+
+    /// Move the network on by one tick.
+    pub fn tick(&mut self) -> Vec<FromShardWebsocket> {
+        self.ticks_since_interval += 1;
+        let send_intervals = self.ticks_since_interval >= INTERVAL_EVERY_TICKS;
+        if send_intervals {
+            self.ticks_since_interval = 0;
+        }
+        let mut msgs = Vec::new();
+
+        for chain_idx in 0..self.chains.len() {
+            // Some nodes come and go:
+            for node_idx in 0..self.chains[chain_idx].nodes.len() {
+                let node = &mut self.chains[chain_idx].nodes[node_idx];
+                match node.local_id {
+                    Some(local_id) if self.rng.gen_bool(DISCONNECT_CHANCE) => {
+                        node.local_id = None;
+                        msgs.push(FromShardWebsocket::Remove {
+                            local_id,
+                            reason: NodeCloseReason::ClientClosed,
+                        });
+                    }
+                    None if self.rng.gen_bool(RECONNECT_CHANCE) => {
+                        self.connect(chain_idx, node_idx, &mut msgs);
+                    }
+                    _ => {}
+                }
+            }
+
+            // New blocks are produced, and most nodes import them straight away:
+            let chain = &mut self.chains[chain_idx];
+            chain.since_block_ms += TICK.as_millis() as u64;
+            while chain.since_block_ms >= chain.block_time_ms {
+                chain.since_block_ms -= chain.block_time_ms;
+                let block = Block {
+                    hash: random_hash(&mut self.rng),
+                    height: chain.best().height + 1,
+                };
+                chain.blocks.push_back(block);
+                if chain.blocks.len() as BlockNumber > FINALITY_LAG + 1 {
+                    chain.blocks.pop_front();
+                }
+
+                for node in &mut chain.nodes {
+                    let local_id = match node.local_id {
+                        Some(local_id) if !node.muted => local_id,
+                        _ => continue,
+                    };
+                    if self.rng.gen_bool(LAG_CHANCE) {
+                        continue;
+                    }
+                    node.best = block;
+                    msgs.push(update(local_id, Payload::BlockImport(block)));
+                }
+            }
+
+            if send_intervals {
+                let finalized = chain.finalized();
+                for node in &chain.nodes {
+                    let local_id = match node.local_id {
+                        Some(local_id) if !node.muted => local_id,
+                        _ => continue,
+                    };
+                    let interval = interval(&mut self.rng, node, finalized);
+                    msgs.push(update(local_id, Payload::SystemInterval(interval)));
+                }
+            }
+        }
+
+        msgs
+    }
+
+    /// Handle a message that the aggregators send back to shards.
+    pub fn handle(&mut self, msg: ToShardWebsocket) -> Vec<FromShardWebsocket> {
+        let mut msgs = Vec::new();
+        let local_id = match msg {
+            ToShardWebsocket::Mute { local_id, .. } => local_id,
+            ToShardWebsocket::Resync { local_id } => local_id,
+            // Demo nodes don't send enough to be worth muting:
+            ToShardWebsocket::MuteChain { .. } | ToShardWebsocket::UnmuteChain { .. } => {
+                return msgs
+            }
+        };
+        let found = self
+            .chains
+            .iter()
+            .enumerate()
+            .find_map(|(chain_idx, chain)| {
+                let node_idx = chain
+                    .nodes
+                    .iter()
+                    .position(|node| node.local_id == Some(local_id))?;
+                Some((chain_idx, node_idx))
+            });
+        let (chain_idx, node_idx) = match found {
+            Some(found) => found,
+            None => return msgs,
+        };
+
+        match msg {
+            ToShardWebsocket::Mute { .. } => {
+                self.chains[chain_idx].nodes[node_idx].muted = true;
+            }
+            // As a real shard would, have the node tell the aggregators about itself
+            // again, under the ID that it's already known by:
+            ToShardWebsocket::Resync { .. } => {
+                let chain = &self.chains[chain_idx];
+                let node = &chain.nodes[node_idx];
+                msgs.push(add(local_id, chain, &node.node));
+                msgs.push(update(local_id, Payload::BlockImport(node.best)));
+            }
+            ToShardWebsocket::MuteChain { .. } | ToShardWebsocket::UnmuteChain { .. } => {}
+        }
+        msgs
+    }
+
+    fn connect(&mut self, chain_idx: usize, node_idx: usize, msgs: &mut Vec<FromShardWebsocket>) {
+        let local_id = ShardNodeId::from(self.next_local_id);
+        self.next_local_id += 1;
+
+        let chain = &mut self.chains[chain_idx];
+        let best = chain.best();
+        let node = &mut chain.nodes[node_idx];
+        node.local_id = Some(local_id);
+        node.best = best;
+        node.muted = false;
+
+        let chain = &self.chains[chain_idx];
+        msgs.push(add(local_id, chain, &chain.nodes[node_idx].node));
+        msgs.push(update(local_id, Payload::BlockImport(best)));
+    }
+}
+
+fn interval(rng: &mut StdRng, node: &NetworkNode, finalized: Block) -> SystemInterval {
+    let peers = node.node.peers;
+    SystemInterval {
+        peers: Some(rng.gen_range(peers.saturating_sub(3)..=peers + 3)),
+        txcount: Some(rng.gen_range(0..20)),
+        bandwidth_upload: Some(rng.gen_range(10_000.0..100_000.0)),
+        bandwidth_download: Some(rng.gen_range(10_000.0..100_000.0)),
+        finalized_height: Some(finalized.height),
+        finalized_hash: Some(finalized.hash),
+        block: Some(node.best),
+        used_state_cache_size: None,
+        offchain_worker_queue_depth: None,
+        swap_used_bytes: None,
+        swap_total_bytes: None,
+        wasm_heap_used_bytes: None,
+        wasm_heap_limit_bytes: None,
+        cpu_steal_pct: None,
+        kademlia_queries_per_sec: None,
+        kademlia_records_stored: None,
+        block_announce_per_sec: None,
+        transaction_per_sec: None,
+        light_request_per_sec: None,
+        load_avg_1m: None,
+        load_avg_5m: None,
+        load_avg_15m: None,
+        cpu_cores: None,
+        database_size_bytes: None,
+        open_fd_count: None,
+        fd_limit: None,
+        ntp_offset_ms: None,
+        avg_peer_reputation: None,
+        gpu_name: None,
+        gpu_usage_pct: None,
+        gpu_memory_used_bytes: None,
+        pallet_trie_sizes: None,
+    }
+}
+
+fn random_hash(rng: &mut StdRng) -> BlockHash {
+    BlockHash::from(rng.gen::<[u8; 32]>())
+}
+
+fn add(local_id: ShardNodeId, chain: &NetworkChain, node: &DemoNode) -> FromShardWebsocket {
+    FromShardWebsocket::Add {
+        local_id,
+        ip: node.ip,
+        node: NodeDetails {
+            chain: chain.label.clone(),
+            name: node.name.clone(),
+            implementation: node.implementation.clone(),
+            version: node.version.clone(),
+            validator: node.validator.clone(),
+            network_id: node.network_id.clone(),
+            startup_time: None,
+            chain_type: None,
+            environment: None,
+            pruning_mode: None,
+            offchain_indexing: false,
+            pending_upgrade_block: None,
+            verbosity: None,
+            publicly_reachable: None,
+            message_schema: None,
+            extra_info: Default::default(),
+        },
+        genesis_hash: chain.genesis_hash,
+        resumed: false,
+    }
+}
+
+fn update(local_id: ShardNodeId, payload: Payload) -> FromShardWebsocket {
+    FromShardWebsocket::Update {
+        local_id,
+        payload,
+        reported_at: None,
+        ingest: IngestTimes::received_now(common::time::now()),
+    }
+}
+
+/// Spawn a task which connects the demo network to the aggregators, as a shard would,
+/// and then keeps it ticking along.
+pub fn spawn_demo_network(aggregator: AggregatorSet, state: DemoState, seed: u64) {
+    tokio::spawn(async move {
+        let mut network = DemoNetwork::new(state, seed);
+        let mut tx_to_aggregator = aggregator.subscribe_shard();
+        let (tx_from_aggregator, rx_from_aggregator) = flume::unbounded();
+
+        let mut msgs = vec![FromShardWebsocket::Initialize {
+            channel: tx_from_aggregator,
+        }];
+        msgs.extend(network.start());
+
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            for msg in msgs.drain(..) {
+                if let Err(e) = tx_to_aggregator.send(msg).await {
+                    log::error!("Error sending demo network message (bailing): {}", e);
+                    return;
+                }
+            }
+
+            interval.tick().await;
+            for msg in rx_from_aggregator.try_iter() {
+                msgs.extend(network.handle(msg));
+            }
+            msgs.extend(network.tick());
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aggregator::{node_filter_from_query, AggregatorOpts};
+    use crate::feed_budget::FeedBudgets;
+    use crate::feed_priority::FeedPriorities;
+    use crate::state::ChainOpts;
+
+    fn demo_state() -> DemoState {
+        let json = r#"{
+            "chains": [
+                {
+                    "label": "Demo One",
+                    "genesis_hash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+                    "best_block": 1000,
+                    "block_time_ms": 1000,
+                    "nodes": [
+                        { "name": "Alice", "implementation": "Parity Polkadot", "version": "0.9.10", "network_id": "12D3KooWAlice" },
+                        { "name": "Bob", "implementation": "Parity Polkadot", "version": "0.9.11", "peers": 40 },
+                        { "name": "Charlie", "implementation": "Kagome", "version": "0.1.0", "validator": "5Charlie" }
+                    ]
+                },
+                {
+                    "label": "Demo Two",
+                    "genesis_hash": "0x0000000000000000000000000000000000000000000000000000000000000002",
+                    "nodes": [
+                        { "name": "Dave", "implementation": "Parity Polkadot", "version": "0.9.10" }
+                    ]
+                }
+            ]
+        }"#;
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// A summary of the messages sent, to compare runs by.
+    fn summarize(msgs: &[FromShardWebsocket]) -> Vec<String> {
+        msgs.iter()
+            .map(|msg| match msg {
+                // When updates are sent depends on the clock rather than the seed:
+                FromShardWebsocket::Update {
+                    local_id,
+                    payload,
+                    reported_at,
+                    ..
+                } => format!("Update({:?}, {:?}, {:?})", local_id, payload, reported_at),
+                msg => format!("{:?}", msg),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fixture_defaults_are_filled_in() {
+        let state = demo_state();
+        let chain = &state.chains[1];
+        assert_eq!(chain.best_block, 0);
+        assert_eq!(chain.block_time_ms, 6000);
+        assert_eq!(chain.nodes[0].ip, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(chain.nodes[0].peers, 25);
+
+        // Typos in fixtures are caught rather than ignored:
+        let typo = r#"{ "chains": [], "chian": [] }"#;
+        assert!(serde_json::from_str::<DemoState>(typo).is_err());
+    }
+
+    #[test]
+    fn same_seed_plays_out_the_same_way() {
+        let run = |seed| {
+            let mut network = DemoNetwork::new(demo_state(), seed);
+            let mut msgs = summarize(&network.start());
+            for _ in 0..200 {
+                msgs.extend(summarize(&network.tick()));
+            }
+            msgs
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    fn nodes_are_added_then_import_blocks_and_send_intervals() {
+        let mut network = DemoNetwork::new(demo_state(), 1);
+        let msgs = network.start();
+        let added = msgs
+            .iter()
+            .filter(|msg| matches!(msg, FromShardWebsocket::Add { .. }))
+            .count();
+        assert_eq!(added, 4);
+
+        let msgs: Vec<_> = (0..INTERVAL_EVERY_TICKS)
+            .flat_map(|_| network.tick())
+            .collect();
+        let imports = msgs.iter().filter(|msg| {
+            matches!(
+                msg,
+                FromShardWebsocket::Update {
+                    payload: Payload::BlockImport(_),
+                    ..
+                }
+            )
+        });
+        assert!(imports.count() > 0);
+        let intervals = msgs.iter().filter(|msg| {
+            matches!(
+                msg,
+                FromShardWebsocket::Update {
+                    payload: Payload::SystemInterval(_),
+                    ..
+                }
+            )
+        });
+        assert!(intervals.count() > 0);
+    }
+
+    #[test]
+    fn resync_adds_the_node_again_and_mute_silences_it() {
+        let mut network = DemoNetwork::new(demo_state(), 1);
+        network.start();
+        let local_id = network.chains[0].nodes[0].local_id.unwrap();
+
+        let msgs = network.handle(ToShardWebsocket::Resync { local_id });
+        assert!(matches!(
+            msgs[0],
+            FromShardWebsocket::Add { local_id: id, .. } if id == local_id
+        ));
+
+        network.handle(ToShardWebsocket::Mute {
+            local_id,
+            reason: common::internal_messages::MuteReason::Overquota,
+        });
+        for _ in 0..100 {
+            for msg in network.tick() {
+                if let FromShardWebsocket::Update { local_id: id, .. } = msg {
+                    assert_ne!(id, local_id, "muted nodes shouldn't send updates");
+                }
+            }
+        }
+    }
+
+    /// Run the demo network through a real aggregator, and check that the aggregator
+    /// ends up agreeing with the network about what's connected and where it's at.
+    #[tokio::test]
+    async fn aggregator_agrees_with_the_demo_network() {
+        let aggregator = AggregatorSet::spawn(
+            1,
+            AggregatorOpts {
+                denylist: Vec::new(),
+                max_queue_len: 100_000,
+                slow_message_threshold: None,
+                chain_opts: ChainOpts::default(),
+                metrics_chain_allowlist: Vec::new(),
+                feed_priorities: FeedPriorities::new(1000, &[]),
+                feed_budgets: FeedBudgets::new(None, &[]),
+                reconnect_debounce: None,
+                shard_restart_grace: None,
+                anonymizers: Default::default(),
+                anonymize_locations: None,
+                ingest_latency_warning: None,
+                max_chains: None,
+                feed_heartbeat_interval: None,
+                name_redactor: Default::default(),
+            },
+        )
+        .await
+        .unwrap();
+        let mut tx = aggregator.subscribe_shard();
+        let (channel, _rx) = flume::unbounded();
+        tx.send(FromShardWebsocket::Initialize { channel })
+            .await
+            .unwrap();
+
+        let mut network = DemoNetwork::new(demo_state(), 7);
+        let mut msgs = network.start();
+        for _ in 0..1000 {
+            msgs.extend(network.tick());
+        }
+        for msg in msgs {
+            tx.send(msg).await.unwrap();
+        }
+
+        for chain in &network.chains {
+            let mut expected: Vec<(String, BlockNumber)> = chain
+                .nodes
+                .iter()
+                .filter(|node| node.local_id.is_some())
+                .map(|node| (node.node.name.to_string(), node.best.height))
+                .collect();
+            expected.sort();
+
+            let filter = node_filter_from_query(None).unwrap();
+            let nodes = aggregator
+                .chain_nodes(chain.genesis_hash, filter)
+                .await
+                .unwrap();
+            let mut actual: Vec<(String, BlockNumber)> = nodes
+                .unwrap_or_default()
+                .iter()
+                .map(|node| (node.details.name.to_string(), node.best_block.height))
+                .collect();
+            actual.sort();
+            assert_eq!(actual, expected, "nodes on {}", chain.label);
+
+            // Every node might have missed the latest block, but the chain can't be
+            // behind any of its nodes or ahead of the network:
+            if let Some(highest) = expected.iter().map(|(_, height)| *height).max() {
+                let details = aggregator
+                    .chain_details(chain.genesis_hash)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert!(details.best_block.height >= highest);
+                assert!(details.best_block.height <= chain.best().height);
+            }
+        }
+    }
+}
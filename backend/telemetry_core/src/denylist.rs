@@ -0,0 +1,128 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A denylist of chain names which can be kept in a file, and reloaded whenever
+//! that file changes, so that chains can be denied without restarting.
+
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Load a denylist from a file listing one chain name per line. Names are matched
+/// exactly, so leading and trailing whitespace is trimmed. Blank lines and lines
+/// starting with a `#` are ignored.
+pub fn from_list_file(path: &Path) -> anyhow::Result<BTreeSet<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read denylist {}", path.display()))?;
+    Ok(parse_list(&contents))
+}
+
+/// Parse a denylist. See [`from_list_file`].
+fn parse_list(contents: &str) -> BTreeSet<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Check the denylist file every `interval`, and hand back the whole denylist whenever
+/// it changes (starting from `current`, which is presumably what the file held when it was
+/// first loaded). If the file can't be read, the last good denylist is kept. This stops
+/// once the returned receiver is dropped.
+pub fn watch(
+    path: PathBuf,
+    mut current: BTreeSet<String>,
+    interval: Duration,
+) -> flume::Receiver<BTreeSet<String>> {
+    let (tx, rx) = flume::unbounded();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if tx.is_disconnected() {
+                return;
+            }
+
+            let denylist = match from_list_file(&path) {
+                Ok(denylist) => denylist,
+                Err(e) => {
+                    log::warn!("Keeping the current denylist: {:#}", e);
+                    continue;
+                }
+            };
+            if denylist == current {
+                continue;
+            }
+
+            log::info!(
+                "Denylist changed; now denying {} chains: {:?}",
+                denylist.len(),
+                denylist
+            );
+            current = denylist.clone();
+            if tx.send(denylist).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn can_parse_list() {
+        let denylist = parse_list("# Spam chains:\nSpam Testnet\n  Eggs \n\n  # Eggs\nChain #5\n");
+        assert_eq!(denylist, names(&["Spam Testnet", "Eggs", "Chain #5"]));
+    }
+
+    #[tokio::test]
+    async fn changes_to_the_file_are_noticed() {
+        let path =
+            std::env::temp_dir().join(format!("telemetry-denylist-test-{}", std::process::id()));
+        std::fs::write(&path, "Spam\n").unwrap();
+        let initial = from_list_file(&path).unwrap();
+        let rx = watch(path.clone(), initial, Duration::from_millis(10));
+
+        std::fs::write(&path, "Spam\nEggs\n").unwrap();
+        let denylist = tokio::time::timeout(Duration::from_secs(5), rx.recv_async())
+            .await
+            .expect("denylist change should be noticed")
+            .unwrap();
+        assert_eq!(denylist, names(&["Spam", "Eggs"]));
+
+        // If the file goes missing, the denylist is left alone until it's back:
+        std::fs::remove_file(&path).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(rx.is_empty());
+        std::fs::write(&path, "").unwrap();
+        let denylist = tokio::time::timeout(Duration::from_secs(5), rx.recv_async())
+            .await
+            .expect("denylist change should be noticed")
+            .unwrap();
+        assert!(denylist.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Large chains can generate far more feed messages than small ones. A chain can be
+//! given a budget of feed messages per second; once it has used up its budget, only
+//! high priority messages (see [`crate::feed_priority`]) about it are sent to feeds
+//! until the next second starts. Dropped messages are mostly regular updates that the
+//! next update supersedes, so feeds see them coalesced rather than lose anything.
+
+use crate::feed_priority::Priority;
+use anyhow::anyhow;
+use common::node_types::{BlockHash, Timestamp};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// How long each budget lasts for before it's topped up again.
+const BUDGET_WINDOW_MS: Timestamp = 1000;
+
+/// Give the chain with some genesis hash a budget, in the form `<genesis_hash>=<messages>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChainFeedBudget {
+    genesis_hash: BlockHash,
+    messages_per_sec: u64,
+}
+
+impl FromStr for ChainFeedBudget {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hash, messages) = match s.find('=') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => return Err(anyhow!("Expecting format `genesis_hash=messages_per_sec`")),
+        };
+        let genesis_hash = crate::api::parse_genesis_hash(hash.trim())
+            .ok_or_else(|| anyhow!("'{}' is not a genesis hash", hash))?;
+        let messages_per_sec = messages
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("'{}' is not a number of messages: {}", messages, e))?;
+        Ok(ChainFeedBudget {
+            genesis_hash,
+            messages_per_sec,
+        })
+    }
+}
+
+/// How many feed messages per second each chain is allowed to send.
+#[derive(Debug, Clone, Default)]
+pub struct FeedBudgets {
+    /// The budget of chains that aren't given one of their own. `None` means no limit.
+    default: Option<u64>,
+    per_chain: HashMap<BlockHash, u64>,
+}
+
+impl FeedBudgets {
+    pub fn new(default: Option<u64>, budgets: &[ChainFeedBudget]) -> Self {
+        FeedBudgets {
+            default,
+            per_chain: budgets
+                .iter()
+                .map(|b| (b.genesis_hash, b.messages_per_sec))
+                .collect(),
+        }
+    }
+
+    /// The budget of the chain with the given genesis hash, if it has one.
+    pub fn budget(&self, genesis_hash: &BlockHash) -> Option<u64> {
+        self.per_chain.get(genesis_hash).copied().or(self.default)
+    }
+}
+
+/// How much of its budget a chain has used up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BudgetUsage {
+    window_start: Timestamp,
+    sent: u64,
+}
+
+impl BudgetUsage {
+    /// We're about to send some messages about a chain to its feeds. Given the chain's
+    /// budget, what's the lowest priority of message that we should still send?
+    pub fn lowest_priority_sent(
+        &mut self,
+        budget: u64,
+        messages: usize,
+        now: Timestamp,
+    ) -> Priority {
+        if now.saturating_sub(self.window_start) >= BUDGET_WINDOW_MS {
+            self.window_start = now;
+            self.sent = 0;
+        }
+        if self.sent >= budget {
+            return Priority::High;
+        }
+        self.sent += messages as u64;
+        Priority::Low
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chain_budgets_can_be_parsed() {
+        let budget: ChainFeedBudget =
+            "0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3 = 500"
+                .parse()
+                .unwrap();
+        assert_eq!(budget.messages_per_sec, 500);
+
+        assert!("0x1234=500".parse::<ChainFeedBudget>().is_err());
+        assert!("500".parse::<ChainFeedBudget>().is_err());
+        assert!(
+            "0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3=lots"
+                .parse::<ChainFeedBudget>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn chains_use_their_own_budget_or_the_default() {
+        let big = BlockHash::from_low_u64_be(1);
+        let other = BlockHash::from_low_u64_be(2);
+        let budgets = FeedBudgets::new(
+            Some(100),
+            &[ChainFeedBudget {
+                genesis_hash: big,
+                messages_per_sec: 10,
+            }],
+        );
+        assert_eq!(budgets.budget(&big), Some(10));
+        assert_eq!(budgets.budget(&other), Some(100));
+        assert_eq!(FeedBudgets::default().budget(&other), None);
+    }
+
+    #[test]
+    fn only_high_priority_messages_once_budget_is_used() {
+        let mut usage = BudgetUsage::default();
+        assert_eq!(usage.lowest_priority_sent(10, 6, 5000), Priority::Low);
+        assert_eq!(usage.lowest_priority_sent(10, 6, 5100), Priority::Low);
+        assert_eq!(usage.lowest_priority_sent(10, 1, 5200), Priority::High);
+        assert_eq!(usage.lowest_priority_sent(10, 1, 5999), Priority::High);
+
+        // The budget is topped up again a second later:
+        assert_eq!(usage.lowest_priority_sent(10, 1, 6000), Priority::Low);
+    }
+}
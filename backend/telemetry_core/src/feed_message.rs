@@ -19,9 +19,11 @@
 
 use serde::Serialize;
 
-use crate::state::Node;
+use crate::name_redaction::NameRedactor;
+use crate::state::{self, ActiveAlert, AlertKind, Distribution, Node};
 use common::node_types::{
-    BlockDetails, BlockHash, BlockNumber, NodeHardware, NodeIO, NodeStats, Timestamp,
+    BlockAge, BlockDetails, BlockHash, BlockNumber, ChainType, NodeHardware, NodeIO, NodeStats,
+    Timestamp,
 };
 use serde_json::to_writer;
 
@@ -30,6 +32,12 @@ type FeedNodeId = usize;
 
 pub trait FeedMessage {
     const ACTION: u8;
+
+    /// If the message is about a single node rather than the chain
+    /// as a whole, this returns the ID of that node.
+    fn node_id(&self) -> Option<FeedNodeId> {
+        None
+    }
 }
 
 pub trait FeedMessageWrite: FeedMessage {
@@ -48,6 +56,8 @@ where
 pub struct FeedMessageSerializer {
     /// Current buffer,
     buffer: Vec<u8>,
+    /// Where each message starts in the buffer, its action, and which node (if any) it's about.
+    messages: Vec<(usize, u8, Option<FeedNodeId>)>,
 }
 
 const BUFCAP: usize = 128;
@@ -56,6 +66,7 @@ impl FeedMessageSerializer {
     pub fn new() -> Self {
         Self {
             buffer: Vec::with_capacity(BUFCAP),
+            messages: Vec::new(),
         }
     }
 
@@ -68,6 +79,8 @@ impl FeedMessageSerializer {
             _ => b',',
         };
 
+        self.messages
+            .push((self.buffer.len(), Message::ACTION, msg.node_id()));
         self.buffer.push(glue);
         self.write(&Message::ACTION);
         self.buffer.push(b',');
@@ -81,6 +94,11 @@ impl FeedMessageSerializer {
         let _ = to_writer(&mut self.buffer, value);
     }
 
+    /// How many messages have been pushed so far.
+    pub fn message_count(&self) -> usize {
+        self.messages.len()
+    }
+
     /// Return the bytes that we've serialized so far, consuming the serializer.
     pub fn into_finalized(mut self) -> Option<bytes::Bytes> {
         if self.buffer.is_empty() {
@@ -90,29 +108,76 @@ impl FeedMessageSerializer {
         self.buffer.push(b']');
         Some(self.buffer.into())
     }
+
+    /// Return the bytes for only those messages for which the filter, given the
+    /// message action and the node (if any) that it's about, returns true. This
+    /// leaves the serializer untouched, so that feeds which want different subsets
+    /// of messages can each be given theirs.
+    pub fn finalized_where<F>(&self, mut filter: F) -> Option<bytes::Bytes>
+    where
+        F: FnMut(u8, Option<FeedNodeId>) -> bool,
+    {
+        let mut buffer = Vec::with_capacity(self.buffer.len());
+        for (idx, &(start, action, node_id)) in self.messages.iter().enumerate() {
+            if !filter(action, node_id) {
+                continue;
+            }
+            let end = match self.messages.get(idx + 1) {
+                Some(&(next_start, _, _)) => next_start,
+                None => self.buffer.len(),
+            };
+            // Skip the glue that this message was written with, and add our own:
+            buffer.push(if buffer.is_empty() { b'[' } else { b',' });
+            buffer.extend_from_slice(&self.buffer[start + 1..end]);
+        }
+
+        if buffer.is_empty() {
+            return None;
+        }
+
+        buffer.push(b']');
+        Some(buffer.into())
+    }
 }
 
+pub use common::FEED_VERSION;
+
 macro_rules! actions {
-    ($($action:literal: $t:ty,)*) => {
+    ($($action:literal: $t:ident $(<$lt:lifetime>)? $([$about:ident])?,)*) => {
         $(
-            impl FeedMessage for $t {
+            impl FeedMessage for $t $(<$lt>)? {
                 const ACTION: u8 = $action;
+                $(node_id_fn!($about);)?
             }
         )*
+
+        /// The action and name of every feed message. Used to look messages up by
+        /// name when configuring their priority, and to check that the feed schema
+        /// describes each of them.
+        pub const ACTIONS: &[(u8, &str)] = &[$(($action, stringify!($t)),)*];
     }
 }
 
+/// Messages marked with `[node]` in [`actions!`] are about the node whose ID is their first field.
+macro_rules! node_id_fn {
+    (node) => {
+        fn node_id(&self) -> Option<FeedNodeId> {
+            Some(self.0)
+        }
+    };
+}
+
 actions! {
      0: Version,
      1: BestBlock,
      2: BestFinalized,
-     3: AddedNode<'_>,
-     4: RemovedNode,
-     5: LocatedNode<'_>,
-     6: ImportedBlock<'_>,
-     7: FinalizedBlock,
-     8: NodeStatsUpdate<'_>,
-     9: Hardware<'_>,
+     3: AddedNode<'_> [node],
+     4: RemovedNode [node],
+     5: LocatedNode<'_> [node],
+     6: ImportedBlock<'_> [node],
+     7: FinalizedBlock [node],
+     8: NodeStatsUpdate<'_> [node],
+     9: Hardware<'_> [node],
     10: TimeSync,
     11: AddedChain<'_>,
     12: RemovedChain<'_>,
@@ -123,8 +188,26 @@ actions! {
     17: AfgReceivedPrevote,
     18: AfgReceivedPrecommit,
     19: AfgAuthoritySet,
-    20: StaleNode,
-    21: NodeIOUpdate<'_>,
+    20: StaleNode [node],
+    21: NodeIOUpdate<'_> [node],
+    22: ChainDistribution<'_>,
+    23: BestBlockAge,
+    24: NodeAlert<'_> [node],
+    25: NodeAlertCleared [node],
+    26: NodeCountHistory<'_>,
+    27: NodeCountSample,
+    28: NodesAtBest,
+    29: SessionKey,
+    30: BatchSignature,
+    31: ChainStats<'_>,
+    32: ServerShutdown,
+    33: BlockProductionRate,
+    34: UpgradeScheduled<'_>,
+    35: UpgradeExecuted<'_>,
+    36: SubscriberCount<'_>,
+    37: Heartbeat,
+    38: PublicNodeShortage<'_>,
+    39: PublicNodeShortageResolved<'_>,
 }
 
 #[derive(Serialize)]
@@ -133,10 +216,16 @@ pub struct Version(pub usize);
 #[derive(Serialize)]
 pub struct BestBlock(pub BlockNumber, pub Timestamp, pub Option<u64>);
 
+/// The best block of a chain, along with how long ago it was produced
+/// (relative to the server time when the message is sent).
+#[derive(Serialize)]
+pub struct BestBlockAge(pub BlockAge);
+
 #[derive(Serialize)]
 pub struct BestFinalized(pub BlockNumber, pub BlockHash);
 
-pub struct AddedNode<'a>(pub FeedNodeId, pub &'a Node);
+/// A node has been added, with its name redacted as the given [`NameRedactor`] says.
+pub struct AddedNode<'a>(pub FeedNodeId, pub &'a Node, pub &'a NameRedactor);
 
 #[derive(Serialize)]
 pub struct RemovedNode(pub FeedNodeId);
@@ -148,10 +237,17 @@ pub struct LocatedNode<'a>(pub FeedNodeId, pub f32, pub f32, pub &'a str);
 pub struct ImportedBlock<'a>(pub FeedNodeId, pub &'a BlockDetails);
 
 #[derive(Serialize)]
-pub struct FinalizedBlock(pub FeedNodeId, pub BlockNumber, pub BlockHash);
+pub struct FinalizedBlock(
+    pub FeedNodeId,
+    pub BlockNumber,
+    pub BlockHash,
+    /// When the node told us about the block, by our clock.
+    pub Option<Timestamp>,
+);
 
+/// A node's stats, along with the peer counts it has reported over time.
 #[derive(Serialize)]
-pub struct NodeStatsUpdate<'a>(pub FeedNodeId, pub &'a NodeStats);
+pub struct NodeStatsUpdate<'a>(pub FeedNodeId, pub &'a NodeStats, pub &'a [u64]);
 
 #[derive(Serialize)]
 pub struct NodeIOUpdate<'a>(pub FeedNodeId, pub &'a NodeIO);
@@ -162,8 +258,9 @@ pub struct Hardware<'a>(pub FeedNodeId, pub &'a NodeHardware);
 #[derive(Serialize)]
 pub struct TimeSync(pub u64);
 
+/// A chain's label, node count and type (if known).
 #[derive(Serialize)]
-pub struct AddedChain<'a>(pub &'a str, pub usize);
+pub struct AddedChain<'a>(pub &'a str, pub usize, pub Option<ChainType>, pub bool);
 
 #[derive(Serialize)]
 pub struct RemovedChain<'a>(pub &'a str);
@@ -208,17 +305,22 @@ pub struct AfgAuthoritySet(
 #[derive(Serialize)]
 pub struct StaleNode(pub FeedNodeId);
 
+#[derive(Serialize)]
+pub struct ChainDistribution<'a>(pub &'a Distribution);
+
 impl FeedMessageWrite for AddedNode<'_> {
     fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
-        let AddedNode(nid, node) = self;
+        let AddedNode(nid, node, redactor) = self;
 
         let details = node.details();
         let details = (
-            &details.name,
+            redactor.redact(&details.name),
             &details.implementation,
             &details.version,
             &details.validator,
             &details.network_id,
+            details.offchain_indexing,
+            &details.extra_info,
         );
 
         ser.write(&(
@@ -230,6 +332,121 @@ impl FeedMessageWrite for AddedNode<'_> {
             node.block_details(),
             &node.location(),
             &node.startup_time(),
+            node.peer_history().slice(),
+        ));
+    }
+}
+
+/// An alert has been raised against a node (or its severity has changed).
+pub struct NodeAlert<'a>(pub FeedNodeId, pub &'a ActiveAlert);
+
+impl FeedMessageWrite for NodeAlert<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let NodeAlert(nid, active) = self;
+        ser.write(&(
+            nid,
+            active.alert.kind(),
+            active.severity,
+            active.alert.value(),
+            active.raised_at,
         ));
     }
 }
+
+/// An alert raised against a node has been cleared.
+#[derive(Serialize)]
+pub struct NodeAlertCleared(pub FeedNodeId, pub AlertKind);
+
+/// The recent node count history of a chain, oldest sample first.
+#[derive(Serialize)]
+pub struct NodeCountHistory<'a>(pub &'a state::NodeCountHistory);
+
+/// A new sample of the node count of a chain.
+#[derive(Serialize)]
+pub struct NodeCountSample(pub state::NodeCountSample);
+
+/// How many nodes have caught up to the chain's best block, out of how many
+/// nodes (excluding those that are syncing) could have.
+pub struct NodesAtBest(pub state::NodesAtBest);
+
+impl FeedMessageWrite for NodesAtBest {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let NodesAtBest(nodes) = self;
+        ser.write(&(nodes.height, nodes.caught_up, nodes.total));
+    }
+}
+
+/// The key that every later batch sent to a feed is signed with, sent first to feeds
+/// that ask for signed batches. See [`common::feed_signing`].
+pub struct SessionKey(pub [u8; 32]);
+
+impl FeedMessageWrite for SessionKey {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        ser.write(&hex::encode(self.0));
+    }
+}
+
+/// Appended to each batch sent to feeds that ask for signed batches: when the batch
+/// was signed (in ms since the unix epoch, always increasing), and the HMAC of the
+/// batch without this message.
+pub struct BatchSignature(pub u64, pub [u8; 32]);
+
+impl FeedMessageWrite for BatchSignature {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let BatchSignature(signed_ts, signature) = self;
+        ser.write(&(signed_ts, hex::encode(signature)));
+    }
+}
+
+/// The stats of a chain: either every field (if the second field is true), or only
+/// those which have changed since the last message about the chain. The fourth field is
+/// how many nodes are on each continent, which is only given every so often. The pallets
+/// taking up the most state trie space follow, but only when they've changed (or the
+/// message is full), and are left out altogether otherwise.
+#[derive(Serialize)]
+pub struct ChainStats<'a>(
+    pub &'a str,
+    pub bool,
+    pub &'a [state::ChainStatsValue],
+    pub Option<&'a [(Box<str>, u32)]>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub Option<&'a [(Box<str>, u64)]>,
+);
+
+/// The server is shutting down, and expects to be back in this many seconds, if it knows.
+/// Nothing more is sent after this, and the connection is closed.
+#[derive(Serialize)]
+pub struct ServerShutdown(pub Option<u32>);
+
+/// How many blocks we estimate the chain is producing each second, based on the
+/// intervals between its recent best blocks.
+#[derive(Serialize)]
+pub struct BlockProductionRate(pub f64);
+
+/// Nodes on the chain with the given label have told us that a runtime upgrade is
+/// scheduled for a block, which is given along with the chain's current best block.
+#[derive(Serialize)]
+pub struct UpgradeScheduled<'a>(pub &'a str, pub BlockNumber, pub BlockNumber);
+
+/// The best block of the chain with the given label has reached the block that a
+/// runtime upgrade was scheduled for.
+#[derive(Serialize)]
+pub struct UpgradeExecuted<'a>(pub &'a str, pub BlockNumber);
+
+/// How many feeds are subscribed to the chain with the given label.
+#[derive(Serialize)]
+pub struct SubscriberCount<'a>(pub &'a str, pub u32);
+
+/// Nothing else has been sent to the feed for a while, but we're still here. Given the
+/// current time, by our clock.
+#[derive(Serialize)]
+pub struct Heartbeat(pub Timestamp);
+
+/// The chain with the given label has dropped below the minimum number of publicly
+/// reachable nodes, and has this many left.
+#[derive(Serialize)]
+pub struct PublicNodeShortage<'a>(pub &'a str, pub usize);
+
+/// The chain with the given label has enough publicly reachable nodes again, and has
+/// this many.
+#[derive(Serialize)]
+pub struct PublicNodeShortageResolved<'a>(pub &'a str, pub usize);
@@ -0,0 +1,219 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! When a feed falls behind, some messages matter more than others. Losing a
+//! hardware update is fine, but losing a block import leaves the UI confused.
+//! Each feed message is given a priority, and the messages with the lowest
+//! priority are dropped first as a feed's queue of unsent messages fills up.
+
+use crate::feed_message::ACTIONS;
+use anyhow::anyhow;
+use std::str::FromStr;
+
+/// How important it is that a feed receives some type of message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Dropped once a feed's queue is half full.
+    Low,
+    /// Dropped once a feed's queue is full.
+    Normal,
+    /// Never dropped.
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(anyhow!(
+                "'{}' is not a valid priority; expecting 'low', 'normal' or 'high'",
+                s
+            )),
+        }
+    }
+}
+
+/// Messages that are sent often and which the UI can happily do without for a while.
+const LOW_PRIORITY: &[&str] = &[
+    "NodeStatsUpdate",
+    "Hardware",
+    "NodeIOUpdate",
+    "ChainDistribution",
+];
+
+/// Messages about blocks and about which nodes and chains exist; missing any of
+/// these leaves the UI showing the wrong thing until it reconnects.
+const HIGH_PRIORITY: &[&str] = &[
+    "Version",
+    "BestBlock",
+    "BestFinalized",
+    "AddedNode",
+    "RemovedNode",
+    "ImportedBlock",
+    "FinalizedBlock",
+    "AddedChain",
+    "RemovedChain",
+    "SubscribedTo",
+    "UnsubscribedFrom",
+];
+
+/// Look up the action of the feed message with the given name.
+fn action_for_name(name: &str) -> Option<u8> {
+    ACTIONS
+        .iter()
+        .find(|(_, n)| n.eq_ignore_ascii_case(name))
+        .map(|(action, _)| *action)
+}
+
+/// Give a feed message a different priority to its default, in the form
+/// `MessageName=priority` (for example `Hardware=normal`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriorityOverride {
+    action: u8,
+    priority: Priority,
+}
+
+impl FromStr for PriorityOverride {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, priority) = match s.find('=') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => return Err(anyhow!("Expecting format `MessageName=priority`")),
+        };
+        let action = action_for_name(name.trim())
+            .ok_or_else(|| anyhow!("'{}' is not the name of a feed message", name))?;
+        Ok(PriorityOverride {
+            action,
+            priority: priority.parse()?,
+        })
+    }
+}
+
+/// The priority of each type of feed message, and how large a feed's queue
+/// can get before messages start being dropped.
+#[derive(Debug, Clone)]
+pub struct FeedPriorities {
+    /// Indexed by message action.
+    priorities: Vec<Priority>,
+    /// Once this many messages are waiting to be sent to a feed, only
+    /// high priority messages are sent.
+    max_queue_len: usize,
+}
+
+impl FeedPriorities {
+    /// Use the default priorities, along with any overrides given.
+    pub fn new(max_queue_len: usize, overrides: &[PriorityOverride]) -> Self {
+        let len = ACTIONS
+            .iter()
+            .map(|(a, _)| *a as usize + 1)
+            .max()
+            .unwrap_or(0);
+        let mut priorities = vec![Priority::Normal; len];
+        for (names, priority) in &[
+            (LOW_PRIORITY, Priority::Low),
+            (HIGH_PRIORITY, Priority::High),
+        ] {
+            for name in names.iter() {
+                let action =
+                    action_for_name(name).expect("default priorities are for known messages");
+                priorities[action as usize] = *priority;
+            }
+        }
+        for o in overrides {
+            priorities[o.action as usize] = o.priority;
+        }
+        FeedPriorities {
+            priorities,
+            max_queue_len,
+        }
+    }
+
+    /// The priority of the feed message with the given action.
+    pub fn priority(&self, action: u8) -> Priority {
+        self.priorities
+            .get(action as usize)
+            .copied()
+            .unwrap_or(Priority::Normal)
+    }
+
+    /// Given how many messages are already waiting to be sent to a feed, what's
+    /// the lowest priority of message that we should still send to it?
+    pub fn lowest_priority_sent(&self, queue_len: usize) -> Priority {
+        if queue_len >= self.max_queue_len {
+            Priority::High
+        } else if queue_len >= self.max_queue_len / 2 {
+            Priority::Normal
+        } else {
+            Priority::Low
+        }
+    }
+}
+
+impl Default for FeedPriorities {
+    fn default() -> Self {
+        FeedPriorities::new(1000, &[])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn action(name: &str) -> u8 {
+        action_for_name(name).unwrap()
+    }
+
+    #[test]
+    fn default_priorities() {
+        let p = FeedPriorities::default();
+        assert_eq!(p.priority(action("Hardware")), Priority::Low);
+        assert_eq!(p.priority(action("NodeStatsUpdate")), Priority::Low);
+        assert_eq!(p.priority(action("StaleNode")), Priority::Normal);
+        assert_eq!(p.priority(action("ImportedBlock")), Priority::High);
+        assert_eq!(p.priority(action("BestFinalized")), Priority::High);
+    }
+
+    #[test]
+    fn priorities_can_be_overridden() {
+        let overrides: Vec<PriorityOverride> = vec![
+            "Hardware=high".parse().unwrap(),
+            "importedblock = Low".parse().unwrap(),
+        ];
+        let p = FeedPriorities::new(1000, &overrides);
+        assert_eq!(p.priority(action("Hardware")), Priority::High);
+        assert_eq!(p.priority(action("ImportedBlock")), Priority::Low);
+        // Others are left alone:
+        assert_eq!(p.priority(action("NodeStatsUpdate")), Priority::Low);
+
+        assert!("Hardware".parse::<PriorityOverride>().is_err());
+        assert!("NotAMessage=low".parse::<PriorityOverride>().is_err());
+        assert!("Hardware=urgent".parse::<PriorityOverride>().is_err());
+    }
+
+    #[test]
+    fn lower_priorities_are_dropped_first_as_queue_fills() {
+        let p = FeedPriorities::new(100, &[]);
+        assert_eq!(p.lowest_priority_sent(0), Priority::Low);
+        assert_eq!(p.lowest_priority_sent(49), Priority::Low);
+        assert_eq!(p.lowest_priority_sent(50), Priority::Normal);
+        assert_eq!(p.lowest_priority_sent(99), Priority::Normal);
+        assert_eq!(p.lowest_priority_sent(100), Priority::High);
+        assert_eq!(p.lowest_priority_sent(10_000), Priority::High);
+    }
+}
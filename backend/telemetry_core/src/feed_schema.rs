@@ -0,0 +1,833 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A machine readable description of the messages in [`crate::feed_message`],
+//! so that third parties can decode the compact feed encoding without having
+//! to reverse engineer it.
+//!
+//! Feed messages are sent as a flat JSON array of `action, value` pairs. Each
+//! message below describes the shape of the value for a given action. This
+//! registry is maintained by hand; the tests check that it describes every
+//! feed message, and that the messages we actually serialize match it.
+
+use crate::feed_message::FEED_VERSION;
+use serde::Serialize;
+
+/// The complete feed schema.
+#[derive(Serialize)]
+pub struct FeedSchema {
+    /// The current feed protocol version.
+    pub version: usize,
+    /// Every message that can be sent to a feed.
+    pub messages: &'static [MessageSchema],
+}
+
+/// A description of a single feed message.
+#[derive(Serialize)]
+pub struct MessageSchema {
+    /// The action ID that precedes this message in the feed.
+    pub action: u8,
+    /// The name of the message.
+    pub name: &'static str,
+    /// The feed version in which this message first appeared in its current form.
+    pub since_version: usize,
+    /// The shape of the message value.
+    pub value: Element,
+}
+
+/// A named element in a message.
+#[derive(Serialize)]
+pub struct Element {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub ty: Type,
+}
+
+/// The type of an element.
+#[derive(Serialize)]
+#[serde(tag = "kind", content = "of", rename_all = "snake_case")]
+pub enum Type {
+    /// An unsigned integer.
+    U64,
+    /// A signed integer.
+    I64,
+    /// A floating point number.
+    F32,
+    /// A floating point number.
+    F64,
+    /// `true` or `false`.
+    Bool,
+    /// A string.
+    String,
+    /// A 32 byte hash, encoded as a "0x" prefixed hex string.
+    Hash,
+    /// Either `null` or a value of the given type.
+    Nullable(&'static Type),
+    /// A value of the given type at the end of a tuple, which is left out of the tuple
+    /// altogether when there's nothing to give.
+    Optional(&'static Type),
+    /// A variable length array of values of the given type.
+    Array(&'static Type),
+    /// A fixed length array whose values are described by the given elements.
+    Tuple(&'static [Element]),
+    /// A JSON object with the given fields.
+    Object(&'static [Element]),
+    /// A JSON object whose string keys map to values of the given type.
+    Map(&'static Type),
+    /// Any JSON value at all; its shape is up to whoever provided it.
+    Json,
+}
+
+/// The feed schema.
+pub fn feed_schema() -> FeedSchema {
+    FeedSchema {
+        version: FEED_VERSION,
+        messages: MESSAGES,
+    }
+}
+
+const fn el(name: &'static str, ty: Type) -> Element {
+    Element { name, ty }
+}
+
+const fn msg(
+    action: u8,
+    name: &'static str,
+    since_version: usize,
+    value: Element,
+) -> MessageSchema {
+    MessageSchema {
+        action,
+        name,
+        since_version,
+        value,
+    }
+}
+
+const NODE_ID: Element = el("node_id", Type::U64);
+const BLOCK_NUMBER: Element = el("block_number", Type::U64);
+const BLOCK_HASH: Element = el("block_hash", Type::Hash);
+const ADDRESS: Element = el("address", Type::String);
+const ALERT_KIND: Element = el("kind", Type::String);
+
+const NODE_STATS: Type = Type::Tuple(&[
+    el("peers", Type::U64),
+    el("txcount", Type::U64),
+    el("wasm_heap_used_bytes", Type::Nullable(&Type::U64)),
+    el("wasm_heap_limit_bytes", Type::Nullable(&Type::U64)),
+    el("block_announce_per_sec", Type::F32),
+    el("transaction_per_sec", Type::F32),
+    el("light_request_per_sec", Type::F32),
+    el("database_size_bytes", Type::Nullable(&Type::U64)),
+    el("avg_peer_reputation", Type::Nullable(&Type::I64)),
+]);
+
+const NODE_IO: Type = Type::Tuple(&[
+    el("used_state_cache_size", Type::Array(&Type::F32)),
+    el("offchain_worker_queue_depth", Type::Nullable(&Type::U64)),
+    el("kademlia_queries_per_sec", Type::Array(&Type::F32)),
+    el("kademlia_records_stored", Type::Nullable(&Type::U64)),
+    el(
+        "pallet_trie_sizes",
+        Type::Optional(&Type::Array(&Type::Tuple(&[
+            el("pallet", Type::String),
+            el("bytes", Type::U64),
+        ]))),
+    ),
+]);
+
+const NODE_HARDWARE: Type = Type::Tuple(&[
+    el("upload", Type::Array(&Type::F64)),
+    el("download", Type::Array(&Type::F64)),
+    el("chart_stamps", Type::Array(&Type::F64)),
+    el("swap_used_bytes", Type::Nullable(&Type::U64)),
+    el("swap_total_bytes", Type::Nullable(&Type::U64)),
+    el("import_latency_mean_ms", Type::Nullable(&Type::F32)),
+    el("import_latency_p95_ms", Type::Nullable(&Type::F32)),
+    el("cpu_steal_pct", Type::Array(&Type::F32)),
+    el("load_avg_1m", Type::Nullable(&Type::F32)),
+    el("load_avg_5m", Type::Nullable(&Type::F32)),
+    el("load_avg_15m", Type::Nullable(&Type::F32)),
+    el("cpu_cores", Type::Nullable(&Type::U64)),
+    el("open_fd_count", Type::Nullable(&Type::U64)),
+    el("fd_limit", Type::Nullable(&Type::U64)),
+    el("ntp_offset_ms", Type::Nullable(&Type::I64)),
+    el(
+        "gpu",
+        Type::Optional(&Type::Tuple(&[
+            el("name", Type::Nullable(&Type::String)),
+            el("usage_pct", Type::Nullable(&Type::Array(&Type::F32))),
+            el(
+                "memory_used_bytes",
+                Type::Nullable(&Type::Array(&Type::U64)),
+            ),
+        ])),
+    ),
+]);
+
+const BLOCK_DETAILS: Type = Type::Tuple(&[
+    el("block_number", Type::U64),
+    el("block_hash", Type::Hash),
+    el("block_time", Type::U64),
+    el("block_timestamp", Type::U64),
+    el("propagation_time", Type::Nullable(&Type::U64)),
+    el("announcement_latency", Type::Nullable(&Type::U64)),
+]);
+
+const NODE_LOCATION: Type = Type::Tuple(&[
+    el("latitude", Type::F32),
+    el("longitude", Type::F32),
+    el("city", Type::String),
+]);
+
+const NODE_COUNT_SAMPLE: Type = Type::Tuple(&[
+    el("timestamp", Type::U64),
+    el("node_count", Type::U64),
+    el("validator_count", Type::U64),
+]);
+
+/// Every feed message, in action order.
+const MESSAGES: &[MessageSchema] = &[
+    msg(0, "Version", 31, el("version", Type::U64)),
+    msg(
+        1,
+        "BestBlock",
+        31,
+        el(
+            "best_block",
+            Type::Tuple(&[
+                BLOCK_NUMBER,
+                el("timestamp", Type::U64),
+                el("average_block_time", Type::Nullable(&Type::U64)),
+            ]),
+        ),
+    ),
+    msg(
+        2,
+        "BestFinalized",
+        31,
+        el("best_finalized", Type::Tuple(&[BLOCK_NUMBER, BLOCK_HASH])),
+    ),
+    msg(
+        3,
+        "AddedNode",
+        37,
+        el(
+            "added_node",
+            Type::Tuple(&[
+                NODE_ID,
+                el(
+                    "details",
+                    Type::Tuple(&[
+                        el("name", Type::String),
+                        el("implementation", Type::String),
+                        el("version", Type::String),
+                        el("validator", Type::Nullable(&Type::String)),
+                        el("network_id", Type::Nullable(&Type::String)),
+                        el("offchain_indexing", Type::Bool),
+                        el("extra_info", Type::Map(&Type::Json)),
+                    ]),
+                ),
+                el("stats", NODE_STATS),
+                el("io", NODE_IO),
+                el("hardware", NODE_HARDWARE),
+                el("block_details", BLOCK_DETAILS),
+                el("location", Type::Nullable(&NODE_LOCATION)),
+                el("startup_time", Type::Nullable(&Type::U64)),
+                el("peer_history", Type::Array(&Type::U64)),
+            ]),
+        ),
+    ),
+    msg(4, "RemovedNode", 31, NODE_ID),
+    msg(
+        5,
+        "LocatedNode",
+        31,
+        el(
+            "located_node",
+            Type::Tuple(&[
+                NODE_ID,
+                el("latitude", Type::F32),
+                el("longitude", Type::F32),
+                el("city", Type::String),
+            ]),
+        ),
+    ),
+    msg(
+        6,
+        "ImportedBlock",
+        31,
+        el(
+            "imported_block",
+            Type::Tuple(&[NODE_ID, el("block_details", BLOCK_DETAILS)]),
+        ),
+    ),
+    msg(
+        7,
+        "FinalizedBlock",
+        32,
+        el(
+            "finalized_block",
+            Type::Tuple(&[
+                NODE_ID,
+                BLOCK_NUMBER,
+                BLOCK_HASH,
+                el("finalized_at", Type::Nullable(&Type::U64)),
+            ]),
+        ),
+    ),
+    msg(
+        8,
+        "NodeStatsUpdate",
+        35,
+        el(
+            "node_stats_update",
+            Type::Tuple(&[
+                NODE_ID,
+                el("stats", NODE_STATS),
+                el("peer_history", Type::Array(&Type::U64)),
+            ]),
+        ),
+    ),
+    msg(
+        9,
+        "Hardware",
+        31,
+        el(
+            "hardware",
+            Type::Tuple(&[NODE_ID, el("hardware", NODE_HARDWARE)]),
+        ),
+    ),
+    msg(10, "TimeSync", 31, el("time", Type::U64)),
+    msg(
+        11,
+        "AddedChain",
+        31,
+        el(
+            "added_chain",
+            Type::Tuple(&[
+                el("label", Type::String),
+                el("node_count", Type::U64),
+                el("chain_type", Type::Nullable(&Type::U64)),
+                el("first_party", Type::Bool),
+            ]),
+        ),
+    ),
+    msg(12, "RemovedChain", 31, el("label", Type::String)),
+    msg(13, "SubscribedTo", 31, el("label", Type::String)),
+    msg(14, "UnsubscribedFrom", 31, el("label", Type::String)),
+    msg(15, "Pong", 31, el("value", Type::String)),
+    msg(
+        16,
+        "AfgFinalized",
+        31,
+        el(
+            "afg_finalized",
+            Type::Tuple(&[ADDRESS, BLOCK_NUMBER, BLOCK_HASH]),
+        ),
+    ),
+    msg(
+        17,
+        "AfgReceivedPrevote",
+        31,
+        el(
+            "afg_received_prevote",
+            Type::Tuple(&[
+                ADDRESS,
+                BLOCK_NUMBER,
+                BLOCK_HASH,
+                el("voter", Type::Nullable(&Type::String)),
+            ]),
+        ),
+    ),
+    msg(
+        18,
+        "AfgReceivedPrecommit",
+        31,
+        el(
+            "afg_received_precommit",
+            Type::Tuple(&[
+                ADDRESS,
+                BLOCK_NUMBER,
+                BLOCK_HASH,
+                el("voter", Type::Nullable(&Type::String)),
+            ]),
+        ),
+    ),
+    msg(
+        19,
+        "AfgAuthoritySet",
+        31,
+        el(
+            "afg_authority_set",
+            Type::Tuple(&[
+                el("authority_id", Type::String),
+                el("authorities", Type::String),
+                el("authority_set_id", Type::String),
+                BLOCK_NUMBER,
+                BLOCK_HASH,
+            ]),
+        ),
+    ),
+    msg(20, "StaleNode", 31, NODE_ID),
+    msg(
+        21,
+        "NodeIOUpdate",
+        31,
+        el("node_io_update", Type::Tuple(&[NODE_ID, el("io", NODE_IO)])),
+    ),
+    msg(
+        22,
+        "ChainDistribution",
+        31,
+        el(
+            "distribution",
+            Type::Object(&[
+                el("implementations", Type::Map(&Type::U64)),
+                el("versions", Type::Map(&Type::U64)),
+            ]),
+        ),
+    ),
+    msg(
+        23,
+        "BestBlockAge",
+        31,
+        el(
+            "best_block_age",
+            Type::Tuple(&[BLOCK_NUMBER, BLOCK_HASH, el("age_ms", Type::U64)]),
+        ),
+    ),
+    msg(
+        24,
+        "NodeAlert",
+        31,
+        el(
+            "node_alert",
+            Type::Tuple(&[
+                NODE_ID,
+                ALERT_KIND,
+                el("severity", Type::String),
+                el("value", Type::Nullable(&Type::F64)),
+                el("raised_at", Type::U64),
+            ]),
+        ),
+    ),
+    msg(
+        25,
+        "NodeAlertCleared",
+        31,
+        el("node_alert_cleared", Type::Tuple(&[NODE_ID, ALERT_KIND])),
+    ),
+    msg(
+        26,
+        "NodeCountHistory",
+        31,
+        el("samples", Type::Array(&NODE_COUNT_SAMPLE)),
+    ),
+    msg(27, "NodeCountSample", 31, el("sample", NODE_COUNT_SAMPLE)),
+    msg(
+        28,
+        "NodesAtBest",
+        31,
+        el(
+            "nodes_at_best",
+            Type::Tuple(&[
+                BLOCK_NUMBER,
+                el("caught_up", Type::U64),
+                el("total", Type::U64),
+            ]),
+        ),
+    ),
+    msg(29, "SessionKey", 31, el("key", Type::String)),
+    msg(
+        30,
+        "BatchSignature",
+        31,
+        el(
+            "signature",
+            Type::Tuple(&[el("signed_ts", Type::U64), el("hmac", Type::String)]),
+        ),
+    ),
+    msg(
+        31,
+        "ChainStats",
+        36,
+        el(
+            "chain_stats",
+            Type::Tuple(&[
+                el("chain_label", Type::String),
+                el("full", Type::Bool),
+                el(
+                    "values",
+                    Type::Array(&Type::Tuple(&[
+                        el("field_id", Type::U64),
+                        el("value", Type::Nullable(&Type::U64)),
+                    ])),
+                ),
+                el(
+                    "continent_distribution",
+                    Type::Nullable(&Type::Array(&Type::Tuple(&[
+                        el("continent", Type::String),
+                        el("node_count", Type::U64),
+                    ]))),
+                ),
+                el(
+                    "top_pallet_trie_sizes",
+                    Type::Optional(&Type::Array(&Type::Tuple(&[
+                        el("pallet", Type::String),
+                        el("bytes", Type::U64),
+                    ]))),
+                ),
+            ]),
+        ),
+    ),
+    msg(
+        32,
+        "ServerShutdown",
+        34,
+        el("restart_in_seconds", Type::Nullable(&Type::U64)),
+    ),
+    msg(
+        33,
+        "BlockProductionRate",
+        38,
+        el("blocks_per_second", Type::F64),
+    ),
+    msg(
+        34,
+        "UpgradeScheduled",
+        39,
+        el(
+            "upgrade_scheduled",
+            Type::Tuple(&[
+                el("chain_label", Type::String),
+                el("at_block", Type::U64),
+                el("current_block", Type::U64),
+            ]),
+        ),
+    ),
+    msg(
+        35,
+        "UpgradeExecuted",
+        39,
+        el(
+            "upgrade_executed",
+            Type::Tuple(&[el("chain_label", Type::String), el("at_block", Type::U64)]),
+        ),
+    ),
+    msg(
+        36,
+        "SubscriberCount",
+        40,
+        el(
+            "subscriber_count",
+            Type::Tuple(&[el("chain_label", Type::String), el("count", Type::U64)]),
+        ),
+    ),
+    msg(37, "Heartbeat", 41, el("timestamp", Type::U64)),
+    msg(
+        38,
+        "PublicNodeShortage",
+        42,
+        el(
+            "public_node_shortage",
+            Type::Tuple(&[el("chain_label", Type::String), el("count", Type::U64)]),
+        ),
+    ),
+    msg(
+        39,
+        "PublicNodeShortageResolved",
+        42,
+        el(
+            "public_node_shortage_resolved",
+            Type::Tuple(&[el("chain_label", Type::String), el("count", Type::U64)]),
+        ),
+    ),
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::{self, FeedMessageSerializer, ACTIONS};
+    use crate::state::{ActiveAlert, Alert, Distribution, Node, NodeCountHistory, Severity};
+    use common::node_types::{
+        BlockDetails, BlockHash, NodeDetails, NodeHardware, NodeIO, NodeStats,
+    };
+    use serde_json::Value;
+    use std::collections::HashSet;
+
+    /// Does the JSON value given match the type?
+    fn matches(ty: &Type, value: &Value) -> bool {
+        match ty {
+            Type::U64 => value.is_u64(),
+            Type::I64 => value.is_i64(),
+            Type::F32 | Type::F64 => value.is_number(),
+            Type::Bool => value.is_boolean(),
+            Type::String => value.is_string(),
+            Type::Hash => value
+                .as_str()
+                .map(|s| s.starts_with("0x") && s.len() == 66)
+                .unwrap_or(false),
+            Type::Nullable(ty) => value.is_null() || matches(ty, value),
+            Type::Optional(ty) => matches(ty, value),
+            Type::Array(ty) => value
+                .as_array()
+                .map(|vals| vals.iter().all(|v| matches(ty, v)))
+                .unwrap_or(false),
+            Type::Tuple(elements) => value
+                .as_array()
+                .map(|vals| {
+                    let required = elements
+                        .iter()
+                        .filter(|e| !matches!(e.ty, Type::Optional(_)))
+                        .count();
+                    (required..=elements.len()).contains(&vals.len())
+                        && vals.iter().zip(*elements).all(|(v, e)| matches(&e.ty, v))
+                })
+                .unwrap_or(false),
+            Type::Object(elements) => value
+                .as_object()
+                .map(|obj| {
+                    obj.len() == elements.len()
+                        && elements
+                            .iter()
+                            .all(|e| obj.get(e.name).map(|v| matches(&e.ty, v)) == Some(true))
+                })
+                .unwrap_or(false),
+            Type::Map(ty) => value
+                .as_object()
+                .map(|obj| obj.values().all(|v| matches(ty, v)))
+                .unwrap_or(false),
+            Type::Json => true,
+        }
+    }
+
+    /// Serialize an example of every feed message. Optional values are given where
+    /// possible, so that we check their inner shape too.
+    fn example_messages() -> Vec<(u8, Value)> {
+        let hash = BlockHash::from_low_u64_be(1);
+        let mut node = Node::new(NodeDetails {
+            chain: "Chain".into(),
+            name: "Node".into(),
+            implementation: "Impl".into(),
+            version: "1.0".into(),
+            validator: Some("Validator".into()),
+            network_id: Some("NetworkId".into()),
+            startup_time: Some("1234".into()),
+            chain_type: Some(common::node_types::ChainType::Testnet),
+            environment: Some("prod".into()),
+            pruning_mode: None,
+            offchain_indexing: false,
+            pending_upgrade_block: None,
+            verbosity: None,
+            publicly_reachable: None,
+            message_schema: None,
+            extra_info: serde_json::from_value(serde_json::json!({
+                "parachain_id": 2000,
+                "collator": { "keys": ["a", "b"] },
+            }))
+            .unwrap(),
+        });
+        node.update_location(Some(std::sync::Arc::new(
+            common::node_types::NodeLocation {
+                latitude: 1.0,
+                longitude: 2.0,
+                city: "City".into(),
+                country: Some("XX".into()),
+            },
+        )));
+        let mut distribution = Distribution::new();
+        distribution.add(node.details());
+        let block_details = BlockDetails {
+            propagation_time: Some(10),
+            announcement_latency: Some(5),
+            ..BlockDetails::default()
+        };
+        let stats = NodeStats {
+            wasm_heap_used_bytes: Some(1),
+            wasm_heap_limit_bytes: Some(2),
+            block_announce_per_sec: 0.5,
+            transaction_per_sec: 1.5,
+            light_request_per_sec: 2.5,
+            database_size_bytes: Some(5_000_000_000),
+            avg_peer_reputation: Some(-5),
+            ..NodeStats::default()
+        };
+        let mut io = NodeIO::default();
+        io.used_state_cache_size.push(1.0);
+        io.offchain_worker_queue_depth = Some(1);
+        io.kademlia_queries_per_sec.push(1.0);
+        io.kademlia_records_stored = Some(1);
+        io.pallet_trie_sizes = Some(vec![("Balances".into(), 2048)].into());
+        let alert = ActiveAlert {
+            alert: Alert::OffchainWorkerBacklog { depth: 1 },
+            severity: Severity::Warning,
+            raised_at: 1,
+        };
+        let mut hardware = NodeHardware::default();
+        hardware.upload.push(1.0);
+        hardware.download.push(1.0);
+        hardware.chart_stamps.push(1.0);
+        hardware.swap_used_bytes = Some(1);
+        hardware.swap_total_bytes = Some(2);
+        hardware.import_latency_ms.push(3.0);
+        hardware.cpu_steal_pct.push(4.0);
+        hardware.load_avg_1m = Some(1.5);
+        hardware.load_avg_5m = Some(1.0);
+        hardware.load_avg_15m = Some(0.5);
+        hardware.cpu_cores = Some(4);
+        hardware.open_fd_count = Some(900);
+        hardware.fd_limit = Some(1024);
+        hardware.ntp_offset_ms = Some(-20);
+        hardware.gpu_name = Some("GPU".into());
+        hardware.gpu_usage_pct = Some(Default::default());
+        hardware.gpu_usage_pct.as_mut().unwrap().push(50.0);
+        hardware.gpu_memory_used_bytes = Some(Default::default());
+        hardware.gpu_memory_used_bytes.as_mut().unwrap().push(1024);
+
+        let mut node_count_history = NodeCountHistory::new();
+        let node_count_sample = node_count_history.sample(1, 2, 1);
+
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(feed_message::Version(FEED_VERSION));
+        ser.push(feed_message::BestBlock(1, 2, Some(3)));
+        ser.push(feed_message::BestFinalized(1, hash));
+        ser.push(feed_message::AddedNode(1, &node, &Default::default()));
+        ser.push(feed_message::RemovedNode(1));
+        ser.push(feed_message::LocatedNode(1, 1.0, 2.0, "City"));
+        ser.push(feed_message::ImportedBlock(1, &block_details));
+        ser.push(feed_message::FinalizedBlock(1, 2, hash, Some(3)));
+        ser.push(feed_message::NodeStatsUpdate(1, &stats, &[8, 9]));
+        ser.push(feed_message::Hardware(1, &hardware));
+        ser.push(feed_message::TimeSync(1));
+        ser.push(feed_message::AddedChain(
+            "Chain",
+            1,
+            Some(common::node_types::ChainType::Testnet),
+            false,
+        ));
+        ser.push(feed_message::RemovedChain("Chain"));
+        ser.push(feed_message::SubscribedTo("Chain"));
+        ser.push(feed_message::UnsubscribedFrom("Chain"));
+        ser.push(feed_message::Pong("Ping"));
+        ser.push(feed_message::AfgFinalized("Addr".into(), 1, hash));
+        ser.push(feed_message::AfgReceivedPrevote(
+            "Addr".into(),
+            1,
+            hash,
+            Some("Voter".into()),
+        ));
+        ser.push(feed_message::AfgReceivedPrecommit(
+            "Addr".into(),
+            1,
+            hash,
+            Some("Voter".into()),
+        ));
+        ser.push(feed_message::AfgAuthoritySet(
+            "A".into(),
+            "B".into(),
+            "C".into(),
+            1,
+            hash,
+        ));
+        ser.push(feed_message::StaleNode(1));
+        ser.push(feed_message::NodeIOUpdate(1, &io));
+        ser.push(feed_message::ChainDistribution(&distribution));
+        ser.push(feed_message::BestBlockAge(
+            common::node_types::Block::zero().with_age(1, 2),
+        ));
+        ser.push(feed_message::NodeAlert(1, &alert));
+        ser.push(feed_message::NodeAlertCleared(1, alert.alert.kind()));
+        ser.push(feed_message::NodeCountHistory(&node_count_history));
+        ser.push(feed_message::NodeCountSample(node_count_sample));
+        ser.push(feed_message::NodesAtBest(crate::state::NodesAtBest {
+            height: 1,
+            caught_up: 2,
+            total: 3,
+        }));
+        ser.push(feed_message::SessionKey([1; 32]));
+        ser.push(feed_message::BatchSignature(1_600_000_000_000, [2; 32]));
+        ser.push(feed_message::ChainStats(
+            "Chain",
+            false,
+            &[(1, Some(2)), (3, None)],
+            Some(&[("Europe".into(), 2), ("Asia".into(), 1)]),
+            Some(&[("Balances".into(), 2048), ("System".into(), 1024)]),
+        ));
+        ser.push(feed_message::ServerShutdown(Some(30)));
+        ser.push(feed_message::BlockProductionRate(0.25));
+        ser.push(feed_message::UpgradeScheduled("Chain", 100, 90));
+        ser.push(feed_message::UpgradeExecuted("Chain", 100));
+        ser.push(feed_message::SubscriberCount("Chain", 12));
+        ser.push(feed_message::Heartbeat(1_700_000_000_000));
+        ser.push(feed_message::PublicNodeShortage("Chain", 2));
+        ser.push(feed_message::PublicNodeShortageResolved("Chain", 3));
+
+        let bytes = ser.into_finalized().unwrap();
+        let values: Vec<Value> = serde_json::from_slice(&bytes).unwrap();
+        values
+            .chunks(2)
+            .map(|pair| (pair[0].as_u64().unwrap() as u8, pair[1].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn schema_describes_every_feed_message() {
+        let schema_actions: Vec<(u8, &str)> = MESSAGES.iter().map(|m| (m.action, m.name)).collect();
+        assert_eq!(
+            schema_actions, ACTIONS,
+            "The feed schema must describe every feed message, in action order"
+        );
+    }
+
+    #[test]
+    fn feed_messages_match_schema() {
+        let examples = example_messages();
+
+        // We should have an example of every message:
+        let example_actions: HashSet<u8> = examples.iter().map(|(a, _)| *a).collect();
+        for (action, name) in ACTIONS {
+            assert!(
+                example_actions.contains(action),
+                "No example given for {} ({})",
+                name,
+                action
+            );
+        }
+
+        // ... and they should all look like the schema says they should:
+        for (action, value) in examples {
+            let schema = MESSAGES
+                .iter()
+                .find(|m| m.action == action)
+                .expect("schema for action");
+            assert!(
+                matches(&schema.value.ty, &value),
+                "{} ({}) does not match the feed schema: {}",
+                schema.name,
+                action,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn since_version_is_not_in_the_future() {
+        for m in MESSAGES {
+            assert!(m.since_version <= FEED_VERSION, "{}", m.name);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Feeds that connect with a `?sign` query have every batch of messages sent to them
+//! signed (see [`common::feed_signing`]). The key is picked when the feed connects and
+//! is the first thing sent to it, so it differs for every session.
+//!
+//! Since the key travels over the same connection as the batches, anyone able to read
+//! and rewrite that connection can re-sign altered batches. Signing therefore only
+//! protects integrity when the transport itself is already protected by TLS (as
+//! WebTransport always is, and websockets are when served over `wss://`); what it adds
+//! on top of that is replay protection via the increasing signed timestamps.
+
+use crate::feed_message::{self, FeedMessageSerializer};
+use common::feed_signing::sign_batch;
+use common::node_types::Timestamp;
+
+/// The signing state of a single feed connection.
+pub struct FeedSession {
+    key: [u8; 32],
+    /// The timestamp that the last batch was signed with. Each batch is signed with
+    /// a later timestamp than the one before, so feeds can reject replayed batches.
+    signed_ts: u64,
+}
+
+impl FeedSession {
+    /// If the feed asked for signed batches in its query, start a new session for it.
+    pub fn from_query(query: Option<&str>) -> Option<FeedSession> {
+        let wants_signing = query
+            .unwrap_or("")
+            .split('&')
+            .any(|pair| pair == "sign" || pair == "sign=true");
+        wants_signing.then(FeedSession::new)
+    }
+
+    /// Start a new session with a random key.
+    pub fn new() -> FeedSession {
+        FeedSession {
+            key: rand::random(),
+            signed_ts: 0,
+        }
+    }
+
+    /// The batch to send to the feed before any others, telling it the session key. This
+    /// is sent in-band, so only keep it secret by sending it over TLS.
+    pub fn key_message(&self) -> bytes::Bytes {
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(feed_message::SessionKey(self.key));
+        ser.into_finalized().expect("a message was pushed")
+    }
+
+    /// Sign a batch of feed messages at (or, if need be, just after) the time given,
+    /// returning the batch with a [`feed_message::BatchSignature`] appended to it.
+    pub fn sign(&mut self, batch: &[u8], now: Timestamp) -> bytes::Bytes {
+        self.signed_ts = now.max(self.signed_ts + 1);
+        let signature = sign_batch(&self.key, batch, self.signed_ts);
+
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(feed_message::BatchSignature(self.signed_ts, signature));
+        let trailer = ser.into_finalized().expect("a message was pushed");
+
+        // Swap the closing ']' of the batch for the trailing message, minus its opening '[':
+        let mut signed = Vec::with_capacity(batch.len() + trailer.len());
+        signed.extend_from_slice(&batch[..batch.len() - 1]);
+        signed.push(b',');
+        signed.extend_from_slice(&trailer[1..]);
+        signed.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::FeedMessage;
+    use common::feed_signing::verify_batch;
+    use serde_json::Value;
+    use std::convert::TryInto;
+
+    const BATCH: &[u8] = br#"[0,31,1,[100,1600000000000,null]]"#;
+
+    /// Pull the key out of a key message.
+    fn key(session: &FeedSession) -> Vec<u8> {
+        let msg: (u8, String) = serde_json::from_slice(&session.key_message()).unwrap();
+        assert_eq!(msg.0, feed_message::SessionKey::ACTION);
+        hex::decode(msg.1).unwrap()
+    }
+
+    /// Split a signed batch into the original batch, the timestamp and the signature.
+    fn split(signed: &[u8]) -> (Vec<u8>, u64, [u8; 32]) {
+        let mut values: Vec<Value> = serde_json::from_slice(signed).unwrap();
+        let (ts, sig): (u64, String) = serde_json::from_value(values.pop().unwrap()).unwrap();
+        assert_eq!(values.pop().unwrap(), feed_message::BatchSignature::ACTION);
+        let sig = hex::decode(sig).unwrap().try_into().unwrap();
+        (serde_json::to_vec(&values).unwrap(), ts, sig)
+    }
+
+    #[test]
+    fn signing_is_opt_in() {
+        assert!(FeedSession::from_query(None).is_none());
+        assert!(FeedSession::from_query(Some("node=foo")).is_none());
+        assert!(FeedSession::from_query(Some("signature")).is_none());
+        assert!(FeedSession::from_query(Some("sign")).is_some());
+        assert!(FeedSession::from_query(Some("node=foo&sign=true")).is_some());
+    }
+
+    #[test]
+    fn signed_batches_verify_with_the_session_key() {
+        let mut session = FeedSession::new();
+        let (batch, ts, sig) = split(&session.sign(BATCH, 1000));
+
+        assert_eq!(batch, BATCH);
+        assert_eq!(ts, 1000);
+        assert!(verify_batch(&key(&session), &batch, ts, &sig));
+        assert!(!verify_batch(&key(&FeedSession::new()), &batch, ts, &sig));
+    }
+
+    #[test]
+    fn signed_timestamps_always_increase() {
+        let mut session = FeedSession::new();
+        let (_, first, _) = split(&session.sign(BATCH, 1000));
+        let (_, second, _) = split(&session.sign(BATCH, 1000));
+        let (_, third, _) = split(&session.sign(BATCH, 900));
+        let (_, fourth, _) = split(&session.sign(BATCH, 2000));
+
+        assert_eq!((first, second, third, fourth), (1000, 1001, 1002, 2000));
+    }
+}
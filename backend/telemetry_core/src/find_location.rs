@@ -15,6 +15,7 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use futures::{Sink, SinkExt};
@@ -22,15 +23,157 @@ use parking_lot::RwLock;
 use rustc_hash::FxHashMap;
 use serde::Deserialize;
 
+use anyhow::Context;
+use common::ip_ranges::IpRanges;
 use common::node_types::NodeLocation;
+use std::net::IpAddr;
+use std::path::Path;
 use tokio::sync::Semaphore;
 
 /// The returned location is optional; it may be None if not found.
 pub type Location = Option<Arc<NodeLocation>>;
 
+/// A request to find the location of an IP address, on behalf of whatever `id` is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocateRequest<Id> {
+    pub id: Id,
+    pub ip: Ipv4Addr,
+    /// Ignore any location we've cached for this IP address and look it up again,
+    /// caching whatever we find in its place.
+    pub refresh: bool,
+}
+
+impl<Id> LocateRequest<Id> {
+    /// Find the location of an IP address, using our cache if possible.
+    pub fn new(id: Id, ip: Ipv4Addr) -> Self {
+        LocateRequest {
+            id,
+            ip,
+            refresh: false,
+        }
+    }
+
+    /// Find the location of an IP address afresh.
+    pub fn refresh(id: Id, ip: Ipv4Addr) -> Self {
+        LocateRequest {
+            id,
+            ip,
+            refresh: true,
+        }
+    }
+}
+
+/// How often location lookups were answered from our cache of locations
+/// rather than by asking a location service. Clones share the same counts.
+#[derive(Debug, Clone, Default)]
+pub struct CacheStats {
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// The fraction of lookups that were cache hits, or `None` if there haven't been any lookups yet.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let hits = self.hits();
+        let total = hits + self.misses();
+        (total > 0).then(|| hits as f64 / total as f64)
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Nodes connecting via known anonymizing proxies (VPNs, Tor exit nodes and so on) would
+/// be located wherever the proxy happens to be, which is misleading. Instead, they're
+/// given a fallback location, or no location at all.
+#[derive(Debug, Default)]
+pub struct Anonymizers {
+    /// The addresses of known anonymizing proxies.
+    pub ranges: IpRanges,
+    /// Where to show nodes connecting from one of the `ranges`. If `None`, they're
+    /// left without a location.
+    pub location: Location,
+}
+
+impl Anonymizers {
+    /// Load the addresses of anonymizing proxies from a file listing one IP address or
+    /// CIDR range per line. Blank lines and anything following a `#` are ignored.
+    pub fn from_list_file(path: &Path, location: Location) -> anyhow::Result<Anonymizers> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read anonymizer list {}", path.display()))?;
+        let ranges = parse_ip_list(&contents)
+            .with_context(|| format!("Cannot parse anonymizer list {}", path.display()))?;
+        Ok(Anonymizers { ranges, location })
+    }
+
+    /// The location to use for the IP address given, if it belongs to an anonymizer.
+    fn location_of(&self, ip: Ipv4Addr) -> Option<Location> {
+        self.ranges
+            .contains(IpAddr::V4(ip))
+            .then(|| self.location.clone())
+    }
+}
+
+/// Parse a list of IP addresses and CIDR ranges, one per line. See
+/// [`Anonymizers::from_list_file`].
+fn parse_ip_list(contents: &str) -> anyhow::Result<IpRanges> {
+    let mut ranges = IpRanges::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let range = line.parse().with_context(|| format!("Line {}", idx + 1))?;
+        ranges.insert(range);
+    }
+    Ok(ranges)
+}
+
+/// Parse a location given as `<latitude>,<longitude>,<city>`, eg `0,0,Unknown region`.
+pub fn parse_location(s: &str) -> anyhow::Result<NodeLocation> {
+    let mut parts = s.splitn(3, ',');
+    let (latitude, longitude, city) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(latitude), Some(longitude), Some(city)) => (latitude, longitude, city),
+        _ => anyhow::bail!("Expected a location like '<latitude>,<longitude>,<city>'"),
+    };
+    let latitude: f32 = latitude
+        .trim()
+        .parse()
+        .ok()
+        .filter(|lat: &f32| (-90.0..=90.0).contains(lat))
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a valid latitude", latitude))?;
+    let longitude: f32 = longitude
+        .trim()
+        .parse()
+        .ok()
+        .filter(|lon: &f32| (-180.0..=180.0).contains(lon))
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a valid longitude", longitude))?;
+    Ok(NodeLocation {
+        latitude,
+        longitude,
+        city: city.trim().into(),
+        country: None,
+    })
+}
+
 /// This is responsible for taking an IP address and attempting
-/// to find a geographical location from this
-pub fn find_location<Id, R>(response_chan: R) -> flume::Sender<(Id, Ipv4Addr)>
+/// to find a geographical location from this. Cache hits and misses
+/// are counted in the `cache_stats` given.
+pub fn find_location<Id, R>(
+    response_chan: R,
+    cache_stats: CacheStats,
+    anonymizers: Arc<Anonymizers>,
+) -> flume::Sender<LocateRequest<Id>>
 where
     R: Sink<(Id, Option<Arc<NodeLocation>>)> + Unpin + Send + Clone + 'static,
     Id: Clone + Send + 'static,
@@ -47,11 +190,12 @@ where
             latitude: 52.516_6667,
             longitude: 13.4,
             city: "Berlin".into(),
+            country: Some("DE".into()),
         })),
     );
 
     // Create a locator with our cache. This is used to obtain locations.
-    let locator = Locator::new(cache);
+    let locator = Locator::new(cache, cache_stats, anonymizers);
 
     // Spawn a loop to handle location requests
     tokio::spawn(async move {
@@ -60,7 +204,7 @@ where
         let semaphore = Arc::new(Semaphore::new(4));
 
         loop {
-            while let Ok((id, ip_address)) = rx.recv_async().await {
+            while let Ok(LocateRequest { id, ip, refresh }) = rx.recv_async().await {
                 let permit = semaphore.clone().acquire_owned().await.unwrap();
                 let mut response_chan = response_chan.clone();
                 let locator = locator.clone();
@@ -68,7 +212,7 @@ where
                 // Once we have acquired our permit, spawn a task to avoid
                 // blocking this loop so that we can handle concurrent requests.
                 tokio::spawn(async move {
-                    match locator.locate(ip_address).await {
+                    match locator.locate(ip, refresh).await {
                         Ok(loc) => {
                             let _ = response_chan.send((id, loc)).await;
                         }
@@ -93,28 +237,53 @@ where
 struct Locator {
     client: reqwest::Client,
     cache: Arc<RwLock<FxHashMap<Ipv4Addr, Option<Arc<NodeLocation>>>>>,
+    cache_stats: CacheStats,
+    anonymizers: Arc<Anonymizers>,
 }
 
 impl Locator {
-    pub fn new(cache: FxHashMap<Ipv4Addr, Option<Arc<NodeLocation>>>) -> Self {
+    pub fn new(
+        cache: FxHashMap<Ipv4Addr, Option<Arc<NodeLocation>>>,
+        cache_stats: CacheStats,
+        anonymizers: Arc<Anonymizers>,
+    ) -> Self {
         let client = reqwest::Client::new();
 
         Locator {
             client,
             cache: Arc::new(RwLock::new(cache)),
+            cache_stats,
+            anonymizers,
         }
     }
 
-    pub async fn locate(&self, ip: Ipv4Addr) -> Result<Option<Arc<NodeLocation>>, reqwest::Error> {
-        // Return location quickly if it's cached:
-        let cached_loc = {
-            let cache_reader = self.cache.read();
-            cache_reader.get(&ip).cloned()
-        };
-        if let Some(loc) = cached_loc {
+    /// Look the IP address up in our cache, counting the hit or miss.
+    fn cached(&self, ip: Ipv4Addr) -> Option<Location> {
+        let cached_loc = self.cache.read().get(&ip).cloned();
+        self.cache_stats.record(cached_loc.is_some());
+        cached_loc
+    }
+
+    /// Find the location of an IP address. Unless `refresh` is set, we'll use the cached
+    /// location if there is one. Loopback addresses are never looked up afresh, since
+    /// location services can't tell us where they are. Nor are known anonymizers, since
+    /// they'd only tell us where the anonymizer is.
+    pub async fn locate(
+        &self,
+        ip: Ipv4Addr,
+        refresh: bool,
+    ) -> Result<Option<Arc<NodeLocation>>, reqwest::Error> {
+        if let Some(loc) = self.anonymizers.location_of(ip) {
             return Ok(loc);
         }
 
+        // Return location quickly if it's cached:
+        if !refresh || ip.is_loopback() {
+            if let Some(loc) = self.cached(ip) {
+                return Ok(loc);
+            }
+        }
+
         // Look it up via the location services if not cached:
         let location = self.iplocate_ipapi_co(ip).await?;
         let location = match location {
@@ -170,11 +339,12 @@ impl Locator {
 struct IPApiLocate {
     city: Box<str>,
     loc: Box<str>,
+    country: Option<Box<str>>,
 }
 
 impl IPApiLocate {
     fn into_node_location(self) -> Option<NodeLocation> {
-        let IPApiLocate { city, loc } = self;
+        let IPApiLocate { city, loc, country } = self;
 
         let mut loc = loc.split(',').map(|n| n.parse());
 
@@ -190,6 +360,7 @@ impl IPApiLocate {
             latitude,
             longitude,
             city,
+            country,
         })
     }
 }
@@ -203,6 +374,7 @@ mod tests {
         let ipapi = IPApiLocate {
             loc: "12.5,56.25".into(),
             city: "Foobar".into(),
+            country: Some("FB".into()),
         };
 
         let location = ipapi.into_node_location().unwrap();
@@ -210,6 +382,7 @@ mod tests {
         assert_eq!(location.latitude, 12.5);
         assert_eq!(location.longitude, 56.25);
         assert_eq!(&*location.city, "Foobar");
+        assert_eq!(location.country.as_deref(), Some("FB"));
     }
 
     #[test]
@@ -217,10 +390,101 @@ mod tests {
         let ipapi = IPApiLocate {
             loc: "12.5,56.25,1.0".into(),
             city: "Foobar".into(),
+            country: None,
         };
 
         let location = ipapi.into_node_location();
 
         assert!(location.is_none());
     }
+
+    #[test]
+    fn cache_hits_and_misses_are_counted() {
+        let mut cache = FxHashMap::default();
+        cache.insert(Ipv4Addr::new(10, 0, 0, 1), None);
+        let stats = CacheStats::default();
+        let locator = Locator::new(cache, stats.clone(), Default::default());
+        assert_eq!(stats.hit_ratio(), None);
+
+        assert!(locator.cached(Ipv4Addr::new(10, 0, 0, 1)).is_some());
+        assert!(locator.cached(Ipv4Addr::new(10, 0, 0, 1)).is_some());
+        assert!(locator.cached(Ipv4Addr::new(10, 0, 0, 1)).is_some());
+        assert!(locator.cached(Ipv4Addr::new(10, 0, 0, 2)).is_none());
+
+        assert_eq!((stats.hits(), stats.misses()), (3, 1));
+        assert_eq!(stats.hit_ratio(), Some(0.75));
+    }
+
+    fn fallback() -> Arc<NodeLocation> {
+        Arc::new(parse_location("0,0,Unknown region").unwrap())
+    }
+
+    fn anonymizer_locator(location: Location) -> Locator {
+        let mut cache = FxHashMap::default();
+        let berlin = Arc::new(parse_location("52.5,13.4,Berlin").unwrap());
+        cache.insert(Ipv4Addr::new(10, 0, 0, 1), Some(berlin.clone()));
+        // Even if we'd already cached a (misleading) location for an anonymizer:
+        cache.insert(Ipv4Addr::new(203, 0, 113, 7), Some(berlin));
+        let anonymizers = Anonymizers {
+            ranges: parse_ip_list("203.0.113.0/24").unwrap(),
+            location,
+        };
+        Locator::new(cache, CacheStats::default(), Arc::new(anonymizers))
+    }
+
+    #[tokio::test]
+    async fn anonymizers_are_given_the_fallback_location() {
+        let locator = anonymizer_locator(Some(fallback()));
+        for refresh in [false, true] {
+            let location = locator
+                .locate(Ipv4Addr::new(203, 0, 113, 7), refresh)
+                .await
+                .unwrap();
+            assert_eq!(location, Some(fallback()));
+        }
+
+        // Other addresses are located as usual:
+        let location = locator
+            .locate(Ipv4Addr::new(10, 0, 0, 1), false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&*location.city, "Berlin");
+    }
+
+    #[tokio::test]
+    async fn anonymizers_are_not_located_without_a_fallback_location() {
+        let locator = anonymizer_locator(None);
+        let location = locator
+            .locate(Ipv4Addr::new(203, 0, 113, 200), false)
+            .await
+            .unwrap();
+        assert_eq!(location, None);
+    }
+
+    #[test]
+    fn ip_lists_ignore_comments_and_blank_lines() {
+        let ranges =
+            parse_ip_list("# Tor exit nodes\n\n198.51.100.1\n  203.0.113.0/24  # a VPN provider\n")
+                .unwrap();
+        let ranges: Vec<String> = ranges.iter().map(|c| c.to_string()).collect();
+        assert_eq!(ranges, vec!["198.51.100.1/32", "203.0.113.0/24"]);
+
+        let err = parse_ip_list("198.51.100.1\nnope\n").unwrap_err();
+        assert!(format!("{:#}", err).starts_with("Line 2"), "{:#}", err);
+    }
+
+    #[test]
+    fn locations_are_parsed() {
+        let location = parse_location("-33.9, 151.2, Somewhere, Far Away").unwrap();
+        assert_eq!(location.latitude, -33.9);
+        assert_eq!(location.longitude, 151.2);
+        assert_eq!(&*location.city, "Somewhere, Far Away");
+        assert_eq!(location.country, None);
+
+        assert!(parse_location("0,0").is_err());
+        assert!(parse_location("91,0,North of the Pole").is_err());
+        assert!(parse_location("0,181,Over the Dateline").is_err());
+        assert!(parse_location("up,0,Nowhere").is_err());
+    }
 }
@@ -0,0 +1,415 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Every HTTP endpoint that hands back a list of things understands the same
+//! query parameters for paging through, sorting and trimming down that list:
+//!
+//! - `limit=N&offset=M` hands back at most N items, skipping the first M.
+//! - `sort=<field>:<asc|desc>` sorts the items by one of the fields that the
+//!   endpoint allows. Nested fields are separated by dots, eg `best_block.height`.
+//! - `fields=a,b,c` hands back only those top level fields of each item.
+//!
+//! These work on the JSON that each item serializes to, so that they can be
+//! applied to whichever snapshot structures an endpoint hands back.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// What a list endpoint allows of a [`ListQuery`].
+#[derive(Debug, Clone, Copy)]
+pub struct ListOpts {
+    /// The fields that items can be sorted by.
+    pub sortable: &'static [&'static str],
+    /// No more than this many items are handed back at once. This is also the
+    /// limit if none is given.
+    pub max_limit: usize,
+}
+
+/// How to page through, sort and trim down a list of items.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListQuery {
+    pub limit: usize,
+    pub offset: usize,
+    pub sort: Option<Sort>,
+    /// Only hand back these fields of each item, if given.
+    pub fields: Option<Vec<Box<str>>>,
+}
+
+/// The field to sort a list of items by, and in which direction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sort {
+    pub field: Box<str>,
+    pub descending: bool,
+}
+
+/// A single page of a list of items.
+#[derive(Debug, Serialize)]
+pub struct Page {
+    /// How many items there are in the whole list.
+    pub total: usize,
+    /// The offset to ask for to get the next page, if this isn't the last one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_offset: Option<usize>,
+    pub items: Vec<Value>,
+}
+
+impl ListQuery {
+    /// Parse the list parameters out of a query string. Any other parameters are
+    /// ignored, so that endpoints can have parameters of their own alongside these.
+    pub fn from_query(query: Option<&str>, opts: &ListOpts) -> anyhow::Result<ListQuery> {
+        let mut limit = None;
+        let mut offset = None;
+        let mut sort = None;
+        let mut fields = None;
+        for pair in query.unwrap_or("").split('&') {
+            let (key, value) = match pair.find('=') {
+                Some(idx) => (&pair[..idx], &pair[idx + 1..]),
+                None => (pair, ""),
+            };
+            match key {
+                "limit" => set_once(&mut limit, key, parse_number(key, value)?)?,
+                "offset" => set_once(&mut offset, key, parse_number(key, value)?)?,
+                "sort" => set_once(&mut sort, key, parse_sort(value, opts)?)?,
+                "fields" => set_once(&mut fields, key, parse_fields(value)?)?,
+                _ => continue,
+            }
+        }
+
+        let limit = limit.unwrap_or(opts.max_limit);
+        if limit > opts.max_limit {
+            anyhow::bail!("The limit can be at most {}", opts.max_limit);
+        }
+        Ok(ListQuery {
+            limit,
+            offset: offset.unwrap_or(0),
+            sort,
+            fields,
+        })
+    }
+
+    /// Sort the items given and hand back the page of them that was asked for.
+    pub fn page<T: Serialize>(&self, items: &[T]) -> anyhow::Result<Page> {
+        let mut items = items
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(sort) = &self.sort {
+            let pointer = format!("/{}", sort.field.replace('.', "/"));
+            items.sort_by(|a, b| {
+                let ordering = compare(
+                    a.pointer(&pointer).unwrap_or(&Value::Null),
+                    b.pointer(&pointer).unwrap_or(&Value::Null),
+                );
+                if sort.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            });
+        }
+
+        let total = items.len();
+        let end = self.offset.saturating_add(self.limit);
+        let next_offset = (end < total).then_some(end);
+        let mut items: Vec<Value> = items
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect();
+
+        if let Some(fields) = &self.fields {
+            for item in &mut items {
+                if let Value::Object(map) = item {
+                    *map = std::mem::take(map)
+                        .into_iter()
+                        .filter(|(key, _)| fields.iter().any(|field| **field == **key))
+                        .collect();
+                }
+            }
+        }
+
+        Ok(Page {
+            total,
+            next_offset,
+            items,
+        })
+    }
+}
+
+fn set_once<T>(current: &mut Option<T>, key: &str, value: T) -> anyhow::Result<()> {
+    if current.is_some() {
+        anyhow::bail!("Only one {} can be given", key);
+    }
+    *current = Some(value);
+    Ok(())
+}
+
+fn parse_number(key: &str, value: &str) -> anyhow::Result<usize> {
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("The {} must be a non-negative number", key))
+}
+
+fn parse_sort(value: &str, opts: &ListOpts) -> anyhow::Result<Sort> {
+    let (field, order) = match value.find(':') {
+        Some(idx) => (&value[..idx], &value[idx + 1..]),
+        None => (value, "asc"),
+    };
+    if !opts.sortable.contains(&field) {
+        anyhow::bail!(
+            "Cannot sort by '{}'; sort by one of: {}",
+            field,
+            opts.sortable.join(", ")
+        );
+    }
+    let descending = match order {
+        "asc" => false,
+        "desc" => true,
+        _ => anyhow::bail!("The sort order must be asc or desc"),
+    };
+    Ok(Sort {
+        field: field.into(),
+        descending,
+    })
+}
+
+fn parse_fields(value: &str) -> anyhow::Result<Vec<Box<str>>> {
+    let fields: Vec<Box<str>> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(Into::into)
+        .collect();
+    if fields.is_empty() {
+        anyhow::bail!("The fields must list at least one field");
+    }
+    Ok(fields)
+}
+
+/// Order JSON values. Values of different types (and missing values, which are
+/// treated as null) are ordered by their type, with nulls first.
+fn compare(a: &Value, b: &Value) -> Ordering {
+    fn type_order(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => match (a.as_u64(), b.as_u64()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => a
+                .as_f64()
+                .partial_cmp(&b.as_f64())
+                .unwrap_or(Ordering::Equal),
+        },
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => type_order(a).cmp(&type_order(b)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    const OPTS: ListOpts = ListOpts {
+        sortable: &["name", "block.height"],
+        max_limit: 3,
+    };
+
+    #[derive(Serialize)]
+    struct Item {
+        name: &'static str,
+        block: Block,
+        validator: Option<&'static str>,
+    }
+
+    #[derive(Serialize)]
+    struct Block {
+        height: u64,
+    }
+
+    fn items() -> Vec<Item> {
+        [("Bob", 12), ("Alice", 100), ("Dave", 5), ("Charlie", 12)]
+            .iter()
+            .map(|&(name, height)| Item {
+                name,
+                block: Block { height },
+                validator: None,
+            })
+            .collect()
+    }
+
+    fn page(query: &str) -> Page {
+        ListQuery::from_query(Some(query), &OPTS)
+            .unwrap()
+            .page(&items())
+            .unwrap()
+    }
+
+    fn names(page: &Page) -> Vec<&str> {
+        page.items
+            .iter()
+            .map(|item| item["name"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn defaults_to_first_page_of_max_limit_items() {
+        let query = ListQuery::from_query(None, &OPTS).unwrap();
+        assert_eq!(
+            query,
+            ListQuery {
+                limit: 3,
+                offset: 0,
+                sort: None,
+                fields: None,
+            }
+        );
+
+        let page = query.page(&items()).unwrap();
+        assert_eq!(names(&page), vec!["Bob", "Alice", "Dave"]);
+        assert_eq!(page.total, 4);
+        assert_eq!(page.next_offset, Some(3));
+    }
+
+    #[test]
+    fn pages_through_items() {
+        let first = page("limit=2");
+        assert_eq!(names(&first), vec!["Bob", "Alice"]);
+        assert_eq!(first.next_offset, Some(2));
+
+        let last = page("limit=2&offset=2");
+        assert_eq!(names(&last), vec!["Dave", "Charlie"]);
+        assert_eq!((last.total, last.next_offset), (4, None));
+
+        let past_the_end = page("offset=10");
+        assert!(past_the_end.items.is_empty());
+        assert_eq!((past_the_end.total, past_the_end.next_offset), (4, None));
+
+        // The next offset is left out of the response once there are no more items:
+        let json = serde_json::to_value(&last).unwrap();
+        assert_eq!(json["total"], 4);
+        assert!(json.get("next_offset").is_none());
+    }
+
+    #[test]
+    fn overlong_and_invalid_limits_are_rejected() {
+        assert!(ListQuery::from_query(Some("limit=3"), &OPTS).is_ok());
+        assert!(ListQuery::from_query(Some("limit=4"), &OPTS).is_err());
+        assert!(ListQuery::from_query(Some("limit=-1"), &OPTS).is_err());
+        assert!(ListQuery::from_query(Some("limit="), &OPTS).is_err());
+        assert!(ListQuery::from_query(Some("offset=many"), &OPTS).is_err());
+        assert!(ListQuery::from_query(Some("limit=1&limit=2"), &OPTS).is_err());
+    }
+
+    #[test]
+    fn sorts_by_allowed_fields() {
+        assert_eq!(names(&page("sort=name")), vec!["Alice", "Bob", "Charlie"]);
+        assert_eq!(
+            names(&page("sort=name:desc")),
+            vec!["Dave", "Charlie", "Bob"]
+        );
+
+        // Nested fields can be sorted by, and ties are left in the order they were in:
+        assert_eq!(
+            names(&page("sort=block.height:asc")),
+            vec!["Dave", "Bob", "Charlie"]
+        );
+        assert_eq!(
+            names(&page("sort=block.height:desc")),
+            vec!["Alice", "Bob", "Charlie"]
+        );
+
+        // Sorting happens before paging:
+        assert_eq!(names(&page("sort=name&offset=3")), vec!["Dave"]);
+    }
+
+    #[test]
+    fn invalid_sorts_are_rejected() {
+        let err = ListQuery::from_query(Some("sort=validator"), &OPTS).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot sort by 'validator'; sort by one of: name, block.height"
+        );
+        assert!(ListQuery::from_query(Some("sort=block"), &OPTS).is_err());
+        assert!(ListQuery::from_query(Some("sort=name:up"), &OPTS).is_err());
+        assert!(ListQuery::from_query(Some("sort=name&sort=name"), &OPTS).is_err());
+    }
+
+    #[test]
+    fn selects_fields_as_they_are_serialized() {
+        let page = page("fields=name,validator&sort=block.height&limit=2");
+        assert_eq!(
+            page.items,
+            vec![
+                // Fields which serialize to null are still handed back, and items
+                // can be sorted by fields that aren't:
+                json!({ "name": "Dave", "validator": null }),
+                json!({ "name": "Bob", "validator": null }),
+            ]
+        );
+
+        // Nested fields are handed back whole, and unknown fields are left out:
+        let page = self::page("fields=block, unknown&limit=1");
+        assert_eq!(page.items, vec![json!({ "block": { "height": 12 } })]);
+
+        assert!(ListQuery::from_query(Some("fields="), &OPTS).is_err());
+        assert!(ListQuery::from_query(Some("fields=,"), &OPTS).is_err());
+    }
+
+    #[test]
+    fn other_parameters_are_ignored() {
+        let query = ListQuery::from_query(Some("offchain_indexing=true&limit=1"), &OPTS).unwrap();
+        assert_eq!(query.limit, 1);
+    }
+
+    #[test]
+    fn json_values_are_ordered_by_type_then_value() {
+        let mut values = vec![
+            json!("b"),
+            json!(2.5),
+            json!(null),
+            json!(true),
+            json!("a"),
+            json!(u64::MAX),
+            json!(1),
+            json!(false),
+        ];
+        values.sort_by(compare);
+        assert_eq!(
+            values,
+            vec![
+                json!(null),
+                json!(false),
+                json!(true),
+                json!(1),
+                json!(2.5),
+                json!(u64::MAX),
+                json!("a"),
+                json!("b"),
+            ]
+        );
+    }
+}
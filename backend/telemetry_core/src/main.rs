@@ -14,26 +14,60 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+mod admin;
 mod aggregator;
+mod api;
+mod cluster;
+mod dataset_export;
+mod demo_state;
+mod denylist;
+mod feed_budget;
 mod feed_message;
+mod feed_priority;
+mod feed_schema;
+mod feed_session;
 mod find_location;
+mod list_query;
+mod name_redaction;
+mod shutdown;
 mod state;
+mod webtransport;
 use std::str::FromStr;
+use std::sync::Arc;
 use tokio::time::{Duration, Instant};
 
 use aggregator::{
-    AggregatorOpts, AggregatorSet, FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket,
-    ToShardWebsocket,
+    node_filter_from_query, AggregatorOpts, AggregatorSet, ChainHeights, ChainIngestLatency,
+    FromFeedWebsocket, FromShardWebsocket, IngestTimes, LatencyHistogram, NodeFilter,
+    ToFeedWebsocket, ToShardWebsocket,
 };
 use bincode::Options;
+use cluster::{Cluster, NodeForwarder, StaticMembership};
+use common::config_check::ConfigCheck;
 use common::http_utils;
 use common::internal_messages;
 use common::ready_chunks_all::ReadyChunksAll;
+use common::shard_token;
+use common::span_logging::LogSubscriber;
+use common::time;
+use feed_budget::{ChainFeedBudget, FeedBudgets};
+use feed_priority::{FeedPriorities, PriorityOverride};
+use feed_session::FeedSession;
 use futures::{SinkExt, StreamExt};
 use hyper::{Method, Response};
+use shutdown::Shutdown;
 use simple_logger::SimpleLogger;
+use state::{
+    AlertThresholds, BlockHeightLimit, BlockTimeSmoothing, BlockTimeSource, BufferKind, ChainOpts,
+    ImportThrottle, StallThreshold,
+};
 use structopt::StructOpt;
 
+// Fuzz tests keep an eye on how much memory parsing uses:
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: test_utils::fuzz::CountingAllocator = test_utils::fuzz::CountingAllocator;
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const NAME: &str = "Substrate Telemetry Backend Core";
@@ -56,10 +90,42 @@ struct Opts {
     /// telemetry. Case sensitive.
     #[structopt(long, required = false)]
     denylist: Vec<String>,
+    /// A file listing the names of more chains that are not allowed to connect, one per
+    /// line. Blank lines and lines starting with `#` are ignored. The file is checked for
+    /// changes every `--denylist-reload-secs` seconds, and any changes are applied to new
+    /// nodes without restarting.
+    #[structopt(long)]
+    denylist_file: Option<std::path::PathBuf>,
+    /// How often, in seconds, to check `--denylist-file` for changes.
+    #[structopt(long, default_value = "5")]
+    denylist_reload_secs: u64,
+    /// When `--denylist-file` changes, also close the connections of nodes which are
+    /// already connected from a chain that's now denied.
+    #[structopt(long)]
+    denylist_close_existing: bool,
+    /// A regular expression; any part of a node name that matches it is replaced with a
+    /// placeholder before the name is sent to feeds or the public API. Names are kept
+    /// as they are internally, and shown in full by the admin endpoints. Can be given
+    /// more than once.
+    #[structopt(long = "redact-name-pattern", number_of_values = 1)]
+    redact_name_patterns: Vec<String>,
+    /// A file listing more patterns to redact from node names, one per line. Blank lines
+    /// and lines starting with `#` are ignored. The file is checked for changes every
+    /// `--redact-name-reload-secs` seconds, and any changes are applied without restarting.
+    #[structopt(long)]
+    redact_name_pattern_file: Option<std::path::PathBuf>,
+    /// How often, in seconds, to check `--redact-name-pattern-file` for changes.
+    #[structopt(long, default_value = "5")]
+    redact_name_reload_secs: u64,
     /// If it takes longer than this number of seconds to send the current batch of messages
     /// to a feed, the feed connection will be closed.
     #[structopt(long, default_value = "10")]
     feed_timeout: u64,
+    /// If given, feeds that support `permessage-deflate` are sent messages of at least
+    /// this many bytes compressed. Smaller messages are always sent uncompressed, since
+    /// compressing them costs CPU for little or no saving.
+    #[structopt(long)]
+    feed_compression_threshold: Option<usize>,
     /// Number of worker threads to spawn. If "0" is given, use the number of CPUs available
     /// on the machine. If no value is given, use an internal default that we have deemed sane.
     #[structopt(long)]
@@ -72,6 +138,258 @@ struct Opts {
     /// messages in an attempt to let it reduce?
     #[structopt(long)]
     aggregator_queue_len: Option<usize>,
+    /// How many messages can be waiting to be sent to a single feed before we only send it
+    /// high priority messages? Once half this many are waiting, low priority messages (eg
+    /// hardware and stats updates) are no longer sent to it.
+    #[structopt(long, default_value = "1000")]
+    feed_queue_len: usize,
+    /// Space delimited list of `MessageName=priority` pairs, to change the priority ("low",
+    /// "normal" or "high") of feed messages from their defaults. For example
+    /// `--feed-priority Hardware=normal StaleNode=high`.
+    #[structopt(long, required = false)]
+    feed_priority: Vec<PriorityOverride>,
+    /// The number of feed messages per second that each chain can send to its feeds before
+    /// only high priority messages about it are sent, until the next second. If not given,
+    /// chains are not limited (unless given a budget with `--chain-feed-budget`).
+    #[structopt(long)]
+    default_chain_feed_budget: Option<u64>,
+    /// If given, feeds that haven't been sent anything for this many seconds are sent a
+    /// heartbeat, so that they can tell a quiet chain from a connection that's gone away.
+    #[structopt(long)]
+    feed_heartbeat_secs: Option<u64>,
+    /// Space delimited list of `genesis_hash=messages_per_sec` pairs, to give some chains a
+    /// different feed message budget to the default.
+    #[structopt(long, required = false)]
+    chain_feed_budget: Vec<ChainFeedBudget>,
+    /// If the aggregator takes longer than this many milliseconds to handle a single message,
+    /// log a warning (including the node and type of message) to help track down pathological
+    /// inputs. If not provided, slow messages aren't logged.
+    #[structopt(long)]
+    slow_message_threshold_ms: Option<u64>,
+    /// Space delimited list of the addresses of other telemetry cores to share chains with.
+    /// Each chain is owned by one core in the cluster, and nodes submitted to a core that
+    /// doesn't own their chain are forwarded on to the one that does.
+    #[structopt(long, required = false)]
+    cluster_peers: Vec<std::net::SocketAddr>,
+    /// The address that the other telemetry cores in the cluster reach this one on. Every
+    /// core in the cluster must be configured with the same set of addresses. Defaults to
+    /// the address that we're listening on.
+    #[structopt(long)]
+    cluster_address: Option<std::net::SocketAddr>,
+    /// Once the historical data kept for a chain (eg block time statistics) takes up more
+    /// than this many bytes, start evicting it. Live node state is counted towards this,
+    /// but never evicted. If not provided, usage is accounted for but not limited.
+    #[structopt(long)]
+    chain_memory_budget: Option<usize>,
+    /// Nodes reporting more than this many tasks queued up for their offchain workers
+    /// are considered to be backed up.
+    #[structopt(long, default_value = "100")]
+    offchain_worker_queue_threshold: u32,
+    /// How many consecutive reports of a backed up offchain worker queue we need to see
+    /// before raising an alert against a node.
+    #[structopt(long, default_value = "3")]
+    offchain_worker_backlog_samples: u32,
+    /// Alerts that have been raised against a node for at least this many seconds are
+    /// escalated to critical.
+    #[structopt(long, default_value = "600")]
+    alert_escalation_secs: u64,
+    /// Raise an alert against nodes whose finalized block is more than this many blocks
+    /// behind their best block.
+    #[structopt(long, default_value = "50")]
+    finality_lag_threshold: u64,
+    /// Raise an alert against nodes using more than this fraction of their swap
+    /// (more urgently if they are validators).
+    #[structopt(long, default_value = "0.1")]
+    swap_usage_threshold: f64,
+    /// Raise an alert against nodes whose 95th percentile block import latency is more than
+    /// this many times the median import latency of the nodes on their chain.
+    #[structopt(long, default_value = "2")]
+    slow_block_import_ratio: f64,
+    /// Raise an alert against nodes whose runtime is using more than this fraction of
+    /// its WASM heap.
+    #[structopt(long, default_value = "0.8")]
+    wasm_heap_usage_threshold: f64,
+    /// Raise an alert against validators which prune the state of all but fewer than
+    /// this many blocks.
+    #[structopt(long, default_value = "256")]
+    min_validator_pruning_blocks: u32,
+    /// Raise an alert against nodes whose mean CPU steal time (the percentage of CPU time that
+    /// the hypervisor of a VM takes away from it) is more than this.
+    #[structopt(long, default_value = "5")]
+    cpu_steal_threshold: f64,
+    /// Raise an alert against nodes handling more than this many times the median rate of
+    /// Kademlia DHT queries of the nodes on their chain, which may be under a DoS attack.
+    #[structopt(long, default_value = "5")]
+    kademlia_query_rate_ratio: f64,
+    /// Raise an alert against nodes receiving more than this many light client requests
+    /// per second from their peers.
+    #[structopt(long, default_value = "100")]
+    light_request_threshold: f64,
+    /// Raise an alert against nodes whose 1 minute load average is more than this many
+    /// times the number of CPU cores that they have.
+    #[structopt(long, default_value = "2")]
+    load_average_per_core: f64,
+    /// Raise an alert against nodes that have more than this fraction of the file
+    /// descriptors they're allowed open.
+    #[structopt(long, default_value = "0.8")]
+    fd_usage_threshold: f64,
+    /// Raise an alert against nodes whose average reputation with their peers is below
+    /// this, since their peers consider them to be misbehaving.
+    #[structopt(long, default_value = "-1000", allow_hyphen_values = true)]
+    min_peer_reputation: i32,
+    /// Raise an alert against nodes whose GPU usage has been above this percentage in
+    /// each of their recent samples.
+    #[structopt(long, default_value = "90")]
+    gpu_usage_threshold: f64,
+    /// Warn about chains which have had at least this many publicly reachable nodes (going
+    /// by what nodes tell us) once they drop below it. 0 disables the warning.
+    #[structopt(long, default_value = "3")]
+    min_public_nodes: usize,
+    /// How to smooth the block times of nodes before they are sent to feeds. One of
+    /// "none", "median:N" (the median of the last N block times) or "ewma:WEIGHT" (an
+    /// exponentially weighted moving average, giving each new block time this weight).
+    #[structopt(long, default_value = "none")]
+    block_time_smoothing: BlockTimeSmoothing,
+    /// What the block times of nodes are measured between. One of "arrival" (when their
+    /// blocks arrive here) or "reported" (the timestamps nodes send with their blocks,
+    /// falling back to when they arrived if a node's timestamps are missing or don't move
+    /// forwards).
+    #[structopt(long, default_value = "arrival")]
+    block_time_source: BlockTimeSource,
+    /// Announcements of blocks more than this many blocks below their chain's best block
+    /// (typically from syncing nodes) only update the node's best block, and feeds are
+    /// told about them at most once per `--import-throttle-interval-ms` for each node.
+    #[structopt(long, default_value = "100")]
+    import_throttle_blocks_behind: u64,
+    /// See `--import-throttle-blocks-behind`.
+    #[structopt(long, default_value = "1000")]
+    import_throttle_interval_ms: u64,
+    /// A chain is marked as having stalled once its best block has gone this many times its
+    /// usual (median) block time without advancing, and at least `--stall-min-secs`.
+    #[structopt(long, default_value = "10")]
+    stall_block_time_multiple: f64,
+    /// See `--stall-block-time-multiple`.
+    #[structopt(long, default_value = "60")]
+    stall_min_secs: u64,
+    /// If given, blocks higher than this are ignored, so that nodes can't claim to be
+    /// ahead of everybody else by announcing an implausibly high block.
+    #[structopt(long)]
+    max_block_height: Option<u64>,
+    /// If given, blocks more than this many blocks above their chain's best block are
+    /// ignored, for the same reason as `--max-block-height`.
+    #[structopt(long)]
+    max_blocks_ahead_of_best: Option<u64>,
+    /// A token that must be provided (as an `Authorization: Bearer <token>` header) in order to
+    /// use the "/admin" endpoints. If no token is given, the admin endpoints are disabled.
+    #[structopt(long)]
+    admin_token: Option<String>,
+    /// If given, shards (and other cores in the cluster) must provide this token in order to
+    /// connect to us, and should be configured with it via their `--core-token` option. If no
+    /// token is given, any shard can connect.
+    #[structopt(long)]
+    shard_token: Option<String>,
+    /// Space delimited list of the labels of third party chains to export per-chain metrics
+    /// (block heights and node counts) for. These are always exported for first party chains.
+    #[structopt(long, required = false)]
+    metrics_chain_allowlist: Vec<String>,
+    /// The genesis hash of a chain to treat as first party. First party chains allow any number
+    /// of nodes to connect, and always have their metrics exported. Can be given more than once.
+    /// If not given, Polkadot, Kusama, Westend and Rococo are first party.
+    #[structopt(long = "first-party", parse(try_from_str = parse_genesis_hash))]
+    first_party: Vec<common::node_types::BlockHash>,
+    /// If a node reconnects within this many seconds of disconnecting, and looks the same as it
+    /// did before, feeds aren't told that it went away and came back. This stops nodes stuck in
+    /// a crash loop from flooding feeds with node additions and removals. Nodes are recognised
+    /// by their network ID. If not given, disconnected nodes are removed straight away.
+    #[structopt(long)]
+    reconnect_debounce_secs: Option<u64>,
+    /// When a shard disconnects, keep its nodes for this many seconds in case they come back
+    /// via a shard that was connected to them before it restarted (shards remember this across
+    /// restarts with `--resumption-cache`). Nodes that come back in time keep their IDs and
+    /// history. If not given, a shard's nodes are removed as soon as it disconnects.
+    #[structopt(long)]
+    shard_restart_grace_secs: Option<u64>,
+    /// Path to a file listing the IP addresses (or CIDR ranges) of known anonymizing proxies,
+    /// such as VPNs and Tor exit nodes, one per line. Nodes connecting from these would appear
+    /// to be wherever the proxy is, so are shown at `--anonymizer-location` instead.
+    #[structopt(long, parse(from_os_str))]
+    anonymizer_list: Option<std::path::PathBuf>,
+    /// Where to show nodes connecting from an address in `--anonymizer-list`, given as
+    /// "<latitude>,<longitude>,<city>" (eg "0,0,Unknown region"). If not given, these nodes
+    /// are left without a location.
+    #[structopt(
+        long,
+        requires = "anonymizer-list",
+        parse(try_from_str = find_location::parse_location)
+    )]
+    anonymizer_location: Option<common::node_types::NodeLocation>,
+    /// If given, node coordinates are rounded to this many decimal places (at most 6), and
+    /// node cities are left blank, before being shown anywhere. One decimal place is a grid
+    /// of roughly 11km. If not given, node locations are shown as precisely as we know them.
+    #[structopt(long, parse(try_from_str = parse_decimal_places))]
+    anonymize_locations: Option<u8>,
+    /// Log a warning about any chain or shard whose node messages, over the last minute, have
+    /// had a 99th percentile shard→core transit time or core queue time of more than this many
+    /// milliseconds. Transit times rely on shard clocks agreeing with ours. If not given,
+    /// latencies are still measured, but not warned about.
+    #[structopt(long)]
+    ingest_latency_warn_ms: Option<u64>,
+    /// If given, track no more than this many chains at once. Once at the limit, nodes on new
+    /// third party chains are disconnected, while nodes on new first party chains cause the
+    /// third party chain with the fewest nodes to be evicted to make room.
+    #[structopt(long)]
+    max_chains: Option<usize>,
+    /// If given, feeds can also connect over WebTransport (HTTP/3) on this UDP socket address,
+    /// as well as over websockets. Requires `--webtransport-cert` and `--webtransport-key`.
+    #[structopt(long, requires_all = &["webtransport-cert", "webtransport-key"])]
+    webtransport_listen: Option<std::net::SocketAddr>,
+    /// Path to a PEM file containing the certificate chain to use for WebTransport.
+    #[structopt(long, parse(from_os_str))]
+    webtransport_cert: Option<std::path::PathBuf>,
+    /// Path to a PEM file containing the private key to use for WebTransport.
+    #[structopt(long, parse(from_os_str))]
+    webtransport_key: Option<std::path::PathBuf>,
+    /// If given, an anonymized snapshot of the nodes on each first party chain is written to a
+    /// subdirectory of this directory (named after the chain's genesis hash) once an hour, as
+    /// gzipped JSON. Node names, IP addresses and precise locations are never included.
+    #[structopt(long, parse(from_os_str))]
+    dataset_export: Option<std::path::PathBuf>,
+    /// How many dataset snapshots to keep for each chain. Older snapshots are deleted.
+    #[structopt(long, default_value = "168")]
+    dataset_export_keep: usize,
+    /// Network IDs are hashed along with this salt in exported datasets. Give the same salt
+    /// each time to be able to follow nodes across restarts of the core. If not given, a
+    /// random salt is picked on startup.
+    #[structopt(long)]
+    dataset_export_salt: Option<String>,
+    /// Check the configuration (that files can be read and parsed, listen addresses are free
+    /// and so on), report any problems and then exit without starting the core. The exit code
+    /// is non-zero if there were problems. These checks are also made every time the core starts.
+    #[structopt(long)]
+    validate_config: bool,
+    /// On SIGTERM (or Ctrl+C), feeds are told that the core is shutting down and every
+    /// connection is closed. Connections still open after this many seconds are cut off.
+    #[structopt(long, default_value = "5")]
+    shutdown_grace_period_secs: u64,
+    /// If given, feeds are told on shutdown that the core expects to be back after this
+    /// many seconds, so that they know when to try reconnecting.
+    #[structopt(long)]
+    shutdown_restart_in_secs: Option<u32>,
+    /// Once shut down, write a summary of how cleanly it went (connections closed cleanly
+    /// or forcibly, feed messages flushed or dropped, and how long it took) to this file in
+    /// Prometheus' text format, for instance for the node exporter's textfile collector.
+    /// The summary is logged either way.
+    #[structopt(long, parse(from_os_str))]
+    shutdown_metrics_file: Option<std::path::PathBuf>,
+    /// For local frontend development: load the chains and nodes in this JSON fixture file,
+    /// and keep them looking alive with made up block imports, interval updates and nodes
+    /// coming and going. These go through the aggregators just as if a shard had sent them.
+    #[structopt(long, parse(from_os_str))]
+    demo_state: Option<std::path::PathBuf>,
+    /// The seed for everything made up about the `--demo-state` network. The same fixture
+    /// and seed always play out the same way.
+    #[structopt(long, default_value = "0")]
+    demo_seed: u64,
 }
 
 fn main() {
@@ -81,6 +399,7 @@ fn main() {
         .with_level(opts.log_level)
         .init()
         .expect("Must be able to start a logger");
+    LogSubscriber::install().expect("Must be able to log tracing spans");
 
     log::info!("Starting Telemetry Core version: {}", VERSION);
 
@@ -107,98 +426,439 @@ fn main() {
         .build()
         .unwrap()
         .block_on(async {
+            if let Err(e) = check_config(&opts).await.finish() {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+            if opts.validate_config {
+                log::info!("Configuration is valid");
+                return;
+            }
             if let Err(e) = start_server(num_aggregators, opts).await {
                 log::error!("Error starting server: {}", e);
             }
         });
 }
 
+/// Look for problems with the configuration that would otherwise only come to light
+/// once the core is up and running (or trying to be).
+async fn check_config(opts: &Opts) -> ConfigCheck {
+    let mut check = ConfigCheck::new();
+
+    check.tcp_listen_addr("--listen", opts.socket);
+    if let Some(addr) = opts.webtransport_listen {
+        check.udp_listen_addr("--webtransport-listen", addr);
+    }
+    if let (Some(cert), Some(key)) = (&opts.webtransport_cert, &opts.webtransport_key) {
+        let identity = webtransport::load_identity(cert, key).await;
+        check.check("--webtransport-cert/--webtransport-key", identity);
+    }
+    if let Some(dir) = &opts.dataset_export {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            check.problem(format!(
+                "--dataset-export: cannot create {}: {}",
+                dir.display(),
+                e
+            ));
+        }
+    }
+
+    if let Some(path) = &opts.demo_state {
+        check.check("--demo-state", demo_state::DemoState::load(path));
+    }
+    if let Some(path) = &opts.anonymizer_list {
+        check.check(
+            "--anonymizer-list",
+            find_location::Anonymizers::from_list_file(path, None),
+        );
+    }
+
+    if let Some(path) = &opts.denylist_file {
+        check.check("--denylist-file", denylist::from_list_file(path));
+    }
+    if opts.stall_block_time_multiple.is_nan() || opts.stall_block_time_multiple <= 0.0 {
+        check.problem("--stall-block-time-multiple: must be greater than 0".to_string());
+    }
+    if opts.denylist_reload_secs == 0 {
+        check.problem("--denylist-reload-secs: must be at least 1".to_string());
+    }
+    check.check(
+        "--redact-name-pattern",
+        name_redaction::NameRedactor::new(&opts.redact_name_patterns),
+    );
+    if let Some(path) = &opts.redact_name_pattern_file {
+        check.check(
+            "--redact-name-pattern-file",
+            name_redaction::from_pattern_file(path)
+                .and_then(|patterns| name_redaction::NameRedactor::new(&patterns)),
+        );
+    }
+    if opts.redact_name_reload_secs == 0 {
+        check.problem("--redact-name-reload-secs: must be at least 1".to_string());
+    }
+
+    // Chain names are matched exactly, so these would never deny anything:
+    for chain in &opts.denylist {
+        if chain.trim().is_empty() || chain.trim() != chain {
+            check.problem(format!(
+                "--denylist: {:?} has leading or trailing whitespace, so will never match a chain",
+                chain
+            ));
+        }
+    }
+    if let Some(token) = &opts.shard_token {
+        check.check("--shard-token", shard_token::validate(token));
+    }
+    check
+}
+
 /// Declare our routes and start the server.
 async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()> {
     let aggregator_queue_len = opts.aggregator_queue_len.unwrap_or(10_000);
+    let anonymizers = match &opts.anonymizer_list {
+        Some(path) => {
+            let location = opts.anonymizer_location.clone().map(Arc::new);
+            find_location::Anonymizers::from_list_file(path, location)?
+        }
+        None => find_location::Anonymizers::default(),
+    };
+    let denylist_from_file = match &opts.denylist_file {
+        Some(path) => denylist::from_list_file(path)?,
+        None => Default::default(),
+    };
+    let denylist_from_args = opts.denylist;
+    let name_patterns_from_file = match &opts.redact_name_pattern_file {
+        Some(path) => name_redaction::from_pattern_file(path)?,
+        None => Vec::new(),
+    };
+    let name_patterns_from_args = opts.redact_name_patterns;
+    let name_redactor = name_redaction::NameRedactor::new(
+        &[&name_patterns_from_args[..], &name_patterns_from_file[..]].concat(),
+    )?;
+    let mut denylist = denylist_from_args.clone();
+    denylist.extend(denylist_from_file.iter().cloned());
     let aggregator = AggregatorSet::spawn(
         num_aggregators,
         AggregatorOpts {
             max_queue_len: aggregator_queue_len,
-            denylist: opts.denylist,
+            denylist,
+            slow_message_threshold: opts.slow_message_threshold_ms.map(Duration::from_millis),
+            chain_opts: ChainOpts {
+                memory_budget: opts.chain_memory_budget,
+                alert_thresholds: AlertThresholds {
+                    offchain_worker_queue_depth: opts.offchain_worker_queue_threshold,
+                    offchain_worker_backlog_samples: opts.offchain_worker_backlog_samples,
+                    escalate_after_ms: opts.alert_escalation_secs * 1000,
+                    finality_lag_blocks: opts.finality_lag_threshold,
+                    swap_usage_ratio: opts.swap_usage_threshold,
+                    slow_block_import_ratio: opts.slow_block_import_ratio,
+                    wasm_heap_usage_ratio: opts.wasm_heap_usage_threshold,
+                    min_validator_pruning_blocks: opts.min_validator_pruning_blocks,
+                    cpu_steal_pct: opts.cpu_steal_threshold,
+                    kademlia_query_rate_ratio: opts.kademlia_query_rate_ratio,
+                    light_requests_per_sec: opts.light_request_threshold,
+                    load_average_per_core: opts.load_average_per_core,
+                    fd_usage_ratio: opts.fd_usage_threshold,
+                    min_peer_reputation: opts.min_peer_reputation,
+                    gpu_usage_pct: opts.gpu_usage_threshold,
+                    min_public_nodes: opts.min_public_nodes,
+                },
+                block_time_smoothing: opts.block_time_smoothing,
+                block_time_source: opts.block_time_source,
+                import_throttle: ImportThrottle {
+                    blocks_behind: opts.import_throttle_blocks_behind,
+                    interval_ms: opts.import_throttle_interval_ms,
+                },
+                stall_threshold: StallThreshold {
+                    block_time_multiple: opts.stall_block_time_multiple,
+                    min_ms: opts.stall_min_secs * 1000,
+                },
+                block_height_limit: BlockHeightLimit {
+                    max_height: opts.max_block_height,
+                    max_ahead_of_best: opts.max_blocks_ahead_of_best,
+                },
+                first_party_chains: Arc::new(if opts.first_party.is_empty() {
+                    state::default_first_party_chains()
+                } else {
+                    opts.first_party.into_iter().collect()
+                }),
+            },
+            metrics_chain_allowlist: opts.metrics_chain_allowlist,
+            feed_priorities: FeedPriorities::new(opts.feed_queue_len, &opts.feed_priority),
+            feed_budgets: FeedBudgets::new(opts.default_chain_feed_budget, &opts.chain_feed_budget),
+            reconnect_debounce: opts.reconnect_debounce_secs.map(Duration::from_secs),
+            shard_restart_grace: opts.shard_restart_grace_secs.map(Duration::from_secs),
+            anonymizers: Arc::new(anonymizers),
+            anonymize_locations: opts.anonymize_locations,
+            ingest_latency_warning: opts.ingest_latency_warn_ms.map(Duration::from_millis),
+            max_chains: opts.max_chains,
+            feed_heartbeat_interval: opts.feed_heartbeat_secs.map(Duration::from_secs),
+            name_redactor: Arc::new(name_redactor),
         },
     )
     .await?;
+    if let Some(path) = opts.denylist_file {
+        let changes = denylist::watch(
+            path,
+            denylist_from_file,
+            Duration::from_secs(opts.denylist_reload_secs),
+        );
+        let close_denied_nodes = opts.denylist_close_existing;
+        let aggregator = aggregator.clone();
+        tokio::spawn(async move {
+            while let Ok(denylist_from_file) = changes.recv_async().await {
+                let mut denylist = denylist_from_args.clone();
+                denylist.extend(denylist_from_file);
+                if let Err(e) = aggregator.set_denylist(denylist, close_denied_nodes).await {
+                    log::error!("Error updating denylist (bailing): {}", e);
+                    return;
+                }
+            }
+        });
+    }
+    if let Some(path) = opts.redact_name_pattern_file {
+        let changes = name_redaction::watch(
+            path,
+            name_patterns_from_args,
+            name_patterns_from_file,
+            Duration::from_secs(opts.redact_name_reload_secs),
+        );
+        let aggregator = aggregator.clone();
+        tokio::spawn(async move {
+            while let Ok(redactor) = changes.recv_async().await {
+                if let Err(e) = aggregator.set_name_redactor(Arc::new(redactor)).await {
+                    log::error!("Error updating name patterns (bailing): {}", e);
+                    return;
+                }
+            }
+        });
+    }
     let socket_addr = opts.socket;
     let feed_timeout = opts.feed_timeout;
+    let feed_compression_threshold = opts.feed_compression_threshold;
+    let shutdown = Shutdown::new();
+    let shutdown_grace_period = Duration::from_secs(opts.shutdown_grace_period_secs);
+    let shutdown_restart_in_secs = opts.shutdown_restart_in_secs;
+    let shutdown_metrics_file = opts.shutdown_metrics_file;
+    let admin_token: Option<Arc<str>> = opts.admin_token.map(Into::into);
+    let shard_token: Option<Arc<str>> = opts.shard_token.map(Into::into);
 
-    let server = http_utils::start_server(socket_addr, move |addr, req| {
-        let aggregator = aggregator.clone();
-        async move {
-            match (req.method(), req.uri().path().trim_end_matches('/')) {
-                // Check that the server is up and running:
-                (&Method::GET, "/health") => Ok(Response::new("OK".into())),
-                // Subscribe to feed messages:
-                (&Method::GET, "/feed") => {
-                    log::info!("Opening /feed connection from {:?}", addr);
-                    Ok(http_utils::upgrade_to_websocket(
-                        req,
-                        move |ws_send, ws_recv| async move {
-                            let (feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_feed_websocket_connection(
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                    feed_timeout,
-                                    feed_id,
-                                )
-                                .await;
-                            log::info!("Closing /feed connection from {:?}", addr);
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
-                            let _ = ws_send.close().await;
-                        },
-                    ))
-                }
-                // Subscribe to shard messages:
-                (&Method::GET, "/shard_submit") => {
-                    Ok(http_utils::upgrade_to_websocket(
-                        req,
-                        move |ws_send, ws_recv| async move {
-                            log::info!("Opening /shard_submit connection from {:?}", addr);
+    let cluster = if opts.cluster_peers.is_empty() {
+        None
+    } else {
+        let local = opts.cluster_address.unwrap_or(socket_addr);
+        let membership = StaticMembership::new(local, opts.cluster_peers);
+        Some(Cluster::spawn(&membership, shard_token.as_deref()).await)
+    };
+
+    if let (Some(addr), Some(cert), Some(key)) = (
+        opts.webtransport_listen,
+        &opts.webtransport_cert,
+        &opts.webtransport_key,
+    ) {
+        webtransport::start_server(
+            addr,
+            cert,
+            key,
+            aggregator.clone(),
+            feed_timeout,
+            shutdown.clone(),
+        )
+        .await?;
+    }
+
+    if let Some(dir) = opts.dataset_export {
+        dataset_export::spawn_dataset_export_loop(
+            aggregator.clone(),
+            dataset_export::DatasetExportOpts {
+                dir,
+                keep_files: opts.dataset_export_keep,
+                salt: opts.dataset_export_salt,
+            },
+        );
+    }
+
+    if let Some(path) = &opts.demo_state {
+        let state = demo_state::DemoState::load(path)?;
+        log::info!(
+            "Starting a demo network of {} chains from {}",
+            state.chains.len(),
+            path.display()
+        );
+        demo_state::spawn_demo_network(aggregator.clone(), state, opts.demo_seed);
+    }
+
+    let server_aggregator = aggregator.clone();
+    let server_shutdown = shutdown.clone();
+    let stop_accepting = shutdown.clone();
+    let server = http_utils::start_server_with_shutdown(
+        socket_addr,
+        move |addr, req| {
+            let aggregator = server_aggregator.clone();
+            let cluster = cluster.clone();
+            let admin_token = admin_token.clone();
+            let shard_token = shard_token.clone();
+            let shutdown = server_shutdown.clone();
+            async move {
+                match (req.method(), req.uri().path().trim_end_matches('/')) {
+                    // Check that the server is up and running:
+                    (&Method::GET, "/health") => Ok(Response::new("OK".into())),
+                    // Subscribe to feed messages:
+                    (&Method::GET, "/feed") => {
+                        let node_filter = match node_filter_from_query(req.uri().query()) {
+                            Ok(node_filter) => node_filter,
+                            Err(e) => return Ok(http_utils::basic_response(400, e.to_string())),
+                        };
+                        let session = FeedSession::from_query(req.uri().query());
+                        log::info!("Opening /feed connection from {:?}", addr);
+                        Ok(http_utils::upgrade_to_websocket_with_compression(
+                            req,
+                            feed_compression_threshold,
+                            move |ws_send, ws_recv| async move {
+                                let _connection = shutdown.connection();
+                                let (feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
+                                let (mut tx_to_aggregator, mut ws_send) =
+                                    handle_feed_websocket_connection(
+                                        ws_send,
+                                        ws_recv,
+                                        tx_to_aggregator,
+                                        feed_timeout,
+                                        feed_id,
+                                        node_filter,
+                                        session,
+                                        shutdown,
+                                    )
+                                    .await;
+                                log::info!("Closing /feed connection from {:?}", addr);
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let _ =
+                                    tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
+                                let _ = ws_send.close().await;
+                            },
+                        ))
+                    }
+                    // Shards and cluster peers must know the shard token, if one is configured:
+                    (&Method::GET, "/shard_submit" | cluster::CLUSTER_SUBMIT_PATH)
+                        if !shard_token::is_authorized(
+                            req.uri().query(),
+                            shard_token.as_deref(),
+                        ) =>
+                    {
+                        log::warn!("Rejecting unauthorized shard connection from {:?}", addr);
+                        Ok(http_utils::basic_response(401, "Unauthorized"))
+                    }
+                    // Subscribe to shard messages:
+                    (&Method::GET, "/shard_submit") => {
+                        Ok(http_utils::upgrade_to_websocket(
+                            req,
+                            move |ws_send, ws_recv| async move {
+                                log::info!("Opening /shard_submit connection from {:?}", addr);
+                                let _connection = shutdown.connection();
+                                let tx_to_aggregator = aggregator.subscribe_shard();
+                                let node_forwarder = cluster.as_ref().map(|c| c.node_forwarder());
+                                let (mut tx_to_aggregator, mut ws_send) =
+                                    handle_shard_websocket_connection(
+                                        ws_send,
+                                        ws_recv,
+                                        tx_to_aggregator,
+                                        node_forwarder,
+                                        shutdown,
+                                    )
+                                    .await;
+                                log::info!("Closing /shard_submit connection from {:?}", addr);
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let _ = tx_to_aggregator
+                                    .send(FromShardWebsocket::Disconnected)
+                                    .await;
+                                let _ = ws_send.close().await;
+                            },
+                        ))
+                    }
+                    // Nodes forwarded to us from other cores in the cluster; these are
+                    // handled just like shard messages, but never forwarded again:
+                    (&Method::GET, cluster::CLUSTER_SUBMIT_PATH) => Ok(
+                        http_utils::upgrade_to_websocket(req, move |ws_send, ws_recv| async move {
+                            log::info!("Opening cluster connection from {:?}", addr);
+                            let _connection = shutdown.connection();
                             let tx_to_aggregator = aggregator.subscribe_shard();
                             let (mut tx_to_aggregator, mut ws_send) =
                                 handle_shard_websocket_connection(
                                     ws_send,
                                     ws_recv,
                                     tx_to_aggregator,
+                                    None,
+                                    shutdown,
                                 )
                                 .await;
-                            log::info!("Closing /shard_submit connection from {:?}", addr);
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
+                            log::info!("Closing cluster connection from {:?}", addr);
                             let _ = tx_to_aggregator
                                 .send(FromShardWebsocket::Disconnected)
                                 .await;
                             let _ = ws_send.close().await;
-                        },
-                    ))
+                        }),
+                    ),
+                    // Return metrics in a prometheus-friendly text based format:
+                    (&Method::GET, "/metrics") => Ok(return_prometheus_metrics(aggregator).await),
+                    // Query details about chains and nodes:
+                    (_, path) if path.starts_with(api::API_PREFIX) => {
+                        Ok(api::handle_api_request(aggregator, req).await)
+                    }
+                    // Operator facing endpoints, which need an admin token:
+                    (_, path) if path.starts_with(admin::ADMIN_PREFIX) => Ok(
+                        admin::handle_admin_request(aggregator, admin_token.as_deref(), req).await,
+                    ),
+                    // 404 for anything else:
+                    _ => Ok(Response::builder()
+                        .status(404)
+                        .body("Not found".into())
+                        .unwrap()),
                 }
-                // Return metrics in a prometheus-friendly text based format:
-                (&Method::GET, "/metrics") => Ok(return_prometheus_metrics(aggregator).await),
-                // 404 for anything else:
-                _ => Ok(Response::builder()
-                    .status(404)
-                    .body("Not found".into())
-                    .unwrap()),
             }
-        }
-    });
+        },
+        async move { stop_accepting.triggered().await },
+    );
 
-    server.await?;
+    // Run until we fail to serve, or are asked to stop:
+    let mut server = tokio::spawn(server);
+    tokio::select! {
+        res = &mut server => return res?,
+        _ = shutdown::signal() => {}
+    }
+
+    // Feeds are told first, and once each aggregator has sent on everything it had
+    // before then. After this, we stop accepting connections and close shards.
+    log::info!(
+        "Shutting down; waiting up to {}s for connections to close",
+        shutdown_grace_period.as_secs()
+    );
+    shutdown.begin();
+    if let Err(e) = aggregator.shutdown(shutdown_restart_in_secs).await {
+        log::error!("Error telling feeds that we're shutting down: {}", e);
+    }
+
+    let summary = shutdown.close_connections(shutdown_grace_period).await;
+    log::info!("{}", summary);
+    if let Some(path) = shutdown_metrics_file {
+        if let Err(e) = std::fs::write(&path, summary.prometheus_metrics()) {
+            log::error!(
+                "Error writing shutdown metrics to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
     Ok(())
 }
 
-/// This handles messages coming to/from a shard connection
+/// This handles messages coming to/from a shard connection. If a node forwarder is
+/// given, nodes on chains owned by other cores in the cluster are handed to it rather
+/// than to the aggregator.
 async fn handle_shard_websocket_connection<S>(
     mut ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
     mut tx_to_aggregator: S,
+    mut node_forwarder: Option<NodeForwarder>,
+    shutdown: Shutdown,
 ) -> (S, http_utils::WsSender)
 where
     S: futures::Sink<FromShardWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
@@ -229,7 +889,8 @@ where
             // connection anyway.
             let msg_info = tokio::select! {
                 msg_info = ws_recv.receive_data(&mut bytes) => msg_info,
-                _ = &mut recv_closer_rx => break
+                _ = &mut recv_closer_rx => break,
+                _ = shutdown.triggered() => break,
             };
 
             // Handle the socket closing, or errors receiving the message.
@@ -256,6 +917,15 @@ where
                     }
                 };
 
+            // Forward the message on to a cluster peer if it's not for us to handle:
+            let msg = match &mut node_forwarder {
+                Some(forwarder) => match forwarder.handle(msg) {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+                None => msg,
+            };
+
             // Convert and send to the aggregator:
             let aggregator_msg = match msg {
                 internal_messages::FromShardAggregator::AddNode {
@@ -263,17 +933,27 @@ where
                     node,
                     local_id,
                     genesis_hash,
+                    resumed,
                 } => FromShardWebsocket::Add {
                     ip,
                     node,
                     genesis_hash,
                     local_id,
+                    resumed,
                 },
-                internal_messages::FromShardAggregator::UpdateNode { payload, local_id } => {
-                    FromShardWebsocket::Update { local_id, payload }
-                }
-                internal_messages::FromShardAggregator::RemoveNode { local_id } => {
-                    FromShardWebsocket::Remove { local_id }
+                internal_messages::FromShardAggregator::UpdateNode {
+                    payload,
+                    local_id,
+                    reported_at,
+                    received_at,
+                } => FromShardWebsocket::Update {
+                    local_id,
+                    payload,
+                    reported_at,
+                    ingest: IngestTimes::received_now(received_at),
+                },
+                internal_messages::FromShardAggregator::RemoveNode { local_id, reason } => {
+                    FromShardWebsocket::Remove { local_id, reason }
                 }
             };
 
@@ -304,6 +984,19 @@ where
                 ToShardWebsocket::Mute { local_id, reason } => {
                     internal_messages::FromTelemetryCore::Mute { local_id, reason }
                 }
+                ToShardWebsocket::Resync { local_id } => {
+                    internal_messages::FromTelemetryCore::Resync { local_id }
+                }
+                ToShardWebsocket::MuteChain {
+                    genesis_hash,
+                    ttl_ms,
+                } => internal_messages::FromTelemetryCore::MuteChain {
+                    genesis_hash,
+                    ttl_ms,
+                },
+                ToShardWebsocket::UnmuteChain { genesis_hash } => {
+                    internal_messages::FromTelemetryCore::UnmuteChain { genesis_hash }
+                }
             };
 
             let bytes = bincode::options()
@@ -335,23 +1028,42 @@ where
 }
 
 /// This handles messages coming from a feed connection
+#[allow(clippy::too_many_arguments)]
 async fn handle_feed_websocket_connection<S>(
     mut ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
     mut tx_to_aggregator: S,
     feed_timeout: u64,
     _feed_id: u64, // <- can be useful for debugging purposes.
+    node_filter: Option<NodeFilter>,
+    mut session: Option<FeedSession>,
+    shutdown: Shutdown,
 ) -> (S, http_utils::WsSender)
 where
     S: futures::Sink<FromFeedWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
 {
+    // Feeds that want signed batches need to know the key before anything else:
+    if let Some(session) = &session {
+        let sent = ws_send.send_binary(&session.key_message()).await;
+        if let Err(e) = sent.and(ws_send.flush().await) {
+            log::warn!(
+                "Closing feed websocket due to error sending session key: {}",
+                e
+            );
+            return (tx_to_aggregator, ws_send);
+        }
+    }
+
     // unbounded channel so that slow feeds don't block aggregator progress:
     let (tx_to_feed_conn, rx_from_aggregator) = flume::unbounded();
+    // Kept so that we know how much is left queued up for the feed if we're cut off:
+    let queued_for_feed = rx_from_aggregator.clone();
     let mut rx_from_aggregator_chunks = ReadyChunksAll::new(rx_from_aggregator.into_stream());
 
     // Tell the aggregator about this new connection, and give it a way to send messages to us:
     let init_msg = FromFeedWebsocket::Initialize {
         channel: tx_to_feed_conn,
+        node_filter,
     };
     if let Err(e) = tx_to_aggregator.send(init_msg).await {
         log::error!("Error sending message to aggregator: {}", e);
@@ -422,6 +1134,10 @@ where
             let msgs = tokio::select! {
                 msgs = rx_from_aggregator_chunks.next() => msgs,
                 _ = &mut send_closer_rx => { break }
+                _ = shutdown.forced() => {
+                    shutdown.record_dropped(queued_for_feed.len());
+                    break
+                }
             };
 
             // End the loop when connection from aggregator ends:
@@ -429,41 +1145,64 @@ where
                 Some(msgs) => msgs,
                 None => break,
             };
+            // If we stop part way through a batch, nothing else queued up for the feed
+            // will be sent either:
+            let batch_len = msgs.len();
+            let record_dropped = || shutdown.record_dropped(batch_len + queued_for_feed.len());
 
             // There is only one message type at the mo; bytes to send
             // to the websocket. collect them all up to dispatch in one shot.
             let all_msg_bytes = msgs.into_iter().map(|msg| match msg {
-                ToFeedWebsocket::Bytes(bytes) => bytes,
+                ToFeedWebsocket::Bytes(bytes) => match &mut session {
+                    Some(session) => session.sign(&bytes, time::now()),
+                    None => bytes,
+                },
             });
 
             // If the feed is too slow to receive the current batch of messages, we'll drop it.
             let message_send_deadline = Instant::now() + Duration::from_secs(feed_timeout);
 
             for bytes in all_msg_bytes {
-                match tokio::time::timeout_at(message_send_deadline, ws_send.send_binary(&bytes))
-                    .await
-                {
+                let sent = tokio::select! {
+                    sent = tokio::time::timeout_at(message_send_deadline, ws_send.send_binary(&bytes)) => sent,
+                    _ = shutdown.forced() => {
+                        record_dropped();
+                        break 'outer;
+                    }
+                };
+                match sent {
                     Err(_) => {
                         log::warn!("Closing feed websocket that was too slow to keep up (too slow to send messages)");
+                        record_dropped();
                         break 'outer;
                     }
                     Ok(Err(e)) => {
                         log::warn!("Closing feed websocket due to error sending data: {}", e);
+                        record_dropped();
                         break 'outer;
                     }
                     Ok(_) => {}
                 }
             }
-            match tokio::time::timeout_at(message_send_deadline, ws_send.flush()).await {
+            let flushed = tokio::select! {
+                flushed = tokio::time::timeout_at(message_send_deadline, ws_send.flush()) => flushed,
+                _ = shutdown.forced() => {
+                    record_dropped();
+                    break;
+                }
+            };
+            match flushed {
                 Err(_) => {
                     log::warn!("Closing feed websocket that was too slow to keep up (too slow to flush messages)");
+                    record_dropped();
                     break;
                 }
                 Ok(Err(e)) => {
                     log::warn!("Closing feed websocket due to error flushing data: {}", e);
+                    record_dropped();
                     break;
                 }
-                Ok(_) => {}
+                Ok(_) => shutdown.record_flushed(batch_len),
             }
 
             debounce.await;
@@ -533,11 +1272,265 @@ async fn return_prometheus_metrics(aggregator: AggregatorSet) -> Response<hyper:
             "telemetry_dropped_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
             idx, m.dropped_messages_to_aggregator, m.timestamp_unix_ms
         ));
+        s.push_str(&format!(
+            "telemetry_dropped_messages_to_feeds{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.dropped_messages_to_feeds, m.timestamp_unix_ms
+        ));
+        s.push_str(&format!(
+            "telemetry_location_cache_hits{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.location_cache_hits, m.timestamp_unix_ms
+        ));
+        s.push_str(&format!(
+            "telemetry_location_cache_misses{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.location_cache_misses, m.timestamp_unix_ms
+        ));
+        if let Some(ratio) = m.location_cache_hit_ratio {
+            s.push_str(&format!(
+                "telemetry_location_cache_hit_ratio{{aggregator=\"{}\"}} {} {}\n\n",
+                idx, ratio, m.timestamp_unix_ms
+            ));
+        }
     }
 
+    // Every aggregator knows about every chain, so only report chain memory usage from the first:
+    if let Some(m) = metrics.first() {
+        for chain in &m.chain_memory {
+            let labels = format!(
+                "chain=\"{}\",genesis_hash=\"{:?}\"",
+                escape_label_value(&chain.label),
+                chain.genesis_hash
+            );
+            for kind in BufferKind::ALL.iter().copied() {
+                s.push_str(&format!(
+                    "telemetry_chain_memory_bytes{{{},kind=\"{}\"}} {} {}\n",
+                    labels,
+                    kind.as_str(),
+                    chain.usage.get(kind),
+                    m.timestamp_unix_ms
+                ));
+            }
+            if let Some(limit) = chain.usage.limit {
+                s.push_str(&format!(
+                    "telemetry_chain_memory_budget_bytes{{{}}} {} {}\n",
+                    labels, limit, m.timestamp_unix_ms
+                ));
+            }
+        }
+    }
+
+    // Each aggregator keeps its own node count history but evicts from it in the same way,
+    // so only report evictions from the first:
+    if let Some(m) = metrics.first() {
+        s.push_str("# TYPE telemetry_retention_evictions_total counter\n");
+        for eviction in &m.retention_evictions {
+            s.push_str(&format!(
+                "telemetry_retention_evictions_total{{chain=\"{}\",genesis_hash=\"{:?}\"}} {} {}\n",
+                escape_label_value(&eviction.chain),
+                eviction.genesis_hash,
+                eviction.evicted_count,
+                m.timestamp_unix_ms
+            ));
+        }
+    }
+
+    // Every aggregator hears about every node removal, so only report these from the first:
+    if let Some(m) = metrics.first() {
+        s.push_str("# TYPE telemetry_removed_nodes_total counter\n");
+        for (reason, count) in &m.removed_nodes {
+            s.push_str(&format!(
+                "telemetry_removed_nodes_total{{reason=\"{}\",code=\"{}\",server_initiated=\"{}\"}} {} {}\n",
+                reason.as_str(),
+                reason.code(),
+                reason.is_server_initiated(),
+                count,
+                m.timestamp_unix_ms
+            ));
+        }
+    }
+
+    // Every aggregator tracks the same chains, and makes the same decisions about which
+    // to let in, so only report these from the first:
+    if let Some(m) = metrics.first() {
+        let capacity = &m.chain_capacity;
+        s.push_str("# TYPE telemetry_chains gauge\n");
+        s.push_str(&format!(
+            "telemetry_chains {} {}\n",
+            capacity.chains, m.timestamp_unix_ms
+        ));
+        if let Some(max_chains) = capacity.max_chains {
+            s.push_str("# TYPE telemetry_max_chains gauge\n");
+            s.push_str(&format!(
+                "telemetry_max_chains {} {}\n",
+                max_chains, m.timestamp_unix_ms
+            ));
+        }
+        s.push_str("# TYPE telemetry_evicted_chains_total counter\n");
+        s.push_str(&format!(
+            "telemetry_evicted_chains_total {} {}\n",
+            capacity.evicted_chains, m.timestamp_unix_ms
+        ));
+        s.push_str("# TYPE telemetry_rejected_chain_nodes_total counter\n");
+        s.push_str(&format!(
+            "telemetry_rejected_chain_nodes_total {} {}\n",
+            capacity.rejected_nodes, m.timestamp_unix_ms
+        ));
+    }
+
+    // Per-chain block heights, for alerting on chains that fall behind other sources. Each
+    // metric is grouped together (and given a type) so that OpenMetrics parsers accept it.
+    if let Some(m) = metrics.first() {
+        let chains = &m.chain_heights;
+        let ts = m.timestamp_unix_ms;
+        push_chain_gauge(
+            &mut s,
+            "telemetry_chain_best_block_height",
+            chains,
+            ts,
+            |c| Some(c.best_height),
+        );
+        push_chain_gauge(
+            &mut s,
+            "telemetry_chain_finalized_block_height",
+            chains,
+            ts,
+            |c| Some(c.finalized_height),
+        );
+        push_chain_gauge(
+            &mut s,
+            "telemetry_chain_best_block_changed_timestamp_ms",
+            chains,
+            ts,
+            |c| c.best_block_changed_at,
+        );
+        push_chain_gauge(&mut s, "telemetry_chain_node_count", chains, ts, |c| {
+            Some(c.node_count as u64)
+        });
+    }
+
+    // How long node messages about each chain took to arrive from shards, and then to be
+    // handled. Each aggregator queues messages separately, so each reports its own. Only
+    // the chains that we report block heights for are included.
+    let ingest_latencies: Vec<_> = metrics
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, m)| {
+            m.chain_ingest_latency
+                .iter()
+                .filter(move |l| {
+                    m.chain_heights
+                        .iter()
+                        .any(|c| c.genesis_hash == l.genesis_hash)
+                })
+                .map(move |l| (idx, m.timestamp_unix_ms, l))
+        })
+        .collect();
+    push_ingest_histogram(
+        &mut s,
+        "telemetry_chain_ingest_transit_ms",
+        &ingest_latencies,
+        |l| &l.transit,
+    );
+    push_ingest_histogram(
+        &mut s,
+        "telemetry_chain_ingest_queued_ms",
+        &ingest_latencies,
+        |l| &l.queued,
+    );
+
     Response::builder()
         // The version number here tells prometheus which version of the text format we're using:
         .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
         .body(s.into())
         .unwrap()
 }
+
+/// Append a gauge with a value for each of the chains given. Chains without a value are skipped.
+/// Parse a genesis hash given on the command line.
+fn parse_genesis_hash(s: &str) -> anyhow::Result<common::node_types::BlockHash> {
+    api::parse_genesis_hash(s).ok_or_else(|| anyhow::anyhow!("'{}' is not a genesis hash", s))
+}
+
+/// Parse a number of decimal places to round node coordinates to.
+fn parse_decimal_places(s: &str) -> anyhow::Result<u8> {
+    // Node coordinates are f32s, which can't usefully be any more precise than this:
+    s.parse()
+        .ok()
+        .filter(|places| *places <= 6)
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a number of decimal places from 0 to 6", s))
+}
+
+fn push_chain_gauge(
+    s: &mut String,
+    name: &str,
+    chains: &[ChainHeights],
+    timestamp_unix_ms: u64,
+    value: impl Fn(&ChainHeights) -> Option<u64>,
+) {
+    s.push_str(&format!("# TYPE {} gauge\n", name));
+    for chain in chains {
+        if let Some(value) = value(chain) {
+            s.push_str(&format!(
+                "{}{{chain=\"{}\",genesis_hash=\"{:?}\"}} {} {}\n",
+                name,
+                escape_label_value(&chain.label),
+                chain.genesis_hash,
+                value,
+                timestamp_unix_ms
+            ));
+        }
+    }
+}
+
+/// Append a histogram of the given latency for each aggregator and chain given.
+fn push_ingest_histogram(
+    s: &mut String,
+    name: &str,
+    latencies: &[(usize, u64, &ChainIngestLatency)],
+    histogram: impl Fn(&ChainIngestLatency) -> &LatencyHistogram,
+) {
+    s.push_str(&format!("# TYPE {} histogram\n", name));
+    for &(idx, timestamp_unix_ms, latency) in latencies {
+        let labels = format!(
+            "aggregator=\"{}\",chain=\"{}\",genesis_hash=\"{:?}\"",
+            idx,
+            escape_label_value(&latency.label),
+            latency.genesis_hash
+        );
+        let histogram = histogram(latency);
+        for (le, count) in histogram.cumulative_counts() {
+            s.push_str(&format!(
+                "{}_bucket{{{},le=\"{}\"}} {} {}\n",
+                name, labels, le, count, timestamp_unix_ms
+            ));
+        }
+        s.push_str(&format!(
+            "{}_bucket{{{},le=\"+Inf\"}} {} {}\n",
+            name,
+            labels,
+            histogram.count(),
+            timestamp_unix_ms
+        ));
+        s.push_str(&format!(
+            "{}_sum{{{}}} {} {}\n",
+            name,
+            labels,
+            histogram.sum_ms(),
+            timestamp_unix_ms
+        ));
+        s.push_str(&format!(
+            "{}_count{{{}}} {} {}\n",
+            name,
+            labels,
+            histogram.count(),
+            timestamp_unix_ms
+        ));
+    }
+}
+
+/// Escape a string for use as a prometheus label value.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,200 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Node names sometimes contain things (email addresses, hostnames and so on) that
+//! operators would rather weren't published. Any part of a name matching one of a list
+//! of patterns can be replaced with a placeholder whenever we hand the name out to feeds
+//! or the public API. We keep the original name internally, and it's still shown by the
+//! admin endpoints.
+
+use anyhow::Context;
+use regex::{Regex, RegexBuilder};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What matching parts of a name are replaced with.
+pub const PLACEHOLDER: &str = "[redacted]";
+
+/// Names longer than this (in bytes) aren't matched against the patterns at all, and
+/// are replaced with the placeholder as a whole. Along with the limits on how big a
+/// compiled pattern can be, this bounds how long redacting a single name can take.
+pub const MAX_NAME_LEN: usize = 256;
+
+/// The most memory, in bytes, that a single compiled pattern (or its lazily built DFA)
+/// is allowed to use. Patterns which need more than this are rejected.
+const PATTERN_SIZE_LIMIT: usize = 1 << 20;
+
+/// A list of compiled patterns to redact from node names.
+#[derive(Debug, Clone, Default)]
+pub struct NameRedactor {
+    patterns: Vec<Regex>,
+}
+
+impl NameRedactor {
+    /// Compile the given patterns, failing if any of them is invalid or too big.
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> anyhow::Result<NameRedactor> {
+        let patterns = patterns
+            .iter()
+            .map(|p| {
+                RegexBuilder::new(p.as_ref())
+                    .size_limit(PATTERN_SIZE_LIMIT)
+                    .dfa_size_limit(PATTERN_SIZE_LIMIT)
+                    .build()
+                    .with_context(|| format!("Invalid name pattern {:?}", p.as_ref()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(NameRedactor { patterns })
+    }
+
+    /// The name with any parts matching a pattern replaced by [`PLACEHOLDER`].
+    pub fn redact<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.patterns.is_empty() {
+            return Cow::Borrowed(name);
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Cow::Borrowed(PLACEHOLDER);
+        }
+        let mut name = Cow::Borrowed(name);
+        for pattern in &self.patterns {
+            // Patterns that can match the empty string would litter the name with
+            // placeholders, so only replace matches that actually cover something:
+            if let Cow::Owned(redacted) = replace_non_empty(pattern, &name) {
+                name = Cow::Owned(redacted);
+            }
+        }
+        name
+    }
+}
+
+fn replace_non_empty<'a>(pattern: &Regex, name: &'a str) -> Cow<'a, str> {
+    let mut redacted = String::new();
+    let mut last = 0;
+    for m in pattern.find_iter(name).filter(|m| !m.as_str().is_empty()) {
+        redacted.push_str(&name[last..m.start()]);
+        redacted.push_str(PLACEHOLDER);
+        last = m.end();
+    }
+    if last == 0 {
+        return Cow::Borrowed(name);
+    }
+    redacted.push_str(&name[last..]);
+    Cow::Owned(redacted)
+}
+
+/// Load patterns from a file listing one per line. Blank lines and lines starting with
+/// a `#` are ignored, and leading and trailing whitespace is trimmed.
+pub fn from_pattern_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read name patterns {}", path.display()))?;
+    Ok(parse_patterns(&contents))
+}
+
+/// Parse a list of patterns. See [`from_pattern_file`].
+fn parse_patterns(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Check the pattern file every `interval`, and hand back a redactor compiled from
+/// `from_args` and the patterns in the file whenever the file changes (starting from
+/// `current`, which is presumably what the file held when it was first loaded). If the
+/// file can't be read, or holds an invalid pattern, the last good patterns are kept.
+/// This stops once the returned receiver is dropped.
+pub fn watch(
+    path: PathBuf,
+    from_args: Vec<String>,
+    mut current: Vec<String>,
+    interval: Duration,
+) -> flume::Receiver<NameRedactor> {
+    let (tx, rx) = flume::unbounded();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if tx.is_disconnected() {
+                return;
+            }
+
+            let patterns = match from_pattern_file(&path) {
+                Ok(patterns) => patterns,
+                Err(e) => {
+                    log::warn!("Keeping the current name patterns: {:#}", e);
+                    continue;
+                }
+            };
+            if patterns == current {
+                continue;
+            }
+            let redactor = match NameRedactor::new(&[&from_args[..], &patterns[..]].concat()) {
+                Ok(redactor) => redactor,
+                Err(e) => {
+                    log::warn!("Keeping the current name patterns: {:#}", e);
+                    continue;
+                }
+            };
+
+            log::info!(
+                "Name patterns changed; now redacting {} patterns",
+                redactor.patterns.len()
+            );
+            current = patterns;
+            if tx.send(redactor).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matching_parts_of_names_are_redacted() {
+        let redactor = NameRedactor::new(&[r"[\w.]+@[\w.]+", r"(?i)\bhost-\d+\b"]).unwrap();
+        assert_eq!(
+            redactor.redact("alice@example.com's Host-12 node"),
+            "[redacted]'s [redacted] node"
+        );
+        assert!(matches!(redactor.redact("Bob"), Cow::Borrowed("Bob")));
+
+        // Patterns matching nothing in particular don't litter names with placeholders:
+        let redactor = NameRedactor::new(&["x*"]).unwrap();
+        assert_eq!(redactor.redact("abxxc"), "ab[redacted]c");
+
+        // Without any patterns, names are left alone however long they are:
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        assert_eq!(NameRedactor::default().redact(&long), long);
+        assert_eq!(redactor.redact(&long), PLACEHOLDER);
+    }
+
+    #[test]
+    fn invalid_or_huge_patterns_are_rejected() {
+        assert!(NameRedactor::new(&["(unclosed"]).is_err());
+        assert!(NameRedactor::new(&[r"\w{1000}{1000}"]).is_err());
+    }
+
+    #[test]
+    fn can_parse_patterns() {
+        let patterns = parse_patterns("# Emails:\n  [\\w.]+@[\\w.]+ \n\nhost-\\d+\n");
+        assert_eq!(patterns, vec![r"[\w.]+@[\w.]+", r"host-\d+"]);
+    }
+}
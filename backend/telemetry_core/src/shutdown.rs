@@ -0,0 +1,355 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Coordinates shutting the core down gracefully: connections are told when to stop,
+//! and we keep track of how many are still open so that we know when they've all gone.
+//! Once they have (or we've given up waiting), we summarise how cleanly it all went.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+/// How long connections that are still open at the end of the grace period are given
+/// to count what they're dropping before we stop waiting for them altogether.
+const FORCE_CLOSE_WAIT: Duration = Duration::from_secs(1);
+
+/// A handle that can be cloned into each connection. Connections should hold on to a
+/// [`ConnectionGuard`] for as long as they're open, and stop once shutdown is triggered.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<ShutdownInner>);
+
+#[derive(Default)]
+struct ShutdownInner {
+    began_at: Mutex<Option<Instant>>,
+    triggered: AtomicBool,
+    on_trigger: Notify,
+    forced: AtomicBool,
+    on_force: Notify,
+    open_connections: AtomicUsize,
+    on_connection_closed: Notify,
+    closed_cleanly: AtomicUsize,
+    force_closed: AtomicUsize,
+    messages_flushed: AtomicU64,
+    messages_dropped: AtomicU64,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Note that we've begun shutting down. From now on, connections closing and the
+    /// messages they flush or drop count towards the [`ShutdownSummary`]. This is called
+    /// by [`Shutdown::trigger`] if it hasn't been already.
+    pub fn begin(&self) {
+        self.0
+            .began_at
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+    }
+
+    /// Have we begun shutting down?
+    pub fn has_begun(&self) -> bool {
+        self.0.began_at.lock().unwrap().is_some()
+    }
+
+    /// Tell everything waiting on [`Shutdown::triggered`] that it's time to stop.
+    pub fn trigger(&self) {
+        self.begin();
+        self.0.triggered.store(true, Ordering::SeqCst);
+        self.0.on_trigger.notify_waiters();
+    }
+
+    /// Resolves once [`Shutdown::trigger`] has been called.
+    pub async fn triggered(&self) {
+        wait_for_flag(&self.0.triggered, &self.0.on_trigger).await
+    }
+
+    /// Resolves once the grace period has run out. Connections still open by then
+    /// should count whatever they haven't sent as dropped, and stop.
+    pub async fn forced(&self) {
+        wait_for_flag(&self.0.forced, &self.0.on_force).await
+    }
+
+    /// Count some messages as having been sent on during shutdown.
+    pub fn record_flushed(&self, messages: usize) {
+        if self.has_begun() {
+            self.0
+                .messages_flushed
+                .fetch_add(messages as u64, Ordering::SeqCst);
+        }
+    }
+
+    /// Count some messages as never being sent on because of shutdown.
+    pub fn record_dropped(&self, messages: usize) {
+        if self.has_begun() {
+            self.0
+                .messages_dropped
+                .fetch_add(messages as u64, Ordering::SeqCst);
+        }
+    }
+
+    /// Note that a connection has been opened. It's counted as open until the guard
+    /// returned is dropped.
+    pub fn connection(&self) -> ConnectionGuard {
+        self.0.open_connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(self.clone())
+    }
+
+    /// How many connections are currently open.
+    pub fn open_connections(&self) -> usize {
+        self.0.open_connections.load(Ordering::SeqCst)
+    }
+
+    /// Wait for every connection to close, or for the grace period to run out. Returns
+    /// how many connections are still open.
+    pub async fn wait_for_connections(&self, grace_period: Duration) -> usize {
+        let all_closed = async {
+            loop {
+                let notified = self.0.on_connection_closed.notified();
+                if self.open_connections() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(grace_period, all_closed).await;
+        self.open_connections()
+    }
+
+    /// Tell connections to stop, and wait up to the grace period for them to close.
+    /// Any that don't are forced to, and then we summarise how it went.
+    pub async fn close_connections(&self, grace_period: Duration) -> ShutdownSummary {
+        self.trigger();
+
+        let open = self.wait_for_connections(grace_period).await;
+        if open > 0 {
+            log::warn!(
+                "{} connections still open after {}s; closing them",
+                open,
+                grace_period.as_secs()
+            );
+            self.0.force_closed.store(open, Ordering::SeqCst);
+            self.0.forced.store(true, Ordering::SeqCst);
+            self.0.on_force.notify_waiters();
+            self.wait_for_connections(FORCE_CLOSE_WAIT).await;
+        }
+
+        self.summary()
+    }
+
+    /// How cleanly we've shut down so far.
+    pub fn summary(&self) -> ShutdownSummary {
+        let began_at = *self.0.began_at.lock().unwrap();
+        ShutdownSummary {
+            closed_cleanly: self.0.closed_cleanly.load(Ordering::SeqCst),
+            force_closed: self.0.force_closed.load(Ordering::SeqCst),
+            messages_flushed: self.0.messages_flushed.load(Ordering::SeqCst),
+            messages_dropped: self.0.messages_dropped.load(Ordering::SeqCst),
+            duration: began_at.map_or(Duration::ZERO, |at| at.elapsed()),
+        }
+    }
+}
+
+/// Resolves once `flag` is set. Whatever sets it should then notify `notify`.
+async fn wait_for_flag(flag: &AtomicBool, notify: &Notify) {
+    loop {
+        // Waiters are notified from when this is created, so nothing is missed
+        // between checking the flag and waiting:
+        let notified = notify.notified();
+        if flag.load(Ordering::SeqCst) {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Counts a connection as open until it's dropped. See [`Shutdown::connection`].
+pub struct ConnectionGuard(Shutdown);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let inner = &(self.0).0;
+        if self.0.has_begun() && !inner.forced.load(Ordering::SeqCst) {
+            inner.closed_cleanly.fetch_add(1, Ordering::SeqCst);
+        }
+        inner.open_connections.fetch_sub(1, Ordering::SeqCst);
+        inner.on_connection_closed.notify_waiters();
+    }
+}
+
+/// How cleanly the core shut down. Connections and messages are only counted from
+/// when shutdown began.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShutdownSummary {
+    /// Connections that closed by themselves within the grace period.
+    pub closed_cleanly: usize,
+    /// Connections still open at the end of the grace period.
+    pub force_closed: usize,
+    /// Messages sent on to feeds while shutting down.
+    pub messages_flushed: u64,
+    /// Messages queued for feeds which were never sent.
+    pub messages_dropped: u64,
+    /// How long shutting down took.
+    pub duration: Duration,
+}
+
+impl ShutdownSummary {
+    /// The summary in Prometheus' text format, for instance to be picked up by the
+    /// node exporter's textfile collector once we've gone.
+    pub fn prometheus_metrics(&self) -> String {
+        format!(
+            "telemetry_shutdown_connections_closed_cleanly {}\n\
+             telemetry_shutdown_connections_force_closed {}\n\
+             telemetry_shutdown_feed_messages_flushed {}\n\
+             telemetry_shutdown_feed_messages_dropped {}\n\
+             telemetry_shutdown_duration_seconds {}\n",
+            self.closed_cleanly,
+            self.force_closed,
+            self.messages_flushed,
+            self.messages_dropped,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Shut down in {:.2}s: {} connections closed cleanly and {} forcibly; \
+             {} feed messages flushed and {} dropped",
+            self.duration.as_secs_f64(),
+            self.closed_cleanly,
+            self.force_closed,
+            self.messages_flushed,
+            self.messages_dropped
+        )
+    }
+}
+
+/// Resolves when the process is asked to stop, via SIGTERM or Ctrl+C.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("can listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn triggered_resolves_for_waiters_before_and_after_trigger() {
+        let shutdown = Shutdown::new();
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.triggered().await }
+        });
+        tokio::task::yield_now().await;
+
+        shutdown.trigger();
+        waiter.await.unwrap();
+        // Already triggered, so this resolves straight away:
+        shutdown.triggered().await;
+    }
+
+    #[tokio::test]
+    async fn waits_for_connections_to_close() {
+        let shutdown = Shutdown::new();
+        let first = shutdown.connection();
+        let second = shutdown.connection();
+        assert_eq!(shutdown.open_connections(), 2);
+
+        tokio::spawn(async move {
+            drop(first);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(second);
+        });
+        let open = shutdown.wait_for_connections(Duration::from_secs(10)).await;
+        assert_eq!(open, 0);
+    }
+
+    #[tokio::test]
+    async fn summarises_responsive_and_stalled_connections() {
+        let shutdown = Shutdown::new();
+
+        // Closed before shutdown began, so not counted:
+        drop(shutdown.connection());
+        shutdown.record_flushed(100);
+
+        // Responsive connections flush what they have and close when told to:
+        for flushed in [1, 2, 3] {
+            let shutdown = shutdown.clone();
+            let connection = shutdown.connection();
+            tokio::spawn(async move {
+                shutdown.triggered().await;
+                shutdown.record_flushed(flushed);
+                drop(connection);
+            });
+        }
+        // Stalled connections only give up once they're forced to:
+        for dropped in [4, 5] {
+            let shutdown = shutdown.clone();
+            let connection = shutdown.connection();
+            tokio::spawn(async move {
+                shutdown.forced().await;
+                shutdown.record_dropped(dropped);
+                drop(connection);
+            });
+        }
+        tokio::task::yield_now().await;
+
+        shutdown.begin();
+        let summary = shutdown.close_connections(Duration::from_millis(50)).await;
+        assert_eq!(summary.closed_cleanly, 3);
+        assert_eq!(summary.force_closed, 2);
+        assert_eq!(summary.messages_flushed, 6);
+        assert_eq!(summary.messages_dropped, 9);
+        assert!(summary.duration >= Duration::from_millis(50));
+        assert_eq!(shutdown.open_connections(), 0);
+
+        let metrics = summary.prometheus_metrics();
+        assert!(metrics.contains("telemetry_shutdown_connections_closed_cleanly 3\n"));
+        assert!(metrics.contains("telemetry_shutdown_connections_force_closed 2\n"));
+        assert!(metrics.contains("telemetry_shutdown_feed_messages_flushed 6\n"));
+        assert!(metrics.contains("telemetry_shutdown_feed_messages_dropped 9\n"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_waiting_after_the_grace_period() {
+        let shutdown = Shutdown::new();
+        let _stuck = shutdown.connection();
+
+        let open = shutdown
+            .wait_for_connections(Duration::from_millis(50))
+            .await;
+        assert_eq!(open, 1);
+    }
+}
@@ -0,0 +1,1393 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Alerts are raised against nodes when something about them looks unhealthy,
+//! and cleared again once they recover. Feeds are told about both.
+
+use common::node_types::{BlockNumber, ChainType, PruningMode, Timestamp};
+use serde::{Serialize, Serializer};
+
+/// Thresholds used to decide when to raise alerts.
+#[derive(Debug, Clone, Copy)]
+pub struct AlertThresholds {
+    /// Offchain worker queue depths above this count towards a backlog.
+    pub offchain_worker_queue_depth: u32,
+    /// How many consecutive samples must exceed the queue depth
+    /// threshold before we raise an alert.
+    pub offchain_worker_backlog_samples: u32,
+    /// Alerts that have been raised for at least this many milliseconds
+    /// are escalated to [`Severity::Critical`].
+    pub escalate_after_ms: u64,
+    /// Nodes whose finalized block is more than this many blocks behind
+    /// their best block are considered to be lagging.
+    pub finality_lag_blocks: BlockNumber,
+    /// Nodes using more than this fraction of their swap are under memory pressure.
+    pub swap_usage_ratio: f64,
+    /// Nodes whose 95th percentile block import latency is more than this many times
+    /// the median import latency of the chain are importing blocks too slowly.
+    pub slow_block_import_ratio: f64,
+    /// Nodes whose runtime is using more than this fraction of its WASM heap
+    /// are at risk of running out.
+    pub wasm_heap_usage_ratio: f64,
+    /// Validators which prune the state of all but fewer than this many blocks
+    /// are keeping too little history.
+    pub min_validator_pruning_blocks: u32,
+    /// Nodes whose mean CPU steal time is more than this percentage are on an overloaded
+    /// host, and so may perform unpredictably.
+    pub cpu_steal_pct: f64,
+    /// Nodes handling more than this many times the median rate of Kademlia DHT queries
+    /// on their chain may be the target of a DoS attack.
+    pub kademlia_query_rate_ratio: f64,
+    /// Nodes receiving more than this many light client requests per second are
+    /// overwhelmed by light clients.
+    pub light_requests_per_sec: f64,
+    /// Nodes whose 1 minute load average is more than this many times the number of
+    /// CPU cores they have are overloaded.
+    pub load_average_per_core: f64,
+    /// Nodes with more than this fraction of their file descriptor limit open are at
+    /// risk of being unable to accept new connections.
+    pub fd_usage_ratio: f64,
+    /// Nodes whose average reputation with their peers is below this are considered
+    /// to be misbehaving by those peers.
+    pub min_peer_reputation: i32,
+    /// Nodes whose GPU has been busier than this percentage throughout their recent
+    /// samples have no headroom left for the work they use it for.
+    pub gpu_usage_pct: f64,
+    /// Chains which have had at least this many publicly reachable nodes are short of
+    /// them if they drop below it. 0 disables the check.
+    pub min_public_nodes: usize,
+}
+
+impl Default for AlertThresholds {
+    fn default() -> Self {
+        AlertThresholds {
+            offchain_worker_queue_depth: 100,
+            offchain_worker_backlog_samples: 3,
+            escalate_after_ms: 10 * 60 * 1000,
+            finality_lag_blocks: 50,
+            swap_usage_ratio: 0.1,
+            slow_block_import_ratio: 2.0,
+            wasm_heap_usage_ratio: 0.8,
+            min_validator_pruning_blocks: 256,
+            cpu_steal_pct: 5.0,
+            kademlia_query_rate_ratio: 5.0,
+            light_requests_per_sec: 100.0,
+            load_average_per_core: 2.0,
+            fd_usage_ratio: 0.8,
+            min_peer_reputation: -1000,
+            gpu_usage_pct: 90.0,
+            min_public_nodes: 3,
+        }
+    }
+}
+
+/// Nodes whose clocks are more than this many milliseconds away from NTP time
+/// will report misleading block timestamps.
+const MAX_CLOCK_OFFSET_MS: u32 = 500;
+
+/// Validators author blocks, so their clocks are held to a stricter limit.
+const MAX_VALIDATOR_CLOCK_OFFSET_MS: u32 = 100;
+
+/// Is a node whose clock is `offset_ms` away from NTP time close enough to it?
+pub fn is_clock_accurate(offset_ms: i32, is_validator: bool) -> bool {
+    let max_offset_ms = if is_validator {
+        MAX_VALIDATOR_CLOCK_OFFSET_MS
+    } else {
+        MAX_CLOCK_OFFSET_MS
+    };
+    offset_ms.unsigned_abs() <= max_offset_ms
+}
+
+/// Chains that are expected to be less stable (testnets and devnets) are given
+/// this many times more leeway before alerts are raised or escalated.
+const UNSTABLE_CHAIN_TOLERANCE: u32 = 4;
+
+impl AlertThresholds {
+    /// The thresholds that should apply to a chain of the given type.
+    pub fn for_chain_type(&self, chain_type: Option<ChainType>) -> AlertThresholds {
+        match chain_type {
+            Some(chain_type) if chain_type.is_unstable() => AlertThresholds {
+                offchain_worker_queue_depth: self
+                    .offchain_worker_queue_depth
+                    .saturating_mul(UNSTABLE_CHAIN_TOLERANCE),
+                offchain_worker_backlog_samples: self
+                    .offchain_worker_backlog_samples
+                    .saturating_mul(UNSTABLE_CHAIN_TOLERANCE),
+                escalate_after_ms: self
+                    .escalate_after_ms
+                    .saturating_mul(UNSTABLE_CHAIN_TOLERANCE as u64),
+                finality_lag_blocks: self
+                    .finality_lag_blocks
+                    .saturating_mul(UNSTABLE_CHAIN_TOLERANCE as u64),
+                // Swapping, CPU steal and slow imports say something about the machine rather
+                // than the chain, and the WASM heap limit is a hard limit wherever the runtime runs:
+                swap_usage_ratio: self.swap_usage_ratio,
+                slow_block_import_ratio: self.slow_block_import_ratio,
+                wasm_heap_usage_ratio: self.wasm_heap_usage_ratio,
+                min_validator_pruning_blocks: self.min_validator_pruning_blocks,
+                cpu_steal_pct: self.cpu_steal_pct,
+                kademlia_query_rate_ratio: self.kademlia_query_rate_ratio,
+                light_requests_per_sec: self.light_requests_per_sec,
+                load_average_per_core: self.load_average_per_core,
+                fd_usage_ratio: self.fd_usage_ratio,
+                min_peer_reputation: self.min_peer_reputation,
+                gpu_usage_pct: self.gpu_usage_pct,
+                min_public_nodes: self.min_public_nodes,
+            },
+            _ => *self,
+        }
+    }
+}
+
+/// How serious an alert is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+/// The different kinds of alert that can be raised against a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    OffchainWorkerBacklog,
+    FinalityLagging,
+    NodeUsingSwap,
+    SlowBlockImport,
+    WasmHeapPressure,
+    ShallowValidatorPruning,
+    CpuStealDetected,
+    KademliaDDoS,
+    KademliaTableEmpty,
+    LightClientOverload,
+    FinalityConflict,
+    HighLoadAverage,
+    FileDescriptorPressure,
+    ClockDrift,
+    PeerReputationDegraded,
+    GpuPressure,
+    DuplicateValidator,
+}
+
+impl AlertKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertKind::OffchainWorkerBacklog => "OffchainWorkerBacklog",
+            AlertKind::FinalityLagging => "FinalityLagging",
+            AlertKind::NodeUsingSwap => "NodeUsingSwap",
+            AlertKind::SlowBlockImport => "SlowBlockImport",
+            AlertKind::WasmHeapPressure => "WasmHeapPressure",
+            AlertKind::ShallowValidatorPruning => "ShallowValidatorPruning",
+            AlertKind::CpuStealDetected => "CPUStealDetected",
+            AlertKind::KademliaDDoS => "KademliaDDoS",
+            AlertKind::KademliaTableEmpty => "KademliaTableEmpty",
+            AlertKind::LightClientOverload => "LightClientOverload",
+            AlertKind::FinalityConflict => "FinalityConflict",
+            AlertKind::HighLoadAverage => "HighLoadAverage",
+            AlertKind::FileDescriptorPressure => "FileDescriptorPressure",
+            AlertKind::ClockDrift => "ClockDrift",
+            AlertKind::PeerReputationDegraded => "PeerReputationDegraded",
+            AlertKind::GpuPressure => "GPUPressure",
+            AlertKind::DuplicateValidator => "DuplicateValidator",
+        }
+    }
+}
+
+impl Serialize for AlertKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// An alert, along with the value that caused it to be raised.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alert {
+    /// The node's offchain worker queue has been too deep for a while.
+    OffchainWorkerBacklog { depth: u32 },
+    /// The node's finalized block is too far behind its best block.
+    FinalityLagging { gap: BlockNumber },
+    /// The node is using more of its swap than it should; `swap_pct` is from 0 to 100.
+    NodeUsingSwap { swap_pct: f64 },
+    /// The node takes much longer to import blocks than most nodes on the chain.
+    SlowBlockImport { p95_ms: f64 },
+    /// The node's runtime is close to running out of WASM heap; `pct` is from 0 to 100.
+    WasmHeapPressure { pct: f64 },
+    /// The node is a validator that keeps the state of too few blocks.
+    ShallowValidatorPruning { keep_blocks: u32 },
+    /// The hypervisor is taking too much CPU time away from the node; `steal_pct` is from 0 to 100.
+    CpuStealDetected { steal_pct: f64 },
+    /// The node is handling many more Kademlia DHT queries than most nodes on the chain.
+    KademliaDDoS { queries_per_sec: f64 },
+    /// The node's Kademlia DHT was storing records, but now isn't storing any.
+    KademliaTableEmpty,
+    /// The node is receiving too many light client requests from its peers.
+    LightClientOverload { requests_per_sec: f64 },
+    /// The node is a validator that finalized a different block to another validator
+    /// at the same height.
+    FinalityConflict { height: BlockNumber },
+    /// The node's 1 minute load average is too high for the number of CPU cores it has.
+    HighLoadAverage { load_avg_1m: f64 },
+    /// The node has too many of the file descriptors it's allowed open.
+    FileDescriptorPressure { pct: f64 },
+    /// The node's clock is too far away from NTP time; `offset_ms` may be negative.
+    ClockDrift { offset_ms: i32 },
+    /// The node's peers have given it a low reputation, so they think it's misbehaving.
+    PeerReputationDegraded { score: i32 },
+    /// The node's GPU has been kept busy for a while; `pct` is from 0 to 100.
+    GpuPressure { pct: f64 },
+    /// The node claims the same validator address as other nodes on the chain; `nodes`
+    /// is how many nodes claim it, including this one.
+    DuplicateValidator { nodes: usize },
+}
+
+impl Alert {
+    pub fn kind(&self) -> AlertKind {
+        match self {
+            Alert::OffchainWorkerBacklog { .. } => AlertKind::OffchainWorkerBacklog,
+            Alert::FinalityLagging { .. } => AlertKind::FinalityLagging,
+            Alert::NodeUsingSwap { .. } => AlertKind::NodeUsingSwap,
+            Alert::SlowBlockImport { .. } => AlertKind::SlowBlockImport,
+            Alert::WasmHeapPressure { .. } => AlertKind::WasmHeapPressure,
+            Alert::ShallowValidatorPruning { .. } => AlertKind::ShallowValidatorPruning,
+            Alert::CpuStealDetected { .. } => AlertKind::CpuStealDetected,
+            Alert::KademliaDDoS { .. } => AlertKind::KademliaDDoS,
+            Alert::KademliaTableEmpty => AlertKind::KademliaTableEmpty,
+            Alert::LightClientOverload { .. } => AlertKind::LightClientOverload,
+            Alert::FinalityConflict { .. } => AlertKind::FinalityConflict,
+            Alert::HighLoadAverage { .. } => AlertKind::HighLoadAverage,
+            Alert::FileDescriptorPressure { .. } => AlertKind::FileDescriptorPressure,
+            Alert::ClockDrift { .. } => AlertKind::ClockDrift,
+            Alert::PeerReputationDegraded { .. } => AlertKind::PeerReputationDegraded,
+            Alert::GpuPressure { .. } => AlertKind::GpuPressure,
+            Alert::DuplicateValidator { .. } => AlertKind::DuplicateValidator,
+        }
+    }
+
+    /// The value that caused the alert to be raised, if there is one.
+    pub fn value(&self) -> Option<f64> {
+        match *self {
+            Alert::OffchainWorkerBacklog { depth } => Some(depth as f64),
+            Alert::FinalityLagging { gap } => Some(gap as f64),
+            Alert::NodeUsingSwap { swap_pct } => Some(swap_pct),
+            Alert::SlowBlockImport { p95_ms } => Some(p95_ms),
+            Alert::WasmHeapPressure { pct } => Some(pct),
+            Alert::ShallowValidatorPruning { keep_blocks } => Some(keep_blocks as f64),
+            Alert::CpuStealDetected { steal_pct } => Some(steal_pct),
+            Alert::KademliaDDoS { queries_per_sec } => Some(queries_per_sec),
+            Alert::KademliaTableEmpty => None,
+            Alert::LightClientOverload { requests_per_sec } => Some(requests_per_sec),
+            Alert::FinalityConflict { height } => Some(height as f64),
+            Alert::HighLoadAverage { load_avg_1m } => Some(load_avg_1m),
+            Alert::FileDescriptorPressure { pct } => Some(pct),
+            Alert::ClockDrift { offset_ms } => Some(offset_ms as f64),
+            Alert::PeerReputationDegraded { score } => Some(score as f64),
+            Alert::GpuPressure { pct } => Some(pct),
+            Alert::DuplicateValidator { nodes } => Some(nodes as f64),
+        }
+    }
+}
+
+/// An alert that is currently raised against a node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ActiveAlert {
+    pub alert: Alert,
+    pub severity: Severity,
+    /// When the alert was first raised.
+    pub raised_at: Timestamp,
+}
+
+/// A change in the alerts raised against a node, which feeds should be told about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertChange {
+    /// An alert was raised, or its severity changed.
+    Raised(ActiveAlert),
+    /// An alert was cleared.
+    Cleared(AlertKind),
+}
+
+/// Keeps track of the alerts raised against a single node.
+#[derive(Debug, Clone, Default)]
+pub struct NodeAlerts {
+    active: Vec<ActiveAlert>,
+    /// How many samples in a row have had a deep offchain worker queue.
+    offchain_worker_backlog_samples: u32,
+    /// Has the node's Kademlia DHT ever been storing any records?
+    has_stored_kademlia_records: bool,
+}
+
+impl NodeAlerts {
+    /// The alerts that are currently raised.
+    pub fn active(&self) -> &[ActiveAlert] {
+        &self.active
+    }
+
+    /// Is an alert of the given kind currently raised?
+    pub fn is_raised(&self, kind: AlertKind) -> bool {
+        self.active.iter().any(|a| a.alert.kind() == kind)
+    }
+
+    /// Take note of the latest offchain worker queue depth reported by a node.
+    pub fn offchain_worker_queue_depth(
+        &mut self,
+        depth: u32,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if depth <= thresholds.offchain_worker_queue_depth {
+            self.offchain_worker_backlog_samples = 0;
+            return self.clear(AlertKind::OffchainWorkerBacklog);
+        }
+
+        self.offchain_worker_backlog_samples =
+            self.offchain_worker_backlog_samples.saturating_add(1);
+        if self.offchain_worker_backlog_samples < thresholds.offchain_worker_backlog_samples {
+            return None;
+        }
+
+        self.raise(
+            Alert::OffchainWorkerBacklog { depth },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of a node's best and finalized block heights.
+    pub fn finality_lag(
+        &mut self,
+        best: BlockNumber,
+        finalized: BlockNumber,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let gap = best.saturating_sub(finalized);
+        if gap <= thresholds.finality_lag_blocks {
+            return self.clear(AlertKind::FinalityLagging);
+        }
+        self.raise(
+            Alert::FinalityLagging { gap },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of whether the block that a validator just finalized conflicts with
+    /// the block finalized by another validator at the same height. This is always
+    /// critical, and stays raised until the validator finalizes a block without conflict.
+    pub fn finality_conflict(
+        &mut self,
+        conflicting_height: Option<BlockNumber>,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        match conflicting_height {
+            Some(height) => self.raise(
+                Alert::FinalityConflict { height },
+                Severity::Critical,
+                thresholds,
+                now,
+            ),
+            None => self.clear(AlertKind::FinalityConflict),
+        }
+    }
+
+    /// Take note of how many nodes on the chain claim the same validator address as
+    /// this one (including itself). More than one means a backup validator is probably
+    /// running when it shouldn't be, and stays raised until the others go away.
+    pub fn duplicate_validator(
+        &mut self,
+        nodes: usize,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if nodes <= 1 {
+            return self.clear(AlertKind::DuplicateValidator);
+        }
+        self.raise(
+            Alert::DuplicateValidator { nodes },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of how much swap a node is using. Validators are alerted about
+    /// this more urgently than other nodes.
+    pub fn swap_usage(
+        &mut self,
+        used: u64,
+        total: u64,
+        is_validator: bool,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if total == 0 || used as f64 / total as f64 <= thresholds.swap_usage_ratio {
+            return self.clear(AlertKind::NodeUsingSwap);
+        }
+
+        let swap_pct = used as f64 * 100.0 / total as f64;
+        let severity = if is_validator {
+            Severity::Critical
+        } else {
+            Severity::Warning
+        };
+        self.raise(Alert::NodeUsingSwap { swap_pct }, severity, thresholds, now)
+    }
+
+    /// Take note of how much of its WASM heap a node's runtime is using.
+    pub fn wasm_heap_usage(
+        &mut self,
+        used: u64,
+        limit: u64,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if limit == 0 || used as f64 / limit as f64 <= thresholds.wasm_heap_usage_ratio {
+            return self.clear(AlertKind::WasmHeapPressure);
+        }
+
+        let pct = used as f64 * 100.0 / limit as f64;
+        self.raise(
+            Alert::WasmHeapPressure { pct },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of the mean percentage of CPU time being taken away from a node by
+    /// the hypervisor that it's running on.
+    pub fn cpu_steal(
+        &mut self,
+        steal_pct: f64,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if steal_pct <= thresholds.cpu_steal_pct {
+            return self.clear(AlertKind::CpuStealDetected);
+        }
+        self.raise(
+            Alert::CpuStealDetected { steal_pct },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of how busy a node's GPU has been; `pct` is the lowest of its recent
+    /// usage samples, so the alert is only raised if it's been busy throughout.
+    pub fn gpu_usage(
+        &mut self,
+        pct: f64,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if pct <= thresholds.gpu_usage_pct {
+            return self.clear(AlertKind::GpuPressure);
+        }
+        self.raise(
+            Alert::GpuPressure { pct },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of a node's 1 minute load average, given how many CPU cores it has.
+    pub fn load_average(
+        &mut self,
+        load_avg_1m: f64,
+        cpu_cores: u32,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if load_avg_1m <= cpu_cores as f64 * thresholds.load_average_per_core {
+            return self.clear(AlertKind::HighLoadAverage);
+        }
+        self.raise(
+            Alert::HighLoadAverage { load_avg_1m },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of how many file descriptors a node has open, given how many it's allowed.
+    pub fn fd_usage(
+        &mut self,
+        open: u32,
+        limit: u32,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if limit == 0 || open as f64 / limit as f64 <= thresholds.fd_usage_ratio {
+            return self.clear(AlertKind::FileDescriptorPressure);
+        }
+
+        let pct = open as f64 * 100.0 / limit as f64;
+        self.raise(
+            Alert::FileDescriptorPressure { pct },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of how far a node's clock is from NTP time. Validators are held
+    /// to a stricter limit than other nodes; see [`is_clock_accurate`].
+    pub fn clock_offset(
+        &mut self,
+        offset_ms: i32,
+        is_validator: bool,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if is_clock_accurate(offset_ms, is_validator) {
+            return self.clear(AlertKind::ClockDrift);
+        }
+        self.raise(
+            Alert::ClockDrift { offset_ms },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of the average reputation that a node's peers have given it.
+    pub fn peer_reputation(
+        &mut self,
+        score: i32,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if score >= thresholds.min_peer_reputation {
+            return self.clear(AlertKind::PeerReputationDegraded);
+        }
+        self.raise(
+            Alert::PeerReputationDegraded { score },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of how many light client requests a node is receiving each second.
+    pub fn light_requests(
+        &mut self,
+        requests_per_sec: f64,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if requests_per_sec <= thresholds.light_requests_per_sec {
+            return self.clear(AlertKind::LightClientOverload);
+        }
+        self.raise(
+            Alert::LightClientOverload { requests_per_sec },
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Take note of how a node prunes old block state. Only validators running in
+    /// constrained mode with too few blocks kept are alerted about.
+    pub fn pruning_mode(
+        &mut self,
+        pruning_mode: Option<PruningMode>,
+        is_validator: bool,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        match pruning_mode {
+            Some(PruningMode::Constrained { keep_blocks })
+                if is_validator && keep_blocks < thresholds.min_validator_pruning_blocks =>
+            {
+                self.raise(
+                    Alert::ShallowValidatorPruning { keep_blocks },
+                    Severity::Warning,
+                    thresholds,
+                    now,
+                )
+            }
+            _ => self.clear(AlertKind::ShallowValidatorPruning),
+        }
+    }
+
+    /// Take note of how long a node takes to import blocks compared to the rest of the
+    /// chain. If we don't know the chain median, we can't say whether the node is slow.
+    pub fn block_import_latency(
+        &mut self,
+        p95_ms: f64,
+        chain_median_ms: Option<f64>,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        match chain_median_ms {
+            Some(median) if p95_ms > median * thresholds.slow_block_import_ratio => self.raise(
+                Alert::SlowBlockImport { p95_ms },
+                Severity::Warning,
+                thresholds,
+                now,
+            ),
+            _ => self.clear(AlertKind::SlowBlockImport),
+        }
+    }
+
+    /// Take note of how many Kademlia DHT queries a node is handling each second, compared
+    /// to the rest of the chain. If we don't know the chain median, we can't say whether the
+    /// node is being flooded.
+    pub fn kademlia_query_rate(
+        &mut self,
+        queries_per_sec: f64,
+        chain_median: Option<f64>,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        match chain_median {
+            Some(median) if queries_per_sec > median * thresholds.kademlia_query_rate_ratio => self
+                .raise(
+                    Alert::KademliaDDoS { queries_per_sec },
+                    Severity::Warning,
+                    thresholds,
+                    now,
+                ),
+            _ => self.clear(AlertKind::KademliaDDoS),
+        }
+    }
+
+    /// Take note of how many records a node's Kademlia DHT is storing. Nodes that have
+    /// never stored any (perhaps because they've only just started) are left alone.
+    pub fn kademlia_records_stored(
+        &mut self,
+        records: u32,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if records > 0 {
+            self.has_stored_kademlia_records = true;
+            return self.clear(AlertKind::KademliaTableEmpty);
+        }
+        if !self.has_stored_kademlia_records {
+            return None;
+        }
+        self.raise(
+            Alert::KademliaTableEmpty,
+            Severity::Warning,
+            thresholds,
+            now,
+        )
+    }
+
+    /// Raise an alert, or update it if it's already raised. Alerts that have been raised
+    /// for long enough are escalated. Feeds only need telling if the alert is new or its
+    /// severity has changed.
+    fn raise(
+        &mut self,
+        alert: Alert,
+        severity: Severity,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let kind = alert.kind();
+        let active = match self.active.iter_mut().find(|a| a.alert.kind() == kind) {
+            Some(active) => active,
+            None => {
+                let active = ActiveAlert {
+                    alert,
+                    severity,
+                    raised_at: now,
+                };
+                self.active.push(active);
+                return Some(AlertChange::Raised(active));
+            }
+        };
+
+        let severity = if now.saturating_sub(active.raised_at) >= thresholds.escalate_after_ms {
+            Severity::Critical
+        } else {
+            severity
+        };
+
+        active.alert = alert;
+        if active.severity != severity {
+            active.severity = severity;
+            Some(AlertChange::Raised(*active))
+        } else {
+            None
+        }
+    }
+
+    /// Clear an alert, if it's raised.
+    fn clear(&mut self, kind: AlertKind) -> Option<AlertChange> {
+        let idx = self.active.iter().position(|a| a.alert.kind() == kind)?;
+        self.active.remove(idx);
+        Some(AlertChange::Cleared(kind))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MINUTE: u64 = 60 * 1000;
+
+    fn thresholds() -> AlertThresholds {
+        AlertThresholds {
+            offchain_worker_queue_depth: 10,
+            offchain_worker_backlog_samples: 3,
+            escalate_after_ms: 10 * MINUTE,
+            finality_lag_blocks: 10,
+            swap_usage_ratio: 0.1,
+            slow_block_import_ratio: 2.0,
+            wasm_heap_usage_ratio: 0.8,
+            min_validator_pruning_blocks: 256,
+            cpu_steal_pct: 5.0,
+            kademlia_query_rate_ratio: 5.0,
+            light_requests_per_sec: 100.0,
+            load_average_per_core: 2.0,
+            fd_usage_ratio: 0.8,
+            min_peer_reputation: -1000,
+            gpu_usage_pct: 90.0,
+            min_public_nodes: 3,
+        }
+    }
+
+    #[test]
+    fn gpu_pressure_alert_raised_above_threshold() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        assert_eq!(alerts.gpu_usage(50.0, &t, 0), None);
+        assert_eq!(alerts.gpu_usage(90.0, &t, 0), None);
+        assert_eq!(
+            alerts.gpu_usage(95.0, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::GpuPressure { pct: 95.0 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(alerts.gpu_usage(99.0, &t, 2), None);
+        assert_eq!(
+            alerts.gpu_usage(80.0, &t, 3),
+            Some(AlertChange::Cleared(AlertKind::GpuPressure))
+        );
+    }
+
+    #[test]
+    fn high_load_average_alert_raised_above_multiple_of_cores() {
+        use common::node_types::NodeHardware;
+
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+        let mut check = |load_avg_1m, now| {
+            let hardware = NodeHardware {
+                load_avg_1m: Some(load_avg_1m),
+                load_avg_5m: Some(1.0),
+                load_avg_15m: Some(1.0),
+                cpu_cores: Some(4),
+                ..NodeHardware::default()
+            };
+            alerts.load_average(
+                hardware.load_avg_1m.unwrap() as f64,
+                hardware.cpu_cores.unwrap(),
+                &t,
+                now,
+            )
+        };
+
+        // 4 cores, so a load of up to 8 is fine:
+        assert_eq!(check(3.0, 0), None);
+        assert_eq!(check(8.0, 0), None);
+        assert_eq!(
+            check(9.5, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::HighLoadAverage { load_avg_1m: 9.5 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(check(12.0, 2), None);
+        assert_eq!(
+            check(4.0, 3),
+            Some(AlertChange::Cleared(AlertKind::HighLoadAverage))
+        );
+    }
+
+    #[test]
+    fn fd_pressure_alert_raised_above_threshold() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        // Up to 80% of the limit is fine:
+        assert_eq!(alerts.fd_usage(500, 1000, &t, 0), None);
+        assert_eq!(alerts.fd_usage(800, 1000, &t, 0), None);
+        // A limit of 0 tells us nothing:
+        assert_eq!(alerts.fd_usage(800, 0, &t, 0), None);
+        assert_eq!(
+            alerts.fd_usage(900, 1000, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::FileDescriptorPressure { pct: 90.0 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(alerts.fd_usage(950, 1000, &t, 2), None);
+        assert_eq!(
+            alerts.fd_usage(100, 1000, &t, 3),
+            Some(AlertChange::Cleared(AlertKind::FileDescriptorPressure))
+        );
+    }
+
+    #[test]
+    fn clock_accuracy_threshold_for_non_validators() {
+        assert!(is_clock_accurate(0, false));
+        assert!(is_clock_accurate(500, false));
+        assert!(is_clock_accurate(-500, false));
+        assert!(!is_clock_accurate(501, false));
+        assert!(!is_clock_accurate(-501, false));
+        assert!(!is_clock_accurate(i32::MIN, false));
+    }
+
+    #[test]
+    fn clock_accuracy_threshold_for_validators_is_stricter() {
+        assert!(is_clock_accurate(100, true));
+        assert!(is_clock_accurate(-100, true));
+        assert!(!is_clock_accurate(101, true));
+        assert!(!is_clock_accurate(-300, true));
+    }
+
+    #[test]
+    fn clock_drift_alert_raised_and_cleared() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        // 300ms out is fine for a regular node, but not for a validator:
+        assert_eq!(alerts.clock_offset(-300, false, &t, 0), None);
+        assert_eq!(
+            alerts.clock_offset(-300, true, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::ClockDrift { offset_ms: -300 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(alerts.clock_offset(250, true, &t, 2), None);
+        assert_eq!(
+            alerts.active()[0].alert,
+            Alert::ClockDrift { offset_ms: 250 }
+        );
+        assert_eq!(
+            alerts.clock_offset(20, true, &t, 3),
+            Some(AlertChange::Cleared(AlertKind::ClockDrift))
+        );
+    }
+
+    #[test]
+    fn peer_reputation_alert_raised_below_threshold() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        // Positive, zero and mildly negative reputations are fine:
+        assert_eq!(alerts.peer_reputation(500, &t, 0), None);
+        assert_eq!(alerts.peer_reputation(0, &t, 0), None);
+        assert_eq!(alerts.peer_reputation(-999, &t, 0), None);
+        // Exactly at the threshold is still fine:
+        assert_eq!(alerts.peer_reputation(-1000, &t, 0), None);
+        assert!(alerts.active().is_empty());
+
+        assert_eq!(
+            alerts.peer_reputation(-1001, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::PeerReputationDegraded { score: -1001 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(alerts.peer_reputation(i32::MIN, &t, 2), None);
+        assert_eq!(
+            alerts.active()[0].alert,
+            Alert::PeerReputationDegraded { score: i32::MIN }
+        );
+        assert_eq!(
+            alerts.peer_reputation(-1000, &t, 3),
+            Some(AlertChange::Cleared(AlertKind::PeerReputationDegraded))
+        );
+    }
+
+    #[test]
+    fn light_client_overload_alert_raised_above_threshold() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        assert_eq!(alerts.light_requests(10.0, &t, 0), None);
+        assert_eq!(alerts.light_requests(100.0, &t, 0), None);
+
+        assert_eq!(
+            alerts.light_requests(150.0, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::LightClientOverload {
+                    requests_per_sec: 150.0
+                },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(alerts.light_requests(200.0, &t, 2), None);
+        assert_eq!(
+            alerts.light_requests(50.0, &t, 3),
+            Some(AlertChange::Cleared(AlertKind::LightClientOverload))
+        );
+    }
+
+    #[test]
+    fn cpu_steal_alert_raised_above_threshold() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        // 5% is not over the threshold:
+        assert_eq!(alerts.cpu_steal(0.0, &t, 0), None);
+        assert_eq!(alerts.cpu_steal(5.0, &t, 0), None);
+
+        assert_eq!(
+            alerts.cpu_steal(7.5, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::CpuStealDetected { steal_pct: 7.5 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        // Still raised, so nothing new to tell feeds:
+        assert_eq!(alerts.cpu_steal(8.0, &t, 2), None);
+        assert_eq!(
+            alerts.cpu_steal(1.0, &t, 3),
+            Some(AlertChange::Cleared(AlertKind::CpuStealDetected))
+        );
+    }
+
+    #[test]
+    fn pruning_alert_only_raised_for_shallow_validators() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+        let shallow = Some(PruningMode::Constrained { keep_blocks: 100 });
+
+        // Archive nodes, nodes keeping enough blocks, nodes that don't say, and
+        // non validators are all left alone:
+        assert_eq!(
+            alerts.pruning_mode(Some(PruningMode::Archive), true, &t, 0),
+            None
+        );
+        assert_eq!(
+            alerts.pruning_mode(
+                Some(PruningMode::Constrained { keep_blocks: 256 }),
+                true,
+                &t,
+                0
+            ),
+            None
+        );
+        assert_eq!(alerts.pruning_mode(None, true, &t, 0), None);
+        assert_eq!(alerts.pruning_mode(shallow, false, &t, 0), None);
+
+        assert_eq!(
+            alerts.pruning_mode(shallow, true, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::ShallowValidatorPruning { keep_blocks: 100 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(
+            alerts.pruning_mode(Some(PruningMode::Archive), true, &t, 2),
+            Some(AlertChange::Cleared(AlertKind::ShallowValidatorPruning))
+        );
+    }
+
+    #[test]
+    fn wasm_heap_alert_raised_above_threshold() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        // 80% is not over the threshold, and a zero limit tells us nothing:
+        assert_eq!(alerts.wasm_heap_usage(80, 100, &t, 0), None);
+        assert_eq!(alerts.wasm_heap_usage(80, 0, &t, 0), None);
+
+        assert_eq!(
+            alerts.wasm_heap_usage(90, 100, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::WasmHeapPressure { pct: 90.0 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(
+            alerts.wasm_heap_usage(50, 100, &t, 2),
+            Some(AlertChange::Cleared(AlertKind::WasmHeapPressure))
+        );
+        assert!(alerts.active().is_empty());
+    }
+
+    #[test]
+    fn slow_block_import_is_relative_to_chain_median() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        // Without a chain median to compare against, nothing is raised:
+        assert_eq!(alerts.block_import_latency(1000.0, None, &t, 0), None);
+        // Exactly twice the median isn't over the threshold:
+        assert_eq!(alerts.block_import_latency(200.0, Some(100.0), &t, 0), None);
+
+        assert_eq!(
+            alerts.block_import_latency(250.0, Some(100.0), &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::SlowBlockImport { p95_ms: 250.0 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        // The rest of the chain slows down too, so this node is no longer unusual:
+        assert_eq!(
+            alerts.block_import_latency(250.0, Some(200.0), &t, 2),
+            Some(AlertChange::Cleared(AlertKind::SlowBlockImport))
+        );
+    }
+
+    #[test]
+    fn kademlia_ddos_is_relative_to_chain_median() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        // Without a chain median to compare against, nothing is raised:
+        assert_eq!(alerts.kademlia_query_rate(1000.0, None, &t, 0), None);
+        // Exactly five times the median isn't over the threshold:
+        assert_eq!(alerts.kademlia_query_rate(50.0, Some(10.0), &t, 0), None);
+
+        assert_eq!(
+            alerts.kademlia_query_rate(60.0, Some(10.0), &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::KademliaDDoS {
+                    queries_per_sec: 60.0
+                },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(
+            alerts.kademlia_query_rate(20.0, Some(10.0), &t, 2),
+            Some(AlertChange::Cleared(AlertKind::KademliaDDoS))
+        );
+    }
+
+    #[test]
+    fn kademlia_table_empty_only_after_storing_records() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        // A node that has never stored records (eg it's just started) is left alone:
+        assert_eq!(alerts.kademlia_records_stored(0, &t, 0), None);
+        assert_eq!(alerts.kademlia_records_stored(120, &t, 1), None);
+
+        assert_eq!(
+            alerts.kademlia_records_stored(0, &t, 2),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::KademliaTableEmpty,
+                severity: Severity::Warning,
+                raised_at: 2,
+            }))
+        );
+        assert_eq!(alerts.kademlia_records_stored(0, &t, 3), None);
+        assert_eq!(
+            alerts.kademlia_records_stored(5, &t, 4),
+            Some(AlertChange::Cleared(AlertKind::KademliaTableEmpty))
+        );
+        assert!(alerts.active().is_empty());
+    }
+
+    #[test]
+    fn swap_alert_for_non_validator_is_a_warning() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        // 10% is not over the threshold, and nodes without swap are fine:
+        assert_eq!(alerts.swap_usage(100, 1000, false, &t, 0), None);
+        assert_eq!(alerts.swap_usage(0, 0, false, &t, 0), None);
+
+        assert_eq!(
+            alerts.swap_usage(250, 1000, false, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::NodeUsingSwap { swap_pct: 25.0 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        // Still swapping; nothing new to say:
+        assert_eq!(alerts.swap_usage(300, 1000, false, &t, 2), None);
+        assert_eq!(
+            alerts.active()[0].alert,
+            Alert::NodeUsingSwap { swap_pct: 30.0 }
+        );
+
+        assert_eq!(
+            alerts.swap_usage(50, 1000, false, &t, 3),
+            Some(AlertChange::Cleared(AlertKind::NodeUsingSwap))
+        );
+        assert!(alerts.active().is_empty());
+    }
+
+    #[test]
+    fn swap_alert_for_validator_is_critical() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        assert_eq!(alerts.swap_usage(100, 1000, true, &t, 0), None);
+        assert_eq!(
+            alerts.swap_usage(500, 1000, true, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::NodeUsingSwap { swap_pct: 50.0 },
+                severity: Severity::Critical,
+                raised_at: 1,
+            }))
+        );
+    }
+
+    #[test]
+    fn unstable_chains_are_given_more_leeway() {
+        let t = thresholds();
+        for chain_type in &[
+            None,
+            Some(ChainType::RelayChain),
+            Some(ChainType::Parachain),
+        ] {
+            let same = t.for_chain_type(*chain_type);
+            assert_eq!(same.finality_lag_blocks, t.finality_lag_blocks);
+            assert_eq!(same.escalate_after_ms, t.escalate_after_ms);
+        }
+
+        let testnet = t.for_chain_type(Some(ChainType::Testnet));
+        assert_eq!(testnet.offchain_worker_queue_depth, 40);
+        assert_eq!(testnet.offchain_worker_backlog_samples, 12);
+        assert_eq!(testnet.escalate_after_ms, 40 * MINUTE);
+        assert_eq!(testnet.finality_lag_blocks, 40);
+
+        // A gap that would raise an alert on a production chain doesn't on a devnet:
+        let devnet = t.for_chain_type(Some(ChainType::Devnet));
+        let mut alerts = NodeAlerts::default();
+        assert_eq!(alerts.finality_lag(100, 80, &devnet, 0), None);
+        assert!(alerts.finality_lag(100, 80, &t, 0).is_some());
+    }
+
+    #[test]
+    fn backlog_needs_consecutive_samples_over_threshold() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        assert_eq!(alerts.offchain_worker_queue_depth(11, &t, 0), None);
+        assert_eq!(alerts.offchain_worker_queue_depth(12, &t, 1), None);
+        // A sample at the threshold isn't over it, so resets the count:
+        assert_eq!(alerts.offchain_worker_queue_depth(10, &t, 2), None);
+        assert_eq!(alerts.offchain_worker_queue_depth(11, &t, 3), None);
+        assert_eq!(alerts.offchain_worker_queue_depth(12, &t, 4), None);
+        assert!(alerts.active().is_empty());
+
+        let change = alerts.offchain_worker_queue_depth(13, &t, 5);
+        assert_eq!(
+            change,
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::OffchainWorkerBacklog { depth: 13 },
+                severity: Severity::Warning,
+                raised_at: 5,
+            }))
+        );
+        assert_eq!(alerts.active().len(), 1);
+    }
+
+    #[test]
+    fn raised_backlog_updates_quietly_and_clears_on_recovery() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+        for now in 0..3 {
+            alerts.offchain_worker_queue_depth(20, &t, now);
+        }
+        assert_eq!(alerts.active().len(), 1);
+
+        // Still backed up; we track the latest depth but feeds don't need telling:
+        assert_eq!(alerts.offchain_worker_queue_depth(30, &t, 4), None);
+        assert_eq!(
+            alerts.active()[0].alert,
+            Alert::OffchainWorkerBacklog { depth: 30 }
+        );
+
+        // Recovered:
+        assert_eq!(
+            alerts.offchain_worker_queue_depth(0, &t, 5),
+            Some(AlertChange::Cleared(AlertKind::OffchainWorkerBacklog))
+        );
+        assert!(alerts.active().is_empty());
+
+        // Clearing again is a no-op:
+        assert_eq!(alerts.offchain_worker_queue_depth(0, &t, 6), None);
+    }
+
+    #[test]
+    fn long_running_backlog_is_escalated() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+        for now in 0..3 {
+            alerts.offchain_worker_queue_depth(20, &t, now);
+        }
+        let raised_at = 2;
+
+        // Not raised for long enough yet:
+        assert_eq!(
+            alerts.offchain_worker_queue_depth(20, &t, raised_at + 10 * MINUTE - 1),
+            None
+        );
+
+        // Now it has been, so it's escalated (once):
+        let change = alerts.offchain_worker_queue_depth(25, &t, raised_at + 10 * MINUTE);
+        assert_eq!(
+            change,
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::OffchainWorkerBacklog { depth: 25 },
+                severity: Severity::Critical,
+                raised_at,
+            }))
+        );
+        assert_eq!(
+            alerts.offchain_worker_queue_depth(25, &t, raised_at + 11 * MINUTE),
+            None
+        );
+
+        // Once cleared, a new backlog starts again at a warning:
+        alerts.offchain_worker_queue_depth(0, &t, raised_at + 12 * MINUTE);
+        let mut change = None;
+        for now in 0..3 {
+            change = alerts.offchain_worker_queue_depth(20, &t, raised_at + 13 * MINUTE + now);
+        }
+        match change {
+            Some(AlertChange::Raised(active)) => assert_eq!(active.severity, Severity::Warning),
+            other => panic!("expected alert to be raised, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn finality_lag_raised_and_cleared_at_threshold() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+        let finalized = 100;
+
+        // Widen the gap between best and finalized; nothing until we're over the threshold:
+        for gap in 0..=10 {
+            assert_eq!(
+                alerts.finality_lag(finalized + gap, finalized, &t, gap),
+                None
+            );
+        }
+        assert_eq!(
+            alerts.finality_lag(finalized + 11, finalized, &t, 11),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::FinalityLagging { gap: 11 },
+                severity: Severity::Warning,
+                raised_at: 11,
+            }))
+        );
+
+        // Widening further just updates the alert:
+        assert_eq!(alerts.finality_lag(finalized + 20, finalized, &t, 12), None);
+        assert_eq!(alerts.active()[0].alert, Alert::FinalityLagging { gap: 20 });
+
+        // Narrow the gap again as finality catches up; it stays raised while over the threshold:
+        assert_eq!(
+            alerts.finality_lag(finalized + 20, finalized + 9, &t, 13),
+            None
+        );
+        assert_eq!(
+            alerts.finality_lag(finalized + 20, finalized + 10, &t, 14),
+            Some(AlertChange::Cleared(AlertKind::FinalityLagging))
+        );
+        assert!(alerts.active().is_empty());
+        assert_eq!(
+            alerts.finality_lag(finalized + 20, finalized + 15, &t, 15),
+            None
+        );
+    }
+
+    #[test]
+    fn finality_conflicts_are_critical_until_resolved() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        assert_eq!(alerts.finality_conflict(None, &t, 0), None);
+        assert_eq!(
+            alerts.finality_conflict(Some(100), &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::FinalityConflict { height: 100 },
+                severity: Severity::Critical,
+                raised_at: 1,
+            }))
+        );
+        assert_eq!(
+            alerts.finality_conflict(None, &t, 2),
+            Some(AlertChange::Cleared(AlertKind::FinalityConflict))
+        );
+    }
+
+    #[test]
+    fn duplicate_validator_raised_while_others_claim_the_address() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+
+        assert_eq!(alerts.duplicate_validator(1, &t, 0), None);
+        assert_eq!(
+            alerts.duplicate_validator(2, &t, 1),
+            Some(AlertChange::Raised(ActiveAlert {
+                alert: Alert::DuplicateValidator { nodes: 2 },
+                severity: Severity::Warning,
+                raised_at: 1,
+            }))
+        );
+        assert!(alerts.is_raised(AlertKind::DuplicateValidator));
+
+        // More nodes claiming the address is tracked quietly:
+        assert_eq!(alerts.duplicate_validator(3, &t, 2), None);
+        assert_eq!(
+            alerts.duplicate_validator(1, &t, 3),
+            Some(AlertChange::Cleared(AlertKind::DuplicateValidator))
+        );
+        assert!(!alerts.is_raised(AlertKind::DuplicateValidator));
+    }
+
+    #[test]
+    fn finality_lag_and_backlog_are_tracked_separately() {
+        let t = thresholds();
+        let mut alerts = NodeAlerts::default();
+        for now in 0..3 {
+            alerts.offchain_worker_queue_depth(20, &t, now);
+        }
+        alerts.finality_lag(50, 0, &t, 3);
+        assert_eq!(alerts.active().len(), 2);
+
+        assert_eq!(
+            alerts.finality_lag(50, 50, &t, 4),
+            Some(AlertChange::Cleared(AlertKind::FinalityLagging))
+        );
+        assert_eq!(
+            alerts.active()[0].alert.kind(),
+            AlertKind::OffchainWorkerBacklog
+        );
+    }
+}
@@ -0,0 +1,101 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! When we first heard about each of the recent blocks on a chain (from any node).
+//! Comparing this with when each node announces a block tells us how far behind
+//! the rest of the network the node is, without relying on its clock.
+
+use common::node_types::{BlockHash, Timestamp};
+use std::collections::{HashMap, VecDeque};
+
+/// How many blocks we remember first-seen times for. Once a block falls out of
+/// this window, a node announcing it will look like it's the first to do so.
+pub const MAX_BLOCKS: usize = 256;
+
+/// A bounded map from recent block hashes to when we first saw them.
+#[derive(Debug, Clone, Default)]
+pub struct BlockFirstSeen {
+    first_seen: HashMap<BlockHash, Timestamp>,
+    /// Hashes in the order that we first saw them, so that we evict the oldest first.
+    order: VecDeque<BlockHash>,
+}
+
+impl BlockFirstSeen {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note that the block with the given hash was seen at `now`, returning
+    /// when it was first seen (which is `now` if we've not seen it before).
+    pub fn observe(&mut self, hash: BlockHash, now: Timestamp) -> Timestamp {
+        if let Some(&first_seen) = self.first_seen.get(&hash) {
+            return first_seen;
+        }
+        if self.order.len() >= MAX_BLOCKS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.first_seen.remove(&oldest);
+            }
+        }
+        self.first_seen.insert(hash, now);
+        self.order.push_back(hash);
+        now
+    }
+
+    /// Forget every block.
+    pub fn clear(&mut self) {
+        self.first_seen.clear();
+        self.order.clear();
+    }
+
+    /// Roughly how many bytes the blocks we remember take up.
+    pub fn memory_usage(&self) -> usize {
+        let entry = std::mem::size_of::<BlockHash>() + std::mem::size_of::<Timestamp>();
+        self.first_seen.len() * entry + self.order.len() * std::mem::size_of::<BlockHash>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn first_sighting_of_a_block_is_remembered() {
+        let mut seen = BlockFirstSeen::new();
+        let hash = BlockHash::from_low_u64_be(1);
+
+        assert_eq!(seen.observe(hash, 1000), 1000);
+        assert_eq!(seen.observe(hash, 1250), 1000);
+        assert_eq!(seen.observe(BlockHash::from_low_u64_be(2), 1300), 1300);
+    }
+
+    #[test]
+    fn oldest_blocks_are_evicted() {
+        let mut seen = BlockFirstSeen::new();
+        for n in 0..(MAX_BLOCKS as u64 + 10) {
+            seen.observe(BlockHash::from_low_u64_be(n), n);
+        }
+        assert_eq!(seen.first_seen.len(), MAX_BLOCKS);
+
+        // The oldest blocks have been forgotten, so look new again:
+        assert_eq!(seen.observe(BlockHash::from_low_u64_be(0), 5000), 5000);
+        // But more recent ones have not:
+        let recent = MAX_BLOCKS as u64 + 9;
+        assert_eq!(
+            seen.observe(BlockHash::from_low_u64_be(recent), 5000),
+            recent
+        );
+    }
+}
@@ -0,0 +1,169 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Works out whether a chain has stopped producing blocks. A chain whose best block
+//! hasn't advanced in many times its usual block time is almost certainly down.
+//!
+//! Once every node on a stalled chain has gone stale, the chain's best block and block
+//! times are reset, so we keep track of the highest block and recent block times here
+//! separately, where they survive that.
+
+use common::node_types::{BlockNumber, Timestamp};
+use std::collections::VecDeque;
+
+/// A chain is never considered stalled until we've seen this many intervals between its
+/// best blocks, so that we have some idea of what its usual block time is.
+pub const MIN_BLOCK_TIME_SAMPLES: usize = 5;
+
+/// How many of the most recent intervals between best blocks the usual block time is
+/// worked out from.
+const MAX_BLOCK_TIME_SAMPLES: usize = 32;
+
+/// How long a chain's best block must go without advancing before the chain is
+/// considered to have stalled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StallThreshold {
+    /// This many times the chain's usual block time...
+    pub block_time_multiple: f64,
+    /// ... or this many milliseconds, whichever is longer.
+    pub min_ms: u64,
+}
+
+impl Default for StallThreshold {
+    fn default() -> Self {
+        StallThreshold {
+            block_time_multiple: 10.0,
+            min_ms: 60_000,
+        }
+    }
+}
+
+/// Keeps track of when a chain's best block last advanced, and how long it usually
+/// takes to.
+pub struct BlockProductionStall {
+    threshold: StallThreshold,
+    /// The highest best block we've seen, and when we first saw it.
+    highest: Option<(BlockNumber, Timestamp)>,
+    /// Recent intervals between new highest blocks, oldest first.
+    block_times_ms: VecDeque<u64>,
+}
+
+impl BlockProductionStall {
+    pub fn new(threshold: StallThreshold) -> Self {
+        BlockProductionStall {
+            threshold,
+            highest: None,
+            block_times_ms: VecDeque::with_capacity(MAX_BLOCK_TIME_SAMPLES),
+        }
+    }
+
+    /// Take note of a new best block. Only blocks higher than any we've seen before count
+    /// as the chain making progress, so that nodes announcing old blocks again after the
+    /// best block was reset don't look like a recovery.
+    pub fn best_block(&mut self, height: BlockNumber, now: Timestamp) {
+        if let Some((highest, at)) = self.highest {
+            if height <= highest {
+                return;
+            }
+            if self.block_times_ms.len() == MAX_BLOCK_TIME_SAMPLES {
+                self.block_times_ms.pop_front();
+            }
+            self.block_times_ms.push_back(now.saturating_sub(at));
+        }
+        self.highest = Some((height, now));
+    }
+
+    /// The median of the recent block times, which (unlike the mean) isn't thrown off by
+    /// the odd long gap, such as the one after a stall. `None` until we've seen
+    /// [`MIN_BLOCK_TIME_SAMPLES`] block times.
+    pub fn usual_block_time(&self) -> Option<u64> {
+        if self.block_times_ms.len() < MIN_BLOCK_TIME_SAMPLES {
+            return None;
+        }
+        let mut block_times: Vec<u64> = self.block_times_ms.iter().copied().collect();
+        block_times.sort_unstable();
+        Some(block_times[block_times.len() / 2])
+    }
+
+    /// Has the best block gone without advancing for longer than the threshold allows?
+    pub fn is_stalled(&self, now: Timestamp) -> bool {
+        let (usual_block_time, advanced_at) = match (self.usual_block_time(), self.highest) {
+            (Some(usual_block_time), Some((_, at))) => (usual_block_time, at),
+            _ => return false,
+        };
+        let limit = (usual_block_time as f64 * self.threshold.block_time_multiple) as u64;
+        now.saturating_sub(advanced_at) > limit.max(self.threshold.min_ms)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A chain which has produced `blocks` blocks every `block_time` ms, starting at 0.
+    fn producing(blocks: u64, block_time: u64) -> BlockProductionStall {
+        let mut stall = BlockProductionStall::new(StallThreshold::default());
+        for height in 0..blocks {
+            stall.best_block(height, height * block_time);
+        }
+        stall
+    }
+
+    #[test]
+    fn never_stalled_without_enough_block_times() {
+        // 5 blocks give only 4 intervals:
+        let stall = producing(5, 6000);
+        assert_eq!(stall.usual_block_time(), None);
+        assert!(!stall.is_stalled(u64::MAX));
+
+        let stall = producing(6, 6000);
+        assert_eq!(stall.usual_block_time(), Some(6000));
+        assert!(stall.is_stalled(u64::MAX));
+    }
+
+    #[test]
+    fn stalled_after_a_multiple_of_the_usual_block_time() {
+        let stall = producing(10, 12_000);
+        let last_block_at = 9 * 12_000;
+        assert!(!stall.is_stalled(last_block_at + 120_000));
+        assert!(stall.is_stalled(last_block_at + 120_001));
+    }
+
+    #[test]
+    fn stalled_no_sooner_than_the_floor() {
+        let stall = producing(10, 1000);
+        let last_block_at = 9 * 1000;
+        assert!(!stall.is_stalled(last_block_at + 60_000));
+        assert!(stall.is_stalled(last_block_at + 60_001));
+    }
+
+    #[test]
+    fn recovers_once_the_best_block_advances() {
+        let mut stall = producing(10, 6000);
+        let now = 10 * 6000 + 600_000;
+        assert!(stall.is_stalled(now));
+
+        // Announcing old blocks again isn't progress:
+        stall.best_block(5, now);
+        stall.best_block(9, now);
+        assert!(stall.is_stalled(now));
+
+        stall.best_block(10, now);
+        assert!(!stall.is_stalled(now));
+        // The long gap doesn't throw off the usual block time:
+        assert_eq!(stall.usual_block_time(), Some(6000));
+    }
+}
@@ -0,0 +1,255 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The block times we measure for nodes jitter quite a lot, because they depend on
+//! when messages happen to arrive. Feeds can be sent a smoothed version instead, or
+//! block times can be measured using the timestamps that nodes send with their blocks.
+
+use anyhow::anyhow;
+use std::collections::VecDeque;
+
+/// The largest window that median smoothing can be asked to use.
+pub const MAX_MEDIAN_WINDOW: usize = 64;
+
+/// How the block times that we hand to feeds are smoothed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BlockTimeSmoothing {
+    /// Block times are reported exactly as they were measured.
+    #[default]
+    None,
+    /// Report the median of the last N block times.
+    Median(usize),
+    /// Report an exponentially weighted moving average of block times. Each
+    /// new block time is given this weight (greater than 0, at most 1).
+    Ewma(f64),
+}
+
+impl std::str::FromStr for BlockTimeSmoothing {
+    type Err = anyhow::Error;
+
+    /// Parse one of `none`, `median:N` or `ewma:WEIGHT`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (kind, value) = match s.find(':') {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None),
+        };
+
+        match (kind, value) {
+            ("none", None) => Ok(BlockTimeSmoothing::None),
+            ("median", Some(n)) => n
+                .parse::<usize>()
+                .ok()
+                .filter(|n| (1..=MAX_MEDIAN_WINDOW).contains(n))
+                .map(BlockTimeSmoothing::Median)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "'{}' is not a valid median window; expecting a number from 1 to {}",
+                        n,
+                        MAX_MEDIAN_WINDOW
+                    )
+                }),
+            ("ewma", Some(weight)) => weight
+                .parse::<f64>()
+                .ok()
+                .filter(|w| *w > 0.0 && *w <= 1.0)
+                .map(BlockTimeSmoothing::Ewma)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "'{}' is not a valid EWMA weight; expecting a number greater than 0 and at most 1",
+                        weight
+                    )
+                }),
+            _ => Err(anyhow!(
+                "'{}' is not a valid block time smoothing; expecting 'none', 'median:N' or 'ewma:WEIGHT'",
+                s
+            )),
+        }
+    }
+}
+
+/// What a node's block times are measured between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockTimeSource {
+    /// When each of its blocks arrived here.
+    #[default]
+    Arrival,
+    /// The timestamps that the node sent with each of its blocks. When a node doesn't
+    /// send a timestamp, or its timestamps don't move forwards, we fall back to when
+    /// the blocks arrived.
+    Reported,
+}
+
+impl std::str::FromStr for BlockTimeSource {
+    type Err = anyhow::Error;
+
+    /// Parse one of `arrival` or `reported`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "arrival" => Ok(BlockTimeSource::Arrival),
+            "reported" => Ok(BlockTimeSource::Reported),
+            _ => Err(anyhow!(
+                "'{}' is not a valid block time source; expecting 'arrival' or 'reported'",
+                s
+            )),
+        }
+    }
+}
+
+/// The state needed to smooth the block times of a single node.
+#[derive(Debug, Default)]
+pub struct BlockTimeSmoother {
+    /// The most recent block times, for median smoothing.
+    recent: VecDeque<u64>,
+    /// The current average, for EWMA smoothing.
+    average: Option<f64>,
+}
+
+impl BlockTimeSmoother {
+    /// Take note of a newly measured block time, and hand back the
+    /// smoothed block time that should be reported in its place.
+    pub fn push(&mut self, block_time: u64, smoothing: BlockTimeSmoothing) -> u64 {
+        match smoothing {
+            BlockTimeSmoothing::None => block_time,
+            BlockTimeSmoothing::Median(window) => {
+                self.recent.push_back(block_time);
+                while self.recent.len() > window.max(1) {
+                    self.recent.pop_front();
+                }
+
+                let mut sorted: Vec<u64> = self.recent.iter().copied().collect();
+                sorted.sort_unstable();
+                let mid = sorted.len() / 2;
+                if sorted.len() % 2 == 1 {
+                    sorted[mid]
+                } else {
+                    // Written this way to avoid overflowing:
+                    let (a, b) = (sorted[mid - 1], sorted[mid]);
+                    a / 2 + b / 2 + (a % 2 + b % 2) / 2
+                }
+            }
+            BlockTimeSmoothing::Ewma(weight) => {
+                let block_time = block_time as f64;
+                let average = match self.average {
+                    Some(average) => average + weight * (block_time - average),
+                    None => block_time,
+                };
+                self.average = Some(average);
+                average.round() as u64
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Roughly 6 second blocks, with the odd wild outlier thrown in:
+    const NOISY: [u64; 10] = [6000, 6100, 5900, 12000, 6050, 5950, 200, 6000, 6020, 5980];
+
+    fn smooth(smoothing: BlockTimeSmoothing) -> Vec<u64> {
+        let mut smoother = BlockTimeSmoother::default();
+        NOISY.iter().map(|&t| smoother.push(t, smoothing)).collect()
+    }
+
+    #[test]
+    fn no_smoothing_reports_raw_block_times() {
+        assert_eq!(smooth(BlockTimeSmoothing::None), NOISY.to_vec());
+    }
+
+    #[test]
+    fn median_smoothing_ignores_outliers() {
+        assert_eq!(
+            smooth(BlockTimeSmoothing::Median(3)),
+            vec![6000, 6050, 6000, 6100, 6050, 6050, 5950, 5950, 6000, 6000]
+        );
+    }
+
+    #[test]
+    fn median_of_one_is_raw() {
+        assert_eq!(smooth(BlockTimeSmoothing::Median(1)), NOISY.to_vec());
+    }
+
+    #[test]
+    fn ewma_smoothing_matches_formula() {
+        let weight = 0.25;
+        let mut average = NOISY[0] as f64;
+        let mut expected = vec![NOISY[0]];
+        for &t in &NOISY[1..] {
+            average += weight * (t as f64 - average);
+            expected.push(average.round() as u64);
+        }
+
+        let smoothed = smooth(BlockTimeSmoothing::Ewma(weight));
+        assert_eq!(smoothed, expected);
+        // The outliers are damped rather than passed straight through:
+        assert!(smoothed[3] < 8000);
+        assert!(smoothed[6] > 4000);
+    }
+
+    #[test]
+    fn ewma_weight_of_one_is_raw() {
+        assert_eq!(smooth(BlockTimeSmoothing::Ewma(1.0)), NOISY.to_vec());
+    }
+
+    #[test]
+    fn parsing_source() {
+        assert_eq!(
+            "arrival".parse::<BlockTimeSource>().unwrap(),
+            BlockTimeSource::Arrival
+        );
+        assert_eq!(
+            " reported ".parse::<BlockTimeSource>().unwrap(),
+            BlockTimeSource::Reported
+        );
+        assert!("".parse::<BlockTimeSource>().is_err());
+        assert!("timestamp".parse::<BlockTimeSource>().is_err());
+    }
+
+    #[test]
+    fn parsing_smoothing() {
+        assert_eq!(
+            "none".parse::<BlockTimeSmoothing>().unwrap(),
+            BlockTimeSmoothing::None
+        );
+        assert_eq!(
+            "median:5".parse::<BlockTimeSmoothing>().unwrap(),
+            BlockTimeSmoothing::Median(5)
+        );
+        assert_eq!(
+            " ewma:0.2 ".parse::<BlockTimeSmoothing>().unwrap(),
+            BlockTimeSmoothing::Ewma(0.2)
+        );
+
+        for bad in &[
+            "",
+            "median",
+            "median:0",
+            "median:65",
+            "ewma:0",
+            "ewma:1.5",
+            "ewma:x",
+            "mean:3",
+        ] {
+            assert!(
+                bad.parse::<BlockTimeSmoothing>().is_err(),
+                "{} should not parse",
+                bad
+            );
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Nodes on a chain don't always agree on what the best block is. This decides which
+//! of the blocks that they report is "the" best block of the chain.
+//!
+//! The canonical block is chosen by comparing, in order:
+//!
+//! 1. Height: higher blocks win.
+//! 2. Reports: at the same height, the block reported by more nodes wins.
+//! 3. Time: if as many nodes report each, the block that was reported first wins.
+//! 4. Hash: if all else is equal, the lower hash wins, so that the choice doesn't
+//!    depend on the order that we look at the blocks in.
+
+use common::node_types::{Block, BlockDetails, BlockHash, Timestamp};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// A block that is the best block of at least one node on a chain.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockCandidate {
+    pub block: Block,
+    /// How many nodes report this block as their best.
+    pub reported_by: usize,
+    /// When the block was first reported by any of these nodes, by our clock.
+    pub first_reported_at: Timestamp,
+}
+
+impl BlockCandidate {
+    /// A block as reported by a single node.
+    pub fn new(details: &BlockDetails) -> Self {
+        BlockCandidate {
+            block: details.block,
+            reported_by: 1,
+            first_reported_at: details.block_timestamp,
+        }
+    }
+
+    /// Compare two candidates according to the rule described in the module docs.
+    /// `Ordering::Greater` means that `self` is the more canonical of the two.
+    pub fn cmp_canonical(&self, other: &BlockCandidate) -> Ordering {
+        self.block
+            .height
+            .cmp(&other.block.height)
+            .then(self.reported_by.cmp(&other.reported_by))
+            .then(other.first_reported_at.cmp(&self.first_reported_at))
+            .then(other.block.hash.cmp(&self.block.hash))
+    }
+
+    /// Merge in another report of the same block.
+    fn merge(&mut self, other: BlockCandidate) {
+        self.reported_by += other.reported_by;
+        self.first_reported_at = self.first_reported_at.min(other.first_reported_at);
+    }
+}
+
+/// Given the best blocks reported by some nodes, pick the canonical one. Reports of the
+/// same block are counted together. Returns `None` if no blocks were given.
+pub fn canonical_block<'a>(
+    reports: impl IntoIterator<Item = &'a BlockDetails>,
+) -> Option<BlockCandidate> {
+    let mut candidates: HashMap<BlockHash, BlockCandidate> = HashMap::new();
+    for details in reports {
+        let candidate = BlockCandidate::new(details);
+        candidates
+            .entry(details.block.hash)
+            .and_modify(|c| c.merge(candidate))
+            .or_insert(candidate);
+    }
+    candidates.into_values().max_by(|a, b| a.cmp_canonical(b))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn details(height: u64, hash: u64, block_timestamp: Timestamp) -> BlockDetails {
+        BlockDetails {
+            block: Block {
+                hash: BlockHash::from_low_u64_be(hash),
+                height,
+            },
+            block_timestamp,
+            ..BlockDetails::default()
+        }
+    }
+
+    fn canonical_hash(reports: &[BlockDetails]) -> BlockHash {
+        canonical_block(reports).unwrap().block.hash
+    }
+
+    #[test]
+    fn highest_block_wins() {
+        let reports = [
+            details(10, 1, 100),
+            details(10, 1, 100),
+            details(11, 2, 200),
+        ];
+        assert_eq!(canonical_hash(&reports), BlockHash::from_low_u64_be(2));
+    }
+
+    #[test]
+    fn most_reported_block_wins_at_the_same_height() {
+        let reports = [
+            details(10, 1, 100),
+            details(10, 2, 150),
+            details(10, 2, 160),
+        ];
+        let canonical = canonical_block(&reports).unwrap();
+
+        assert_eq!(canonical.block.hash, BlockHash::from_low_u64_be(2));
+        assert_eq!(canonical.reported_by, 2);
+        assert_eq!(canonical.first_reported_at, 150);
+    }
+
+    #[test]
+    fn earliest_reported_block_wins_if_equally_reported() {
+        let reports = [details(10, 1, 200), details(10, 2, 100)];
+        assert_eq!(canonical_hash(&reports), BlockHash::from_low_u64_be(2));
+    }
+
+    #[test]
+    fn choice_does_not_depend_on_report_order() {
+        let mut reports = vec![
+            details(10, 3, 100),
+            details(10, 1, 100),
+            details(10, 2, 100),
+        ];
+        assert_eq!(canonical_hash(&reports), BlockHash::from_low_u64_be(1));
+        reports.reverse();
+        assert_eq!(canonical_hash(&reports), BlockHash::from_low_u64_be(1));
+    }
+
+    #[test]
+    fn no_reports_no_canonical_block() {
+        assert_eq!(canonical_block(&[]), None);
+    }
+}
@@ -15,16 +15,30 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use common::node_message::Payload;
-use common::node_types::{Block, Timestamp};
+use common::node_types::{Block, ChainType, Timestamp};
 use common::node_types::{BlockHash, BlockNumber};
 use common::{id_type, time, DenseMap, MostSeen, NumStats};
-use once_cell::sync::Lazy;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 use crate::feed_message::{self, FeedMessageSerializer};
 use crate::find_location;
 
-use super::node::Node;
+use super::alerts::{AlertChange, AlertThresholds};
+use super::block_first_seen::BlockFirstSeen;
+use super::block_production_stall::{BlockProductionStall, StallThreshold};
+use super::block_time_smoothing::{BlockTimeSmoothing, BlockTimeSource};
+use super::canonical_block::canonical_block;
+use super::continent::country_to_continent;
+use super::distribution::Distribution;
+use super::finalized_hashes::FinalizedHashes;
+use super::memory_budget::{BufferKind, MemoryBudget, MemoryUsage};
+use super::node::{Node, STALE_TIMEOUT};
+use super::node_count_history::{NodeCountHistory, NodeCountSample, RetentionPolicy};
+use super::production_rate::ProductionRate;
+use super::public_nodes::{PublicNodes, ShortageChange};
+use super::recent_blocks::RecentBlocks;
+use super::validator_index::ValidatorIndex;
 
 id_type! {
     /// A Node ID that is unique to the chain it's in.
@@ -33,12 +47,13 @@ id_type! {
 
 pub type Label = Box<str>;
 
-const STALE_TIMEOUT: u64 = 2 * 60 * 1000; // 2 minutes
-
 pub struct Chain {
     /// Labels that nodes use for this chain. We keep track of
     /// the most commonly used label as nodes are added/removed.
     labels: MostSeen<Label>,
+    /// The type of chain that nodes report this to be. We keep track of the
+    /// most commonly reported type in the same way as we do labels.
+    chain_types: MostSeen<Option<ChainType>>,
     /// Set of nodes that are in this chain
     nodes: DenseMap<ChainNodeId, Node>,
     /// Best block
@@ -49,10 +64,174 @@ pub struct Chain {
     block_times: NumStats<u64>,
     /// Calculated average block time
     average_block_time: Option<u64>,
+    /// Recent intervals between best blocks, from which we estimate how quickly blocks are produced
+    production_rate: ProductionRate,
+    /// Whether the best block has stopped advancing
+    production_stall: BlockProductionStall,
     /// When the best block first arrived
     timestamp: Option<Timestamp>,
     /// Genesis hash of this chain
     genesis_hash: BlockHash,
+    /// Which implementations and versions the nodes on this chain are running
+    distribution: Distribution,
+    /// Approximately how much memory the buffers on this chain are using
+    memory: MemoryBudget,
+    /// When to raise alerts against nodes on this chain
+    alert_thresholds: AlertThresholds,
+    /// How node block times are smoothed before being handed to feeds
+    block_time_smoothing: BlockTimeSmoothing,
+    /// What node block times are measured between
+    block_time_source: BlockTimeSource,
+    /// How announcements of blocks well below the best block are throttled
+    import_throttle: ImportThrottle,
+    /// Which block heights are too high to be believed
+    block_height_limit: BlockHeightLimit,
+    /// Recent samples of how many nodes this chain has
+    node_count_history: NodeCountHistory,
+    /// When we last evicted node count history according to the retention policy
+    retention_enforced_at: Option<Timestamp>,
+    /// How many node count samples have been evicted by the retention policy
+    retention_evictions: u64,
+    /// When we first saw each recent block, from any node
+    block_first_seen: BlockFirstSeen,
+    /// Which block validators finalized at each recent height
+    finalized_hashes: FinalizedHashes,
+    /// Which nodes claim which validator address
+    validators: ValidatorIndex,
+    /// How many nodes are publicly reachable, and how many aren't
+    public_nodes: PublicNodes,
+    /// How many nodes have the chain's best block as their best block. This is
+    /// kept up to date as blocks are announced rather than recounted each time.
+    nodes_at_best: usize,
+    /// Has the number of nodes at the best block changed since feeds were last told?
+    nodes_at_best_changed: bool,
+    /// How many nodes have ever joined this chain, so that each new node
+    /// can be told where it comes in the order of nodes joining.
+    nodes_joined: u64,
+    /// Is this one of the chains configured as being first party?
+    first_party: bool,
+    /// The block at which nodes have told us that a runtime upgrade is scheduled, until
+    /// the best block reaches it.
+    pending_upgrade: Option<BlockNumber>,
+}
+
+/// Nodes whose best block is more than this many blocks behind the chain's best
+/// block are considered to be syncing.
+pub const SYNCING_DISTANCE: BlockNumber = 10;
+
+/// How many of a chain's nodes have caught up to its best block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodesAtBest {
+    /// The height of the chain's best block.
+    pub height: BlockNumber,
+    /// How many nodes have the best block as their best block.
+    pub caught_up: usize,
+    /// How many nodes aren't syncing or stale, and so could be expected to be caught up.
+    pub total: usize,
+}
+
+/// Options which apply to every chain.
+#[derive(Debug, Clone)]
+pub struct ChainOpts {
+    /// If given, historical data is evicted from a chain to keep it within this many bytes.
+    pub memory_budget: Option<usize>,
+    /// Thresholds used to decide when to raise alerts against nodes.
+    pub alert_thresholds: AlertThresholds,
+    /// How the block times of nodes are smoothed before they are sent to feeds.
+    pub block_time_smoothing: BlockTimeSmoothing,
+    /// What the block times of nodes are measured between.
+    pub block_time_source: BlockTimeSource,
+    /// How announcements of blocks well below a chain's best block are throttled.
+    pub import_throttle: ImportThrottle,
+    /// How long a chain's best block can go without advancing before it's stalled.
+    pub stall_threshold: StallThreshold,
+    /// Which block heights are too high to be believed.
+    pub block_height_limit: BlockHeightLimit,
+    /// Genesis hashes of the chains we consider "first party". These chains allow
+    /// any number of nodes to connect, and always have their metrics reported.
+    pub first_party_chains: Arc<HashSet<BlockHash>>,
+}
+
+impl Default for ChainOpts {
+    fn default() -> Self {
+        ChainOpts {
+            memory_budget: None,
+            alert_thresholds: AlertThresholds::default(),
+            block_time_smoothing: BlockTimeSmoothing::default(),
+            block_time_source: BlockTimeSource::default(),
+            import_throttle: ImportThrottle::default(),
+            stall_threshold: StallThreshold::default(),
+            block_height_limit: BlockHeightLimit::default(),
+            first_party_chains: Arc::new(default_first_party_chains()),
+        }
+    }
+}
+
+/// Nodes can claim whatever block height they like, so a node could claim to be far
+/// ahead of everybody else in order to look like the chain leader. Blocks higher than
+/// these limits are ignored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockHeightLimit {
+    /// No block can be higher than this.
+    pub max_height: Option<BlockNumber>,
+    /// No block can be more than this many blocks above the chain's best block. This
+    /// doesn't apply until the chain has a best block.
+    pub max_ahead_of_best: Option<BlockNumber>,
+}
+
+impl BlockHeightLimit {
+    /// Is a block at this height plausible, given the chain's best block?
+    pub fn allows(&self, height: BlockNumber, best: BlockNumber) -> bool {
+        let below_max = match self.max_height {
+            Some(max_height) => height <= max_height,
+            None => true,
+        };
+        let near_best = match self.max_ahead_of_best {
+            Some(max_ahead) if best > 0 => height <= best.saturating_add(max_ahead),
+            _ => true,
+        };
+        below_max && near_best
+    }
+}
+
+/// When lots of nodes are syncing, they announce thousands of old blocks between them.
+/// Feeds don't need to hear about each of those, so announcements of blocks more than
+/// `blocks_behind` below the chain's best block only update the node's best block, and
+/// feeds are told about them at most once every `interval_ms` for each node. Blocks
+/// closer to the best block than that are never throttled by this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImportThrottle {
+    pub blocks_behind: BlockNumber,
+    pub interval_ms: u64,
+}
+
+impl Default for ImportThrottle {
+    fn default() -> Self {
+        ImportThrottle {
+            blocks_behind: 100,
+            interval_ms: 1000,
+        }
+    }
+}
+
+/// Genesis hashes of the chains that are first party unless we're told otherwise:
+/// Polkadot, Kusama, Westend and Rococo.
+pub const DEFAULT_FIRST_PARTY_CHAINS: [&str; 4] = [
+    "91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3",
+    "b0a8d493285c2df73290dfb7e61f870f17b41801197a149ca93654499ea3dafe",
+    "e143f23803ac50e8f6f8e62695d1ce9e4e1d68aa36c1cd2cfd15340213f3423e",
+    "6408de7737c59c238890533af25896a2c20608d8b380bb01029acb392781063e",
+];
+
+/// The genesis hashes in [`DEFAULT_FIRST_PARTY_CHAINS`].
+pub fn default_first_party_chains() -> HashSet<BlockHash> {
+    DEFAULT_FIRST_PARTY_CHAINS
+        .iter()
+        .map(|hash| {
+            hash.parse()
+                .expect("default first party genesis hashes are valid")
+        })
+        .collect()
 }
 
 pub enum AddNodeResult {
@@ -60,65 +239,118 @@ pub enum AddNodeResult {
     Added {
         id: ChainNodeId,
         chain_renamed: bool,
+        /// The block that a runtime upgrade is scheduled for, if the node told us about
+        /// one that we didn't already know about.
+        upgrade_scheduled: Option<BlockNumber>,
+        /// Changes to the alerts raised against other nodes on the chain as a result of
+        /// adding this one. Alerts raised against the node itself are left on it.
+        alert_changes: Vec<(ChainNodeId, AlertChange)>,
     },
 }
 
 pub struct RemoveNodeResult {
     pub chain_renamed: bool,
+    /// Changes to the alerts raised against the remaining nodes on the chain as a result
+    /// of removing this one.
+    pub alert_changes: Vec<(ChainNodeId, AlertChange)>,
 }
 
-/// Labels of chains we consider "first party". These chains allow any
-/// number of nodes to connect.
-static FIRST_PARTY_NETWORKS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
-    let mut set = HashSet::new();
-    set.insert("Polkadot");
-    set.insert("Kusama");
-    set.insert("Westend");
-    set.insert("Rococo");
-    set
-});
-
 /// Max number of nodes allowed to connect to the telemetry server.
 const THIRD_PARTY_NETWORKS_MAX_NODES: usize = 500;
 
+/// How many of the pallets taking up the most state trie space feeds are told about.
+const TOP_PALLETS: usize = 5;
+
 impl Chain {
     /// Create a new chain with an initial label.
-    pub fn new(genesis_hash: BlockHash) -> Self {
+    pub fn new(genesis_hash: BlockHash, opts: ChainOpts) -> Self {
         Chain {
             labels: MostSeen::default(),
+            chain_types: MostSeen::default(),
             nodes: DenseMap::new(),
             best: Block::zero(),
             finalized: Block::zero(),
             block_times: NumStats::new(50),
             average_block_time: None,
+            production_rate: ProductionRate::new(),
+            production_stall: BlockProductionStall::new(opts.stall_threshold),
             timestamp: None,
             genesis_hash,
+            distribution: Distribution::new(),
+            memory: MemoryBudget::new(opts.memory_budget),
+            alert_thresholds: opts.alert_thresholds,
+            block_time_smoothing: opts.block_time_smoothing,
+            block_time_source: opts.block_time_source,
+            import_throttle: opts.import_throttle,
+            block_height_limit: opts.block_height_limit,
+            node_count_history: NodeCountHistory::new(),
+            retention_enforced_at: None,
+            retention_evictions: 0,
+            block_first_seen: BlockFirstSeen::new(),
+            finalized_hashes: FinalizedHashes::new(),
+            validators: ValidatorIndex::new(),
+            public_nodes: PublicNodes::new(),
+            nodes_at_best: 0,
+            nodes_at_best_changed: false,
+            nodes_joined: 0,
+            first_party: opts.first_party_chains.contains(&genesis_hash),
+            pending_upgrade: None,
         }
     }
 
     /// Is the chain the node belongs to overquota?
     pub fn is_overquota(&self) -> bool {
-        // Dynamically determine the max nodes based on the most common
-        // label so far, in case it changes to something with a different limit.
-        self.nodes.len() >= max_nodes(self.labels.best())
+        self.nodes.len() >= max_nodes(self.first_party)
     }
 
     /// Assign a node to this chain.
-    pub fn add_node(&mut self, node: Node) -> AddNodeResult {
+    pub fn add_node(&mut self, mut node: Node) -> AddNodeResult {
         if self.is_overquota() {
             return AddNodeResult::Overquota;
         }
 
         let node_chain_label = &node.details().chain;
         let label_result = self.labels.insert(node_chain_label);
+        self.chain_types.insert(&node.details().chain_type);
+        self.distribution.add(node.details());
+        self.public_nodes.add(node.details().publicly_reachable);
+        self.memory
+            .add(BufferKind::NodeState, node_memory_usage(&node));
+        // Feeds haven't heard of the node yet, so they'll be told about any alert
+        // raised here along with the rest of the node:
+        node.update_pruning_alert(&self.alert_thresholds(), time::now());
+        node.set_join_order(self.nodes_joined);
+        self.nodes_joined += 1;
+        let upgrade_scheduled = match node.details().pending_upgrade_block {
+            Some(at_block) if self.schedule_upgrade(at_block) => Some(at_block),
+            _ => None,
+        };
+        let validator = node.details().validator.clone();
         let node_id = self.nodes.add(node);
+        let mut alert_changes = self.set_validator_address(node_id, validator.as_deref());
+        self.enforce_memory_budget();
+        alert_changes.retain(|(nid, _)| *nid != node_id);
 
         AddNodeResult::Added {
             id: node_id,
             chain_renamed: label_result.has_changed(),
+            upgrade_scheduled,
+            alert_changes,
         }
     }
 
+    /// A node has told us that a runtime upgrade is scheduled for the given block. Returns
+    /// `true` if this is news, ie the block hasn't been reached yet and the upgrade isn't
+    /// already scheduled for it. The most recently reported block wins, in case the
+    /// upgrade has been rescheduled.
+    fn schedule_upgrade(&mut self, at_block: BlockNumber) -> bool {
+        if at_block <= self.best.height || self.pending_upgrade == Some(at_block) {
+            return false;
+        }
+        self.pending_upgrade = Some(at_block);
+        true
+    }
+
     /// Remove a node from this chain.
     pub fn remove_node(&mut self, node_id: ChainNodeId) -> RemoveNodeResult {
         let node = match self.nodes.remove(node_id) {
@@ -126,15 +358,31 @@ impl Chain {
             None => {
                 return RemoveNodeResult {
                     chain_renamed: false,
+                    alert_changes: Vec::new(),
                 }
             }
         };
 
         let node_chain_label = &node.details().chain;
         let label_result = self.labels.remove(node_chain_label);
+        self.chain_types.remove(&node.details().chain_type);
+        self.distribution.remove(node.details());
+        self.public_nodes.remove(node.details().publicly_reachable);
+        self.memory
+            .sub(BufferKind::NodeState, node_memory_usage(&node));
+        if self.best.height > 0 && node.best().hash == self.best.hash {
+            self.nodes_at_best = self.nodes_at_best.saturating_sub(1);
+            self.nodes_at_best_changed = true;
+        }
+
+        let validators_usage = self.validators.memory_usage();
+        let affected = self.validators.remove(node_id);
+        self.account_validators(validators_usage);
+        let alert_changes = self.update_duplicate_validator_alerts(affected);
 
         RemoveNodeResult {
             chain_renamed: label_result.has_changed(),
+            alert_changes,
         }
     }
 
@@ -144,29 +392,87 @@ impl Chain {
         &mut self,
         nid: ChainNodeId,
         payload: Payload,
+        reported_at: Option<Timestamp>,
         feed: &mut FeedMessageSerializer,
     ) -> bool {
         if let Some(block) = payload.best_block() {
-            self.handle_block(block, nid, feed);
+            self.handle_block(block, nid, reported_at, feed);
         }
 
+        let alert_thresholds = self.alert_thresholds();
         if let Some(node) = self.nodes.get_mut(nid) {
+            node.observe_verbosity(payload.verbosity(), time::now());
             match payload {
                 Payload::SystemInterval(ref interval) => {
+                    node.observe_interval(time::now());
                     if node.update_hardware(interval) {
                         feed.push(feed_message::Hardware(nid.into(), node.hardware()));
                     }
+                    let change = node.update_swap_alert(&alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
+                    let change = node.update_cpu_steal_alert(&alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
+                    let change = node.update_load_average_alert(&alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
+                    let change = node.update_fd_alert(&alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
+                    let change = node.update_clock_alert(&alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
+                    let change = node.update_gpu_alert(&alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
 
-                    if let Some(stats) = node.update_stats(interval) {
-                        feed.push(feed_message::NodeStatsUpdate(nid.into(), stats));
+                    if node.update_stats(interval).is_some() {
+                        feed.push(feed_message::NodeStatsUpdate(
+                            nid.into(),
+                            node.stats(),
+                            node.peer_history().slice(),
+                        ));
                     }
+                    node.sample_database_size(time::now());
+                    let change =
+                        node.update_light_request_alert(interval, &alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
+                    let change =
+                        node.update_peer_reputation_alert(interval, &alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
+                    let change = node.update_wasm_heap_alert(&alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
 
                     if let Some(io) = node.update_io(interval) {
                         feed.push(feed_message::NodeIOUpdate(nid.into(), io));
                     }
+
+                    let change =
+                        node.update_offchain_worker_alert(interval, &alert_thresholds, time::now());
+                    push_alert_change(nid, change, feed);
+                    let change = node.update_kademlia_records_alert(
+                        interval,
+                        &alert_thresholds,
+                        time::now(),
+                    );
+                    push_alert_change(nid, change, feed);
+                }
+                Payload::PreparedBlockForProposing(prepared) => {
+                    if let Some(import_latency_ms) = prepared.import_latency_ms {
+                        node.update_import_latency(import_latency_ms);
+                        feed.push(feed_message::Hardware(nid.into(), node.hardware()));
+
+                        let chain_median_ms = self.median_import_latency();
+                        if let Some(node) = self.nodes.get_mut(nid) {
+                            let change = node.update_slow_import_alert(
+                                chain_median_ms,
+                                &alert_thresholds,
+                                time::now(),
+                            );
+                            push_alert_change(nid, change, feed);
+                        }
+                    }
+                    return false;
                 }
                 Payload::AfgAuthoritySet(authority) => {
                     node.set_validator_address(authority.authority_id.clone());
+                    let changes = self.set_validator_address(nid, Some(&authority.authority_id));
+                    push_alert_changes(&changes, feed);
                     return false;
                 }
                 Payload::AfgFinalized(finalized) => {
@@ -215,28 +521,128 @@ impl Chain {
             }
 
             if let Some(block) = payload.finalized_block() {
-                if let Some(finalized) = node.update_finalized(block) {
+                let now = time::now();
+                if let Some(finalized) = node.update_finalized(block, now).copied() {
                     feed.push(feed_message::FinalizedBlock(
                         nid.into(),
                         finalized.height,
                         finalized.hash,
+                        node.finalized_at(),
                     ));
 
                     if finalized.height > self.finalized.height {
-                        self.finalized = *finalized;
+                        self.finalized = finalized;
                         feed.push(feed_message::BestFinalized(
                             finalized.height,
                             finalized.hash,
                         ));
                     }
+
+                    // Validators should never disagree about what's been finalized:
+                    if node.details().validator.is_some() {
+                        let conflict = self.finalized_hashes.observe(finalized);
+                        if let Some(other_hash) = conflict {
+                            log::error!(
+                                "Finality conflict on chain {}: validator {} finalized {:?} at height {}, but {:?} was already finalized there",
+                                self.genesis_hash,
+                                node.details().name,
+                                finalized.hash,
+                                finalized.height,
+                                other_hash,
+                            );
+                        }
+                        let change = node.update_finality_conflict_alert(
+                            conflict.map(|_| finalized.height),
+                            &alert_thresholds,
+                            now,
+                        );
+                        push_alert_change(nid, change, feed);
+                    }
                 }
             }
+
+            let change = node.update_finality_lag_alert(&alert_thresholds, time::now());
+            push_alert_change(nid, change, feed);
+        }
+        self.account_recent_blocks();
+
+        // Kademlia query rates are compared against the rest of the chain, which
+        // can't be looked at while the node is being updated:
+        if let Payload::SystemInterval(interval) = &payload {
+            if interval.kademlia_queries_per_sec.is_some() {
+                self.update_kademlia_query_alert(nid, &alert_thresholds, feed);
+            }
         }
 
         false
     }
 
-    fn handle_block(&mut self, block: &Block, nid: ChainNodeId, feed: &mut FeedMessageSerializer) {
+    /// Take note of the validator address that a node claims, handing back any resulting
+    /// changes to the alerts raised against the nodes on this chain.
+    fn set_validator_address(
+        &mut self,
+        nid: ChainNodeId,
+        address: Option<&str>,
+    ) -> Vec<(ChainNodeId, AlertChange)> {
+        let validators_usage = self.validators.memory_usage();
+        let affected = self.validators.set(nid, address);
+        self.account_validators(validators_usage);
+        self.update_duplicate_validator_alerts(affected)
+    }
+
+    /// Check whether each of the nodes given claims the same validator address as another.
+    fn update_duplicate_validator_alerts(
+        &mut self,
+        nids: Vec<ChainNodeId>,
+    ) -> Vec<(ChainNodeId, AlertChange)> {
+        let alert_thresholds = self.alert_thresholds();
+        let now = time::now();
+        let mut changes = Vec::new();
+        for nid in nids {
+            let claimants = self.validators.claimants(nid);
+            let node = match self.nodes.get_mut(nid) {
+                Some(node) => node,
+                None => continue,
+            };
+            let change = node.update_duplicate_validator_alert(claimants, &alert_thresholds, now);
+            if let Some(change) = change {
+                if let AlertChange::Raised(_) = change {
+                    log::warn!(
+                        "Duplicate validator on chain {}: node {} claims validator address {:?}, as do {} other nodes",
+                        self.genesis_hash,
+                        node.details().name,
+                        node.details().validator.as_deref().unwrap_or(""),
+                        claimants.saturating_sub(1),
+                    );
+                }
+                changes.push((nid, change));
+            }
+        }
+        changes
+    }
+
+    /// Check whether a node is handling many more Kademlia DHT queries than the rest of the chain.
+    fn update_kademlia_query_alert(
+        &mut self,
+        nid: ChainNodeId,
+        alert_thresholds: &AlertThresholds,
+        feed: &mut FeedMessageSerializer,
+    ) {
+        let chain_median = self.median_kademlia_query_rate();
+        if let Some(node) = self.nodes.get_mut(nid) {
+            let change =
+                node.update_kademlia_query_alert(chain_median, alert_thresholds, time::now());
+            push_alert_change(nid, change, feed);
+        }
+    }
+
+    fn handle_block(
+        &mut self,
+        block: &Block,
+        nid: ChainNodeId,
+        reported_at: Option<Timestamp>,
+        feed: &mut FeedMessageSerializer,
+    ) {
         let mut propagation_time = None;
         let now = time::now();
         let nodes_len = self.nodes.len();
@@ -248,41 +654,143 @@ impl Chain {
             None => return,
         };
 
-        if node.update_block(*block) {
+        if !self
+            .block_height_limit
+            .allows(block.height, self.best.height)
+        {
+            log::debug!(
+                "[{}] node {} announced implausibly high block {} (best is {}); ignoring it",
+                self.labels.best(),
+                node.details().name,
+                block.to_log_string(),
+                self.best.to_log_string(),
+            );
+            return;
+        }
+
+        // A block at height 0 is the genesis block, so should be the one the node told us
+        // about when it connected. If it isn't, the node is confused about which chain
+        // it's on, and nothing it says about blocks can be trusted:
+        let genesis = Block {
+            hash: self.genesis_hash,
+            height: 0,
+        };
+        if !Block::verify_genesis_consistency(&genesis, block) {
+            log::warn!(
+                "[{}] node {} announced {} as its genesis block, but the chain's genesis block is {}; ignoring it",
+                self.labels.best(),
+                node.details().name,
+                block.to_log_string(),
+                genesis.to_log_string(),
+            );
+            return;
+        }
+
+        // There's no way for a node to tell us about a reorg, so if it announces a
+        // different block at a height it already announced, something is wrong:
+        if let Some(previous_hash) = node.check_block_hash(block) {
+            log::warn!(
+                "[{}] node {} announced {}, having already announced {}; ignoring it",
+                self.labels.best(),
+                node.details().name,
+                block.to_log_string(),
+                Block {
+                    hash: previous_hash,
+                    height: block.height,
+                }
+                .to_log_string(),
+            );
+            // The node's own best block is no longer trusted either:
+            if node.best().height == self.best.height {
+                self.reconsider_best_block(now, feed);
+            }
+            return;
+        }
+
+        let first_seen = self.block_first_seen.observe(block.hash, now);
+        // A node announcing an old block that nobody else has mentioned recently is
+        // probably syncing; that tells us nothing about how quickly it hears of blocks.
+        let is_syncing = first_seen == now && block.height < self.best.height;
+
+        let is_new_best = node.update_block(*block);
+        if is_new_best {
+            if !is_syncing {
+                node.update_announcement_latency(now - first_seen);
+            }
+
             if block.height > self.best.height {
                 self.best = *block;
+                self.nodes_at_best = 1;
+                self.nodes_at_best_changed = true;
                 log::debug!(
-                    "[{}] [nodes={}] new best block={}/{:?}",
+                    "[{}] [nodes={}] new best block {}",
                     self.labels.best(),
                     nodes_len,
-                    self.best.height,
-                    self.best.hash,
+                    self.best.to_log_string(),
                 );
                 if let Some(timestamp) = self.timestamp {
                     self.block_times.push(now - timestamp);
                     self.average_block_time = Some(self.block_times.average());
+                    self.production_rate.push_interval(now - timestamp);
                 }
+                self.production_stall.best_block(block.height, now);
                 self.timestamp = Some(now);
                 feed.push(feed_message::BestBlock(
                     self.best.height,
                     now,
                     self.average_block_time,
                 ));
+                if let Some(rate) = self.production_rate.blocks_per_second() {
+                    feed.push(feed_message::BlockProductionRate(rate));
+                }
+                feed.push(feed_message::BestBlockAge(self.best.with_age(now, now)));
+                if let Some(at_block) = self.pending_upgrade {
+                    if self.best.height >= at_block {
+                        feed.push(feed_message::UpgradeExecuted(self.labels.best(), at_block));
+                        self.pending_upgrade = None;
+                    }
+                }
                 propagation_time = Some(0);
             } else if block.height == self.best.height {
                 if let Some(timestamp) = self.timestamp {
                     propagation_time = Some(now - timestamp);
                 }
+                if block.hash == self.best.hash {
+                    self.nodes_at_best += 1;
+                    self.nodes_at_best_changed = true;
+                }
             }
 
-            if let Some(details) = node.update_details(now, propagation_time) {
+            let far_behind = block.height + self.import_throttle.blocks_behind < self.best.height;
+            let throttle_interval = far_behind.then_some(self.import_throttle.interval_ms);
+            let block_timestamp = match self.block_time_source {
+                BlockTimeSource::Arrival => None,
+                BlockTimeSource::Reported => reported_at,
+            };
+            if let Some(details) = node.update_details(
+                now,
+                block_timestamp,
+                propagation_time,
+                self.block_time_smoothing,
+                throttle_interval,
+            ) {
                 feed.push(feed_message::ImportedBlock(nid.into(), details));
             }
+            node.record_recent_block(reported_at);
         }
+
+        if is_new_best && block.height == self.best.height && block.hash != self.best.hash {
+            self.reconsider_best_block(now, feed);
+        }
+
+        self.account_block_times();
+        self.account_recent_blocks();
+        self.enforce_memory_budget();
     }
 
     /// Check if the chain is stale (has not received a new best block in a while).
     /// If so, find a new best block, ignoring any stale nodes and marking them as such.
+    /// What makes a node stale depends on its verbosity; see [`Node::update_stale`].
     fn update_stale_nodes(&mut self, now: u64, feed: &mut FeedMessageSerializer) {
         let threshold = now - STALE_TIMEOUT;
         let timestamp = match self.timestamp {
@@ -295,17 +803,10 @@ impl Chain {
             return;
         }
 
-        let mut best = Block::zero();
         let mut finalized = Block::zero();
-        let mut timestamp = None;
 
         for (nid, node) in self.nodes.iter_mut() {
-            if !node.update_stale(threshold) {
-                if node.best().height > best.height {
-                    best = *node.best();
-                    timestamp = Some(node.best_timestamp());
-                }
-
+            if !node.update_stale(now) {
                 if node.finalized().height > finalized.height {
                     finalized = *node.finalized();
                 }
@@ -314,10 +815,24 @@ impl Chain {
             }
         }
 
+        let canonical = canonical_block(
+            self.nodes
+                .iter()
+                .filter(|(_, node)| !node.stale() && node.hash_anomaly().is_none())
+                .map(|(_, node)| node.block_details()),
+        );
+        let best = canonical.map_or(Block::zero(), |c| c.block);
+        let timestamp = canonical.map(|c| c.first_reported_at);
+
         if self.best.height != 0 || self.finalized.height != 0 {
             self.best = best;
             self.finalized = finalized;
+            // The best block has gone backwards, so there's nothing for it but to recount:
+            self.nodes_at_best = canonical.map_or(0, |c| c.reported_by);
+            self.nodes_at_best_changed = true;
             self.block_times.reset();
+            self.account_block_times();
+            self.production_rate.reset();
             self.timestamp = timestamp;
 
             feed.push(feed_message::BestBlock(
@@ -325,6 +840,9 @@ impl Chain {
                 timestamp.unwrap_or(now),
                 None,
             ));
+            feed.push(feed_message::BestBlockAge(
+                self.best.with_age(timestamp.unwrap_or(now), now),
+            ));
             feed.push(feed_message::BestFinalized(
                 finalized.height,
                 finalized.hash,
@@ -332,6 +850,88 @@ impl Chain {
         }
     }
 
+    /// A node has reported a different block at the height of our best block. If that
+    /// block is now more canonical than our best block (see [`canonical_block`]), for
+    /// instance because more nodes have reported it, it becomes our best block.
+    fn reconsider_best_block(&mut self, now: Timestamp, feed: &mut FeedMessageSerializer) {
+        let height = self.best.height;
+        let canonical = canonical_block(
+            self.nodes
+                .iter()
+                .filter(|(_, node)| {
+                    !node.stale() && node.hash_anomaly().is_none() && node.best().height == height
+                })
+                .map(|(_, node)| node.block_details()),
+        );
+        let canonical = match canonical {
+            Some(canonical) if canonical.block.hash != self.best.hash => canonical,
+            _ => return,
+        };
+
+        self.best = canonical.block;
+        self.nodes_at_best = canonical.reported_by;
+        self.nodes_at_best_changed = true;
+        feed.push(feed_message::BestBlockAge(
+            self.best.with_age(self.timestamp.unwrap_or(now), now),
+        ));
+    }
+
+    fn account_block_times(&mut self) {
+        let bytes = self.block_times.len() * std::mem::size_of::<u64>();
+        self.memory.set(BufferKind::Histogram, bytes);
+    }
+
+    fn account_recent_blocks(&mut self) {
+        let bytes = self.block_first_seen.memory_usage() + self.finalized_hashes.memory_usage();
+        self.memory.set(BufferKind::RecentBlocks, bytes);
+    }
+
+    fn account_node_count_history(&mut self) {
+        let bytes = self.node_count_history.memory_usage();
+        self.memory.set(BufferKind::Replay, bytes);
+    }
+
+    /// The validator index is live node state too; swap the bytes it used
+    /// before some change for what it uses now.
+    fn account_validators(&mut self, bytes_before: usize) {
+        self.memory.sub(BufferKind::NodeState, bytes_before);
+        self.memory
+            .add(BufferKind::NodeState, self.validators.memory_usage());
+    }
+
+    /// If we're over our memory budget, evict historical data in priority
+    /// order until we're back under it. Live node state is never evicted, so if
+    /// that alone puts us over budget, nothing is.
+    fn enforce_memory_budget(&mut self) {
+        for kind in self.memory.kinds_to_evict() {
+            if self.memory.excess() == 0 {
+                break;
+            }
+            log::debug!(
+                "[{}] over memory budget by {} bytes; evicting {} data",
+                self.labels.best(),
+                self.memory.excess(),
+                kind.as_str()
+            );
+            match kind {
+                BufferKind::Replay => {
+                    self.node_count_history.clear();
+                    self.account_node_count_history();
+                }
+                BufferKind::Histogram => {
+                    self.block_times.reset();
+                    self.account_block_times();
+                }
+                BufferKind::RecentBlocks => {
+                    self.block_first_seen.clear();
+                    self.finalized_hashes.clear();
+                    self.account_recent_blocks();
+                }
+                BufferKind::NodeState => {}
+            }
+        }
+    }
+
     pub fn update_node_location(
         &mut self,
         node_id: ChainNodeId,
@@ -351,9 +951,19 @@ impl Chain {
     pub fn nodes_slice(&self) -> &[Option<Node>] {
         self.nodes.as_slice()
     }
+    pub fn is_first_party(&self) -> bool {
+        self.first_party
+    }
     pub fn label(&self) -> &str {
         &self.labels.best()
     }
+    pub fn chain_type(&self) -> Option<ChainType> {
+        *self.chain_types.best()
+    }
+    /// The alert thresholds that apply to this type of chain.
+    fn alert_thresholds(&self) -> AlertThresholds {
+        self.alert_thresholds.for_chain_type(self.chain_type())
+    }
     pub fn node_count(&self) -> usize {
         self.nodes.len()
     }
@@ -366,19 +976,249 @@ impl Chain {
     pub fn average_block_time(&self) -> Option<u64> {
         self.average_block_time
     }
+    /// How many blocks we estimate the chain produces each second, if we can say yet.
+    pub fn block_production_rate(&self) -> Option<f64> {
+        self.production_rate.blocks_per_second()
+    }
+    /// The block at which a runtime upgrade is scheduled, if any.
+    pub fn pending_upgrade(&self) -> Option<BlockNumber> {
+        self.pending_upgrade
+    }
+    /// Has the best block gone for much longer than usual without advancing?
+    pub fn block_production_stalled(&self, now: Timestamp) -> bool {
+        self.production_stall.is_stalled(now)
+    }
     pub fn finalized_block(&self) -> &Block {
         &self.finalized
     }
     pub fn genesis_hash(&self) -> &BlockHash {
         &self.genesis_hash
     }
+    /// Take a sample of the current node and validator count.
+    pub fn sample_node_count(&mut self, now: Timestamp) -> NodeCountSample {
+        let validator_count = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.details().validator.is_some())
+            .count();
+        let sample = self
+            .node_count_history
+            .sample(now, self.nodes.len(), validator_count);
+        self.account_node_count_history();
+        self.enforce_memory_budget();
+        sample
+    }
+    pub fn node_count_history(&self) -> &NodeCountHistory {
+        &self.node_count_history
+    }
+    /// The chain's retention policy has changed, so the next time that it's enforced
+    /// should be straight away rather than on the old policy's schedule.
+    pub fn retention_policy_changed(&mut self) {
+        self.retention_enforced_at = None;
+    }
+    /// Evict node count history according to the given retention policy, if it's been
+    /// long enough since we last did. Returns how many samples were evicted.
+    pub fn enforce_retention_policy(&mut self, policy: &RetentionPolicy, now: Timestamp) -> usize {
+        let interval = policy.eviction_interval_ms();
+        if let Some(enforced_at) = self.retention_enforced_at {
+            if now < enforced_at.saturating_add(interval) {
+                return 0;
+            }
+        }
+        self.retention_enforced_at = Some(now);
+        let evicted = self.node_count_history.evict(policy, now);
+        self.account_node_count_history();
+        self.retention_evictions += evicted as u64;
+        evicted
+    }
+    /// How many node count samples have been evicted by the retention policy so far.
+    pub fn retention_evictions(&self) -> u64 {
+        self.retention_evictions
+    }
+    /// How many nodes have caught up to the best block, out of those that we'd expect to.
+    pub fn nodes_at_best(&self) -> NodesAtBest {
+        let min_height = self.best.height.saturating_sub(SYNCING_DISTANCE);
+        let total = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| !node.stale() && node.best().height >= min_height)
+            .count();
+        NodesAtBest {
+            height: self.best.height,
+            caught_up: self.nodes_at_best,
+            // Don't let a node that's caught up but stale leave us reporting over 100%:
+            total: total.max(self.nodes_at_best),
+        }
+    }
+
+    /// If the number of nodes at the best block has changed since this was last
+    /// called, hand back the latest numbers.
+    pub fn take_nodes_at_best_change(&mut self) -> Option<NodesAtBest> {
+        if !std::mem::take(&mut self.nodes_at_best_changed) {
+            return None;
+        }
+        Some(self.nodes_at_best())
+    }
+
+    pub fn public_nodes(&self) -> &PublicNodes {
+        &self.public_nodes
+    }
+
+    /// If the chain has dropped below the minimum number of public nodes, or recovered
+    /// from doing so, since this was last called, hand back the change.
+    pub fn take_public_node_shortage_change(&mut self) -> Option<ShortageChange> {
+        let min_public_nodes = self.alert_thresholds().min_public_nodes;
+        self.public_nodes.check_shortage(min_public_nodes)
+    }
+
+    /// The median of the mean block import latencies reported by nodes on this chain,
+    /// if enough nodes have reported them for this to be meaningful.
+    pub fn median_import_latency(&self) -> Option<f32> {
+        median(
+            self.nodes
+                .iter()
+                .filter_map(|(_, node)| node.hardware().import_latency_ms.mean())
+                .collect(),
+        )
+    }
+
+    /// The median of the mean Kademlia DHT query rates reported by nodes on this chain,
+    /// if enough nodes have reported them for this to be meaningful.
+    pub fn median_kademlia_query_rate(&self) -> Option<f32> {
+        median(
+            self.nodes
+                .iter()
+                .filter_map(|(_, node)| node.io().kademlia_queries_per_sec.mean())
+                .collect(),
+        )
+    }
+
+    /// The median of the database sizes reported by nodes on this chain, in bytes, or
+    /// `None` if no node has reported one.
+    pub fn median_database_size(&self) -> Option<u64> {
+        let mut sizes: Vec<u64> = self
+            .nodes
+            .iter()
+            .filter_map(|(_, node)| node.stats().database_size_bytes)
+            .collect();
+        if sizes.is_empty() {
+            return None;
+        }
+        sizes.sort_unstable();
+        Some(sizes[sizes.len() / 2])
+    }
+
+    /// The pallets taking up the most of this chain's state trie, largest first, going
+    /// by the median size that the nodes reporting each pallet give for it.
+    pub fn top_pallet_trie_sizes(&self) -> Vec<(Box<str>, u64)> {
+        let mut sizes: HashMap<&str, Vec<u64>> = HashMap::new();
+        for (_, node) in self.nodes.iter() {
+            for (pallet, size) in node.io().pallet_trie_sizes.iter().flatten() {
+                sizes.entry(pallet).or_default().push(*size);
+            }
+        }
+        let mut medians: Vec<(Box<str>, u64)> = sizes
+            .into_iter()
+            .map(|(pallet, mut sizes)| {
+                sizes.sort_unstable();
+                (pallet.into(), sizes[sizes.len() / 2])
+            })
+            .collect();
+        medians.sort_by(|(a_name, a_size), (b_name, b_size)| {
+            b_size.cmp(a_size).then_with(|| a_name.cmp(b_name))
+        });
+        medians.truncate(TOP_PALLETS);
+        medians
+    }
+
+    /// How many nodes on this chain are on each continent, most first. Nodes whose
+    /// country isn't known are left out.
+    pub fn continent_distribution(&self) -> Vec<(Box<str>, u32)> {
+        let mut counts: HashMap<&'static str, u32> = HashMap::new();
+        for (_, node) in self.nodes.iter() {
+            let country = node.location().and_then(|loc| loc.country.as_deref());
+            if let Some(country) = country {
+                *counts.entry(country_to_continent(country)).or_insert(0) += 1;
+            }
+        }
+        let mut distribution: Vec<(Box<str>, u32)> = counts
+            .into_iter()
+            .map(|(continent, count)| (continent.into(), count))
+            .collect();
+        distribution.sort_by(|(a_name, a_count), (b_name, b_count)| {
+            b_count.cmp(a_count).then_with(|| a_name.cmp(b_name))
+        });
+        distribution
+    }
+    pub fn distribution(&self) -> &Distribution {
+        &self.distribution
+    }
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory.report()
+    }
+}
+
+/// Roughly how many bytes are used to store a node.
+fn node_memory_usage(node: &Node) -> usize {
+    let details = node.details();
+    let strings = [
+        Some(&details.chain),
+        Some(&details.name),
+        Some(&details.implementation),
+        Some(&details.version),
+        details.validator.as_ref(),
+        details.network_id.as_ref(),
+        details.startup_time.as_ref(),
+    ];
+    let string_bytes: usize = strings.iter().flatten().map(|s| s.len()).sum();
+    std::mem::size_of::<Node>() + RecentBlocks::max_memory_usage() + string_bytes
+}
+
+/// We need values from at least this many nodes on a chain before
+/// we compare nodes against the chain median.
+const MIN_NODES_FOR_CHAIN_MEDIAN: usize = 3;
+
+/// The median of some per-node values, if there are enough of them.
+fn median(mut values: Vec<f32>) -> Option<f32> {
+    if values.len() < MIN_NODES_FOR_CHAIN_MEDIAN {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    Some(values[values.len() / 2])
+}
+
+/// Tell feeds about changes in the alerts raised against the nodes on a chain.
+pub fn push_alert_changes(
+    changes: &[(ChainNodeId, AlertChange)],
+    feed: &mut FeedMessageSerializer,
+) {
+    for &(nid, change) in changes {
+        push_alert_change(nid, Some(change), feed);
+    }
+}
+
+/// Tell feeds about a change in the alerts raised against a node, if there is one.
+fn push_alert_change(
+    nid: ChainNodeId,
+    change: Option<AlertChange>,
+    feed: &mut FeedMessageSerializer,
+) {
+    match change {
+        Some(AlertChange::Raised(alert)) => {
+            feed.push(feed_message::NodeAlert(nid.into(), &alert));
+        }
+        Some(AlertChange::Cleared(kind)) => {
+            feed.push(feed_message::NodeAlertCleared(nid.into(), kind));
+        }
+        None => {}
+    }
 }
 
 /// First party networks (Polkadot, Kusama etc) are allowed any number of nodes.
 /// Third party networks are allowed `THIRD_PARTY_NETWORKS_MAX_NODES` nodes and
 /// no more.
-fn max_nodes(label: &str) -> usize {
-    if FIRST_PARTY_NETWORKS.contains(label) {
+fn max_nodes(first_party: bool) -> usize {
+    if first_party {
         usize::MAX
     } else {
         THIRD_PARTY_NETWORKS_MAX_NODES
@@ -0,0 +1,408 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Summary stats about each chain, which are sent periodically to every feed. Usually
+//! only one or two of these change from one second to the next, so after the first
+//! message about a chain, we only send the fields that have changed. How the nodes on
+//! a chain are spread across continents is sent alongside these every so often.
+
+use super::StateChain;
+use common::node_types::{BlockHash, Timestamp};
+use std::collections::HashMap;
+
+/// After this many diffs, the next message about a chain contains every field again,
+/// in case a feed has somehow got out of step.
+pub const FULL_REFRESH_EVERY: usize = 60;
+
+/// Every this many updates, the continent distribution of a chain is sent along with
+/// whatever else has changed. Updates are made once a second, so this is every 30s.
+pub const CONTINENT_DISTRIBUTION_EVERY: usize = 30;
+
+/// A stable ID for each field of [`ChainStats`]. These are what feeds see, and so must
+/// never be reused or renumbered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ChainStatsField {
+    NodeCount = 0,
+    BestBlock = 1,
+    FinalizedBlock = 2,
+    AverageBlockTime = 3,
+    BestBlockTimestamp = 4,
+    NodesAtBest = 5,
+    MedianDatabaseSize = 6,
+    /// 1 if the best block has gone for much longer than usual without advancing, else 0.
+    BlockProductionStalled = 7,
+}
+
+/// One field of [`ChainStats`] and its value, as sent to feeds.
+pub type ChainStatsValue = (u8, Option<u64>);
+
+/// Summary stats about a chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainStats {
+    pub node_count: u64,
+    pub best_block: u64,
+    pub finalized_block: u64,
+    pub average_block_time: Option<u64>,
+    pub best_block_timestamp: Option<u64>,
+    pub nodes_at_best: u64,
+    /// In bytes.
+    pub median_database_size: Option<u64>,
+    pub block_production_stalled: bool,
+    /// Continent name to the number of nodes on it, most first. This isn't one of the
+    /// fields in [`ChainStats::values`], and is sent separately.
+    pub continent_distribution: Vec<(Box<str>, u32)>,
+    /// The pallets taking up the most of the chain's state trie, and how many bytes
+    /// they take, largest first. Like the continent distribution, this is sent separately.
+    pub top_pallet_trie_sizes: Vec<(Box<str>, u64)>,
+}
+
+impl ChainStats {
+    pub fn new(chain: &StateChain<'_>, now: Timestamp) -> ChainStats {
+        ChainStats {
+            node_count: chain.node_count() as u64,
+            best_block: chain.best_block().height,
+            finalized_block: chain.finalized_block().height,
+            average_block_time: chain.average_block_time(),
+            best_block_timestamp: chain.best_block_changed_at(),
+            nodes_at_best: chain.nodes_at_best().caught_up as u64,
+            median_database_size: chain.median_database_size(),
+            block_production_stalled: chain.block_production_stalled(now),
+            continent_distribution: chain.continent_distribution(),
+            top_pallet_trie_sizes: chain.top_pallet_trie_sizes(),
+        }
+    }
+
+    /// Every field, along with its ID.
+    pub fn values(&self) -> Vec<ChainStatsValue> {
+        vec![
+            (ChainStatsField::NodeCount as u8, Some(self.node_count)),
+            (ChainStatsField::BestBlock as u8, Some(self.best_block)),
+            (
+                ChainStatsField::FinalizedBlock as u8,
+                Some(self.finalized_block),
+            ),
+            (
+                ChainStatsField::AverageBlockTime as u8,
+                self.average_block_time,
+            ),
+            (
+                ChainStatsField::BestBlockTimestamp as u8,
+                self.best_block_timestamp,
+            ),
+            (ChainStatsField::NodesAtBest as u8, Some(self.nodes_at_best)),
+            (
+                ChainStatsField::MedianDatabaseSize as u8,
+                self.median_database_size,
+            ),
+            (
+                ChainStatsField::BlockProductionStalled as u8,
+                Some(self.block_production_stalled as u64),
+            ),
+        ]
+    }
+
+    /// The fields which differ from those in `previous`, along with their IDs.
+    pub fn diff(&self, previous: &ChainStats) -> Vec<ChainStatsValue> {
+        self.values()
+            .into_iter()
+            .zip(previous.values())
+            .filter(|(new, old)| new != old)
+            .map(|(new, _)| new)
+            .collect()
+    }
+}
+
+/// What to tell feeds about the stats of a chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainStatsUpdate {
+    pub genesis_hash: BlockHash,
+    /// Whether `values` contains every field (replacing whatever the feed had), or
+    /// only those that have changed.
+    pub full: bool,
+    pub values: Vec<ChainStatsValue>,
+    /// The continent distribution of the chain, if it's due to be sent.
+    pub continent_distribution: Option<Vec<(Box<str>, u32)>>,
+    /// The pallets taking up the most state trie space, if they've changed (or this
+    /// update is full).
+    pub top_pallet_trie_sizes: Option<Vec<(Box<str>, u64)>>,
+}
+
+struct LastSent {
+    stats: ChainStats,
+    diffs_since_full: usize,
+    updates_since_distribution: usize,
+}
+
+/// Keeps track of the stats last sent to feeds about each chain, so that we can work
+/// out what has changed since.
+#[derive(Default)]
+pub struct ChainStatsDiffer {
+    last_sent: HashMap<BlockHash, LastSent>,
+}
+
+impl ChainStatsDiffer {
+    pub fn new() -> ChainStatsDiffer {
+        ChainStatsDiffer::default()
+    }
+
+    /// Work out what feeds need to be told, given the current stats of a chain. Returns
+    /// `None` if nothing has changed since we last sent them and the continent
+    /// distribution isn't due.
+    pub fn update(
+        &mut self,
+        genesis_hash: BlockHash,
+        stats: ChainStats,
+    ) -> Option<ChainStatsUpdate> {
+        let last = match self.last_sent.get_mut(&genesis_hash) {
+            Some(last) if last.diffs_since_full < FULL_REFRESH_EVERY => last,
+            _ => {
+                let update = ChainStatsUpdate {
+                    genesis_hash,
+                    full: true,
+                    values: stats.values(),
+                    continent_distribution: Some(stats.continent_distribution.clone()),
+                    top_pallet_trie_sizes: Some(stats.top_pallet_trie_sizes.clone()),
+                };
+                self.last_sent.insert(
+                    genesis_hash,
+                    LastSent {
+                        stats,
+                        diffs_since_full: 0,
+                        updates_since_distribution: 0,
+                    },
+                );
+                return Some(update);
+            }
+        };
+
+        last.updates_since_distribution += 1;
+        let distribution_due = last.updates_since_distribution >= CONTINENT_DISTRIBUTION_EVERY;
+        let values = stats.diff(&last.stats);
+        let top_pallets_changed = stats.top_pallet_trie_sizes != last.stats.top_pallet_trie_sizes;
+        if values.is_empty() && !distribution_due && !top_pallets_changed {
+            return None;
+        }
+        let continent_distribution = if distribution_due {
+            last.updates_since_distribution = 0;
+            Some(stats.continent_distribution.clone())
+        } else {
+            None
+        };
+        let top_pallet_trie_sizes = if top_pallets_changed {
+            Some(stats.top_pallet_trie_sizes.clone())
+        } else {
+            None
+        };
+        last.stats = stats;
+        last.diffs_since_full += 1;
+        Some(ChainStatsUpdate {
+            genesis_hash,
+            full: false,
+            values,
+            continent_distribution,
+            top_pallet_trie_sizes,
+        })
+    }
+
+    /// The stats last sent about a chain, which is what a feed that's just connected
+    /// should be given in full so that the diffs that follow apply to it.
+    pub fn last_sent(&self, genesis_hash: &BlockHash) -> Option<&ChainStats> {
+        self.last_sent.get(genesis_hash).map(|last| &last.stats)
+    }
+
+    /// Forget about a chain, so that the next update about it is sent in full. This
+    /// should be done whenever feeds are told that the chain has been removed.
+    pub fn forget(&mut self, genesis_hash: &BlockHash) {
+        self.last_sent.remove(genesis_hash);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::{self, FeedMessageSerializer};
+    use std::collections::BTreeMap;
+    use test_utils::feed_message_de::{ChainStatsView, FeedMessage};
+
+    fn stats(best_block: u64) -> ChainStats {
+        ChainStats {
+            node_count: 3,
+            best_block,
+            finalized_block: best_block.saturating_sub(2),
+            average_block_time: Some(6000),
+            best_block_timestamp: None,
+            nodes_at_best: 2,
+            median_database_size: None,
+            block_production_stalled: false,
+            continent_distribution: vec![("Europe".into(), 2), ("Asia".into(), 1)],
+            top_pallet_trie_sizes: vec![("Balances".into(), 2000), ("System".into(), 1000)],
+        }
+    }
+
+    #[test]
+    fn first_update_is_full_and_later_ones_only_have_changes() {
+        let genesis = BlockHash::from_low_u64_be(1);
+        let mut differ = ChainStatsDiffer::new();
+
+        let first = differ.update(genesis, stats(10)).unwrap();
+        assert!(first.full);
+        assert_eq!(first.values, stats(10).values());
+        assert_eq!(
+            first.continent_distribution,
+            Some(stats(10).continent_distribution)
+        );
+
+        assert_eq!(differ.update(genesis, stats(10)), None);
+
+        let next = differ.update(genesis, stats(11)).unwrap();
+        assert!(!next.full);
+        assert_eq!(next.continent_distribution, None);
+        assert_eq!(
+            next.values,
+            vec![
+                (ChainStatsField::BestBlock as u8, Some(11)),
+                (ChainStatsField::FinalizedBlock as u8, Some(9)),
+            ]
+        );
+        assert_eq!(differ.last_sent(&genesis), Some(&stats(11)));
+    }
+
+    #[test]
+    fn full_refresh_is_sent_periodically_and_after_forgetting() {
+        let genesis = BlockHash::from_low_u64_be(1);
+        let mut differ = ChainStatsDiffer::new();
+
+        differ.update(genesis, stats(0));
+        for height in 1..=FULL_REFRESH_EVERY as u64 {
+            assert!(!differ.update(genesis, stats(height)).unwrap().full);
+        }
+        // Even if nothing has changed, a full refresh is due:
+        let refresh = FULL_REFRESH_EVERY as u64;
+        assert!(differ.update(genesis, stats(refresh)).unwrap().full);
+        assert!(!differ.update(genesis, stats(refresh + 1)).unwrap().full);
+
+        differ.forget(&genesis);
+        assert_eq!(differ.last_sent(&genesis), None);
+        assert!(differ.update(genesis, stats(refresh + 1)).unwrap().full);
+    }
+
+    #[test]
+    fn continent_distribution_is_sent_every_so_often() {
+        let genesis = BlockHash::from_low_u64_be(1);
+        let mut differ = ChainStatsDiffer::new();
+
+        differ.update(genesis, stats(0));
+        let mut current = stats(0);
+        current.continent_distribution = vec![("Europe".into(), 3)];
+        for _ in 1..CONTINENT_DISTRIBUTION_EVERY {
+            // Changes to the distribution alone aren't sent until it's due:
+            assert_eq!(differ.update(genesis, current.clone()), None);
+        }
+
+        // Once it's due, it's sent even if nothing else has changed:
+        let update = differ.update(genesis, current.clone()).unwrap();
+        assert!(!update.full);
+        assert!(update.values.is_empty());
+        assert_eq!(
+            update.continent_distribution,
+            Some(vec![("Europe".into(), 3)])
+        );
+        assert_eq!(differ.last_sent(&genesis), Some(&current));
+
+        // ... and then not again for a while:
+        let update = differ.update(genesis, stats(1)).unwrap();
+        assert_eq!(update.continent_distribution, None);
+    }
+
+    #[test]
+    fn top_pallets_are_only_sent_when_they_change() {
+        let genesis = BlockHash::from_low_u64_be(1);
+        let mut differ = ChainStatsDiffer::new();
+
+        let first = differ.update(genesis, stats(0)).unwrap();
+        assert_eq!(
+            first.top_pallet_trie_sizes,
+            Some(stats(0).top_pallet_trie_sizes)
+        );
+
+        let update = differ.update(genesis, stats(1)).unwrap();
+        assert_eq!(update.top_pallet_trie_sizes, None);
+
+        // A change to the top pallets alone is sent straight away:
+        let mut current = stats(1);
+        current.top_pallet_trie_sizes = vec![("Balances".into(), 3000)];
+        let update = differ.update(genesis, current).unwrap();
+        assert!(update.values.is_empty());
+        assert_eq!(
+            update.top_pallet_trie_sizes,
+            Some(vec![("Balances".into(), 3000)])
+        );
+    }
+
+    #[test]
+    fn client_reconstructs_the_servers_view_from_diffs() {
+        let genesis = BlockHash::from_low_u64_be(1);
+        let mut differ = ChainStatsDiffer::new();
+        let mut client = ChainStatsView::default();
+
+        let mut server = stats(0);
+        for tick in 0..(3 * FULL_REFRESH_EVERY as u64) {
+            // Change a different handful of fields each tick:
+            server.best_block += tick % 2;
+            server.finalized_block += tick % 3 / 2;
+            server.node_count = 3 + tick % 5;
+            server.best_block_timestamp = (tick % 4 != 0).then_some(tick * 1000);
+            server.block_production_stalled = tick % 7 == 0;
+
+            let update = match differ.update(genesis, server.clone()) {
+                Some(update) => update,
+                None => continue,
+            };
+            let mut ser = FeedMessageSerializer::new();
+            ser.push(feed_message::ChainStats(
+                "Chain One",
+                update.full,
+                &update.values,
+                update.continent_distribution.as_deref(),
+                update.top_pallet_trie_sizes.as_deref(),
+            ));
+            let bytes = ser.into_finalized().unwrap();
+            for msg in FeedMessage::from_bytes(&bytes).unwrap() {
+                match msg {
+                    FeedMessage::ChainStats {
+                        name,
+                        full,
+                        values,
+                        continent_distribution,
+                        ..
+                    } => {
+                        assert_eq!(name, "Chain One");
+                        assert_eq!(
+                            continent_distribution.is_some(),
+                            update.continent_distribution.is_some()
+                        );
+                        client.apply(full, &values);
+                    }
+                    msg => panic!("unexpected message: {:?}", msg),
+                }
+            }
+
+            let expected: BTreeMap<u8, Option<u64>> = server.values().into_iter().collect();
+            assert_eq!(client.values(), &expected);
+        }
+    }
+}
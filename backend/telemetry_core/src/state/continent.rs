@@ -0,0 +1,119 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+const AFRICA: &str = "Africa";
+const ANTARCTICA: &str = "Antarctica";
+const ASIA: &str = "Asia";
+const EUROPE: &str = "Europe";
+const NORTH_AMERICA: &str = "North America";
+const OCEANIA: &str = "Oceania";
+const SOUTH_AMERICA: &str = "South America";
+
+/// Country codes that we don't recognise are counted here.
+pub const UNKNOWN_CONTINENT: &str = "Unknown";
+
+/// The continent that the country with the given two letter ISO 3166-1 code is on.
+/// Codes are matched case insensitively. Countries that span continents are counted
+/// on just one of them.
+pub fn country_to_continent(code: &str) -> &'static str {
+    let code = match *code.as_bytes() {
+        [a, b] => [a.to_ascii_uppercase(), b.to_ascii_uppercase()],
+        _ => return UNKNOWN_CONTINENT,
+    };
+    match &code {
+        b"AO" | b"BF" | b"BI" | b"BJ" | b"BW" | b"CD" | b"CF" | b"CG" | b"CI" | b"CM" | b"CV"
+        | b"DJ" | b"DZ" | b"EG" | b"EH" | b"ER" | b"ET" | b"GA" | b"GH" | b"GM" | b"GN" | b"GQ"
+        | b"GW" | b"KE" | b"KM" | b"LR" | b"LS" | b"LY" | b"MA" | b"MG" | b"ML" | b"MR" | b"MU"
+        | b"MW" | b"MZ" | b"NA" | b"NE" | b"NG" | b"RE" | b"RW" | b"SC" | b"SD" | b"SH" | b"SL"
+        | b"SN" | b"SO" | b"SS" | b"ST" | b"SZ" | b"TD" | b"TG" | b"TN" | b"TZ" | b"UG" | b"YT"
+        | b"ZA" | b"ZM" | b"ZW" => AFRICA,
+        b"AQ" | b"BV" | b"GS" | b"HM" | b"TF" => ANTARCTICA,
+        b"AE" | b"AF" | b"AM" | b"AZ" | b"BD" | b"BH" | b"BN" | b"BT" | b"CC" | b"CN" | b"CX"
+        | b"GE" | b"HK" | b"ID" | b"IL" | b"IN" | b"IO" | b"IQ" | b"IR" | b"JO" | b"JP" | b"KG"
+        | b"KH" | b"KP" | b"KR" | b"KW" | b"KZ" | b"LA" | b"LB" | b"LK" | b"MM" | b"MN" | b"MO"
+        | b"MV" | b"MY" | b"NP" | b"OM" | b"PH" | b"PK" | b"PS" | b"QA" | b"SA" | b"SG" | b"SY"
+        | b"TH" | b"TJ" | b"TL" | b"TM" | b"TR" | b"TW" | b"UZ" | b"VN" | b"YE" => ASIA,
+        b"AD" | b"AL" | b"AT" | b"AX" | b"BA" | b"BE" | b"BG" | b"BY" | b"CH" | b"CY" | b"CZ"
+        | b"DE" | b"DK" | b"EE" | b"ES" | b"FI" | b"FO" | b"FR" | b"GB" | b"GG" | b"GI" | b"GR"
+        | b"HR" | b"HU" | b"IE" | b"IM" | b"IS" | b"IT" | b"JE" | b"LI" | b"LT" | b"LU" | b"LV"
+        | b"MC" | b"MD" | b"ME" | b"MK" | b"MT" | b"NL" | b"NO" | b"PL" | b"PT" | b"RO" | b"RS"
+        | b"RU" | b"SE" | b"SI" | b"SJ" | b"SK" | b"SM" | b"UA" | b"VA" => EUROPE,
+        b"AG" | b"AI" | b"AW" | b"BB" | b"BL" | b"BM" | b"BQ" | b"BS" | b"BZ" | b"CA" | b"CR"
+        | b"CU" | b"CW" | b"DM" | b"DO" | b"GD" | b"GL" | b"GP" | b"GT" | b"HN" | b"HT" | b"JM"
+        | b"KN" | b"KY" | b"LC" | b"MF" | b"MQ" | b"MS" | b"MX" | b"NI" | b"PA" | b"PM" | b"PR"
+        | b"SV" | b"SX" | b"TC" | b"TT" | b"US" | b"VC" | b"VG" | b"VI" => NORTH_AMERICA,
+        b"AS" | b"AU" | b"CK" | b"FJ" | b"FM" | b"GU" | b"KI" | b"MH" | b"MP" | b"NC" | b"NF"
+        | b"NR" | b"NU" | b"NZ" | b"PF" | b"PG" | b"PN" | b"PW" | b"SB" | b"TK" | b"TO" | b"TV"
+        | b"UM" | b"VU" | b"WF" | b"WS" => OCEANIA,
+        b"AR" | b"BO" | b"BR" | b"CL" | b"CO" | b"EC" | b"FK" | b"GF" | b"GY" | b"PE" | b"PY"
+        | b"SR" | b"UY" | b"VE" => SOUTH_AMERICA,
+        _ => UNKNOWN_CONTINENT,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn looks_up_continents() {
+        assert_eq!(country_to_continent("DE"), EUROPE);
+        assert_eq!(country_to_continent("US"), NORTH_AMERICA);
+        assert_eq!(country_to_continent("BR"), SOUTH_AMERICA);
+        assert_eq!(country_to_continent("JP"), ASIA);
+        assert_eq!(country_to_continent("NG"), AFRICA);
+        assert_eq!(country_to_continent("NZ"), OCEANIA);
+        assert_eq!(country_to_continent("AQ"), ANTARCTICA);
+    }
+
+    #[test]
+    fn codes_are_case_insensitive() {
+        assert_eq!(country_to_continent("gb"), EUROPE);
+        assert_eq!(country_to_continent("Au"), OCEANIA);
+    }
+
+    #[test]
+    fn unknown_codes_are_unknown() {
+        assert_eq!(country_to_continent(""), UNKNOWN_CONTINENT);
+        assert_eq!(country_to_continent("X"), UNKNOWN_CONTINENT);
+        assert_eq!(country_to_continent("DEU"), UNKNOWN_CONTINENT);
+        assert_eq!(country_to_continent("ZZ"), UNKNOWN_CONTINENT);
+        assert_eq!(country_to_continent("É"), UNKNOWN_CONTINENT);
+    }
+
+    #[test]
+    fn every_assigned_code_is_on_a_continent() {
+        let mut counts = std::collections::HashMap::new();
+        for a in b'A'..=b'Z' {
+            for b in b'A'..=b'Z' {
+                let code = [a, b];
+                let continent = country_to_continent(std::str::from_utf8(&code).unwrap());
+                *counts.entry(continent).or_insert(0) += 1;
+            }
+        }
+        counts.remove(UNKNOWN_CONTINENT);
+
+        // There are 249 officially assigned ISO 3166-1 alpha-2 codes:
+        assert_eq!(counts.values().sum::<usize>(), 249);
+        assert_eq!(counts[AFRICA], 58);
+        assert_eq!(counts[ANTARCTICA], 5);
+        assert_eq!(counts[ASIA], 53);
+        assert_eq!(counts[EUROPE], 52);
+        assert_eq!(counts[NORTH_AMERICA], 41);
+        assert_eq!(counts[OCEANIA], 26);
+        assert_eq!(counts[SOUTH_AMERICA], 14);
+    }
+}
@@ -0,0 +1,361 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_types::NodeDetails;
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// How many distinct values will we count before lumping any new
+/// values into the "other" bucket?
+const MAX_DISTINCT_VALUES: usize = 50;
+
+/// The key that values which don't fit into our distinct value limit are
+/// reported under.
+const OTHER: &str = "other";
+
+/// Values that are empty once normalised are counted here.
+const UNKNOWN: &str = "unknown";
+
+/// A breakdown of which client implementations and versions the nodes
+/// on a chain are running.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Distribution {
+    implementations: Counts,
+    versions: Counts,
+}
+
+impl Distribution {
+    pub fn new() -> Self {
+        Distribution::default()
+    }
+
+    /// Count the details of a node that's been added.
+    pub fn add(&mut self, details: &NodeDetails) {
+        self.implementations.add(&details.implementation);
+        self.versions.add(&details.version);
+    }
+
+    /// Stop counting the details of a node that's been removed.
+    pub fn remove(&mut self, details: &NodeDetails) {
+        self.implementations.remove(&details.implementation);
+        self.versions.remove(&details.version);
+    }
+}
+
+#[cfg(test)]
+impl Distribution {
+    pub fn implementations(&self) -> &Counts {
+        &self.implementations
+    }
+
+    pub fn versions(&self) -> &Counts {
+        &self.versions
+    }
+}
+
+/// Count occurrences of normalised strings, up to some maximum number of
+/// distinct strings, after which everything new is counted as "other".
+///
+/// The "other" count is kept apart from the values, so that a value which really is
+/// "other" is counted like any other value rather than mixed up with the overflow.
+#[derive(Debug, Clone, Default)]
+pub struct Counts {
+    values: HashMap<Box<str>, usize>,
+    other: usize,
+}
+
+impl Counts {
+    /// Increment the count for the value given.
+    ///
+    /// While anything is counted as "other", no new values are given a count of their own
+    /// even if there's room for them. Otherwise a value could end up counted partly in its
+    /// own bucket and partly in "other", and we'd not know which to decrement on removal.
+    pub fn add(&mut self, value: &str) {
+        let value = normalise(value);
+        let has_room = self.other == 0 && self.values.len() < MAX_DISTINCT_VALUES;
+        if let Some(count) = self.values.get_mut(&*value) {
+            *count += 1;
+        } else if has_room {
+            self.values.insert(value.into_boxed_str(), 1);
+        } else {
+            self.other += 1;
+        }
+    }
+
+    /// Decrement the count for the value given. If the value isn't found,
+    /// it must have been counted as "other", so decrement that instead.
+    pub fn remove(&mut self, value: &str) {
+        let value = normalise(value);
+        match self.values.get_mut(&*value) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    self.values.remove(&*value);
+                }
+            }
+            None => self.other = self.other.saturating_sub(1),
+        }
+    }
+}
+
+/// Counts are handed out as a map from value to count, with anything counted as
+/// "other" under that key (added to the count of any value that really is "other").
+impl Serialize for Counts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let has_other_value = self.values.contains_key(OTHER);
+        let separate_other = self.other > 0 && !has_other_value;
+        let mut map =
+            serializer.serialize_map(Some(self.values.len() + separate_other as usize))?;
+        for (value, &count) in &self.values {
+            let count = if &**value == OTHER {
+                count + self.other
+            } else {
+                count
+            };
+            map.serialize_entry(value, &count)?;
+        }
+        if separate_other {
+            map.serialize_entry(OTHER, &self.other)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+impl Counts {
+    pub fn get(&self, value: &str) -> usize {
+        self.values.get(value).copied().unwrap_or(0)
+    }
+
+    /// How many values were counted as "other" because there wasn't room for them.
+    pub fn other(&self) -> usize {
+        self.other
+    }
+
+    /// How many values have been counted in total.
+    pub fn total(&self) -> usize {
+        self.values.values().sum::<usize>() + self.other
+    }
+
+    /// How many buckets values are counted in, including "other".
+    pub fn len(&self) -> usize {
+        self.values.len() + (self.other > 0) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Trim and lowercase values so that trivially different strings are counted together.
+fn normalise(value: &str) -> String {
+    let value = value.trim();
+    if value.is_empty() {
+        UNKNOWN.to_owned()
+    } else {
+        value.to_lowercase()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn details(implementation: &str, version: &str) -> NodeDetails {
+        NodeDetails {
+            chain: "Chain".into(),
+            name: "Node".into(),
+            implementation: implementation.into(),
+            version: version.into(),
+            validator: None,
+            network_id: None,
+            startup_time: None,
+            chain_type: None,
+            environment: None,
+            pruning_mode: None,
+            offchain_indexing: false,
+            pending_upgrade_block: None,
+            verbosity: None,
+            publicly_reachable: None,
+            message_schema: None,
+            extra_info: Default::default(),
+        }
+    }
+
+    #[test]
+    fn values_are_normalised() {
+        let mut counts = Counts::default();
+        counts.add("Parity Polkadot");
+        counts.add("  parity polkadot ");
+        counts.add("PARITY POLKADOT");
+        counts.add("   ");
+
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts.get("parity polkadot"), 3);
+        assert_eq!(counts.get(UNKNOWN), 1);
+    }
+
+    #[test]
+    fn removing_values_decrements_and_tidies_up() {
+        let mut counts = Counts::default();
+        counts.add("Substrate Node");
+        counts.add("Substrate Node");
+
+        counts.remove("substrate node ");
+        assert_eq!(counts.get("substrate node"), 1);
+
+        counts.remove("Substrate Node");
+        assert_eq!(counts.get("substrate node"), 0);
+        assert!(counts.is_empty());
+
+        // Removing something that isn't there is fine:
+        counts.remove("Substrate Node");
+        assert!(counts.is_empty());
+    }
+
+    #[test]
+    fn cardinality_is_bounded() {
+        let mut counts = Counts::default();
+        for n in 0..MAX_DISTINCT_VALUES {
+            counts.add(&format!("impl {}", n));
+        }
+        assert_eq!(counts.len(), MAX_DISTINCT_VALUES);
+
+        // Over the limit now, so new values are counted as "other":
+        counts.add("Custom Fork");
+        counts.add("Another Fork");
+        assert_eq!(counts.len(), MAX_DISTINCT_VALUES + 1);
+        assert_eq!(counts.other(), 2);
+        assert_eq!(counts.get("custom fork"), 0);
+
+        // Values we already know about are still counted individually:
+        counts.add("Impl 0");
+        assert_eq!(counts.get("impl 0"), 2);
+
+        // Removing values counted as "other" decrements that:
+        counts.remove("Custom Fork");
+        assert_eq!(counts.other(), 1);
+
+        // The total count always matches what's been added and removed:
+        assert_eq!(counts.total(), MAX_DISTINCT_VALUES + 2);
+    }
+
+    #[test]
+    fn values_stay_in_other_until_it_empties() {
+        let mut counts = Counts::default();
+        for n in 0..MAX_DISTINCT_VALUES {
+            counts.add(&format!("impl {}", n));
+        }
+        counts.add("Custom Fork");
+        assert_eq!(counts.other(), 1);
+
+        // Make room, and add the value that overflowed again:
+        counts.remove("Impl 0");
+        counts.remove("Impl 1");
+        counts.add("Custom Fork");
+        assert_eq!(counts.get("custom fork"), 0);
+        assert_eq!(counts.other(), 2);
+
+        // Both are removed from the bucket they were counted in:
+        counts.remove("Custom Fork");
+        counts.remove("Custom Fork");
+        assert_eq!(counts.other(), 0);
+        assert_eq!(counts.len(), MAX_DISTINCT_VALUES - 2);
+        assert_eq!(counts.total(), MAX_DISTINCT_VALUES - 2);
+
+        // Now that nothing is counted as "other", new values get their own count again:
+        counts.add("Custom Fork");
+        assert_eq!(counts.get("custom fork"), 1);
+    }
+
+    #[test]
+    fn values_called_other_are_not_mixed_up_with_the_overflow() {
+        let mut counts = Counts::default();
+        counts.add("Other");
+        for n in 1..MAX_DISTINCT_VALUES {
+            counts.add(&format!("impl {}", n));
+        }
+        counts.add("Custom Fork");
+        counts.add("other");
+        assert_eq!(counts.get(OTHER), 2);
+        assert_eq!(counts.other(), 1);
+
+        // Removing the nodes really called "other" leaves the overflow alone:
+        counts.remove("Other");
+        counts.remove("other");
+        assert_eq!(counts.get(OTHER), 0);
+        assert_eq!(counts.other(), 1);
+        assert_eq!(counts.total(), MAX_DISTINCT_VALUES);
+
+        // ...and removing the value that overflowed empties it:
+        counts.remove("Custom Fork");
+        assert_eq!(counts.other(), 0);
+        assert_eq!(counts.total(), MAX_DISTINCT_VALUES - 1);
+    }
+
+    #[test]
+    fn other_values_and_the_overflow_are_reported_together() {
+        let mut counts = Counts::default();
+        counts.add("other");
+        for n in 1..MAX_DISTINCT_VALUES {
+            counts.add(&format!("impl {}", n));
+        }
+        counts.add("Custom Fork");
+        counts.add("Another Fork");
+
+        let json = serde_json::to_value(&counts).unwrap();
+        assert_eq!(json["other"], 3);
+        assert_eq!(json.as_object().unwrap().len(), MAX_DISTINCT_VALUES);
+    }
+
+    #[test]
+    fn distribution_counts_implementations_and_versions() {
+        let mut dist = Distribution::new();
+        let a = details("Parity Polkadot", "0.9.8");
+        let b = details("Parity Polkadot", "0.9.9");
+        let c = details("Substrate Node", "0.9.9");
+
+        dist.add(&a);
+        dist.add(&b);
+        dist.add(&c);
+
+        assert_eq!(dist.implementations().get("parity polkadot"), 2);
+        assert_eq!(dist.implementations().get("substrate node"), 1);
+        assert_eq!(dist.versions().get("0.9.8"), 1);
+        assert_eq!(dist.versions().get("0.9.9"), 2);
+
+        dist.remove(&b);
+
+        assert_eq!(dist.implementations().get("parity polkadot"), 1);
+        assert_eq!(dist.versions().get("0.9.9"), 1);
+    }
+
+    #[test]
+    fn distribution_serializes_as_maps() {
+        let mut dist = Distribution::new();
+        dist.add(&details("Parity Polkadot", "0.9.8"));
+
+        let json = serde_json::to_value(&dist).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "implementations": { "parity polkadot": 1 },
+                "versions": { "0.9.8": 1 }
+            })
+        );
+    }
+}
@@ -0,0 +1,105 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Which block validators on a chain have finalized at each recent height. Finality
+//! should never conflict, so two validators finalizing different blocks at the same
+//! height means that something has gone badly wrong.
+
+use common::node_types::{Block, BlockHash, BlockNumber};
+use std::collections::BTreeMap;
+
+/// How many heights below the highest finalized block we remember hashes for.
+pub const MAX_HEIGHTS: BlockNumber = 256;
+
+/// A bounded map from recently finalized heights to the hash first finalized at each.
+#[derive(Debug, Clone, Default)]
+pub struct FinalizedHashes {
+    by_height: BTreeMap<BlockNumber, BlockHash>,
+}
+
+impl FinalizedHashes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note that a validator has finalized the given block. If a different block was
+    /// already finalized at the same height, hand back its hash.
+    pub fn observe(&mut self, block: Block) -> Option<BlockHash> {
+        if let Some(&hash) = self.by_height.get(&block.height) {
+            return (hash != block.hash).then_some(hash);
+        }
+
+        self.by_height.insert(block.height, block.hash);
+        let highest = *self.by_height.keys().next_back().expect("just inserted");
+        let lowest_kept = highest.saturating_sub(MAX_HEIGHTS - 1);
+        self.by_height = self.by_height.split_off(&lowest_kept);
+        None
+    }
+
+    /// Forget every finalized block.
+    pub fn clear(&mut self) {
+        self.by_height.clear();
+    }
+
+    /// Roughly how many bytes the finalized blocks we remember take up.
+    pub fn memory_usage(&self) -> usize {
+        self.by_height.len()
+            * (std::mem::size_of::<BlockNumber>() + std::mem::size_of::<BlockHash>())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(height: BlockNumber, hash: u64) -> Block {
+        Block {
+            hash: BlockHash::from_low_u64_be(hash),
+            height,
+        }
+    }
+
+    #[test]
+    fn different_hashes_at_the_same_height_conflict() {
+        let mut hashes = FinalizedHashes::new();
+
+        assert_eq!(hashes.observe(block(10, 1)), None);
+        assert_eq!(hashes.observe(block(10, 1)), None);
+        assert_eq!(hashes.observe(block(11, 2)), None);
+        assert_eq!(
+            hashes.observe(block(10, 3)),
+            Some(BlockHash::from_low_u64_be(1))
+        );
+    }
+
+    #[test]
+    fn old_heights_are_forgotten() {
+        let mut hashes = FinalizedHashes::new();
+        for height in 0..(MAX_HEIGHTS + 10) {
+            hashes.observe(block(height, height));
+        }
+        assert_eq!(hashes.by_height.len(), MAX_HEIGHTS as usize);
+
+        // The oldest heights can no longer conflict:
+        assert_eq!(hashes.observe(block(0, 1000)), None);
+        // But more recent ones can:
+        let recent = MAX_HEIGHTS + 9;
+        assert_eq!(
+            hashes.observe(block(recent, 1000)),
+            Some(BlockHash::from_low_u64_be(recent))
+        );
+    }
+}
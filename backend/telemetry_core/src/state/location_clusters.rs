@@ -0,0 +1,121 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Drawing thousands of nodes on a map means clustering them first. Rather than leave
+//! that to every client, node locations can be handed out grouped into a grid of cells,
+//! each given as the centroid of the nodes in it along with how many nodes there are.
+
+use common::node_types::NodeLocation;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The smallest and largest cells, in degrees, that locations can be clustered into.
+pub const MIN_CELL_DEGREES: f32 = 0.01;
+pub const MAX_CELL_DEGREES: f32 = 90.0;
+
+/// The nodes in one cell of the grid.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LocationCluster {
+    /// The mean latitude of the nodes in the cell.
+    pub latitude: f32,
+    /// The mean longitude of the nodes in the cell.
+    pub longitude: f32,
+    pub nodes: usize,
+}
+
+/// Group locations into square cells of `cell_degrees` on a side, largest cluster first.
+pub fn cluster_locations<'a>(
+    locations: impl IntoIterator<Item = &'a NodeLocation>,
+    cell_degrees: f32,
+) -> Vec<LocationCluster> {
+    let cell_degrees = cell_degrees.clamp(MIN_CELL_DEGREES, MAX_CELL_DEGREES);
+    let cell_of = |degrees: f32| (degrees / cell_degrees).floor() as i32;
+
+    // Sum in f64 so that large cells don't lose precision:
+    let mut cells: HashMap<(i32, i32), (f64, f64, usize)> = HashMap::new();
+    for location in locations {
+        let cell = (cell_of(location.latitude), cell_of(location.longitude));
+        let (lat_sum, lon_sum, nodes) = cells.entry(cell).or_default();
+        *lat_sum += location.latitude as f64;
+        *lon_sum += location.longitude as f64;
+        *nodes += 1;
+    }
+
+    let mut clusters: Vec<_> = cells.into_iter().collect();
+    // Most nodes first, and then in a stable order for cells with as many nodes:
+    clusters.sort_unstable_by(|(a_cell, a), (b_cell, b)| b.2.cmp(&a.2).then(a_cell.cmp(b_cell)));
+    clusters
+        .into_iter()
+        .map(|(_, (lat_sum, lon_sum, nodes))| LocationCluster {
+            latitude: (lat_sum / nodes as f64) as f32,
+            longitude: (lon_sum / nodes as f64) as f32,
+            nodes,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn location(latitude: f32, longitude: f32) -> NodeLocation {
+        NodeLocation {
+            latitude,
+            longitude,
+            city: "".into(),
+            country: None,
+        }
+    }
+
+    #[test]
+    fn nearby_nodes_are_grouped_into_one_cell() {
+        let locations = vec![
+            location(51.2, -0.4),
+            location(51.8, -0.2),
+            location(51.5, -0.9),
+            location(48.8, 2.3),
+        ];
+        let clusters = cluster_locations(&locations, 1.0);
+        assert_eq!(clusters.len(), 2);
+
+        assert_eq!(clusters[0].nodes, 3);
+        assert!((clusters[0].latitude - 51.5).abs() < 1e-4);
+        assert!((clusters[0].longitude - -0.5).abs() < 1e-4);
+        assert_eq!(
+            clusters[1],
+            LocationCluster {
+                latitude: 48.8,
+                longitude: 2.3,
+                nodes: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn cell_size_decides_what_is_nearby() {
+        let locations = vec![location(51.2, -0.4), location(51.8, -0.2)];
+        assert_eq!(cluster_locations(&locations, 0.5).len(), 2);
+        assert_eq!(cluster_locations(&locations, 5.0).len(), 1);
+
+        // Cells either side of the equator and meridian are different cells:
+        let locations = vec![location(0.1, 0.1), location(-0.1, -0.1)];
+        let clusters = cluster_locations(&locations, 1.0);
+        assert_eq!(clusters.len(), 2);
+        assert!(clusters.iter().all(|c| c.nodes == 1));
+
+        assert!(cluster_locations(&[], 1.0).is_empty());
+    }
+}
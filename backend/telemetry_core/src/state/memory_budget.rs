@@ -0,0 +1,275 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use serde::Serialize;
+
+/// The kinds of per-chain data that we account memory usage for. When a chain
+/// goes over its memory budget, data is evicted from each kind of buffer in the
+/// order that they're declared here, until the chain is back under budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferKind {
+    /// Recent history kept around to replay to feeds (the node count history).
+    /// Evicted first.
+    Replay,
+    /// Histograms and statistics about past blocks.
+    Histogram,
+    /// What we remember about recent blocks: when each was first seen, and
+    /// what validators have finalized.
+    RecentBlocks,
+    /// Live state about the nodes connected to the chain. This is accounted
+    /// for, but never evicted.
+    NodeState,
+}
+
+impl BufferKind {
+    /// Every kind of buffer, in eviction order.
+    pub const ALL: [BufferKind; 4] = [
+        BufferKind::Replay,
+        BufferKind::Histogram,
+        BufferKind::RecentBlocks,
+        BufferKind::NodeState,
+    ];
+
+    /// Can data be evicted from this kind of buffer?
+    pub fn is_evictable(self) -> bool {
+        self != BufferKind::NodeState
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BufferKind::Replay => "replay",
+            BufferKind::Histogram => "histogram",
+            BufferKind::RecentBlocks => "recent_blocks",
+            BufferKind::NodeState => "node_state",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Keeps track of approximately how many bytes each kind of buffer on a
+/// chain is using, against an optional budget for the chain as a whole.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    usage: [usize; BufferKind::ALL.len()],
+}
+
+impl MemoryBudget {
+    /// Create a new budget. If no limit is given, usage is still
+    /// accounted for but nothing will ever be evicted.
+    pub fn new(limit: Option<usize>) -> Self {
+        MemoryBudget {
+            limit,
+            usage: Default::default(),
+        }
+    }
+
+    /// Set the number of bytes used by some kind of buffer.
+    pub fn set(&mut self, kind: BufferKind, bytes: usize) {
+        self.usage[kind.index()] = bytes;
+    }
+
+    /// Account for some more bytes used by a kind of buffer.
+    pub fn add(&mut self, kind: BufferKind, bytes: usize) {
+        let usage = &mut self.usage[kind.index()];
+        *usage = usage.saturating_add(bytes);
+    }
+
+    /// Account for some bytes no longer being used by a kind of buffer.
+    pub fn sub(&mut self, kind: BufferKind, bytes: usize) {
+        let usage = &mut self.usage[kind.index()];
+        *usage = usage.saturating_sub(bytes);
+    }
+
+    /// How many bytes does this kind of buffer use?
+    pub fn usage(&self, kind: BufferKind) -> usize {
+        self.usage[kind.index()]
+    }
+
+    /// How many bytes are used across every kind of buffer?
+    pub fn total(&self) -> usize {
+        self.usage.iter().sum()
+    }
+
+    /// How many bytes over budget are we?
+    pub fn excess(&self) -> usize {
+        match self.limit {
+            Some(limit) => self.total().saturating_sub(limit),
+            None => 0,
+        }
+    }
+
+    /// If we're over budget, this hands back the kinds of buffer that have
+    /// data which can be evicted, in the order that they should be evicted from.
+    /// If evicting all of them wouldn't bring us back under budget (because live
+    /// node state alone is over it), nothing is handed back; there's no point
+    /// throwing data away for nothing.
+    pub fn kinds_to_evict(&self) -> Vec<BufferKind> {
+        let excess = self.excess();
+        let evictable: usize = BufferKind::ALL
+            .iter()
+            .filter(|kind| kind.is_evictable())
+            .map(|kind| self.usage(*kind))
+            .sum();
+        if excess == 0 || evictable < excess {
+            return Vec::new();
+        }
+        BufferKind::ALL
+            .iter()
+            .copied()
+            .filter(|kind| kind.is_evictable() && self.usage(*kind) > 0)
+            .collect()
+    }
+
+    /// A summary of the accounted usage, for metrics and the admin API.
+    pub fn report(&self) -> MemoryUsage {
+        MemoryUsage {
+            limit: self.limit,
+            total: self.total(),
+            replay: self.usage(BufferKind::Replay),
+            histogram: self.usage(BufferKind::Histogram),
+            recent_blocks: self.usage(BufferKind::RecentBlocks),
+            node_state: self.usage(BufferKind::NodeState),
+        }
+    }
+}
+
+/// The accounted memory usage of a chain, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct MemoryUsage {
+    pub limit: Option<usize>,
+    pub total: usize,
+    pub replay: usize,
+    pub histogram: usize,
+    pub recent_blocks: usize,
+    pub node_state: usize,
+}
+
+impl MemoryUsage {
+    /// The bytes used by some kind of buffer.
+    pub fn get(&self, kind: BufferKind) -> usize {
+        match kind {
+            BufferKind::Replay => self.replay,
+            BufferKind::Histogram => self.histogram,
+            BufferKind::RecentBlocks => self.recent_blocks,
+            BufferKind::NodeState => self.node_state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn usage_is_accounted_per_kind() {
+        let mut budget = MemoryBudget::new(None);
+        budget.add(BufferKind::NodeState, 100);
+        budget.add(BufferKind::NodeState, 50);
+        budget.set(BufferKind::Histogram, 20);
+        budget.sub(BufferKind::NodeState, 30);
+
+        assert_eq!(budget.usage(BufferKind::NodeState), 120);
+        assert_eq!(budget.usage(BufferKind::Histogram), 20);
+        assert_eq!(budget.usage(BufferKind::Replay), 0);
+        assert_eq!(budget.total(), 140);
+
+        // Usage never goes negative:
+        budget.sub(BufferKind::Replay, 10);
+        assert_eq!(budget.usage(BufferKind::Replay), 0);
+    }
+
+    #[test]
+    fn nothing_evicted_without_a_limit() {
+        let mut budget = MemoryBudget::new(None);
+        budget.set(BufferKind::Replay, usize::MAX / 2);
+        assert_eq!(budget.excess(), 0);
+        assert!(budget.kinds_to_evict().is_empty());
+    }
+
+    #[test]
+    fn nothing_evicted_within_budget() {
+        let mut budget = MemoryBudget::new(Some(100));
+        budget.set(BufferKind::Replay, 50);
+        budget.set(BufferKind::Histogram, 50);
+        assert_eq!(budget.excess(), 0);
+        assert!(budget.kinds_to_evict().is_empty());
+    }
+
+    #[test]
+    fn eviction_order_over_budget() {
+        let mut budget = MemoryBudget::new(Some(100));
+        budget.set(BufferKind::NodeState, 80);
+        budget.set(BufferKind::Histogram, 30);
+        budget.set(BufferKind::Replay, 10);
+        assert_eq!(budget.excess(), 20);
+
+        // Replay buffers go first, then histograms, and node state never:
+        assert_eq!(
+            budget.kinds_to_evict(),
+            vec![BufferKind::Replay, BufferKind::Histogram]
+        );
+
+        // Empty buffers have nothing to give up:
+        budget.set(BufferKind::Replay, 0);
+        assert_eq!(budget.kinds_to_evict(), vec![BufferKind::Histogram]);
+
+        // Live node state alone can put us over budget, but can't be evicted:
+        budget.set(BufferKind::Histogram, 0);
+        budget.set(BufferKind::NodeState, 200);
+        assert_eq!(budget.excess(), 100);
+        assert!(budget.kinds_to_evict().is_empty());
+    }
+
+    #[test]
+    fn nothing_evicted_if_it_would_not_help() {
+        let mut budget = MemoryBudget::new(Some(100));
+        budget.set(BufferKind::NodeState, 150);
+        budget.set(BufferKind::Histogram, 30);
+        budget.set(BufferKind::RecentBlocks, 10);
+
+        // Evicting everything we can would still leave us 50 bytes over:
+        assert_eq!(budget.excess(), 90);
+        assert!(budget.kinds_to_evict().is_empty());
+
+        // But once it would bring us under budget, we evict:
+        budget.set(BufferKind::NodeState, 90);
+        assert_eq!(
+            budget.kinds_to_evict(),
+            vec![BufferKind::Histogram, BufferKind::RecentBlocks]
+        );
+    }
+
+    #[test]
+    fn report_matches_usage() {
+        let mut budget = MemoryBudget::new(Some(1000));
+        budget.set(BufferKind::Replay, 1);
+        budget.set(BufferKind::Histogram, 2);
+        budget.set(BufferKind::RecentBlocks, 3);
+        budget.set(BufferKind::NodeState, 4);
+
+        let report = budget.report();
+        assert_eq!(report.limit, Some(1000));
+        assert_eq!(report.total, 10);
+        for kind in BufferKind::ALL.iter().copied() {
+            assert_eq!(report.get(kind), budget.usage(kind));
+        }
+    }
+}
@@ -14,10 +14,52 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+mod alerts;
+mod block_first_seen;
+mod block_production_stall;
+mod block_time_smoothing;
+mod canonical_block;
 mod chain;
+mod chain_stats;
+mod continent;
+mod distribution;
+mod finalized_hashes;
+mod location_clusters;
+mod memory_budget;
 mod node;
+mod node_count_history;
+mod node_info;
+mod production_rate;
+mod public_nodes;
+mod recent_blocks;
+mod validator_index;
+mod verbosity;
 
 mod state;
 
+pub use alerts::{ActiveAlert, AlertKind, AlertThresholds};
+#[cfg(test)]
+pub use alerts::{Alert, Severity};
+pub use block_production_stall::StallThreshold;
+pub use block_time_smoothing::{BlockTimeSmoothing, BlockTimeSource};
+#[cfg(test)]
+pub use chain::DEFAULT_FIRST_PARTY_CHAINS;
+pub use chain::{
+    default_first_party_chains, push_alert_changes, BlockHeightLimit, ChainOpts, ImportThrottle,
+    NodesAtBest, SYNCING_DISTANCE,
+};
+pub use chain_stats::{ChainStats, ChainStatsDiffer, ChainStatsValue};
+pub use distribution::Distribution;
+pub use location_clusters::{
+    cluster_locations, LocationCluster, MAX_CELL_DEGREES, MIN_CELL_DEGREES,
+};
+pub use memory_budget::{BufferKind, MemoryUsage};
 pub use node::Node;
+pub use node_count_history::{
+    NodeCountHistory, NodeCountSample, RetentionPolicy,
+    SAMPLE_INTERVAL_MS as NODE_COUNT_SAMPLE_INTERVAL_MS,
+};
+pub use node_info::NodeInfo;
+pub use public_nodes::ShortageChange;
+pub use recent_blocks::RecentBlock;
 pub use state::*;
@@ -14,17 +14,30 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use super::alerts::{AlertChange, AlertThresholds, NodeAlerts};
+use super::block_time_smoothing::{BlockTimeSmoother, BlockTimeSmoothing};
+use super::recent_blocks::{RecentBlock, RecentBlocks};
+use super::verbosity::Verbosity;
 use crate::find_location;
-use common::node_message::SystemInterval;
+use common::node_message::{SystemInterval, VERBOSITY_BASIC};
 use common::node_types::{
-    Block, BlockDetails, NodeDetails, NodeHardware, NodeIO, NodeLocation, NodeStats, Timestamp,
+    Block, BlockDetails, BlockHash, BlockNumber, NodeDetails, NodeHardware, NodeIO, NodeLocation,
+    NodeStats, Timestamp,
 };
-use common::time;
+use common::{time, MeanList, OverflowPolicy};
 
 /// Minimum time between block below broadcasting updates to the browser gets throttled, in ms.
 const THROTTLE_THRESHOLD: u64 = 100;
 /// Minimum time of intervals for block updates sent to the browser when throttled, in ms.
 const THROTTLE_INTERVAL: u64 = 1000;
+/// How often we take a sample of a node's database size for its history, in ms.
+const DATABASE_SIZE_SAMPLE_INTERVAL: u64 = 10 * 60 * 1000;
+/// How many GPU usage samples we need before deciding whether a node's GPU is under pressure.
+const MIN_GPU_USAGE_SAMPLES: usize = 3;
+/// How long a node can go without a new best block before it's considered stale, in ms.
+/// Nodes above the lowest verbosity are also considered stale if they go this long
+/// without sending a `system.interval` message.
+pub const STALE_TIMEOUT: u64 = 2 * 60 * 1000;
 
 pub struct Node {
     /// Static details
@@ -35,10 +48,21 @@ pub struct Node {
     io: NodeIO,
     /// Best block
     best: BlockDetails,
+    /// The block details that feeds were last sent about this node
+    sent_best: Option<BlockDetails>,
+    /// The timestamp that the node sent with its best block, if we're measuring block
+    /// times between those
+    best_reported_at: Option<Timestamp>,
+    /// Used to smooth the block times we report
+    block_time_smoother: BlockTimeSmoother,
     /// Finalized block
     finalized: Block,
+    /// When the node told us about its finalized block, by our clock
+    finalized_at: Option<Timestamp>,
     /// Timer for throttling block updates
     throttle: u64,
+    /// Timer for throttling updates about blocks well below the chain's best block
+    far_behind_throttle: u64,
     /// Hardware stats over time
     hardware: NodeHardware,
     /// Physical location details
@@ -47,6 +71,28 @@ pub struct Node {
     stale: bool,
     /// Unix timestamp for when node started up (falls back to connection time)
     startup_time: Option<Timestamp>,
+    /// Alerts currently raised against this node
+    alerts: NodeAlerts,
+    /// How long after a block was first seen on the chain this node announced it,
+    /// for the blocks it has announced
+    announcement_latencies: MeanList<f64>,
+    /// Where this node comes in the order that nodes joined its chain
+    join_order: u64,
+    /// The last few new best blocks that this node announced
+    recent_blocks: RecentBlocks,
+    /// The height at which this node last silently changed the hash of a block it had
+    /// already announced, if it hasn't announced a new best block since
+    hash_anomaly: Option<BlockNumber>,
+    /// The node's database size in bytes, sampled every so often, for charting
+    database_size_history: MeanList<f32>,
+    /// When we last took a sample of the node's database size
+    database_size_sampled_at: Option<Timestamp>,
+    /// The peer counts that the node has reported over time
+    peer_history: MeanList<u64>,
+    /// How verbose the node's telemetry is
+    verbosity: Verbosity,
+    /// When the node last sent us a `system.interval` message (or connected, if it hasn't)
+    last_interval_at: Timestamp,
 }
 
 impl Node {
@@ -55,21 +101,47 @@ impl Node {
             .startup_time
             .take()
             .and_then(|time| time.parse().ok());
+        let verbosity = Verbosity::new(details.verbosity);
 
         Node {
             details,
             stats: NodeStats::default(),
             io: NodeIO::default(),
             best: BlockDetails::default(),
+            sent_best: None,
+            best_reported_at: None,
+            block_time_smoother: BlockTimeSmoother::default(),
             finalized: Block::zero(),
+            finalized_at: None,
             throttle: 0,
+            far_behind_throttle: 0,
             hardware: NodeHardware::default(),
             location: None,
             stale: false,
             startup_time,
+            alerts: NodeAlerts::default(),
+            announcement_latencies: MeanList::default(),
+            join_order: 0,
+            recent_blocks: RecentBlocks::default(),
+            hash_anomaly: None,
+            database_size_history: MeanList::default(),
+            database_size_sampled_at: None,
+            peer_history: MeanList::default(),
+            verbosity,
+            last_interval_at: time::now(),
         }
     }
 
+    /// Where this node comes in the order that nodes joined its chain; nodes
+    /// that joined earlier have lower numbers.
+    pub fn join_order(&self) -> u64 {
+        self.join_order
+    }
+
+    pub fn set_join_order(&mut self, join_order: u64) {
+        self.join_order = join_order;
+    }
+
     pub fn details(&self) -> &NodeDetails {
         &self.details
     }
@@ -78,6 +150,11 @@ impl Node {
         &self.stats
     }
 
+    /// The peer counts that the node has reported over time.
+    pub fn peer_history(&self) -> &MeanList<u64> {
+        &self.peer_history
+    }
+
     pub fn io(&self) -> &NodeIO {
         &self.io
     }
@@ -86,14 +163,15 @@ impl Node {
         &self.best.block
     }
 
-    pub fn best_timestamp(&self) -> u64 {
-        self.best.block_timestamp
-    }
-
     pub fn finalized(&self) -> &Block {
         &self.finalized
     }
 
+    /// When the node told us about its finalized block, if it has.
+    pub fn finalized_at(&self) -> Option<Timestamp> {
+        self.finalized_at
+    }
+
     pub fn hardware(&self) -> &NodeHardware {
         &self.hardware
     }
@@ -113,6 +191,7 @@ impl Node {
     pub fn update_block(&mut self, block: Block) -> bool {
         if block.height > self.best.block.height {
             self.stale = false;
+            self.hash_anomaly = None;
             self.best.block = block;
 
             true
@@ -121,36 +200,165 @@ impl Node {
         }
     }
 
+    /// Check a block against those the node recently announced. If it has already
+    /// announced a different block at the same height, that's flagged as an anomaly
+    /// and the hash it announced before is returned; the block shouldn't be used.
+    pub fn check_block_hash(&mut self, block: &Block) -> Option<BlockHash> {
+        let previous = self.recent_blocks.hash_at(block.height)?;
+        if previous == block.hash {
+            return None;
+        }
+        self.hash_anomaly = Some(block.height);
+        Some(previous)
+    }
+
+    /// The height at which this node silently changed the hash of a block it had
+    /// already announced, if it hasn't announced a new best block since. Until then,
+    /// its best block isn't trusted.
+    pub fn hash_anomaly(&self) -> Option<BlockNumber> {
+        self.hash_anomaly
+    }
+
+    /// Record how long after the block was first seen on the chain this node announced
+    /// it. The average of these is sent along with the node's block details.
+    pub fn update_announcement_latency(&mut self, latency: u64) {
+        self.announcement_latencies.push(latency as f64);
+        self.best.announcement_latency = self.announcement_latencies.mean().map(|l| l as u64);
+    }
+
+    /// Update the details of the node's best block, returning them if feeds should be
+    /// told. If the block is well below the chain's best block, `far_behind_interval`
+    /// is the minimum time between telling feeds about such blocks.
+    ///
+    /// The block time is measured from the node's previous best block to this one, using
+    /// `reported_at` (the timestamp that the node sent with the block) if it's given for
+    /// both and has moved forwards, and otherwise `timestamp` (when the block arrived).
     pub fn update_details(
         &mut self,
         timestamp: u64,
+        reported_at: Option<Timestamp>,
         propagation_time: Option<u64>,
+        smoothing: BlockTimeSmoothing,
+        far_behind_interval: Option<u64>,
     ) -> Option<&BlockDetails> {
-        self.best.block_time = timestamp - self.best.block_timestamp;
+        // Feeds already know about this block, so there's no point telling them again:
+        if let Some(sent) = &self.sent_best {
+            if sent.same_block(&self.best) {
+                return None;
+            }
+        }
+
+        let raw_block_time = match (reported_at, self.best_reported_at) {
+            (Some(reported_at), Some(previous)) if reported_at > previous => reported_at - previous,
+            _ => timestamp - self.best.block_timestamp,
+        };
+        self.best_reported_at = reported_at;
+        self.best.raw_block_time = raw_block_time;
+        self.best.block_time = self.block_time_smoother.push(raw_block_time, smoothing);
         self.best.block_timestamp = timestamp;
         self.best.propagation_time = propagation_time;
 
-        if self.throttle < timestamp {
-            if self.best.block_time <= THROTTLE_THRESHOLD {
+        let far_behind_throttled =
+            far_behind_interval.is_some() && timestamp < self.far_behind_throttle;
+        if self.throttle < timestamp && !far_behind_throttled {
+            if raw_block_time <= THROTTLE_THRESHOLD {
                 self.throttle = timestamp + THROTTLE_INTERVAL;
             }
+            if let Some(interval) = far_behind_interval {
+                self.far_behind_throttle = timestamp + interval;
+            }
 
+            self.sent_best = Some(self.best);
             Some(&self.best)
         } else {
             None
         }
     }
 
+    /// Remember the current best block, along with when the node says that it
+    /// announced it, for operators trying to debug why the node looks behind.
+    pub fn record_recent_block(&mut self, reported_at: Option<Timestamp>) {
+        self.recent_blocks
+            .push(RecentBlock::new(&self.best, reported_at));
+    }
+
+    /// The last few new best blocks that this node announced, oldest first.
+    pub fn recent_blocks(&self) -> impl Iterator<Item = &RecentBlock> {
+        self.recent_blocks.iter()
+    }
+
     pub fn update_hardware(&mut self, interval: &SystemInterval) -> bool {
         let mut changed = false;
 
+        let now = time::now();
         if let Some(upload) = interval.bandwidth_upload {
             changed |= self.hardware.upload.push(upload);
+            self.hardware.upload_total.add_sample(upload, now);
         }
         if let Some(download) = interval.bandwidth_download {
             changed |= self.hardware.download.push(download);
+            self.hardware.download_total.add_sample(download, now);
         }
-        self.hardware.chart_stamps.push(time::now() as f64);
+        if interval.swap_used_bytes.is_some()
+            && self.hardware.swap_used_bytes != interval.swap_used_bytes
+        {
+            self.hardware.swap_used_bytes = interval.swap_used_bytes;
+            changed = true;
+        }
+        if interval.swap_total_bytes.is_some()
+            && self.hardware.swap_total_bytes != interval.swap_total_bytes
+        {
+            self.hardware.swap_total_bytes = interval.swap_total_bytes;
+            changed = true;
+        }
+        if let Some(steal) = interval.cpu_steal_pct {
+            changed |= self.hardware.cpu_steal_pct.push(steal);
+        }
+        for (reported, current) in [
+            (interval.load_avg_1m, &mut self.hardware.load_avg_1m),
+            (interval.load_avg_5m, &mut self.hardware.load_avg_5m),
+            (interval.load_avg_15m, &mut self.hardware.load_avg_15m),
+        ] {
+            if reported.is_some() && *current != reported {
+                *current = reported;
+                changed = true;
+            }
+        }
+        for (reported, current) in [
+            (interval.cpu_cores, &mut self.hardware.cpu_cores),
+            (interval.open_fd_count, &mut self.hardware.open_fd_count),
+            (interval.fd_limit, &mut self.hardware.fd_limit),
+        ] {
+            if reported.is_some() && *current != reported {
+                *current = reported;
+                changed = true;
+            }
+        }
+        if interval.ntp_offset_ms.is_some() && self.hardware.ntp_offset_ms != interval.ntp_offset_ms
+        {
+            self.hardware.ntp_offset_ms = interval.ntp_offset_ms;
+            changed = true;
+        }
+        if interval.gpu_name.is_some() && self.hardware.gpu_name != interval.gpu_name {
+            self.hardware.gpu_name = interval.gpu_name.clone();
+            changed = true;
+        }
+        if let Some(pct) = interval.gpu_usage_pct {
+            // Only recent usage matters for whether the GPU is under pressure:
+            changed |= self
+                .hardware
+                .gpu_usage_pct
+                .get_or_insert_with(|| MeanList::new(OverflowPolicy::SlidingWindow))
+                .push(pct);
+        }
+        if let Some(used) = interval.gpu_memory_used_bytes {
+            changed |= self
+                .hardware
+                .gpu_memory_used_bytes
+                .get_or_insert_with(MeanList::default)
+                .push(used);
+        }
+        self.hardware.chart_stamps.push(now as f64);
 
         changed
     }
@@ -159,6 +367,7 @@ impl Node {
         let mut changed = false;
 
         if let Some(peers) = interval.peers {
+            changed |= self.peer_history.push(peers);
             if peers != self.stats.peers {
                 self.stats.peers = peers;
                 changed = true;
@@ -170,6 +379,52 @@ impl Node {
                 changed = true;
             }
         }
+        if interval.wasm_heap_used_bytes.is_some()
+            && self.stats.wasm_heap_used_bytes != interval.wasm_heap_used_bytes
+        {
+            self.stats.wasm_heap_used_bytes = interval.wasm_heap_used_bytes;
+            changed = true;
+        }
+        if interval.wasm_heap_limit_bytes.is_some()
+            && self.stats.wasm_heap_limit_bytes != interval.wasm_heap_limit_bytes
+        {
+            self.stats.wasm_heap_limit_bytes = interval.wasm_heap_limit_bytes;
+            changed = true;
+        }
+        let rates = [
+            (
+                interval.block_announce_per_sec,
+                &mut self.stats.block_announce_per_sec,
+            ),
+            (
+                interval.transaction_per_sec,
+                &mut self.stats.transaction_per_sec,
+            ),
+            (
+                interval.light_request_per_sec,
+                &mut self.stats.light_request_per_sec,
+            ),
+        ];
+        for (reported, rate) in rates {
+            if let Some(reported) = reported {
+                if reported != *rate {
+                    *rate = reported;
+                    changed = true;
+                }
+            }
+        }
+        if interval.database_size_bytes.is_some()
+            && self.stats.database_size_bytes != interval.database_size_bytes
+        {
+            self.stats.database_size_bytes = interval.database_size_bytes;
+            changed = true;
+        }
+        if interval.avg_peer_reputation.is_some()
+            && self.stats.avg_peer_reputation != interval.avg_peer_reputation
+        {
+            self.stats.avg_peer_reputation = interval.avg_peer_reputation;
+            changed = true;
+        }
 
         if changed {
             Some(&self.stats)
@@ -178,11 +433,65 @@ impl Node {
         }
     }
 
+    /// Add the node's current database size to its history, if it reports one and we
+    /// haven't taken a sample recently. Returns `true` if a sample was taken.
+    pub fn sample_database_size(&mut self, now: Timestamp) -> bool {
+        let size = match self.stats.database_size_bytes {
+            Some(size) => size,
+            None => return false,
+        };
+        if let Some(sampled_at) = self.database_size_sampled_at {
+            if now < sampled_at + DATABASE_SIZE_SAMPLE_INTERVAL {
+                return false;
+            }
+        }
+        self.database_size_sampled_at = Some(now);
+        self.database_size_history.push(size as f32);
+        true
+    }
+
+    /// The node's database size in bytes, sampled every 10 minutes.
+    pub fn database_size_history(&self) -> &MeanList<f32> {
+        &self.database_size_history
+    }
+
     pub fn update_io(&mut self, interval: &SystemInterval) -> Option<&NodeIO> {
         let mut changed = false;
 
         if let Some(size) = interval.used_state_cache_size {
-            changed |= self.io.used_state_cache_size.push(size);
+            // This is often constant for long periods, so ignore repeated values. Feeds
+            // are only sent the means, so there's nothing new to tell them unless a new
+            // mean was calculated (or the means were squashed to make room):
+            let means = &mut self.io.used_state_cache_size;
+            let before = (means.slice().len(), means.slice().last().copied());
+            if means.push_if_changed(size) {
+                changed |= (means.slice().len(), means.slice().last().copied()) != before;
+            }
+        }
+
+        if let Some(depth) = interval.offchain_worker_queue_depth {
+            if self.io.offchain_worker_queue_depth != Some(depth) {
+                self.io.offchain_worker_queue_depth = Some(depth);
+                changed = true;
+            }
+        }
+
+        if let Some(queries) = interval.kademlia_queries_per_sec {
+            changed |= self.io.kademlia_queries_per_sec.push(queries);
+        }
+
+        if let Some(records) = interval.kademlia_records_stored {
+            if self.io.kademlia_records_stored != Some(records) {
+                self.io.kademlia_records_stored = Some(records);
+                changed = true;
+            }
+        }
+
+        if let Some(sizes) = &interval.pallet_trie_sizes {
+            if self.io.pallet_trie_sizes.as_ref() != Some(sizes) {
+                self.io.pallet_trie_sizes = Some(sizes.clone());
+                changed = true;
+            }
         }
 
         if changed {
@@ -192,23 +501,284 @@ impl Node {
         }
     }
 
-    pub fn update_finalized(&mut self, block: Block) -> Option<&Block> {
+    /// Check whether the node's offchain worker queue is backing up.
+    pub fn update_offchain_worker_alert(
+        &mut self,
+        interval: &SystemInterval,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let depth = interval.offchain_worker_queue_depth?;
+        self.alerts
+            .offchain_worker_queue_depth(depth, thresholds, now)
+    }
+
+    /// Check whether the node is handling many more Kademlia DHT queries than the rest of
+    /// the chain, given the median query rate of nodes on the chain.
+    pub fn update_kademlia_query_alert(
+        &mut self,
+        chain_median: Option<f32>,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let queries_per_sec = self.io.kademlia_queries_per_sec.mean()?;
+        self.alerts.kademlia_query_rate(
+            queries_per_sec as f64,
+            chain_median.map(|m| m as f64),
+            thresholds,
+            now,
+        )
+    }
+
+    /// Check whether the node's Kademlia DHT has emptied.
+    pub fn update_kademlia_records_alert(
+        &mut self,
+        interval: &SystemInterval,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let records = interval.kademlia_records_stored?;
+        self.alerts
+            .kademlia_records_stored(records, thresholds, now)
+    }
+
+    /// Check whether the node is a validator that keeps the state of too few blocks.
+    pub fn update_pruning_alert(
+        &mut self,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let is_validator = self.details.validator.is_some();
+        self.alerts
+            .pruning_mode(self.details.pruning_mode, is_validator, thresholds, now)
+    }
+
+    /// Check whether the node's runtime is close to running out of WASM heap. Nodes
+    /// that don't report both their heap usage and its limit are left alone.
+    pub fn update_wasm_heap_alert(
+        &mut self,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let used = self.stats.wasm_heap_used_bytes?;
+        let limit = self.stats.wasm_heap_limit_bytes?;
+        self.alerts.wasm_heap_usage(used, limit, thresholds, now)
+    }
+
+    /// Check whether the node is using too much of its swap. This is more
+    /// serious for validators, since swapping can cause them to miss blocks.
+    pub fn update_swap_alert(
+        &mut self,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let used = self.hardware.swap_used_bytes?;
+        let total = self.hardware.swap_total_bytes?;
+        let is_validator = self.details.validator.is_some();
+        self.alerts
+            .swap_usage(used, total, is_validator, thresholds, now)
+    }
+
+    /// Check whether the hypervisor is taking too much CPU time away from the node.
+    /// Nodes that don't report their CPU steal time are left alone.
+    pub fn update_cpu_steal_alert(
+        &mut self,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let steal_pct = self.hardware.cpu_steal_pct.mean()?;
+        self.alerts.cpu_steal(steal_pct as f64, thresholds, now)
+    }
+
+    /// Check whether the node's GPU has been busy throughout its recent samples. Nodes
+    /// that don't report their GPU usage are left alone.
+    pub fn update_gpu_alert(
+        &mut self,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let usage = self.hardware.gpu_usage_pct.as_ref()?;
+        if usage.slice().len() < MIN_GPU_USAGE_SAMPLES {
+            return None;
+        }
+        let lowest_pct = usage.percentile(0)?;
+        self.alerts.gpu_usage(lowest_pct as f64, thresholds, now)
+    }
+
+    /// Check whether the node's 1 minute load average is too high for the number of
+    /// CPU cores it has. Nodes that don't report both are left alone.
+    pub fn update_load_average_alert(
+        &mut self,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let load_avg_1m = self.hardware.load_avg_1m?;
+        let cpu_cores = self.hardware.cpu_cores?;
+        self.alerts
+            .load_average(load_avg_1m as f64, cpu_cores, thresholds, now)
+    }
+
+    /// Check whether the node has too many of the file descriptors it's allowed open.
+    /// Nodes that don't report both are left alone.
+    pub fn update_fd_alert(
+        &mut self,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let open = self.hardware.open_fd_count?;
+        let limit = self.hardware.fd_limit?;
+        self.alerts.fd_usage(open, limit, thresholds, now)
+    }
+
+    /// Check whether the node's clock has drifted too far from NTP time. Validators are
+    /// held to a stricter limit. Nodes that don't report their offset are left alone.
+    pub fn update_clock_alert(
+        &mut self,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let offset_ms = self.hardware.ntp_offset_ms?;
+        let is_validator = self.details.validator.is_some();
+        self.alerts
+            .clock_offset(offset_ms, is_validator, thresholds, now)
+    }
+
+    /// Check whether the node's peers think that it's misbehaving. Nodes that don't
+    /// report a reputation are left alone.
+    pub fn update_peer_reputation_alert(
+        &mut self,
+        interval: &SystemInterval,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        interval.avg_peer_reputation?;
+        let score = self.stats.avg_peer_reputation?;
+        self.alerts.peer_reputation(score, thresholds, now)
+    }
+
+    /// Check whether the node is being sent more light client requests than it should
+    /// have to handle. Nodes that don't report their P2P message rates are left alone.
+    pub fn update_light_request_alert(
+        &mut self,
+        interval: &SystemInterval,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        interval.light_request_per_sec?;
+        let requests_per_sec = self.stats.light_request_per_sec;
+        self.alerts
+            .light_requests(requests_per_sec as f64, thresholds, now)
+    }
+
+    /// Record how long the node took to import a block.
+    pub fn update_import_latency(&mut self, import_latency_ms: u32) {
+        self.hardware
+            .import_latency_ms
+            .push(import_latency_ms as f32);
+    }
+
+    /// Check whether the node is importing blocks much more slowly than the rest of the
+    /// chain, given the median import latency of nodes on the chain.
+    pub fn update_slow_import_alert(
+        &mut self,
+        chain_median_ms: Option<f32>,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        let p95_ms = self.hardware.import_latency_p95_ms()?;
+        self.alerts.block_import_latency(
+            p95_ms as f64,
+            chain_median_ms.map(|m| m as f64),
+            thresholds,
+            now,
+        )
+    }
+
+    /// Check whether the node's finalized block is lagging too far behind its best block.
+    /// Nodes that haven't told us about any finalized block yet are left alone.
+    pub fn update_finality_lag_alert(
+        &mut self,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        if self.finalized.height == 0 {
+            return None;
+        }
+        self.alerts.finality_lag(
+            self.best.block.height,
+            self.finalized.height,
+            thresholds,
+            now,
+        )
+    }
+
+    pub fn alerts(&self) -> &NodeAlerts {
+        &self.alerts
+    }
+
+    /// Check whether the node, if it's a validator, finalized a different block to
+    /// another validator at the same height.
+    pub fn update_finality_conflict_alert(
+        &mut self,
+        conflicting_height: Option<BlockNumber>,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        self.alerts
+            .finality_conflict(conflicting_height, thresholds, now)
+    }
+
+    /// Check whether other nodes on the chain claim the same validator address as this
+    /// one; `nodes` is how many nodes claim it, including this one.
+    pub fn update_duplicate_validator_alert(
+        &mut self,
+        nodes: usize,
+        thresholds: &AlertThresholds,
+        now: Timestamp,
+    ) -> Option<AlertChange> {
+        self.alerts.duplicate_validator(nodes, thresholds, now)
+    }
+
+    pub fn update_finalized(&mut self, block: Block, now: Timestamp) -> Option<&Block> {
         if block.height > self.finalized.height {
             self.finalized = block;
+            self.finalized_at = Some(now);
             Some(self.finalized())
         } else {
             None
         }
     }
 
-    pub fn update_stale(&mut self, threshold: u64) -> bool {
-        if self.best.block_timestamp < threshold {
+    /// Mark the node as stale if it's gone too long without a new best block, or, unless
+    /// it's at the lowest verbosity (where block announcements are all we rely on), without
+    /// a `system.interval` message. Returns whether the node is stale.
+    pub fn update_stale(&mut self, now: Timestamp) -> bool {
+        let threshold = now.saturating_sub(STALE_TIMEOUT);
+        let expects_intervals = self.verbosity.level(now) > VERBOSITY_BASIC;
+        if self.best.block_timestamp < threshold
+            || (expects_intervals && self.last_interval_at < threshold)
+        {
             self.stale = true;
         }
 
         self.stale
     }
 
+    /// Take note of the node sending a `system.interval` message.
+    pub fn observe_interval(&mut self, now: Timestamp) {
+        self.last_interval_at = self.last_interval_at.max(now);
+    }
+
+    /// Take note of the verbosity of a message that the node has sent, from which we
+    /// infer how verbose its telemetry is if it didn't tell us.
+    pub fn observe_verbosity(&mut self, verbosity: u8, now: Timestamp) {
+        self.verbosity.observe(verbosity, now);
+    }
+
+    pub fn verbosity(&self) -> &Verbosity {
+        &self.verbosity
+    }
+
     pub fn stale(&self) -> bool {
         self.stale
     }
@@ -221,3 +791,198 @@ impl Node {
         self.startup_time
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node() -> Node {
+        Node::new(NodeDetails {
+            chain: "Chain One".into(),
+            name: "A".into(),
+            implementation: "Bar".into(),
+            version: "0.1".into(),
+            validator: None,
+            network_id: None,
+            startup_time: None,
+            chain_type: None,
+            environment: None,
+            pruning_mode: None,
+            offchain_indexing: false,
+            pending_upgrade_block: None,
+            verbosity: None,
+            publicly_reachable: None,
+            message_schema: None,
+            extra_info: Default::default(),
+        })
+    }
+
+    #[test]
+    fn database_size_is_sampled_every_ten_minutes() {
+        let mut node = node();
+        let start = time::now();
+        // Nothing to sample until the node reports a size:
+        assert!(!node.sample_database_size(start));
+
+        node.stats.database_size_bytes = Some(1_000);
+        assert!(node.sample_database_size(start));
+        node.stats.database_size_bytes = Some(2_000);
+        assert!(!node.sample_database_size(start + DATABASE_SIZE_SAMPLE_INTERVAL - 1));
+        assert!(node.sample_database_size(start + DATABASE_SIZE_SAMPLE_INTERVAL));
+        assert_eq!(node.database_size_history().slice(), &[1_000.0, 2_000.0]);
+    }
+
+    #[test]
+    fn io_is_only_updated_when_a_new_mean_is_calculated() {
+        let mut node = node();
+        let cache_size = |size: f32| -> SystemInterval {
+            serde_json::from_value(serde_json::json!({ "used_state_cache_size": size })).unwrap()
+        };
+        for size in 0..20 {
+            assert!(node.update_io(&cache_size(size as f32)).is_some());
+        }
+
+        // The means are squashed to make room, which feeds need to hear about, but from
+        // then on each mean covers two samples, so a different sample on its own leaves
+        // the means as they were, and there's nothing new to tell feeds:
+        assert!(node.update_io(&cache_size(20.0)).is_some());
+        assert!(node.update_io(&cache_size(21.0)).is_some());
+        assert!(node.update_io(&cache_size(22.0)).is_none());
+        assert!(node.update_io(&cache_size(23.0)).is_some());
+        // Repeated samples are ignored altogether:
+        assert!(node.update_io(&cache_size(23.0)).is_none());
+        assert!(node.update_io(&cache_size(23.0)).is_none());
+    }
+
+    #[test]
+    fn basic_nodes_are_only_expected_to_announce_blocks() {
+        let mut node = Node::new(NodeDetails {
+            verbosity: Some(VERBOSITY_BASIC),
+            ..node().details
+        });
+        node.last_interval_at = 1_000;
+
+        // The node never sends another interval, but keeps announcing blocks, so it's fine:
+        let now = 1_000 + 2 * STALE_TIMEOUT;
+        node.best.block_timestamp = now;
+        assert!(!node.update_stale(now));
+
+        // Once it stops announcing blocks, it goes stale:
+        assert!(!node.update_stale(now + STALE_TIMEOUT));
+        assert!(node.update_stale(now + STALE_TIMEOUT + 1));
+    }
+
+    #[test]
+    fn verbose_nodes_are_also_expected_to_send_intervals() {
+        use common::node_message::VERBOSITY_CONSENSUS_INFO;
+
+        let mut node = Node::new(NodeDetails {
+            verbosity: Some(VERBOSITY_CONSENSUS_INFO),
+            ..node().details
+        });
+        node.last_interval_at = 1_000;
+
+        // Announcing blocks and sending intervals keeps the node fresh:
+        let now = 1_000 + 2 * STALE_TIMEOUT;
+        node.best.block_timestamp = now;
+        node.observe_interval(now);
+        assert!(!node.update_stale(now));
+
+        // ...but if it stops sending intervals, announcing blocks isn't enough:
+        let later = now + STALE_TIMEOUT + 1;
+        node.best.block_timestamp = later;
+        assert!(node.update_stale(later));
+    }
+
+    #[test]
+    fn inferred_verbosity_decides_whether_intervals_are_expected() {
+        use common::node_message::VERBOSITY_CONSENSUS_INFO;
+
+        let mut node = node();
+        node.last_interval_at = 1_000;
+        let now = 1_000 + STALE_TIMEOUT + 1;
+        node.best.block_timestamp = now;
+
+        // Until the node sends anything more verbose, it's assumed to be at the lowest
+        // verbosity, and so isn't expected to send intervals:
+        assert!(!node.update_stale(now));
+        node.observe_verbosity(VERBOSITY_CONSENSUS_INFO, now);
+        assert!(node.update_stale(now));
+    }
+
+    /// Import blocks 1..=count, 500ms apart (too slow for the usual throttling to
+    /// kick in), returning the heights feeds would have been told about.
+    fn import_blocks(node: &mut Node, count: u64, far_behind_interval: Option<u64>) -> Vec<u64> {
+        let start = time::now();
+        let mut sent = Vec::new();
+        for height in 1..=count {
+            node.update_block(Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            });
+            let details = node.update_details(
+                start + height * 500,
+                None,
+                None,
+                BlockTimeSmoothing::default(),
+                far_behind_interval,
+            );
+            if let Some(details) = details {
+                sent.push(details.block.height);
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn block_times_measured_between_reported_timestamps_if_given() {
+        let mut node = node();
+        let start = time::now();
+        // Blocks arrive 500ms apart, but the node says they're 6s apart, except where
+        // it leaves the timestamp out or sends one that doesn't move forwards:
+        let blocks = [
+            (500, Some(6_000)),
+            (500, Some(12_000)),
+            (500, Some(18_000)),
+            (500, None),
+            (500, Some(30_000)),
+            (500, Some(36_000)),
+            (500, Some(36_000)),
+            (500, Some(42_000)),
+        ];
+        let mut arrived_at = start;
+        let mut block_times = Vec::new();
+        for (height, (after, reported_at)) in (1..).zip(blocks) {
+            arrived_at += after;
+            node.update_block(Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            });
+            node.update_details(
+                arrived_at,
+                reported_at.map(|t| start + t),
+                None,
+                BlockTimeSmoothing::default(),
+                None,
+            );
+            block_times.push(node.best.block_time);
+        }
+        // The first block has no timestamp before it to measure from:
+        assert_eq!(
+            block_times[1..],
+            [6_000, 6_000, 500, 500, 6_000, 500, 6_000]
+        );
+    }
+
+    #[test]
+    fn far_behind_blocks_are_sent_at_most_once_per_interval() {
+        let sent = import_blocks(&mut node(), 8, Some(1000));
+        assert_eq!(sent, vec![1, 3, 5, 7]);
+    }
+
+    #[test]
+    fn blocks_near_the_best_are_not_throttled() {
+        let sent = import_blocks(&mut node(), 8, None);
+        assert_eq!(sent, (1..=8).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,256 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A short, in-memory history of how many nodes (and validators) a chain has had,
+//! so that feeds can draw a "node count over time" sparkline.
+
+use common::node_types::Timestamp;
+use serde::ser::{SerializeTuple, Serializer};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// How often we take a sample of the node count, in milliseconds.
+pub const SAMPLE_INTERVAL_MS: u64 = 15 * 1000;
+
+/// How many samples we keep per chain (an hour's worth).
+pub const MAX_SAMPLES: usize = 240;
+
+/// How much of a chain's node count history to keep. Samples older than `max_age_ms`,
+/// and the oldest samples beyond the most recent `max_samples`, are evicted. No more
+/// than [`MAX_SAMPLES`] are ever kept, whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_samples: usize,
+    pub max_age_ms: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_samples: MAX_SAMPLES,
+            max_age_ms: MAX_SAMPLES as u64 * SAMPLE_INTERVAL_MS,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// How often samples should be checked against this policy, in milliseconds.
+    pub fn eviction_interval_ms(&self) -> u64 {
+        (self.max_age_ms / 10).max(1)
+    }
+
+    /// Check that the policy would keep something, returning a reason if not.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.max_samples == 0 {
+            return Err("max_samples must be at least 1");
+        }
+        if self.max_age_ms == 0 {
+            return Err("max_age_ms must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// The node and validator count of a chain at some point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeCountSample {
+    pub timestamp: Timestamp,
+    pub node_count: usize,
+    pub validator_count: usize,
+}
+
+impl Serialize for NodeCountSample {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tup = serializer.serialize_tuple(3)?;
+        tup.serialize_element(&self.timestamp)?;
+        tup.serialize_element(&self.node_count)?;
+        tup.serialize_element(&self.validator_count)?;
+        tup.end()
+    }
+}
+
+/// A fixed size ring of the most recent node count samples for a chain.
+#[derive(Debug, Clone, Default)]
+pub struct NodeCountHistory {
+    samples: VecDeque<NodeCountSample>,
+}
+
+impl NodeCountHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a new sample and hand it back. The oldest sample is dropped if
+    /// we're already holding [`MAX_SAMPLES`]. Samples are expected to be taken
+    /// every [`SAMPLE_INTERVAL_MS`].
+    pub fn sample(
+        &mut self,
+        now: Timestamp,
+        node_count: usize,
+        validator_count: usize,
+    ) -> NodeCountSample {
+        let sample = NodeCountSample {
+            timestamp: now,
+            node_count,
+            validator_count,
+        };
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        sample
+    }
+
+    /// Evict the samples that the retention policy says we shouldn't keep any longer,
+    /// returning how many were evicted.
+    pub fn evict(&mut self, policy: &RetentionPolicy, now: Timestamp) -> usize {
+        let before = self.samples.len();
+        let oldest_allowed = now.saturating_sub(policy.max_age_ms);
+        while let Some(oldest) = self.samples.front() {
+            if oldest.timestamp >= oldest_allowed && self.samples.len() <= policy.max_samples {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        before - self.samples.len()
+    }
+
+    /// The samples we hold, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &NodeCountSample> {
+        self.samples.iter()
+    }
+
+    /// Forget every sample.
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Roughly how many bytes the samples take up.
+    pub fn memory_usage(&self) -> usize {
+        self.samples.len() * std::mem::size_of::<NodeCountSample>()
+    }
+}
+
+impl Serialize for NodeCountHistory {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn samples_are_kept_oldest_first() {
+        let mut history = NodeCountHistory::new();
+
+        history.sample(1000, 5, 1);
+        assert_eq!(
+            history.sample(1000 + SAMPLE_INTERVAL_MS, 7, 2),
+            NodeCountSample {
+                timestamp: 1000 + SAMPLE_INTERVAL_MS,
+                node_count: 7,
+                validator_count: 2
+            }
+        );
+
+        let counts: Vec<_> = history.iter().map(|s| s.node_count).collect();
+        assert_eq!(counts, vec![5, 7]);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = NodeCountHistory::new();
+        for i in 0..(MAX_SAMPLES + 10) {
+            history.sample(i as u64 * SAMPLE_INTERVAL_MS, i, 0);
+        }
+
+        assert_eq!(history.iter().count(), MAX_SAMPLES);
+        // The oldest samples were dropped:
+        assert_eq!(history.iter().next().unwrap().node_count, 10);
+        assert_eq!(history.iter().last().unwrap().node_count, MAX_SAMPLES + 9);
+    }
+
+    #[test]
+    fn old_samples_are_evicted() {
+        let mut history = NodeCountHistory::new();
+        for i in 0..10 {
+            history.sample(i * 1000, i as usize, 0);
+        }
+        let policy = RetentionPolicy {
+            max_samples: 100,
+            max_age_ms: 4000,
+        };
+
+        // Samples taken at 0..=5s are more than 4s old at 9.5s:
+        assert_eq!(history.evict(&policy, 9500), 6);
+        let counts: Vec<_> = history.iter().map(|s| s.node_count).collect();
+        assert_eq!(counts, vec![6, 7, 8, 9]);
+        // Nothing more to evict until time moves on:
+        assert_eq!(history.evict(&policy, 9500), 0);
+        assert_eq!(history.evict(&policy, 20_000), 4);
+        assert_eq!(history.iter().count(), 0);
+    }
+
+    #[test]
+    fn samples_beyond_max_samples_are_evicted() {
+        let mut history = NodeCountHistory::new();
+        for i in 0..10 {
+            history.sample(i * 1000, i as usize, 0);
+        }
+        let policy = RetentionPolicy {
+            max_samples: 3,
+            max_age_ms: 60_000,
+        };
+
+        assert_eq!(history.evict(&policy, 10_000), 7);
+        let counts: Vec<_> = history.iter().map(|s| s.node_count).collect();
+        assert_eq!(counts, vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn retention_policy_must_keep_something() {
+        assert!(RetentionPolicy::default().validate().is_ok());
+        assert_eq!(RetentionPolicy::default().eviction_interval_ms(), 360_000);
+        let policy = RetentionPolicy {
+            max_samples: 0,
+            max_age_ms: 1000,
+        };
+        assert!(policy.validate().is_err());
+        let policy = RetentionPolicy {
+            max_samples: 1,
+            max_age_ms: 0,
+        };
+        assert!(policy.validate().is_err());
+    }
+
+    #[test]
+    fn history_serializes_as_array_of_tuples() {
+        let mut history = NodeCountHistory::new();
+        history.sample(1, 2, 3);
+        history.sample(1 + SAMPLE_INTERVAL_MS, 4, 5);
+
+        let json = serde_json::to_string(&history).unwrap();
+        assert_eq!(json, format!("[[1,2,3],[{},4,5]]", 1 + SAMPLE_INTERVAL_MS));
+    }
+}
@@ -0,0 +1,170 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A snapshot of everything we know about a single node, for the REST API.
+//! Unlike the messages sent to feeds, which are packed into tuples to keep
+//! them small, every value here is named.
+
+use super::{AlertKind, Node};
+use common::node_types::{Block, BlockHash, BlockNumber, NodeDetails, Timestamp};
+use common::time;
+use serde::Serialize;
+
+/// The current state of a node.
+#[derive(Clone, Debug, Serialize)]
+pub struct NodeInfo {
+    /// The ID that feeds know this node by on its chain.
+    pub id: usize,
+    pub details: NodeDetails,
+    pub startup_time: Option<Timestamp>,
+    pub stats: NodeStatsInfo,
+    pub hardware: NodeHardwareInfo,
+    pub location: Option<NodeLocationInfo>,
+    pub best_block: BestBlockInfo,
+    pub finalized_block: Block,
+    /// When the node told us about its finalized block, by our clock.
+    pub finalized_at: Option<Timestamp>,
+    pub stale: bool,
+    /// How verbose the node's telemetry is: what it told us when it connected, or
+    /// otherwise what we've inferred from the messages it's sent recently.
+    pub verbosity: u8,
+    pub verbosity_inferred: bool,
+    /// Whether other nodes on the chain claim the same validator address as this one.
+    pub duplicate_validator: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NodeStatsInfo {
+    pub peers: u64,
+    pub txcount: u64,
+    pub wasm_heap_used_bytes: Option<u64>,
+    pub wasm_heap_limit_bytes: Option<u64>,
+    pub database_size_bytes: Option<u64>,
+    pub avg_peer_reputation: Option<i32>,
+    /// The database size in bytes, sampled every 10 minutes.
+    pub database_size_history: Vec<f32>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NodeHardwareInfo {
+    pub upload: Vec<f64>,
+    pub download: Vec<f64>,
+    pub chart_stamps: Vec<f64>,
+    /// How many bytes the node has uploaded since we first saw it, going by the rates it
+    /// reports.
+    pub upload_total_bytes: u64,
+    /// How many bytes the node has downloaded since we first saw it, likewise.
+    pub download_total_bytes: u64,
+    pub swap_used_bytes: Option<u64>,
+    pub swap_total_bytes: Option<u64>,
+    pub import_latency_mean_ms: Option<f32>,
+    pub import_latency_p95_ms: Option<f32>,
+    pub cpu_steal_pct: Vec<f32>,
+    pub load_avg_1m: Option<f32>,
+    pub load_avg_5m: Option<f32>,
+    pub load_avg_15m: Option<f32>,
+    pub cpu_cores: Option<u32>,
+    pub open_fd_count: Option<u32>,
+    pub fd_limit: Option<u32>,
+    pub ntp_offset_ms: Option<i32>,
+    pub gpu_name: Option<Box<str>>,
+    pub gpu_usage_pct: Option<Vec<f32>>,
+    pub gpu_memory_used_bytes: Option<Vec<u64>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NodeLocationInfo {
+    pub latitude: f32,
+    pub longitude: f32,
+    pub city: Box<str>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BestBlockInfo {
+    pub height: BlockNumber,
+    pub hash: BlockHash,
+    pub block_time: u64,
+    pub block_timestamp: u64,
+    pub propagation_time: Option<u64>,
+    pub announcement_latency: Option<u64>,
+}
+
+impl NodeInfo {
+    pub fn new(id: usize, node: &Node) -> NodeInfo {
+        let stats = node.stats();
+        let hardware = node.hardware();
+        let best = node.block_details();
+
+        NodeInfo {
+            id,
+            details: node.details().clone(),
+            startup_time: node.startup_time(),
+            stats: NodeStatsInfo {
+                peers: stats.peers,
+                txcount: stats.txcount,
+                wasm_heap_used_bytes: stats.wasm_heap_used_bytes,
+                wasm_heap_limit_bytes: stats.wasm_heap_limit_bytes,
+                database_size_bytes: stats.database_size_bytes,
+                avg_peer_reputation: stats.avg_peer_reputation,
+                database_size_history: node.database_size_history().slice().to_vec(),
+            },
+            hardware: NodeHardwareInfo {
+                upload: hardware.upload.slice().to_vec(),
+                download: hardware.download.slice().to_vec(),
+                chart_stamps: hardware.chart_stamps.slice().to_vec(),
+                upload_total_bytes: hardware.upload_total.bytes(),
+                download_total_bytes: hardware.download_total.bytes(),
+                swap_used_bytes: hardware.swap_used_bytes,
+                swap_total_bytes: hardware.swap_total_bytes,
+                import_latency_mean_ms: hardware.import_latency_ms.mean(),
+                import_latency_p95_ms: hardware.import_latency_p95_ms(),
+                cpu_steal_pct: hardware.cpu_steal_pct.slice().to_vec(),
+                load_avg_1m: hardware.load_avg_1m,
+                load_avg_5m: hardware.load_avg_5m,
+                load_avg_15m: hardware.load_avg_15m,
+                cpu_cores: hardware.cpu_cores,
+                open_fd_count: hardware.open_fd_count,
+                fd_limit: hardware.fd_limit,
+                ntp_offset_ms: hardware.ntp_offset_ms,
+                gpu_name: hardware.gpu_name.clone(),
+                gpu_usage_pct: hardware.gpu_usage_pct.as_ref().map(|l| l.slice().to_vec()),
+                gpu_memory_used_bytes: hardware
+                    .gpu_memory_used_bytes
+                    .as_ref()
+                    .map(|l| l.slice().to_vec()),
+            },
+            location: node.location().map(|location| NodeLocationInfo {
+                latitude: location.latitude,
+                longitude: location.longitude,
+                city: location.city.clone(),
+            }),
+            best_block: BestBlockInfo {
+                height: best.block.height,
+                hash: best.block.hash,
+                block_time: best.block_time,
+                block_timestamp: best.block_timestamp,
+                propagation_time: best.propagation_time,
+                announcement_latency: best.announcement_latency,
+            },
+            finalized_block: *node.finalized(),
+            finalized_at: node.finalized_at(),
+            stale: node.stale(),
+            verbosity: node.verbosity().level(time::now()),
+            verbosity_inferred: node.verbosity().is_inferred(),
+            duplicate_validator: node.alerts().is_raised(AlertKind::DuplicateValidator),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! An estimate of how quickly a chain is producing blocks, based on the intervals
+//! between its recent best blocks. For PoW chains this stands in for the network
+//! hashrate, which we have no way to measure directly.
+
+use common::{MeanList, OverflowPolicy};
+
+/// The recent intervals between a chain's best blocks.
+pub struct ProductionRate {
+    intervals_ms: MeanList<f64>,
+}
+
+impl Default for ProductionRate {
+    fn default() -> Self {
+        ProductionRate {
+            // Only the most recent intervals tell us how quickly blocks are produced now:
+            intervals_ms: MeanList::new(OverflowPolicy::SlidingWindow),
+        }
+    }
+}
+
+impl ProductionRate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take note of how many milliseconds passed between a best block and the one before it.
+    pub fn push_interval(&mut self, interval_ms: u64) {
+        self.intervals_ms.push(interval_ms as f64);
+    }
+
+    /// Forget every interval, eg because the best block has gone backwards.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// The estimated number of blocks produced each second, or `None` if we've
+    /// not seen enough blocks (or they all arrived at once) to say.
+    pub fn blocks_per_second(&self) -> Option<f64> {
+        let mean_ms = self.intervals_ms.mean()?;
+        if mean_ms <= 0.0 {
+            return None;
+        }
+        Some(1000.0 / mean_ms)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("expected an estimate");
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {} blocks per second, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn no_estimate_without_intervals() {
+        let mut rate = ProductionRate::new();
+        assert_eq!(rate.blocks_per_second(), None);
+
+        // Blocks arriving at the same time tell us nothing either:
+        rate.push_interval(0);
+        assert_eq!(rate.blocks_per_second(), None);
+    }
+
+    #[test]
+    fn estimate_is_the_inverse_of_the_mean_interval() {
+        let mut rate = ProductionRate::new();
+        for interval_ms in [5000, 7000, 6000, 4000, 8000] {
+            rate.push_interval(interval_ms);
+        }
+        // The mean interval is 6 seconds:
+        assert_close(rate.blocks_per_second(), 1.0 / 6.0);
+    }
+
+    #[test]
+    fn estimate_follows_the_most_recent_intervals() {
+        let mut rate = ProductionRate::new();
+        for _ in 0..20 {
+            rate.push_interval(12_000);
+        }
+        assert_close(rate.blocks_per_second(), 1.0 / 12.0);
+
+        // Block production speeds up; once the window is full of the new
+        // intervals, the old ones no longer count:
+        for _ in 0..20 {
+            rate.push_interval(2000);
+        }
+        assert_close(rate.blocks_per_second(), 0.5);
+    }
+
+    #[test]
+    fn reset_forgets_intervals() {
+        let mut rate = ProductionRate::new();
+        rate.push_interval(6000);
+        rate.reset();
+        assert_eq!(rate.blocks_per_second(), None);
+    }
+}
@@ -0,0 +1,179 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Nodes may tell us whether they're publicly reachable or behind a NAT. New nodes can
+//! only join a network through public nodes, so we keep count of them on each chain, and
+//! notice when a chain that had enough of them no longer does.
+
+/// How many of a chain's nodes are public and private, going by what they told us.
+#[derive(Debug, Clone, Default)]
+pub struct PublicNodes {
+    public: usize,
+    private: usize,
+    /// Has the chain had at least the minimum number of public nodes since it was last
+    /// short of them? Chains that have never had enough (eg because their nodes don't
+    /// tell us) aren't considered to be short of them.
+    had_minimum: bool,
+    in_shortage: bool,
+}
+
+/// A change in whether a chain is short of public nodes, along with how many it has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShortageChange {
+    Started { count: usize },
+    Ended { count: usize },
+}
+
+impl PublicNodes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take note of a node joining the chain.
+    pub fn add(&mut self, publicly_reachable: Option<bool>) {
+        match publicly_reachable {
+            Some(true) => self.public += 1,
+            Some(false) => self.private += 1,
+            None => {}
+        }
+    }
+
+    /// Take note of a node leaving the chain.
+    pub fn remove(&mut self, publicly_reachable: Option<bool>) {
+        match publicly_reachable {
+            Some(true) => self.public = self.public.saturating_sub(1),
+            Some(false) => self.private = self.private.saturating_sub(1),
+            None => {}
+        }
+    }
+
+    /// How many nodes have told us that they're publicly reachable.
+    pub fn public(&self) -> usize {
+        self.public
+    }
+
+    /// How many nodes have told us that they aren't publicly reachable.
+    pub fn private(&self) -> usize {
+        self.private
+    }
+
+    /// The fraction of the nodes that told us either way which are public, from 0 to 1.
+    pub fn public_ratio(&self) -> Option<f64> {
+        let total = self.public + self.private;
+        (total > 0).then(|| self.public as f64 / total as f64)
+    }
+
+    /// Check whether the chain has dropped below `min` public nodes, or has recovered
+    /// from doing so, since this was last called. A `min` of 0 disables the check.
+    pub fn check_shortage(&mut self, min: usize) -> Option<ShortageChange> {
+        let count = self.public;
+        if min == 0 || count >= min {
+            self.had_minimum = min > 0;
+            if self.in_shortage {
+                self.in_shortage = false;
+                return Some(ShortageChange::Ended { count });
+            }
+            return None;
+        }
+        if self.had_minimum && !self.in_shortage {
+            self.in_shortage = true;
+            self.had_minimum = false;
+            return Some(ShortageChange::Started { count });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn public_ratio_counts_nodes_that_told_us() {
+        let mut nodes = PublicNodes::new();
+        assert_eq!(nodes.public_ratio(), None);
+
+        nodes.add(Some(true));
+        nodes.add(Some(false));
+        nodes.add(Some(false));
+        nodes.add(Some(false));
+        nodes.add(None);
+        assert_eq!(nodes.public(), 1);
+        assert_eq!(nodes.private(), 3);
+        assert_eq!(nodes.public_ratio(), Some(0.25));
+
+        nodes.remove(Some(false));
+        nodes.remove(None);
+        assert_eq!(nodes.public_ratio(), Some(1.0 / 3.0));
+
+        nodes.remove(Some(true));
+        assert_eq!(nodes.public_ratio(), Some(0.0));
+    }
+
+    #[test]
+    fn shortage_detected_when_public_nodes_drop_below_minimum() {
+        let mut nodes = PublicNodes::new();
+
+        // Chains which have never had enough public nodes aren't short of them:
+        nodes.add(Some(true));
+        nodes.add(Some(true));
+        assert_eq!(nodes.check_shortage(3), None);
+
+        nodes.add(Some(true));
+        assert_eq!(nodes.check_shortage(3), None);
+
+        nodes.remove(Some(true));
+        assert_eq!(
+            nodes.check_shortage(3),
+            Some(ShortageChange::Started { count: 2 })
+        );
+        // We're only told about the shortage once:
+        nodes.remove(Some(true));
+        assert_eq!(nodes.check_shortage(3), None);
+
+        nodes.add(Some(true));
+        nodes.add(Some(true));
+        assert_eq!(
+            nodes.check_shortage(3),
+            Some(ShortageChange::Ended { count: 3 })
+        );
+        assert_eq!(nodes.check_shortage(3), None);
+
+        // Having recovered, the chain can become short of public nodes again:
+        nodes.remove(Some(true));
+        assert_eq!(
+            nodes.check_shortage(3),
+            Some(ShortageChange::Started { count: 2 })
+        );
+    }
+
+    #[test]
+    fn private_nodes_dont_count_towards_the_minimum() {
+        let mut nodes = PublicNodes::new();
+        for _ in 0..5 {
+            nodes.add(Some(false));
+        }
+        assert_eq!(nodes.check_shortage(3), None);
+
+        // And a minimum of 0 turns the check off:
+        for _ in 0..3 {
+            nodes.add(Some(true));
+        }
+        assert_eq!(nodes.check_shortage(0), None);
+        nodes.remove(Some(true));
+        assert_eq!(nodes.check_shortage(0), None);
+    }
+}
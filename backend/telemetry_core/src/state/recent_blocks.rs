@@ -0,0 +1,144 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The last few new best blocks that a node told us about, kept so that operators
+//! can see exactly what their node reported (and what we made of it) when trying
+//! to work out why it looks like it's behind.
+
+use common::node_types::{BlockDetails, BlockHash, BlockNumber, Timestamp};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// How many blocks we remember for each node.
+pub const MAX_RECENT_BLOCKS: usize = 16;
+
+/// A new best block that a node announced.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RecentBlock {
+    pub height: BlockNumber,
+    pub hash: BlockHash,
+    /// When the node says that it sent the announcement, by its own clock.
+    /// `None` if it didn't tell us, or we couldn't make sense of it.
+    pub reported_at: Option<Timestamp>,
+    /// When the announcement arrived, by our clock.
+    pub received_at: Timestamp,
+    /// The block time as we measured it.
+    pub block_time: u64,
+    /// How long after the first node on the chain announced a block at this height
+    /// this node did, as sent to feeds.
+    pub propagation_time: Option<u64>,
+}
+
+impl RecentBlock {
+    pub fn new(details: &BlockDetails, reported_at: Option<Timestamp>) -> Self {
+        RecentBlock {
+            height: details.block.height,
+            hash: details.block.hash,
+            reported_at,
+            received_at: details.block_timestamp,
+            block_time: details.raw_block_time,
+            propagation_time: details.propagation_time,
+        }
+    }
+}
+
+/// A ring buffer of the most recent blocks that a node announced, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct RecentBlocks {
+    blocks: VecDeque<RecentBlock>,
+}
+
+impl RecentBlocks {
+    /// Remember a block, forgetting the oldest if we're already remembering as many
+    /// blocks as we're allowed.
+    pub fn push(&mut self, block: RecentBlock) {
+        if self.blocks.len() >= MAX_RECENT_BLOCKS {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back(block);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RecentBlock> {
+        self.blocks.iter()
+    }
+
+    /// The hash of the most recent block we remember at the given height, if any.
+    pub fn hash_at(&self, height: BlockNumber) -> Option<BlockHash> {
+        self.blocks
+            .iter()
+            .rev()
+            .find(|block| block.height == height)
+            .map(|block| block.hash)
+    }
+
+    /// Roughly how many bytes these blocks could take up at most.
+    pub const fn max_memory_usage() -> usize {
+        MAX_RECENT_BLOCKS * std::mem::size_of::<RecentBlock>()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(height: BlockNumber) -> RecentBlock {
+        RecentBlock {
+            height,
+            hash: BlockHash::from_low_u64_be(height),
+            reported_at: Some(height * 1000 - 250),
+            received_at: height * 1000,
+            block_time: 1000,
+            propagation_time: Some(0),
+        }
+    }
+
+    #[test]
+    fn blocks_are_kept_oldest_first() {
+        let mut recent = RecentBlocks::default();
+        recent.push(block(1));
+        recent.push(block(2));
+
+        let heights: Vec<_> = recent.iter().map(|b| b.height).collect();
+        assert_eq!(heights, vec![1, 2]);
+    }
+
+    #[test]
+    fn oldest_blocks_are_forgotten() {
+        let mut recent = RecentBlocks::default();
+        for height in 1..=(MAX_RECENT_BLOCKS as u64 + 4) {
+            recent.push(block(height));
+        }
+
+        let heights: Vec<_> = recent.iter().map(|b| b.height).collect();
+        let expected: Vec<_> = (5..=(MAX_RECENT_BLOCKS as u64 + 4)).collect();
+        assert_eq!(heights, expected);
+    }
+
+    #[test]
+    fn hash_at_finds_the_latest_block_at_a_height() {
+        let mut recent = RecentBlocks::default();
+        recent.push(block(1));
+        recent.push(block(2));
+        recent.push(RecentBlock {
+            hash: BlockHash::from_low_u64_be(100),
+            ..block(2)
+        });
+
+        assert_eq!(recent.hash_at(1), Some(BlockHash::from_low_u64_be(1)));
+        assert_eq!(recent.hash_at(2), Some(BlockHash::from_low_u64_be(100)));
+        assert_eq!(recent.hash_at(3), None);
+    }
+}
@@ -14,16 +14,24 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use super::alerts::AlertChange;
+use super::distribution::Distribution;
+use super::memory_budget::MemoryUsage;
 use super::node::Node;
+use super::node_count_history::{NodeCountHistory, NodeCountSample, RetentionPolicy};
+use super::public_nodes::{PublicNodes, ShortageChange};
 use crate::feed_message::FeedMessageSerializer;
 use crate::find_location;
 use common::node_message::Payload;
-use common::node_types::{Block, BlockHash, NodeDetails, Timestamp};
+use common::node_types::{
+    Block, BlockAge, BlockHash, BlockNumber, ChainType, NodeDetails, Timestamp,
+};
 use common::{id_type, DenseMap};
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 use std::iter::IntoIterator;
 
-use super::chain::{self, Chain, ChainNodeId};
+use super::chain::{self, Chain, ChainNodeId, ChainOpts, NodesAtBest};
 
 id_type! {
     /// A globally unique Chain ID.
@@ -41,6 +49,13 @@ impl NodeId {
     }
 }
 
+#[cfg(test)]
+impl NodeId {
+    pub fn new_for_test(chain_id: usize, chain_node_id: usize) -> NodeId {
+        NodeId(chain_id.into(), chain_node_id.into())
+    }
+}
+
 /// Our state constains node and chain information
 pub struct State {
     chains: DenseMap<ChainId, Chain>,
@@ -51,6 +66,32 @@ pub struct State {
 
     /// Chain labels that we do not want to allow connecting.
     denylist: HashSet<String>,
+
+    /// Options handed to each new chain.
+    chain_opts: ChainOpts,
+
+    /// If given, we track no more than this many chains at once.
+    max_chains: Option<usize>,
+    /// How many third party chains have been evicted to make room for first party ones.
+    evicted_chains: u64,
+    /// How many nodes have been turned away because their chain was new, third party,
+    /// and there was no room for it.
+    rejected_nodes: u64,
+
+    /// How much node count history to keep for chains, by genesis hash, if not the
+    /// default. These outlive the chains themselves, so that a chain which loses all of
+    /// its nodes keeps its policy when it comes back.
+    retention_policies: HashMap<BlockHash, RetentionPolicy>,
+}
+
+/// How many chains we're tracking, out of how many we're allowed to, and what we've
+/// had to do to stay within that.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ChainCapacity {
+    pub chains: usize,
+    pub max_chains: Option<usize>,
+    pub evicted_chains: u64,
+    pub rejected_nodes: u64,
 }
 
 /// Adding a node to a chain leads to this node_idult
@@ -59,6 +100,9 @@ pub enum AddNodeResult<'a> {
     ChainOnDenyList,
     /// The chain is over quota (too many nodes connected), so can't add the node
     ChainOverQuota,
+    /// The chain is new and third party, and we're already tracking as many chains as
+    /// we're allowed to, so can't add the node
+    TooManyChains,
     /// The node was added to the chain
     NodeAddedToChain(NodeAddedToChain<'a>),
 }
@@ -86,6 +130,18 @@ pub struct NodeAddedToChain<'a> {
     pub chain_node_count: usize,
     /// Has the chain label been updated?
     pub has_chain_label_changed: bool,
+    /// The type of the chain, if known.
+    pub chain_type: Option<ChainType>,
+    /// Is the chain first party?
+    pub first_party: bool,
+    /// The block that a runtime upgrade is scheduled for, if the node told us about one
+    /// that we didn't already know about.
+    pub upgrade_scheduled: Option<BlockNumber>,
+    /// The height of the chain's best block.
+    pub best_block: BlockNumber,
+    /// Changes to the alerts raised against other nodes on the chain as a result of
+    /// adding this one.
+    pub alert_changes: Vec<(ChainNodeId, AlertChange)>,
 }
 
 /// if removing a node is successful, we get this information back.
@@ -98,16 +154,112 @@ pub struct RemovedNode {
     pub old_chain_label: Box<str>,
     /// The new label of the chain.
     pub new_chain_label: Box<str>,
+    /// The type of the chain, if known.
+    pub chain_type: Option<ChainType>,
+    /// Is the chain first party?
+    pub first_party: bool,
+    /// Changes to the alerts raised against the remaining nodes on the chain as a result
+    /// of removing this one.
+    pub alert_changes: Vec<(ChainNodeId, AlertChange)>,
 }
 
 impl State {
-    pub fn new<T: IntoIterator<Item = String>>(denylist: T) -> State {
+    pub fn new<T: IntoIterator<Item = String>>(denylist: T, chain_opts: ChainOpts) -> State {
         State {
             chains: DenseMap::new(),
             chains_by_genesis_hash: HashMap::new(),
             chains_by_label: HashMap::new(),
             denylist: denylist.into_iter().collect(),
+            chain_opts,
+            max_chains: None,
+            evicted_chains: 0,
+            rejected_nodes: 0,
+            retention_policies: HashMap::new(),
+        }
+    }
+
+    /// Track no more than this many chains at once, if given. Nodes on new third party
+    /// chains are turned away once we're at the limit, while first party chains are
+    /// always let in (see [`State::make_room_for_chain`]).
+    pub fn with_max_chains(mut self, max_chains: Option<usize>) -> State {
+        self.max_chains = max_chains;
+        self
+    }
+
+    /// Replace the names of the chains that nodes aren't allowed to connect from. This only
+    /// affects nodes added from now on; see [`State::denied_nodes`] for those already here.
+    pub fn set_denylist<T: IntoIterator<Item = String>>(&mut self, denylist: T) {
+        self.denylist = denylist.into_iter().collect();
+    }
+
+    /// The IDs of every node that claims to be on a chain which is on the denylist. These
+    /// will have been added before their chain was denied.
+    pub fn denied_nodes(&self) -> Vec<NodeId> {
+        let mut node_ids = Vec::new();
+        for (chain_id, chain) in self.chains.iter() {
+            for (idx, node) in chain.nodes_slice().iter().enumerate() {
+                if let Some(node) = node {
+                    if self.denylist.contains(&*node.details().chain) {
+                        node_ids.push(NodeId(chain_id, idx.into()));
+                    }
+                }
+            }
+        }
+        node_ids
+    }
+
+    /// How many chains we're tracking, and how close that is to the limit.
+    pub fn chain_capacity(&self) -> ChainCapacity {
+        ChainCapacity {
+            chains: self.chains.len(),
+            max_chains: self.max_chains,
+            evicted_chains: self.evicted_chains,
+            rejected_nodes: self.rejected_nodes,
+        }
+    }
+
+    /// Is there room for the chain with the given genesis hash? There always is if
+    /// we're already tracking it.
+    fn has_room_for_chain(&self, genesis_hash: &BlockHash) -> bool {
+        self.chains_by_genesis_hash.contains_key(genesis_hash)
+            || self.max_chains.is_none_or(|max| self.chains.len() < max)
+    }
+
+    /// If a node is about to join a first party chain that there's no room for, pick the
+    /// third party chain with the fewest nodes to evict, and hand back the IDs of its
+    /// nodes. These need removing before the node is added, else we'll go over the limit
+    /// (which we'd do anyway if every chain is first party).
+    pub fn make_room_for_chain(&mut self, genesis_hash: &BlockHash) -> Vec<NodeId> {
+        if self.has_room_for_chain(genesis_hash)
+            || !self.chain_opts.first_party_chains.contains(genesis_hash)
+        {
+            return Vec::new();
         }
+
+        let smallest = self
+            .chains
+            .iter()
+            .filter(|(_, chain)| !chain.is_first_party())
+            .min_by_key(|(_, chain)| chain.node_count());
+        let (chain_id, chain) = match smallest {
+            Some(smallest) => smallest,
+            None => return Vec::new(),
+        };
+
+        log::info!(
+            "Too many chains; evicting {:?} ({} nodes) to make room for {:?}",
+            chain.label(),
+            chain.node_count(),
+            genesis_hash
+        );
+        self.evicted_chains += 1;
+        chain
+            .nodes_slice()
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.is_some())
+            .map(|(idx, _)| NodeId(chain_id, idx.into()))
+            .collect()
     }
 
     pub fn iter_chains(&self) -> impl Iterator<Item = StateChain<'_>> {
@@ -127,6 +279,15 @@ impl State {
             .map(|chain| StateChain { chain })
     }
 
+    /// The ID of the node with the given (feed facing) ID on the chain with the given
+    /// genesis hash, if there is such a node.
+    pub fn get_node_id(&self, genesis_hash: &BlockHash, idx: usize) -> Option<NodeId> {
+        let chain_id = *self.chains_by_genesis_hash.get(genesis_hash)?;
+        let chain_node_id = idx.into();
+        self.chains.get(chain_id)?.get_node(chain_node_id)?;
+        Some(NodeId(chain_id, chain_node_id))
+    }
+
     pub fn get_chain_by_label(&self, label: &str) -> Option<StateChain<'_>> {
         self.chains_by_label
             .get(label)
@@ -142,6 +303,12 @@ impl State {
         if self.denylist.contains(&*node_details.chain) {
             return AddNodeResult::ChainOnDenyList;
         }
+        if !self.has_room_for_chain(&genesis_hash)
+            && !self.chain_opts.first_party_chains.contains(&genesis_hash)
+        {
+            self.rejected_nodes += 1;
+            return AddNodeResult::TooManyChains;
+        }
 
         // Get the chain ID, creating a new empty chain if one doesn't exist.
         // If we create a chain here, we are expecting that it will allow at
@@ -150,7 +317,9 @@ impl State {
         let chain_id = match self.chains_by_genesis_hash.get(&genesis_hash) {
             Some(id) => *id,
             None => {
-                let chain_id = self.chains.add(Chain::new(genesis_hash));
+                let chain_id = self
+                    .chains
+                    .add(Chain::new(genesis_hash, self.chain_opts.clone()));
                 self.chains_by_genesis_hash.insert(genesis_hash, chain_id);
                 chain_id
             }
@@ -166,7 +335,12 @@ impl State {
 
         match chain.add_node(node) {
             chain::AddNodeResult::Overquota => AddNodeResult::ChainOverQuota,
-            chain::AddNodeResult::Added { id, chain_renamed } => {
+            chain::AddNodeResult::Added {
+                id,
+                chain_renamed,
+                upgrade_scheduled,
+                alert_changes,
+            } => {
                 let chain = &*chain;
 
                 // Update the label we use to reference the chain if
@@ -183,6 +357,11 @@ impl State {
                     new_chain_label: chain.label(),
                     chain_node_count: chain.node_count(),
                     has_chain_label_changed: chain_renamed,
+                    chain_type: chain.chain_type(),
+                    first_party: chain.is_first_party(),
+                    upgrade_scheduled,
+                    best_block: chain.best_block().height,
+                    alert_changes,
                 })
             }
         }
@@ -199,6 +378,8 @@ impl State {
         // Get updated chain details.
         let new_chain_label: Box<str> = chain.label().into();
         let chain_node_count = chain.node_count();
+        let chain_type = chain.chain_type();
+        let first_party = chain.is_first_party();
 
         // Is the chain empty? Remove if so and clean up indexes to it
         if chain_node_count == 0 {
@@ -220,6 +401,9 @@ impl State {
             new_chain_label,
             chain_node_count: chain_node_count,
             has_chain_label_changed: remove_result.chain_renamed,
+            chain_type,
+            first_party,
+            alert_changes: remove_result.alert_changes,
         })
     }
 
@@ -229,6 +413,7 @@ impl State {
         &mut self,
         NodeId(chain_id, chain_node_id): NodeId,
         payload: Payload,
+        reported_at: Option<Timestamp>,
         feed: &mut FeedMessageSerializer,
     ) -> bool {
         let chain = match self.chains.get_mut(chain_id) {
@@ -239,7 +424,90 @@ impl State {
             }
         };
 
-        chain.update_node(chain_node_id, payload, feed)
+        chain.update_node(chain_node_id, payload, reported_at, feed)
+    }
+
+    /// Sample the node count of every chain, handing back the genesis
+    /// hash of each chain along with its new sample.
+    pub fn sample_node_counts(&mut self, now: Timestamp) -> Vec<(BlockHash, NodeCountSample)> {
+        self.chains
+            .iter_mut()
+            .map(|(_, chain)| (*chain.genesis_hash(), chain.sample_node_count(now)))
+            .collect()
+    }
+
+    /// Evict node count history from every chain that's due it, according to each
+    /// chain's retention policy. Hands back how many samples were evicted from each chain
+    /// that had any evicted.
+    pub fn enforce_retention_policies(&mut self, now: Timestamp) -> Vec<(BlockHash, usize)> {
+        let policies = &self.retention_policies;
+        self.chains
+            .iter_mut()
+            .filter_map(|(_, chain)| {
+                let genesis_hash = *chain.genesis_hash();
+                let policy = policies.get(&genesis_hash).copied().unwrap_or_default();
+                let evicted = chain.enforce_retention_policy(&policy, now);
+                (evicted > 0).then_some((genesis_hash, evicted))
+            })
+            .collect()
+    }
+
+    /// The retention policy of the chain with the given genesis hash, or `None` if
+    /// there is no such chain.
+    pub fn retention_policy(&self, genesis_hash: &BlockHash) -> Option<RetentionPolicy> {
+        self.chains_by_genesis_hash.get(genesis_hash)?;
+        Some(
+            self.retention_policies
+                .get(genesis_hash)
+                .copied()
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Change the retention policy of the chain with the given genesis hash, returning
+    /// `false` if there is no such chain. The policy sticks even if the chain is later
+    /// removed and comes back.
+    pub fn set_retention_policy(
+        &mut self,
+        genesis_hash: &BlockHash,
+        policy: RetentionPolicy,
+    ) -> bool {
+        let chain = match self.chains_by_genesis_hash.get(genesis_hash) {
+            Some(&chain_id) => self.chains.get_mut(chain_id),
+            None => None,
+        };
+        match chain {
+            Some(chain) => {
+                chain.retention_policy_changed();
+                self.retention_policies.insert(*genesis_hash, policy);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hand back the genesis hash and latest numbers of each chain whose count of
+    /// nodes at the best block has changed since this was last called.
+    pub fn take_nodes_at_best_changes(&mut self) -> Vec<(BlockHash, NodesAtBest)> {
+        self.chains
+            .iter_mut()
+            .filter_map(|(_, chain)| {
+                let nodes_at_best = chain.take_nodes_at_best_change()?;
+                Some((*chain.genesis_hash(), nodes_at_best))
+            })
+            .collect()
+    }
+
+    /// Hand back the label of each chain which has dropped below the minimum number of
+    /// public nodes, or recovered from doing so, since this was last called.
+    pub fn take_public_node_shortage_changes(&mut self) -> Vec<(Box<str>, ShortageChange)> {
+        self.chains
+            .iter_mut()
+            .filter_map(|(_, chain)| {
+                let change = chain.take_public_node_shortage_change()?;
+                Some((chain.label().into(), change))
+            })
+            .collect()
     }
 
     /// Update the location for a node. Return `false` if the node was not found.
@@ -274,26 +542,97 @@ impl<'a> StateChain<'a> {
     pub fn node_count(&self) -> usize {
         self.chain.node_count()
     }
+    pub fn chain_type(&self) -> Option<ChainType> {
+        self.chain.chain_type()
+    }
+    pub fn is_first_party(&self) -> bool {
+        self.chain.is_first_party()
+    }
     pub fn best_block(&self) -> &'a Block {
         self.chain.best_block()
     }
     pub fn timestamp(&self) -> Timestamp {
         self.chain.timestamp().unwrap_or(0)
     }
+    /// When the best block last changed, if we've seen one yet.
+    pub fn best_block_changed_at(&self) -> Option<Timestamp> {
+        self.chain.timestamp()
+    }
     pub fn average_block_time(&self) -> Option<u64> {
         self.chain.average_block_time()
     }
+    pub fn block_production_rate(&self) -> Option<f64> {
+        self.chain.block_production_rate()
+    }
+    pub fn block_production_stalled(&self, now: Timestamp) -> bool {
+        self.chain.block_production_stalled(now)
+    }
+    pub fn pending_upgrade(&self) -> Option<BlockNumber> {
+        self.chain.pending_upgrade()
+    }
+    /// The best block and its age at `now`, if we know when it was produced.
+    pub fn best_block_age(&self, now: Timestamp) -> Option<BlockAge> {
+        self.chain
+            .timestamp()
+            .map(|timestamp| self.chain.best_block().with_age(timestamp, now))
+    }
     pub fn finalized_block(&self) -> &'a Block {
         self.chain.finalized_block()
     }
     pub fn nodes_slice(&self) -> &[Option<Node>] {
         self.chain.nodes_slice()
     }
+    /// The node on this chain with the given index into [`Self::nodes_slice`], if any.
+    pub fn get_node(&self, idx: usize) -> Option<&'a Node> {
+        self.chain.nodes_slice().get(idx)?.as_ref()
+    }
+    /// The nodes on this chain (along with their index into [`Self::nodes_slice`]), in
+    /// the order that they joined it. Unlike the order of the slice, where new nodes fill
+    /// the gaps left by old ones, this doesn't depend on which nodes have come and gone.
+    pub fn nodes_in_join_order(&self) -> Vec<(usize, &'a Node)> {
+        let mut nodes: Vec<_> = self
+            .chain
+            .nodes_slice()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, n)| n.as_ref().map(|n| (idx, n)))
+            .collect();
+        nodes.sort_by_key(|(_, n)| n.join_order());
+        nodes
+    }
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.chain.memory_usage()
+    }
+    pub fn node_count_history(&self) -> &'a NodeCountHistory {
+        self.chain.node_count_history()
+    }
+    pub fn nodes_at_best(&self) -> NodesAtBest {
+        self.chain.nodes_at_best()
+    }
+    pub fn distribution(&self) -> &'a Distribution {
+        self.chain.distribution()
+    }
+    pub fn median_database_size(&self) -> Option<u64> {
+        self.chain.median_database_size()
+    }
+    pub fn public_nodes(&self) -> &PublicNodes {
+        self.chain.public_nodes()
+    }
+    pub fn top_pallet_trie_sizes(&self) -> Vec<(Box<str>, u64)> {
+        self.chain.top_pallet_trie_sizes()
+    }
+    pub fn continent_distribution(&self) -> Vec<(Box<str>, u32)> {
+        self.chain.continent_distribution()
+    }
+    pub fn retention_evictions(&self) -> u64 {
+        self.chain.retention_evictions()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::state::BlockHeightLimit;
 
     fn node(name: &str, chain: &str) -> NodeDetails {
         NodeDetails {
@@ -304,12 +643,1271 @@ mod test {
             validator: None,
             network_id: None,
             startup_time: None,
+            chain_type: None,
+            environment: None,
+            pruning_mode: None,
+            offchain_indexing: false,
+            pending_upgrade_block: None,
+            verbosity: None,
+            publicly_reachable: None,
+            message_schema: None,
+            extra_info: Default::default(),
+        }
+    }
+
+    fn import_block(state: &mut State, node_id: NodeId, height: u64) {
+        let block = Block {
+            hash: BlockHash::from_low_u64_be(height),
+            height,
+        };
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, Payload::BlockImport(block), None, &mut feed);
+    }
+
+    #[test]
+    fn chain_memory_usage_is_accounted() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+
+        // The first best block sets a timestamp; subsequent ones record block times:
+        for height in 1..=3 {
+            import_block(&mut state, node_id, height);
+        }
+
+        let usage = state
+            .get_chain_by_genesis_hash(&genesis)
+            .unwrap()
+            .memory_usage();
+        assert!(usage.node_state > 0);
+        assert_eq!(usage.histogram, 2 * std::mem::size_of::<u64>());
+        assert!(usage.recent_blocks > 0);
+        assert_eq!(usage.replay, 0);
+        assert_eq!(
+            usage.total,
+            usage.node_state + usage.histogram + usage.recent_blocks
+        );
+        assert_eq!(usage.limit, None);
+
+        // Nodes are accounted for as they come and go:
+        let other_node_id = state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+        let usage_with_two_nodes = state.get_chain_by_node_id(node_id).unwrap().memory_usage();
+        assert_eq!(usage_with_two_nodes.node_state, 2 * usage.node_state);
+
+        state.remove_node(other_node_id);
+        let usage_with_one_node = state.get_chain_by_node_id(node_id).unwrap().memory_usage();
+        assert_eq!(usage_with_one_node.node_state, usage.node_state);
+    }
+
+    #[test]
+    fn node_count_history_restarts_when_chain_reappears() {
+        use super::super::node_count_history::SAMPLE_INTERVAL_MS;
+
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let history = |state: &State| -> Vec<(u64, usize, usize)> {
+            state
+                .get_chain_by_genesis_hash(&genesis)
+                .unwrap()
+                .node_count_history()
+                .iter()
+                .map(|s| (s.timestamp, s.node_count, s.validator_count))
+                .collect()
+        };
+
+        let a = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let mut validator = node("B", "Chain One");
+        validator.validator = Some("Validator".into());
+        let b = state.add_node(genesis, validator).unwrap_id();
+
+        let sampled = state.sample_node_counts(1000);
+        assert_eq!(sampled.len(), 1);
+        assert_eq!(sampled[0].0, genesis);
+        state.remove_node(a);
+        state.sample_node_counts(1000 + SAMPLE_INTERVAL_MS);
+        assert_eq!(
+            history(&state),
+            vec![(1000, 2, 1), (1000 + SAMPLE_INTERVAL_MS, 1, 1)]
+        );
+
+        // Removing the last node removes the chain, and its history with it:
+        state.remove_node(b);
+        assert!(state.get_chain_by_genesis_hash(&genesis).is_none());
+        assert!(state
+            .sample_node_counts(1000 + 2 * SAMPLE_INTERVAL_MS)
+            .is_empty());
+
+        // When the chain comes back, sampling starts again from scratch:
+        state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        assert!(history(&state).is_empty());
+        state.sample_node_counts(1000 + 3 * SAMPLE_INTERVAL_MS);
+        assert_eq!(history(&state), vec![(1000 + 3 * SAMPLE_INTERVAL_MS, 1, 0)]);
+    }
+
+    fn finalize_block(state: &mut State, node_id: NodeId, height: u64) {
+        let finalized = common::node_message::Finalized {
+            hash: BlockHash::from_low_u64_be(height),
+            height: height.to_string().into(),
+        };
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_id,
+            Payload::NotifyFinalized(finalized),
+            None,
+            &mut feed,
+        );
+    }
+
+    fn active_alert_kinds(state: &State, node_id: NodeId) -> Vec<crate::state::AlertKind> {
+        let chain = state.get_chain_by_node_id(node_id).unwrap();
+        let idx: usize = node_id.get_chain_node_id().into();
+        let node = chain.nodes_slice()[idx].as_ref().unwrap();
+        node.alerts()
+            .active()
+            .iter()
+            .map(|a| a.alert.kind())
+            .collect()
+    }
+
+    #[test]
+    fn finality_lag_alert_follows_the_gap() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let lag = ChainOpts::default().alert_thresholds.finality_lag_blocks;
+
+        finalize_block(&mut state, node_id, 1);
+        import_block(&mut state, node_id, 1 + lag);
+        assert!(active_alert_kinds(&state, node_id).is_empty());
+
+        import_block(&mut state, node_id, 2 + lag);
+        assert_eq!(
+            active_alert_kinds(&state, node_id),
+            vec![crate::state::AlertKind::FinalityLagging]
+        );
+
+        finalize_block(&mut state, node_id, 2);
+        assert!(active_alert_kinds(&state, node_id).is_empty());
+    }
+
+    #[test]
+    fn conflicting_finality_between_validators_is_critical() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let validator = |name| NodeDetails {
+            validator: Some(format!("5{}", name).into()),
+            ..node(name, "Chain One")
+        };
+        let a = state.add_node(genesis, validator("A")).unwrap_id();
+        let b = state.add_node(genesis, validator("B")).unwrap_id();
+        let c = state.add_node(genesis, node("C", "Chain One")).unwrap_id();
+
+        finalize_block(&mut state, a, 5);
+        finalize_block(&mut state, b, 5);
+        assert!(active_alert_kinds(&state, b).is_empty());
+
+        // A different hash at the same height from a non-validator isn't tracked:
+        let conflicting = |state: &mut State, node_id| {
+            let finalized = common::node_message::Finalized {
+                hash: BlockHash::from_low_u64_be(1000),
+                height: "6".into(),
+            };
+            let mut feed = FeedMessageSerializer::new();
+            state.update_node(
+                node_id,
+                Payload::NotifyFinalized(finalized),
+                None,
+                &mut feed,
+            );
+        };
+        finalize_block(&mut state, a, 6);
+        conflicting(&mut state, c);
+        assert!(active_alert_kinds(&state, c).is_empty());
+
+        conflicting(&mut state, b);
+        assert_eq!(
+            active_alert_kinds(&state, b),
+            vec![crate::state::AlertKind::FinalityConflict]
+        );
+        assert!(active_alert_kinds(&state, a).is_empty());
+
+        finalize_block(&mut state, b, 7);
+        assert!(active_alert_kinds(&state, b).is_empty());
+    }
+
+    #[test]
+    fn nodes_claiming_the_same_validator_address_are_flagged() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let validator = |name, address: &str| NodeDetails {
+            validator: Some(address.into()),
+            ..node(name, "Chain One")
+        };
+        let is_flagged = |state: &State, node_id: NodeId| {
+            let chain = state.get_chain_by_node_id(node_id).unwrap();
+            let idx: usize = node_id.get_chain_node_id().into();
+            let node = chain.nodes_slice()[idx].as_ref().unwrap();
+            crate::state::NodeInfo::new(idx, node).duplicate_validator
+        };
+
+        let a = state
+            .add_node(genesis, validator("A", "5Alice"))
+            .unwrap_id();
+        assert!(!is_flagged(&state, a));
+
+        // The new node is flagged along with the node it clashes with, which feeds are
+        // told about separately:
+        let b = match state.add_node(genesis, validator("B", " 5Alice ")) {
+            AddNodeResult::NodeAddedToChain(added) => {
+                let changed: Vec<_> = added.alert_changes.iter().map(|(nid, _)| *nid).collect();
+                assert_eq!(changed, vec![a.get_chain_node_id()]);
+                added.id
+            }
+            _ => panic!("node should have been added"),
+        };
+        assert!(is_flagged(&state, a));
+        assert!(is_flagged(&state, b));
+
+        // The conflict resolves when one of them starts claiming another address...
+        let mut feed = FeedMessageSerializer::new();
+        let authority_set = |address: &str| {
+            Payload::AfgAuthoritySet(common::node_message::AfgAuthoritySet {
+                authority_id: address.into(),
+                authorities: "[]".into(),
+                authority_set_id: "1".into(),
+            })
+        };
+        state.update_node(b, authority_set("5Bob"), None, &mut feed);
+        assert!(!is_flagged(&state, a));
+        assert!(!is_flagged(&state, b));
+
+        // ...or when one of them goes away:
+        state.update_node(b, authority_set("5Alice"), None, &mut feed);
+        assert!(is_flagged(&state, a));
+        let removed = state.remove_node(b).unwrap();
+        assert_eq!(removed.alert_changes.len(), 1);
+        assert!(!is_flagged(&state, a));
+    }
+
+    #[test]
+    fn chains_that_lose_their_public_nodes_are_short_of_them() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let reachable = |name, publicly_reachable| NodeDetails {
+            publicly_reachable: Some(publicly_reachable),
+            ..node(name, "Chain One")
+        };
+        let public_ids: Vec<_> = ["A", "B", "C"]
+            .iter()
+            .map(|name| state.add_node(genesis, reachable(name, true)).unwrap_id())
+            .collect();
+        state.add_node(genesis, reachable("D", false));
+        assert!(state.take_public_node_shortage_changes().is_empty());
+
+        let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+        assert_eq!(chain.public_nodes().public_ratio(), Some(0.75));
+
+        state.remove_node(public_ids[0]);
+        assert_eq!(
+            state.take_public_node_shortage_changes(),
+            vec![("Chain One".into(), ShortageChange::Started { count: 2 })]
+        );
+        assert!(state.take_public_node_shortage_changes().is_empty());
+    }
+
+    fn announcement_latency(state: &State, node_id: NodeId) -> Option<u64> {
+        let chain = state.get_chain_by_node_id(node_id).unwrap();
+        let idx: usize = node_id.get_chain_node_id().into();
+        let node = chain.nodes_slice()[idx].as_ref().unwrap();
+        node.block_details().announcement_latency
+    }
+
+    #[test]
+    fn announcement_latency_only_tracked_for_announced_blocks() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let a = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let b = state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+
+        // Nodes which haven't announced any blocks have no latency:
+        assert_eq!(announcement_latency(&state, a), None);
+        assert_eq!(announcement_latency(&state, b), None);
+
+        import_block(&mut state, a, 1);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        import_block(&mut state, b, 1);
+
+        // A was first to announce the block; B announced it later:
+        assert!(announcement_latency(&state, a).unwrap() < 20);
+        assert!(announcement_latency(&state, b).unwrap() >= 20);
+
+        // A node that's catching up with old blocks nobody else has mentioned recently
+        // isn't given a latency for them:
+        import_block(&mut state, a, 5);
+        let c = state.add_node(genesis, node("C", "Chain One")).unwrap_id();
+        let old_block = Block {
+            hash: BlockHash::from_low_u64_be(1000),
+            height: 3,
+        };
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(c, Payload::BlockImport(old_block), None, &mut feed);
+        assert_eq!(announcement_latency(&state, c), None);
+    }
+
+    /// A system interval message which only reports the peer count.
+    fn system_interval() -> common::node_message::SystemInterval {
+        common::node_message::SystemInterval {
+            peers: Some(1),
+            txcount: None,
+            bandwidth_upload: None,
+            bandwidth_download: None,
+            finalized_height: None,
+            finalized_hash: None,
+            block: None,
+            used_state_cache_size: None,
+            offchain_worker_queue_depth: None,
+            swap_used_bytes: None,
+            swap_total_bytes: None,
+            wasm_heap_used_bytes: None,
+            wasm_heap_limit_bytes: None,
+            cpu_steal_pct: None,
+            kademlia_queries_per_sec: None,
+            kademlia_records_stored: None,
+            block_announce_per_sec: None,
+            transaction_per_sec: None,
+            light_request_per_sec: None,
+            load_avg_1m: None,
+            load_avg_5m: None,
+            load_avg_15m: None,
+            cpu_cores: None,
+            database_size_bytes: None,
+            open_fd_count: None,
+            fd_limit: None,
+            ntp_offset_ms: None,
+            avg_peer_reputation: None,
+            gpu_name: None,
+            gpu_usage_pct: None,
+            gpu_memory_used_bytes: None,
+            pallet_trie_sizes: None,
+        }
+    }
+
+    fn report_wasm_heap(state: &mut State, node_id: NodeId, used: Option<u64>, limit: Option<u64>) {
+        let interval = common::node_message::SystemInterval {
+            wasm_heap_used_bytes: used,
+            wasm_heap_limit_bytes: limit,
+            ..system_interval()
+        };
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+    }
+
+    fn report_cpu_steal(state: &mut State, node_id: NodeId, steal_pct: Option<f32>) {
+        let interval = common::node_message::SystemInterval {
+            cpu_steal_pct: steal_pct,
+            ..system_interval()
+        };
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+    }
+
+    fn report_gpu_usage(state: &mut State, node_id: NodeId, usage_pct: Option<f32>) {
+        let interval = common::node_message::SystemInterval {
+            gpu_usage_pct: usage_pct,
+            ..system_interval()
+        };
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+    }
+
+    fn report_kademlia(
+        state: &mut State,
+        node_id: NodeId,
+        queries_per_sec: Option<f32>,
+        records_stored: Option<u32>,
+    ) {
+        let interval = common::node_message::SystemInterval {
+            kademlia_queries_per_sec: queries_per_sec,
+            kademlia_records_stored: records_stored,
+            ..system_interval()
+        };
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+    }
+
+    #[test]
+    fn kademlia_ddos_alert_raised_against_chain_median() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let nodes: Vec<_> = ["A", "B", "C"]
+            .iter()
+            .map(|name| state.add_node(genesis, node(name, "Chain One")).unwrap_id())
+            .collect();
+        let flooded = state.add_node(genesis, node("D", "Chain One")).unwrap_id();
+
+        // Until enough nodes report a query rate, there's nothing to compare against:
+        report_kademlia(&mut state, flooded, Some(1000.0), None);
+        assert!(active_alert_kinds(&state, flooded).is_empty());
+
+        for &node_id in &nodes {
+            report_kademlia(&mut state, node_id, Some(10.0), None);
+        }
+        report_kademlia(&mut state, flooded, Some(1000.0), None);
+        assert_eq!(
+            active_alert_kinds(&state, flooded),
+            vec![crate::state::AlertKind::KademliaDDoS]
+        );
+        for &node_id in &nodes {
+            assert!(active_alert_kinds(&state, node_id).is_empty());
+        }
+    }
+
+    #[test]
+    fn kademlia_table_empty_alert_raised_when_records_drop_to_zero() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+
+        report_kademlia(&mut state, node_id, None, Some(0));
+        assert!(active_alert_kinds(&state, node_id).is_empty());
+
+        report_kademlia(&mut state, node_id, None, Some(250));
+        report_kademlia(&mut state, node_id, None, None);
+        assert!(active_alert_kinds(&state, node_id).is_empty());
+
+        report_kademlia(&mut state, node_id, None, Some(0));
+        assert_eq!(
+            active_alert_kinds(&state, node_id),
+            vec![crate::state::AlertKind::KademliaTableEmpty]
+        );
+    }
+
+    #[test]
+    fn retention_policy_evicts_node_count_history_periodically() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let history_len = |state: &State| {
+            state
+                .get_chain_by_genesis_hash(&genesis)
+                .unwrap()
+                .node_count_history()
+                .iter()
+                .count()
+        };
+
+        let policy = RetentionPolicy {
+            max_samples: 5,
+            max_age_ms: 10_000,
+        };
+        assert!(state.set_retention_policy(&genesis, policy));
+        assert!(!state.set_retention_policy(&BlockHash::from_low_u64_be(2), policy));
+
+        // A sample every second, from 1s to 8s:
+        for secs in 1..=8 {
+            state.sample_node_counts(secs * 1000);
+        }
+        // Only the 5 most recent samples are kept:
+        assert_eq!(state.enforce_retention_policies(8000), vec![(genesis, 3)]);
+        assert_eq!(history_len(&state), 5);
+
+        // Samples are only checked every max_age_ms / 10:
+        state.sample_node_counts(20_000);
+        assert!(state.enforce_retention_policies(8999).is_empty());
+        assert_eq!(history_len(&state), 6);
+
+        // By 20s, everything from before 10s is too old:
+        assert_eq!(state.enforce_retention_policies(20_000), vec![(genesis, 5)]);
+        assert_eq!(history_len(&state), 1);
+
+        assert_eq!(state.retention_policy(&genesis), Some(policy));
+        let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+        assert_eq!(chain.retention_evictions(), 8);
+    }
+
+    #[test]
+    fn retention_policy_survives_chain_being_removed() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        assert_eq!(
+            state.retention_policy(&genesis),
+            Some(RetentionPolicy::default())
+        );
+
+        let policy = RetentionPolicy {
+            max_samples: 5,
+            max_age_ms: 10_000,
+        };
+        assert!(state.set_retention_policy(&genesis, policy));
+
+        // The last node leaves, so the chain goes away:
+        state.remove_node(node_id);
+        assert!(state.get_chain_by_genesis_hash(&genesis).is_none());
+        assert_eq!(state.retention_policy(&genesis), None);
+
+        // When it comes back, the policy still applies:
+        state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        assert_eq!(state.retention_policy(&genesis), Some(policy));
+        for secs in 1..=8 {
+            state.sample_node_counts(secs * 1000);
+        }
+        assert_eq!(state.enforce_retention_policies(8000), vec![(genesis, 3)]);
+    }
+
+    #[test]
+    fn peer_count_history_is_sent_with_node_stats() {
+        use test_utils::feed_message_de::FeedMessage;
+
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+
+        let mut last_sent = None;
+        for peers in [3, 5, 5, 8, 2] {
+            let interval = common::node_message::SystemInterval {
+                peers: Some(peers),
+                ..system_interval()
+            };
+            let mut feed = FeedMessageSerializer::new();
+            state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+            let bytes = feed.into_finalized().unwrap();
+            for msg in FeedMessage::from_bytes(&bytes).unwrap() {
+                if let FeedMessage::NodeStatsUpdate {
+                    stats,
+                    peer_history,
+                    ..
+                } = msg
+                {
+                    assert_eq!(stats.peers, peers);
+                    last_sent = Some(peer_history);
+                }
+            }
+        }
+
+        // Repeated peer counts are samples too, so are kept:
+        assert_eq!(last_sent, Some(vec![3, 5, 5, 8, 2]));
+        let chain = state.get_chain_by_node_id(node_id).unwrap();
+        let idx: usize = node_id.get_chain_node_id().into();
+        let node = chain.nodes_slice()[idx].as_ref().unwrap();
+        assert_eq!(node.peer_history().slice(), &[3, 5, 5, 8, 2]);
+    }
+
+    #[test]
+    fn database_size_is_tracked_with_a_median_per_chain() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let nodes: Vec<_> = ["A", "B", "C", "D"]
+            .iter()
+            .map(|name| state.add_node(genesis, node(name, "Chain One")).unwrap_id())
+            .collect();
+        let report = |state: &mut State, node_id, database_size_bytes| {
+            let interval = common::node_message::SystemInterval {
+                database_size_bytes,
+                ..system_interval()
+            };
+            let mut feed = FeedMessageSerializer::new();
+            state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+        };
+        let median = |state: &State| {
+            state
+                .get_chain_by_genesis_hash(&genesis)
+                .unwrap()
+                .median_database_size()
+        };
+        assert_eq!(median(&state), None);
+
+        // Sizes beyond what an f32 can hold exactly are kept as they are:
+        let big = (1u64 << 40) + 1;
+        report(&mut state, nodes[0], Some(big));
+        report(&mut state, nodes[1], Some(300));
+        report(&mut state, nodes[2], Some(100));
+        // Nodes that stop reporting a size keep the last one they reported:
+        report(&mut state, nodes[0], None);
+        assert_eq!(median(&state), Some(300));
+
+        let chain = state.get_chain_by_node_id(nodes[0]).unwrap();
+        let idx: usize = nodes[0].get_chain_node_id().into();
+        let node = chain.nodes_slice()[idx].as_ref().unwrap();
+        assert_eq!(node.stats().database_size_bytes, Some(big));
+        // Only one sample is taken for the history in any 10 minutes:
+        assert_eq!(node.database_size_history().slice(), &[big as f32]);
+
+        let idx: usize = nodes[3].get_chain_node_id().into();
+        let node = chain.nodes_slice()[idx].as_ref().unwrap();
+        assert_eq!(node.stats().database_size_bytes, None);
+        assert!(node.database_size_history().slice().is_empty());
+    }
+
+    #[test]
+    fn top_pallets_by_state_trie_size_are_found_across_nodes() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let report = |state: &mut State, name: &str, sizes: &[(&str, u64)]| {
+            let node_id = state.add_node(genesis, node(name, "Chain One")).unwrap_id();
+            let interval = common::node_message::SystemInterval {
+                pallet_trie_sizes: Some(
+                    sizes
+                        .iter()
+                        .map(|&(pallet, size)| (pallet.into(), size))
+                        .collect(),
+                ),
+                ..system_interval()
+            };
+            let mut feed = FeedMessageSerializer::new();
+            state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+        };
+        report(
+            &mut state,
+            "A",
+            &[
+                ("Balances", 9000),
+                ("System", 5000),
+                ("Staking", 4000),
+                ("Session", 300),
+                ("Timestamp", 8),
+                ("Indices", 100),
+            ],
+        );
+        report(
+            &mut state,
+            "B",
+            &[("Balances", 10_000), ("System", 5200), ("Staking", 3000)],
+        );
+        // A node that's way off doesn't skew the median of each pallet:
+        report(
+            &mut state,
+            "C",
+            &[
+                ("Balances", 11_000),
+                ("System", 1),
+                ("Staking", 3500),
+                ("Session", 400),
+            ],
+        );
+        // Nor do nodes that don't report pallet sizes at all:
+        state.add_node(genesis, node("D", "Chain One"));
+
+        let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+        assert_eq!(
+            chain.top_pallet_trie_sizes(),
+            vec![
+                ("Balances".into(), 10_000),
+                ("System".into(), 5000),
+                ("Staking".into(), 3500),
+                ("Session".into(), 400),
+                ("Indices".into(), 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn continent_distribution_counts_nodes_with_a_known_country() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let countries = [Some("DE"), Some("us"), Some("FR"), None, Some("ZZ")];
+        for (idx, country) in countries.iter().enumerate() {
+            let node_id = state
+                .add_node(genesis, node(&idx.to_string(), "Chain One"))
+                .unwrap_id();
+            state.update_node_location(
+                node_id,
+                Some(std::sync::Arc::new(common::node_types::NodeLocation {
+                    latitude: 0.0,
+                    longitude: 0.0,
+                    city: "City".into(),
+                    country: country.map(Into::into),
+                })),
+            );
+        }
+        // A node that hasn't been located yet isn't counted either:
+        state.add_node(genesis, node("Unlocated", "Chain One"));
+
+        let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+        assert_eq!(
+            chain.continent_distribution(),
+            vec![
+                ("Europe".into(), 2),
+                ("North America".into(), 1),
+                ("Unknown".into(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn cpu_steal_alert_follows_mean_steal_time() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let bare_metal = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let vm = state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+
+        // Nodes that don't report steal time are never alerted about:
+        report_cpu_steal(&mut state, bare_metal, None);
+        assert!(active_alert_kinds(&state, bare_metal).is_empty());
+
+        // A single spike is averaged out by the steal time reported before it:
+        report_cpu_steal(&mut state, vm, Some(1.0));
+        report_cpu_steal(&mut state, vm, Some(1.0));
+        report_cpu_steal(&mut state, vm, Some(10.0));
+        assert!(active_alert_kinds(&state, vm).is_empty());
+
+        // But sustained steal time isn't, and is remembered between reports:
+        report_cpu_steal(&mut state, vm, Some(10.0));
+        report_cpu_steal(&mut state, vm, None);
+        assert_eq!(
+            active_alert_kinds(&state, vm),
+            vec![crate::state::AlertKind::CpuStealDetected]
+        );
+    }
+
+    #[test]
+    fn gpu_pressure_alert_needs_consistently_high_usage() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let no_gpu = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let gpu = state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+
+        report_gpu_usage(&mut state, no_gpu, None);
+        assert!(active_alert_kinds(&state, no_gpu).is_empty());
+
+        // A few busy samples aren't enough to go on...
+        report_gpu_usage(&mut state, gpu, Some(95.0));
+        report_gpu_usage(&mut state, gpu, Some(95.0));
+        assert!(active_alert_kinds(&state, gpu).is_empty());
+        // ... and one quiet sample amongst the recent ones means the GPU has some headroom:
+        report_gpu_usage(&mut state, gpu, Some(50.0));
+        for _ in 0..10 {
+            report_gpu_usage(&mut state, gpu, Some(95.0));
+        }
+        assert!(active_alert_kinds(&state, gpu).is_empty());
+
+        // Once the quiet sample is old enough to be forgotten, the alert is raised:
+        for _ in 0..10 {
+            report_gpu_usage(&mut state, gpu, Some(95.0));
+        }
+        assert_eq!(
+            active_alert_kinds(&state, gpu),
+            vec![crate::state::AlertKind::GpuPressure]
+        );
+        report_gpu_usage(&mut state, gpu, Some(10.0));
+        assert!(active_alert_kinds(&state, gpu).is_empty());
+    }
+
+    #[test]
+    fn light_client_overload_alert_follows_light_request_rate() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let report = |state: &mut State, light_request_per_sec| {
+            let interval = common::node_message::SystemInterval {
+                light_request_per_sec,
+                ..system_interval()
+            };
+            let mut feed = FeedMessageSerializer::new();
+            state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+        };
+
+        report(&mut state, Some(50.0));
+        assert!(active_alert_kinds(&state, node_id).is_empty());
+
+        // The default threshold is 100 requests per second:
+        report(&mut state, Some(250.0));
+        report(&mut state, None);
+        assert_eq!(
+            active_alert_kinds(&state, node_id),
+            vec![crate::state::AlertKind::LightClientOverload]
+        );
+
+        report(&mut state, Some(20.0));
+        assert!(active_alert_kinds(&state, node_id).is_empty());
+    }
+
+    #[test]
+    fn peer_reputation_alert_follows_reported_reputation() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let report = |state: &mut State, avg_peer_reputation| {
+            let interval = common::node_message::SystemInterval {
+                avg_peer_reputation,
+                ..system_interval()
+            };
+            let mut feed = FeedMessageSerializer::new();
+            state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+        };
+
+        // The default threshold is -1000, and only scores below it are a problem:
+        for score in [100, 0, -500, -1000].iter().copied() {
+            report(&mut state, Some(score));
+            assert!(active_alert_kinds(&state, node_id).is_empty(), "{}", score);
+        }
+
+        report(&mut state, Some(-1001));
+        report(&mut state, None);
+        assert_eq!(
+            active_alert_kinds(&state, node_id),
+            vec![crate::state::AlertKind::PeerReputationDegraded]
+        );
+        let chain = state.get_chain_by_node_id(node_id).unwrap();
+        let idx: usize = node_id.get_chain_node_id().into();
+        let node = chain.nodes_slice()[idx].as_ref().unwrap();
+        assert_eq!(node.stats().avg_peer_reputation, Some(-1001));
+
+        report(&mut state, Some(0));
+        assert!(active_alert_kinds(&state, node_id).is_empty());
+    }
+
+    #[test]
+    fn load_average_alert_needs_load_and_core_count() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let report = |state: &mut State, load_avg_1m, cpu_cores| {
+            let interval = common::node_message::SystemInterval {
+                load_avg_1m,
+                cpu_cores,
+                ..system_interval()
+            };
+            let mut feed = FeedMessageSerializer::new();
+            state.update_node(node_id, Payload::SystemInterval(interval), None, &mut feed);
+        };
+
+        // Without knowing how many cores the node has, we can't tell:
+        report(&mut state, Some(10.0), None);
+        assert!(active_alert_kinds(&state, node_id).is_empty());
+
+        // The core count is remembered between intervals:
+        report(&mut state, None, Some(2));
+        assert_eq!(
+            active_alert_kinds(&state, node_id),
+            vec![crate::state::AlertKind::HighLoadAverage]
+        );
+
+        report(&mut state, Some(3.0), None);
+        assert!(active_alert_kinds(&state, node_id).is_empty());
+    }
+
+    #[test]
+    fn wasm_heap_alert_not_raised_for_nodes_without_wasm_metrics() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let old = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let partial = state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+        let new = state.add_node(genesis, node("C", "Chain One")).unwrap_id();
+
+        // Old nodes don't send any WASM metrics, and some may only send usage:
+        report_wasm_heap(&mut state, old, None, None);
+        report_wasm_heap(&mut state, partial, Some(1000), None);
+        report_wasm_heap(&mut state, new, Some(900), Some(1000));
+        assert!(active_alert_kinds(&state, old).is_empty());
+        assert!(active_alert_kinds(&state, partial).is_empty());
+        assert_eq!(
+            active_alert_kinds(&state, new),
+            vec![crate::state::AlertKind::WasmHeapPressure]
+        );
+
+        // An interval without WASM metrics doesn't forget what the node last told us:
+        report_wasm_heap(&mut state, new, None, None);
+        assert_eq!(
+            active_alert_kinds(&state, new),
+            vec![crate::state::AlertKind::WasmHeapPressure]
+        );
+        report_wasm_heap(&mut state, new, Some(100), None);
+        assert!(active_alert_kinds(&state, new).is_empty());
+    }
+
+    #[test]
+    fn pruning_alert_raised_when_shallow_validator_added() {
+        use common::node_types::PruningMode;
+
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let shallow = Some(PruningMode::Constrained { keep_blocks: 16 });
+
+        let validator = state
+            .add_node(
+                genesis,
+                NodeDetails {
+                    validator: Some("5Foo".into()),
+                    pruning_mode: shallow,
+                    ..node("A", "Chain One")
+                },
+            )
+            .unwrap_id();
+        let full_node = state
+            .add_node(
+                genesis,
+                NodeDetails {
+                    pruning_mode: shallow,
+                    ..node("B", "Chain One")
+                },
+            )
+            .unwrap_id();
+        let archive = state
+            .add_node(
+                genesis,
+                NodeDetails {
+                    validator: Some("5Bar".into()),
+                    pruning_mode: Some(PruningMode::Archive),
+                    ..node("C", "Chain One")
+                },
+            )
+            .unwrap_id();
+
+        assert_eq!(
+            active_alert_kinds(&state, validator),
+            vec![crate::state::AlertKind::ShallowValidatorPruning]
+        );
+        assert!(active_alert_kinds(&state, full_node).is_empty());
+        assert!(active_alert_kinds(&state, archive).is_empty());
+    }
+
+    fn report_import_latency(state: &mut State, node_id: NodeId, import_latency_ms: u32) {
+        let prepared = common::node_message::PreparedBlock {
+            import_latency_ms: Some(import_latency_ms),
+        };
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_id,
+            Payload::PreparedBlockForProposing(prepared),
+            None,
+            &mut feed,
+        );
+    }
+
+    #[test]
+    fn slow_block_import_alert_raised_against_chain_median() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let fast: Vec<_> = ["A", "B", "C"]
+            .iter()
+            .map(|name| state.add_node(genesis, node(name, "Chain One")).unwrap_id())
+            .collect();
+        let slow = state.add_node(genesis, node("D", "Chain One")).unwrap_id();
+
+        // Until enough nodes have reported, there's no median to compare against:
+        report_import_latency(&mut state, slow, 1000);
+        report_import_latency(&mut state, fast[0], 100);
+        assert!(active_alert_kinds(&state, slow).is_empty());
+
+        for &node_id in &fast[1..] {
+            report_import_latency(&mut state, node_id, 100);
+        }
+        report_import_latency(&mut state, slow, 1000);
+        assert_eq!(
+            active_alert_kinds(&state, slow),
+            vec![crate::state::AlertKind::SlowBlockImport]
+        );
+        for &node_id in &fast {
+            assert!(active_alert_kinds(&state, node_id).is_empty());
+        }
+    }
+
+    fn nodes_at_best(state: &State, genesis: BlockHash) -> (u64, usize, usize) {
+        let n = state
+            .get_chain_by_genesis_hash(&genesis)
+            .unwrap()
+            .nodes_at_best();
+        (n.height, n.caught_up, n.total)
+    }
+
+    #[test]
+    fn nodes_at_best_follows_block_announcements() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let a = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let b = state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+        let c = state.add_node(genesis, node("C", "Chain One")).unwrap_id();
+        let syncing = state.add_node(genesis, node("D", "Chain One")).unwrap_id();
+
+        for height in 1..=20 {
+            import_block(&mut state, a, height);
+        }
+        import_block(&mut state, b, 20);
+        import_block(&mut state, c, 19);
+        import_block(&mut state, syncing, 2);
+
+        // The syncing node is too far behind to count towards the total:
+        assert_eq!(nodes_at_best(&state, genesis), (20, 2, 3));
+        assert_eq!(state.take_nodes_at_best_changes().len(), 1);
+        assert!(state.take_nodes_at_best_changes().is_empty());
+
+        // A new best block resets the count:
+        import_block(&mut state, c, 21);
+        assert_eq!(nodes_at_best(&state, genesis), (21, 1, 3));
+
+        // Removing a caught up node takes it off the count:
+        state.remove_node(c);
+        assert_eq!(nodes_at_best(&state, genesis), (21, 0, 2));
+        assert_eq!(state.take_nodes_at_best_changes().len(), 1);
+    }
+
+    fn import_fork_block(state: &mut State, node_id: NodeId, height: u64, fork: u64) -> Block {
+        let block = Block {
+            hash: BlockHash::from_low_u64_be(height + fork * 1000),
+            height,
+        };
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, Payload::BlockImport(block), None, &mut feed);
+        block
+    }
+
+    #[test]
+    fn best_block_follows_the_fork_most_nodes_are_on() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let a = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let b = state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+        let c = state.add_node(genesis, node("C", "Chain One")).unwrap_id();
+        let best_block = |state: &State| {
+            *state
+                .get_chain_by_genesis_hash(&genesis)
+                .unwrap()
+                .best_block()
+        };
+
+        // The first block at a new height is the best, until more nodes report another:
+        let first = import_fork_block(&mut state, a, 10, 1);
+        assert_eq!(best_block(&state), first);
+        let second = import_fork_block(&mut state, b, 10, 2);
+        assert_eq!(best_block(&state), first);
+        import_fork_block(&mut state, c, 10, 2);
+        assert_eq!(best_block(&state), second);
+        assert_eq!(nodes_at_best(&state, genesis), (10, 2, 3));
+
+        // A higher block is always best, however few nodes report it:
+        let higher = import_fork_block(&mut state, a, 11, 1);
+        assert_eq!(best_block(&state), higher);
+        assert_eq!(nodes_at_best(&state, genesis), (11, 1, 3));
+    }
+
+    #[test]
+    fn same_height_hash_change_is_an_anomaly_and_ignored() {
+        let mut state = State::new(None, ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        let a = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let b = state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+        let hash_anomaly = |state: &State, NodeId(_, nid): NodeId| {
+            let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+            chain.get_node(nid.into()).unwrap().hash_anomaly()
+        };
+        let best_blocks = |state: &State| {
+            let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+            let a_best = *chain.get_node(a.1.into()).unwrap().best();
+            (*chain.best_block(), a_best)
+        };
+
+        let first = import_fork_block(&mut state, a, 10, 1);
+        let other = import_fork_block(&mut state, b, 10, 2);
+        assert_eq!(best_blocks(&state), (first, first));
+        assert_eq!(hash_anomaly(&state, a), None);
+
+        // Node A now claims a different block at the same height:
+        import_fork_block(&mut state, a, 10, 3);
+        assert_eq!(hash_anomaly(&state, a), Some(10));
+        assert_eq!(hash_anomaly(&state, b), None);
+        // ..so its block no longer counts towards the chain's best block:
+        assert_eq!(best_blocks(&state), (other, first));
+
+        // Repeating a block it already announced is fine:
+        import_fork_block(&mut state, b, 10, 2);
+        assert_eq!(hash_anomaly(&state, b), None);
+
+        // A new best block clears the anomaly:
+        let higher = import_fork_block(&mut state, a, 11, 1);
+        assert_eq!(hash_anomaly(&state, a), None);
+        assert_eq!(best_blocks(&state), (higher, higher));
+    }
+
+    #[test]
+    fn chain_over_memory_budget_evicts_history_but_not_nodes() {
+        let genesis = BlockHash::from_low_u64_be(1);
+        let state_with_budget = |memory_budget| {
+            let mut state = State::new(
+                None,
+                ChainOpts {
+                    memory_budget,
+                    ..ChainOpts::default()
+                },
+            );
+            let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+            state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+            (state, node_id)
+        };
+
+        // Leave room for the nodes, but not for any history about blocks:
+        let node_state = state_with_budget(None)
+            .0
+            .get_chain_by_genesis_hash(&genesis)
+            .unwrap()
+            .memory_usage()
+            .node_state;
+        let (mut state, node_id) = state_with_budget(Some(node_state + 1));
+
+        for height in 1..=3 {
+            import_block(&mut state, node_id, height);
+        }
+
+        let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+        let usage = chain.memory_usage();
+        assert_eq!(chain.node_count(), 2);
+        assert_eq!(usage.histogram, 0);
+        assert_eq!(usage.recent_blocks, 0);
+        assert_eq!(usage.node_state, node_state);
+        assert_eq!(usage.limit, Some(node_state + 1));
+    }
+
+    #[test]
+    fn history_survives_when_node_state_alone_is_over_budget() {
+        let mut state = State::new(
+            None,
+            ChainOpts {
+                memory_budget: Some(1),
+                ..ChainOpts::default()
+            },
+        );
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+
+        for height in 1..=3 {
+            import_block(&mut state, node_id, height);
+        }
+
+        // Evicting the block times wouldn't get us under budget, so they're kept:
+        let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+        let usage = chain.memory_usage();
+        assert!(usage.node_state > 1);
+        assert_eq!(usage.histogram, 2 * std::mem::size_of::<u64>());
+        assert!(usage.recent_blocks > 0);
+        assert!(chain.average_block_time().is_some());
+    }
+
+    #[test]
+    fn first_party_chains_are_configured_by_genesis_hash() {
+        let first_party = BlockHash::from_low_u64_be(1);
+        let third_party = BlockHash::from_low_u64_be(2);
+        let mut state = State::new(
+            None,
+            ChainOpts {
+                first_party_chains: std::sync::Arc::new(std::iter::once(first_party).collect()),
+                ..ChainOpts::default()
+            },
+        );
+
+        // Being called "Polkadot" doesn't make a chain first party:
+        for _ in 0..600 {
+            state.add_node(first_party, node("A", "My Chain"));
+            state.add_node(third_party, node("A", "Polkadot"));
         }
+        let first = state.get_chain_by_genesis_hash(&first_party).unwrap();
+        let third = state.get_chain_by_genesis_hash(&third_party).unwrap();
+        assert!(first.is_first_party());
+        assert_eq!(first.node_count(), 600);
+        assert!(!third.is_first_party());
+        assert_eq!(third.node_count(), 500);
+        assert!(matches!(
+            state.add_node(third_party, node("A", "Polkadot")),
+            AddNodeResult::ChainOverQuota
+        ));
+    }
+
+    #[test]
+    fn third_party_chains_are_rejected_once_at_the_chain_limit() {
+        let mut state = State::new(None, ChainOpts::default()).with_max_chains(Some(2));
+        state
+            .add_node(BlockHash::from_low_u64_be(1), node("A", "One"))
+            .unwrap_id();
+        state
+            .add_node(BlockHash::from_low_u64_be(2), node("A", "Two"))
+            .unwrap_id();
+
+        // Chains we already track can still grow:
+        state
+            .add_node(BlockHash::from_low_u64_be(2), node("B", "Two"))
+            .unwrap_id();
+        assert!(matches!(
+            state.add_node(BlockHash::from_low_u64_be(3), node("A", "Three")),
+            AddNodeResult::TooManyChains
+        ));
+        // Third party chains don't get room made for them:
+        assert!(state
+            .make_room_for_chain(&BlockHash::from_low_u64_be(3))
+            .is_empty());
+
+        assert_eq!(
+            state.chain_capacity(),
+            ChainCapacity {
+                chains: 2,
+                max_chains: Some(2),
+                evicted_chains: 0,
+                rejected_nodes: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn smallest_third_party_chain_makes_room_for_first_party_chain() {
+        let first_party = BlockHash::from_low_u64_be(10);
+        let mut state = State::new(
+            None,
+            ChainOpts {
+                first_party_chains: std::sync::Arc::new(std::iter::once(first_party).collect()),
+                ..ChainOpts::default()
+            },
+        )
+        .with_max_chains(Some(2));
+
+        let big = BlockHash::from_low_u64_be(1);
+        let small = BlockHash::from_low_u64_be(2);
+        state.add_node(big, node("A", "Big")).unwrap_id();
+        state.add_node(big, node("B", "Big")).unwrap_id();
+        let small_node = state.add_node(small, node("A", "Small")).unwrap_id();
+
+        // There's room for chains we already track:
+        assert!(state.make_room_for_chain(&big).is_empty());
+
+        let evicted = state.make_room_for_chain(&first_party);
+        assert_eq!(evicted, vec![small_node]);
+        for node_id in evicted {
+            state.remove_node(node_id);
+        }
+        state.add_node(first_party, node("A", "First")).unwrap_id();
+
+        assert!(state.get_chain_by_genesis_hash(&small).is_none());
+        assert_eq!(state.chain_capacity().chains, 2);
+        assert_eq!(state.chain_capacity().evicted_chains, 1);
+
+        // With only first party chains left to evict, first party chains are let in anyway:
+        let other_first_party = BlockHash::from_low_u64_be(11);
+        let mut state = State::new(
+            None,
+            ChainOpts {
+                first_party_chains: std::sync::Arc::new(
+                    [first_party, other_first_party].iter().copied().collect(),
+                ),
+                ..ChainOpts::default()
+            },
+        )
+        .with_max_chains(Some(1));
+        state.add_node(first_party, node("A", "First")).unwrap_id();
+        assert!(state.make_room_for_chain(&other_first_party).is_empty());
+        state
+            .add_node(other_first_party, node("A", "Other"))
+            .unwrap_id();
+        assert_eq!(state.chain_capacity().chains, 2);
+    }
+
+    #[test]
+    fn default_first_party_chains_are_the_polkadot_relay_chains() {
+        let state = State::new(None, ChainOpts::default());
+        assert_eq!(state.chain_opts.first_party_chains.len(), 4);
+        let polkadot = chain::DEFAULT_FIRST_PARTY_CHAINS[0].parse().unwrap();
+        assert!(state.chain_opts.first_party_chains.contains(&polkadot));
     }
 
     #[test]
     fn adding_a_node_returns_expected_response() {
-        let mut state = State::new(None);
+        let mut state = State::new(None, ChainOpts::default());
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
 
@@ -318,6 +1916,7 @@ mod test {
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::TooManyChains => panic!("Not too many chains"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
@@ -332,6 +1931,7 @@ mod test {
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::TooManyChains => panic!("Not too many chains"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
@@ -344,7 +1944,7 @@ mod test {
 
     #[test]
     fn adding_and_removing_nodes_updates_chain_label_mapping() {
-        let mut state = State::new(None);
+        let mut state = State::new(None, ChainOpts::default());
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id0 = state
@@ -408,9 +2008,34 @@ mod test {
         assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_some());
     }
 
+    #[test]
+    fn adding_and_removing_nodes_updates_distribution() {
+        let mut state = State::new(None, ChainOpts::default());
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id0 = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        let mut other = node("B", "Chain One");
+        other.implementation = "Baz".into();
+        state.add_node(chain1_genesis, other).unwrap_id();
+
+        let chain = state.get_chain_by_genesis_hash(&chain1_genesis).unwrap();
+        assert_eq!(chain.distribution().implementations().get("bar"), 1);
+        assert_eq!(chain.distribution().implementations().get("baz"), 1);
+        assert_eq!(chain.distribution().versions().get("0.1"), 2);
+
+        state.remove_node(node_id0);
+
+        let chain = state.get_chain_by_genesis_hash(&chain1_genesis).unwrap();
+        assert_eq!(chain.distribution().implementations().get("bar"), 0);
+        assert_eq!(chain.distribution().implementations().get("baz"), 1);
+        assert_eq!(chain.distribution().versions().get("0.1"), 1);
+    }
+
     #[test]
     fn chain_removed_when_last_node_is() {
-        let mut state = State::new(None);
+        let mut state = State::new(None, ChainOpts::default());
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id = state
@@ -427,4 +2052,84 @@ mod test {
         assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_none());
         assert_eq!(state.iter_chains().count(), 0);
     }
+
+    #[test]
+    fn denylist_can_be_changed() {
+        let mut state = State::new(vec!["Spam".to_string()], ChainOpts::default());
+        let genesis = BlockHash::from_low_u64_be(1);
+        assert!(matches!(
+            state.add_node(genesis, node("A", "Spam")),
+            AddNodeResult::ChainOnDenyList
+        ));
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        assert!(state.denied_nodes().is_empty());
+
+        state.set_denylist(vec!["Chain One".to_string()]);
+        assert!(matches!(
+            state.add_node(genesis, node("B", "Chain One")),
+            AddNodeResult::ChainOnDenyList
+        ));
+        state.add_node(genesis, node("B", "Spam")).unwrap_id();
+        // Nodes added before the change are still around, for the caller to deal with:
+        assert_eq!(state.denied_nodes(), vec![node_id]);
+    }
+
+    #[test]
+    fn implausibly_high_blocks_are_ignored() {
+        let mut state = State::new(
+            None,
+            ChainOpts {
+                block_height_limit: BlockHeightLimit {
+                    max_height: Some(1_000_000),
+                    max_ahead_of_best: Some(100),
+                },
+                ..ChainOpts::default()
+            },
+        );
+        let genesis = BlockHash::from_low_u64_be(1);
+        let honest = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let spoofer = state.add_node(genesis, node("B", "Chain One")).unwrap_id();
+        let best = |state: &State| {
+            state
+                .get_chain_by_genesis_hash(&genesis)
+                .unwrap()
+                .best_block()
+                .height
+        };
+
+        // Until the chain has a best block, only the absolute limit applies:
+        import_block(&mut state, spoofer, u64::MAX);
+        assert_eq!(best(&state), 0);
+        import_block(&mut state, honest, 5000);
+        assert_eq!(best(&state), 5000);
+
+        // After that, blocks too far ahead of the best block are ignored too:
+        import_block(&mut state, spoofer, 5101);
+        assert_eq!(best(&state), 5000);
+        import_block(&mut state, spoofer, 5100);
+        assert_eq!(best(&state), 5100);
+        import_block(&mut state, honest, 5101);
+        assert_eq!(best(&state), 5101);
+    }
+
+    #[test]
+    fn block_height_limit() {
+        let unlimited = BlockHeightLimit::default();
+        assert!(unlimited.allows(u64::MAX, 10));
+
+        let limit = BlockHeightLimit {
+            max_height: Some(1000),
+            max_ahead_of_best: Some(10),
+        };
+        assert!(limit.allows(1000, 0));
+        assert!(!limit.allows(1001, 0));
+        assert!(limit.allows(20, 10));
+        assert!(!limit.allows(21, 10));
+        // Nor does the relative limit overflow:
+        let limit = BlockHeightLimit {
+            max_height: None,
+            max_ahead_of_best: Some(u64::MAX),
+        };
+        assert!(limit.allows(u64::MAX, 10));
+    }
 }
@@ -0,0 +1,162 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Keeps track of which nodes on a chain claim which validator address. More than one
+//! node claiming the same address usually means that a backup validator is running when
+//! it shouldn't be, which risks getting the validator slashed.
+
+use super::chain::ChainNodeId;
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct ValidatorIndex {
+    nodes_by_address: HashMap<Box<str>, Vec<ChainNodeId>>,
+    address_by_node: HashMap<ChainNodeId, Box<str>>,
+}
+
+impl ValidatorIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the validator address that a node claims. Hands back the nodes that might now be,
+    /// or no longer be, claiming the same address as another node.
+    pub fn set(&mut self, nid: ChainNodeId, address: Option<&str>) -> Vec<ChainNodeId> {
+        let address = address.and_then(normalize);
+        if self.address_by_node.get(&nid) == address.as_ref() {
+            return Vec::new();
+        }
+
+        let mut affected = self.remove(nid);
+        if let Some(address) = address {
+            let nodes = self.nodes_by_address.entry(address.clone()).or_default();
+            nodes.push(nid);
+            affected.extend(nodes.iter().copied());
+            self.address_by_node.insert(nid, address);
+        }
+        affected.sort_unstable_by_key(|&n| usize::from(n));
+        affected.dedup();
+        affected
+    }
+
+    /// Forget about a node. Hands back the nodes that might now be, or no longer be,
+    /// claiming the same address as another node, including the node forgotten.
+    pub fn remove(&mut self, nid: ChainNodeId) -> Vec<ChainNodeId> {
+        let address = match self.address_by_node.remove(&nid) {
+            Some(address) => address,
+            None => return Vec::new(),
+        };
+        let nodes = match self.nodes_by_address.get_mut(&address) {
+            Some(nodes) => nodes,
+            None => return vec![nid],
+        };
+        nodes.retain(|&n| n != nid);
+        let mut affected = nodes.clone();
+        if nodes.is_empty() {
+            self.nodes_by_address.remove(&address);
+        }
+        affected.push(nid);
+        affected.sort_unstable_by_key(|&n| usize::from(n));
+        affected
+    }
+
+    /// How many nodes claim the same validator address as this one, including itself.
+    pub fn claimants(&self, nid: ChainNodeId) -> usize {
+        self.address_by_node
+            .get(&nid)
+            .and_then(|address| self.nodes_by_address.get(address))
+            .map_or(0, |nodes| nodes.len())
+    }
+
+    /// Roughly how many bytes the index takes up. Each address is stored twice.
+    pub fn memory_usage(&self) -> usize {
+        let id = std::mem::size_of::<ChainNodeId>();
+        self.address_by_node
+            .values()
+            .map(|address| 2 * address.len() + 2 * id)
+            .sum()
+    }
+}
+
+/// Nodes report addresses in whatever form they like, so ignore surrounding whitespace, and
+/// the case of hex addresses. SS58 addresses are case sensitive, so are otherwise left alone.
+fn normalize(address: &str) -> Option<Box<str>> {
+    let address = address.trim();
+    if address.is_empty() {
+        None
+    } else if address.starts_with("0x") || address.starts_with("0X") {
+        Some(address.to_ascii_lowercase().into())
+    } else {
+        Some(address.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nid(n: usize) -> ChainNodeId {
+        ChainNodeId::from(n)
+    }
+
+    #[test]
+    fn nodes_claiming_the_same_address_are_duplicates() {
+        let mut index = ValidatorIndex::new();
+        assert_eq!(index.set(nid(1), Some("5Alice")), vec![nid(1)]);
+        assert!(index.claimants(nid(1)) <= 1);
+
+        assert_eq!(index.set(nid(2), Some(" 5Alice\n")), vec![nid(1), nid(2)]);
+        assert!(index.claimants(nid(1)) > 1);
+        assert!(index.claimants(nid(2)) > 1);
+        assert_eq!(index.claimants(nid(1)), 2);
+
+        // Setting the same address again changes nothing:
+        assert!(index.set(nid(2), Some("5Alice")).is_empty());
+
+        // SS58 addresses are case sensitive, but hex ones aren't:
+        index.set(nid(3), Some("5alice"));
+        assert!(index.claimants(nid(3)) <= 1);
+        index.set(nid(4), Some("0xABCD"));
+        index.set(nid(5), Some("0xabcd"));
+        assert!(index.claimants(nid(4)) > 1);
+    }
+
+    #[test]
+    fn conflicts_resolve_when_addresses_change_or_nodes_leave() {
+        let mut index = ValidatorIndex::new();
+        index.set(nid(1), Some("5Alice"));
+        index.set(nid(2), Some("5Alice"));
+        index.set(nid(3), Some("5Alice"));
+
+        assert_eq!(
+            index.set(nid(3), Some("5Bob")),
+            vec![nid(1), nid(2), nid(3)]
+        );
+        assert!(index.claimants(nid(3)) <= 1);
+        assert!(index.claimants(nid(1)) > 1);
+
+        assert_eq!(index.remove(nid(2)), vec![nid(1), nid(2)]);
+        assert!(index.claimants(nid(1)) <= 1);
+        assert!(index.claimants(nid(2)) <= 1);
+
+        // An empty address is no address at all:
+        index.set(nid(4), Some("5Bob"));
+        assert!(index.claimants(nid(3)) > 1);
+        index.set(nid(4), Some("  "));
+        assert!(index.claimants(nid(3)) <= 1);
+        assert_eq!(index.claimants(nid(4)), 0);
+    }
+}
@@ -0,0 +1,119 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Works out how verbose a node's telemetry is. Nodes may tell us when they connect, but
+//! most don't, and so otherwise we infer it from the kinds of message that they send.
+
+use common::node_message::VERBOSITY_BASIC;
+use common::node_types::Timestamp;
+
+/// Messages seen longer ago than this no longer count towards the inferred verbosity, so
+/// that a node whose verbosity is turned down is noticed rather than remembered forever.
+pub const INFERENCE_WINDOW_MS: u64 = 60_000;
+
+/// What we know about how verbose a node's telemetry is.
+#[derive(Debug, Clone)]
+pub struct Verbosity {
+    /// The verbosity the node told us about, if it did.
+    reported: Option<u8>,
+    /// Each verbosity that we've seen messages at, and when we last saw one.
+    last_seen: Vec<(u8, Timestamp)>,
+}
+
+impl Verbosity {
+    pub fn new(reported: Option<u8>) -> Self {
+        Verbosity {
+            reported,
+            last_seen: Vec::new(),
+        }
+    }
+
+    /// Take note of a message sent at the given verbosity.
+    pub fn observe(&mut self, verbosity: u8, now: Timestamp) {
+        match self.last_seen.iter_mut().find(|(v, _)| *v == verbosity) {
+            Some((_, at)) => *at = (*at).max(now),
+            None => self.last_seen.push((verbosity, now)),
+        }
+    }
+
+    /// The highest verbosity that the node has sent messages at recently.
+    pub fn inferred(&self, now: Timestamp) -> u8 {
+        self.last_seen
+            .iter()
+            .filter(|(_, at)| now.saturating_sub(*at) <= INFERENCE_WINDOW_MS)
+            .map(|(v, _)| *v)
+            .max()
+            .unwrap_or(VERBOSITY_BASIC)
+    }
+
+    /// The node's verbosity: what it told us, or failing that, what we've inferred.
+    pub fn level(&self, now: Timestamp) -> u8 {
+        self.reported.unwrap_or_else(|| self.inferred(now))
+    }
+
+    /// Whether [`Verbosity::level`] is inferred rather than told to us by the node.
+    pub fn is_inferred(&self) -> bool {
+        self.reported.is_none()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_message::{VERBOSITY_CONSENSUS_DEBUG, VERBOSITY_CONSENSUS_INFO};
+
+    #[test]
+    fn inferred_from_the_most_verbose_recent_message() {
+        let mut verbosity = Verbosity::new(None);
+        assert_eq!(verbosity.level(0), VERBOSITY_BASIC);
+
+        verbosity.observe(VERBOSITY_BASIC, 1000);
+        verbosity.observe(VERBOSITY_CONSENSUS_INFO, 2000);
+        verbosity.observe(VERBOSITY_BASIC, 3000);
+        assert_eq!(verbosity.level(3000), VERBOSITY_CONSENSUS_INFO);
+        assert!(verbosity.is_inferred());
+    }
+
+    #[test]
+    fn inference_follows_changes_in_verbosity() {
+        let mut verbosity = Verbosity::new(None);
+        verbosity.observe(VERBOSITY_CONSENSUS_DEBUG, 0);
+        verbosity.observe(VERBOSITY_BASIC, INFERENCE_WINDOW_MS);
+        assert_eq!(
+            verbosity.level(INFERENCE_WINDOW_MS),
+            VERBOSITY_CONSENSUS_DEBUG
+        );
+
+        // Once the node stops sending the more verbose messages, they're forgotten:
+        verbosity.observe(VERBOSITY_BASIC, INFERENCE_WINDOW_MS + 1);
+        assert_eq!(verbosity.level(INFERENCE_WINDOW_MS + 1), VERBOSITY_BASIC);
+
+        // ...and noticed again if it starts sending them again:
+        verbosity.observe(VERBOSITY_CONSENSUS_INFO, INFERENCE_WINDOW_MS + 2);
+        assert_eq!(
+            verbosity.level(INFERENCE_WINDOW_MS + 2),
+            VERBOSITY_CONSENSUS_INFO
+        );
+    }
+
+    #[test]
+    fn reported_verbosity_is_preferred() {
+        let mut verbosity = Verbosity::new(Some(VERBOSITY_BASIC));
+        verbosity.observe(VERBOSITY_CONSENSUS_INFO, 0);
+        assert_eq!(verbosity.level(0), VERBOSITY_BASIC);
+        assert!(!verbosity.is_inferred());
+    }
+}
@@ -0,0 +1,346 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Feeds can be served over WebTransport (HTTP/3) as well as over websockets.
+//!
+//! A client opens a session on [`FEED_PATH`] and then opens a single bidirectional
+//! stream. Every message sent in either direction on that stream is framed as a
+//! 4 byte big endian length followed by that many bytes. Frames from the client are
+//! the same text commands that a websocket feed would send (eg `subscribe:Polkadot`),
+//! and frames from us contain exactly the same bytes that we'd send in a single binary
+//! websocket message. As with websockets, sessions opened with a `?sign` query have every
+//! batch signed, after first being sent the session key (see [`crate::feed_session`]).
+
+use crate::aggregator::{
+    node_filter_from_query, AggregatorSet, FromFeedWebsocket, NodeFilter, ToFeedWebsocket,
+};
+use crate::feed_session::FeedSession;
+use crate::shutdown::Shutdown;
+use common::ready_chunks_all::ReadyChunksAll;
+use common::time;
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::time::{Duration, Instant};
+use wtransport::{Endpoint, Identity, RecvStream, SendStream, ServerConfig};
+
+/// The path that WebTransport feed sessions are opened on.
+pub const FEED_PATH: &str = "/feed";
+
+/// Commands from feeds are short; refuse to buffer anything bigger than this.
+const MAX_COMMAND_LEN: u32 = 64 * 1024;
+
+/// Load the certificate chain and private key in the PEM files given.
+pub async fn load_identity(cert_path: &Path, key_path: &Path) -> anyhow::Result<Identity> {
+    Identity::load_pemfiles(cert_path, key_path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to load WebTransport certificate: {}", e))
+}
+
+/// Bind a WebTransport endpoint using the certificate chain and private key in the
+/// PEM files given, and serve feeds on it in the background until we're shutting down.
+pub async fn start_server(
+    addr: SocketAddr,
+    cert_path: &Path,
+    key_path: &Path,
+    aggregator: AggregatorSet,
+    feed_timeout: u64,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let identity = load_identity(cert_path, key_path).await?;
+    let config = ServerConfig::builder()
+        .with_bind_address(addr)
+        .with_identity(identity)
+        .keep_alive_interval(Some(Duration::from_secs(5)))
+        .build();
+    let endpoint = Endpoint::server(config)?;
+
+    log::info!(
+        "listening on https://{} (WebTransport)",
+        endpoint.local_addr()?
+    );
+
+    tokio::spawn(async move {
+        loop {
+            let incoming = tokio::select! {
+                incoming = endpoint.accept() => incoming,
+                _ = shutdown.triggered() => break,
+            };
+            let aggregator = aggregator.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_session(incoming, aggregator, feed_timeout, shutdown).await {
+                    log::warn!("WebTransport feed session ended with an error: {}", e);
+                }
+            });
+        }
+        log::info!("No longer accepting WebTransport feed sessions");
+    });
+
+    Ok(())
+}
+
+/// Accept an incoming session and, if it's asking for the feed, handle it until it closes.
+async fn handle_session(
+    incoming: wtransport::endpoint::IncomingSession,
+    aggregator: AggregatorSet,
+    feed_timeout: u64,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let request = incoming.await?;
+    let addr = request.remote_address();
+    let path_and_query = request.path().to_owned();
+    let (path, query) = match path_and_query.find('?') {
+        Some(idx) => (&path_and_query[..idx], Some(&path_and_query[idx + 1..])),
+        None => (&*path_and_query, None),
+    };
+    if path != FEED_PATH {
+        request.not_found().await;
+        return Ok(());
+    }
+    let node_filter = match node_filter_from_query(query) {
+        Ok(node_filter) => node_filter,
+        Err(e) => {
+            log::warn!("Refusing WebTransport feed session from {:?}: {}", addr, e);
+            request.forbidden().await;
+            return Ok(());
+        }
+    };
+    let session = FeedSession::from_query(query);
+
+    let connection = request.accept().await?;
+    let (send, recv) = connection.accept_bi().await?;
+
+    log::info!("Opening WebTransport feed connection from {:?}", addr);
+    let _connection = shutdown.connection();
+    let (feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
+    let (mut tx_to_aggregator, mut send) = handle_feed_connection(
+        send,
+        recv,
+        tx_to_aggregator,
+        feed_timeout,
+        feed_id,
+        node_filter,
+        session,
+        shutdown,
+    )
+    .await;
+    log::info!("Closing WebTransport feed connection from {:?}", addr);
+
+    // Tell the aggregator that this connection has closed, so it can tidy up.
+    let _ = tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
+    let _ = send.finish().await;
+    Ok(())
+}
+
+/// This is the WebTransport equivalent of the websocket feed handler; commands are read
+/// from the stream and handed to the aggregator, and feed messages from the aggregator
+/// are written back to the stream.
+#[allow(clippy::too_many_arguments)]
+async fn handle_feed_connection<S>(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    mut tx_to_aggregator: S,
+    feed_timeout: u64,
+    _feed_id: u64, // <- can be useful for debugging purposes.
+    node_filter: Option<NodeFilter>,
+    mut session: Option<FeedSession>,
+    shutdown: Shutdown,
+) -> (S, SendStream)
+where
+    S: futures::Sink<FromFeedWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
+{
+    // Feeds that want signed batches need to know the key before anything else:
+    if let Some(session) = &session {
+        if let Err(e) = write_frame(&mut send, &session.key_message()).await {
+            log::warn!(
+                "Closing WebTransport feed due to error sending session key: {}",
+                e
+            );
+            return (tx_to_aggregator, send);
+        }
+    }
+
+    // unbounded channel so that slow feeds don't block aggregator progress:
+    let (tx_to_feed_conn, rx_from_aggregator) = flume::unbounded();
+    // Kept so that we know how much is left queued up for the feed if we're cut off:
+    let queued_for_feed = rx_from_aggregator.clone();
+    let mut rx_from_aggregator_chunks = ReadyChunksAll::new(rx_from_aggregator.into_stream());
+
+    // Tell the aggregator about this new connection, and give it a way to send messages to us:
+    let init_msg = FromFeedWebsocket::Initialize {
+        channel: tx_to_feed_conn,
+        node_filter,
+    };
+    if let Err(e) = tx_to_aggregator.send(init_msg).await {
+        log::error!("Error sending message to aggregator: {}", e);
+        return (tx_to_aggregator, send);
+    }
+
+    // Channels to notify each loop if the other closes:
+    let (recv_closer_tx, mut recv_closer_rx) = tokio::sync::oneshot::channel::<()>();
+    let (send_closer_tx, mut send_closer_rx) = tokio::sync::oneshot::channel::<()>();
+
+    // Receive commands from the feed:
+    let recv_handle = tokio::spawn(async move {
+        loop {
+            let frame = tokio::select! {
+                frame = read_frame(&mut recv) => frame,
+                _ = &mut recv_closer_rx => { break }
+            };
+
+            let bytes = match frame {
+                Ok(Some(bytes)) => bytes,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!(
+                        "Shutting down WebTransport feed connection: Failed to receive data: {}",
+                        e
+                    );
+                    break;
+                }
+            };
+
+            // We ignore all but valid UTF8 text commands from the frontend:
+            let text = match String::from_utf8(bytes) {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+
+            // Parse the message into a command we understand and send it to the aggregator:
+            let cmd = match FromFeedWebsocket::from_str(&text) {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    log::warn!(
+                        "Ignoring invalid command '{}' from the frontend: {}",
+                        text,
+                        e
+                    );
+                    continue;
+                }
+            };
+            if let Err(e) = tx_to_aggregator.send(cmd).await {
+                log::error!("Failed to send message to aggregator; closing feed: {}", e);
+                break;
+            }
+        }
+
+        drop(send_closer_tx); // Kill the send task if this recv task ends
+        tx_to_aggregator
+    });
+
+    // Send feed messages back:
+    let send_handle = tokio::spawn(async move {
+        'outer: loop {
+            let debounce = tokio::time::sleep_until(Instant::now() + Duration::from_millis(75));
+
+            let msgs = tokio::select! {
+                msgs = rx_from_aggregator_chunks.next() => msgs,
+                _ = &mut send_closer_rx => { break }
+                _ = shutdown.forced() => {
+                    shutdown.record_dropped(queued_for_feed.len());
+                    break
+                }
+            };
+
+            // End the loop when connection from aggregator ends:
+            let msgs = match msgs {
+                Some(msgs) => msgs,
+                None => break,
+            };
+            // If we stop part way through a batch, nothing else queued up for the feed
+            // will be sent either:
+            let batch_len = msgs.len();
+            let record_dropped = || shutdown.record_dropped(batch_len + queued_for_feed.len());
+
+            // If the feed is too slow to receive the current batch of messages, we'll drop it.
+            let message_send_deadline = Instant::now() + Duration::from_secs(feed_timeout);
+
+            let all_msg_bytes = msgs.into_iter().map(|msg| match msg {
+                ToFeedWebsocket::Bytes(bytes) => match &mut session {
+                    Some(session) => session.sign(&bytes, time::now()),
+                    None => bytes,
+                },
+            });
+            for bytes in all_msg_bytes {
+                let sent = tokio::select! {
+                    sent = tokio::time::timeout_at(message_send_deadline, write_frame(&mut send, &bytes)) => sent,
+                    _ = shutdown.forced() => {
+                        record_dropped();
+                        break 'outer;
+                    }
+                };
+                match sent {
+                    Err(_) => {
+                        log::warn!("Closing WebTransport feed that was too slow to keep up (too slow to send messages)");
+                        record_dropped();
+                        break 'outer;
+                    }
+                    Ok(Err(e)) => {
+                        log::warn!("Closing WebTransport feed due to error sending data: {}", e);
+                        record_dropped();
+                        break 'outer;
+                    }
+                    Ok(_) => {}
+                }
+            }
+            shutdown.record_flushed(batch_len);
+
+            debounce.await;
+        }
+
+        drop(recv_closer_tx); // Kill the recv task if this send task ends
+        send
+    });
+
+    // If our send/recv tasks are stopped (if one of them dies, they both will),
+    // collect the bits we need to hand back from them:
+    let send = send_handle.await.unwrap();
+    let tx_to_aggregator = recv_handle.await.unwrap();
+
+    (tx_to_aggregator, send)
+}
+
+/// Read a single length prefixed frame. Returns `None` if the stream was
+/// finished cleanly before a new frame started.
+async fn read_frame(recv: &mut RecvStream) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match recv.read(&mut len[read..]).await? {
+            Some(n) => read += n,
+            None if read == 0 => return Ok(None),
+            None => anyhow::bail!("Stream finished part way through a frame"),
+        }
+    }
+
+    let len = u32::from_be_bytes(len);
+    if len > MAX_COMMAND_LEN {
+        anyhow::bail!("Frame of {} bytes is too large", len);
+    }
+
+    let mut bytes = vec![0; len as usize];
+    recv.read_exact(&mut bytes).await?;
+    Ok(Some(bytes))
+}
+
+/// Write a single length prefixed frame.
+async fn write_frame(send: &mut SendStream, bytes: &[u8]) -> anyhow::Result<()> {
+    send.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    send.write_all(bytes).await?;
+    Ok(())
+}
@@ -37,7 +37,8 @@ cargo test e2e -- --ignored
 */
 
 use common::node_types::BlockHash;
-use common::ws_client::SentMessage;
+use common::ws_client::{self, SentMessage};
+use common::FEED_VERSION;
 use serde_json::json;
 use std::time::Duration;
 use test_utils::{
@@ -56,11 +57,11 @@ async fn e2e_feed_sent_version_on_connect() {
     // Connect a feed:
     let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
 
-    // Expect a version response of 31:
+    // Expect a version response:
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
     assert_eq!(
         feed_messages,
-        vec![FeedMessage::Version(31)],
+        vec![FeedMessage::Version(FEED_VERSION)],
         "expecting version"
     );
 
@@ -68,6 +69,42 @@ async fn e2e_feed_sent_version_on_connect() {
     server.shutdown().await;
 }
 
+/// When the core is asked to stop, feeds are told before their connection is closed.
+#[ignore]
+#[tokio::test]
+async fn e2e_feeds_told_about_shutdown_before_being_closed() {
+    let server = start_server_debug().await;
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::Version(FEED_VERSION)));
+
+    server.get_core().terminate().await.unwrap();
+
+    let feed_messages = feed_rx
+        .recv_feed_messages_once_timeout(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(
+        feed_messages,
+        vec![FeedMessage::ServerShutdown {
+            restart_in_seconds: None
+        }]
+    );
+
+    // Nothing more is sent, and the connection is closed:
+    let closed = feed_rx
+        .recv_feed_messages_once_timeout(Duration::from_secs(10))
+        .await;
+    assert!(
+        closed.is_err(),
+        "feed should have been closed: {:?}",
+        closed
+    );
+
+    server.shutdown().await;
+}
+
 /// Another very simple test: pings from feeds should be responded to by pongs
 /// with the same message content.
 #[ignore]
@@ -94,6 +131,36 @@ async fn e2e_feed_ping_responded_to_with_pong() {
     server.shutdown().await;
 }
 
+/// Feeds that ask for signed batches are sent a session key first, and every batch
+/// after that is signed with it.
+#[ignore]
+#[tokio::test]
+async fn e2e_feed_batches_are_signed_if_asked() {
+    let server = start_server_debug().await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed_signed().await.unwrap();
+    let key = match &feed_rx.recv_feed_messages_once().await.unwrap()[..] {
+        [FeedMessage::SessionKey { key }] => key.clone(),
+        msgs => panic!("Expecting a session key first, got {:?}", msgs),
+    };
+
+    // The version is sent on connecting, and then we ask for a pong:
+    let feed_messages = feed_rx.recv_signed_feed_messages_once(&key).await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::Version(FEED_VERSION)));
+
+    feed_tx.send_command("ping", "hello!").unwrap();
+    let feed_messages = feed_rx.recv_signed_feed_messages_once(&key).await.unwrap();
+    assert_eq!(
+        feed_messages,
+        vec![FeedMessage::Pong {
+            msg: "hello!".to_owned()
+        }]
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
 /// As a prelude to `lots_of_mute_messages_dont_cause_a_deadlock`, we can check that
 /// a lot of nodes can simultaneously subscribe and are all sent the expected response.
 #[ignore]
@@ -120,7 +187,7 @@ async fn e2e_multiple_feeds_sent_version_on_connect() {
     for feed_messages in responses {
         assert_eq!(
             feed_messages.expect("should have messages"),
-            vec![FeedMessage::Version(31)],
+            vec![FeedMessage::Version(FEED_VERSION)],
             "expecting version"
         );
     }
@@ -254,6 +321,75 @@ async fn e2e_feed_add_and_remove_node() {
     server.shutdown().await;
 }
 
+/// Feeds are sent the node count history of a chain when they subscribe to it,
+/// and then new samples as they are taken.
+#[ignore]
+#[tokio::test]
+async fn e2e_feed_sent_node_count_history() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": BlockHash::from_low_u64_ne(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx.send_command("subscribe", "Local Testnet").unwrap();
+
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(
+        feed_messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::NodeCountHistory { .. })),
+        "expected node count history on subscribe: {:?}",
+        feed_messages
+    );
+
+    // Samples are taken every 15 seconds; wait for the next one:
+    let sample = tokio::time::timeout(Duration::from_secs(20), async {
+        loop {
+            let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+            if let Some(sample) = feed_messages.into_iter().find_map(|m| match m {
+                FeedMessage::NodeCountSample { sample } => Some(sample),
+                _ => None,
+            }) {
+                break sample;
+            }
+        }
+    })
+    .await
+    .expect("should be sent a node count sample");
+    assert_eq!(sample.node_count, 1);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
 /// If nodes connect and the chain name changes, feeds will be told about this
 /// and will keep receiving messages about the renamed chain (despite subscribing
 /// to it by name).
@@ -294,6 +430,10 @@ async fn e2e_feeds_told_about_chain_rename_and_stay_subscribed() {
         .send_json_text(node_init_msg(1, "Initial chain name", "Node 1"))
         .unwrap();
 
+    // Wait a little for this message to propagate to the core
+    // (so that our feed subscribes after the core knows and not before).
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
     // Connect a feed and subscribe to the above chain:
     let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
     feed_tx
@@ -535,119 +675,938 @@ async fn e2e_feed_can_subscribe_and_unsubscribe_from_chain() {
     server.shutdown().await;
 }
 
-/// If a node sends more than some rolling average amount of data, it'll be booted.
+/// Feeds that connect with a `?node=<network_id>` filter are only told about that
+/// node (and about the chain as a whole), while other feeds are told about every node.
 #[ignore]
 #[tokio::test]
-async fn e2e_node_banned_if_it_sends_too_much_data() {
-    async fn try_send_data(max_bytes: usize, send_msgs: usize, bytes_per_msg: usize) -> bool {
-        let mut server = start_server(
-            ServerOpts::default(),
-            CoreOpts::default(),
-            ShardOpts {
-                // Remember, this is (currently) averaged over the last 10 seconds,
-                // so we need to send 10x this amount of data for an imemdiate ban:
-                max_node_data_per_second: Some(max_bytes),
-                ..Default::default()
-            },
-        )
-        .await;
+async fn e2e_feed_filtered_to_one_node_only_hears_about_that_node() {
+    use FeedMessage::*;
 
-        // Give us a shard to talk to:
-        let shard_id = server.add_shard().await.unwrap();
-        let (node_tx, _node_rx) = server
+    const ALICE_ID: &str = "12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp";
+    const BOB_ID: &str = "12D3KooWHdiAxVd8uMQR1hGWXccidmfCwLqcMpGwR6QcTP6QRMuD";
+
+    // The node that a feed message is about, if any:
+    fn about_node(msg: &FeedMessage) -> Option<usize> {
+        match msg {
+            AddedNode { node_id, .. }
+            | RemovedNode { node_id }
+            | LocatedNode { node_id, .. }
+            | ImportedBlock { node_id, .. }
+            | FinalizedBlock { node_id, .. }
+            | NodeStatsUpdate { node_id, .. }
+            | Hardware { node_id }
+            | StaleNode { node_id } => Some(*node_id),
+            _ => None,
+        }
+    }
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // Alice and Bob connect separately, so that they can disconnect separately:
+    let mut node_txs = Vec::new();
+    for (name, network_id) in &[("Alice", ALICE_ID), ("Bob", BOB_ID), ("Charlie", BOB_ID)] {
+        let (mut node_tx, _node_rx) = server
             .get_shard(shard_id)
             .unwrap()
             .connect_node()
             .await
             .unwrap();
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":1,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":"Local Testnet",
+                        "config":"",
+                        "genesis_hash": BlockHash::from_low_u64_ne(1),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":name,
+                        "network_id":network_id,
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+        node_txs.push(node_tx);
+        // Make sure the nodes are given IDs in order:
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
 
-        // Send the data requested to the shard:
-        for _ in 0..send_msgs {
-            node_tx
-                .unbounded_send(SentMessage::Binary(vec![1; bytes_per_msg]))
-                .unwrap();
-        }
+    // A filter needs a network ID to filter by:
+    assert!(server.get_core().connect_feed_for_node("").await.is_err());
 
-        // Wait a little for the shard to react and cut off the connection (or not):
-        tokio::time::sleep(Duration::from_millis(250)).await;
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let (alice_feed_tx, mut alice_feed_rx) = server
+        .get_core()
+        .connect_feed_for_node(ALICE_ID)
+        .await
+        .unwrap();
+    feed_tx.send_command("subscribe", "Local Testnet").unwrap();
+    alice_feed_tx
+        .send_command("subscribe", "Local Testnet")
+        .unwrap();
 
-        // Has the connection been closed?
-        node_tx.is_closed()
-    }
+    // The unfiltered feed is told about everybody, and the filtered one only Alice:
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(1))
+        .await
+        .unwrap();
+    let added: Vec<_> = feed_messages
+        .iter()
+        .filter(|m| matches!(m, AddedNode { .. }))
+        .filter_map(about_node)
+        .collect();
+    assert_eq!(added, vec![0, 1, 2]);
+
+    let alice_messages = alice_feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_contains_matches!(
+        &alice_messages,
+        SubscribedTo { name } if name == "Local Testnet",
+        BestBlock { .. },
+        AddedNode { node_id: 0, node: NodeDetails { name, .. }, .. } if name == "Alice",
+    );
+    assert!(alice_messages
+        .iter()
+        .filter_map(about_node)
+        .all(|id| id == 0));
+
+    // Bob imports a block. Everybody hears about the new best block, but only
+    // the unfiltered feed hears that Bob imported it:
+    node_txs[1]
+        .send_json_text(json!(
+            {"id":1, "payload":{ "best":BlockHash::from_low_u64_ne(2), "height":1, "msg":"block.import", "origin":"Own" },"ts":"2021-07-12T10:37:48.330433+01:00" }
+        ))
+        .unwrap();
 
-    assert_eq!(
-        try_send_data(1000, 10, 1000).await,
-        false,
-        "shouldn't be closed; we didn't exceed 10x threshold"
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        BestBlock {
+            block_number: 1,
+            ..
+        },
+        ImportedBlock { node_id: 1, .. },
     );
-    assert_eq!(
-        try_send_data(999, 10, 1000).await,
-        true,
-        "should be closed; we sent just over 10x the block threshold"
+    let alice_messages = alice_feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &alice_messages,
+        BestBlock {
+            block_number: 1,
+            ..
+        }
     );
+    assert!(alice_messages
+        .iter()
+        .filter_map(about_node)
+        .all(|id| id == 0));
+
+    // Updates that are only about Bob don't reach the filtered feed at all:
+    node_txs[1].send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, NodeStatsUpdate { node_id: 1, .. });
+    tokio::time::timeout(Duration::from_secs(1), alice_feed_rx.recv_feed_messages())
+        .await
+        .expect_err("Timeout should elapse since no messages about Alice were sent");
+
+    // Updates about Alice do:
+    node_txs[0].send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    let alice_messages = alice_feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&alice_messages, NodeStatsUpdate { node_id: 0, .. });
+
+    // When Bob leaves, the filtered feed isn't told about it, but it is told when Alice does:
+    node_txs.remove(1).close().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, RemovedNode { node_id: 1 });
+    let alice_messages = alice_feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(1))
+        .await
+        .unwrap_or_default();
+    assert!(alice_messages
+        .iter()
+        .filter_map(about_node)
+        .all(|id| id == 0));
+
+    node_txs.remove(0).close().await.unwrap();
+    let alice_messages = alice_feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&alice_messages, RemovedNode { node_id: 0 });
+
+    // Tidy up:
+    server.shutdown().await;
 }
 
-/// Feeds will be disconnected if they can't receive messages quickly enough.
+/// Feeds that connect with an `?environment=<environment>` filter are only told about
+/// nodes reporting that environment; nodes that don't report one are "unknown".
 #[ignore]
 #[tokio::test]
-async fn e2e_slow_feeds_are_disconnected() {
-    let mut server = start_server(
-        ServerOpts::default(),
-        // Timeout faster so the test can be quicker:
-        CoreOpts {
-            feed_timeout: Some(1),
-            ..Default::default()
-        },
-        // Allow us to send more messages in more easily:
-        ShardOpts {
-            max_nodes_per_connection: Some(100_000),
-            // Prevent the shard being being banned when it sends a load of data at once:
-            max_node_data_per_second: Some(100_000_000),
-            ..Default::default()
-        },
-    )
-    .await;
+async fn e2e_feed_filtered_by_environment_only_hears_about_matching_nodes() {
+    use FeedMessage::*;
 
-    // Give us a shard to talk to:
+    let mut server = start_server_debug().await;
     let shard_id = server.add_shard().await.unwrap();
-    let (mut node_tx, _node_rx) = server
-        .get_shard(shard_id)
-        .unwrap()
-        .connect_node()
-        .await
-        .unwrap();
 
-    // Add a load of nodes from this shard so there's plenty of data to give to a feed.
-    // We want to exhaust any buffers between core and feed (eg BufWriters). If the number
-    // is too low, data will happily be sent into a buffer and the connection won't need to
-    // be closed.
-    for n in 1..100_000 {
+    let mut node_txs = Vec::new();
+    for (name, environment) in &[
+        ("Alice", Some("prod")),
+        ("Bob", Some("staging")),
+        ("Charlie", None),
+        ("Dave", Some("prod")),
+    ] {
+        let (mut node_tx, _node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .unwrap();
         node_tx
-            .send_json_text(json!({
-                "id":n,
-                "ts":"2021-07-12T10:37:47.714666+01:00",
-                "payload": {
-                    "authority":true,
-                    "chain":"Polkadot",
-                    "config":"",
-                    "genesis_hash": BlockHash::from_low_u64_ne(1),
-                    "implementation":"Substrate Node",
-                    "msg":"system.connected",
-                    "name": format!("Alice {}", n),
-                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
-                    "startup_time":"1625565542717",
-                    "version":"2.0.0-07a1af348-aarch64-macos"
+            .send_json_text(json!(
+                {
+                    "id":1,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":"Local Testnet",
+                        "config":"",
+                        "genesis_hash": BlockHash::from_low_u64_ne(1),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":name,
+                        "environment":environment,
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
                 }
-            }))
+            ))
             .unwrap();
+        node_txs.push(node_tx);
+        // Make sure the nodes are given IDs in order:
+        tokio::time::sleep(Duration::from_millis(250)).await;
     }
 
-    // Connect a raw feed so that we can control how fast we consume data from the websocket
-    let (mut raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
-
-    // Subscribe the feed:
-    raw_feed_tx.send_text("subscribe:Polkadot").await.unwrap();
-
+    // The names of the nodes that a feed subscribing to the chain is told about:
+    async fn added_node_names(
+        server: &test_utils::server::Server,
+        environment: &str,
+    ) -> Vec<String> {
+        let (feed_tx, mut feed_rx) = server
+            .get_core()
+            .connect_feed_for_environment(environment)
+            .await
+            .unwrap();
+        feed_tx.send_command("subscribe", "Local Testnet").unwrap();
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|m| match m {
+                AddedNode { node, .. } => Some(node.name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    assert_eq!(
+        added_node_names(&server, "prod").await,
+        vec!["Alice", "Dave"]
+    );
+    assert_eq!(added_node_names(&server, "staging").await, vec!["Bob"]);
+    assert_eq!(added_node_names(&server, "unknown").await, vec!["Charlie"]);
+    assert!(added_node_names(&server, "dev").await.is_empty());
+
+    // Nodes joining later are only passed on to matching feeds:
+    let (staging_feed_tx, mut staging_feed_rx) = server
+        .get_core()
+        .connect_feed_for_environment("staging")
+        .await
+        .unwrap();
+    staging_feed_tx
+        .send_command("subscribe", "Local Testnet")
+        .unwrap();
+    staging_feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(1))
+        .await
+        .unwrap();
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": BlockHash::from_low_u64_ne(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Eve",
+                    "environment":"prod",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+    let staging_messages = staging_feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(1))
+        .await
+        .unwrap_or_default();
+    assert!(!staging_messages
+        .iter()
+        .any(|m| matches!(m, AddedNode { .. })));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// The admin API can list the nodes on a chain, filtered by whether they have
+/// off-chain indexing enabled.
+#[ignore]
+#[tokio::test]
+async fn e2e_admin_nodes_filtered_by_offchain_indexing() {
+    let admin_token = "let-me-in";
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some(admin_token.to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let genesis_hash = BlockHash::from_low_u64_be(1);
+
+    let mut node_txs = Vec::new();
+    for (name, offchain_indexing) in &[
+        ("Alice", Some(true)),
+        ("Bob", None),
+        ("Charlie", Some(true)),
+    ] {
+        let (mut node_tx, _node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .unwrap();
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":1,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":"Local Testnet",
+                        "config":"",
+                        "genesis_hash": genesis_hash,
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":name,
+                        "offchain_indexing":offchain_indexing,
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+        node_txs.push(node_tx);
+        // Make sure the nodes are given IDs in order:
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    // The names of the nodes handed back by the admin API, given a query:
+    async fn node_names(
+        server: &test_utils::server::Server,
+        path_and_query: &str,
+        admin_token: &str,
+    ) -> Vec<String> {
+        let (status, body) = server
+            .get_core()
+            .admin_get(path_and_query, admin_token)
+            .await
+            .unwrap();
+        assert_eq!(status, 200, "unexpected response: {}", body);
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| node["details"]["name"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    let nodes_path = format!("/chains/{:x}/nodes", genesis_hash);
+    assert_eq!(
+        node_names(&server, &nodes_path, admin_token).await,
+        vec!["Alice", "Bob", "Charlie"]
+    );
+    assert_eq!(
+        node_names(
+            &server,
+            &format!("{}?offchain_indexing=true", nodes_path),
+            admin_token
+        )
+        .await,
+        vec!["Alice", "Charlie"]
+    );
+    assert_eq!(
+        node_names(
+            &server,
+            &format!("{}?offchain_indexing=false", nodes_path),
+            admin_token
+        )
+        .await,
+        vec!["Bob"]
+    );
+
+    // Nodes can be sorted and paged through, alongside filtering:
+    assert_eq!(
+        node_names(
+            &server,
+            &format!("{}?sort=details.name:desc&limit=2", nodes_path),
+            admin_token
+        )
+        .await,
+        vec!["Charlie", "Bob"]
+    );
+    assert_eq!(
+        node_names(
+            &server,
+            &format!("{}?offchain_indexing=true&offset=1", nodes_path),
+            admin_token
+        )
+        .await,
+        vec!["Charlie"]
+    );
+    let core = server.get_core();
+    let (status, body) = core
+        .admin_get(
+            &format!("{}?limit=1&fields=id,details", nodes_path),
+            admin_token,
+        )
+        .await
+        .unwrap();
+    assert_eq!(status, 200, "unexpected response: {}", body);
+    let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(page["total"], 3);
+    assert_eq!(page["next_offset"], 1);
+    let node = page["items"][0].as_object().unwrap();
+    assert_eq!(node.keys().collect::<Vec<_>>(), vec!["details", "id"]);
+
+    // Bad filters, bad list queries, unknown chains and missing tokens are rejected:
+    for query in ["sort=location", "limit=1001", "fields="] {
+        let (status, _) = core
+            .admin_get(&format!("{}?{}", nodes_path, query), admin_token)
+            .await
+            .unwrap();
+        assert_eq!(status, 400, "unexpected status for {}", query);
+    }
+    let (status, _) = core
+        .admin_get(
+            &format!("{}?offchain_indexing=maybe", nodes_path),
+            admin_token,
+        )
+        .await
+        .unwrap();
+    assert_eq!(status, 400);
+    let unknown_chain = format!("/chains/{:x}/nodes", BlockHash::from_low_u64_be(2));
+    let (status, _) = core.admin_get(&unknown_chain, admin_token).await.unwrap();
+    assert_eq!(status, 404);
+    let (status, _) = core.admin_get(&nodes_path, "wrong-token").await.unwrap();
+    assert_ne!(status, 200);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// The retention policy of a chain can be looked at and changed through the admin API.
+#[ignore]
+#[tokio::test]
+async fn e2e_admin_can_change_chain_retention_policy() {
+    let admin_token = "let-me-in";
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some(admin_token.to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let genesis_hash = BlockHash::from_low_u64_be(1);
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": genesis_hash,
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let core = server.get_core();
+    let retention_path = format!("/chains/{:x}/retention", genesis_hash);
+    let get_policy = || async {
+        let (status, body) = core.admin_get(&retention_path, admin_token).await.unwrap();
+        assert_eq!(status, 200, "unexpected response: {}", body);
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()
+    };
+
+    // Chains start off with the default policy:
+    assert_eq!(
+        get_policy().await,
+        json!({ "max_samples": 240, "max_age_ms": 3_600_000 })
+    );
+
+    let policy = json!({ "max_samples": 10, "max_age_ms": 60_000 });
+    let (status, body) = core
+        .admin_put(&retention_path, admin_token, policy.to_string())
+        .await
+        .unwrap();
+    assert_eq!(status, 200, "unexpected response: {}", body);
+    assert_eq!(get_policy().await, policy);
+
+    // Policies that would keep nothing, bad bodies and unknown chains are rejected:
+    for body in [
+        json!({ "max_samples": 0, "max_age_ms": 60_000 }).to_string(),
+        "not json".to_owned(),
+    ] {
+        let (status, _) = core
+            .admin_put(&retention_path, admin_token, body)
+            .await
+            .unwrap();
+        assert_eq!(status, 400);
+    }
+    let unknown_chain = format!("/chains/{:x}/retention", BlockHash::from_low_u64_be(2));
+    let (status, _) = core
+        .admin_put(&unknown_chain, admin_token, policy.to_string())
+        .await
+        .unwrap();
+    assert_eq!(status, 404);
+    assert_eq!(get_policy().await, policy);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Admins can see how many messages are waiting in the aggregators' internal queues,
+/// even while they're busy.
+#[ignore]
+#[tokio::test]
+async fn e2e_admin_can_see_queue_depths() {
+    let admin_token = "let-me-in";
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some(admin_token.to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": BlockHash::from_low_u64_be(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    let mut feeds = server.get_core().connect_multiple_feeds(5).await.unwrap();
+    for (feed_tx, _) in &mut feeds {
+        feed_tx.send_command("subscribe", "Local Testnet").unwrap();
+    }
+
+    // Keep the aggregator busy while we ask about its queues:
+    for n in 1..=500 {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":1,
+                    "ts":"2021-07-12T10:37:48.330433+01:00",
+                    "payload": {
+                        "best": BlockHash::from_low_u64_be(n),
+                        "height": n,
+                        "msg": "block.import",
+                        "origin": "Own"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+
+    let (status, body) = server
+        .get_core()
+        .admin_get("/queues", admin_token)
+        .await
+        .unwrap();
+    assert_eq!(status, 200, "unexpected response: {}", body);
+    let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let aggregators = report["aggregators"].as_array().unwrap();
+    assert!(!aggregators.is_empty());
+    for queues in aggregators {
+        assert!(queues["timestamp_unix_ms"].is_u64());
+        assert!(queues["ingest"].is_u64());
+        for key in ["feeds", "total", "p95", "max"] {
+            assert!(queues["feeds"][key].is_u64(), "bad feeds.{}: {}", key, body);
+        }
+        assert!(queues["feeds"]["mean"].is_number());
+        assert!(queues["dropped"]["ingest"].is_u64());
+        assert!(queues["dropped"]["feeds"].is_u64());
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Admins can ask for a node to be located again, and feeds are told where it is.
+#[ignore]
+#[tokio::test]
+async fn e2e_admin_can_relocate_node() {
+    let admin_token = "let-me-in";
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some(admin_token.to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let genesis_hash = BlockHash::from_low_u64_be(1);
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": genesis_hash,
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx.send_command("subscribe", "Local Testnet").unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Our node connects from localhost, which is always found in Berlin:
+    let core = server.get_core();
+    let relocate_path = format!("/nodes/{:x}/0/relocate", genesis_hash);
+    let (status, body) = core
+        .admin_post(&relocate_path, admin_token, "")
+        .await
+        .unwrap();
+    assert_eq!(status, 202, "unexpected response: {}", body);
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::LocatedNode { node_id: 0, city, .. } if city == "Berlin"
+    );
+
+    // Unknown nodes and chains, and bad IDs, are rejected:
+    for (path, expected_status) in [
+        (format!("/nodes/{:x}/1/relocate", genesis_hash), 404),
+        (
+            format!("/nodes/{:x}/0/relocate", BlockHash::from_low_u64_be(2)),
+            404,
+        ),
+        (format!("/nodes/{:x}/alice/relocate", genesis_hash), 400),
+        ("/nodes/0x1234/0/relocate".to_owned(), 400),
+    ] {
+        let (status, _) = core.admin_post(&path, admin_token, "").await.unwrap();
+        assert_eq!(status, expected_status, "unexpected status for {}", path);
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds can opt in to being told about the implementations and versions that
+/// nodes on the subscribed chain are running, and are kept up to date as nodes come and go.
+#[ignore]
+#[tokio::test]
+async fn e2e_feed_can_opt_in_to_chain_distribution() {
+    use FeedMessage::*;
+
+    // Start server, add shard, connect node:
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Send a "system connected" message for a couple of nodes on the same chain:
+    for (id, implementation) in [(1, "Substrate Node"), (2, " Parity Polkadot ")] {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":id,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":"Local Testnet",
+                        "config":"",
+                        "genesis_hash": BlockHash::from_low_u64_ne(1),
+                        "implementation":implementation,
+                        "msg":"system.connected",
+                        "name":format!("Alice {}", id),
+                        "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+
+    // Wait a little for these messages to propagate to the core:
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Connect a feed and subscribe to the chain:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx.send_command("subscribe", "Local Testnet").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, ChainDistribution { .. })));
+
+    // Opt in to distribution updates, and we're told about the current distribution:
+    feed_tx.send_command("send-distribution", "").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        ChainDistribution { implementations, versions }
+            if implementations.get("substrate node") == Some(&1)
+            && implementations.get("parity polkadot") == Some(&1)
+            && versions.get("2.0.0-07a1af348-aarch64-macos") == Some(&2)
+    );
+
+    // Connect another node, and we're told about the new distribution:
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":3,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": BlockHash::from_low_u64_ne(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Bob",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.1"
+                },
+            }
+        ))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        ChainDistribution { implementations, versions }
+            if implementations.get("substrate node") == Some(&2)
+            && versions.get("2.0.1") == Some(&1)
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If a node sends more than some rolling average amount of data, it'll be booted.
+#[ignore]
+#[tokio::test]
+async fn e2e_node_banned_if_it_sends_too_much_data() {
+    async fn try_send_data(max_bytes: usize, send_msgs: usize, bytes_per_msg: usize) -> bool {
+        let mut server = start_server(
+            ServerOpts::default(),
+            CoreOpts::default(),
+            ShardOpts {
+                // Remember, this is (currently) averaged over the last 10 seconds,
+                // so we need to send 10x this amount of data for an imemdiate ban:
+                max_node_data_per_second: Some(max_bytes),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // Give us a shard to talk to:
+        let shard_id = server.add_shard().await.unwrap();
+        let (node_tx, _node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .unwrap();
+
+        // Send the data requested to the shard:
+        for _ in 0..send_msgs {
+            node_tx
+                .unbounded_send(SentMessage::Binary(vec![1; bytes_per_msg]))
+                .unwrap();
+        }
+
+        // Wait a little for the shard to react and cut off the connection (or not):
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        // Has the connection been closed?
+        node_tx.is_closed()
+    }
+
+    assert_eq!(
+        try_send_data(1000, 10, 1000).await,
+        false,
+        "shouldn't be closed; we didn't exceed 10x threshold"
+    );
+    assert_eq!(
+        try_send_data(999, 10, 1000).await,
+        true,
+        "should be closed; we sent just over 10x the block threshold"
+    );
+}
+
+/// Feeds will be disconnected if they can't receive messages quickly enough.
+#[ignore]
+#[tokio::test]
+async fn e2e_slow_feeds_are_disconnected() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        // Timeout faster so the test can be quicker:
+        CoreOpts {
+            feed_timeout: Some(1),
+            ..Default::default()
+        },
+        // Allow us to send more messages in more easily:
+        ShardOpts {
+            max_nodes_per_connection: Some(100_000),
+            // Prevent the shard being being banned when it sends a load of data at once:
+            max_node_data_per_second: Some(100_000_000),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Give us a shard to talk to:
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Add a load of nodes from this shard so there's plenty of data to give to a feed.
+    // We want to exhaust any buffers between core and feed (eg BufWriters). If the number
+    // is too low, data will happily be sent into a buffer and the connection won't need to
+    // be closed.
+    for n in 1..100_000 {
+        node_tx
+            .send_json_text(json!({
+                "id":n,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Polkadot",
+                    "config":"",
+                    // Polkadot's real genesis hash, so that the chain is first party and
+                    // allows this many nodes:
+                    "genesis_hash": "0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3",
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name": format!("Alice {}", n),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                }
+            }))
+            .unwrap();
+    }
+
+    // Connect a raw feed so that we can control how fast we consume data from the websocket
+    let (mut raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+
+    // Subscribe the feed:
+    raw_feed_tx.send_text("subscribe:Polkadot").await.unwrap();
+
     // Wait a little.. the feed hasn't been receiving messages so it should
     // be booted after ~a second.
     tokio::time::sleep(Duration::from_secs(5)).await;
@@ -754,14 +1713,17 @@ async fn e2e_max_nodes_per_connection_is_enforced() {
         0
     );
 
-    // Third message ID should be ignored:
+    // Third message ID should be ignored (chain stats are sent to every feed
+    // periodically, so they don't count):
     node_tx.send_json_text(json_msg(3)).unwrap();
     assert_eq!(
         feed_rx
             .recv_feed_messages_timeout(Duration::from_secs(1))
             .await
             .unwrap()
-            .len(),
+            .into_iter()
+            .filter(|msg| !matches!(msg, FeedMessage::ChainStats { .. }))
+            .count(),
         0
     );
 
@@ -772,7 +1734,9 @@ async fn e2e_max_nodes_per_connection_is_enforced() {
             .recv_feed_messages_timeout(Duration::from_secs(1))
             .await
             .unwrap()
-            .len(),
+            .into_iter()
+            .filter(|msg| !matches!(msg, FeedMessage::ChainStats { .. }))
+            .count(),
         0
     );
 
@@ -837,3 +1801,501 @@ async fn e2e_max_nodes_per_connection_is_enforced() {
     // Tidy up:
     server.shutdown().await;
 }
+
+/// Feeds can connect over WebTransport as well as websockets, and are sent exactly the
+/// same bytes either way.
+#[ignore]
+#[tokio::test]
+async fn e2e_webtransport_feed_matches_websocket_feed() {
+    use common::ws_client::RecvMessage;
+    use futures::StreamExt;
+    use FeedMessage::*;
+
+    // Generate a certificate for the server, and trust it by hash on the client:
+    let identity = wtransport::Identity::self_signed(["localhost", "127.0.0.1"]).unwrap();
+    let cert = &identity.certificate_chain().as_slice()[0];
+    let dir = std::env::temp_dir().join(format!("telemetry-e2e-wt-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    std::fs::write(&cert_path, cert.to_pem()).unwrap();
+    std::fs::write(&key_path, identity.private_key().to_secret_pem()).unwrap();
+
+    // Find a free UDP port to listen on:
+    let wt_addr = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            webtransport: Some((wt_addr, cert_path, key_path)),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": BlockHash::from_low_u64_ne(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    // Wait until the core knows about the chain, so both feeds below see the same state:
+    let (_, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedChain { name, node_count: 1 } if name == "Local Testnet");
+
+    // Connect a websocket feed and a WebTransport feed:
+    let (_ws_tx, mut ws_rx) = server.get_core().connect_feed().await.unwrap();
+    let config = wtransport::ClientConfig::builder()
+        .with_bind_default()
+        .with_server_certificate_hashes([cert.hash()])
+        .build();
+    let connection = wtransport::Endpoint::client(config)
+        .unwrap()
+        .connect(format!("https://{}/feed", wt_addr))
+        .await
+        .unwrap();
+    let (mut wt_send, mut wt_recv) = connection.open_bi().await.unwrap().await.unwrap();
+
+    // Frames are a 4 byte big endian length followed by the bytes:
+    async fn read_frame(recv: &mut wtransport::RecvStream) -> Vec<u8> {
+        let mut len = [0u8; 4];
+        recv.read_exact(&mut len).await.unwrap();
+        let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+        recv.read_exact(&mut bytes).await.unwrap();
+        bytes
+    }
+
+    // The first messages that each feed is sent are identical:
+    let ws_bytes = match ws_rx.next().await.unwrap().unwrap() {
+        RecvMessage::Binary(bytes) => bytes,
+        RecvMessage::Text(text) => text.into_bytes(),
+    };
+    let wt_bytes = tokio::time::timeout(Duration::from_secs(10), read_frame(&mut wt_recv))
+        .await
+        .expect("WebTransport feed should be sent messages");
+    assert_eq!(wt_bytes, ws_bytes);
+
+    let feed_messages = FeedMessage::from_bytes(&wt_bytes).unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        Version(FEED_VERSION),
+        AddedChain { name, node_count: 1 } if name == "Local Testnet"
+    );
+
+    // Commands can be sent over WebTransport, too:
+    let cmd = b"subscribe:Local Testnet";
+    wt_send
+        .write_all(&(cmd.len() as u32).to_be_bytes())
+        .await
+        .unwrap();
+    wt_send.write_all(cmd).await.unwrap();
+
+    // Gather up messages until the feed goes quiet:
+    let mut feed_messages = FeedMessage::from_bytes(&read_frame(&mut wt_recv).await).unwrap();
+    while let Ok(bytes) =
+        tokio::time::timeout(Duration::from_secs(1), read_frame(&mut wt_recv)).await
+    {
+        feed_messages.extend(FeedMessage::from_bytes(&bytes).unwrap());
+    }
+    assert_contains_matches!(
+        feed_messages,
+        SubscribedTo { name } if name == "Local Testnet",
+        AddedNode { node_id: 0, node: NodeDetails { name, .. }, .. } if name == "Alice"
+    );
+
+    // Sessions can ask for signed batches, just like websocket feeds:
+    let config = wtransport::ClientConfig::builder()
+        .with_bind_default()
+        .with_server_certificate_hashes([cert.hash()])
+        .build();
+    let signed_connection = wtransport::Endpoint::client(config)
+        .unwrap()
+        .connect(format!("https://{}/feed?sign", wt_addr))
+        .await
+        .unwrap();
+    let (_signed_send, mut signed_recv) = signed_connection.open_bi().await.unwrap().await.unwrap();
+    let key = match &FeedMessage::from_bytes(&read_frame(&mut signed_recv).await).unwrap()[..] {
+        [SessionKey { key }] => key.clone(),
+        msgs => panic!("Expecting a session key first, got {:?}", msgs),
+    };
+    let feed_messages =
+        FeedMessage::from_signed_bytes(&read_frame(&mut signed_recv).await, &key).unwrap();
+    assert!(feed_messages.contains(&Version(FEED_VERSION)));
+
+    // Tidy up:
+    let _ = std::fs::remove_dir_all(&dir);
+    server.shutdown().await;
+}
+
+/// Legacy nodes can send newline delimited JSON over plain TCP if the shard is asked to
+/// listen for it. They are added and removed just like websocket nodes, and are subject
+/// to the same limits.
+#[ignore]
+#[tokio::test]
+async fn e2e_legacy_tcp_nodes_are_added_removed_and_limited() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use FeedMessage::*;
+
+    // Find a free TCP port to listen on:
+    let legacy_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            legacy_tcp_listen: Some(legacy_addr),
+            ..Default::default()
+        },
+    )
+    .await;
+    server.add_shard().await.unwrap();
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Connect a legacy node, and send a couple of lines in one go:
+    let mut stream = TcpStream::connect(legacy_addr).await.unwrap();
+    let connected = json!({
+        "id":1,
+        "ts":"2021-07-12T10:37:47.714666+01:00",
+        "payload": {
+            "authority":true,
+            "chain":"Legacy Testnet",
+            "config":"",
+            "genesis_hash": BlockHash::from_low_u64_ne(1),
+            "implementation":"Substrate Node",
+            "msg":"system.connected",
+            "name":"Old Alice",
+            "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+            "startup_time":"1625565542717",
+            "version":"0.9.0"
+        },
+    });
+    let interval = json!({
+        "id":1,
+        "ts":"2021-07-12T10:37:48.330433+01:00",
+        "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":3 }
+    });
+    stream
+        .write_all(format!("{}\n{}\n", connected, interval).as_bytes())
+        .await
+        .unwrap();
+
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        AddedChain { name, node_count: 1 } if name == "Legacy Testnet"
+    );
+    feed_tx.send_command("subscribe", "Legacy Testnet").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        AddedNode { node: NodeDetails { name, .. }, stats, .. } if name == "Old Alice" && stats.peers == 3
+    );
+
+    // Closing the TCP connection removes the node:
+    drop(stream);
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        RemovedChain { name } if name == "Legacy Testnet"
+    );
+
+    // Sending too much data gets the connection closed and the address banned, just
+    // as it would for a websocket connection (the default limit is 256k/s averaged
+    // over 10 seconds):
+    let mut stream = TcpStream::connect(legacy_addr).await.unwrap();
+    let line = format!("{}\n", "a".repeat(100 * 1024));
+    let mut buf = [0u8; 16];
+    let closed = async {
+        for _ in 0..40 {
+            if stream.write_all(line.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+        // Once we've been booted, reads will end:
+        let _ = stream.read(&mut buf).await;
+    };
+    tokio::time::timeout(Duration::from_secs(10), closed)
+        .await
+        .expect("connection should be closed for sending too much data");
+
+    // ... and new connections from the same address are closed straight away:
+    let mut stream = TcpStream::connect(legacy_addr).await.unwrap();
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("banned connection should be closed");
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If a shard restarts, nodes that reconnect to it keep their node IDs and history, and
+/// feeds aren't told that they went away.
+#[ignore]
+#[tokio::test]
+async fn e2e_nodes_keep_their_history_across_shard_restarts() {
+    let cache_path = std::env::temp_dir().join(format!(
+        "telemetry-e2e-resumption-cache-{}.json",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&cache_path);
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            shard_restart_grace_secs: Some(60),
+            ..Default::default()
+        },
+        ShardOpts {
+            resumption_cache: Some(cache_path.clone()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let system_connected = json!({
+        "id":1,
+        "ts":"2021-07-12T10:37:47.714666+01:00",
+        "payload": {
+            "authority":true,
+            "chain":"Local Testnet",
+            "config":"",
+            "genesis_hash": BlockHash::from_low_u64_ne(1),
+            "implementation":"Substrate Node",
+            "msg":"system.connected",
+            "name":"Alice",
+            "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+            "startup_time":"1625565542717",
+            "version":"2.0.0-07a1af348-aarch64-macos"
+        },
+    });
+    let system_interval = |peers: u64| {
+        json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:48.330433+01:00",
+            "payload": { "msg":"system.interval", "peers":peers },
+        })
+    };
+
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx.send_json_text(system_connected.clone()).unwrap();
+    node_tx.send_json_text(system_interval(3)).unwrap();
+
+    // The shard saves its resumption cache every few seconds, when messages arrive:
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    node_tx.send_json_text(system_interval(5)).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx.send_command("subscribe", "Local Testnet").unwrap();
+    let added_node = |feed_messages: Vec<FeedMessage>| {
+        feed_messages.into_iter().find_map(|m| match m {
+            FeedMessage::AddedNode {
+                node_id,
+                peer_history,
+                ..
+            } => Some((node_id, peer_history)),
+            _ => None,
+        })
+    };
+    let (node_id, peer_history) = added_node(feed_rx.recv_feed_messages().await.unwrap())
+        .expect("feed should be told about the node");
+    assert!(!peer_history.is_empty());
+
+    // Restart the shard, and reconnect the node to it:
+    server.kill_shard(shard_id).await;
+    drop(node_tx);
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx.send_json_text(system_connected).unwrap();
+
+    // Feeds aren't told that the node went away:
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(2))
+        .await
+        .unwrap();
+    assert!(
+        !feed_messages.iter().any(|m| matches!(
+            m,
+            FeedMessage::RemovedNode { .. } | FeedMessage::AddedNode { .. }
+        )),
+        "node shouldn't have been removed and added again: {:?}",
+        feed_messages
+    );
+
+    // New feeds see the node with the same ID and history:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx.send_command("subscribe", "Local Testnet").unwrap();
+    assert_eq!(
+        added_node(feed_rx.recv_feed_messages().await.unwrap()),
+        Some((node_id, peer_history))
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+    let _ = std::fs::remove_file(&cache_path);
+}
+
+/// A system.connected message for a node on the "Local Testnet" chain.
+fn local_testnet_node_connected() -> serde_json::Value {
+    json!({
+        "id":1,
+        "ts":"2021-07-12T10:37:47.714666+01:00",
+        "payload": {
+            "authority":true,
+            "chain":"Local Testnet",
+            "config":"",
+            "genesis_hash": BlockHash::from_low_u64_ne(1),
+            "implementation":"Substrate Node",
+            "msg":"system.connected",
+            "name":"Alice",
+            "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+            "startup_time":"1625565542717",
+            "version":"2.0.0-07a1af348-aarch64-macos"
+        },
+    })
+}
+
+/// If the core is given a shard token, shards which don't provide it can't connect,
+/// and nothing they're told about reaches feeds.
+#[ignore]
+#[tokio::test]
+async fn e2e_shards_without_the_shard_token_are_refused() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            shard_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts {
+            core_token: Some("wrong".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Connections to the shard endpoint without the right token are turned away:
+    for query in &["", "?token=wrong"] {
+        let uri: http::Uri = format!("http://{}/shard_submit{}", server.get_core().host(), query)
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            ws_client::connect(&uri).await,
+            Err(ws_client::ConnectError::ConnectionFailedRejected { status_code: 401 })
+        ));
+    }
+
+    // A shard given the wrong token never gets to tell the core about its nodes:
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(local_testnet_node_connected())
+        .unwrap();
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(2))
+        .await
+        .unwrap();
+    assert!(
+        !feed_messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::AddedChain { .. })),
+        "no chains should have been added: {:?}",
+        feed_messages
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Shards which provide the core's shard token are let in as usual.
+#[ignore]
+#[tokio::test]
+async fn e2e_shards_with_the_shard_token_are_accepted() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            shard_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts {
+            core_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(local_testnet_node_connected())
+        .unwrap();
+
+    // Wait a little for this message to propagate to the core:
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        node_count: 1
+    }));
+
+    // Tidy up:
+    server.shutdown().await;
+}
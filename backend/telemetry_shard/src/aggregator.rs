@@ -14,9 +14,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::connection::{create_ws_connection_to_core, Message};
+use crate::muted_chains::MutedChains;
+use crate::resumption::{ResumptionCache, ResumptionToken};
+use common::internal_connection::{create_ws_connection_to_core, Message};
 use common::{
-    internal_messages::{self, ShardNodeId},
+    internal_messages::{self, MuteReason, NodeCloseReason, ShardNodeId},
     node_message,
     node_types::BlockHash,
     AssignId,
@@ -25,6 +27,8 @@ use futures::{Sink, SinkExt};
 use std::collections::{HashMap, HashSet};
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::Duration;
+use tracing::Instrument;
 
 /// A unique Id is assigned per websocket connection (or more accurately,
 /// per thing-that-subscribes-to-the-aggregator). That connection might send
@@ -36,6 +40,9 @@ type ConnId = u64;
 /// from the telemetry core. This can be private since the only
 /// external messages are via subscriptions that take
 /// [`FromWebsocket`] instances.
+// Node messages make up almost all of the traffic here, so there's no point
+// boxing them to make the rarer variants smaller.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug)]
 enum ToAggregator {
     /// Sent when the telemetry core is disconnected.
@@ -56,10 +63,13 @@ pub enum FromWebsocket {
     /// Fire this when the connection is established.
     Initialize {
         /// When a message is sent back up this channel, we terminate
-        /// the websocket connection and force the node to reconnect
-        /// so that it sends its system info again incase the telemetry
-        /// core has restarted.
-        close_connection: flume::Sender<()>,
+        /// the websocket connection (for the reason given) and force the
+        /// node to reconnect so that it sends its system info again incase
+        /// the telemetry core has restarted or lost track of it.
+        close_connection: flume::Sender<NodeCloseReason>,
+        /// The token that this connection has been given, so that we can recognise the
+        /// nodes it tells us about if they come back via another connection.
+        resumption_token: ResumptionToken,
     },
     /// Tell the aggregator about a new node.
     Add {
@@ -72,9 +82,13 @@ pub enum FromWebsocket {
     Update {
         message_id: node_message::NodeMessageId,
         payload: node_message::Payload,
+        /// When the node says that it sent the message, by its own clock.
+        reported_at: Option<common::node_types::Timestamp>,
+        /// When we received the message from the node.
+        received_at: common::node_types::Timestamp,
     },
-    /// Make a note when the node disconnects.
-    Disconnected,
+    /// Make a note when the node disconnects, and why.
+    Disconnected { reason: NodeCloseReason },
 }
 
 pub type FromAggregator = internal_messages::FromShardAggregator;
@@ -94,11 +108,17 @@ struct AggregatorInternal {
     /// stored here so that anybody holding an `Aggregator` handle can
     /// make use of it.
     tx_to_aggregator: flume::Sender<ToAggregator>,
+    /// The chains that the telemetry core has told us to mute. Node connections
+    /// consult this to drop updates for them as they're parsed.
+    muted_chains: MutedChains,
 }
 
 impl Aggregator {
     /// Spawn a new Aggregator. This connects to the telemetry backend
-    pub async fn spawn(telemetry_uri: http::Uri) -> anyhow::Result<Aggregator> {
+    pub async fn spawn(
+        telemetry_uri: http::Uri,
+        resumption_cache: ResumptionCache,
+    ) -> anyhow::Result<Aggregator> {
         let (tx_to_aggregator, rx_from_external) = flume::bounded(10);
 
         // Establish a resiliant connection to the core (this retries as needed):
@@ -122,15 +142,19 @@ impl Aggregator {
         });
 
         // Start our aggregator loop, handling any incoming messages:
+        let muted_chains = MutedChains::new();
         tokio::spawn(Aggregator::handle_messages(
             rx_from_external,
             tx_to_telemetry_core,
+            resumption_cache,
+            muted_chains.clone(),
         ));
 
         // Return a handle to our aggregator so that we can send in messages to it:
         Ok(Aggregator(Arc::new(AggregatorInternal {
             conn_id: AtomicU64::new(1),
             tx_to_aggregator,
+            muted_chains,
         })))
     }
 
@@ -140,6 +164,8 @@ impl Aggregator {
     async fn handle_messages(
         rx_from_external: flume::Receiver<ToAggregator>,
         tx_to_telemetry_core: flume::Sender<FromAggregator>,
+        mut resumption_cache: ResumptionCache,
+        muted_chains: MutedChains,
     ) {
         use internal_messages::{FromShardAggregator, FromTelemetryCore};
 
@@ -149,7 +175,7 @@ impl Aggregator {
 
         // A list of close channels for the currently connected substrate nodes. Send an empty
         // tuple to these to ask the connections to be closed.
-        let mut close_connections: HashMap<ConnId, flume::Sender<()>> = HashMap::new();
+        let mut close_connections: HashMap<ConnId, flume::Sender<NodeCloseReason>> = HashMap::new();
 
         // Maintain mappings from the connection ID and node message ID to the "local ID" which we
         // broadcast to the telemetry core.
@@ -158,8 +184,12 @@ impl Aggregator {
         // Any messages coming from nodes that have been muted are ignored:
         let mut muted: HashSet<ShardNodeId> = HashSet::new();
 
+        // The resumption token handed to each connection:
+        let mut resumption_tokens: HashMap<ConnId, ResumptionToken> = HashMap::new();
+
         // Now, loop and receive messages to handle.
         while let Ok(msg) = rx_from_external.recv_async().await {
+            resumption_cache.save_if_due();
             match msg {
                 ToAggregator::ConnectedToTelemetryCore => {
                     // Take hold of the connection closers and run them all.
@@ -167,13 +197,15 @@ impl Aggregator {
 
                     for (_, closer) in closers {
                         // if this fails, it probably means the connection has died already anyway.
-                        let _ = closer.send_async(()).await;
+                        let _ = closer.send_async(NodeCloseReason::CoreReconnected).await;
                     }
 
                     // We've told everything to disconnect. Now, reset our state:
                     close_connections = HashMap::new();
                     to_local_id.clear();
                     muted.clear();
+                    // The core tells us about any chains that it still wants muted:
+                    muted_chains.clear();
 
                     connected_to_telemetry_core = true;
                     log::info!("Connected to telemetry core");
@@ -184,12 +216,16 @@ impl Aggregator {
                 }
                 ToAggregator::FromWebsocket(
                     conn_id,
-                    FromWebsocket::Initialize { close_connection },
+                    FromWebsocket::Initialize {
+                        close_connection,
+                        resumption_token,
+                    },
                 ) => {
                     // We boot all connections on a reconnect-to-core to force new systemconnected
                     // messages to be sent. We could boot on muting, but need to be careful not to boot
                     // connections where we mute one set of messages it sends and not others.
                     close_connections.insert(conn_id, close_connection);
+                    resumption_tokens.insert(conn_id, resumption_token);
                 }
                 ToAggregator::FromWebsocket(
                     conn_id,
@@ -209,14 +245,30 @@ impl Aggregator {
                     // Generate a new "local ID" for messages from this connection:
                     let local_id = to_local_id.assign_id((conn_id, message_id));
 
+                    // Nodes that we've seen via another connection (perhaps before we restarted,
+                    // or lost our connection to the core) are resuming:
+                    let resumed = match (resumption_tokens.get(&conn_id), &node.network_id) {
+                        (Some(&token), Some(network_id)) => {
+                            resumption_cache.add(token, genesis_hash, network_id)
+                        }
+                        _ => false,
+                    };
+
                     // Send the message to the telemetry core with this local ID:
+                    let span = tracing::debug_span!(
+                        "forward_to_core",
+                        local_id = ?local_id,
+                        message_type = "system.connected",
+                    );
                     let _ = tx_to_telemetry_core
                         .send_async(FromShardAggregator::AddNode {
                             ip,
                             node,
                             genesis_hash,
                             local_id,
+                            resumed,
                         })
+                        .instrument(span)
                         .await;
                 }
                 ToAggregator::FromWebsocket(
@@ -224,6 +276,8 @@ impl Aggregator {
                     FromWebsocket::Update {
                         message_id,
                         payload,
+                        reported_at,
+                        received_at,
                     },
                 ) => {
                     // Ignore incoming messages if we're not connected to the backend:
@@ -243,11 +297,25 @@ impl Aggregator {
                     }
 
                     // Send the message to the telemetry core with this local ID:
+                    let span = tracing::debug_span!(
+                        "forward_to_core",
+                        local_id = ?local_id,
+                        message_type = payload.name(),
+                    );
                     let _ = tx_to_telemetry_core
-                        .send_async(FromShardAggregator::UpdateNode { local_id, payload })
+                        .send_async(FromShardAggregator::UpdateNode {
+                            local_id,
+                            payload,
+                            reported_at,
+                            received_at,
+                        })
+                        .instrument(span)
                         .await;
                 }
-                ToAggregator::FromWebsocket(disconnected_conn_id, FromWebsocket::Disconnected) => {
+                ToAggregator::FromWebsocket(
+                    disconnected_conn_id,
+                    FromWebsocket::Disconnected { reason },
+                ) => {
                     // Find all of the local IDs corresponding to the disconnected connection ID and
                     // remove them, telling Telemetry Core about them too. This could be more efficient,
                     // but the mapping isn't currently cached and it's not a super frequent op.
@@ -259,20 +327,63 @@ impl Aggregator {
 
                     close_connections.remove(&disconnected_conn_id);
 
+                    // Nodes booted because we reconnected to the core will be back, and should
+                    // be resumed when they are. Otherwise, they've gone for good:
+                    if let Some(token) = resumption_tokens.remove(&disconnected_conn_id) {
+                        if reason != NodeCloseReason::CoreReconnected {
+                            resumption_cache.remove(token);
+                        }
+                    }
+
                     for local_id in local_ids_disconnected {
                         to_local_id.remove_by_id(local_id);
                         muted.remove(&local_id);
                         let _ = tx_to_telemetry_core
-                            .send_async(FromShardAggregator::RemoveNode { local_id })
+                            .send_async(FromShardAggregator::RemoveNode { local_id, reason })
                             .await;
                     }
                 }
-                ToAggregator::FromTelemetryCore(FromTelemetryCore::Mute {
-                    local_id,
-                    reason: _,
-                }) => {
+                ToAggregator::FromTelemetryCore(FromTelemetryCore::Mute { local_id, reason }) => {
                     // Mute the local ID we've been told to:
                     muted.insert(local_id);
+
+                    // If the core is full, there's no point in the node staying connected:
+                    if let MuteReason::TelemetryFull = reason {
+                        let conn_id = match to_local_id.get_details(local_id) {
+                            Some(&(conn_id, _)) => conn_id,
+                            None => continue,
+                        };
+                        if let Some(closer) = close_connections.get(&conn_id) {
+                            // If this fails, the connection is already being closed:
+                            let _ = closer.try_send(NodeCloseReason::TelemetryFull);
+                        }
+                    }
+                }
+                ToAggregator::FromTelemetryCore(FromTelemetryCore::Resync { local_id }) => {
+                    // Close the node's connection so that it reconnects and is added again.
+                    // Its messages are ignored until then, since the core can't use them:
+                    let conn_id = match to_local_id.get_details(local_id) {
+                        Some(&(conn_id, _)) => conn_id,
+                        None => continue,
+                    };
+                    muted.insert(local_id);
+                    if let Some(closer) = close_connections.get(&conn_id) {
+                        // If this fails, the connection is already being closed:
+                        let _ = closer.try_send(NodeCloseReason::Resync);
+                    }
+                }
+                ToAggregator::FromTelemetryCore(FromTelemetryCore::MuteChain {
+                    genesis_hash,
+                    ttl_ms,
+                }) => {
+                    log::info!("Muting chain {:?} for {}ms", genesis_hash, ttl_ms);
+                    muted_chains.mute(genesis_hash, Duration::from_millis(ttl_ms));
+                }
+                ToAggregator::FromTelemetryCore(FromTelemetryCore::UnmuteChain {
+                    genesis_hash,
+                }) => {
+                    log::info!("Unmuting chain {:?}", genesis_hash);
+                    muted_chains.unmute(&genesis_hash);
                 }
             }
         }
@@ -296,4 +407,9 @@ impl Aggregator {
                 .with(move |msg| async move { Ok(ToAggregator::FromWebsocket(conn_id, msg)) }),
         )
     }
+
+    /// The chains that the telemetry core has told us to mute.
+    pub fn muted_chains(&self) -> MutedChains {
+        self.0.muted_chains.clone()
+    }
 }
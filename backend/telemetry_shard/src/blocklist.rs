@@ -0,0 +1,70 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::Context;
+use common::ip_ranges::IpRanges;
+use serde::Deserialize;
+use std::path::Path;
+
+/// The shape of the TOML file that we can load an initial blocklist from:
+///
+/// ```toml
+/// blocked = ["192.168.1.0/24", "2001:db8::/32"]
+/// ```
+#[derive(Deserialize)]
+struct BlocklistFile {
+    #[serde(default)]
+    blocked: Vec<String>,
+}
+
+/// Load a blocklist from a TOML file containing a `blocked` array of CIDR ranges.
+pub fn from_toml_file(path: impl AsRef<Path>) -> anyhow::Result<IpRanges> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read blocklist file {}", path.display()))?;
+    from_toml_str(&contents)
+        .with_context(|| format!("Cannot parse blocklist file {}", path.display()))
+}
+
+/// Parse a blocklist from a TOML string. See [`from_toml_file`].
+pub fn from_toml_str(contents: &str) -> anyhow::Result<IpRanges> {
+    let file: BlocklistFile = toml::from_str(contents)?;
+    let mut blocklist = IpRanges::new();
+    for range in file.blocked {
+        blocklist.insert(range.parse()?);
+    }
+    Ok(blocklist)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn can_load_from_toml() {
+        let b = from_toml_str(
+            r#"
+            blocked = ["192.168.1.0/24", "2001:db8::/32"]
+            "#,
+        )
+        .unwrap();
+
+        let ranges: Vec<String> = b.iter().map(|c| c.to_string()).collect();
+        assert_eq!(ranges, vec!["192.168.1.0/24", "2001:db8::/32"]);
+
+        assert!(from_toml_str(r#"blocked = ["nope"]"#).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::internal_messages::NodeCloseReason;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Counts how many node connections have been closed for each reason.
+/// This is cheap to clone, and every clone shares the same counts.
+#[derive(Clone, Default)]
+pub struct CloseCounts(Arc<[AtomicU64; NodeCloseReason::ALL.len()]>);
+
+impl CloseCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make a note that a connection was closed.
+    pub fn record(&self, reason: NodeCloseReason) {
+        self.0[index(reason)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many connections have been closed for this reason?
+    pub fn get(&self, reason: NodeCloseReason) -> u64 {
+        self.0[index(reason)].load(Ordering::Relaxed)
+    }
+
+    /// The counts in a prometheus-friendly text based format.
+    pub fn to_prometheus(&self) -> String {
+        let mut s = String::new();
+        s.push_str("# TYPE telemetry_shard_node_connections_closed_total counter\n");
+        for reason in NodeCloseReason::ALL.iter().copied() {
+            s.push_str(&format!(
+                "telemetry_shard_node_connections_closed_total{{reason=\"{}\",code=\"{}\"}} {}\n",
+                reason.as_str(),
+                reason.code(),
+                self.get(reason)
+            ));
+        }
+        s
+    }
+}
+
+fn index(reason: NodeCloseReason) -> usize {
+    NodeCloseReason::ALL
+        .iter()
+        .position(|r| *r == reason)
+        .expect("every reason is in NodeCloseReason::ALL")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_are_shared_between_clones() {
+        let counts = CloseCounts::new();
+        let counts2 = counts.clone();
+        counts.record(NodeCloseReason::RateLimited);
+        counts2.record(NodeCloseReason::RateLimited);
+        counts2.record(NodeCloseReason::ClientClosed);
+
+        assert_eq!(counts.get(NodeCloseReason::RateLimited), 2);
+        assert_eq!(counts.get(NodeCloseReason::ClientClosed), 1);
+        assert_eq!(counts.get(NodeCloseReason::Banned), 0);
+    }
+
+    #[test]
+    fn every_reason_is_reported() {
+        let counts = CloseCounts::new();
+        counts.record(NodeCloseReason::Banned);
+        let text = counts.to_prometheus();
+
+        for reason in NodeCloseReason::ALL.iter().copied() {
+            let expected = if reason == NodeCloseReason::Banned {
+                1
+            } else {
+                0
+            };
+            let line = format!(
+                "telemetry_shard_node_connections_closed_total{{reason=\"{}\",code=\"{}\"}} {}",
+                reason.as_str(),
+                reason.code(),
+                expected
+            );
+            assert!(text.contains(&line), "missing line: {}", line);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Everything that can end (or upset) a connection from a node, whichever way the node
+//! connected to us. Each failure maps to a [`NodeCloseReason`], which is what we count in
+//! metrics and hand to the aggregator, and is logged along with the connection it happened on.
+
+use common::internal_messages::NodeCloseReason;
+use std::net::IpAddr;
+
+/// The message that we were trying to hand to the aggregator when something went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Telling the aggregator about a new connection.
+    Initialize,
+    /// Telling the aggregator about a new node on the connection.
+    Add,
+    /// Passing on an update about a node.
+    Update,
+}
+
+impl MessageKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageKind::Initialize => "initialize",
+            MessageKind::Add => "add",
+            MessageKind::Update => "update",
+        }
+    }
+}
+
+/// Details about a connection, to log alongside anything that goes wrong with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionContext {
+    pub addr: IpAddr,
+    /// How many bytes of messages the node has sent us.
+    pub bytes_received: usize,
+    /// How many messages the node has sent us.
+    pub messages_received: usize,
+}
+
+impl ConnectionContext {
+    pub fn new(addr: IpAddr) -> Self {
+        ConnectionContext {
+            addr,
+            bytes_received: 0,
+            messages_received: 0,
+        }
+    }
+}
+
+/// Why a connection from a node ended, or something that went wrong along the way.
+#[derive(thiserror::Error, Debug)]
+pub enum ConnectionError {
+    /// The aggregator asked for the connection to be closed.
+    #[error("Closed by aggregator ({})", .0.as_str())]
+    ClosedByAggregator(NodeCloseReason),
+    /// The node closed the connection.
+    #[error("Closed by node")]
+    ClientClosed,
+    /// The node was sending batches of messages, and stopped for long enough that its
+    /// session expired.
+    #[error("Session expired")]
+    SessionExpired,
+    /// Receiving data from the node failed.
+    #[error("Failed to receive data: {0}")]
+    Receive(anyhow::Error),
+    /// The node sent us more data than it's allowed to.
+    #[error("Too much traffic ({bytes_per_second}bps averaged over last 10s)")]
+    RateLimited { bytes_per_second: usize },
+    /// A message from the node couldn't be parsed. The message is ignored, but the
+    /// connection is left open.
+    #[error("Failed to parse node message: {0}")]
+    Parse(#[from] serde_json::Error),
+    /// The node didn't say which chain it's on, and we're rejecting such nodes.
+    #[error("Node reported no chain")]
+    NoChain,
+    /// A message couldn't be handed to the aggregator.
+    #[error("Failed to send {} message to aggregator: {error}", .kind.as_str())]
+    Forward {
+        kind: MessageKind,
+        error: anyhow::Error,
+    },
+}
+
+impl ConnectionError {
+    /// Why the connection should be closed as a result of this, or `None` if it can stay open.
+    pub fn close_reason(&self) -> Option<NodeCloseReason> {
+        match self {
+            ConnectionError::ClosedByAggregator(reason) => Some(*reason),
+            ConnectionError::ClientClosed => Some(NodeCloseReason::ClientClosed),
+            ConnectionError::SessionExpired => Some(NodeCloseReason::SessionExpired),
+            ConnectionError::Receive(_) => Some(NodeCloseReason::ReceiveError),
+            ConnectionError::RateLimited { .. } => Some(NodeCloseReason::RateLimited),
+            ConnectionError::Parse(_) => None,
+            ConnectionError::NoChain => Some(NodeCloseReason::NoChain),
+            ConnectionError::Forward { .. } => Some(NodeCloseReason::Internal),
+        }
+    }
+
+    /// How loudly this should be logged.
+    pub fn log_level(&self) -> log::Level {
+        match self {
+            ConnectionError::ClosedByAggregator(_)
+            | ConnectionError::ClientClosed
+            | ConnectionError::SessionExpired => log::Level::Info,
+            ConnectionError::Receive(_) | ConnectionError::NoChain => log::Level::Warn,
+            ConnectionError::RateLimited { .. } | ConnectionError::Forward { .. } => {
+                log::Level::Error
+            }
+            ConnectionError::Parse(_) => log::Level::Debug,
+        }
+    }
+
+    /// Log this, along with details of the connection that it happened on.
+    pub fn log(&self, context: &ConnectionContext) {
+        log::log!(
+            self.log_level(),
+            "Node connection from {} ({} messages, {} bytes received): {}",
+            context.addr,
+            context.messages_received,
+            context.bytes_received,
+            self
+        );
+    }
+}
+
+impl From<soketto::connection::Error> for ConnectionError {
+    fn from(e: soketto::connection::Error) -> Self {
+        match e {
+            soketto::connection::Error::Closed => ConnectionError::ClientClosed,
+            e => ConnectionError::Receive(e.into()),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset => {
+                ConnectionError::ClientClosed
+            }
+            _ => ConnectionError::Receive(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn close_reason(e: impl Into<ConnectionError>) -> Option<NodeCloseReason> {
+        e.into().close_reason()
+    }
+
+    #[test]
+    fn websocket_errors_map_to_close_reasons() {
+        assert_eq!(
+            close_reason(soketto::connection::Error::Closed),
+            Some(NodeCloseReason::ClientClosed)
+        );
+        assert_eq!(
+            close_reason(soketto::connection::Error::MessageTooLarge {
+                current: 10,
+                maximum: 5
+            }),
+            Some(NodeCloseReason::ReceiveError)
+        );
+    }
+
+    #[test]
+    fn io_errors_map_to_close_reasons() {
+        use std::io::{Error, ErrorKind};
+        assert_eq!(
+            close_reason(Error::new(ErrorKind::ConnectionReset, "reset")),
+            Some(NodeCloseReason::ClientClosed)
+        );
+        assert_eq!(
+            close_reason(Error::new(ErrorKind::InvalidData, "too long")),
+            Some(NodeCloseReason::ReceiveError)
+        );
+    }
+
+    #[test]
+    fn parse_errors_dont_close_the_connection() {
+        let e = serde_json::from_slice::<serde_json::Value>(b"not json").unwrap_err();
+        let e = ConnectionError::from(e);
+        assert!(matches!(e, ConnectionError::Parse(_)));
+        assert_eq!(e.close_reason(), None);
+        assert_eq!(e.log_level(), log::Level::Debug);
+    }
+
+    #[test]
+    fn our_failures_map_to_close_reasons_and_labels() {
+        let rate_limited = ConnectionError::RateLimited {
+            bytes_per_second: 1_000_000,
+        };
+        assert_eq!(
+            rate_limited.close_reason().map(NodeCloseReason::as_str),
+            Some("rate_limited")
+        );
+        assert_eq!(rate_limited.log_level(), log::Level::Error);
+
+        let forward = ConnectionError::Forward {
+            kind: MessageKind::Initialize,
+            error: anyhow::anyhow!("aggregator gone"),
+        };
+        assert_eq!(forward.close_reason(), Some(NodeCloseReason::Internal));
+        assert_eq!(
+            forward.to_string(),
+            "Failed to send initialize message to aggregator: aggregator gone"
+        );
+
+        let closed = ConnectionError::ClosedByAggregator(NodeCloseReason::Resync);
+        assert_eq!(closed.close_reason(), Some(NodeCloseReason::Resync));
+        assert_eq!(closed.log_level(), log::Level::Info);
+    }
+}
@@ -0,0 +1,340 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Some nodes run in environments that can't hold a websocket open, but can make
+//! periodic HTTP requests. These nodes POST batches of the usual JSON telemetry messages
+//! to `/submit/batch`, along with a session ID of their choosing. Each session is treated
+//! as a connection: its messages are handled exactly as those from a websocket would be,
+//! and once we stop hearing from it for a while, it's closed and its nodes are removed.
+
+use crate::aggregator::{Aggregator, FromWebsocket};
+use crate::blocked_addrs::BlockedAddrs;
+use crate::close_counts::CloseCounts;
+use crate::connection_error::{ConnectionContext, ConnectionError};
+use crate::muted_chains::MutedChains;
+use crate::node_connection::{self, NodeConnection, NodeConnectionLimits};
+use common::http_utils;
+use common::internal_messages::NodeCloseReason;
+use hyper::{Body, Response};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The ID that a node gives to group its batches into a session. This is a UUID
+/// in its usual hyphenated form, eg `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId([u8; 16]);
+
+impl FromStr for SessionId {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let groups: Vec<&str> = s.split('-').collect();
+        let group_lens: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+        if group_lens != [8, 4, 4, 4, 12] {
+            anyhow::bail!("Session ID '{}' is not a UUID", s);
+        }
+        let mut bytes = [0; 16];
+        hex::decode_to_slice(groups.concat(), &mut bytes)
+            .map_err(|_| anyhow::anyhow!("Session ID '{}' is not a UUID", s))?;
+        Ok(SessionId(bytes))
+    }
+}
+
+/// The body of a request to `/submit/batch`.
+#[derive(Deserialize)]
+struct BatchRequest<'a> {
+    session: &'a str,
+    #[serde(borrow)]
+    messages: Vec<&'a RawValue>,
+}
+
+/// A batch of messages handed to a session, along with a way to tell the
+/// request that submitted them how it went.
+struct Batch {
+    messages: Vec<Box<[u8]>>,
+    result: flume::Sender<Result<(), NodeCloseReason>>,
+}
+
+struct Session {
+    real_addr: IpAddr,
+    tx: flume::Sender<Batch>,
+}
+
+/// The sessions that nodes submitting batches of telemetry have open with us.
+#[derive(Clone)]
+pub struct BatchSessions(Arc<BatchSessionsInner>);
+
+struct BatchSessionsInner {
+    aggregator: Aggregator,
+    limits: NodeConnectionLimits,
+    block_list: BlockedAddrs,
+    close_counts: CloseCounts,
+    inactivity_timeout: Duration,
+    sessions: Mutex<HashMap<SessionId, Session>>,
+}
+
+impl BatchSessions {
+    pub fn new(
+        aggregator: Aggregator,
+        limits: NodeConnectionLimits,
+        block_list: BlockedAddrs,
+        close_counts: CloseCounts,
+        inactivity_timeout: Duration,
+    ) -> Self {
+        BatchSessions(Arc::new(BatchSessionsInner {
+            aggregator,
+            limits,
+            block_list,
+            close_counts,
+            inactivity_timeout,
+            sessions: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Handle the body of a `/submit/batch` request from the given address, opening a
+    /// new session for it if need be, and respond once every message has been handled.
+    pub async fn submit(&self, real_addr: IpAddr, body: &[u8]) -> Response<Body> {
+        let req: BatchRequest = match serde_json::from_slice(body) {
+            Ok(req) => req,
+            Err(e) => return http_utils::basic_response(400, e.to_string()),
+        };
+        let session_id: SessionId = match req.session.parse() {
+            Ok(id) => id,
+            Err(e) => return http_utils::basic_response(400, e.to_string()),
+        };
+
+        let tx = {
+            let mut sessions = self.0.sessions.lock().unwrap();
+            match sessions.get(&session_id) {
+                // Don't let one address submit messages as though they're from another:
+                Some(session) if session.real_addr != real_addr => {
+                    return http_utils::basic_response(403, "Session belongs to another address");
+                }
+                Some(session) => session.tx.clone(),
+                None => {
+                    let tx = self.open_session(session_id, real_addr);
+                    sessions.insert(
+                        session_id,
+                        Session {
+                            real_addr,
+                            tx: tx.clone(),
+                        },
+                    );
+                    tx
+                }
+            }
+        };
+
+        let (result_tx, result_rx) = flume::bounded(1);
+        let batch = Batch {
+            messages: req
+                .messages
+                .into_iter()
+                .map(|msg| msg.get().as_bytes().into())
+                .collect(),
+            result: result_tx,
+        };
+        // If the session closed before it handled our batch, the node can try again
+        // and a new session will be opened for it.
+        if tx.send_async(batch).await.is_err() {
+            return http_utils::basic_response(503, "Session closed");
+        }
+        match result_rx.recv_async().await {
+            Ok(Ok(())) => http_utils::basic_response(200, "OK"),
+            Ok(Err(reason)) => http_utils::basic_response(403, reason.as_str()),
+            Err(_) => http_utils::basic_response(503, "Session closed"),
+        }
+    }
+
+    /// Spawn a task to handle the batches sent to a new session, handing back a
+    /// channel to send them to it.
+    fn open_session(&self, session_id: SessionId, real_addr: IpAddr) -> flume::Sender<Batch> {
+        let (tx, rx) = flume::unbounded();
+        let this = self.clone();
+        tokio::spawn(async move {
+            log::info!("Opening /submit/batch session from {:?}", real_addr);
+            let tx_to_aggregator = this.0.aggregator.subscribe_node();
+            let (mut tx_to_aggregator, error, context) = handle_batch_session(
+                real_addr,
+                rx,
+                tx_to_aggregator,
+                this.0.limits,
+                this.0.block_list.clone(),
+                this.0.aggregator.muted_chains(),
+                this.0.inactivity_timeout,
+            )
+            .await;
+            // Forget the session first, so that any further batches open a new one:
+            this.0.sessions.lock().unwrap().remove(&session_id);
+            node_connection::close(
+                "/submit/batch",
+                error,
+                context,
+                &this.0.close_counts,
+                &mut tx_to_aggregator,
+            )
+            .await;
+        });
+        tx
+    }
+}
+
+/// Handle the batches sent to a session until it closes, and hand back why it closed.
+async fn handle_batch_session<S>(
+    real_addr: IpAddr,
+    batches: flume::Receiver<Batch>,
+    mut tx_to_aggregator: S,
+    limits: NodeConnectionLimits,
+    block_list: BlockedAddrs,
+    muted_chains: MutedChains,
+    inactivity_timeout: Duration,
+) -> (S, ConnectionError, ConnectionContext)
+where
+    S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin,
+{
+    let close_connection_rx = match node_connection::initialize(&mut tx_to_aggregator).await {
+        Ok((rx, _)) => rx,
+        Err(e) => return (tx_to_aggregator, e, ConnectionContext::new(real_addr)),
+    };
+    let mut conn = NodeConnection::new(real_addr, limits, block_list, muted_chains);
+
+    let error = loop {
+        tokio::select! {
+            reason = close_connection_rx.recv_async() => {
+                break ConnectionError::ClosedByAggregator(reason.unwrap_or(NodeCloseReason::CoreReconnected))
+            },
+            batch = tokio::time::timeout(inactivity_timeout, batches.recv_async()) => {
+                let batch = match batch {
+                    Ok(Ok(batch)) => batch,
+                    Ok(Err(_)) => break ConnectionError::ClientClosed,
+                    Err(_) => break ConnectionError::SessionExpired,
+                };
+
+                let mut error = None;
+                for bytes in batch.messages.iter() {
+                    if let Err(e) = conn.handle_message(bytes, &mut tx_to_aggregator).await {
+                        error = Some(e);
+                        break;
+                    }
+                }
+                let reason = error.as_ref().and_then(|e| e.close_reason());
+                let _ = batch.result.send(reason.map_or(Ok(()), Err));
+                if let Some(e) = error {
+                    break e;
+                }
+            }
+        }
+    };
+
+    (tx_to_aggregator, error, conn.context())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::byte_size::ByteSize;
+    use futures::SinkExt;
+
+    fn limits() -> NodeConnectionLimits {
+        NodeConnectionLimits {
+            max_nodes_per_connection: 20,
+            bytes_per_second: "256k".parse::<ByteSize>().unwrap(),
+            reject_empty_chain: false,
+            max_node_name_len: 64,
+        }
+    }
+
+    const CONNECTED: &str = r#"{"id":1,"ts":"2021-07-12T10:37:47.714666+01:00","payload":{"authority":true,"chain":"Local Testnet","config":"","genesis_hash":"0x0000000000000000000000000000000000000000000000000000000000000001","implementation":"Substrate Node","msg":"system.connected","name":"Alice","network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp","startup_time":"1625565542717","version":"2.0.0"}}"#;
+    const INTERVAL: &str = r#"{"id":1,"ts":"2021-07-12T10:37:48.330433+01:00","payload":{"bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1}}"#;
+
+    fn batch(messages: &[&str]) -> (Batch, flume::Receiver<Result<(), NodeCloseReason>>) {
+        let (result_tx, result_rx) = flume::bounded(1);
+        let batch = Batch {
+            messages: messages.iter().map(|m| m.as_bytes().into()).collect(),
+            result: result_tx,
+        };
+        (batch, result_rx)
+    }
+
+    #[test]
+    fn session_ids_must_be_uuids() {
+        assert!("67e55044-10b1-426f-9247-bb680e5fe0c8"
+            .parse::<SessionId>()
+            .is_ok());
+        assert!("67e5504410b1426f9247bb680e5fe0c8"
+            .parse::<SessionId>()
+            .is_err());
+        assert!("67e55044-10b1-426f-9247-bb680e5fe0cZ"
+            .parse::<SessionId>()
+            .is_err());
+        assert!("".parse::<SessionId>().is_err());
+    }
+
+    #[test]
+    fn batch_request_keeps_messages_as_raw_json() {
+        let body = format!(
+            r#"{{"session":"67e55044-10b1-426f-9247-bb680e5fe0c8","messages":[{},{}]}}"#,
+            CONNECTED, INTERVAL
+        );
+        let req: BatchRequest = serde_json::from_str(&body).unwrap();
+        assert_eq!(req.messages.len(), 2);
+        assert_eq!(req.messages[1].get(), INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn batches_are_handled_like_websocket_messages_until_session_expires() {
+        let (tx, rx) = flume::unbounded();
+        let tx = tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e));
+        let (batch_tx, batch_rx) = flume::unbounded();
+
+        let (first, first_result) = batch(&[CONNECTED, "not json"]);
+        let (second, second_result) = batch(&[INTERVAL]);
+        batch_tx.send(first).unwrap();
+        batch_tx.send(second).unwrap();
+
+        let (_, error, context) = handle_batch_session(
+            "127.0.0.1".parse().unwrap(),
+            batch_rx,
+            tx,
+            limits(),
+            BlockedAddrs::new(Duration::from_secs(60)),
+            MutedChains::new(),
+            Duration::from_millis(50),
+        )
+        .await;
+
+        assert_eq!(error.close_reason(), Some(NodeCloseReason::SessionExpired));
+        assert_eq!(context.messages_received, 3);
+        assert_eq!(first_result.recv().unwrap(), Ok(()));
+        assert_eq!(second_result.recv().unwrap(), Ok(()));
+
+        let msgs: Vec<_> = rx.drain().collect();
+        assert_eq!(msgs.len(), 3, "{:?}", msgs);
+        assert!(matches!(msgs[0], FromWebsocket::Initialize { .. }));
+        assert!(
+            matches!(&msgs[1], FromWebsocket::Add { node, .. } if &*node.name == "Alice"),
+            "{:?}",
+            msgs[1]
+        );
+        assert!(matches!(msgs[2], FromWebsocket::Update { .. }));
+        // Keep the sender alive until now, so the session expires rather than closing:
+        drop(batch_tx);
+    }
+}
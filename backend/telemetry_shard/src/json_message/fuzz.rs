@@ -0,0 +1,147 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Fuzz targets for the JSON that nodes send us. See [`test_utils::fuzz`] for how
+//! to run these for longer than the smoke run that happens with the other tests.
+
+use super::NodeMessage;
+use common::node_message as internal;
+use test_utils::fuzz;
+
+/// Parsing a message can allocate several times its size. The worst case is extra
+/// info made up of lots of tiny JSON objects, since each one gets a map node of its
+/// own, but it should never be more than this per input byte.
+const BUDGET_PER_BYTE: usize = 128;
+/// And on top of that, a little fixed overhead.
+const BUDGET_OVERHEAD: usize = 64 * 1024;
+
+const HASH: &str = "0x031c3521ca2f9c673812d692fc330b9a18e18a2781e3f9976992f861fd3ea0cb";
+
+/// One of each payload that we understand, so that mutations explore all of them.
+fn payloads() -> Vec<String> {
+    vec![
+        format!(
+            r#""msg":"system.connected","genesis_hash":"{}","chain":"Polkadot","name":"Alice","implementation":"Parity Polkadot","version":"0.9.0","authority":true,"validator":"5Gr","network_id":"12D3KooW","startup_time":"1625565542717","chain_type":"relay_chain","environment":"prod","pruning_mode":256,"offchain_indexing":true,"extra_info":{{"a":[1,{{"b":2}}]}}"#,
+            HASH
+        ),
+        format!(
+            r#""msg":"system.interval","peers":5,"txcount":2,"bandwidth_upload":1.5,"bandwidth_download":2.5,"finalized_height":100,"finalized_hash":"{0}","best":"{0}","height":102,"used_state_cache_size":12.5,"wasm_heap_used_bytes":1024,"wasm_heap_limit_bytes":4096,"cpu_cores":8,"open_fd_count":100,"fd_limit":1024,"ntp_offset_ms":-12"#,
+            HASH
+        ),
+        format!(r#""msg":"block.import","best":"{}","height":5"#, HASH),
+        format!(r#""msg":"notify.finalized","best":"{}","height":"50""#, HASH),
+        r#""msg":"txpool.import","ready":1"#.to_string(),
+        format!(
+            r#""msg":"afg.finalized","finalized_hash":"{}","finalized_number":"50""#,
+            HASH
+        ),
+        format!(
+            r#""msg":"afg.received_precommit","target_hash":"{}","target_number":"50","voter":"foo""#,
+            HASH
+        ),
+        r#""msg":"afg.authority_set","authority_id":"a","authorities":"[a,b]","authority_set_id":"1""#
+            .to_string(),
+        r#""msg":"prepared_block_for_proposing","import_latency_ms":12"#.to_string(),
+    ]
+}
+
+/// Messages in the current format: `{"id":_,"ts":_,"payload":{..}}`.
+fn v2_seeds() -> Vec<String> {
+    payloads()
+        .iter()
+        .map(|p| {
+            format!(
+                r#"{{"id":1,"ts":"2021-01-13T12:38:25.410Z","payload":{{{}}}}}"#,
+                p
+            )
+        })
+        .collect()
+}
+
+/// Messages in the legacy format, where the payload is flattened into the envelope.
+fn v1_seeds() -> Vec<String> {
+    payloads()
+        .iter()
+        .map(|p| {
+            format!(
+                r#"{{"ts":"2021-01-13T12:38:25.410794650+01:00","level":"INFO",{}}}"#,
+                p
+            )
+        })
+        .collect()
+}
+
+/// Parse a message, checking that we stay within our allocation budget and that anything
+/// we accept can be sent on to the core and decoded there.
+fn parse(bytes: &[u8]) {
+    let (msg, allocated) =
+        fuzz::bytes_allocated_during(|| serde_json::from_slice::<NodeMessage>(bytes));
+    fuzz::assert_within_budget(bytes, allocated, BUDGET_PER_BYTE, BUDGET_OVERHEAD);
+
+    let msg = match msg {
+        Ok(msg) => msg,
+        Err(_) => return,
+    };
+    let _ = msg.reported_at();
+    let msg: internal::NodeMessage = msg.into();
+
+    // Shards send messages on to the core with bincode, so that has to round trip:
+    let encoded = bincode::serialize(&msg).expect("accepted messages can be encoded");
+    let decoded: internal::NodeMessage =
+        bincode::deserialize(&encoded).expect("accepted messages can be decoded");
+    assert_eq!(
+        bincode::serialize(&decoded).unwrap(),
+        encoded,
+        "{:?} didn't round trip",
+        String::from_utf8_lossy(bytes)
+    );
+}
+
+#[test]
+fn fuzz_node_messages() {
+    let seeds = v2_seeds();
+    let seeds: Vec<&str> = seeds.iter().map(|s| &**s).collect();
+    fuzz::run(&seeds, parse);
+}
+
+#[test]
+fn fuzz_legacy_envelopes() {
+    let seeds = v1_seeds();
+    let seeds: Vec<&str> = seeds.iter().map(|s| &**s).collect();
+    fuzz::run(&seeds, parse);
+}
+
+#[test]
+fn seeds_are_valid_messages() {
+    for seed in v2_seeds() {
+        let msg = serde_json::from_str::<NodeMessage>(&seed);
+        assert!(
+            matches!(msg, Ok(NodeMessage::V2 { .. })),
+            "{}: {:?}",
+            seed,
+            msg
+        );
+    }
+    for seed in v1_seeds() {
+        let msg = serde_json::from_str::<NodeMessage>(&seed);
+        assert!(
+            matches!(msg, Ok(NodeMessage::V1 { .. })),
+            "{}: {:?}",
+            seed,
+            msg
+        );
+    }
+}
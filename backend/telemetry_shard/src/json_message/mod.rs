@@ -16,6 +16,8 @@
 
 //! This module contains the types we need to deserialize JSON messages from nodes
 
+#[cfg(test)]
+mod fuzz;
 mod hash;
 mod node_message;
 
@@ -23,6 +23,7 @@ use super::hash::Hash;
 use common::node_message as internal;
 use common::node_types;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 
 /// This struct represents a telemetry message sent from a node as
 /// a JSON payload. Since JSON is self describing, we can use attributes
@@ -40,22 +41,83 @@ use serde::Deserialize;
 #[serde(untagged)]
 pub enum NodeMessage {
     V1 {
+        #[serde(default)]
+        ts: ReportedTime,
         #[serde(flatten)]
         payload: Payload,
     },
     V2 {
         id: NodeMessageId,
+        #[serde(default)]
+        ts: ReportedTime,
         payload: Payload,
     },
 }
 
+impl NodeMessage {
+    /// When the node says that it sent this message, according to its own clock.
+    pub fn reported_at(&self) -> Option<node_types::Timestamp> {
+        match self {
+            NodeMessage::V1 { ts, .. } | NodeMessage::V2 { ts, .. } => ts.0,
+        }
+    }
+
+    /// The shape of this message, which tells us roughly how old the node sending it is.
+    pub fn schema(&self) -> node_types::MessageSchema {
+        match self {
+            NodeMessage::V1 { .. } => node_types::MessageSchema::V1,
+            NodeMessage::V2 { .. } => node_types::MessageSchema::V2,
+        }
+    }
+}
+
+/// The `ts` that nodes send along with each message, as a unix timestamp in
+/// milliseconds. A timestamp that we can't make sense of is treated as missing
+/// rather than causing the message to be rejected.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReportedTime(Option<node_types::Timestamp>);
+
+impl<'de> Deserialize<'de> for ReportedTime {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct ReportedTimeVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ReportedTimeVisitor {
+            type Value = ReportedTime;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an RFC 3339 timestamp")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<ReportedTime, E> {
+                let parsed = time::OffsetDateTime::parse(
+                    value,
+                    &time::format_description::well_known::Rfc3339,
+                );
+                let millis = parsed.ok().and_then(|t| {
+                    std::convert::TryFrom::try_from(t.unix_timestamp_nanos() / 1_000_000).ok()
+                });
+                Ok(ReportedTime(millis))
+            }
+
+            fn visit_unit<E: serde::de::Error>(self) -> Result<ReportedTime, E> {
+                Ok(ReportedTime(None))
+            }
+        }
+
+        deserializer.deserialize_any(ReportedTimeVisitor)
+    }
+}
+
 impl From<NodeMessage> for internal::NodeMessage {
     fn from(msg: NodeMessage) -> Self {
         match msg {
-            NodeMessage::V1 { payload } => internal::NodeMessage::V1 {
+            NodeMessage::V1 { payload, .. } => internal::NodeMessage::V1 {
                 payload: payload.into(),
             },
-            NodeMessage::V2 { id, payload } => internal::NodeMessage::V2 {
+            NodeMessage::V2 { id, payload, .. } => internal::NodeMessage::V2 {
                 id,
                 payload: payload.into(),
             },
@@ -91,7 +153,7 @@ pub enum Payload {
     #[serde(rename = "aura.pre_sealed_block")]
     AuraPreSealedBlock,
     #[serde(rename = "prepared_block_for_proposing")]
-    PreparedBlockForProposing,
+    PreparedBlockForProposing(PreparedBlock),
 }
 
 impl From<Payload> for internal::Payload {
@@ -109,7 +171,9 @@ impl From<Payload> for internal::Payload {
             Payload::AfgAuthoritySet(m) => internal::Payload::AfgAuthoritySet(m.into()),
             Payload::AfgFinalizedBlocksUpTo => internal::Payload::AfgFinalizedBlocksUpTo,
             Payload::AuraPreSealedBlock => internal::Payload::AuraPreSealedBlock,
-            Payload::PreparedBlockForProposing => internal::Payload::PreparedBlockForProposing,
+            Payload::PreparedBlockForProposing(m) => {
+                internal::Payload::PreparedBlockForProposing(m.into())
+            }
         }
     }
 }
@@ -130,6 +194,19 @@ impl From<SystemConnected> for internal::SystemConnected {
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct PreparedBlock {
+    pub import_latency_ms: Option<u32>,
+}
+
+impl From<PreparedBlock> for internal::PreparedBlock {
+    fn from(msg: PreparedBlock) -> Self {
+        internal::PreparedBlock {
+            import_latency_ms: msg.import_latency_ms,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct SystemInterval {
     pub peers: Option<u64>,
@@ -141,6 +218,39 @@ pub struct SystemInterval {
     #[serde(flatten)]
     pub block: Option<Block>,
     pub used_state_cache_size: Option<f32>,
+    pub offchain_worker_queue_depth: Option<u32>,
+    pub swap_used_bytes: Option<u64>,
+    pub swap_total_bytes: Option<u64>,
+    pub wasm_heap_used_bytes: Option<u64>,
+    pub wasm_heap_limit_bytes: Option<u64>,
+    pub cpu_steal_pct: Option<f32>,
+    pub kademlia_queries_per_sec: Option<f32>,
+    pub kademlia_records_stored: Option<u32>,
+    /// How many of each kind of notification/request the node receives from its peers each second.
+    pub block_announce_per_sec: Option<f32>,
+    pub transaction_per_sec: Option<f32>,
+    pub light_request_per_sec: Option<f32>,
+    /// The Unix load averages of the node's machine, and how many CPU cores it has.
+    pub load_avg_1m: Option<f32>,
+    pub load_avg_5m: Option<f32>,
+    pub load_avg_15m: Option<f32>,
+    pub cpu_cores: Option<u32>,
+    /// How large the node's database is on disk, in bytes.
+    pub database_size_bytes: Option<u64>,
+    /// How many file descriptors the node has open, and how many it's allowed to.
+    pub open_fd_count: Option<u32>,
+    pub fd_limit: Option<u32>,
+    /// How far the node's clock is from NTP time, in milliseconds. Negative if it's behind.
+    pub ntp_offset_ms: Option<i32>,
+    /// The average reputation score that the node's peers have given it.
+    pub avg_peer_reputation: Option<i32>,
+    /// The GPU the node uses to accelerate its work (for instance, verifying ZK proofs),
+    /// how busy it is as a percentage, and how much of its memory is in use.
+    pub gpu_name: Option<Box<str>>,
+    pub gpu_usage_pct: Option<f32>,
+    pub gpu_memory_used_bytes: Option<u64>,
+    /// How many bytes of the state trie each pallet takes up, by pallet name.
+    pub pallet_trie_sizes: Option<BTreeMap<Box<str>, u64>>,
 }
 
 impl From<SystemInterval> for internal::SystemInterval {
@@ -154,22 +264,69 @@ impl From<SystemInterval> for internal::SystemInterval {
             finalized_hash: msg.finalized_hash.map(|h| h.into()),
             block: msg.block.map(|b| b.into()),
             used_state_cache_size: msg.used_state_cache_size,
+            offchain_worker_queue_depth: msg.offchain_worker_queue_depth,
+            swap_used_bytes: msg.swap_used_bytes,
+            swap_total_bytes: msg.swap_total_bytes,
+            wasm_heap_used_bytes: msg.wasm_heap_used_bytes,
+            wasm_heap_limit_bytes: msg.wasm_heap_limit_bytes,
+            cpu_steal_pct: msg.cpu_steal_pct,
+            kademlia_queries_per_sec: msg.kademlia_queries_per_sec,
+            kademlia_records_stored: msg.kademlia_records_stored,
+            block_announce_per_sec: msg.block_announce_per_sec,
+            transaction_per_sec: msg.transaction_per_sec,
+            light_request_per_sec: msg.light_request_per_sec,
+            load_avg_1m: msg.load_avg_1m,
+            load_avg_5m: msg.load_avg_5m,
+            load_avg_15m: msg.load_avg_15m,
+            cpu_cores: msg.cpu_cores,
+            database_size_bytes: msg.database_size_bytes,
+            open_fd_count: msg.open_fd_count,
+            fd_limit: msg.fd_limit,
+            ntp_offset_ms: msg.ntp_offset_ms,
+            avg_peer_reputation: msg.avg_peer_reputation,
+            gpu_name: msg.gpu_name,
+            gpu_usage_pct: msg.gpu_usage_pct,
+            gpu_memory_used_bytes: msg.gpu_memory_used_bytes,
+            pallet_trie_sizes: msg.pallet_trie_sizes.map(largest_pallets),
         }
     }
 }
 
+/// Keep only the pallets taking up the most of the state trie, largest first.
+fn largest_pallets(sizes: BTreeMap<Box<str>, u64>) -> node_types::PalletTrieSizes {
+    let mut sizes: Vec<_> = sizes.into_iter().collect();
+    sizes.sort_by(|(a_name, a_size), (b_name, b_size)| {
+        b_size.cmp(a_size).then_with(|| a_name.cmp(b_name))
+    });
+    sizes.truncate(node_types::MAX_PALLET_TRIE_SIZES);
+    sizes.into_boxed_slice()
+}
+
+/// Older nodes report their finalized block as `best` with a string `height`, whereas
+/// newer ones use `hash` and a numeric `height`. We accept either.
 #[derive(Deserialize, Debug)]
 pub struct Finalized {
-    #[serde(rename = "best")]
+    #[serde(rename = "best", alias = "hash")]
     pub hash: Hash,
-    pub height: Box<str>,
+    pub height: FinalizedHeight,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum FinalizedHeight {
+    Number(node_types::BlockNumber),
+    String(Box<str>),
 }
 
 impl From<Finalized> for internal::Finalized {
     fn from(msg: Finalized) -> Self {
+        let height = match msg.height {
+            FinalizedHeight::Number(n) => n.to_string().into_boxed_str(),
+            FinalizedHeight::String(s) => s,
+        };
         internal::Finalized {
             hash: msg.hash.into(),
-            height: msg.height,
+            height,
         }
     }
 }
@@ -248,10 +405,34 @@ pub struct NodeDetails {
     pub validator: Option<Box<str>>,
     pub network_id: Option<Box<str>>,
     pub startup_time: Option<Box<str>>,
+    #[serde(default)]
+    pub chain_type: Option<Box<str>>,
+    #[serde(default)]
+    pub environment: Option<Box<str>>,
+    #[serde(default)]
+    pub pruning_mode: Option<u32>,
+    #[serde(default)]
+    pub offchain_indexing: Option<bool>,
+    #[serde(default)]
+    pub pending_upgrade_block: Option<BlockNumber>,
+    #[serde(default)]
+    pub verbosity: Option<u8>,
+    #[serde(default)]
+    pub publicly_reachable: Option<bool>,
+    #[serde(default)]
+    pub extra_info: HashMap<Box<str>, serde_json::Value>,
 }
 
 impl From<NodeDetails> for node_types::NodeDetails {
     fn from(details: NodeDetails) -> Self {
+        // Prefer the chain type that the node tells us about, but fall back to
+        // working it out from the chain name if we can:
+        let chain_type = details
+            .chain_type
+            .as_deref()
+            .and_then(node_types::ChainType::from_str)
+            .or_else(|| node_types::ChainType::from_str(&details.chain));
+
         node_types::NodeDetails {
             chain: details.chain,
             name: details.name,
@@ -260,6 +441,17 @@ impl From<NodeDetails> for node_types::NodeDetails {
             validator: details.validator,
             network_id: details.network_id,
             startup_time: details.startup_time,
+            chain_type,
+            environment: details.environment,
+            pruning_mode: details
+                .pruning_mode
+                .map(node_types::PruningMode::from_keep_blocks),
+            offchain_indexing: details.offchain_indexing.unwrap_or(false),
+            pending_upgrade_block: details.pending_upgrade_block,
+            verbosity: details.verbosity,
+            publicly_reachable: details.publicly_reachable,
+            message_schema: None,
+            extra_info: node_types::bound_extra_info(details.extra_info),
         }
     }
 }
@@ -289,6 +481,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn both_finalized_formats_are_parsed() {
+        let finalized = |json: &str| match serde_json::from_str::<NodeMessage>(json).unwrap() {
+            NodeMessage::V1 {
+                payload: Payload::NotifyFinalized(f),
+                ..
+            } => internal::Finalized::from(f),
+            msg => panic!("unexpected message: {:?}", msg),
+        };
+
+        let old = finalized(
+            r#"{"msg":"notify.finalized","best":"0x031c3521ca2f9c673812d692fc330b9a18e18a2781e3f9976992f861fd3ea0cb","height":"50"}"#,
+        );
+        let new = finalized(
+            r#"{"msg":"notify.finalized","hash":"0x031c3521ca2f9c673812d692fc330b9a18e18a2781e3f9976992f861fd3ea0cb","height":50}"#,
+        );
+        assert_eq!(&*old.height, "50");
+        assert_eq!(&*new.height, "50");
+        assert_eq!(old.hash, new.hash);
+    }
+
+    #[test]
+    fn reported_at_is_parsed_from_ts() {
+        let reported_at = |json: &str| {
+            serde_json::from_str::<NodeMessage>(json)
+                .unwrap()
+                .reported_at()
+        };
+
+        assert_eq!(
+            reported_at(
+                r#"{"msg":"block.import","ts":"2021-01-13T12:38:25.410794650+01:00","best":"0x031c3521ca2f9c673812d692fc330b9a18e18a2781e3f9976992f861fd3ea0cb","height":5}"#
+            ),
+            Some(1610537905410)
+        );
+        assert_eq!(
+            reported_at(
+                r#"{"id":1,"ts":"2021-01-13T11:38:25.410Z","payload":{"msg":"block.import","best":"0x031c3521ca2f9c673812d692fc330b9a18e18a2781e3f9976992f861fd3ea0cb","height":5}}"#
+            ),
+            Some(1610537905410)
+        );
+        // A missing or nonsensical timestamp doesn't stop the message being handled:
+        assert_eq!(
+            reported_at(
+                r#"{"id":1,"payload":{"msg":"block.import","best":"0x031c3521ca2f9c673812d692fc330b9a18e18a2781e3f9976992f861fd3ea0cb","height":5}}"#
+            ),
+            None
+        );
+        assert_eq!(
+            reported_at(
+                r#"{"id":1,"ts":"yesterday","payload":{"msg":"block.import","best":"0x031c3521ca2f9c673812d692fc330b9a18e18a2781e3f9976992f861fd3ea0cb","height":5}}"#
+            ),
+            None
+        );
+    }
+
     #[test]
     fn message_v2() {
         let json = r#"{
@@ -357,4 +605,539 @@ mod tests {
             "message did not match the expected output",
         );
     }
+
+    #[test]
+    fn message_v2_system_interval_with_offchain_worker_queue_depth() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "txcount":0,
+                "offchain_worker_queue_depth":12,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        offchain_worker_queue_depth: Some(12),
+                        ..
+                    }),
+                    ..
+                },
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_wasm_heap() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "wasm_heap_used_bytes":1024,
+                "wasm_heap_limit_bytes":4096,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        wasm_heap_used_bytes: Some(1024),
+                        wasm_heap_limit_bytes: Some(4096),
+                        ..
+                    }),
+                    ..
+                },
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_without_wasm_heap() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        wasm_heap_used_bytes: None,
+                        wasm_heap_limit_bytes: None,
+                        ..
+                    }),
+                    ..
+                },
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_swap() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "swap_used_bytes":1024,
+                "swap_total_bytes":4096,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        swap_used_bytes: Some(1024),
+                        swap_total_bytes: Some(4096),
+                        ..
+                    }),
+                    ..
+                },
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_cpu_steal() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "cpu_steal_pct":7.5,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        cpu_steal_pct: Some(steal),
+                        ..
+                    }),
+                    ..
+                } if steal == 7.5,
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_kademlia() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "kademlia_queries_per_sec":12.5,
+                "kademlia_records_stored":340,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        kademlia_queries_per_sec: Some(queries),
+                        kademlia_records_stored: Some(340),
+                        ..
+                    }),
+                    ..
+                } if queries == 12.5,
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_p2p_message_rates() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "block_announce_per_sec":0.5,
+                "transaction_per_sec":20.25,
+                "light_request_per_sec":150,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        block_announce_per_sec: Some(announces),
+                        transaction_per_sec: Some(txs),
+                        light_request_per_sec: Some(light),
+                        ..
+                    }),
+                    ..
+                } if announces == 0.5 && txs == 20.25 && light == 150.0,
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_database_size() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "database_size_bytes":1099511627777,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        database_size_bytes: Some(1_099_511_627_777),
+                        ..
+                    }),
+                    ..
+                }
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_load_average() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "load_avg_1m":3.5,
+                "load_avg_5m":2.25,
+                "load_avg_15m":1,
+                "cpu_cores":4,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        load_avg_1m: Some(one),
+                        load_avg_5m: Some(five),
+                        load_avg_15m: Some(fifteen),
+                        cpu_cores: Some(4),
+                        ..
+                    }),
+                    ..
+                } if one == 3.5 && five == 2.25 && fifteen == 1.0,
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_file_descriptors() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "open_fd_count":900,
+                "fd_limit":1024,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        open_fd_count: Some(900),
+                        fd_limit: Some(1024),
+                        ..
+                    }),
+                    ..
+                },
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_negative_ntp_offset() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "ntp_offset_ms":-120,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        ntp_offset_ms: Some(-120),
+                        ..
+                    }),
+                    ..
+                },
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_peer_reputation() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "avg_peer_reputation":-2500,
+                "msg":"system.interval"
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        avg_peer_reputation: Some(-2500),
+                        ..
+                    }),
+                    ..
+                },
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_system_interval_with_gpu() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "peers":5,
+                "gpu_name":"NVIDIA A100",
+                "gpu_usage_pct":95.5,
+                "gpu_memory_used_bytes":8589934592,
+                "msg":"system.interval"
+            }
+        }"#;
+        let interval = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+            NodeMessage::V2 {
+                payload: Payload::SystemInterval(interval),
+                ..
+            } => interval,
+            msg => panic!("message did not match the expected output: {:?}", msg),
+        };
+        assert_eq!(interval.gpu_name.as_deref(), Some("NVIDIA A100"));
+        assert_eq!(interval.gpu_usage_pct, Some(95.5));
+        assert_eq!(interval.gpu_memory_used_bytes, Some(8_589_934_592));
+    }
+
+    #[test]
+    fn pallet_trie_sizes_are_sorted_and_bounded() {
+        let sizes: Vec<String> = (0..60).map(|n| format!(r#""Pallet{}":{}"#, n, n)).collect();
+        let json = format!(
+            r#"{{"id":1,"ts":"2021-01-13T12:22:20.053527101+01:00","payload":{{"msg":"system.interval","pallet_trie_sizes":{{{}}}}}}}"#,
+            sizes.join(",")
+        );
+        let interval = match serde_json::from_str::<NodeMessage>(&json).unwrap() {
+            NodeMessage::V2 {
+                payload: Payload::SystemInterval(interval),
+                ..
+            } => internal::SystemInterval::from(interval),
+            msg => panic!("message did not match the expected output: {:?}", msg),
+        };
+        let sizes = interval.pallet_trie_sizes.unwrap();
+        assert_eq!(sizes.len(), node_types::MAX_PALLET_TRIE_SIZES);
+        assert_eq!(sizes[0], ("Pallet59".into(), 59));
+        assert_eq!(sizes[49], ("Pallet10".into(), 10));
+    }
+
+    fn connected_details(extra: &str) -> node_types::NodeDetails {
+        let json = format!(
+            r#"{{
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload":{{
+                "authority":true,
+                "chain":"Kusama",
+                "config":"",
+                "genesis_hash":"0x0000000000000000000000000000000000000000000000000000000000000001",
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                {}
+                "version":"2.0.0"
+            }}
+        }}"#,
+            extra
+        );
+        let msg: common::node_message::NodeMessage =
+            serde_json::from_str::<NodeMessage>(&json).unwrap().into();
+        match msg.into_payload() {
+            common::node_message::Payload::SystemConnected(connected) => connected.node,
+            _ => panic!("expected system.connected"),
+        }
+    }
+
+    #[test]
+    fn chain_type_is_taken_from_node_or_inferred_from_chain_name() {
+        // Inferred from the chain name if not provided:
+        assert_eq!(
+            connected_details("").chain_type,
+            Some(node_types::ChainType::RelayChain)
+        );
+        // Provided explicitly:
+        assert_eq!(
+            connected_details(r#""chain_type":"testnet","#).chain_type,
+            Some(node_types::ChainType::Testnet)
+        );
+        // Not recognised, so falls back to the chain name:
+        assert_eq!(
+            connected_details(r#""chain_type":"wibble","#).chain_type,
+            Some(node_types::ChainType::RelayChain)
+        );
+    }
+
+    #[test]
+    fn prepared_block_for_proposing_import_latency_is_optional() {
+        let import_latency = |json: &str| {
+            let msg: common::node_message::NodeMessage =
+                serde_json::from_str::<NodeMessage>(json).unwrap().into();
+            match msg.into_payload() {
+                common::node_message::Payload::PreparedBlockForProposing(prepared) => {
+                    prepared.import_latency_ms
+                }
+                _ => panic!("expected prepared_block_for_proposing"),
+            }
+        };
+        assert_eq!(
+            import_latency(
+                r#"{"id":1,"ts":"2021-01-13T12:38:25.410794650+01:00","payload":{"msg":"prepared_block_for_proposing","number":"5","hash":"0x00","import_latency_ms":250}}"#
+            ),
+            Some(250)
+        );
+        assert_eq!(
+            import_latency(
+                r#"{"id":1,"ts":"2021-01-13T12:38:25.410794650+01:00","payload":{"msg":"prepared_block_for_proposing","number":"5","hash":"0x00"}}"#
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn environment_is_optional() {
+        let details = connected_details(r#""environment":"staging","#);
+        assert_eq!(details.environment.as_deref(), Some("staging"));
+        assert_eq!(details.environment(), "staging");
+
+        let details = connected_details("");
+        assert_eq!(details.environment, None);
+        assert_eq!(details.environment(), node_types::UNKNOWN_ENVIRONMENT);
+    }
+
+    #[test]
+    fn pruning_mode_is_optional() {
+        let details = connected_details(r#""pruning_mode":256,"#);
+        assert_eq!(
+            details.pruning_mode,
+            Some(node_types::PruningMode::Constrained { keep_blocks: 256 })
+        );
+
+        let details = connected_details(r#""pruning_mode":4294967295,"#);
+        assert_eq!(details.pruning_mode, Some(node_types::PruningMode::Archive));
+
+        let details = connected_details("");
+        assert_eq!(details.pruning_mode, None);
+    }
+
+    #[test]
+    fn offchain_indexing_defaults_to_off() {
+        let details = connected_details(r#""offchain_indexing":true,"#);
+        assert!(details.offchain_indexing);
+
+        let details = connected_details(r#""offchain_indexing":null,"#);
+        assert!(!details.offchain_indexing);
+
+        let details = connected_details("");
+        assert!(!details.offchain_indexing);
+    }
+
+    #[test]
+    fn pending_upgrade_block_is_optional() {
+        let details = connected_details(r#""pending_upgrade_block":1200,"#);
+        assert_eq!(details.pending_upgrade_block, Some(1200));
+
+        let details = connected_details("");
+        assert_eq!(details.pending_upgrade_block, None);
+    }
+
+    #[test]
+    fn verbosity_is_optional() {
+        let details = connected_details(r#""verbosity":1,"#);
+        assert_eq!(details.verbosity, Some(1));
+
+        let details = connected_details("");
+        assert_eq!(details.verbosity, None);
+    }
+
+    #[test]
+    fn publicly_reachable_is_optional() {
+        let details = connected_details(r#""publicly_reachable":false,"#);
+        assert_eq!(details.publicly_reachable, Some(false));
+
+        let details = connected_details("");
+        assert_eq!(details.publicly_reachable, None);
+    }
+
+    #[test]
+    fn extra_info_is_bounded() {
+        let details = connected_details(
+            r#""extra_info":{
+                "parachain_id":2000,
+                "collator":{"keys":["a","b"]},
+                "too_deep":{"a":{"b":[1]}},
+                "a_key_which_is_much_too_long_to_keep":true
+            },"#,
+        );
+
+        let mut keys: Vec<_> = details.extra_info.keys().map(|k| &**k).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["collator", "parachain_id"]);
+        assert_eq!(details.extra_info["parachain_id"], serde_json::json!(2000));
+
+        let details = connected_details("");
+        assert!(details.extra_info.is_empty());
+    }
 }
@@ -0,0 +1,243 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Some very old nodes can't speak websockets, and instead send their telemetry as
+//! newline delimited JSON over a plain TCP connection. Each line is treated exactly
+//! as a single websocket message would be, and the node is disconnected when the TCP
+//! connection closes.
+
+use crate::aggregator::{Aggregator, FromWebsocket};
+use crate::blocked_addrs::BlockedAddrs;
+use crate::close_counts::CloseCounts;
+use crate::connection_error::{ConnectionContext, ConnectionError};
+use crate::muted_chains::MutedChains;
+use crate::node_connection::{self, NodeConnection, NodeConnectionLimits};
+use common::http_utils::MAX_WEBSOCKET_MESSAGE_SIZE;
+use common::internal_messages::NodeCloseReason;
+use common::ip_ranges::IpRanges;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::TcpListener;
+
+/// Bind to the address given and handle legacy node connections on it in the background.
+pub async fn start_listener(
+    addr: SocketAddr,
+    aggregator: Aggregator,
+    limits: NodeConnectionLimits,
+    block_list: BlockedAddrs,
+    blocked_ranges: Arc<RwLock<IpRanges>>,
+    close_counts: CloseCounts,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!(
+        "listening on tcp://{} (legacy nodes)",
+        listener.local_addr()?
+    );
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log::error!("Error accepting legacy node connection: {}", e);
+                    continue;
+                }
+            };
+            // There are no headers to consult, so the peer address is the real address:
+            let real_addr = addr.ip();
+
+            if block_list.blocked_reason(&real_addr).is_some() {
+                close_counts.record(NodeCloseReason::Banned);
+                continue;
+            }
+            if blocked_ranges.read().unwrap().contains(real_addr) {
+                close_counts.record(NodeCloseReason::AddressBlocked);
+                continue;
+            }
+
+            let tx_to_aggregator = aggregator.subscribe_node();
+            let block_list = block_list.clone();
+            let muted_chains = aggregator.muted_chains();
+            let close_counts = close_counts.clone();
+            tokio::spawn(async move {
+                log::info!("Opening legacy TCP connection from {:?}", addr);
+                let (mut tx_to_aggregator, error, context) = handle_legacy_node_connection(
+                    real_addr,
+                    BufReader::new(stream),
+                    tx_to_aggregator,
+                    limits,
+                    block_list,
+                    muted_chains,
+                )
+                .await;
+                node_connection::close(
+                    "legacy TCP",
+                    error,
+                    context,
+                    &close_counts,
+                    &mut tx_to_aggregator,
+                )
+                .await;
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Handle the lines sent on a legacy node connection until it closes, and hand back why it closed.
+async fn handle_legacy_node_connection<R, S>(
+    real_addr: IpAddr,
+    mut reader: R,
+    mut tx_to_aggregator: S,
+    limits: NodeConnectionLimits,
+    block_list: BlockedAddrs,
+    muted_chains: MutedChains,
+) -> (S, ConnectionError, ConnectionContext)
+where
+    R: AsyncBufRead + Unpin,
+    S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin,
+{
+    let close_connection_rx = match node_connection::initialize(&mut tx_to_aggregator).await {
+        Ok((rx, _)) => rx,
+        Err(e) => return (tx_to_aggregator, e, ConnectionContext::new(real_addr)),
+    };
+    let mut conn = NodeConnection::new(real_addr, limits, block_list, muted_chains);
+
+    let error = loop {
+        let mut bytes = Vec::new();
+        tokio::select! {
+            // The close channel has fired, so end the loop. Reading a line is *not*
+            // cancel safe, but since we're closing the connection we don't care.
+            reason = close_connection_rx.recv_async() => {
+                break ConnectionError::ClosedByAggregator(reason.unwrap_or(NodeCloseReason::CoreReconnected))
+            },
+            line = read_line(&mut reader, &mut bytes, MAX_WEBSOCKET_MESSAGE_SIZE) => {
+                match line {
+                    Ok(true) => {},
+                    Ok(false) => break ConnectionError::ClientClosed,
+                    Err(e) => break e,
+                }
+
+                if let Err(e) = conn.handle_message(&bytes, &mut tx_to_aggregator).await {
+                    break e;
+                }
+            }
+        }
+    };
+
+    (tx_to_aggregator, error, conn.context())
+}
+
+/// Read a single line into the buffer provided, without the trailing newline. Lines
+/// longer than `max_len` bytes are an error (we use the same limit as for websocket
+/// messages). Returns false if the connection was closed before a new line started.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    bytes: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<bool, ConnectionError> {
+    // Allow for the newline on the end:
+    let limit = max_len as u64 + 1;
+    let n = (&mut *reader).take(limit).read_until(b'\n', bytes).await?;
+    if n == 0 {
+        return Ok(false);
+    }
+
+    if bytes.last() == Some(&b'\n') {
+        bytes.pop();
+        if bytes.last() == Some(&b'\r') {
+            bytes.pop();
+        }
+    } else if n as u64 == limit {
+        return Err(ConnectionError::Receive(anyhow::anyhow!(
+            "Message is larger than the maximum of {} bytes",
+            max_len
+        )));
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::byte_size::ByteSize;
+    use futures::SinkExt;
+    use std::time::Duration;
+
+    fn limits() -> NodeConnectionLimits {
+        NodeConnectionLimits {
+            max_nodes_per_connection: 20,
+            bytes_per_second: "256k".parse::<ByteSize>().unwrap(),
+            reject_empty_chain: false,
+            max_node_name_len: 64,
+        }
+    }
+
+    async fn handle_lines(input: &'static [u8]) -> (Vec<FromWebsocket>, ConnectionError) {
+        let (tx, rx) = flume::unbounded();
+        let tx = tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e));
+        let (_, error, context) = handle_legacy_node_connection(
+            "127.0.0.1".parse().unwrap(),
+            input,
+            tx,
+            limits(),
+            BlockedAddrs::new(Duration::from_secs(60)),
+            MutedChains::new(),
+        )
+        .await;
+        assert_eq!(context.messages_received, 4);
+        (rx.drain().collect(), error)
+    }
+
+    #[tokio::test]
+    async fn lines_are_handled_like_websocket_messages() {
+        let input = concat!(
+            r#"{"id":1,"ts":"2021-07-12T10:37:47.714666+01:00","payload":{"authority":true,"chain":"Local Testnet","config":"","genesis_hash":"0x0000000000000000000000000000000000000000000000000000000000000001","implementation":"Substrate Node","msg":"system.connected","name":"Alice","network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp","startup_time":"1625565542717","version":"2.0.0"}}"#,
+            "\r\n",
+            "\n",
+            "not json\n",
+            r#"{"id":1,"ts":"2021-07-12T10:37:48.330433+01:00","payload":{"bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1}}"#,
+        );
+        let (msgs, error) = handle_lines(input.as_bytes()).await;
+
+        assert_eq!(error.close_reason(), Some(NodeCloseReason::ClientClosed));
+        assert_eq!(msgs.len(), 3, "{:?}", msgs);
+        assert!(matches!(msgs[0], FromWebsocket::Initialize { .. }));
+        assert!(
+            matches!(&msgs[1], FromWebsocket::Add { node, .. } if &*node.name == "Alice"),
+            "{:?}",
+            msgs[1]
+        );
+        assert!(matches!(msgs[2], FromWebsocket::Update { .. }));
+    }
+
+    #[tokio::test]
+    async fn lines_over_the_size_limit_are_an_error() {
+        let mut reader = &b"hello\nworld\nhello world\n"[..];
+        let mut bytes = Vec::new();
+        assert!(read_line(&mut reader, &mut bytes, 5).await.unwrap());
+        assert_eq!(bytes, b"hello");
+
+        bytes.clear();
+        assert!(read_line(&mut reader, &mut bytes, 5).await.unwrap());
+        assert_eq!(bytes, b"world");
+
+        bytes.clear();
+        assert!(read_line(&mut reader, &mut bytes, 5).await.is_err());
+    }
+}
@@ -17,24 +17,46 @@
 #[warn(missing_docs)]
 mod aggregator;
 mod blocked_addrs;
-mod connection;
+mod blocklist;
+mod close_counts;
+mod connection_error;
+mod http_batch;
 mod json_message;
+mod legacy_tcp;
+mod muted_chains;
+mod node_connection;
 mod real_ip;
+mod resumption;
 
-use std::{collections::HashSet, net::IpAddr, time::Duration};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::{net::IpAddr, time::Duration};
 
 use aggregator::{Aggregator, FromWebsocket};
 use blocked_addrs::BlockedAddrs;
+use close_counts::CloseCounts;
 use common::byte_size::ByteSize;
+use common::config_check::ConfigCheck;
 use common::http_utils;
-use common::node_message;
-use common::rolling_total::RollingTotalBuilder;
-use futures::SinkExt;
+use common::internal_messages::NodeCloseReason;
+use common::ip_ranges::{Cidr, IpRanges};
+use common::shard_token;
+use common::span_logging::LogSubscriber;
+use connection_error::{ConnectionContext, ConnectionError};
 use http::Uri;
-use hyper::{Method, Response};
+use http_batch::BatchSessions;
+use hyper::{Body, Method, Request, Response};
+use muted_chains::MutedChains;
+use node_connection::{NodeConnection, NodeConnectionLimits};
+use resumption::ResumptionCache;
 use simple_logger::SimpleLogger;
 use structopt::StructOpt;
 
+// Fuzz tests keep an eye on how much memory parsing uses:
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: test_utils::fuzz::CountingAllocator = test_utils::fuzz::CountingAllocator;
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const NAME: &str = "Substrate Telemetry Backend Shard";
@@ -60,6 +82,10 @@ struct Opts {
         default_value = "ws://127.0.0.1:8000/shard_submit/"
     )]
     core_url: Uri,
+    /// The token to give to the core when connecting to it. This must match the core's
+    /// `--shard-token`, if it has one.
+    #[structopt(long)]
+    core_token: Option<String>,
     /// How many different nodes is a given connection to the /submit endpoint allowed to
     /// tell us about before we ignore the rest?
     ///
@@ -80,10 +106,54 @@ struct Opts {
     /// value prevented from reconnecting to this shard for, in seconds.
     #[structopt(long, default_value = "600")]
     node_block_seconds: u64,
+    /// Disconnect nodes which don't say what chain they're on (ie whose chain name is empty
+    /// or only whitespace). By default they're accepted and grouped under an empty name.
+    #[structopt(long)]
+    reject_empty_chain: bool,
+    /// The longest node name, in characters, that's passed on to the core. Longer names are
+    /// truncated. Control characters are always stripped from names, since they can be used
+    /// to mess with the UI.
+    #[structopt(long, default_value = "64")]
+    max_node_name_len: usize,
     /// Number of worker threads to spawn. If "0" is given, use the number of CPUs available
     /// on the machine. If no value is given, use an internal default that we have deemed sane.
     #[structopt(long)]
     worker_threads: Option<usize>,
+    /// Path to a TOML file containing IP ranges (in CIDR notation) that are not allowed to
+    /// submit data to this shard, eg `blocked = ["192.168.1.0/24", "2001:db8::/32"]`. Ranges
+    /// can also be added and removed at runtime via the admin API.
+    #[structopt(long)]
+    blocklist: Option<PathBuf>,
+    /// A token that must be provided (as an `Authorization: Bearer <token>` header) in order to
+    /// use the "/admin" endpoints. If no token is given, the admin endpoints are disabled.
+    #[structopt(long)]
+    admin_token: Option<String>,
+    /// If given, also accept node telemetry on this TCP socket address, for old nodes which send
+    /// newline delimited JSON messages over plain TCP rather than using websockets. The same
+    /// limits apply to these connections as to websocket ones. Disabled unless given.
+    #[structopt(long)]
+    legacy_tcp_listen: Option<std::net::SocketAddr>,
+    /// Accept node telemetry POSTed in batches to "/submit/batch", for nodes that can't hold a
+    /// websocket open. Each batch is a JSON object like `{"session":"<uuid>","messages":[...]}`,
+    /// and the same limits apply to each session as to a websocket connection. Disabled by default.
+    #[structopt(long)]
+    batch_submit: bool,
+    /// How many seconds can pass without a batch being submitted to a session before it's
+    /// closed and its nodes are removed.
+    #[structopt(long, default_value = "60")]
+    batch_session_timeout: u64,
+    /// Check the configuration (that files can be read and parsed, listen addresses are free,
+    /// the core URL resolves and so on), report any problems and then exit without starting the
+    /// shard. The exit code is non-zero if there were problems. These checks are also made
+    /// every time the shard starts.
+    #[structopt(long)]
+    validate_config: bool,
+    /// Keep track of which nodes we're connected to in this file, so that if the shard restarts
+    /// and they reconnect, the core can be told that they're picking up where they left off
+    /// (see the core's `--shard-restart-grace-secs`). If not given, this is only remembered
+    /// until the shard restarts.
+    #[structopt(long)]
+    resumption_cache: Option<PathBuf>,
 }
 
 fn main() {
@@ -93,6 +163,7 @@ fn main() {
         .with_level(opts.log_level)
         .init()
         .expect("Must be able to start a logger");
+    LogSubscriber::install().expect("Must be able to log tracing spans");
 
     log::info!("Starting Telemetry Shard version: {}", VERSION);
 
@@ -111,25 +182,108 @@ fn main() {
         .build()
         .unwrap()
         .block_on(async {
+            if let Err(e) = check_config(&opts).finish() {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+            if opts.validate_config {
+                log::info!("Configuration is valid");
+                return;
+            }
             if let Err(e) = start_server(opts).await {
                 log::error!("Error starting server: {}", e);
             }
         });
 }
 
+/// Look for problems with the configuration that would otherwise only come to light
+/// once the shard is up and running (or trying to be).
+fn check_config(opts: &Opts) -> ConfigCheck {
+    let mut check = ConfigCheck::new();
+
+    let mut listen_addrs = vec![("--listen", opts.socket)];
+    if let Some(addr) = opts.legacy_tcp_listen {
+        listen_addrs.push(("--legacy-tcp-listen", addr));
+    }
+    check.distinct_listen_addrs(&listen_addrs);
+    for (what, addr) in listen_addrs {
+        check.tcp_listen_addr(what, addr);
+    }
+
+    check.resolves("--core", &opts.core_url);
+    if let Some(token) = &opts.core_token {
+        check.check("--core-token", shard_token::validate(token));
+    }
+    if let Some(path) = &opts.blocklist {
+        check.check("--blocklist", blocklist::from_toml_file(path));
+    }
+    if let Some(path) = &opts.resumption_cache {
+        check.check("--resumption-cache", ResumptionCache::from_file(path));
+    }
+    check
+}
+
 /// Declare our routes and start the server.
 async fn start_server(opts: Opts) -> anyhow::Result<()> {
     let block_list = BlockedAddrs::new(Duration::from_secs(opts.node_block_seconds));
-    let aggregator = Aggregator::spawn(opts.core_url).await?;
+    let resumption_cache = match opts.resumption_cache {
+        Some(path) => ResumptionCache::from_file(path)?,
+        None => ResumptionCache::new(),
+    };
+    let core_url = match &opts.core_token {
+        Some(token) => shard_token::add_to_uri(opts.core_url, token)?,
+        None => opts.core_url,
+    };
+    let aggregator = Aggregator::spawn(core_url, resumption_cache).await?;
     let socket_addr = opts.socket;
-    let max_nodes_per_connection = opts.max_nodes_per_connection;
-    let bytes_per_second = opts.max_node_data_per_second;
+    let limits = NodeConnectionLimits {
+        max_nodes_per_connection: opts.max_nodes_per_connection,
+        bytes_per_second: opts.max_node_data_per_second,
+        reject_empty_chain: opts.reject_empty_chain,
+        max_node_name_len: opts.max_node_name_len,
+    };
+    let admin_token: Option<Arc<str>> = opts.admin_token.map(Into::into);
+    let blocked_ranges = match opts.blocklist {
+        Some(path) => blocklist::from_toml_file(path)?,
+        None => IpRanges::new(),
+    };
+    let blocked_ranges = Arc::new(RwLock::new(blocked_ranges));
+    let close_counts = CloseCounts::new();
+
+    if let Some(addr) = opts.legacy_tcp_listen {
+        legacy_tcp::start_listener(
+            addr,
+            aggregator.clone(),
+            limits,
+            block_list.clone(),
+            blocked_ranges.clone(),
+            close_counts.clone(),
+        )
+        .await?;
+    }
+
+    let batch_sessions = if opts.batch_submit {
+        Some(BatchSessions::new(
+            aggregator.clone(),
+            limits,
+            block_list.clone(),
+            close_counts.clone(),
+            Duration::from_secs(opts.batch_session_timeout),
+        ))
+    } else {
+        None
+    };
 
     let server = http_utils::start_server(socket_addr, move |addr, req| {
         let aggregator = aggregator.clone();
+        let batch_sessions = batch_sessions.clone();
         let block_list = block_list.clone();
+        let blocked_ranges = blocked_ranges.clone();
+        let admin_token = admin_token.clone();
+        let close_counts = close_counts.clone();
         async move {
-            match (req.method(), req.uri().path().trim_end_matches('/')) {
+            let path = req.uri().path().trim_end_matches('/').to_owned();
+            match (req.method(), path.as_str()) {
                 // Check that the server is up and running:
                 (&Method::GET, "/health") => Ok(Response::new("OK".into())),
                 // Nodes send messages here:
@@ -137,32 +291,90 @@ async fn start_server(opts: Opts) -> anyhow::Result<()> {
                     let real_addr = real_ip::real_ip(addr, req.headers());
 
                     if let Some(reason) = block_list.blocked_reason(&real_addr) {
+                        close_counts.record(NodeCloseReason::Banned);
                         return Ok(Response::builder().status(403).body(reason.into()).unwrap());
                     }
+                    if blocked_ranges.read().unwrap().contains(real_addr) {
+                        close_counts.record(NodeCloseReason::AddressBlocked);
+                        return Ok(Response::builder()
+                            .status(403)
+                            .body("Address is blocked".into())
+                            .unwrap());
+                    }
+                    if !http_utils::is_upgrade_request(&req) {
+                        close_counts.record(NodeCloseReason::BadHandshake);
+                    }
 
                     Ok(http_utils::upgrade_to_websocket(
                         req,
                         move |ws_send, ws_recv| async move {
                             log::info!("Opening /submit connection from {:?}", addr);
                             let tx_to_aggregator = aggregator.subscribe_node();
-                            let (mut tx_to_aggregator, mut ws_send) =
+                            let muted_chains = aggregator.muted_chains();
+                            let (mut tx_to_aggregator, mut ws_send, error, context) =
                                 handle_node_websocket_connection(
                                     real_addr,
                                     ws_send,
                                     ws_recv,
                                     tx_to_aggregator,
-                                    max_nodes_per_connection,
-                                    bytes_per_second,
+                                    limits,
                                     block_list,
+                                    muted_chains,
                                 )
                                 .await;
-                            log::info!("Closing /submit connection from {:?}", addr);
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator.send(FromWebsocket::Disconnected).await;
+                            node_connection::close(
+                                "/submit",
+                                error,
+                                context,
+                                &close_counts,
+                                &mut tx_to_aggregator,
+                            )
+                            .await;
                             let _ = ws_send.close().await;
                         },
                     ))
                 }
+                // Nodes that can't hold a websocket open POST batches of messages here:
+                (&Method::POST, "/submit/batch") if batch_sessions.is_some() => {
+                    let real_addr = real_ip::real_ip(addr, req.headers());
+
+                    if let Some(reason) = block_list.blocked_reason(&real_addr) {
+                        close_counts.record(NodeCloseReason::Banned);
+                        return Ok(Response::builder().status(403).body(reason.into()).unwrap());
+                    }
+                    if blocked_ranges.read().unwrap().contains(real_addr) {
+                        close_counts.record(NodeCloseReason::AddressBlocked);
+                        return Ok(Response::builder()
+                            .status(403)
+                            .body("Address is blocked".into())
+                            .unwrap());
+                    }
+
+                    let body = hyper::body::to_bytes(req.into_body()).await?;
+                    let batch_sessions = batch_sessions.expect("checked above");
+                    Ok(batch_sessions.submit(real_addr, &body).await)
+                }
+                // Return metrics in a prometheus-friendly text based format:
+                (&Method::GET, "/metrics") => Ok(Response::builder()
+                    .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(
+                        format!(
+                            "{}{}",
+                            close_counts.to_prometheus(),
+                            aggregator.muted_chains().to_prometheus()
+                        )
+                        .into(),
+                    )
+                    .unwrap()),
+                // Inspect and modify the blocked IP ranges:
+                (_, path)
+                    if path == "/admin/blocklist" || path.starts_with("/admin/blocklist/") =>
+                {
+                    if let Some(res) = http_utils::check_admin_token(&req, admin_token.as_deref()) {
+                        return Ok(res);
+                    }
+                    handle_blocklist_request(req, &blocked_ranges).await
+                }
                 // 404 for anything else:
                 _ => Ok(Response::builder()
                     .status(404)
@@ -176,130 +388,119 @@ async fn start_server(opts: Opts) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// This takes care of handling messages from an established socket connection.
+/// Handle the admin endpoints for listing, adding and removing blocked IP ranges:
+///
+/// - `GET /admin/blocklist`: return a JSON array of the blocked ranges.
+/// - `POST /admin/blocklist`: block the range given in the request body, eg `10.0.0.0/8`.
+/// - `DELETE /admin/blocklist/{cidr}`: unblock the given range, eg `/admin/blocklist/10.0.0.0/8`.
+async fn handle_blocklist_request(
+    req: Request<Body>,
+    blocked_ranges: &RwLock<IpRanges>,
+) -> anyhow::Result<Response<Body>> {
+    let path = req.uri().path().trim_end_matches('/');
+    let cidr_in_path = path
+        .strip_prefix("/admin/blocklist")
+        .and_then(|s| s.strip_prefix('/'))
+        .map(|s| s.to_owned());
+
+    match (req.method(), cidr_in_path) {
+        (&Method::GET, None) => {
+            let ranges: Vec<String> = blocked_ranges
+                .read()
+                .unwrap()
+                .iter()
+                .map(|cidr| cidr.to_string())
+                .collect();
+            Ok(http_utils::json_response(200, &ranges))
+        }
+        (&Method::POST, None) => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let cidr: Cidr = match std::str::from_utf8(&body).map(|s| s.parse()) {
+                Ok(Ok(cidr)) => cidr,
+                Ok(Err(e)) => return Ok(http_utils::basic_response(400, e.to_string())),
+                Err(_) => return Ok(http_utils::basic_response(400, "Body is not valid UTF8")),
+            };
+            if blocked_ranges.write().unwrap().insert(cidr) {
+                log::info!("Blocking IP range {}", cidr);
+                Ok(http_utils::basic_response(201, cidr.to_string()))
+            } else {
+                Ok(http_utils::basic_response(200, cidr.to_string()))
+            }
+        }
+        (&Method::DELETE, Some(cidr)) => {
+            let cidr: Cidr = match cidr.parse() {
+                Ok(cidr) => cidr,
+                Err(e) => return Ok(http_utils::basic_response(400, e.to_string())),
+            };
+            if blocked_ranges.write().unwrap().remove(&cidr) {
+                log::info!("Unblocking IP range {}", cidr);
+                Ok(http_utils::basic_response(200, cidr.to_string()))
+            } else {
+                Ok(http_utils::basic_response(404, "IP range is not blocked"))
+            }
+        }
+        _ => Ok(http_utils::basic_response(405, "Method not allowed")),
+    }
+}
+
+/// This takes care of handling messages from an established socket connection. Once the
+/// connection is finished with, we hand back why it was closed.
 async fn handle_node_websocket_connection<S>(
     real_addr: IpAddr,
-    ws_send: http_utils::WsSender,
+    mut ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
     mut tx_to_aggregator: S,
-    max_nodes_per_connection: usize,
-    bytes_per_second: ByteSize,
+    limits: NodeConnectionLimits,
     block_list: BlockedAddrs,
-) -> (S, http_utils::WsSender)
+    muted_chains: MutedChains,
+) -> (S, http_utils::WsSender, ConnectionError, ConnectionContext)
 where
     S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
 {
-    // Limit the number of bytes based on a rolling total and the incoming bytes per second
-    // that has been configured via the CLI opts.
-    let bytes_per_second = bytes_per_second.num_bytes();
-    let mut rolling_total_bytes = RollingTotalBuilder::new()
-        .granularity(Duration::from_secs(1))
-        .window_size_multiple(10)
-        .start();
-
-    // Track all of the message IDs that we've seen so far. If we exceed the
-    // max_nodes_per_connection limit we ignore subsequent message IDs.
-    let mut message_ids_seen = HashSet::new();
-
-    // This could be a oneshot channel, but it's useful to be able to clone
-    // messages, and we can't clone oneshot channel senders.
-    let (close_connection_tx, close_connection_rx) = flume::bounded(1);
-
-    // Tell the aggregator about this new connection, and give it a way to close this connection:
-    let init_msg = FromWebsocket::Initialize {
-        close_connection: close_connection_tx,
+    let close_connection_rx = match node_connection::initialize(&mut tx_to_aggregator).await {
+        Ok((rx, resumption_token)) => {
+            // Nodes needn't do anything with this, so if we can't send it, carry on regardless:
+            let frame = resumption::token_frame(resumption_token);
+            if let Err(e) = ws_send.send_text(&frame).await {
+                log::debug!("Cannot send resumption token to {:?}: {}", real_addr, e);
+            }
+            let _ = ws_send.flush().await;
+            rx
+        }
+        Err(e) => {
+            return (
+                tx_to_aggregator,
+                ws_send,
+                e,
+                ConnectionContext::new(real_addr),
+            )
+        }
     };
-    if let Err(e) = tx_to_aggregator.send(init_msg).await {
-        log::error!("Error sending message to aggregator: {}", e);
-        return (tx_to_aggregator, ws_send);
-    }
+    let mut conn = NodeConnection::new(real_addr, limits, block_list, muted_chains);
 
-    // Now we've "initialized", wait for messages from the node. Messages will
-    // either be `SystemConnected` type messages that inform us that a new set
-    // of messages with some message ID will be sent (a node could have more
-    // than one of these), or updates linked to a specific message_id.
-    loop {
+    // Now we've "initialized", wait for messages from the node.
+    let error = loop {
         let mut bytes = Vec::new();
         tokio::select! {
             // The close channel has fired, so end the loop. `ws_recv.receive_data` is
             // *not* cancel safe, but since we're closing the connection we don't care.
-            _ = close_connection_rx.recv_async() => {
-                log::info!("connection to {:?} being closed by aggregator", real_addr);
-                break
+            reason = close_connection_rx.recv_async() => {
+                break ConnectionError::ClosedByAggregator(reason.unwrap_or(NodeCloseReason::CoreReconnected))
             },
             // A message was received; handle it:
             msg_info = ws_recv.receive_data(&mut bytes) => {
                 // Handle the socket closing, or errors receiving the message.
-                if let Err(soketto::connection::Error::Closed) = msg_info {
-                    break;
-                }
                 if let Err(e) = msg_info {
-                    log::error!("Shutting down websocket connection: Failed to receive data: {}", e);
-                    break;
+                    break e.into();
                 }
 
-                // Keep track of total bytes and bail if average over last 10 secs exceeds preference.
-                rolling_total_bytes.push(bytes.len());
-                let this_bytes_per_second = rolling_total_bytes.total() / 10;
-                if this_bytes_per_second > bytes_per_second {
-                    block_list.block_addr(real_addr, "Too much traffic");
-                    log::error!("Shutting down websocket connection: Too much traffic ({}bps averaged over last 10s)", this_bytes_per_second);
-                    break;
-                }
-
-                // Deserialize from JSON, warning in debug mode if deserialization fails:
-                let node_message: json_message::NodeMessage = match serde_json::from_slice(&bytes) {
-                    Ok(node_message) => node_message,
-                    #[cfg(debug)]
-                    Err(e) => {
-                        let bytes: &[u8] = bytes.get(..512).unwrap_or_else(|| &bytes);
-                        let msg_start = std::str::from_utf8(bytes).unwrap_or_else(|_| "INVALID UTF8");
-                        log::warn!("Failed to parse node message ({}): {}", msg_start, e);
-                        continue;
-                    },
-                    #[cfg(not(debug))]
-                    Err(_) => {
-                        continue;
-                    }
-                };
-
-                // Pull relevant details from the message:
-                let node_message: node_message::NodeMessage = node_message.into();
-                let message_id = node_message.id();
-                let payload = node_message.into_payload();
-
-                // Ignore messages from IDs that exceed our limit:
-                if message_ids_seen.contains(&message_id) {
-                    // continue on; we're happy
-                } else if message_ids_seen.len() >= max_nodes_per_connection {
-                    // ignore this message; it's not a "seen" ID and we've hit our limit.
-                    continue;
-                } else {
-                    // not seen ID, not hit limit; make note of new ID
-                    message_ids_seen.insert(message_id);
-                }
-
-                // Until the aggregator receives an `Add` message, which we can create once
-                // we see one of these SystemConnected ones, it will ignore messages with
-                // the corresponding message_id.
-                if let node_message::Payload::SystemConnected(info) = payload {
-                    let _ = tx_to_aggregator.send(FromWebsocket::Add {
-                        message_id,
-                        ip: real_addr,
-                        node: info.node,
-                        genesis_hash: info.genesis_hash,
-                    }).await;
-                }
-                // Anything that's not an "Add" is an Update. The aggregator will ignore
-                // updates against a message_id that hasn't first been Added, above.
-                else if let Err(e) = tx_to_aggregator.send(FromWebsocket::Update { message_id, payload } ).await {
-                    log::error!("Failed to send node message to aggregator: {}", e);
-                    continue;
+                if let Err(e) = conn.handle_message(&bytes, &mut tx_to_aggregator).await {
+                    break e;
                 }
             }
         }
-    }
+    };
 
     // Return what we need to close the connection gracefully:
-    (tx_to_aggregator, ws_send)
+    (tx_to_aggregator, ws_send, error, conn.context())
 }
@@ -0,0 +1,116 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The telemetry core can tell us to mute a whole chain for a while (for instance, if it's
+//! flooding us), in which case updates for it are dropped as they're parsed, before they
+//! cost us or the core anything more.
+
+use common::node_types::BlockHash;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// The chains that we've been told to mute, and until when. This is cheap to clone,
+/// and every clone shares the same state.
+#[derive(Clone, Default)]
+pub struct MutedChains(Arc<MutedChainsInner>);
+
+#[derive(Default)]
+struct MutedChainsInner {
+    until: RwLock<HashMap<BlockHash, Instant>>,
+    muted_messages: AtomicU64,
+}
+
+impl MutedChains {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mute the chain with the given genesis hash for this long. The mute ends by
+    /// itself once the time is up, whether or not we're told to unmute it.
+    pub fn mute(&self, genesis_hash: BlockHash, ttl: Duration) {
+        let now = Instant::now();
+        let mut mutes = self.0.until.write().unwrap();
+        mutes.retain(|_, until| *until > now);
+        mutes.insert(genesis_hash, now + ttl);
+    }
+
+    /// Unmute the chain with the given genesis hash.
+    pub fn unmute(&self, genesis_hash: &BlockHash) {
+        self.0.until.write().unwrap().remove(genesis_hash);
+    }
+
+    /// Unmute every chain.
+    pub fn clear(&self) {
+        self.0.until.write().unwrap().clear();
+    }
+
+    /// Is the chain with the given genesis hash muted right now?
+    pub fn is_muted(&self, genesis_hash: &BlockHash) -> bool {
+        // This is checked for every message, so rather than take a write lock here,
+        // expired mutes are left for the next call to `mute` to tidy up:
+        let mutes = self.0.until.read().unwrap();
+        matches!(mutes.get(genesis_hash), Some(&until) if until > Instant::now())
+    }
+
+    /// Make a note that a message was dropped because its chain is muted.
+    pub fn record_muted_message(&self) {
+        self.0.muted_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many messages have been dropped because their chain was muted?
+    pub fn muted_messages(&self) -> u64 {
+        self.0.muted_messages.load(Ordering::Relaxed)
+    }
+
+    /// The muted message count in a prometheus-friendly text based format.
+    pub fn to_prometheus(&self) -> String {
+        format!(
+            "# TYPE telemetry_shard_muted_messages_total counter\n\
+             telemetry_shard_muted_messages_total {}\n",
+            self.muted_messages()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mutes_expire_by_themselves() {
+        let muted_chains = MutedChains::new();
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        assert!(!muted_chains.is_muted(&genesis_hash));
+
+        muted_chains.mute(genesis_hash, Duration::from_secs(60));
+        assert!(muted_chains.is_muted(&genesis_hash));
+        assert!(!muted_chains.is_muted(&BlockHash::from_low_u64_be(2)));
+
+        muted_chains.mute(genesis_hash, Duration::from_secs(0));
+        assert!(!muted_chains.is_muted(&genesis_hash));
+    }
+
+    #[test]
+    fn chains_can_be_unmuted_early() {
+        let muted_chains = MutedChains::new();
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        muted_chains.mute(genesis_hash, Duration::from_secs(60));
+        muted_chains.clone().unmute(&genesis_hash);
+        assert!(!muted_chains.is_muted(&genesis_hash));
+    }
+}
@@ -0,0 +1,507 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Nodes can connect to us in more than one way, but however they connect, the messages
+//! they send are parsed, limited and handed to the aggregator in exactly the same way.
+
+use crate::aggregator::FromWebsocket;
+use crate::blocked_addrs::BlockedAddrs;
+use crate::close_counts::CloseCounts;
+use crate::connection_error::{ConnectionContext, ConnectionError, MessageKind};
+use crate::json_message;
+use crate::muted_chains::MutedChains;
+use crate::resumption::ResumptionToken;
+use common::byte_size::ByteSize;
+use common::internal_messages::NodeCloseReason;
+use common::rolling_total::{RollingTotal, RollingTotalBuilder};
+use common::{node_message, node_types};
+use futures::SinkExt;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::Instrument;
+
+/// The limits that apply to every connection from a node.
+#[derive(Clone, Copy, Debug)]
+pub struct NodeConnectionLimits {
+    /// How many different nodes is a single connection allowed to tell us about?
+    pub max_nodes_per_connection: usize,
+    /// How much data, on average, can a single connection send us per second?
+    pub bytes_per_second: ByteSize,
+    /// Should nodes whose chain name is empty (once surrounding whitespace is trimmed)
+    /// be disconnected? If not, they're passed on like any other node.
+    pub reject_empty_chain: bool,
+    /// The longest name, in characters, that we'll pass on for a node. Longer names are
+    /// truncated, and control characters are always stripped.
+    pub max_node_name_len: usize,
+}
+
+/// Tell the aggregator about a new node connection. If this succeeds, we hand back a
+/// channel that will receive a message when the aggregator wants the connection closed,
+/// saying why, and the resumption token that the connection has been given.
+pub async fn initialize<S>(
+    tx_to_aggregator: &mut S,
+) -> Result<(flume::Receiver<NodeCloseReason>, ResumptionToken), ConnectionError>
+where
+    S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin,
+{
+    // This could be a oneshot channel, but it's useful to be able to clone
+    // messages, and we can't clone oneshot channel senders.
+    let (close_connection_tx, close_connection_rx) = flume::bounded(1);
+    let resumption_token = ResumptionToken::random();
+
+    // Tell the aggregator about this new connection, and give it a way to close this connection:
+    let init_msg = FromWebsocket::Initialize {
+        close_connection: close_connection_tx,
+        resumption_token,
+    };
+    tx_to_aggregator
+        .send(init_msg)
+        .await
+        .map_err(|error| ConnectionError::Forward {
+            kind: MessageKind::Initialize,
+            error,
+        })?;
+
+    Ok((close_connection_rx, resumption_token))
+}
+
+/// Once a node connection has ended, however it was connected, this is the one place that
+/// logs why, counts it, and tells the aggregator so that it can tidy up. Hands back the
+/// reason that the connection was closed with.
+pub async fn close<S>(
+    transport: &str,
+    error: ConnectionError,
+    context: ConnectionContext,
+    close_counts: &CloseCounts,
+    tx_to_aggregator: &mut S,
+) -> NodeCloseReason
+where
+    S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin,
+{
+    let reason = error.close_reason().unwrap_or(NodeCloseReason::Internal);
+    error.log(&context);
+    log::info!(
+        "Closing {} connection from {:?} ({}, code {})",
+        transport,
+        context.addr,
+        reason.as_str(),
+        reason.code()
+    );
+    close_counts.record(reason);
+    let _ = tx_to_aggregator
+        .send(FromWebsocket::Disconnected { reason })
+        .await;
+    reason
+}
+
+/// The state we need to keep for a single connection from a node in order to
+/// enforce our limits on it.
+pub struct NodeConnection {
+    context: ConnectionContext,
+    limits: NodeConnectionLimits,
+    block_list: BlockedAddrs,
+    /// Used to limit the number of bytes based on a rolling total and the incoming bytes per second.
+    rolling_total_bytes: RollingTotal<usize>,
+    /// Track all of the message IDs that we've seen so far. If we exceed the
+    /// max_nodes_per_connection limit we ignore subsequent message IDs.
+    message_ids_seen: HashSet<node_message::NodeMessageId>,
+    /// Chains that we've been told to mute, whose updates are dropped here.
+    muted_chains: MutedChains,
+    /// The chain that each message ID has said it's on.
+    genesis_hashes: HashMap<node_message::NodeMessageId, node_types::BlockHash>,
+}
+
+impl NodeConnection {
+    pub fn new(
+        real_addr: IpAddr,
+        limits: NodeConnectionLimits,
+        block_list: BlockedAddrs,
+        muted_chains: MutedChains,
+    ) -> Self {
+        NodeConnection {
+            context: ConnectionContext::new(real_addr),
+            limits,
+            block_list,
+            muted_chains,
+            genesis_hashes: HashMap::new(),
+            rolling_total_bytes: RollingTotalBuilder::new()
+                .granularity(Duration::from_secs(1))
+                .window_size_multiple(10)
+                .start(),
+            message_ids_seen: HashSet::new(),
+        }
+    }
+
+    /// Details about this connection so far, to log alongside any errors.
+    pub fn context(&self) -> ConnectionContext {
+        self.context
+    }
+
+    /// Handle a single message received from the node. Messages will either be
+    /// `SystemConnected` type messages that inform us that a new set of messages
+    /// with some message ID will be sent (a node could have more than one of these),
+    /// or updates linked to a specific message_id.
+    ///
+    /// If the connection should be closed as a result, we hand back why. Anything that
+    /// goes wrong which doesn't need the connection closing is logged here.
+    pub async fn handle_message<S>(
+        &mut self,
+        bytes: &[u8],
+        tx_to_aggregator: &mut S,
+    ) -> Result<(), ConnectionError>
+    where
+        S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin,
+    {
+        self.context.bytes_received += bytes.len();
+        self.context.messages_received += 1;
+
+        match self.forward_message(bytes, tx_to_aggregator).await {
+            Err(e) if e.close_reason().is_none() => {
+                e.log(&self.context);
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    async fn forward_message<S>(
+        &mut self,
+        bytes: &[u8],
+        tx_to_aggregator: &mut S,
+    ) -> Result<(), ConnectionError>
+    where
+        S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin,
+    {
+        // Keep track of total bytes and bail if average over last 10 secs exceeds preference.
+        self.rolling_total_bytes.push(bytes.len());
+        let bytes_per_second = self.rolling_total_bytes.total() / 10;
+        if bytes_per_second > self.limits.bytes_per_second.num_bytes() {
+            self.block_list
+                .block_addr(self.context.addr, "Too much traffic");
+            return Err(ConnectionError::RateLimited { bytes_per_second });
+        }
+
+        let received_at = common::time::now();
+        let node_message: json_message::NodeMessage = serde_json::from_slice(bytes)?;
+
+        // Pull relevant details from the message:
+        let reported_at = node_message.reported_at();
+        let schema = node_message.schema();
+        let node_message: node_message::NodeMessage = node_message.into();
+        let message_id = node_message.id();
+        let payload = node_message.into_payload();
+
+        let span = tracing::debug_span!(
+            "node_message",
+            addr = %self.context.addr,
+            message_id = ?message_id,
+            message_type = payload.name(),
+        );
+        let (kind, msg) = {
+            let _entered = span.enter();
+
+            // Ignore messages from IDs that exceed our limit:
+            if self.message_ids_seen.contains(&message_id) {
+                // continue on; we're happy
+            } else if self.message_ids_seen.len() >= self.limits.max_nodes_per_connection {
+                // ignore this message; it's not a "seen" ID and we've hit our limit.
+                return Ok(());
+            } else {
+                // not seen ID, not hit limit; make note of new ID
+                self.message_ids_seen.insert(message_id);
+            }
+
+            // Until the aggregator receives an `Add` message, which we can create once
+            // we see one of these SystemConnected ones, it will ignore messages with
+            // the corresponding message_id.
+            if let node_message::Payload::SystemConnected(mut info) = payload {
+                let chain = info.node.chain.trim();
+                if chain.is_empty() && self.limits.reject_empty_chain {
+                    return Err(ConnectionError::NoChain);
+                }
+                if chain.len() != info.node.chain.len() {
+                    info.node.chain = chain.into();
+                }
+                info.node.name = node_types::sanitize_node_name(
+                    std::mem::take(&mut info.node.name),
+                    self.limits.max_node_name_len,
+                );
+
+                info.node.message_schema = Some(schema);
+
+                self.genesis_hashes.insert(message_id, info.genesis_hash);
+                let msg = FromWebsocket::Add {
+                    message_id,
+                    ip: self.context.addr,
+                    node: info.node,
+                    genesis_hash: info.genesis_hash,
+                };
+                (MessageKind::Add, msg)
+            }
+            // Anything that's not an "Add" is an Update. The aggregator will ignore
+            // updates against a message_id that hasn't first been Added, above.
+            else {
+                // Nodes on muted chains are still added, so that they pick up where they
+                // left off once the chain is unmuted, but their updates go no further:
+                let muted = matches!(
+                    self.genesis_hashes.get(&message_id),
+                    Some(genesis_hash) if self.muted_chains.is_muted(genesis_hash)
+                );
+                if muted {
+                    self.muted_chains.record_muted_message();
+                    return Ok(());
+                }
+                let msg = FromWebsocket::Update {
+                    message_id,
+                    payload,
+                    reported_at,
+                    received_at,
+                };
+                (MessageKind::Update, msg)
+            }
+        };
+
+        tx_to_aggregator
+            .send(msg)
+            .instrument(span)
+            .await
+            .map_err(|error| ConnectionError::Forward { kind, error })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::SinkExt;
+    use test_utils::recorded_spans::{RecordingSubscriber, SpanFields};
+
+    fn connected(chain: &str) -> String {
+        connected_as(chain, "Alice")
+    }
+
+    fn connected_as(chain: &str, name: &str) -> String {
+        format!(
+            r#"{{"id":1,"ts":"2021-07-12T10:37:47.714666+01:00","payload":{{"authority":true,"chain":"{}","config":"","genesis_hash":"0x0000000000000000000000000000000000000000000000000000000000000001","implementation":"Substrate Node","msg":"system.connected","name":{},"network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp","startup_time":"1625565542717","version":"2.0.0"}}}}"#,
+            chain,
+            serde_json::to_string(name).unwrap()
+        )
+    }
+
+    /// Hand a single message to a new connection with the given policy, returning the result
+    /// and anything that was sent to the aggregator.
+    async fn handle(
+        msg: &str,
+        reject_empty_chain: bool,
+    ) -> (Result<(), ConnectionError>, Vec<FromWebsocket>) {
+        let (tx, rx) = flume::unbounded();
+        let mut tx = tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e));
+        let limits = NodeConnectionLimits {
+            max_nodes_per_connection: 20,
+            bytes_per_second: "256k".parse::<ByteSize>().unwrap(),
+            reject_empty_chain,
+            max_node_name_len: 16,
+        };
+        let mut conn = NodeConnection::new(
+            "127.0.0.1".parse().unwrap(),
+            limits,
+            BlockedAddrs::new(Duration::from_secs(60)),
+            MutedChains::new(),
+        );
+        let res = conn.handle_message(msg.as_bytes(), &mut tx).await;
+        (res, rx.drain().collect())
+    }
+
+    #[tokio::test]
+    async fn nodes_without_a_chain_are_rejected_if_configured() {
+        for chain in &["", "   "] {
+            let (res, msgs) = handle(&connected(chain), true).await;
+            let reason = res.unwrap_err().close_reason();
+            assert_eq!(reason, Some(NodeCloseReason::NoChain));
+            assert!(msgs.is_empty(), "{:?}", msgs);
+        }
+    }
+
+    #[tokio::test]
+    async fn nodes_without_a_chain_are_accepted_if_lenient() {
+        let (res, msgs) = handle(&connected("  "), false).await;
+        assert!(res.is_ok());
+        assert!(
+            matches!(&msgs[..], [FromWebsocket::Add { node, .. }] if node.chain.is_empty()),
+            "{:?}",
+            msgs
+        );
+    }
+
+    #[tokio::test]
+    async fn chain_names_are_trimmed() {
+        let (res, msgs) = handle(&connected(" Kusama "), true).await;
+        assert!(res.is_ok());
+        assert!(
+            matches!(&msgs[..], [FromWebsocket::Add { node, .. }] if &*node.chain == "Kusama"),
+            "{:?}",
+            msgs
+        );
+    }
+
+    #[tokio::test]
+    async fn node_names_are_sanitized() {
+        let (res, msgs) = handle(&connected_as("Kusama", "\u{1b}[2JAli\nce\u{7}"), true).await;
+        assert!(res.is_ok());
+        assert!(
+            matches!(&msgs[..], [FromWebsocket::Add { node, .. }] if &*node.name == "[2JAlice"),
+            "{:?}",
+            msgs
+        );
+
+        let (res, msgs) = handle(&connected_as("Kusama", &"a".repeat(100)), true).await;
+        assert!(res.is_ok());
+        assert!(
+            matches!(&msgs[..], [FromWebsocket::Add { node, .. }] if *node.name == *"a".repeat(16)),
+            "{:?}",
+            msgs
+        );
+    }
+
+    #[tokio::test]
+    async fn message_schema_is_detected_per_node() {
+        // Older nodes send flat messages without an ID:
+        const CONNECTED_V1: &str = r#"{"authority":true,"chain":"Kusama","config":"","genesis_hash":"0x0000000000000000000000000000000000000000000000000000000000000001","implementation":"Substrate Node","msg":"system.connected","name":"Bob","network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp","startup_time":"1625565542717","ts":"2021-07-12T10:37:47.714666+01:00","version":"0.9.0"}"#;
+        let (tx, rx) = flume::unbounded();
+        let mut tx = tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e));
+        let limits = NodeConnectionLimits {
+            max_nodes_per_connection: 20,
+            bytes_per_second: "256k".parse::<ByteSize>().unwrap(),
+            reject_empty_chain: true,
+            max_node_name_len: 16,
+        };
+        let mut conn = NodeConnection::new(
+            "127.0.0.1".parse().unwrap(),
+            limits,
+            BlockedAddrs::new(Duration::from_secs(60)),
+            MutedChains::new(),
+        );
+
+        conn.handle_message(CONNECTED_V1.as_bytes(), &mut tx)
+            .await
+            .unwrap();
+        conn.handle_message(connected("Kusama").as_bytes(), &mut tx)
+            .await
+            .unwrap();
+
+        let schemas: Vec<_> = rx
+            .drain()
+            .map(|msg| match msg {
+                FromWebsocket::Add {
+                    message_id, node, ..
+                } => (message_id, node.name, node.message_schema),
+                other => panic!("expected an Add message, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            schemas,
+            vec![
+                (0, "Bob".into(), Some(node_types::MessageSchema::V1)),
+                (1, "Alice".into(), Some(node_types::MessageSchema::V2)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn updates_for_muted_chains_are_dropped() {
+        const INTERVAL: &str = r#"{"id":1,"ts":"2021-07-12T10:37:48.330433+01:00","payload":{"bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1}}"#;
+        let (tx, rx) = flume::unbounded();
+        let mut tx = tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e));
+        let limits = NodeConnectionLimits {
+            max_nodes_per_connection: 20,
+            bytes_per_second: "256k".parse::<ByteSize>().unwrap(),
+            reject_empty_chain: true,
+            max_node_name_len: 16,
+        };
+        let muted_chains = MutedChains::new();
+        let mut conn = NodeConnection::new(
+            "127.0.0.1".parse().unwrap(),
+            limits,
+            BlockedAddrs::new(Duration::from_secs(60)),
+            muted_chains.clone(),
+        );
+
+        conn.handle_message(connected("Kusama").as_bytes(), &mut tx)
+            .await
+            .unwrap();
+        conn.handle_message(INTERVAL.as_bytes(), &mut tx)
+            .await
+            .unwrap();
+        assert_eq!(rx.drain().count(), 2);
+
+        // Muting the chain applies to connections that are already open:
+        muted_chains.mute(
+            node_types::BlockHash::from_low_u64_be(1),
+            Duration::from_secs(60),
+        );
+        conn.handle_message(INTERVAL.as_bytes(), &mut tx)
+            .await
+            .unwrap();
+        assert_eq!(rx.drain().count(), 0);
+        assert_eq!(muted_chains.muted_messages(), 1);
+
+        muted_chains.unmute(&node_types::BlockHash::from_low_u64_be(1));
+        conn.handle_message(INTERVAL.as_bytes(), &mut tx)
+            .await
+            .unwrap();
+        assert_eq!(rx.drain().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn node_messages_are_traced() {
+        const INTERVAL: &str = r#"{"id":1,"ts":"2021-07-12T10:37:48.330433+01:00","payload":{"bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1}}"#;
+        let spans = RecordingSubscriber::new();
+        let _guard = tracing::subscriber::set_default(spans.clone());
+
+        let (tx, _rx) = flume::unbounded();
+        let mut tx = tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e));
+        let limits = NodeConnectionLimits {
+            max_nodes_per_connection: 20,
+            bytes_per_second: "256k".parse::<ByteSize>().unwrap(),
+            reject_empty_chain: true,
+            max_node_name_len: 16,
+        };
+        let mut conn = NodeConnection::new(
+            "127.0.0.1".parse().unwrap(),
+            limits,
+            BlockedAddrs::new(Duration::from_secs(60)),
+            MutedChains::new(),
+        );
+        conn.handle_message(connected("Kusama").as_bytes(), &mut tx)
+            .await
+            .unwrap();
+        conn.handle_message(INTERVAL.as_bytes(), &mut tx)
+            .await
+            .unwrap();
+
+        let fields = |message_type: &str| {
+            SpanFields::from([
+                ("addr", "127.0.0.1".to_string()),
+                ("message_id", "1".to_string()),
+                ("message_type", message_type.to_string()),
+            ])
+        };
+        assert_eq!(
+            spans.fields_of("node_message"),
+            vec![fields("system.connected"), fields("system.interval")]
+        );
+    }
+}
@@ -0,0 +1,235 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Each node connection is handed an opaque resumption token, and we remember which nodes
+//! (by chain and network ID) each token was used for. If a node comes back after we've lost
+//! our connection to the core, or restarted (if the cache is kept in a file), we can tell the
+//! core that we're picking the node back up rather than seeing it for the first time, so
+//! that it keeps its ID and history.
+
+use anyhow::Context;
+use common::node_types::BlockHash;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Changes to the cache are written to its file no more often than this.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An opaque, random token handed to a node connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ResumptionToken(u128);
+
+impl ResumptionToken {
+    pub fn random() -> ResumptionToken {
+        ResumptionToken(rand::random())
+    }
+}
+
+impl std::fmt::Display for ResumptionToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl std::str::FromStr for ResumptionToken {
+    type Err = std::num::ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u128::from_str_radix(s, 16).map(ResumptionToken)
+    }
+}
+
+/// The small frame that tells a node which resumption token it's been given.
+pub fn token_frame(token: ResumptionToken) -> String {
+    serde_json::json!({ "resumption_token": token.to_string() }).to_string()
+}
+
+/// How each node is stored in the cache file.
+#[derive(Serialize, Deserialize)]
+struct CachedNode {
+    token: String,
+    genesis_hash: BlockHash,
+    network_id: Box<str>,
+}
+
+/// Which resumption token each node we're connected to was last seen with.
+pub struct ResumptionCache {
+    /// Where the cache is kept across restarts, if anywhere.
+    path: Option<PathBuf>,
+    nodes: HashMap<(BlockHash, Box<str>), ResumptionToken>,
+    /// When the cache was last written to its file, if it's been changed since.
+    changed_since: Option<Instant>,
+}
+
+impl ResumptionCache {
+    /// A cache that's only kept in memory, and so is lost on restart.
+    pub fn new() -> ResumptionCache {
+        ResumptionCache {
+            path: None,
+            nodes: HashMap::new(),
+            changed_since: None,
+        }
+    }
+
+    /// A cache kept in the given file, starting with whatever the file holds. The file
+    /// needn't exist yet.
+    pub fn from_file(path: impl Into<PathBuf>) -> anyhow::Result<ResumptionCache> {
+        let path = path.into();
+        let nodes = if path.exists() {
+            read_file(&path)?
+        } else {
+            HashMap::new()
+        };
+        Ok(ResumptionCache {
+            path: Some(path),
+            nodes,
+            changed_since: None,
+        })
+    }
+
+    /// Make a note of a node that's connected with the given token. Returns `true` if we'd
+    /// already seen this node with a different token, in which case it's resuming.
+    pub fn add(
+        &mut self,
+        token: ResumptionToken,
+        genesis_hash: BlockHash,
+        network_id: &str,
+    ) -> bool {
+        let previous = self.nodes.insert((genesis_hash, network_id.into()), token);
+        if previous != Some(token) {
+            self.changed();
+        }
+        matches!(previous, Some(previous) if previous != token)
+    }
+
+    /// Forget about the nodes connected with the given token.
+    pub fn remove(&mut self, token: ResumptionToken) {
+        let len = self.nodes.len();
+        self.nodes.retain(|_, t| *t != token);
+        if self.nodes.len() != len {
+            self.changed();
+        }
+    }
+
+    fn changed(&mut self) {
+        self.changed_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Write the cache to its file if it's changed, and enough time has passed since it
+    /// was last written. A failure to write is logged, and tried again later.
+    pub fn save_if_due(&mut self) {
+        let path = match (&self.path, self.changed_since) {
+            (Some(path), Some(changed_since)) if changed_since.elapsed() >= SAVE_INTERVAL => path,
+            _ => return,
+        };
+        match write_file(path, &self.nodes) {
+            Ok(()) => self.changed_since = None,
+            Err(e) => log::warn!("Cannot save resumption cache: {:#}", e),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> anyhow::Result<HashMap<(BlockHash, Box<str>), ResumptionToken>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read resumption cache {}", path.display()))?;
+    let cached: Vec<CachedNode> = serde_json::from_str(&contents)
+        .with_context(|| format!("Cannot parse resumption cache {}", path.display()))?;
+    cached
+        .into_iter()
+        .map(|node| {
+            let token = node
+                .token
+                .parse()
+                .with_context(|| format!("Invalid resumption token {:?}", node.token))?;
+            Ok(((node.genesis_hash, node.network_id), token))
+        })
+        .collect()
+}
+
+/// Write the cache to a temporary file first, so that a restart part way through
+/// writing doesn't leave a broken cache behind.
+fn write_file(
+    path: &Path,
+    nodes: &HashMap<(BlockHash, Box<str>), ResumptionToken>,
+) -> anyhow::Result<()> {
+    let cached: Vec<CachedNode> = nodes
+        .iter()
+        .map(|((genesis_hash, network_id), token)| CachedNode {
+            token: token.to_string(),
+            genesis_hash: *genesis_hash,
+            network_id: network_id.clone(),
+        })
+        .collect();
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(&cached)?)
+        .with_context(|| format!("Cannot write {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Cannot replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tokens_round_trip_through_strings() {
+        let token = ResumptionToken::random();
+        assert_eq!(token.to_string().len(), 32);
+        assert_eq!(token.to_string().parse(), Ok(token));
+        assert_ne!(token, ResumptionToken::random());
+    }
+
+    #[test]
+    fn nodes_seen_with_another_token_are_resuming() {
+        let mut cache = ResumptionCache::new();
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let (first, second) = (ResumptionToken::random(), ResumptionToken::random());
+
+        assert!(!cache.add(first, genesis_hash, "node"));
+        // The same connection telling us about the node again isn't resuming it:
+        assert!(!cache.add(first, genesis_hash, "node"));
+        assert!(cache.add(second, genesis_hash, "node"));
+
+        // Once the connection goes away for good, the node is forgotten:
+        cache.remove(second);
+        assert!(!cache.add(first, genesis_hash, "node"));
+    }
+
+    #[test]
+    fn cache_survives_restarts_via_its_file() {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry-resumption-cache-test-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+
+        let mut cache = ResumptionCache::from_file(&path).unwrap();
+        cache.add(ResumptionToken::random(), genesis_hash, "node");
+        // Pretend that the cache was changed long enough ago to be saved:
+        cache.changed_since = Some(Instant::now() - SAVE_INTERVAL);
+        cache.save_if_due();
+        assert_eq!(cache.changed_since, None);
+
+        let mut restarted = ResumptionCache::from_file(&path).unwrap();
+        assert!(restarted.add(ResumptionToken::random(), genesis_hash, "node"));
+        assert!(!restarted.add(ResumptionToken::random(), genesis_hash, "other node"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
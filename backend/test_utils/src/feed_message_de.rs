@@ -19,8 +19,12 @@ use common::node_types::{
     BlockDetails, BlockHash, BlockNumber, NodeLocation, NodeStats, Timestamp,
 };
 use serde_json::value::RawValue;
+use std::collections::{BTreeMap, HashMap};
+
+const BATCH_SIGNATURE_ACTION: u8 = 30;
 
 #[derive(Debug, PartialEq)]
+#[allow(clippy::large_enum_variant)]
 pub enum FeedMessage {
     Version(usize),
     BestBlock {
@@ -41,6 +45,7 @@ pub enum FeedMessage {
         block_details: BlockDetails,
         location: Option<NodeLocation>,
         startup_time: Option<Timestamp>,
+        peer_history: Vec<u64>,
     },
     RemovedNode {
         node_id: usize,
@@ -59,10 +64,12 @@ pub enum FeedMessage {
         node_id: usize,
         block_number: BlockNumber,
         block_hash: BlockHash,
+        finalized_at: Option<Timestamp>,
     },
     NodeStatsUpdate {
         node_id: usize,
         stats: NodeStats,
+        peer_history: Vec<u64>,
     },
     Hardware {
         node_id: usize,
@@ -119,6 +126,85 @@ pub enum FeedMessage {
         node_id: usize,
         // details: NodeIO, // can't losslessly deserialize
     },
+    ChainDistribution {
+        implementations: HashMap<String, usize>,
+        versions: HashMap<String, usize>,
+    },
+    BestBlockAge {
+        block_number: BlockNumber,
+        block_hash: BlockHash,
+        age_ms: u64,
+    },
+    NodeAlert {
+        node_id: usize,
+        kind: String,
+        severity: String,
+        value: Option<f64>,
+        raised_at: Timestamp,
+    },
+    NodeAlertCleared {
+        node_id: usize,
+        kind: String,
+    },
+    NodeCountHistory {
+        samples: Vec<NodeCountSample>,
+    },
+    NodeCountSample {
+        sample: NodeCountSample,
+    },
+    NodesAtBest {
+        block_number: BlockNumber,
+        caught_up: usize,
+        total: usize,
+    },
+    SessionKey {
+        key: Vec<u8>,
+    },
+    BatchSignature {
+        signed_ts: u64,
+        signature: [u8; 32],
+    },
+    /// Either every field of a chain's stats (if `full`), or just those that have
+    /// changed, keyed by field ID. [`ChainStatsView`] can put these back together.
+    /// Every so often, how many nodes are on each continent is given too.
+    ChainStats {
+        name: String,
+        full: bool,
+        values: Vec<(u8, Option<u64>)>,
+        continent_distribution: Option<Vec<(String, u32)>>,
+        /// The pallets taking up the most state trie space, if they've changed.
+        top_pallet_trie_sizes: Option<Vec<(String, u64)>>,
+    },
+    ServerShutdown {
+        restart_in_seconds: Option<u32>,
+    },
+    BlockProductionRate {
+        blocks_per_second: f64,
+    },
+    UpgradeScheduled {
+        chain: String,
+        at_block: BlockNumber,
+        current_block: BlockNumber,
+    },
+    UpgradeExecuted {
+        chain: String,
+        at_block: BlockNumber,
+    },
+    SubscriberCount {
+        chain: String,
+        count: u32,
+    },
+    Heartbeat {
+        timestamp: Timestamp,
+    },
+    PublicNodeShortage {
+        chain: String,
+        count: usize,
+    },
+    PublicNodeShortageResolved {
+        chain: String,
+        count: usize,
+    },
     /// A "special" case when we don't know how to decode an action:
     UnknownValue {
         action: u8,
@@ -133,6 +219,46 @@ pub struct NodeDetails {
     pub version: String,
     pub validator: Option<String>,
     pub network_id: Option<String>,
+    pub offchain_indexing: bool,
+    pub extra_info: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct NodeCountSample {
+    pub timestamp: Timestamp,
+    pub node_count: usize,
+    pub validator_count: usize,
+}
+
+impl From<(Timestamp, usize, usize)> for NodeCountSample {
+    fn from((timestamp, node_count, validator_count): (Timestamp, usize, usize)) -> Self {
+        NodeCountSample {
+            timestamp,
+            node_count,
+            validator_count,
+        }
+    }
+}
+
+/// The stats of a chain, as reconstructed from [`FeedMessage::ChainStats`] messages.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ChainStatsView {
+    values: BTreeMap<u8, Option<u64>>,
+}
+
+impl ChainStatsView {
+    /// Apply the fields from a [`FeedMessage::ChainStats`] message.
+    pub fn apply(&mut self, full: bool, values: &[(u8, Option<u64>)]) {
+        if full {
+            self.values.clear();
+        }
+        self.values.extend(values.iter().copied());
+    }
+
+    /// The value of every field that we've been told about, by field ID.
+    pub fn values(&self) -> &BTreeMap<u8, Option<u64>> {
+        &self.values
+    }
 }
 
 impl FeedMessage {
@@ -154,6 +280,33 @@ impl FeedMessage {
         Ok(feed_messages)
     }
 
+    /// Decode a batch of feed messages that should end in a [`FeedMessage::BatchSignature`]
+    /// made with the key given, checking the signature. The signature is not returned.
+    pub fn from_signed_bytes(bytes: &[u8], key: &[u8]) -> Result<Vec<FeedMessage>, anyhow::Error> {
+        let mut feed_messages = FeedMessage::from_bytes(bytes)?;
+        let (signed_ts, signature) = match feed_messages.pop() {
+            Some(FeedMessage::BatchSignature {
+                signed_ts,
+                signature,
+            }) => (signed_ts, signature),
+            _ => anyhow::bail!("Batch does not end in a signature"),
+        };
+
+        // The signature is of the batch as it would be without the signature on the end:
+        let trailer = format!(",{},[", BATCH_SIGNATURE_ACTION);
+        let trailer_start = bytes
+            .windows(trailer.len())
+            .rposition(|w| w == trailer.as_bytes())
+            .context("Cannot find the signature in the batch")?;
+        let mut batch = bytes[..trailer_start].to_vec();
+        batch.push(b']');
+
+        if !common::feed_signing::verify_batch(key, &batch, signed_ts, &signature) {
+            anyhow::bail!("Batch signature does not match");
+        }
+        Ok(feed_messages)
+    }
+
     // Deserialize the feed message to a value based on the "action" key
     fn decode(action: u8, raw_val: &RawValue) -> Result<FeedMessage, anyhow::Error> {
         let feed_message = match action {
@@ -184,13 +337,22 @@ impl FeedMessage {
             3 => {
                 let (
                     node_id,
-                    (name, implementation, version, validator, network_id),
+                    (
+                        name,
+                        implementation,
+                        version,
+                        validator,
+                        network_id,
+                        offchain_indexing,
+                        extra_info,
+                    ),
                     stats,
                     io,
                     hardware,
                     block_details,
                     location,
                     startup_time,
+                    peer_history,
                 ) = serde_json::from_str(raw_val.get())?;
 
                 // Give these two types but don't use the results:
@@ -204,11 +366,14 @@ impl FeedMessage {
                         version,
                         validator,
                         network_id,
+                        offchain_indexing,
+                        extra_info,
                     },
                     stats,
                     block_details,
                     location,
                     startup_time,
+                    peer_history,
                 }
             }
             // RemoveNode
@@ -236,17 +401,23 @@ impl FeedMessage {
             }
             // FinalizedBlock
             7 => {
-                let (node_id, block_number, block_hash) = serde_json::from_str(raw_val.get())?;
+                let (node_id, block_number, block_hash, finalized_at) =
+                    serde_json::from_str(raw_val.get())?;
                 FeedMessage::FinalizedBlock {
                     node_id,
                     block_number,
                     block_hash,
+                    finalized_at,
                 }
             }
             // NodeStatsUpdate
             8 => {
-                let (node_id, stats) = serde_json::from_str(raw_val.get())?;
-                FeedMessage::NodeStatsUpdate { node_id, stats }
+                let (node_id, stats, peer_history) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeStatsUpdate {
+                    node_id,
+                    stats,
+                    peer_history,
+                }
             }
             // Hardware
             9 => {
@@ -260,7 +431,8 @@ impl FeedMessage {
             }
             // AddedChain
             11 => {
-                let (name, node_count) = serde_json::from_str(raw_val.get())?;
+                let (name, node_count, _chain_type, _first_party): (_, _, Option<u8>, bool) =
+                    serde_json::from_str(raw_val.get())?;
                 FeedMessage::AddedChain { name, node_count }
             }
             // RemovedChain
@@ -336,6 +508,152 @@ impl FeedMessage {
                 let (node_id, _node_io): (_, &RawValue) = serde_json::from_str(raw_val.get())?;
                 FeedMessage::NodeIOUpdate { node_id }
             }
+            // ChainDistribution
+            22 => {
+                let mut dist: HashMap<String, HashMap<String, usize>> =
+                    serde_json::from_str(raw_val.get())?;
+                let implementations = dist.remove("implementations").unwrap_or_default();
+                let versions = dist.remove("versions").unwrap_or_default();
+                FeedMessage::ChainDistribution {
+                    implementations,
+                    versions,
+                }
+            }
+            // BestBlockAge
+            23 => {
+                let (block_number, block_hash, age_ms) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::BestBlockAge {
+                    block_number,
+                    block_hash,
+                    age_ms,
+                }
+            }
+            // NodeAlert
+            24 => {
+                let (node_id, kind, severity, value, raised_at) =
+                    serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeAlert {
+                    node_id,
+                    kind,
+                    severity,
+                    value,
+                    raised_at,
+                }
+            }
+            // NodeAlertCleared
+            25 => {
+                let (node_id, kind) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeAlertCleared { node_id, kind }
+            }
+            // NodeCountHistory
+            26 => {
+                let samples: Vec<(Timestamp, usize, usize)> = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeCountHistory {
+                    samples: samples.into_iter().map(NodeCountSample::from).collect(),
+                }
+            }
+            // NodeCountSample
+            27 => {
+                let sample: (Timestamp, usize, usize) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeCountSample {
+                    sample: sample.into(),
+                }
+            }
+            // NodesAtBest
+            28 => {
+                let (block_number, caught_up, total) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodesAtBest {
+                    block_number,
+                    caught_up,
+                    total,
+                }
+            }
+            // SessionKey
+            29 => {
+                let key: String = serde_json::from_str(raw_val.get())?;
+                FeedMessage::SessionKey {
+                    key: hex::decode(key)?,
+                }
+            }
+            // BatchSignature
+            BATCH_SIGNATURE_ACTION => {
+                let (signed_ts, signature): (u64, String) = serde_json::from_str(raw_val.get())?;
+                let signature = hex::decode(signature)?;
+                FeedMessage::BatchSignature {
+                    signed_ts,
+                    signature: std::convert::TryFrom::try_from(&*signature)?,
+                }
+            }
+            // ChainStats
+            31 => {
+                // The top pallets are left off the end unless they've changed:
+                let (name, full, values, continent_distribution, top_pallet_trie_sizes) =
+                    match serde_json::from_str(raw_val.get()) {
+                        Ok((name, full, values, continent_distribution, top_pallets)) => (
+                            name,
+                            full,
+                            values,
+                            continent_distribution,
+                            Some(top_pallets),
+                        ),
+                        Err(_) => {
+                            let (name, full, values, continent_distribution) =
+                                serde_json::from_str(raw_val.get())?;
+                            (name, full, values, continent_distribution, None)
+                        }
+                    };
+                FeedMessage::ChainStats {
+                    name,
+                    full,
+                    values,
+                    continent_distribution,
+                    top_pallet_trie_sizes,
+                }
+            }
+            // ServerShutdown
+            32 => {
+                let restart_in_seconds = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ServerShutdown { restart_in_seconds }
+            }
+            // BlockProductionRate
+            33 => {
+                let blocks_per_second = serde_json::from_str(raw_val.get())?;
+                FeedMessage::BlockProductionRate { blocks_per_second }
+            }
+            // UpgradeScheduled
+            34 => {
+                let (chain, at_block, current_block) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::UpgradeScheduled {
+                    chain,
+                    at_block,
+                    current_block,
+                }
+            }
+            // UpgradeExecuted
+            35 => {
+                let (chain, at_block) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::UpgradeExecuted { chain, at_block }
+            }
+            // SubscriberCount
+            36 => {
+                let (chain, count) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::SubscriberCount { chain, count }
+            }
+            // Heartbeat
+            37 => {
+                let timestamp = serde_json::from_str(raw_val.get())?;
+                FeedMessage::Heartbeat { timestamp }
+            }
+            // PublicNodeShortage
+            38 => {
+                let (chain, count) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::PublicNodeShortage { chain, count }
+            }
+            // PublicNodeShortageResolved
+            39 => {
+                let (chain, count) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::PublicNodeShortageResolved { chain, count }
+            }
             // A catchall for messages we don't know/care about yet:
             _ => {
                 let value = raw_val.to_string();
@@ -368,7 +686,7 @@ mod test {
     #[test]
     fn decode_remove_then_add_node_msg() {
         // "remove chain '', then add chain 'Local Testnet' with 1 node":
-        let msg = r#"[12,"",11,["Local Testnet",1]]"#;
+        let msg = r#"[12,"",11,["Local Testnet",1,null,false]]"#;
 
         assert_eq!(
             FeedMessage::from_bytes(msg.as_bytes()).unwrap(),
@@ -383,4 +701,23 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn decode_signed_batch() {
+        let key = b"session key";
+        let batch = r#"[12,"",11,["Local Testnet",1,null,false]]"#;
+        let signature = common::feed_signing::sign_batch(key, batch.as_bytes(), 1000);
+        let signed = format!(
+            r#"[12,"",11,["Local Testnet",1,null,false],30,[1000,"{}"]]"#,
+            hex::encode(signature)
+        );
+
+        let messages = FeedMessage::from_signed_bytes(signed.as_bytes(), key).unwrap();
+        assert_eq!(messages, FeedMessage::from_bytes(batch.as_bytes()).unwrap());
+
+        assert!(FeedMessage::from_signed_bytes(signed.as_bytes(), b"wrong key").is_err());
+        assert!(FeedMessage::from_signed_bytes(batch.as_bytes(), key).is_err());
+        let tampered = signed.replace("Local", "Other");
+        assert!(FeedMessage::from_signed_bytes(tampered.as_bytes(), key).is_err());
+    }
 }
@@ -0,0 +1,324 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Helpers to fuzz the parsers that attacker controlled input goes through.
+//!
+//! Each fuzz target is a normal test which asks [`run`] to hand it inputs. By default
+//! only a short smoke run happens, so that the targets can live in the normal test
+//! suite. For a proper run, set `FUZZ_ITERATIONS` to something large; every input is
+//! generated from `FUZZ_SEED` (random unless given, and printed on failure), so that
+//! any failure can be reproduced:
+//!
+//! ```text
+//! FUZZ_ITERATIONS=10000000 cargo test --release fuzz_
+//! ```
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// How many inputs each target is given if `FUZZ_ITERATIONS` isn't set.
+pub const SMOKE_ITERATIONS: usize = 2_000;
+
+/// Hand `target` inputs made by mutating the valid `seeds`, along with some
+/// entirely random ones. If the target panics, the seed needed to reproduce the
+/// failure is printed.
+pub fn run(seeds: &[&str], mut target: impl FnMut(&[u8])) {
+    let iterations = env_var("FUZZ_ITERATIONS").unwrap_or(SMOKE_ITERATIONS as u64);
+    let seed = env_var("FUZZ_SEED").unwrap_or_else(rand::random);
+    let mut mutator = Mutator::new(seed, seeds);
+
+    let _report = ReportSeedOnPanic(seed);
+    for _ in 0..iterations {
+        target(&mutator.next_input());
+    }
+}
+
+fn env_var(name: &str) -> Option<u64> {
+    let value = std::env::var(name).ok()?;
+    Some(
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} should be a number, not {:?}", name, value)),
+    )
+}
+
+struct ReportSeedOnPanic(u64);
+
+impl Drop for ReportSeedOnPanic {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            eprintln!("fuzz target failed; rerun with FUZZ_SEED={}", self.0);
+        }
+    }
+}
+
+/// Generates inputs, mostly by mutating some known good inputs. Mutations are
+/// either made to the raw bytes, or (when the seed is JSON) to the structure of
+/// the JSON, so that we get past the JSON parser and into the messages themselves.
+pub struct Mutator {
+    rng: StdRng,
+    seeds: Vec<Vec<u8>>,
+    json_seeds: Vec<Value>,
+}
+
+impl Mutator {
+    pub fn new(seed: u64, seeds: &[&str]) -> Mutator {
+        Mutator {
+            rng: StdRng::seed_from_u64(seed),
+            seeds: seeds.iter().map(|s| s.as_bytes().to_vec()).collect(),
+            json_seeds: seeds
+                .iter()
+                .filter_map(|s| serde_json::from_str(s).ok())
+                .collect(),
+        }
+    }
+
+    /// The next input to try.
+    pub fn next_input(&mut self) -> Vec<u8> {
+        let roll = self.rng.gen_range(0..10);
+        match roll {
+            0 => self.random_bytes(),
+            _ if !self.seeds.is_empty() && (roll <= 3 || self.json_seeds.is_empty()) => {
+                let mut bytes = self.seeds.choose(&mut self.rng).unwrap().clone();
+                for _ in 0..self.rng.gen_range(1..4) {
+                    self.mutate_bytes(&mut bytes);
+                }
+                bytes
+            }
+            _ if !self.json_seeds.is_empty() => {
+                let mut value = self.json_seeds.choose(&mut self.rng).unwrap().clone();
+                for _ in 0..self.rng.gen_range(1..4) {
+                    self.mutate_json(&mut value, 0);
+                }
+                serde_json::to_vec(&value).unwrap()
+            }
+            _ => self.random_bytes(),
+        }
+    }
+
+    fn random_bytes(&mut self) -> Vec<u8> {
+        let len = self.rng.gen_range(0..256);
+        (0..len).map(|_| self.rng.gen()).collect()
+    }
+
+    fn mutate_bytes(&mut self, bytes: &mut Vec<u8>) {
+        let idx = self.rng.gen_range(0..=bytes.len());
+        match self.rng.gen_range(0..5) {
+            0 => bytes.truncate(idx),
+            1 => bytes.insert(idx, *INTERESTING_BYTES.choose(&mut self.rng).unwrap()),
+            2 if idx < bytes.len() => {
+                bytes.remove(idx);
+            }
+            3 if idx < bytes.len() => bytes[idx] ^= 1 << self.rng.gen_range(0..8),
+            _ => {
+                // Repeat a chunk, to make long strings and deeply nested structures:
+                let end = self.rng.gen_range(idx..=bytes.len().min(idx + 16));
+                let chunk = bytes[idx..end].repeat(self.rng.gen_range(1..64));
+                bytes.splice(idx..idx, chunk);
+            }
+        }
+    }
+
+    fn mutate_json(&mut self, value: &mut Value, depth: usize) {
+        // Usually mutate something inside of objects and arrays, rather than replacing them:
+        let descend = depth < 8 && self.rng.gen_bool(0.7);
+        match value {
+            Value::Object(fields) if descend && !fields.is_empty() => {
+                let key = fields
+                    .keys()
+                    .nth(self.rng.gen_range(0..fields.len()))
+                    .cloned();
+                let key = key.unwrap();
+                match self.rng.gen_range(0..4) {
+                    0 => {
+                        fields.remove(&key);
+                    }
+                    1 => {
+                        let new_key = self.random_string();
+                        let value = self.random_value(depth);
+                        fields.insert(new_key, value);
+                    }
+                    _ => self.mutate_json(fields.get_mut(&key).unwrap(), depth + 1),
+                }
+            }
+            Value::Array(items) if descend && !items.is_empty() => {
+                let idx = self.rng.gen_range(0..items.len());
+                self.mutate_json(&mut items[idx], depth + 1)
+            }
+            _ => *value = self.random_value(depth),
+        }
+    }
+
+    fn random_value(&mut self, depth: usize) -> Value {
+        match self.rng.gen_range(0..if depth < 8 { 12 } else { 10 }) {
+            0 => Value::Null,
+            1 => Value::Bool(self.rng.gen()),
+            2 => serde_json::from_str(INTERESTING_NUMBERS.choose(&mut self.rng).unwrap()).unwrap(),
+            3 => self.rng.gen::<i64>().into(),
+            4 => self.rng.gen::<f64>().into(),
+            5 => (-self.rng.gen::<f64>() * 1e300).into(),
+            6 => self.random_string().into(),
+            7 => INTERESTING_NUMBERS
+                .choose(&mut self.rng)
+                .unwrap()
+                .to_string()
+                .into(),
+            8 => "x".repeat(self.rng.gen_range(0..4096)).into(),
+            9 => format!("0x{}", "ab".repeat(self.rng.gen_range(0..40))).into(),
+            10 => (0..self.rng.gen_range(0..8))
+                .map(|_| self.random_value(depth + 1))
+                .collect(),
+            _ => (0..self.rng.gen_range(0..8))
+                .map(|_| (self.random_string(), self.random_value(depth + 1)))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+
+    fn random_string(&mut self) -> String {
+        let len = self.rng.gen_range(0..24);
+        (0..len)
+            .map(|_| match self.rng.gen_range(0..4) {
+                0 => char::from(self.rng.gen_range(0..0x20u8)),
+                1 => self.rng.gen::<char>(),
+                _ => char::from(self.rng.gen_range(0x20..0x7fu8)),
+            })
+            .collect()
+    }
+}
+
+const INTERESTING_BYTES: &[u8] = b"{}[]\":,\\0-.eE \n\x00\xff\xc3";
+
+const INTERESTING_NUMBERS: &[&str] = &[
+    "0",
+    "1",
+    "-1",
+    "255",
+    "256",
+    "65535",
+    "-2147483648",
+    "2147483647",
+    "4294967295",
+    "-9223372036854775808",
+    "9223372036854775807",
+    "18446744073709551615",
+    "18446744073709551616",
+];
+
+/// A global allocator which keeps track of how many bytes each thread has
+/// allocated, so that tests can check that handling some input doesn't allocate
+/// an unreasonable amount of memory. To use it, a test binary needs to install it:
+///
+/// ```ignore
+/// #[cfg(test)]
+/// #[global_allocator]
+/// static ALLOCATOR: test_utils::fuzz::CountingAllocator = test_utils::fuzz::CountingAllocator;
+/// ```
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation(new_size.saturating_sub(layout.size()));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn count_allocation(bytes: usize) {
+    // If the thread is being torn down, there's nothing to count against:
+    let _ = ALLOCATED.try_with(|n| n.set(n.get().saturating_add(bytes)));
+}
+
+/// Run `f`, and hand back how many bytes this thread allocated while doing so (ignoring
+/// anything that was freed again). Panics if [`CountingAllocator`] isn't installed.
+pub fn bytes_allocated_during<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = ALLOCATED.with(Cell::get);
+    drop(std::hint::black_box(Box::new(0u64)));
+    assert!(
+        ALLOCATED.with(Cell::get) > before,
+        "CountingAllocator needs to be the global allocator"
+    );
+
+    let before = ALLOCATED.with(Cell::get);
+    let res = f();
+    (res, ALLOCATED.with(Cell::get) - before)
+}
+
+/// Panic if handling an input of `input_len` bytes allocated more than `budget_per_byte`
+/// bytes for every byte of input (plus a fixed `overhead`).
+pub fn assert_within_budget(
+    input: &[u8],
+    allocated: usize,
+    budget_per_byte: usize,
+    overhead: usize,
+) {
+    let budget = input.len() * budget_per_byte + overhead;
+    assert!(
+        allocated <= budget,
+        "allocated {} bytes handling {} bytes of input (budget {}): {:?}",
+        allocated,
+        input.len(),
+        budget,
+        String::from_utf8_lossy(input)
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_same_inputs() {
+        let seeds = [r#"{"a":[1,2,{"b":"c"}]}"#, "not json"];
+        let mut a = Mutator::new(1234, &seeds);
+        let mut b = Mutator::new(1234, &seeds);
+        for _ in 0..100 {
+            assert_eq!(a.next_input(), b.next_input());
+        }
+    }
+
+    #[test]
+    fn json_mutations_stay_valid_json() {
+        let mut mutator = Mutator::new(42, &[]);
+        let mut value: Value = serde_json::from_str(r#"{"a":[1,2,{"b":"c"}]}"#).unwrap();
+        for _ in 0..1000 {
+            mutator.mutate_json(&mut value, 0);
+            let bytes = serde_json::to_vec(&value).unwrap();
+            serde_json::from_slice::<Value>(&bytes).unwrap();
+        }
+    }
+}
@@ -31,3 +31,9 @@ pub mod workspace;
 
 /// A utility to generate fake telemetry messages at realistic intervals.
 pub mod fake_telemetry;
+
+/// Helpers to fuzz parsers with mutated inputs, and check how much they allocate.
+pub mod fuzz;
+
+/// A tracing subscriber that records the spans created while it's in use, to test them.
+pub mod recorded_spans;
@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+/// The fields recorded on a span, by name.
+pub type SpanFields = BTreeMap<&'static str, String>;
+
+/// A tracing subscriber which records every span created, and the fields recorded on it.
+/// Clones share the same record of spans.
+#[derive(Clone, Default)]
+pub struct RecordingSubscriber {
+    spans: Arc<Mutex<Vec<(&'static str, SpanFields)>>>,
+}
+
+impl RecordingSubscriber {
+    pub fn new() -> Self {
+        RecordingSubscriber::default()
+    }
+
+    /// The fields of every span with the given name, in the order they were created.
+    pub fn fields_of(&self, name: &str) -> Vec<SpanFields> {
+        self.spans
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(span_name, _)| *span_name == name)
+            .map(|(_, fields)| fields.clone())
+            .collect()
+    }
+}
+
+struct FieldRecorder<'a>(&'a mut SpanFields);
+
+impl Visit for FieldRecorder<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+impl Subscriber for RecordingSubscriber {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut spans = self.spans.lock().unwrap();
+        let mut fields = BTreeMap::new();
+        span.record(&mut FieldRecorder(&mut fields));
+        spans.push((span.metadata().name(), fields));
+        Id::from_u64(spans.len() as u64)
+    }
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = self.spans.lock().unwrap();
+        let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+        values.record(&mut FieldRecorder(fields));
+    }
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+    fn event(&self, _: &Event<'_>) {}
+    fn enter(&self, _: &Id) {}
+    fn exit(&self, _: &Id) {}
+}
@@ -193,6 +193,27 @@ impl FeedReceiver {
         }
     }
 
+    /// Wait for the next set of feed messages to arrive on a feed that asked for signed
+    /// batches, checking that they were signed with the key given. The signature itself
+    /// is not returned.
+    pub async fn recv_signed_feed_messages_once(
+        &mut self,
+        key: &[u8],
+    ) -> Result<Vec<FeedMessage>, anyhow::Error> {
+        let msg = self
+            .0
+            .next()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Stream closed: no more messages"))??;
+
+        match msg {
+            ws_client::RecvMessage::Binary(data) => FeedMessage::from_signed_bytes(&data, key),
+            ws_client::RecvMessage::Text(text) => {
+                FeedMessage::from_signed_bytes(text.as_bytes(), key)
+            }
+        }
+    }
+
     /// Wait for the next set of feed messages to arrive.
     /// See `recv_feed_messages_once_timeout`.
     pub async fn recv_feed_messages_once(&mut self) -> Result<Vec<FeedMessage>, anyhow::Error> {
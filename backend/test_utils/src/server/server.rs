@@ -112,6 +112,10 @@ pub enum Error {
     CannotAddShard,
     #[error("The URI provided was invalid: {0}")]
     InvalidUri(#[from] http::uri::InvalidUri),
+    #[error("Could not build HTTP request: {0}")]
+    HttpError(#[from] http::Error),
+    #[error("HTTP request failed: {0}")]
+    HyperError(#[from] hyper::Error),
 }
 
 impl Server {
@@ -227,11 +231,15 @@ impl Server {
                 // Attempt to wait until we've received word that the shard is connected to the
                 // core before continuing. If we don't wait for this, the connection may happen
                 // after we've attempted to connect node sockets, and they would be booted and
-                // made to reconnect, which we don't want to deal with in general.
-                let _ = utils::wait_for_line_containing(
-                    &mut child_stdout,
-                    |s| s.contains("Connected to telemetry core"),
+                // made to reconnect, which we don't want to deal with in general. A shard that
+                // can't connect keeps logging about it, so give up after a while regardless.
+                let _ = tokio::time::timeout(
                     std::time::Duration::from_secs(5),
+                    utils::wait_for_line_containing(
+                        &mut child_stdout,
+                        |s| s.contains("Connected to telemetry core"),
+                        std::time::Duration::from_secs(5),
+                    ),
                 )
                 .await;
 
@@ -383,6 +391,27 @@ impl<Channel> Process<Channel> {
         &self.host
     }
 
+    /// Ask the process to shut down gracefully by sending it SIGTERM. This doesn't
+    /// wait for it to exit.
+    pub async fn terminate(&self) -> Result<(), Error> {
+        let pid = self
+            .handle
+            .as_ref()
+            .and_then(|handle| handle.id())
+            .ok_or(Error::CannotKillNoHandle)?;
+        let status = process::Command::new("kill")
+            .arg("-TERM")
+            .arg(pid.to_string())
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(
+                std::io::Error::other(format!("kill -TERM {} failed: {}", pid, status)).into(),
+            );
+        }
+        Ok(())
+    }
+
     /// Kill the process and wait for this to complete
     /// Not public: Klling done via Server.
     async fn kill(self) -> Result<(), Error> {
@@ -413,6 +442,15 @@ impl<Send: From<ws_client::Sender>, Recv: From<ws_client::Receiver>> Process<(Se
             .map_err(|e| e.into())
     }
 
+    /// Establish a connection to the process that asks for every batch of feed messages
+    /// to be signed. The first message sent back will be the session key.
+    pub async fn connect_feed_signed(
+        &self,
+    ) -> Result<(channels::FeedSender, channels::FeedReceiver), Error> {
+        let uri = format!("http://{}/feed?sign", self.host).parse()?;
+        Process::connect_to_uri(&uri).await
+    }
+
     /// Establish multiple connections to the process
     async fn connect_multiple_to_uri(
         uri: &http::Uri,
@@ -469,6 +507,84 @@ impl CoreProcess {
         Process::connect_to_uri(&uri).await
     }
 
+    /// Establish a connection to the process that is only told about nodes
+    /// with the given network ID (and about chains as a whole).
+    pub async fn connect_feed_for_node(
+        &self,
+        network_id: &str,
+    ) -> Result<(channels::FeedSender, channels::FeedReceiver), Error> {
+        let uri = format!("http://{}/feed?node={}", self.host, network_id).parse()?;
+        Process::connect_to_uri(&uri).await
+    }
+
+    /// Establish a connection to the process that is only told about nodes
+    /// in the given environment (and about chains as a whole).
+    pub async fn connect_feed_for_environment(
+        &self,
+        environment: &str,
+    ) -> Result<(channels::FeedSender, channels::FeedReceiver), Error> {
+        let uri = format!("http://{}/feed?environment={}", self.host, environment).parse()?;
+        Process::connect_to_uri(&uri).await
+    }
+
+    /// Make a GET request to one of the "/admin" endpoints, handing back the status
+    /// code and body of the response.
+    pub async fn admin_get(
+        &self,
+        path_and_query: &str,
+        admin_token: &str,
+    ) -> Result<(http::StatusCode, String), Error> {
+        self.admin_request(
+            http::Method::GET,
+            path_and_query,
+            admin_token,
+            String::new(),
+        )
+        .await
+    }
+
+    /// Make a PUT request with the given body to one of the "/admin" endpoints, handing
+    /// back the status code and body of the response.
+    pub async fn admin_put(
+        &self,
+        path_and_query: &str,
+        admin_token: &str,
+        body: impl Into<String>,
+    ) -> Result<(http::StatusCode, String), Error> {
+        self.admin_request(http::Method::PUT, path_and_query, admin_token, body.into())
+            .await
+    }
+
+    /// Make a POST request with the given body to one of the "/admin" endpoints, handing
+    /// back the status code and body of the response.
+    pub async fn admin_post(
+        &self,
+        path_and_query: &str,
+        admin_token: &str,
+        body: impl Into<String>,
+    ) -> Result<(http::StatusCode, String), Error> {
+        self.admin_request(http::Method::POST, path_and_query, admin_token, body.into())
+            .await
+    }
+
+    async fn admin_request(
+        &self,
+        method: http::Method,
+        path_and_query: &str,
+        admin_token: &str,
+        body: String,
+    ) -> Result<(http::StatusCode, String), Error> {
+        let req = http::Request::builder()
+            .method(method)
+            .uri(format!("http://{}/admin{}", self.host, path_and_query))
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .body(hyper::Body::from(body))?;
+        let res = hyper::Client::new().request(req).await?;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        Ok((status, String::from_utf8_lossy(&body).into_owned()))
+    }
+
     /// Establish multiple connections to the process
     pub async fn connect_multiple_feeds(
         &self,
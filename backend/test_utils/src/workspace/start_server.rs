@@ -16,6 +16,8 @@
 
 use super::commands;
 use crate::server::{self, Command, Server};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 /// Options for the server
 pub struct ServerOpts {
@@ -37,6 +39,15 @@ pub struct CoreOpts {
     pub feed_timeout: Option<u64>,
     pub worker_threads: Option<usize>,
     pub num_aggregators: Option<usize>,
+    /// Serve feeds over WebTransport on this address, using the
+    /// certificate and private key in the given PEM files.
+    pub webtransport: Option<(SocketAddr, PathBuf, PathBuf)>,
+    /// Enable the "/admin" endpoints, which need this token to use.
+    pub admin_token: Option<String>,
+    /// Keep a disconnected shard's nodes for this many seconds in case it restarts.
+    pub shard_restart_grace_secs: Option<u64>,
+    /// Only accept shards that provide this token.
+    pub shard_token: Option<String>,
 }
 
 impl Default for CoreOpts {
@@ -45,6 +56,10 @@ impl Default for CoreOpts {
             feed_timeout: None,
             worker_threads: None,
             num_aggregators: None,
+            webtransport: None,
+            admin_token: None,
+            shard_restart_grace_secs: None,
+            shard_token: None,
         }
     }
 }
@@ -55,6 +70,12 @@ pub struct ShardOpts {
     pub max_node_data_per_second: Option<usize>,
     pub node_block_seconds: Option<u64>,
     pub worker_threads: Option<usize>,
+    /// Also accept newline delimited JSON from legacy nodes over plain TCP on this address.
+    pub legacy_tcp_listen: Option<SocketAddr>,
+    /// Remember which nodes are connected in this file, across restarts.
+    pub resumption_cache: Option<PathBuf>,
+    /// Provide this token to the core when connecting to it.
+    pub core_token: Option<String>,
 }
 
 impl Default for ShardOpts {
@@ -64,6 +85,9 @@ impl Default for ShardOpts {
             max_node_data_per_second: None,
             node_block_seconds: None,
             worker_threads: None,
+            legacy_tcp_listen: None,
+            resumption_cache: None,
+            core_token: None,
         }
     }
 }
@@ -142,6 +166,17 @@ pub async fn start_server(
     if let Some(val) = shard_opts.worker_threads {
         shard_command = shard_command.arg("--worker-threads").arg(val.to_string());
     }
+    if let Some(val) = shard_opts.legacy_tcp_listen {
+        shard_command = shard_command
+            .arg("--legacy-tcp-listen")
+            .arg(val.to_string());
+    }
+    if let Some(path) = shard_opts.resumption_cache {
+        shard_command = shard_command.arg("--resumption-cache").arg(path);
+    }
+    if let Some(token) = shard_opts.core_token {
+        shard_command = shard_command.arg("--core-token").arg(token);
+    }
 
     // Build the core command
     let mut core_command = std::env::var("TELEMETRY_CORE_BIN")
@@ -161,6 +196,26 @@ pub async fn start_server(
     if let Some(val) = core_opts.num_aggregators {
         core_command = core_command.arg("--num-aggregators").arg(val.to_string());
     }
+    if let Some((addr, cert, key)) = core_opts.webtransport {
+        core_command = core_command
+            .arg("--webtransport-listen")
+            .arg(addr.to_string())
+            .arg("--webtransport-cert")
+            .arg(cert)
+            .arg("--webtransport-key")
+            .arg(key);
+    }
+    if let Some(token) = core_opts.admin_token {
+        core_command = core_command.arg("--admin-token").arg(token);
+    }
+    if let Some(val) = core_opts.shard_restart_grace_secs {
+        core_command = core_command
+            .arg("--shard-restart-grace-secs")
+            .arg(val.to_string());
+    }
+    if let Some(token) = core_opts.shard_token {
+        core_command = core_command.arg("--shard-token").arg(token);
+    }
 
     // Start the server
     Server::start(server::StartOpts::ShardAndCore {
@@ -24,6 +24,8 @@ import {
   City,
   NodeId,
   NodeCount,
+  PeerCount,
+  ChainType,
   NodeDetails,
   NodeStats,
   NodeIO,
@@ -96,7 +98,8 @@ export namespace Variants {
       NodeHardware,
       BlockDetails,
       Maybe<NodeLocation>,
-      Maybe<Timestamp>
+      Maybe<Timestamp>,
+      Array<PeerCount>
     ];
   }
 
@@ -117,12 +120,12 @@ export namespace Variants {
 
   export interface FinalizedBlockMessage extends MessageBase {
     action: typeof ACTIONS.FinalizedBlock;
-    payload: [NodeId, BlockNumber, BlockHash];
+    payload: [NodeId, BlockNumber, BlockHash, Maybe<Timestamp>];
   }
 
   export interface NodeStatsMessage extends MessageBase {
     action: typeof ACTIONS.NodeStats;
-    payload: [NodeId, NodeStats];
+    payload: [NodeId, NodeStats, Array<PeerCount>];
   }
 
   export interface NodeHardwareMessage extends MessageBase {
@@ -142,7 +145,7 @@ export namespace Variants {
 
   export interface AddedChainMessage extends MessageBase {
     action: typeof ACTIONS.AddedChain;
-    payload: [ChainLabel, NodeCount];
+    payload: [ChainLabel, NodeCount, Maybe<ChainType>, boolean];
   }
 
   export interface RemovedChainMessage extends MessageBase {
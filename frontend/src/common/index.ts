@@ -24,5 +24,6 @@ import * as FeedMessage from './feed';
 
 export { Types, FeedMessage };
 
-// Increment this if breaking changes were made to types in `feed.ts`
-export const VERSION: Types.FeedVersion = 31 as Types.FeedVersion;
+// Increment this if breaking changes were made to types in `feed.ts`. This must match
+// `FEED_VERSION` in the backend, or the page keeps reloading in search of a newer one.
+export const VERSION: Types.FeedVersion = 42 as Types.FeedVersion;
@@ -41,6 +41,7 @@ export type CPUUse = Opaque<number, 'CPUUse'>;
 export type Bytes = Opaque<number, 'Bytes'>;
 export type BytesPerSecond = Opaque<number, 'BytesPerSecond'>;
 export type NetworkId = Opaque<string, 'NetworkId'>;
+export type ChainType = Opaque<number, 'ChainType'>;
 
 export type BlockDetails = [
   BlockNumber,